@@ -1,4 +1,13 @@
 fn main() {
     println!("cargo:rerun-if-changed=target.json");
     println!("cargo:rerun-if-changed=layout.ld");
+
+    // The kernel the no-disk feature builds in
+    if std::env::var_os("CARGO_FEATURE_NO_DISK").is_some() {
+        println!("cargo:rerun-if-env-changed=RHFW_KERNEL");
+        match std::env::var("RHFW_KERNEL") {
+            Ok(path) => println!("cargo:rerun-if-changed={}", path),
+            Err(_) => panic!("The no-disk feature needs RHFW_KERNEL set to the kernel to build in"),
+        }
+    }
 }
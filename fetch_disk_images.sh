@@ -44,9 +44,20 @@ FOCAL_OS_IMAGE_URL="$FOCAL_OS_IMAGE_BASE/$FOCAL_OS_IMAGE_NAME"
 fetch_image "$FOCAL_OS_IMAGE_NAME" "$FOCAL_OS_IMAGE_URL"
 convert_image "$FOCAL_OS_IMAGE_NAME" "$FOCAL_OS_RAW_IMAGE_NAME"
 
+# Booted as the ESP's BOOTX64.EFI through the EFI handover entry
+FOCAL_KERNEL_NAME="focal-server-cloudimg-amd64-vmlinuz-generic"
+FOCAL_INITRD_NAME="focal-server-cloudimg-amd64-initrd-generic"
+fetch_image "$FOCAL_KERNEL_NAME" "$FOCAL_OS_IMAGE_BASE/unpacked/$FOCAL_KERNEL_NAME"
+fetch_image "$FOCAL_INITRD_NAME" "$FOCAL_OS_IMAGE_BASE/unpacked/$FOCAL_INITRD_NAME"
+
 GROOVY_OS_IMAGE_NAME="groovy-server-cloudimg-amd64.img"
 GROOVY_OS_RAW_IMAGE_NAME="groovy-server-cloudimg-amd64-raw.img"
 GROOVY_OS_IMAGE_BASE="https://cloud-images.ubuntu.com/groovy/current"
 GROOVY_OS_IMAGE_URL="$GROOVY_OS_IMAGE_BASE/$GROOVY_OS_IMAGE_NAME"
 fetch_image "$GROOVY_OS_IMAGE_NAME" "$GROOVY_OS_IMAGE_URL"
 convert_image "$GROOVY_OS_IMAGE_NAME" "$GROOVY_OS_RAW_IMAGE_NAME"
+
+# Chainloaded over TFTP by the net-boot tests
+IPXE_EFI_NAME="ipxe.efi"
+IPXE_EFI_URL="https://boot.ipxe.org/$IPXE_EFI_NAME"
+fetch_image "$IPXE_EFI_NAME" "$IPXE_EFI_URL"
@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: Apache-2.0
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// The ACPI 1.0 part of the RSDP and the full revision 2 structure
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidSignature,
+    InvalidChecksum,
+    InvalidLength,
+    NotFound,
+}
+
+// All the bytes of a valid table add up to zero
+fn checksum(data: &[u8]) -> Result<(), Error> {
+    if data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0 {
+        Ok(())
+    } else {
+        Err(Error::InvalidChecksum)
+    }
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+unsafe fn bytes(address: u64, length: usize) -> &'static [u8] {
+    core::slice::from_raw_parts(address as *const u8, length)
+}
+
+/// Returns the contents of the table at address after checking its header
+pub fn table(address: u64) -> Result<&'static [u8], Error> {
+    let header = unsafe { bytes(address, SDT_HEADER_SIZE) };
+    let length = read_u32(header, 4) as usize;
+    if length < SDT_HEADER_SIZE {
+        return Err(Error::InvalidLength);
+    }
+    let data = unsafe { bytes(address, length) };
+    checksum(data)?;
+    Ok(data)
+}
+
+/// Finds the physical address of the table with the given signature, using
+/// the XSDT if the RSDP has one and the RSDT otherwise
+pub fn find_table(rsdp: u64, signature: &[u8; 4]) -> Result<u64, Error> {
+    let data = unsafe { bytes(rsdp, RSDP_V1_SIZE) };
+    if &data[0..8] != RSDP_SIGNATURE {
+        return Err(Error::InvalidSignature);
+    }
+    checksum(data)?;
+
+    // revision: 15, rsdt_address: 16, length: 20, xsdt_address: 24
+    let (sdt, entry_size, expected) = if data[15] >= 2 {
+        let data = unsafe { bytes(rsdp, RSDP_V2_SIZE) };
+        let length = read_u32(data, 20) as usize;
+        if length < RSDP_V2_SIZE {
+            return Err(Error::InvalidLength);
+        }
+        checksum(unsafe { bytes(rsdp, length) })?;
+        (read_u64(data, 24), 8, b"XSDT")
+    } else {
+        (u64::from(read_u32(data, 16)), 4, b"RSDT")
+    };
+
+    let sdt = table(sdt)?;
+    if &sdt[0..4] != expected {
+        return Err(Error::InvalidSignature);
+    }
+
+    for entry in sdt[SDT_HEADER_SIZE..].chunks_exact(entry_size) {
+        let address = if entry_size == 8 {
+            read_u64(entry, 0)
+        } else {
+            u64::from(read_u32(entry, 0))
+        };
+        let header = unsafe { bytes(address, SDT_HEADER_SIZE) };
+        if &header[0..4] == signature {
+            table(address)?;
+            return Ok(address);
+        }
+    }
+
+    Err(Error::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_table, table, Error};
+
+    fn fix_checksum(data: &mut [u8], offset: usize) {
+        data[offset] = 0;
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        data[offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn make_table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 36];
+        data[0..4].copy_from_slice(signature);
+        data[4..8].copy_from_slice(&((36 + body.len()) as u32).to_le_bytes());
+        data[8] = 1;
+        data[10..16].copy_from_slice(b"RHFW  ");
+        data.extend_from_slice(body);
+        fix_checksum(&mut data, 9);
+        data
+    }
+
+    fn make_rsdp(revision: u8, rsdt: u32, xsdt: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 36];
+        data[0..8].copy_from_slice(b"RSD PTR ");
+        data[15] = revision;
+        data[16..20].copy_from_slice(&rsdt.to_le_bytes());
+        data[20..24].copy_from_slice(&36u32.to_le_bytes());
+        data[24..32].copy_from_slice(&xsdt.to_le_bytes());
+        let (v1, _) = data.split_at_mut(20);
+        fix_checksum(v1, 8);
+        fix_checksum(&mut data, 32);
+        data
+    }
+
+    fn address(data: &[u8]) -> u64 {
+        data.as_ptr() as u64
+    }
+
+    #[test]
+    fn test_find_table_xsdt() {
+        let apic = make_table(b"APIC", &[1, 2, 3, 4]);
+        let facp = make_table(b"FACP", &[0; 100]);
+        let mut entries = Vec::new();
+        entries.extend_from_slice(&address(&apic).to_le_bytes());
+        entries.extend_from_slice(&address(&facp).to_le_bytes());
+        let xsdt = make_table(b"XSDT", &entries);
+        let rsdp = make_rsdp(2, 0, address(&xsdt));
+
+        assert_eq!(find_table(address(&rsdp), b"APIC"), Ok(address(&apic)));
+        assert_eq!(find_table(address(&rsdp), b"FACP"), Ok(address(&facp)));
+        assert_eq!(find_table(address(&rsdp), b"HPET"), Err(Error::NotFound));
+        assert_eq!(table(address(&apic)).unwrap()[36..], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        let apic = make_table(b"APIC", &[1, 2, 3, 4]);
+        let xsdt = make_table(b"XSDT", &address(&apic).to_le_bytes());
+
+        let mut rsdp = make_rsdp(2, 0, address(&xsdt));
+        rsdp[0] = b'X';
+        assert_eq!(
+            find_table(address(&rsdp), b"APIC"),
+            Err(Error::InvalidSignature)
+        );
+
+        let mut rsdp = make_rsdp(2, 0, address(&xsdt));
+        rsdp[35] = 1;
+        assert_eq!(
+            find_table(address(&rsdp), b"APIC"),
+            Err(Error::InvalidChecksum)
+        );
+
+        let mut apic = apic;
+        apic[36] = 0;
+        let xsdt = make_table(b"XSDT", &address(&apic).to_le_bytes());
+        let rsdp = make_rsdp(2, 0, address(&xsdt));
+        assert_eq!(
+            find_table(address(&rsdp), b"APIC"),
+            Err(Error::InvalidChecksum)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The boot CPU's local APIC timer, which interrupts whatever is running often
+// enough for the watchdogs to be checked, even when it never calls back into
+// the firmware. It is calibrated against the TSC, and both the xAPIC and
+// x2APIC interfaces are supported.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+
+use x86_64::registers::model_specific::Msr;
+
+use crate::delay;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+// In x2APIC mode each register is an MSR, 16 bytes of the MMIO page to one
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Registers, as offsets into the xAPIC's MMIO page
+const EOI: u32 = 0x0b0;
+const SPURIOUS_VECTOR: u32 = 0x0f0;
+const LVT_TIMER: u32 = 0x320;
+const INITIAL_COUNT: u32 = 0x380;
+const CURRENT_COUNT: u32 = 0x390;
+const DIVIDE_CONFIGURATION: u32 = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+// The timer counts at the bus clock divided by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const CALIBRATION_MS: u64 = 10;
+const PERIOD_MS: u64 = 10;
+
+// IA32_APIC_BASE once the timer is started, 0 before
+static APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+fn read(register: u32) -> u32 {
+    let base = APIC_BASE.load(Ordering::Relaxed);
+    if base & APIC_BASE_X2APIC != 0 {
+        unsafe { Msr::new(X2APIC_MSR_BASE + register / 16).read() as u32 }
+    } else {
+        let address = (base & APIC_BASE_ADDRESS_MASK) + u64::from(register);
+        unsafe { core::ptr::read_volatile(address as *const u32) }
+    }
+}
+
+fn write(register: u32, value: u32) {
+    let base = APIC_BASE.load(Ordering::Relaxed);
+    if base & APIC_BASE_X2APIC != 0 {
+        unsafe { Msr::new(X2APIC_MSR_BASE + register / 16).write(u64::from(value)) }
+    } else {
+        let address = (base & APIC_BASE_ADDRESS_MASK) + u64::from(register);
+        unsafe { core::ptr::write_volatile(address as *mut u32, value) }
+    }
+}
+
+// The initial count for the period, from how far the timer counted down in
+// the calibration period
+fn period_count(calibration_ticks: u32) -> Option<u32> {
+    let count = u64::from(calibration_ticks) * PERIOD_MS / CALIBRATION_MS;
+    match count {
+        0 => None,
+        count => Some(count.min(u64::from(u32::MAX)) as u32),
+    }
+}
+
+// Has the timer interrupt with the vector every PERIOD_MS, spurious
+// interrupts coming in with the other. Returns false if there is no local
+// APIC or its timer doesn't count.
+pub fn start_timer(vector: u8, spurious_vector: u8) -> bool {
+    // CPUID.01H:EDX.APIC[bit 9]
+    if unsafe { __cpuid(1) }.edx & (1 << 9) == 0 {
+        return false;
+    }
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if base & APIC_BASE_ENABLE == 0 {
+        return false;
+    }
+    APIC_BASE.store(base, Ordering::Relaxed);
+
+    write(
+        SPURIOUS_VECTOR,
+        SOFTWARE_ENABLE | u32::from(spurious_vector),
+    );
+    write(DIVIDE_CONFIGURATION, DIVIDE_BY_16);
+    // Counting down once from the top, without interrupting
+    write(LVT_TIMER, LVT_MASKED | u32::from(vector));
+    write(INITIAL_COUNT, u32::MAX);
+    delay::mdelay(CALIBRATION_MS);
+    let count = match period_count(u32::MAX - read(CURRENT_COUNT)) {
+        Some(count) => count,
+        None => {
+            stop_timer();
+            return false;
+        }
+    };
+    write(LVT_TIMER, LVT_PERIODIC | u32::from(vector));
+    write(INITIAL_COUNT, count);
+    true
+}
+
+pub fn stop_timer() {
+    if APIC_BASE.load(Ordering::Relaxed) != 0 {
+        write(LVT_TIMER, LVT_MASKED);
+        write(INITIAL_COUNT, 0);
+    }
+}
+
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::period_count;
+
+    #[test]
+    fn test_period_count() {
+        assert_eq!(period_count(0), None);
+        assert_eq!(period_count(62_500), Some(62_500));
+        assert_eq!(period_count(u32::MAX), Some(u32::MAX));
+    }
+}
@@ -0,0 +1,45 @@
+.section .text, "ax"
+.global efi_start_image
+.global efi_exit_image
+.code64
+
+# Calls an image's entry point using the EFI calling convention, saving the
+# stack pointer so that Exit() can return from anywhere inside the image.
+#   %rdi: entry point
+#   %rsi: image handle
+#   %rdx: system table
+#   %rcx: where to save the stack pointer
+efi_start_image:
+    # Our callee-saved registers, which Exit() skips the image restoring
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq %rsp, (%rcx)
+
+    movq %rdi, %rax
+    movq %rsi, %rcx
+    # The system table is already in %rdx, the second argument
+    # Shadow space for the callee, keeping the stack 16 byte aligned
+    subq $40, %rsp
+    callq *%rax
+    addq $40, %rsp
+
+efi_image_return:
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    retq
+
+# Returns from the efi_start_image() call that saved the stack pointer
+#   %rdi: the saved stack pointer
+#   %rsi: status to return
+efi_exit_image:
+    movq %rdi, %rsp
+    movq %rsi, %rax
+    jmp efi_image_return
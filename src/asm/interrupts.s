@@ -0,0 +1,88 @@
+.section .text, "ax"
+.global exception_stubs
+.global timer_stub
+.global spurious_stub
+.code64
+
+# An entry point for each of the 32 exception vectors, which pushes the
+# vector and calls exception(). Those for which the CPU doesn't push an error
+# code push 0 in its place, so that the frame always looks the same.
+.irp vector, 0,1,2,3,4,5,6,7,9,15,16,18,19,20,22,23,24,25,26,27,28,31
+exception_stub\vector:
+    pushq $0
+    pushq $\vector
+    jmp exception_common
+.endr
+
+.irp vector, 8,10,11,12,13,14,17,21,29,30
+exception_stub\vector:
+    pushq $\vector
+    jmp exception_common
+.endr
+
+# Pushes the general purpose registers, the rest of interrupts::Frame, and
+# calls exception() with the frame on a 16 byte aligned stack. It never
+# returns.
+exception_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    cld
+    movq %rsp, %rdi
+    andq $-16, %rsp
+    callq exception
+    ud2
+
+# The timer interrupts whatever is running, so everything timer_interrupt()
+# can change is saved around it. The firmware is built without SSE, so that
+# is only the general purpose registers the SysV ABI doesn't preserve.
+timer_stub:
+    pushq %rax
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %rbp
+    movq %rsp, %rbp
+    andq $-16, %rsp
+    cld
+    callq timer_interrupt
+    movq %rbp, %rsp
+    popq %rbp
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rax
+    iretq
+
+# The local APIC doesn't expect an EOI for these
+spurious_stub:
+    iretq
+
+.section .rodata
+# Where each vector's entry point is, for the IDT
+exception_stubs:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad exception_stub\vector
+.endr
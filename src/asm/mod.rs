@@ -1 +1,6 @@
+#[cfg(not(test))]
 global_asm!(include_str!("ram32.s"));
+global_asm!(include_str!("efi.s"));
+#[cfg(not(test))]
+global_asm!(include_str!("interrupts.s"));
+global_asm!(include_str!("start32.s"));
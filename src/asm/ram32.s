@@ -3,8 +3,20 @@
 .code32
 
 ram32_start:
-    # Stash the PVH start_info struct in %rdi.
+    # We are either started here in 32-bit protected mode through our PVH
+    # note, or in 64-bit mode as the ELF entrypoint, which is how some VMMs
+    # start an ELF kernel. These bytes run the same in both modes except for
+    # the 0x40, which is "incl %eax" in 32-bit mode but only a REX prefix on
+    # the nop in 64-bit mode.
+    xorl %eax, %eax
+    .byte 0x40
+    nop
+    testl %eax, %eax
+    jz ram64_start
+
+    # Stash the PVH start_info struct in %rdi, with no boot parameters in %rsi.
     movl %ebx, %edi
+    xorl %esi, %esi
 
 setup_page_tables:
     # First L2 entry identity maps [0, 2 MiB)
@@ -53,3 +65,25 @@ jump_to_64bit:
     # Set CS to a 64-bit segment and jump to 64-bit Rust code.
     # PVH start_info is in %rdi, the first paramter of the System V ABI.
     ljmpl $0x08, $rust64_start
+
+.code64
+ram64_start:
+    # The VMM's page tables are still in use, but its GDT and stack could be
+    # anywhere. %rbx might have the PVH start_info struct and %rsi the Linux
+    # boot parameters, which rust64_start checks before using either.
+    cli
+    cld
+    movq %rbx, %rdi
+    lgdt GDT64_PTR(%rip)
+    movq $stack_start, %rsp
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %gs
+    movw %ax, %fs
+    movw %ax, %ss
+    # Set CS to our 64-bit segment with a far return to 64-bit Rust code.
+    pushq $0x08
+    movabsq $rust64_start, %rax
+    pushq %rax
+    lretq
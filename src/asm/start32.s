@@ -0,0 +1,50 @@
+.section .text, "ax"
+.global start32
+.code64
+
+# Starts a kernel in the state the Multiboot2 and PVH boot protocols expect:
+# 32-bit protected mode with paging and interrupts disabled, and what the
+# kernel is to be told in %ebx and %eax. Everything has to be below 4GiB.
+#   %rdi: entry point
+#   %rsi: value for %ebx
+#   %rdx: value for %eax
+start32:
+    cli
+    # Far return to the 32-bit code segment, into compatibility mode
+    pushq $0x18
+    leaq start32_compat(%rip), %rax
+    pushq %rax
+    lretq
+
+.code32
+start32_compat:
+    # rdmsr and wrmsr use %eax and %edx
+    movl %edx, %ebp
+
+    # Clear CR0.PG (Paging), which leaves long mode
+    movl %cr0, %eax
+    andl $~(1 << 31), %eax
+    movl %eax, %cr0
+    # Clear EFER.LME (Long Mode Enable)
+    movl $0xC0000080, %ecx
+    rdmsr
+    andl $~(1 << 8), %eax
+    wrmsr
+    # Clear CR4.PAE (Physical Address Extension)
+    movl %cr4, %eax
+    andl $~(1 << 5), %eax
+    movl %eax, %cr4
+
+    # Set segment registers to a 32-bit segment.
+    movw $0x20, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %fs
+    movw %ax, %gs
+    movw %ax, %ss
+
+    movl %esi, %ebx
+    movl %ebp, %eax
+    jmp *%edi
+
+.code64
@@ -12,50 +12,61 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::cell::RefCell;
+use core::{
+    cell::{Cell, RefCell},
+    fmt,
+};
 
-use crate::virtio::{Error as VirtioError, VirtioTransport};
+use crate::{
+    mmio, pci,
+    virtio::{
+        self, AvailRing, Desc, Error as VirtioError, EventSuppression, PackedDesc, UsedRing,
+        VirtioTransport, QUEUE_SIZE, VIRTIO_PCI_VENDOR_ID,
+    },
+};
 
-const QUEUE_SIZE: usize = 16;
+const CACHE_SIZE: usize = 16;
+// Largest read issued to the device as a single request
+const MAX_REQUEST_SECTORS: usize = 128;
+// Largest logical block size a device can have
+const MAX_BLOCK_SIZE: usize = 4096;
+// Most ranges sent in a single discard request
+const MAX_DISCARD_SEGMENTS: usize = 16;
 
-#[repr(C)]
-#[repr(align(16))]
-#[derive(Default)]
-/// A virtio qeueue entry descriptor
-struct Desc {
-    addr: u64,
-    length: u32,
-    flags: u16,
-    next: u16,
-}
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
 
-#[repr(C)]
-#[repr(align(2))]
-#[derive(Default)]
-/// The virtio available ring
-struct AvailRing {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE],
-}
+const VIRTIO_PCI_BLOCK_DEVICE_ID: u16 = 0x1042;
+const VIRTIO_PCI_TRANSITIONAL_BLOCK_DEVICE_ID: u16 = 0x1001;
 
-#[repr(C)]
-#[repr(align(4))]
-#[derive(Default)]
-/// The virtio used ring
-struct UsedRing {
-    flags: u16,
-    idx: u16,
-    ring: [UsedElem; QUEUE_SIZE],
-}
+// Where QEMU's microvm machine places its virtio-mmio transports
+const VIRTIO_MMIO_BASE: u64 = 0xfeb0_0000;
+const VIRTIO_MMIO_COUNT: u64 = 24;
+const VIRTIO_MMIO_BLOCK_DEVICE_ID: u32 = 2;
 
-#[repr(C)]
-#[derive(Default)]
-/// A single element in the used ring
-struct UsedElem {
-    id: u32,
-    len: u32,
-}
+// Mass storage controllers of the non-volatile memory kind
+#[cfg(feature = "nvme")]
+const PCI_CLASS_MASS_STORAGE: u8 = 0x01;
+#[cfg(feature = "nvme")]
+const PCI_SUBCLASS_NVM: u8 = 0x08;
+
+/// The most disks looked at for booting
+pub const MAX_DISKS: usize = 16;
+
+// Picks the disk, by its number, to boot from before any other
+const BOOT_DISK_OPTION: &[u8] = b"rhfw.boot_disk=";
+
+const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+// Packed ring descriptors are available when the first of these matches
+// the wrap counter and the second doesn't, and used when both match it
+const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+// How the driver asks a packed ring's device not to interrupt
+const RING_EVENT_FLAGS_DISABLE: u16 = 1;
 
 #[repr(C)]
 #[repr(align(64))]
@@ -63,6 +74,17 @@ struct UsedElem {
 pub struct VirtioBlockDevice<'a> {
     transport: &'a mut dyn VirtioTransport,
     state: RefCell<DriverState>,
+    features: u64,
+    read_only: bool,
+    requests: Cell<u64>,
+    block_size: u32,
+    // For reads and writes of less than a block, when blocks are bigger
+    // than a sector
+    bounce: RefCell<[u8; MAX_BLOCK_SIZE]>,
+    // Limits on discard requests, with the alignment in sectors
+    max_discard_sectors: u32,
+    max_discard_segments: u32,
+    discard_alignment: u32,
 }
 
 #[repr(C)]
@@ -72,13 +94,109 @@ struct DriverState {
     descriptors: [Desc; QUEUE_SIZE],
     avail: AvailRing,
     used: UsedRing,
+    // Only used when the device will only take a packed ring
+    packed: [PackedDesc; QUEUE_SIZE],
+    driver_event: EventSuppression,
+    device_event: EventSuppression,
     next_head: usize,
+    // Whether the packed ring has been gone round an odd number of times,
+    // which flips the wrap counter
+    wrapped: bool,
+}
+
+impl DriverState {
+    // Puts the chain of (address, length, flags) in the descriptor table
+    // from the next free descriptor and makes it available
+    fn push_split(&mut self, chain: &[(u64, u32, u16)]) {
+        let head = self.next_head;
+        for (i, (addr, length, flags)) in chain.iter().enumerate() {
+            let index = (head + i) % QUEUE_SIZE;
+            let next = (index + 1) % QUEUE_SIZE;
+            let d = &mut self.descriptors[index];
+            d.addr = *addr;
+            d.length = *length;
+            d.flags = *flags;
+            if i + 1 < chain.len() {
+                d.flags |= VIRTQ_DESC_F_NEXT;
+                d.next = next as u16;
+            } else {
+                d.next = 0;
+            }
+        }
+
+        // Update ring to point to head of chain. Fence. Then update idx
+        let avail_index = self.avail.idx;
+        self.avail.ring[(avail_index % QUEUE_SIZE as u16) as usize] = head as u16;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        self.avail.idx = avail_index.wrapping_add(1);
+
+        self.next_head = (head + chain.len()) % QUEUE_SIZE;
+    }
+
+    fn wait_split(&self) {
+        while unsafe { core::ptr::read_volatile(&self.used.idx) } != self.avail.idx {
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+            crate::watchdog::check();
+            crate::delay::relax();
+        }
+    }
+
+    // As push_split, returning where the chain starts and the wrap counter
+    // there, which is where the device says it is done with it
+    fn push_packed(&mut self, chain: &[(u64, u32, u16)]) -> (usize, bool) {
+        let head = (self.next_head, !self.wrapped);
+        let mut head_flags = 0;
+        for (i, (addr, length, flags)) in chain.iter().enumerate() {
+            let mut flags = *flags;
+            if i + 1 < chain.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            flags |= if self.wrapped {
+                VIRTQ_DESC_F_USED
+            } else {
+                VIRTQ_DESC_F_AVAIL
+            };
+            let d = &mut self.packed[self.next_head];
+            d.addr = *addr;
+            d.length = *length;
+            d.id = 0;
+            // The device can start on the chain as soon as the first
+            // descriptor is available, so that is done last
+            if i == 0 {
+                head_flags = flags;
+            } else {
+                d.flags = flags;
+            }
+            self.next_head += 1;
+            if self.next_head == QUEUE_SIZE {
+                self.next_head = 0;
+                self.wrapped = !self.wrapped;
+            }
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        unsafe { core::ptr::write_volatile(&mut self.packed[head.0].flags, head_flags) };
+        head
+    }
+
+    fn wait_packed(&self, (head, wrap_counter): (usize, bool)) {
+        let both = VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED;
+        let used = if wrap_counter { both } else { 0 };
+        while unsafe { core::ptr::read_volatile(&self.packed[head].flags) } & both != used {
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+            crate::watchdog::check();
+            crate::delay::relax();
+        }
+    }
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Error {
     BlockIOError,
 
     BlockNotSupported,
+
+    // A write to a device that says it is read-only, which isn't sent to it
+    BlockReadOnly,
 }
 
 #[repr(C)]
@@ -95,10 +213,42 @@ struct BlockRequestFooter {
     status: u8,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// A range of sectors for a discard request
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
 pub trait SectorRead {
     /// Read a single sector (512 bytes) from the block device. `data` must be
     /// exactly 512 bytes long.
     fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error>;
+
+    /// Read consecutive sectors starting at `start_sector`. `data` must be a
+    /// multiple of 512 bytes long.
+    fn read_multi(&self, start_sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        assert_eq!(data.len() % 512, 0);
+        for (i, chunk) in data.chunks_exact_mut(512).enumerate() {
+            self.read(start_sector + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// The logical block size of the device in bytes, which is what on-disk
+    /// structures like partition tables count in. Sectors are still 512
+    /// bytes, with a block being a whole number of them.
+    fn block_size(&self) -> u32 {
+        512
+    }
+
+    /// The number of sectors on the device, if it's known, for finding what
+    /// is kept at its end
+    fn capacity(&self) -> Option<u64> {
+        None
+    }
 }
 
 pub trait SectorWrite {
@@ -106,6 +256,25 @@ pub trait SectorWrite {
     /// exactly 512 bytes long.
     fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error>;
     fn flush(&self) -> Result<(), Error>;
+
+    /// Tells the device that `count` sectors from `start_sector` are no
+    /// longer needed. The device may drop as little or as much of what they
+    /// held as it likes, so they must be written before being read again.
+    fn discard(&self, _start_sector: u64, _count: u64) -> Result<(), Error> {
+        Err(Error::BlockNotSupported)
+    }
+}
+
+/// A whole disk, as booted from and handed to EFI applications
+pub trait BlockDevice: SectorRead + SectorWrite {
+    /// Number of sectors that this device holds
+    fn get_capacity(&self) -> u64;
+
+    /// Whether the device refuses writes
+    fn is_read_only(&self) -> bool;
+
+    /// Number of requests submitted to the device
+    fn request_count(&self) -> u64;
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -113,6 +282,7 @@ enum RequestType {
     Read = 0,
     Write = 1,
     Flush = 4,
+    Discard = 11,
 }
 
 impl<'a> VirtioBlockDevice<'a> {
@@ -120,23 +290,49 @@ impl<'a> VirtioBlockDevice<'a> {
         VirtioBlockDevice {
             transport,
             state: RefCell::new(DriverState::default()),
+            features: 0,
+            read_only: false,
+            requests: Cell::new(0),
+            block_size: 512,
+            bounce: RefCell::new([0; MAX_BLOCK_SIZE]),
+            max_discard_sectors: 0,
+            max_discard_segments: 0,
+            discard_alignment: 1,
         }
     }
 
-    pub fn init(&mut self) -> Result<(), VirtioError> {
-        const VIRTIO_SUBSYSTEM_BLOCK: u32 = 0x2;
+    // The first sector of the block holding the sector, and how far into
+    // the block the sector is in bytes
+    fn block_of(&self, sector: u64) -> (u64, usize) {
+        let sectors_per_block = u64::from(self.block_size / 512);
+        let offset = sector % sectors_per_block;
+        (sector - offset, offset as usize * 512)
+    }
+
+    // Offers the features and reports whether the device accepts them. A
+    // legacy device has no say.
+    fn negotiate(&self, features: u64) -> bool {
+        const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+
+        self.transport.set_features(features);
+        if self.transport.legacy() {
+            return true;
+        }
+        self.transport.add_status(VIRTIO_STATUS_FEATURES_OK);
+        self.transport.get_status() & VIRTIO_STATUS_FEATURES_OK == VIRTIO_STATUS_FEATURES_OK
+    }
+
+    // Resets the device and agrees on the features to use with it
+    fn handshake(&mut self) -> Result<(), VirtioError> {
         const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+        const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+        const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
 
         const VIRTIO_STATUS_RESET: u32 = 0;
         const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
         const VIRTIO_STATUS_DRIVER: u32 = 2;
-        const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
-        const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
         const VIRTIO_STATUS_FAILED: u32 = 128;
 
-        // Initialise the transport
-        self.transport.init(VIRTIO_SUBSYSTEM_BLOCK)?;
-
         // Reset device
         self.transport.set_status(VIRTIO_STATUS_RESET);
 
@@ -149,22 +345,81 @@ impl<'a> VirtioBlockDevice<'a> {
         // Request device features
         let device_features = self.transport.get_features();
 
-        if device_features & VIRTIO_F_VERSION_1 != VIRTIO_F_VERSION_1 {
+        if device_features & VIRTIO_F_VERSION_1 != VIRTIO_F_VERSION_1 && !self.transport.legacy() {
             self.transport.add_status(VIRTIO_STATUS_FAILED);
             return Err(VirtioError::VirtioLegacyOnly);
         }
 
-        // Don't support any advanced features for now
-        let supported_features = VIRTIO_F_VERSION_1;
+        // Writes fail on a read-only device whether or not we accept the
+        // feature, so just note it and refuse them ourselves
+        self.read_only = device_features & VIRTIO_BLK_F_RO != 0;
 
-        // Report driver features
-        self.transport
-            .set_features(device_features & supported_features);
+        let supported_features =
+            VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_DISCARD;
 
-        self.transport.add_status(VIRTIO_STATUS_FEATURES_OK);
-        if self.transport.get_status() & VIRTIO_STATUS_FEATURES_OK != VIRTIO_STATUS_FEATURES_OK {
-            self.transport.add_status(VIRTIO_STATUS_FAILED);
-            return Err(VirtioError::VirtioFeatureNegotiationFailed);
+        // Report driver features, only those the device also offers
+        self.features = device_features & supported_features;
+
+        // The split ring is used unless the device turns down the features
+        // without the packed ring, leaving that as the only option. Features
+        // can only be offered again after starting over.
+        if !self.negotiate(self.features) {
+            if device_features & VIRTIO_F_RING_PACKED == 0 {
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(VirtioError::VirtioFeatureNegotiationFailed);
+            }
+            self.transport.set_status(VIRTIO_STATUS_RESET);
+            self.transport.add_status(VIRTIO_STATUS_ACKNOWLEDGE);
+            self.transport.add_status(VIRTIO_STATUS_DRIVER);
+            self.features |= VIRTIO_F_RING_PACKED;
+            if !self.negotiate(self.features) {
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(VirtioError::VirtioFeatureNegotiationFailed);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn init(&mut self) -> Result<(), VirtioError> {
+        const VIRTIO_SUBSYSTEM_BLOCK: u32 = 0x2;
+        const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+
+        const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        // Initialise the transport
+        self.transport.init(VIRTIO_SUBSYSTEM_BLOCK)?;
+
+        // The device may not be ready to agree straight away
+        virtio::retry_handshake(|| self.handshake())?;
+        if self.read_only {
+            log!("Block device is read-only");
+        }
+
+        // Requests are still in 512 byte sectors, but have to cover whole
+        // blocks
+        if self.features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+            let block_size = self.transport.read_device_config(20);
+            if !block_size.is_power_of_two()
+                || block_size < 512
+                || block_size as usize > MAX_BLOCK_SIZE
+            {
+                log!("Unsupported block size: {}", block_size);
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(VirtioError::VirtioUnsupportedDevice);
+            }
+            self.block_size = block_size;
+        }
+
+        // Whole blocks are discarded even if the device would take less
+        if self.features & VIRTIO_BLK_F_DISCARD != 0 {
+            self.max_discard_sectors = self.transport.read_device_config(36);
+            self.max_discard_segments = core::cmp::min(
+                self.transport.read_device_config(40),
+                MAX_DISCARD_SEGMENTS as u32,
+            );
+            self.discard_alignment =
+                core::cmp::max(self.transport.read_device_config(44), self.block_size / 512);
         }
 
         // Program queues
@@ -179,19 +434,37 @@ impl<'a> VirtioBlockDevice<'a> {
         }
         self.transport.set_queue_size(QUEUE_SIZE as u16);
 
-        // Update all queue parts
-        let state = self.state.borrow_mut();
-        let addr = state.descriptors.as_ptr() as u64;
-        self.transport.set_descriptors_address(addr);
+        // Update all queue parts. A packed ring has the event suppression
+        // structures where the split ring has its avail and used rings.
+        let mut state = self.state.borrow_mut();
+        if self.features & VIRTIO_F_RING_PACKED != 0 {
+            // We poll for requests to be done
+            state.driver_event.flags = RING_EVENT_FLAGS_DISABLE;
 
-        let addr = (&state.avail as *const _) as u64;
-        self.transport.set_avail_ring(addr);
+            let addr = state.packed.as_ptr() as u64;
+            self.transport.set_descriptors_address(addr);
 
-        let addr = (&state.used as *const _) as u64;
-        self.transport.set_used_ring(addr);
+            let addr = (&state.driver_event as *const _) as u64;
+            self.transport.set_avail_ring(addr);
+
+            let addr = (&state.device_event as *const _) as u64;
+            self.transport.set_used_ring(addr);
+        } else {
+            let addr = state.descriptors.as_ptr() as u64;
+            self.transport.set_descriptors_address(addr);
+
+            let addr = (&state.avail as *const _) as u64;
+            self.transport.set_avail_ring(addr);
+
+            let addr = (&state.used as *const _) as u64;
+            self.transport.set_used_ring(addr);
+        }
 
         // Confirm queue
-        self.transport.set_queue_enable();
+        if let Err(e) = self.transport.set_queue_enable() {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(e);
+        }
 
         // Report driver ready
         self.transport.add_status(VIRTIO_STATUS_DRIVER_OK);
@@ -199,25 +472,17 @@ impl<'a> VirtioBlockDevice<'a> {
         Ok(())
     }
 
-    // Number of sectors that this device holds
-    pub fn get_capacity(&self) -> u64 {
-        u64::from(self.transport.read_device_config(0))
-            | u64::from(self.transport.read_device_config(4)) << 32
-    }
-
     fn request(
         &self,
         sector: u64,
         data: Option<&mut [u8]>,
         request: RequestType,
     ) -> Result<(), Error> {
-        if request != RequestType::Flush {
-            assert_eq!(512, data.as_ref().unwrap().len());
+        if request == RequestType::Read || request == RequestType::Write {
+            let len = data.as_ref().unwrap().len();
+            assert!(len > 0 && len % 512 == 0 && len <= MAX_REQUEST_SECTORS * 512);
         }
 
-        const VIRTQ_DESC_F_NEXT: u16 = 1;
-        const VIRTQ_DESC_F_WRITE: u16 = 2;
-
         const VIRTIO_BLK_S_OK: u8 = 0;
         const VIRTIO_BLK_S_IOERR: u8 = 1;
         const VIRTIO_BLK_S_UNSUPP: u8 = 2;
@@ -230,76 +495,1152 @@ impl<'a> VirtioBlockDevice<'a> {
 
         let footer = BlockRequestFooter { status: 0 };
 
-        let mut state = self.state.borrow_mut();
-
-        let next_head = state.next_head;
-        let mut d = &mut state.descriptors[next_head];
-        let next_desc = (next_head + 1) % QUEUE_SIZE;
-        d.addr = (&header as *const _) as u64;
-        d.length = core::mem::size_of::<BlockRequestHeader>() as u32;
-        d.flags = VIRTQ_DESC_F_NEXT;
-        d.next = next_desc as u16;
-
-        let mut d = &mut state.descriptors[next_desc];
-        let next_desc = (next_desc + 1) % QUEUE_SIZE;
-        if request != RequestType::Flush {
-            d.addr = data.unwrap().as_ptr() as u64;
-            d.length = core::mem::size_of::<[u8; 512]>() as u32;
-        }
-
-        d.flags = VIRTQ_DESC_F_NEXT
-            | if request == RequestType::Read {
+        // The (address, length, flags) of the descriptors for the request
+        let header_desc = (
+            (&header as *const _) as u64,
+            core::mem::size_of::<BlockRequestHeader>() as u32,
+            0,
+        );
+        let footer_desc = (
+            (&footer as *const _) as u64,
+            core::mem::size_of::<BlockRequestFooter>() as u32,
+            VIRTQ_DESC_F_WRITE,
+        );
+        let mut chain = [header_desc, footer_desc, footer_desc];
+        // Flush requests have no data so go straight to the footer
+        let chain = if request == RequestType::Flush {
+            &chain[..2]
+        } else {
+            let data = data.unwrap();
+            let flags = if request == RequestType::Read {
                 VIRTQ_DESC_F_WRITE
             } else {
                 0
             };
-        d.next = next_desc as u16;
-
-        let mut d = &mut state.descriptors[next_desc];
-        d.addr = (&footer as *const _) as u64;
-        d.length = core::mem::size_of::<BlockRequestFooter>() as u32;
-        d.flags = VIRTQ_DESC_F_WRITE;
-        d.next = 0;
-
-        // Update ring to point to head of chain. Fence. Then update idx
-        let avail_index = state.avail.idx;
-        state.avail.ring[(avail_index % QUEUE_SIZE as u16) as usize] = state.next_head as u16;
-        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
-
-        state.avail.idx = state.avail.idx.wrapping_add(1);
+            chain[1] = (data.as_ptr() as u64, data.len() as u32, flags);
+            &chain[..]
+        };
 
-        // Next free descriptor to use
-        state.next_head = (next_desc + 1) % QUEUE_SIZE;
+        let mut state = self.state.borrow_mut();
+        let packed = self.features & VIRTIO_F_RING_PACKED != 0;
+        // Only one request is ever in flight, so the device is always idle
+        // and waiting to be notified of the next
+        let head = if packed {
+            Some(state.push_packed(chain))
+        } else {
+            state.push_split(chain);
+            None
+        };
 
         // Notify queue has been updated
         self.transport.notify_queue(0);
+        self.requests.set(self.requests.get() + 1);
 
         // Check for the completion of the request
-        while unsafe { core::ptr::read_volatile(&state.used.idx) } != state.avail.idx {
-            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        match head {
+            Some(head) => state.wait_packed(head),
+            None => state.wait_split(),
         }
 
-        match footer.status {
+        // The device writes the status behind the compiler's back
+        match unsafe { core::ptr::read_volatile(&footer.status) } {
             VIRTIO_BLK_S_OK => Ok(()),
             VIRTIO_BLK_S_IOERR => Err(Error::BlockIOError),
             VIRTIO_BLK_S_UNSUPP => Err(Error::BlockNotSupported),
-            _ => Err(Error::BlockNotSupported),
+            _ => Err(Error::BlockIOError),
         }
     }
 }
 
+impl<'a> BlockDevice for VirtioBlockDevice<'a> {
+    fn get_capacity(&self) -> u64 {
+        u64::from(self.transport.read_device_config(0))
+            | u64::from(self.transport.read_device_config(4)) << 32
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Number of requests submitted to the virtqueue
+    fn request_count(&self) -> u64 {
+        self.requests.get()
+    }
+}
+
 impl<'a> SectorRead for VirtioBlockDevice<'a> {
     fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
-        self.request(sector, Some(data), RequestType::Read)
+        assert_eq!(data.len(), 512);
+        if self.block_size == 512 {
+            return self.request(sector, Some(data), RequestType::Read);
+        }
+        let (block, offset) = self.block_of(sector);
+        let mut bounce = self.bounce.borrow_mut();
+        let bounce = &mut bounce[..self.block_size as usize];
+        self.request(block, Some(bounce), RequestType::Read)?;
+        data.copy_from_slice(&bounce[offset..offset + 512]);
+        Ok(())
+    }
+
+    fn read_multi(&self, start_sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        assert_eq!(data.len() % 512, 0);
+
+        // Only whole blocks can be read straight into data, the sectors
+        // either side of them go through the bounce buffer
+        let block_size = self.block_size as usize;
+        let head = match self.block_of(start_sector) {
+            (_, 0) => 0,
+            (_, offset) => core::cmp::min(block_size - offset, data.len()),
+        };
+        let (head, rest) = data.split_at_mut(head);
+        let whole = rest.len() - rest.len() % block_size;
+        let (whole, tail) = rest.split_at_mut(whole);
+
+        let mut sector = start_sector;
+        for chunk in head.chunks_exact_mut(512) {
+            self.read(sector, chunk)?;
+            sector += 1;
+        }
+        for chunk in whole.chunks_mut(MAX_REQUEST_SECTORS * 512) {
+            self.request(sector, Some(chunk), RequestType::Read)?;
+            sector += (chunk.len() / 512) as u64;
+        }
+        for chunk in tail.chunks_exact_mut(512) {
+            self.read(sector, chunk)?;
+            sector += 1;
+        }
+        Ok(())
+    }
+
+    fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        Some(self.get_capacity())
     }
 }
 
 impl<'a> SectorWrite for VirtioBlockDevice<'a> {
     fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
-        self.request(sector, Some(data), RequestType::Write)
+        assert_eq!(data.len(), 512);
+        if self.read_only {
+            return Err(Error::BlockReadOnly);
+        }
+        if self.block_size == 512 {
+            return self.request(sector, Some(data), RequestType::Write);
+        }
+        // The rest of the block has to be written back as it was
+        let (block, offset) = self.block_of(sector);
+        let mut bounce = self.bounce.borrow_mut();
+        let bounce = &mut bounce[..self.block_size as usize];
+        self.request(block, Some(bounce), RequestType::Read)?;
+        bounce[offset..offset + 512].copy_from_slice(data);
+        self.request(block, Some(bounce), RequestType::Write)
     }
 
     fn flush(&self) -> Result<(), Error> {
+        if self.features & VIRTIO_BLK_F_FLUSH != VIRTIO_BLK_F_FLUSH {
+            log!("Block device does not support flush");
+            return Err(Error::BlockNotSupported);
+        }
         self.request(0, None, RequestType::Flush)
     }
+
+    // Only the aligned part of the range is discarded, in as few requests
+    // as the device's limits allow
+    fn discard(&self, start_sector: u64, count: u64) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::BlockReadOnly);
+        }
+        if self.features & VIRTIO_BLK_F_DISCARD == 0
+            || self.max_discard_sectors == 0
+            || self.max_discard_segments == 0
+        {
+            log!("Block device does not support discard");
+            return Err(Error::BlockNotSupported);
+        }
+
+        let alignment = u64::from(self.discard_alignment);
+        let end = (start_sector + count) / alignment * alignment;
+        let mut sector = (start_sector + alignment - 1) / alignment * alignment;
+        // Segments are kept to a multiple of the alignment too
+        let max_sectors = match u64::from(self.max_discard_sectors) / alignment * alignment {
+            0 => return Err(Error::BlockNotSupported),
+            max_sectors => max_sectors,
+        };
+
+        while sector < end {
+            let mut segments = [DiscardSegment::default(); MAX_DISCARD_SEGMENTS];
+            let mut used = 0;
+            while used < self.max_discard_segments as usize && sector < end {
+                let num_sectors = core::cmp::min(end - sector, max_sectors);
+                segments[used] = DiscardSegment {
+                    sector,
+                    num_sectors: num_sectors as u32,
+                    flags: 0,
+                };
+                sector += num_sectors;
+                used += 1;
+            }
+            // The device only reads the segments
+            let data = unsafe {
+                core::slice::from_raw_parts_mut(
+                    segments.as_mut_ptr() as *mut u8,
+                    used * core::mem::size_of::<DiscardSegment>(),
+                )
+            };
+            self.request(0, Some(data), RequestType::Discard)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CacheEntry {
+    sector: Option<u64>,
+    last_used: u64,
+    data: [u8; 512],
+}
+
+/// Keeps the most recently read sectors of a block device in memory
+pub struct CachedBlock<'a, T: SectorRead + ?Sized> {
+    device: &'a T,
+    entries: RefCell<[CacheEntry; CACHE_SIZE]>,
+    clock: Cell<u64>,
+    hits: Cell<u64>,
+}
+
+impl<'a, T: SectorRead + ?Sized> CachedBlock<'a, T> {
+    pub fn new(device: &'a T) -> CachedBlock<'a, T> {
+        CachedBlock {
+            device,
+            entries: RefCell::new(
+                [CacheEntry {
+                    sector: None,
+                    last_used: 0,
+                    data: [0; 512],
+                }; CACHE_SIZE],
+            ),
+            clock: Cell::new(0),
+            hits: Cell::new(0),
+        }
+    }
+
+    pub fn device(&self) -> &T {
+        self.device
+    }
+
+    // Number of reads that were served without going to the device, which
+    // is only logged
+    #[cfg_attr(not(any(feature = "log-serial", test)), allow(dead_code))]
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    fn invalidate(&self, sector: u64) {
+        for e in self.entries.borrow_mut().iter_mut() {
+            if e.sector == Some(sector) {
+                e.sector = None;
+            }
+        }
+    }
+}
+
+impl<'a, T: SectorRead + ?Sized> SectorRead for CachedBlock<'a, T> {
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        assert_eq!(data.len(), 512);
+
+        let clock = self.clock.get() + 1;
+        self.clock.set(clock);
+
+        let mut entries = self.entries.borrow_mut();
+        if let Some(e) = entries.iter_mut().find(|e| e.sector == Some(sector)) {
+            e.last_used = clock;
+            data.copy_from_slice(&e.data);
+            self.hits.set(self.hits.get() + 1);
+            return Ok(());
+        }
+
+        self.device.read(sector, data)?;
+
+        // Replace an empty entry or else the least recently used
+        let e = entries
+            .iter_mut()
+            .min_by_key(|e| (e.sector.is_some(), e.last_used))
+            .unwrap();
+        e.sector = Some(sector);
+        e.last_used = clock;
+        e.data.copy_from_slice(data);
+        Ok(())
+    }
+
+    // Bulk reads go straight to the device, the cache is write-through so
+    // it can't hold anything newer.
+    fn read_multi(&self, start_sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        self.device.read_multi(start_sector, data)
+    }
+
+    fn block_size(&self) -> u32 {
+        self.device.block_size()
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        self.device.capacity()
+    }
+}
+
+impl<'a, T: SectorRead + SectorWrite + ?Sized> SectorWrite for CachedBlock<'a, T> {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), Error> {
+        self.invalidate(sector);
+        self.device.write(sector, data)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.device.flush()
+    }
+
+    fn discard(&self, start_sector: u64, count: u64) -> Result<(), Error> {
+        for e in self.entries.borrow_mut().iter_mut() {
+            if matches!(e.sector, Some(s) if s >= start_sector && s - start_sector < count) {
+                e.sector = None;
+            }
+        }
+        self.device.discard(start_sector, count)
+    }
+}
+
+/// Where a disk is, so that it can be set up each time it is used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    /// A virtio-blk PCI function, by bus, device and function number
+    VirtioPci(u8, u8, u8),
+    /// An NVMe controller's PCI function
+    #[cfg(feature = "nvme")]
+    Nvme(u8, u8, u8),
+    /// A virtio-mmio transport at this address
+    VirtioMmio(u64),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::VirtioPci(bus, device, func) => {
+                write!(f, "virtio-blk at {}:{}.{}", bus, device, func)
+            }
+            #[cfg(feature = "nvme")]
+            Location::Nvme(bus, device, func) => write!(f, "NVMe at {}:{}.{}", bus, device, func),
+            Location::VirtioMmio(address) => write!(f, "virtio-mmio at {:#x}", address),
+        }
+    }
+}
+
+/// The disks found, numbered in the order they were found in
+pub struct Disks {
+    locations: [Location; MAX_DISKS],
+    count: usize,
+}
+
+impl Disks {
+    fn add(&mut self, location: Location) {
+        if self.count == MAX_DISKS {
+            log!("Too many disks, ignoring {}", location);
+            return;
+        }
+        self.locations[self.count] = location;
+        self.count += 1;
+    }
+
+    pub fn as_slice(&self) -> &[Location] {
+        &self.locations[..self.count]
+    }
+}
+
+/// Every disk there is to boot from: the virtio-blk PCI devices, then the
+/// NVMe controllers and then the virtio-mmio devices, each in the order they
+/// are on the PCI buses or in memory
+pub fn enumerate() -> Disks {
+    let mut disks = Disks {
+        locations: [Location::VirtioMmio(0); MAX_DISKS],
+        count: 0,
+    };
+    for device_id in &[
+        VIRTIO_PCI_BLOCK_DEVICE_ID,
+        VIRTIO_PCI_TRANSITIONAL_BLOCK_DEVICE_ID,
+    ] {
+        pci::scan(|bus, device, func| {
+            if pci::get_device_details(bus, device, func) == (VIRTIO_PCI_VENDOR_ID, *device_id) {
+                disks.add(Location::VirtioPci(bus, device, func));
+            }
+            false
+        });
+    }
+    #[cfg(feature = "nvme")]
+    pci::scan(|bus, device, func| {
+        if pci::get_class_details(bus, device, func) == (PCI_CLASS_MASS_STORAGE, PCI_SUBCLASS_NVM) {
+            disks.add(Location::Nvme(bus, device, func));
+        }
+        false
+    });
+    mmio::find_devices(
+        VIRTIO_MMIO_BASE,
+        VIRTIO_MMIO_COUNT,
+        VIRTIO_MMIO_BLOCK_DEVICE_ID,
+        |address| disks.add(Location::VirtioMmio(address)),
+    );
+    disks
+}
+
+/// The disk number in the last valid rhfw.boot_disk option
+pub fn boot_disk(cmdline: &[u8]) -> Option<usize> {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .filter_map(|arg| arg.strip_prefix(BOOT_DISK_OPTION))
+        .filter_map(|value| core::str::from_utf8(value).ok()?.parse().ok())
+        .last()
+}
+
+/// The numbers of the disks in the order they are booted from: the one
+/// rhfw.boot_disk picks, then those with an EFI System partition and then
+/// the rest, which could still have a kernel on another partition. Other than
+/// the one picked, disks are tried in the order they were found in, so that
+/// a disk without an ESP, such as a cloud-init one, never goes before one
+/// with.
+pub fn boot_order(has_esp: &[bool], preferred: Option<usize>) -> impl Iterator<Item = usize> + '_ {
+    let preferred = preferred.filter(|p| *p < has_esp.len());
+    let others = move |esp: bool| {
+        (0..has_esp.len()).filter(move |i| Some(*i) != preferred && has_esp[*i] == esp)
+    };
+    preferred
+        .into_iter()
+        .chain(others(true))
+        .chain(others(false))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::{
+        boot_disk, boot_order, AvailRing, BlockDevice, BlockRequestFooter, BlockRequestHeader,
+        CachedBlock, Desc, DiscardSegment, DriverState, Error, EventSuppression, PackedDesc,
+        SectorRead, SectorWrite, UsedRing, VirtioBlockDevice, VIRTIO_BLK_F_DISCARD,
+        VIRTIO_BLK_F_FLUSH, VIRTIO_F_RING_PACKED, VIRTQ_DESC_F_AVAIL, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_USED,
+    };
+    use crate::virtio::{Error as VirtioError, VirtioTransport, QUEUE_SIZE};
+
+    /// Emulates a virtio block device backed by memory, processing requests
+    /// synchronously when the queue is notified.
+    pub struct FakeTransport {
+        pub disk: RefCell<Vec<u8>>,
+        pub device_features: u64,
+        pub driver_features: Cell<u64>,
+        pub read_only: bool,
+        pub requests: Cell<usize>,
+        pub flushes: Cell<usize>,
+        // Refuses the features unless they include the packed ring
+        pub packed_only: bool,
+        // A legacy device
+        pub legacy: bool,
+        // How many more handshakes to refuse the features in, as a device
+        // that isn't ready yet might
+        pub refused_handshakes: Cell<u32>,
+        // Requests not covering whole blocks fail
+        pub block_size: u32,
+        pub max_discard_sectors: u32,
+        pub max_discard_segments: u32,
+        pub discard_alignment: u32,
+        // The (sector, count) segments of each discard request
+        pub discards: RefCell<Vec<Vec<(u64, u32)>>>,
+        status: Cell<u32>,
+        queue_size: Cell<u16>,
+        descriptors: Cell<u64>,
+        avail: Cell<u64>,
+        used: Cell<u64>,
+        last_avail: Cell<u16>,
+        wrapped: Cell<bool>,
+    }
+
+    impl FakeTransport {
+        pub fn new(sectors: usize) -> FakeTransport {
+            const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+            FakeTransport {
+                disk: RefCell::new(vec![0; sectors * 512]),
+                device_features: VIRTIO_F_VERSION_1,
+                driver_features: Cell::new(0),
+                read_only: false,
+                requests: Cell::new(0),
+                flushes: Cell::new(0),
+                packed_only: false,
+                legacy: false,
+                refused_handshakes: Cell::new(0),
+                block_size: 512,
+                max_discard_sectors: 0,
+                max_discard_segments: 0,
+                discard_alignment: 0,
+                discards: RefCell::new(Vec::new()),
+                status: Cell::new(0),
+                queue_size: Cell::new(0),
+                descriptors: Cell::new(0),
+                avail: Cell::new(0),
+                used: Cell::new(0),
+                last_avail: Cell::new(0),
+                wrapped: Cell::new(false),
+            }
+        }
+
+        fn is_packed(&self) -> bool {
+            self.driver_features.get() & VIRTIO_F_RING_PACKED != 0
+        }
+
+        unsafe fn process_split(&self) {
+            let size = self.queue_size.get();
+            let avail = &*(self.avail.get() as *const AvailRing);
+            let used = &mut *(self.used.get() as *mut UsedRing);
+            let descriptors = self.descriptors.get() as *const Desc;
+            while self.last_avail.get() != avail.idx {
+                let slot = (self.last_avail.get() % size) as usize;
+                let head = avail.ring[slot];
+                let mut chain = Vec::new();
+                let mut index = head;
+                loop {
+                    let d = &*descriptors.add(index as usize);
+                    chain.push((d.addr, d.length));
+                    if d.flags & VIRTQ_DESC_F_NEXT == 0 {
+                        break;
+                    }
+                    index = d.next;
+                }
+                self.process(&chain);
+
+                used.ring[(used.idx % size) as usize].id = u32::from(head);
+                used.ring[(used.idx % size) as usize].len = 0;
+                used.idx = used.idx.wrapping_add(1);
+                self.last_avail.set(self.last_avail.get().wrapping_add(1));
+            }
+        }
+
+        // The used descriptor for each chain goes where the chain started,
+        // as they are all processed in order
+        unsafe fn process_packed(&self) {
+            let size = self.queue_size.get();
+            let descriptors = self.descriptors.get() as *mut PackedDesc;
+            loop {
+                let head = self.last_avail.get();
+                let wrap_counter = !self.wrapped.get();
+                let flags = (*descriptors.add(head as usize)).flags;
+                if (flags & VIRTQ_DESC_F_AVAIL != 0) != wrap_counter
+                    || (flags & VIRTQ_DESC_F_USED != 0) == wrap_counter
+                {
+                    return;
+                }
+                let mut chain = Vec::new();
+                loop {
+                    let index = self.last_avail.get();
+                    let d = &*descriptors.add(index as usize);
+                    chain.push((d.addr, d.length));
+                    self.last_avail.set((index + 1) % size);
+                    if index + 1 == size {
+                        self.wrapped.set(!self.wrapped.get());
+                    }
+                    if d.flags & VIRTQ_DESC_F_NEXT == 0 {
+                        break;
+                    }
+                }
+                self.process(&chain);
+
+                let d = &mut *descriptors.add(head as usize);
+                d.length = 0;
+                d.flags = if wrap_counter {
+                    VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+                } else {
+                    0
+                };
+            }
+        }
+
+        unsafe fn process(&self, chain: &[(u64, u32)]) {
+            const VIRTIO_BLK_S_OK: u8 = 0;
+            const VIRTIO_BLK_S_IOERR: u8 = 1;
+            const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+            self.requests.set(self.requests.get() + 1);
+            let header = &*(chain[0].0 as *const BlockRequestHeader);
+            let footer = chain[chain.len() - 1].0 as *mut BlockRequestFooter;
+            let data = &chain[1..chain.len() - 1];
+
+            let mut disk = self.disk.borrow_mut();
+            let mut offset = header.sector as usize * 512;
+            let status = match header.request {
+                0 | 1 => {
+                    let len: usize = data.iter().map(|(_, l)| *l as usize).sum();
+                    let block_size = self.block_size as usize;
+                    if offset + len > disk.len()
+                        || (header.request == 1 && self.read_only)
+                        || offset % block_size != 0
+                        || len % block_size != 0
+                    {
+                        VIRTIO_BLK_S_IOERR
+                    } else {
+                        for (addr, len) in data {
+                            let buf =
+                                std::slice::from_raw_parts_mut(*addr as *mut u8, *len as usize);
+                            let sectors = &mut disk[offset..offset + *len as usize];
+                            if header.request == 0 {
+                                buf.copy_from_slice(sectors);
+                            } else {
+                                sectors.copy_from_slice(buf);
+                            }
+                            offset += *len as usize;
+                        }
+                        VIRTIO_BLK_S_OK
+                    }
+                }
+                4 if self.driver_features.get() & VIRTIO_BLK_F_FLUSH != 0 => {
+                    assert!(data.is_empty());
+                    self.flushes.set(self.flushes.get() + 1);
+                    VIRTIO_BLK_S_OK
+                }
+                // Discarded sectors read back as zeroes
+                11 if self.driver_features.get() & VIRTIO_BLK_F_DISCARD != 0 => {
+                    let (addr, len) = data[0];
+                    let segments = std::slice::from_raw_parts(
+                        addr as *const DiscardSegment,
+                        len as usize / std::mem::size_of::<DiscardSegment>(),
+                    );
+                    let segments: Vec<(u64, u32)> =
+                        segments.iter().map(|s| (s.sector, s.num_sectors)).collect();
+                    let valid = segments.len() <= self.max_discard_segments as usize
+                        && segments.iter().all(|(sector, count)| {
+                            *count <= self.max_discard_sectors
+                                && (sector + u64::from(*count)) * 512 <= disk.len() as u64
+                        });
+                    if valid {
+                        for (sector, count) in segments.iter() {
+                            let start = *sector as usize * 512;
+                            disk[start..start + *count as usize * 512].fill(0);
+                        }
+                        self.discards.borrow_mut().push(segments);
+                        VIRTIO_BLK_S_OK
+                    } else {
+                        VIRTIO_BLK_S_IOERR
+                    }
+                }
+                _ => VIRTIO_BLK_S_UNSUPP,
+            };
+            (*footer).status = status;
+        }
+    }
+
+    impl VirtioTransport for FakeTransport {
+        fn init(&mut self, _device_type: u32) -> Result<(), VirtioError> {
+            Ok(())
+        }
+        fn legacy(&self) -> bool {
+            self.legacy
+        }
+        fn get_status(&self) -> u32 {
+            self.status.get()
+        }
+        fn set_status(&self, status: u32) {
+            self.status.set(status)
+        }
+        fn add_status(&self, status: u32) {
+            const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+            let refused =
+                status & VIRTIO_STATUS_FEATURES_OK != 0 && self.refused_handshakes.get() > 0;
+            if refused {
+                self.refused_handshakes
+                    .set(self.refused_handshakes.get() - 1);
+            }
+            let status = if refused || (self.packed_only && !self.is_packed()) {
+                status & !VIRTIO_STATUS_FEATURES_OK
+            } else {
+                status
+            };
+            self.status.set(self.status.get() | status)
+        }
+        fn reset(&self) {
+            self.status.set(0)
+        }
+        fn get_features(&self) -> u64 {
+            self.device_features
+        }
+        fn set_features(&self, features: u64) {
+            self.driver_features.set(features)
+        }
+        fn set_queue(&self, _queue: u16) {}
+        fn get_queue_max_size(&self) -> u16 {
+            256
+        }
+        fn set_queue_size(&self, queue_size: u16) {
+            self.queue_size.set(queue_size)
+        }
+        fn set_descriptors_address(&self, address: u64) {
+            self.descriptors.set(address)
+        }
+        fn set_avail_ring(&self, address: u64) {
+            self.avail.set(address)
+        }
+        fn set_used_ring(&self, address: u64) {
+            self.used.set(address)
+        }
+        fn set_queue_enable(&self) -> Result<(), VirtioError> {
+            Ok(())
+        }
+        fn notify_queue(&self, _queue: u16) {
+            if self.is_packed() {
+                unsafe { self.process_packed() }
+            } else {
+                unsafe { self.process_split() }
+            }
+        }
+        fn read_device_config(&self, offset: u64) -> u32 {
+            let capacity = self.disk.borrow().len() as u64 / 512;
+            match offset {
+                0 => capacity as u32,
+                4 => (capacity >> 32) as u32,
+                20 => self.block_size,
+                36 => self.max_discard_sectors,
+                40 => self.max_discard_segments,
+                44 => self.discard_alignment,
+                _ => 0,
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_read_round_trip() {
+        let mut transport = FakeTransport::new(64);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_eq!(device.get_capacity(), 64);
+
+        // Enough requests to wrap around the queue several times
+        for sector in 0..64 {
+            let mut data = [sector as u8; 512];
+            data[0] = 0xaa;
+            device.write(sector, &mut data).unwrap();
+        }
+        for sector in (0..64).rev() {
+            let mut data = [0; 512];
+            device.read(sector, &mut data).unwrap();
+            assert_eq!(data[0], 0xaa);
+            assert!(data[1..].iter().all(|b| *b == sector as u8));
+        }
+
+        drop(device);
+        assert_eq!(transport.disk.borrow()[5 * 512 + 1], 5);
+    }
+
+    #[test]
+    fn test_write_error_status() {
+        let mut transport = FakeTransport::new(8);
+        transport.read_only = true;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+
+        let mut data = [0x55; 512];
+        assert_eq!(device.write(0, &mut data), Err(Error::BlockIOError));
+        assert_eq!(device.read(8, &mut data), Err(Error::BlockIOError));
+        device.read(0, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_handshake_retry() {
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        // Turned down once, then accepted after starting over
+        let mut transport = FakeTransport::new(8);
+        transport.refused_handshakes.set(1);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        let mut data = [0; 512];
+        device.read(0, &mut data).unwrap();
+        drop(device);
+        assert_eq!(transport.refused_handshakes.get(), 0);
+        assert_eq!(transport.get_status() & VIRTIO_STATUS_FAILED, 0);
+
+        // A device that keeps turning them down is given up on
+        let mut transport = FakeTransport::new(8);
+        transport.refused_handshakes.set(u32::MAX);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(
+            device.init(),
+            Err(VirtioError::VirtioFeatureNegotiationFailed)
+        ));
+        drop(device);
+        assert_eq!(transport.refused_handshakes.get(), u32::MAX - 4);
+        assert_ne!(transport.get_status() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_read_only() {
+        const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+        let mut transport = FakeTransport::new(8);
+        transport.device_features |= VIRTIO_BLK_F_RO | VIRTIO_BLK_F_DISCARD;
+        transport.read_only = true;
+        transport.max_discard_sectors = 8;
+        transport.max_discard_segments = 1;
+        transport.discard_alignment = 1;
+        transport.disk.borrow_mut()[512..1024].fill(0xaa);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert!(device.is_read_only());
+
+        // Writes are refused without the device seeing them
+        let requests = device.request_count();
+        let mut data = [0x55; 512];
+        assert_eq!(device.write(1, &mut data), Err(Error::BlockReadOnly));
+        assert_eq!(device.discard(0, 8), Err(Error::BlockReadOnly));
+        assert_eq!(device.request_count(), requests);
+
+        device.read(1, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0xaa));
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut transport = FakeTransport::new(8);
+        transport.device_features |= VIRTIO_BLK_F_FLUSH;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+
+        let mut data = [0x55; 512];
+        device.write(1, &mut data).unwrap();
+        device.flush().unwrap();
+        device.read(1, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0x55));
+
+        drop(device);
+        assert_eq!(transport.flushes.get(), 1);
+        assert_ne!(transport.driver_features.get() & VIRTIO_BLK_F_FLUSH, 0);
+    }
+
+    #[test]
+    fn test_flush_unsupported() {
+        let mut transport = FakeTransport::new(8);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_eq!(device.flush(), Err(Error::BlockNotSupported));
+
+        drop(device);
+        assert_eq!(transport.requests.get(), 0);
+        assert_eq!(transport.driver_features.get() & VIRTIO_BLK_F_FLUSH, 0);
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut transport = FakeTransport::new(64);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        for sector in 0..64 {
+            device.write(sector, &mut [sector as u8; 512]).unwrap();
+        }
+        let cache = CachedBlock::new(&device);
+
+        let mut data = [0; 512];
+        for _ in 0..4 {
+            for sector in 0..16 {
+                cache.read(sector, &mut data).unwrap();
+                assert_eq!(data[0], sector as u8);
+            }
+        }
+        assert_eq!(cache.hits(), 48);
+
+        // Keep sector 0 recently used while streaming past the others
+        for sector in 16..64 {
+            cache.read(0, &mut data).unwrap();
+            cache.read(sector, &mut data).unwrap();
+        }
+        assert_eq!(cache.hits(), 96);
+        cache.read(1, &mut data).unwrap();
+        assert_eq!(cache.hits(), 96);
+
+        // Writes must not leave stale data behind
+        cache.write(0, &mut [0xff; 512]).unwrap();
+        cache.read(0, &mut data).unwrap();
+        assert_eq!(cache.hits(), 96);
+        assert!(data.iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn test_read_multi() {
+        let mut transport = FakeTransport::new(512);
+        for (i, b) in transport.disk.borrow_mut().iter_mut().enumerate() {
+            *b = (i / 512) as u8;
+        }
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+
+        let mut data = vec![0; 40 * 512];
+        device.read_multi(3, &mut data).unwrap();
+        assert_eq!(device.request_count(), 1);
+        for (i, chunk) in data.chunks(512).enumerate() {
+            assert!(chunk.iter().all(|b| *b == (i + 3) as u8));
+        }
+
+        // Split into requests the device can take
+        let mut data = vec![0; 300 * 512];
+        device.read_multi(100, &mut data).unwrap();
+        assert_eq!(device.request_count(), 4);
+        for (i, chunk) in data.chunks(512).enumerate() {
+            assert!(chunk.iter().all(|b| *b == (i + 100) as u8));
+        }
+
+        assert_eq!(
+            device.read_multi(500, &mut data[..20 * 512]),
+            Err(Error::BlockIOError)
+        );
+    }
+
+    #[test]
+    fn test_feature_negotiation() {
+        const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+        const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+        let mut transport = FakeTransport::new(8);
+        transport.device_features |= VIRTIO_BLK_F_FLUSH | VIRTIO_F_RING_PACKED | VIRTIO_BLK_F_RO;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+
+        assert!(device.is_read_only());
+        drop(device);
+        // Only the features both sides know about, across both feature words
+        assert_eq!(
+            transport.driver_features.get(),
+            VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH
+        );
+    }
+
+    #[test]
+    fn test_packed_ring() {
+        const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+        let mut transport = FakeTransport::new(64);
+        transport.device_features |= VIRTIO_BLK_F_FLUSH | VIRTIO_F_RING_PACKED;
+        transport.packed_only = true;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+
+        // Chains of three and two descriptors, which wrap around the ring
+        // part of the way through
+        for sector in 0..64 {
+            device.write(sector, &mut [sector as u8; 512]).unwrap();
+            if sector % 5 == 0 {
+                device.flush().unwrap();
+            }
+        }
+        for sector in (0..64).rev() {
+            let mut data = [0; 512];
+            device.read(sector, &mut data).unwrap();
+            assert!(data.iter().all(|b| *b == sector as u8));
+        }
+        let mut data = vec![0; 40 * 512];
+        device.read_multi(10, &mut data).unwrap();
+        assert_eq!(data[39 * 512], 49);
+        assert_eq!(device.read(64, &mut data[..512]), Err(Error::BlockIOError));
+
+        drop(device);
+        assert_eq!(transport.requests.get(), 64 + 13 + 64 + 2);
+        assert_eq!(
+            transport.driver_features.get(),
+            VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_FLUSH | VIRTIO_F_RING_PACKED
+        );
+
+        // A device that won't have either ring
+        let mut transport = FakeTransport::new(8);
+        transport.packed_only = true;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(
+            device.init(),
+            Err(VirtioError::VirtioFeatureNegotiationFailed)
+        ));
+    }
+
+    #[test]
+    fn test_legacy_device() {
+        const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+
+        // Used without VIRTIO_F_VERSION_1, on a legacy transport
+        let mut transport = FakeTransport::new(8);
+        transport.device_features = VIRTIO_BLK_F_FLUSH;
+        transport.legacy = true;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        device.write(3, &mut [0x55; 512]).unwrap();
+        let mut data = [0; 512];
+        device.read(3, &mut data).unwrap();
+        assert_eq!(data, [0x55; 512]);
+        drop(device);
+        assert_eq!(transport.driver_features.get(), VIRTIO_BLK_F_FLUSH);
+        // A legacy device has no FEATURES_OK
+        assert_eq!(transport.status.get() & VIRTIO_STATUS_FEATURES_OK, 0);
+    }
+
+    // A request is only ever made with the device waiting for it, so
+    // VIRTIO_RING_F_EVENT_IDX would never save a notification
+    #[test]
+    fn test_no_event_idx() {
+        const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+
+        for packed in [false, true].iter() {
+            let mut transport = FakeTransport::new(64);
+            transport.device_features |= VIRTIO_RING_F_EVENT_IDX;
+            if *packed {
+                transport.device_features |= VIRTIO_F_RING_PACKED;
+                transport.packed_only = true;
+            }
+            let mut device = VirtioBlockDevice::new(&mut transport);
+            device.init().expect("Error initialising device");
+            for sector in 0..64 {
+                device.write(sector, &mut [sector as u8; 512]).unwrap();
+            }
+            let mut data = vec![0; 64 * 512];
+            device.read_multi(0, &mut data).unwrap();
+            for (i, chunk) in data.chunks(512).enumerate() {
+                assert!(chunk.iter().all(|b| *b == i as u8));
+            }
+
+            drop(device);
+            assert_eq!(transport.requests.get(), 64 + 1);
+            assert_eq!(transport.driver_features.get() & VIRTIO_RING_F_EVENT_IDX, 0);
+        }
+    }
+
+    #[test]
+    fn test_block_size() {
+        const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+
+        let mut transport = FakeTransport::new(64);
+        for (i, b) in transport.disk.borrow_mut().iter_mut().enumerate() {
+            *b = (i / 512) as u8;
+        }
+        transport.device_features |= VIRTIO_BLK_F_BLK_SIZE;
+        transport.block_size = 4096;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_eq!(device.block_size(), 4096);
+
+        // Sectors inside a block, with the rest of the block left alone
+        let mut data = [0; 512];
+        device.read(11, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 11));
+        device.write(13, &mut [0xaa; 512]).unwrap();
+        device.read(13, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0xaa));
+
+        // Reads starting and ending part of the way through a block
+        for (start, count) in [(3, 30), (8, 16), (9, 2), (0, 64)].iter() {
+            let mut data = vec![0; count * 512];
+            device.read_multi(*start, &mut data).unwrap();
+            for (i, chunk) in data.chunks(512).enumerate() {
+                let sector = *start as usize + i;
+                let expected = if sector == 13 { 0xaa } else { sector as u8 };
+                assert!(chunk.iter().all(|b| *b == expected));
+            }
+        }
+        drop(device);
+        assert_eq!(transport.disk.borrow()[12 * 512], 12);
+        assert_eq!(transport.disk.borrow()[14 * 512], 14);
+
+        // Without the feature sectors are blocks
+        let mut transport = FakeTransport::new(8);
+        transport.block_size = 4096;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_eq!(device.block_size(), 512);
+
+        let mut transport = FakeTransport::new(8);
+        transport.device_features |= VIRTIO_BLK_F_BLK_SIZE;
+        transport.block_size = 1000;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(
+            device.init(),
+            Err(VirtioError::VirtioUnsupportedDevice)
+        ));
+    }
+
+    #[test]
+    fn test_discard() {
+        let mut transport = FakeTransport::new(64);
+        transport
+            .disk
+            .borrow_mut()
+            .iter_mut()
+            .for_each(|b| *b = 0x55);
+        transport.device_features |= VIRTIO_BLK_F_DISCARD;
+        transport.max_discard_sectors = 8;
+        transport.max_discard_segments = 2;
+        transport.discard_alignment = 2;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        let cache = CachedBlock::new(&device);
+
+        let mut data = [0; 512];
+        cache.read(10, &mut data).unwrap();
+        cache.discard(3, 30).unwrap();
+        cache.read(10, &mut data).unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+        for sector in 0..64 {
+            device.read(sector, &mut data).unwrap();
+            let discarded = (4..32).contains(&sector);
+            assert!(data.iter().all(|b| (*b == 0) == discarded));
+        }
+        // Nothing is left once the range is shrunk to the alignment
+        cache.discard(41, 2).unwrap();
+
+        drop(cache);
+        drop(device);
+        assert_eq!(
+            *transport.discards.borrow(),
+            [vec![(4, 8), (12, 8)], vec![(20, 8), (28, 4)]]
+        );
+
+        let mut transport = FakeTransport::new(64);
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_eq!(device.discard(0, 8), Err(Error::BlockNotSupported));
+        drop(device);
+        assert_eq!(transport.requests.get(), 0);
+    }
+
+    #[test]
+    fn test_legacy_only_device() {
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        let mut transport = FakeTransport::new(8);
+        transport.device_features = 0;
+        let mut device = VirtioBlockDevice::new(&mut transport);
+        assert!(matches!(
+            device.init(),
+            Err(crate::virtio::Error::VirtioLegacyOnly)
+        ));
+
+        drop(device);
+        assert_ne!(transport.get_status() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_boot_order() {
+        let order =
+            |has_esp: &[bool], preferred| boot_order(has_esp, preferred).collect::<Vec<_>>();
+
+        // A cloud-init disk found before the OS disk isn't booted from first
+        assert_eq!(order(&[false, true], None), [1, 0]);
+        assert_eq!(order(&[true, false], None), [0, 1]);
+        assert_eq!(order(&[true, false, true, false], None), [0, 2, 1, 3]);
+        // Unless it is asked for, and a disk that isn't there can't be
+        assert_eq!(order(&[true, false, true], Some(1)), [1, 0, 2]);
+        assert_eq!(order(&[true, false, true], Some(2)), [2, 0, 1]);
+        assert_eq!(order(&[false, true], Some(2)), [1, 0]);
+        assert_eq!(order(&[], Some(0)), []);
+
+        assert_eq!(boot_disk(b"quiet rhfw.boot_disk=2"), Some(2));
+        assert_eq!(boot_disk(b"rhfw.boot_disk=1 rhfw.boot_disk=x"), Some(1));
+        assert_eq!(boot_disk(b"rhfw.boot_disk=-1"), None);
+    }
 }
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::cell::Cell;
+
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+use crate::{
+    boot::{Framebuffer, PixelFormat},
+    pci,
+};
+
+// QEMU's standard VGA device
+const BOCHS_VGA_VENDOR_ID: u16 = 0x1234;
+const BOCHS_VGA_DEVICE_ID: u16 = 0x1111;
+
+// VBE DISPI interface registers
+const VBE_DISPI_INDEX_ID: u16 = 0x0;
+const VBE_DISPI_INDEX_XRES: u16 = 0x1;
+const VBE_DISPI_INDEX_YRES: u16 = 0x2;
+const VBE_DISPI_INDEX_BPP: u16 = 0x3;
+const VBE_DISPI_INDEX_ENABLE: u16 = 0x4;
+const VBE_DISPI_INDEX_VIRT_WIDTH: u16 = 0x6;
+const VBE_DISPI_INDEX_VIRT_HEIGHT: u16 = 0x7;
+const VBE_DISPI_INDEX_X_OFFSET: u16 = 0x8;
+const VBE_DISPI_INDEX_Y_OFFSET: u16 = 0x9;
+const VBE_DISPI_INDEX_VIDEO_MEMORY_64K: u16 = 0xa;
+
+const VBE_DISPI_ID0: u16 = 0xb0c0;
+const VBE_DISPI_ID5: u16 = 0xb0c5;
+
+const VBE_DISPI_ENABLED: u16 = 0x01;
+const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+
+fn read_register(index: u16) -> u16 {
+    let mut index_port = PortWriteOnly::new(0x1ce);
+    let mut data_port = Port::new(0x1cf);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+fn write_register(index: u16, value: u16) {
+    let mut index_port = PortWriteOnly::new(0x1ce);
+    let mut data_port = Port::new(0x1cf);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+/// A Bochs/QEMU VGA adapter driven through its DISPI registers, with the
+/// linear framebuffer in BAR 0
+#[derive(Clone, Copy)]
+pub struct BochsDisplay {
+    lfb: u64,
+    vram_size: u64,
+}
+
+impl BochsDisplay {
+    pub fn probe() -> Option<BochsDisplay> {
+        let display = Cell::new(None);
+        pci::with_devices(BOCHS_VGA_VENDOR_ID, BOCHS_VGA_DEVICE_ID, |mut device| {
+            device.init();
+            let lfb = device.bar_address(0);
+            if lfb == 0 {
+                log!("Bochs VGA framebuffer not mapped");
+                return false;
+            }
+
+            let id = read_register(VBE_DISPI_INDEX_ID);
+            if !(VBE_DISPI_ID0..=VBE_DISPI_ID5).contains(&id) {
+                log!("Bochs VGA without DISPI interface: {:x}", id);
+                return false;
+            }
+
+            let vram_size = u64::from(read_register(VBE_DISPI_INDEX_VIDEO_MEMORY_64K)) * 64 * 1024;
+            log!("Bochs VGA framebuffer at {:x} size {:x}", lfb, vram_size);
+            display.set(Some(BochsDisplay { lfb, vram_size }));
+            true
+        });
+        display.get()
+    }
+
+    pub fn vram_size(&self) -> u64 {
+        self.vram_size
+    }
+
+    // Switches to a 32 bits per pixel mode of the given resolution
+    pub fn set_mode(&self, width: u32, height: u32) -> Framebuffer {
+        write_register(VBE_DISPI_INDEX_ENABLE, 0);
+        write_register(VBE_DISPI_INDEX_BPP, 32);
+        write_register(VBE_DISPI_INDEX_XRES, width as u16);
+        write_register(VBE_DISPI_INDEX_YRES, height as u16);
+        write_register(VBE_DISPI_INDEX_VIRT_WIDTH, width as u16);
+        write_register(VBE_DISPI_INDEX_VIRT_HEIGHT, height as u16);
+        write_register(VBE_DISPI_INDEX_X_OFFSET, 0);
+        write_register(VBE_DISPI_INDEX_Y_OFFSET, 0);
+        write_register(
+            VBE_DISPI_INDEX_ENABLE,
+            VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED,
+        );
+
+        Framebuffer {
+            base: self.lfb,
+            width,
+            height,
+            stride: width,
+            format: PixelFormat::Bgrx,
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use core::mem;
+use core::{ffi::c_void, mem};
 
 use crate::{
     common,
     fat::{Error, Read},
     mem::MemoryRegion,
+    paging,
+    sha256::Sha256,
+    tpm,
 };
 
 // Common data needed for all boot paths
@@ -17,6 +20,28 @@ pub trait Info {
     // Methods to access the E820 Memory map
     fn num_entries(&self) -> u8;
     fn entry(&self, idx: u8) -> E820Entry;
+    // A framebuffer already set up by the VMM, if there is one
+    fn framebuffer(&self) -> Option<Framebuffer> {
+        None
+    }
+}
+
+// Order of the colour bytes within each 32-bit pixel in memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    Rgbx,
+    Bgrx,
+}
+
+// A linear framebuffer with 32 bits per pixel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Framebuffer {
+    pub base: u64,
+    pub width: u32,
+    pub height: u32,
+    // Pixels per scan line
+    pub stride: u32,
+    pub format: PixelFormat,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -29,6 +54,174 @@ pub struct E820Entry {
 
 impl E820Entry {
     pub const RAM_TYPE: u32 = 1;
+    pub const ACPI_TYPE: u32 = 3;
+    pub const NVS_TYPE: u32 = 4;
+}
+
+// Kernels started in 32-bit protected mode can only get at the first 4GiB
+const MAX_ADDRESS: u64 = 1 << 32;
+const PAGE_SIZE: u64 = 4096;
+pub const MAX_ENTRIES: usize = 128;
+
+// Whether the ranges of addresses have any in common
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+// Where the firmware is, which kernels can't be loaded over
+#[cfg(not(test))]
+pub fn firmware() -> (u64, u64) {
+    extern "C" {
+        #[link_name = "ram_min"]
+        static RAM_MIN: c_void;
+        #[link_name = "stack_start"]
+        static STACK_START: c_void;
+    }
+    unsafe { (&RAM_MIN as *const _ as u64, &STACK_START as *const _ as u64) }
+}
+
+// Tests aren't linked with layout.ld, so go by where it puts the firmware
+#[cfg(test)]
+pub fn firmware() -> (u64, u64) {
+    (0x10_0000, 0x20_0000)
+}
+
+// A copy of the memory map, for finding where in the RAM below 4GiB a kernel
+// started in 32-bit protected mode can be loaded
+pub struct MemoryMap {
+    entries: [E820Entry; MAX_ENTRIES],
+    num_entries: usize,
+}
+
+impl MemoryMap {
+    pub fn new(info: &dyn Info) -> Self {
+        let mut map = Self {
+            entries: [E820Entry {
+                addr: 0,
+                size: 0,
+                entry_type: 0,
+            }; MAX_ENTRIES],
+            num_entries: core::cmp::min(usize::from(info.num_entries()), MAX_ENTRIES),
+        };
+        for i in 0..map.num_entries {
+            map.entries[i] = info.entry(i as u8);
+        }
+        map
+    }
+
+    pub fn entries(&self) -> &[E820Entry] {
+        &self.entries[..self.num_entries]
+    }
+
+    // Whether the memory is all in one RAM region below 4GiB, clear of the
+    // firmware
+    pub fn usable(&self, region: (u64, u64)) -> bool {
+        region.1 <= MAX_ADDRESS
+            && !overlaps(region, firmware())
+            && self.entries().iter().any(|entry| {
+                entry.entry_type == E820Entry::RAM_TYPE
+                    && entry.addr <= region.0
+                    && region.1 <= entry.addr + entry.size
+            })
+    }
+
+    // The lowest multiple of align, a power of two, from min on that size
+    // bytes fit at in RAM below 4GiB, clear of the firmware
+    pub fn lowest_fit(&self, size: u64, align: u64, min: u64) -> Option<u64> {
+        let align_up = |addr: u64| Some(addr.checked_add(align - 1)? & !(align - 1));
+        self.entries()
+            .iter()
+            .filter(|entry| entry.entry_type == E820Entry::RAM_TYPE)
+            .filter_map(|entry| {
+                let end = core::cmp::min(entry.addr + entry.size, MAX_ADDRESS);
+                let mut addr = align_up(core::cmp::max(entry.addr, min))?;
+                if overlaps((addr, addr.saturating_add(size)), firmware()) {
+                    addr = align_up(firmware().1)?;
+                }
+                if addr.checked_add(size)? > end {
+                    return None;
+                }
+                Some(addr)
+            })
+            .min()
+    }
+
+    // The highest page in RAM below 4GiB that size bytes fit at, clear of the
+    // firmware and of what is already loaded
+    pub fn highest_fit(&self, size: u64, loaded: (u64, u64)) -> Option<u64> {
+        let top = core::cmp::min(paging::mapped_size(), MAX_ADDRESS);
+        self.entries()
+            .iter()
+            .filter(|entry| entry.entry_type == E820Entry::RAM_TYPE)
+            .filter_map(|entry| {
+                let end = core::cmp::min(entry.addr + entry.size, top);
+                let addr = end.checked_sub(size)? & !(PAGE_SIZE - 1);
+                if addr < entry.addr
+                    || overlaps((addr, addr + size), loaded)
+                    || overlaps((addr, addr + size), firmware())
+                {
+                    return None;
+                }
+                Some(addr)
+            })
+            .max()
+    }
+}
+
+pub const CMDLINE_MAX_LEN: usize = 4096;
+
+// A command line built up from pieces separated by spaces, which is always
+// followed by a NUL
+pub struct Cmdline {
+    data: [u8; CMDLINE_MAX_LEN],
+    length: usize,
+}
+
+impl Cmdline {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; CMDLINE_MAX_LEN],
+            length: 0,
+        }
+    }
+
+    // Anything that doesn't fit is dropped
+    pub fn append(&mut self, addition: &[u8]) {
+        if addition.is_empty() {
+            return;
+        }
+        let separator = if self.length == 0 { 0 } else { 1 };
+        // Leaving space for the NUL
+        let space = CMDLINE_MAX_LEN - 1 - self.length;
+        if separator + addition.len() > space {
+            log!("Truncating command line to {} bytes", CMDLINE_MAX_LEN - 1);
+        }
+        let addition = &addition[..core::cmp::min(addition.len(), space.saturating_sub(separator))];
+        if addition.is_empty() {
+            return;
+        }
+        if separator != 0 {
+            self.data[self.length] = b' ';
+            self.length += 1;
+        }
+        self.data[self.length..self.length + addition.len()].copy_from_slice(addition);
+        self.length += addition.len();
+    }
+
+    // Without the NUL
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.length]
+    }
+
+    pub fn as_bytes_with_nul(&self) -> &[u8] {
+        &self.data[..self.length + 1]
+    }
+
+    pub fn measure(&self) {
+        let mut hash = Sha256::new();
+        hash.update(self.as_bytes());
+        tpm::measure(tpm::PCR_COMMAND_LINE, &hash.finish(), self.as_bytes());
+    }
 }
 
 // The so-called "zeropage"
@@ -46,8 +239,8 @@ pub struct Params {
     hd1_info: HdInfo,               // 0x090 - obsolete
     sys_desc_table: SysDescTable,   // 0x0a0 - obsolete
     olpc_ofw_header: OlpcOfwHeader, // 0x0b0
-    ext_ramdisk_image: u32,         // 0x0c0
-    ext_ramdisk_size: u32,          // 0x0c4
+    pub ext_ramdisk_image: u32,     // 0x0c0
+    pub ext_ramdisk_size: u32,      // 0x0c4
     ext_cmd_line_ptr: u32,          // 0x0c8
     _pad4: [u8; 0x74],              // 0x0cc
     edd_info: EdidInfo,             // 0x140
@@ -79,12 +272,29 @@ impl Default for Params {
 }
 
 impl Params {
+    // The boot parameters at the address, if their header is valid
+    #[cfg(any(test, not(feature = "coreboot")))]
+    pub unsafe fn from_addr<'a>(addr: u64) -> Option<&'a Params> {
+        if addr == 0 {
+            return None;
+        }
+        let params = &*(addr as *const Params);
+        if !params.hdr.is_valid() {
+            return None;
+        }
+        Some(params)
+    }
+
     pub fn set_entries(&mut self, info: &dyn Info) {
         self.e820_entries = info.num_entries();
         for i in 0..self.e820_entries {
             self.e820_table[i as usize] = info.entry(i);
         }
     }
+
+    pub fn set_framebuffer(&mut self, fb: &Framebuffer) {
+        self.screen_info.set_framebuffer(fb);
+    }
 }
 
 impl Info for Params {
@@ -104,6 +314,9 @@ impl Info for Params {
         assert!(idx < self.num_entries());
         self.e820_table[idx as usize]
     }
+    fn framebuffer(&self) -> Option<Framebuffer> {
+        self.screen_info.framebuffer()
+    }
 }
 
 const HEADER_START: usize = 0x1f1;
@@ -153,6 +366,26 @@ pub struct Header {
 }
 
 impl Header {
+    // The kernel has a 64-bit EFI handover entry point
+    const XLF_EFI_HANDOVER_64: u16 = 1 << 3;
+
+    pub fn is_valid(&self) -> bool {
+        self.boot_flag == 0xAA55 && self.header == *b"HdrS"
+    }
+
+    // Offset of the 64-bit EFI handover entry from the start of the 64-bit
+    // kernel, which is 0x200 past the 32-bit entry point
+    pub fn efi_handover_offset(&self) -> Option<u32> {
+        if !self.is_valid()
+            || self.version < 0x20b
+            || self.xloadflags & Self::XLF_EFI_HANDOVER_64 == 0
+            || self.handover_offset == 0
+        {
+            return None;
+        }
+        Some(self.handover_offset)
+    }
+
     // Read a kernel header from the first two sectors of a file
     pub fn from_file(f: &mut dyn Read) -> Result<Self, Error> {
         let mut data: [u8; 1024] = [0; 1024];
@@ -160,7 +393,11 @@ impl Header {
 
         f.seek(0)?;
         f.load_file(&mut region)?;
+        Ok(Self::from_bytes(data))
+    }
 
+    // The header in the first two sectors of a bzImage
+    pub fn from_bytes(data: [u8; 1024]) -> Self {
         #[repr(C)]
         struct HeaderData {
             before: [u8; HEADER_START],
@@ -168,7 +405,7 @@ impl Header {
             after: [u8; 1024 - HEADER_END],
         }
         // SAFETY: Struct consists entirely of primitive integral types.
-        Ok(unsafe { mem::transmute::<_, HeaderData>(data) }.hdr)
+        unsafe { mem::transmute::<_, HeaderData>(data) }.hdr
     }
 }
 
@@ -176,7 +413,94 @@ impl Header {
 // size. Update test_size_and_offset if a struct's real definition is added.
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
-struct ScreenInfo([u8; 0x40]);
+struct ScreenInfo {
+    _pad1: [u8; 0x0f],
+    orig_video_is_vga: u8, // 0x0f
+    _orig_video_points: u16,
+    lfb_width: u16,  // 0x12
+    lfb_height: u16, // 0x14
+    lfb_depth: u16,  // 0x16
+    lfb_base: u32,   // 0x18
+    lfb_size: u32,   // 0x1c
+    _cl_magic: u16,
+    _cl_offset: u16,
+    lfb_linelength: u16, // 0x24
+    red_size: u8,        // 0x26
+    red_pos: u8,         // 0x27
+    green_size: u8,      // 0x28
+    green_pos: u8,       // 0x29
+    blue_size: u8,       // 0x2a
+    blue_pos: u8,        // 0x2b
+    rsvd_size: u8,       // 0x2c
+    rsvd_pos: u8,        // 0x2d
+    _pad2: [u8; 0x8],
+    capabilities: u32, // 0x36
+    ext_lfb_base: u32, // 0x3a
+    _pad3: [u8; 2],
+}
+
+impl ScreenInfo {
+    const VIDEO_TYPE_VLFB: u8 = 0x23;
+    const VIDEO_TYPE_EFI: u8 = 0x70;
+    const VIDEO_CAPABILITY_64BIT_BASE: u32 = 1 << 1;
+
+    fn framebuffer(&self) -> Option<Framebuffer> {
+        if self.orig_video_is_vga != Self::VIDEO_TYPE_VLFB
+            && self.orig_video_is_vga != Self::VIDEO_TYPE_EFI
+        {
+            return None;
+        }
+        if self.lfb_depth != 32 {
+            return None;
+        }
+
+        let format = match (self.red_pos, self.green_pos, self.blue_pos) {
+            (0, 8, 16) => PixelFormat::Rgbx,
+            (16, 8, 0) => PixelFormat::Bgrx,
+            _ => return None,
+        };
+
+        let mut base = u64::from(self.lfb_base);
+        if self.capabilities & Self::VIDEO_CAPABILITY_64BIT_BASE != 0 {
+            base |= u64::from(self.ext_lfb_base) << 32;
+        }
+
+        Some(Framebuffer {
+            base,
+            width: u32::from(self.lfb_width),
+            height: u32::from(self.lfb_height),
+            stride: u32::from(self.lfb_linelength) / 4,
+            format,
+        })
+    }
+
+    // As the EFI stub fills it in from GOP, which Linux's efifb takes over
+    fn set_framebuffer(&mut self, fb: &Framebuffer) {
+        self.orig_video_is_vga = Self::VIDEO_TYPE_EFI;
+        self.lfb_width = fb.width as u16;
+        self.lfb_height = fb.height as u16;
+        self.lfb_depth = 32;
+        self.lfb_base = fb.base as u32;
+        self.ext_lfb_base = (fb.base >> 32) as u32;
+        if self.ext_lfb_base != 0 {
+            self.capabilities |= Self::VIDEO_CAPABILITY_64BIT_BASE;
+        }
+        self.lfb_linelength = (fb.stride * 4) as u16;
+        self.lfb_size = fb.stride * 4 * fb.height;
+        let (red_pos, blue_pos) = match fb.format {
+            PixelFormat::Rgbx => (0, 16),
+            PixelFormat::Bgrx => (16, 0),
+        };
+        self.red_size = 8;
+        self.red_pos = red_pos;
+        self.green_size = 8;
+        self.green_pos = 8;
+        self.blue_size = 8;
+        self.blue_pos = blue_pos;
+        self.rsvd_size = 8;
+        self.rsvd_pos = 24;
+    }
+}
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 struct ApmBiosInfo([u8; 0x14]);
@@ -210,7 +534,176 @@ mod tests {
         assert_eq!(mem::size_of::<Header>(), 119);
         assert_eq!(mem::size_of::<E820Entry>(), 20);
         assert_eq!(mem::size_of::<Params>(), 4096);
+        assert_eq!(mem::size_of::<ScreenInfo>(), 0x40);
 
         assert_eq!(offset_of!(Params, hdr), HEADER_START);
     }
+
+    #[test]
+    fn test_params_from_addr() {
+        let mut params = Params::default();
+        let addr = &params as *const _ as u64;
+        assert!(unsafe { Params::from_addr(addr) }.is_none());
+        params.hdr.boot_flag = 0xAA55;
+        params.hdr.header = *b"HdrS";
+        assert!(unsafe { Params::from_addr(addr) }.is_some());
+        assert!(unsafe { Params::from_addr(0) }.is_none());
+    }
+
+    #[test]
+    fn test_efi_handover_offset() {
+        let mut params = Params::default();
+        assert_eq!(params.hdr.efi_handover_offset(), None);
+
+        params.hdr.boot_flag = 0xAA55;
+        params.hdr.header = *b"HdrS";
+        params.hdr.version = 0x20f;
+        params.hdr.handover_offset = 0x190;
+        assert_eq!(params.hdr.efi_handover_offset(), None);
+
+        params.hdr.xloadflags = 0x7f;
+        assert_eq!(params.hdr.efi_handover_offset(), Some(0x190));
+
+        params.hdr.version = 0x20a;
+        assert_eq!(params.hdr.efi_handover_offset(), None);
+    }
+
+    #[test]
+    fn test_screen_info_framebuffer() {
+        let mut params = Params::default();
+        assert_eq!(params.framebuffer(), None);
+
+        let si = &mut params.screen_info;
+        si.orig_video_is_vga = ScreenInfo::VIDEO_TYPE_EFI;
+        si.lfb_width = 1024;
+        si.lfb_height = 768;
+        si.lfb_depth = 32;
+        si.lfb_base = 0xc000_0000;
+        si.lfb_linelength = 1024 * 4 + 64;
+        si.red_pos = 16;
+        si.green_pos = 8;
+        si.blue_pos = 0;
+        assert_eq!(
+            params.framebuffer(),
+            Some(Framebuffer {
+                base: 0xc000_0000,
+                width: 1024,
+                height: 768,
+                stride: 1040,
+                format: PixelFormat::Bgrx,
+            })
+        );
+
+        params.screen_info.capabilities = ScreenInfo::VIDEO_CAPABILITY_64BIT_BASE;
+        params.screen_info.ext_lfb_base = 0x1;
+        assert_eq!(params.framebuffer().unwrap().base, 0x1_c000_0000);
+
+        // Only 32 bits per pixel is supported
+        params.screen_info.lfb_depth = 16;
+        assert_eq!(params.framebuffer(), None);
+    }
+
+    #[test]
+    fn test_set_framebuffer() {
+        for fb in &[
+            Framebuffer {
+                base: 0xc000_0000,
+                width: 1024,
+                height: 768,
+                stride: 1024,
+                format: PixelFormat::Bgrx,
+            },
+            Framebuffer {
+                base: 0x80_0000_0000,
+                width: 800,
+                height: 600,
+                stride: 832,
+                format: PixelFormat::Rgbx,
+            },
+        ] {
+            let mut params = Params::default();
+            params.set_framebuffer(fb);
+            assert_eq!(params.framebuffer(), Some(*fb));
+            let si = params.screen_info;
+            assert_eq!({ si.lfb_size }, fb.stride * 4 * fb.height);
+            assert_eq!((si.red_size, si.green_size, si.blue_size), (8, 8, 8));
+            assert_eq!((si.rsvd_size, si.rsvd_pos), (8, 24));
+        }
+    }
+
+    struct TestInfo {}
+
+    impl Info for TestInfo {
+        fn name(&self) -> &str {
+            "Test"
+        }
+        fn rsdp_addr(&self) -> u64 {
+            0
+        }
+        fn cmdline(&self) -> &[u8] {
+            b""
+        }
+        fn num_entries(&self) -> u8 {
+            1
+        }
+        fn entry(&self, _idx: u8) -> E820Entry {
+            E820Entry {
+                addr: 0,
+                size: 0x10_0000_0000,
+                entry_type: E820Entry::RAM_TYPE,
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_map_usable() {
+        let memory = MemoryMap::new(&TestInfo {});
+        assert_eq!(memory.entries().len(), 1);
+        assert!(memory.usable((0x20_0000, 0x40_0000)));
+        // Not over the firmware, nor above 4GiB
+        assert!(!memory.usable((0x1f_f000, 0x40_0000)));
+        assert!(!memory.usable((0xffff_f000, 0x1_0000_1000)));
+        // Only what is in the map
+        let memory = MemoryMap::new(&Params::default());
+        assert!(!memory.usable((0x20_0000, 0x40_0000)));
+    }
+
+    #[test]
+    fn test_memory_map_lowest_fit() {
+        let memory = MemoryMap::new(&TestInfo {});
+        // Past the firmware, at the alignment
+        assert_eq!(memory.lowest_fit(0x10_0000, 0x1000, 0), Some(0));
+        assert_eq!(
+            memory.lowest_fit(0x10_0000, 0x1000, 0x1000),
+            Some(0x20_0000)
+        );
+        assert_eq!(
+            memory.lowest_fit(0x10_0000, 0x100_0000, 0x10_0000),
+            Some(0x100_0000)
+        );
+        assert_eq!(
+            memory.lowest_fit(0x10_0000, 0x20_0000, 0x10_0001),
+            Some(0x20_0000)
+        );
+        // Below 4GiB
+        assert_eq!(
+            memory.lowest_fit(0x1000, 0x1000, 0xffff_f000),
+            Some(0xffff_f000)
+        );
+        assert_eq!(memory.lowest_fit(0x2000, 0x1000, 0xffff_f000), None);
+        let memory = MemoryMap::new(&Params::default());
+        assert_eq!(memory.lowest_fit(0x1000, 0x1000, 0), None);
+    }
+
+    #[test]
+    fn test_cmdline() {
+        let mut cmdline = Cmdline::new();
+        cmdline.append(b"console=ttyS0");
+        cmdline.append(b"");
+        cmdline.append(b"quiet");
+        assert_eq!(cmdline.as_bytes_with_nul(), b"console=ttyS0 quiet\0");
+        cmdline.append(&[b'a'; CMDLINE_MAX_LEN]);
+        assert_eq!(cmdline.as_bytes().len(), CMDLINE_MAX_LEN - 1);
+        assert_eq!(cmdline.as_bytes_with_nul()[CMDLINE_MAX_LEN - 1], 0);
+    }
 }
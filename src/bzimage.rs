@@ -11,12 +11,17 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use core::ffi::c_void;
+
 use atomic_refcell::AtomicRefCell;
 
 use crate::{
-    boot::{E820Entry, Header, Info, Params},
+    boot::{E820Entry, Header, Info, MemoryMap, Params},
     fat::{self, Read},
     mem::MemoryRegion,
+    paging,
+    sha256::Sha256,
+    tpm,
 };
 
 #[derive(Debug)]
@@ -25,6 +30,7 @@ pub enum Error {
     NoInitrdMemory,
     MagicMissing,
     NotRelocatable,
+    NoKernelMemory,
 }
 
 impl From<fat::Error> for Error {
@@ -33,7 +39,13 @@ impl From<fat::Error> for Error {
     }
 }
 
-const KERNEL_LOCATION: u64 = 0x20_0000;
+// Where kernels that don't say otherwise are loaded, and the alignment of
+// those that are relocatable without a valid kernel_alignment
+const DEFAULT_LOCATION: u64 = 0x10_0000;
+const DEFAULT_ALIGNMENT: u64 = 0x20_0000;
+
+// The kernel can use an initrd above 4GiB, whatever initrd_addr_max says
+const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
 
 #[repr(transparent)]
 pub struct Kernel(Params);
@@ -43,18 +55,29 @@ impl Kernel {
         let mut kernel = Self(Params::default());
         kernel.0.acpi_rsdp_addr = info.rsdp_addr();
         kernel.0.set_entries(info);
+        if let Some(fb) = info.framebuffer() {
+            kernel.0.set_framebuffer(&fb);
+        }
         kernel
     }
 
-    pub fn load_kernel(&mut self, f: &mut dyn Read) -> Result<(), Error> {
-        self.0.hdr = Header::from_file(f)?;
-
-        if self.0.hdr.boot_flag != 0xAA55 || self.0.hdr.header != *b"HdrS" {
+    // Returns the SHA-256 hash of the whole file, setup sectors included, as
+    // it was read in loading it
+    pub fn load_kernel(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        // The header is taken from the sectors that are hashed, rather than
+        // from reading them again
+        let mut hash = Sha256::new();
+        let mut data = [0; 1024];
+        if f.get_size() < data.len() as u32 {
             return Err(Error::MagicMissing);
         }
-        // Check relocatable
-        if self.0.hdr.version < 0x205 || self.0.hdr.relocatable_kernel == 0 {
-            return Err(Error::NotRelocatable);
+        f.seek(0)?;
+        f.load_file(&mut MemoryRegion::from_bytes(&mut data))?;
+        hash.update(&data);
+        self.0.hdr = Header::from_bytes(data);
+
+        if !self.0.hdr.is_valid() {
+            return Err(Error::MagicMissing);
         }
 
         // Skip over the setup sectors
@@ -63,31 +86,108 @@ impl Kernel {
             n => n as u32,
         };
         let setup_bytes = (setup_sects + 1) * 512;
-        let remaining_bytes = f.get_size() - setup_bytes;
+        let remaining_bytes = f
+            .get_size()
+            .checked_sub(setup_bytes)
+            .ok_or(Error::MagicMissing)?;
+        let address = self.load_address(u64::from(remaining_bytes))?;
 
-        let mut region = MemoryRegion::new(KERNEL_LOCATION, remaining_bytes as u64);
-        f.seek(setup_bytes)?;
+        let mut sector = [0; 512];
+        for _ in 2..setup_sects + 1 {
+            f.read(&mut sector)?;
+            hash.update(&sector);
+        }
+
+        let mut region = MemoryRegion::new(address, remaining_bytes as u64);
         f.load_file(&mut region)?;
+        hash.update(region.as_bytes());
+        let digest = hash.finish();
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"kernel");
 
         // Fill out "write/modify" fields
         self.0.hdr.type_of_loader = 0xff; // Unknown Loader
-        self.0.hdr.code32_start = KERNEL_LOCATION as u32; // Where we load the kernel
+        self.0.hdr.code32_start = address as u32; // Where we load the kernel
         self.0.hdr.cmd_line_ptr = CMDLINE_START as u32; // Where we load the cmdline
-        Ok(())
+        Ok(digest)
+    }
+
+    // Where the kernel goes: the address it prefers if that is free, or with
+    // a relocatable kernel otherwise the lowest free one that is a multiple
+    // of kernel_alignment, or of min_alignment failing that. A kernel that
+    // isn't relocatable can only go at the address it prefers.
+    fn load_address(&self, file_size: u64) -> Result<u64, Error> {
+        let hdr = self.0.hdr;
+        // Before 2.10 there is only the file size to go by
+        let size = core::cmp::max(u64::from(hdr.init_size), file_size);
+        let preferred = match (hdr.version >= 0x20a, hdr.pref_address, hdr.code32_start) {
+            (true, address, _) if address != 0 => address,
+            (_, _, 0) => DEFAULT_LOCATION,
+            (_, _, address) => u64::from(address),
+        };
+        let memory = MemoryMap::new(&self.0);
+        if let Some(end) = preferred.checked_add(size) {
+            if memory.usable((preferred, end)) {
+                return Ok(preferred);
+            }
+        }
+
+        if hdr.version < 0x205 || hdr.relocatable_kernel == 0 {
+            log!(
+                "Kernel isn't relocatable and {:#x}-{:#x} isn't free RAM",
+                preferred,
+                preferred.saturating_add(size)
+            );
+            return Err(Error::NotRelocatable);
+        }
+        let alignment = match u64::from(hdr.kernel_alignment) {
+            a if a.is_power_of_two() => a,
+            _ => DEFAULT_ALIGNMENT,
+        };
+        let min_alignment = if hdr.version >= 0x20a && hdr.min_alignment < 32 {
+            1 << hdr.min_alignment
+        } else {
+            alignment
+        };
+        let address = memory
+            .lowest_fit(size, alignment, DEFAULT_LOCATION)
+            .or_else(|| memory.lowest_fit(size, min_alignment, DEFAULT_LOCATION))
+            .ok_or(Error::NoKernelMemory)?;
+        log!("Loading kernel at {:#x}", address);
+        Ok(address)
+    }
+
+    // For a kernel the raw loader put at start, which has no setup header of
+    // its own. The boot parameters get one as for a 2.15 protocol kernel.
+    pub fn set_headerless(&mut self, start: u64, size: u64) {
+        self.0.hdr.boot_flag = 0xaa55;
+        self.0.hdr.header = *b"HdrS";
+        self.0.hdr.version = 0x020f;
+        self.0.hdr.type_of_loader = 0xff; // Unknown Loader
+        self.0.hdr.code32_start = start as u32;
+        self.0.hdr.init_size = size as u32;
+        self.0.hdr.cmd_line_ptr = CMDLINE_START as u32;
+        self.0.hdr.cmdline_size = CMDLINE_MAX_LEN as u32 - 1;
     }
 
     // Compute the load address for the initial ramdisk
     fn initrd_addr(&self, size: u64) -> Option<u64> {
-        let initrd_addr_max = match self.0.hdr.initrd_addr_max {
-            0 => 0x37FF_FFFF,
-            a => a as u64,
+        // We can only write to memory that is identity mapped
+        let initrd_addr_max = if self.0.hdr.xloadflags & XLF_CAN_BE_LOADED_ABOVE_4G != 0 {
+            paging::mapped_size() - 1
+        } else {
+            match self.0.hdr.initrd_addr_max {
+                0 => 0x37FF_FFFF,
+                a => a as u64,
+            }
         };
-        let max_start = (initrd_addr_max + 1) - size;
+        let max_start = initrd_addr_max.checked_sub(size)?.saturating_add(1);
+        let kernel_start = u64::from(self.0.hdr.code32_start);
+        let kernel_end = kernel_start + u64::from(self.0.hdr.init_size);
 
         let mut option_addr = None;
         for i in 0..self.0.num_entries() {
             let entry = self.0.entry(i);
-            if entry.entry_type != E820Entry::RAM_TYPE {
+            if entry.entry_type != E820Entry::RAM_TYPE || entry.size < size {
                 continue;
             }
             let addr = entry.addr + entry.size - size;
@@ -97,6 +197,10 @@ impl Kernel {
             if addr > max_start || addr < entry.addr {
                 continue;
             }
+            // Nor can it be over the kernel
+            if addr < kernel_end && kernel_start < addr + size {
+                continue;
+            }
             // Use the largest address we can find
             if let Some(load_addr) = option_addr {
                 if load_addr >= addr {
@@ -108,7 +212,8 @@ impl Kernel {
         option_addr
     }
 
-    pub fn load_initrd(&mut self, f: &mut dyn Read) -> Result<(), Error> {
+    // Returns the SHA-256 hash of the initrd as it was loaded
+    pub fn load_initrd(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
         let size = f.get_size() as u64;
         let addr = match self.initrd_addr(size) {
             Some(addr) => addr,
@@ -118,27 +223,102 @@ impl Kernel {
         let mut region = MemoryRegion::new(addr, size);
         f.seek(0)?;
         f.load_file(&mut region)?;
+        let mut hash = Sha256::new();
+        hash.update(region.as_bytes());
+        let digest = hash.finish();
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"initrd");
 
-        // initrd pointer/size
+        // initrd pointer/size, with the top halves going in the zero page
         self.0.hdr.ramdisk_image = addr as u32;
         self.0.hdr.ramdisk_size = size as u32;
-        Ok(())
+        self.0.ext_ramdisk_image = (addr >> 32) as u32;
+        self.0.ext_ramdisk_size = (size >> 32) as u32;
+        log!("Loaded {} byte initrd at {:#x}", size, addr);
+        Ok(digest)
     }
 
     pub fn append_cmdline(&mut self, addition: &[u8]) {
         if !addition.is_empty() {
-            CMDLINE.borrow_mut().append(addition);
-            assert!(CMDLINE.borrow().len() < self.0.hdr.cmdline_size);
+            // Before 2.06 the limit was fixed, it doesn't include the NUL
+            let max_len = if self.0.hdr.version < 0x206 {
+                255
+            } else {
+                self.0.hdr.cmdline_size
+            };
+            CMDLINE.borrow_mut().append(addition, max_len as usize);
         }
     }
 
+    // Without the NUL
+    pub fn cmdline(&self) -> &[u8] {
+        let length = CMDLINE.borrow().length;
+        unsafe { core::slice::from_raw_parts(CMDLINE_START as *const u8, length) }
+    }
+
+    // The command line is measured once it can't change any more, as the
+    // kernel is started
+    fn measure_cmdline(&self) {
+        let cmdline = self.cmdline();
+        let mut hash = Sha256::new();
+        hash.update(cmdline);
+        tpm::measure(tpm::PCR_COMMAND_LINE, &hash.finish(), cmdline);
+    }
+
+    pub fn efi_handover_offset(&self) -> Option<u32> {
+        self.0.hdr.efi_handover_offset()
+    }
+
+    // The memory the kernel, initrd and command line are using
+    pub fn regions(&self) -> [(u64, u64); 3] {
+        let hdr = self.0.hdr;
+        let initrd_addr = u64::from(hdr.ramdisk_image) | u64::from(self.0.ext_ramdisk_image) << 32;
+        let initrd_size = u64::from(hdr.ramdisk_size) | u64::from(self.0.ext_ramdisk_size) << 32;
+        [
+            (u64::from(hdr.code32_start), u64::from(hdr.init_size)),
+            (initrd_addr, initrd_size),
+            (CMDLINE_START, CMDLINE_MAX_LEN),
+        ]
+    }
+
+    // Enters the kernel through the EFI stub, which takes care of filling
+    // in the rest of the boot parameters from the EFI environment
+    pub fn efi_handover(&mut self, handle: *mut c_void, system_table: *mut c_void) {
+        self.measure_cmdline();
+        let offset = self.efi_handover_offset().unwrap();
+        let jump_address = u64::from(self.0.hdr.code32_start) + 0x200 + u64::from(offset);
+        let ptr = jump_address as *const ();
+        let code: extern "sysv64" fn(*mut c_void, *mut c_void, *mut Params) =
+            unsafe { core::mem::transmute(ptr) };
+        (code)(handle, system_table, &mut self.0);
+    }
+
     pub fn boot(&mut self) {
         // 0x200 is the startup_64 offset
         let jump_address = self.0.hdr.code32_start as u64 + 0x200;
+        self.boot_at(jump_address, true);
+    }
+
+    // Enters the kernel in 64-bit mode at the address, with %rsi pointing at
+    // the boot parameters or, without them, 0
+    pub fn boot_at(&mut self, jump_address: u64, boot_params: bool) {
+        // Without the EFI stub to find the display through GOP the kernel
+        // only has a console on it if it's told about it here
+        #[cfg(feature = "gop")]
+        if self.0.framebuffer().is_none() {
+            if let Some(fb) = crate::efi::bochs_framebuffer() {
+                self.0.set_framebuffer(&fb);
+            }
+        }
+        self.measure_cmdline();
+        let params = if boot_params {
+            &mut self.0 as *mut _ as usize
+        } else {
+            0
+        };
         // Rely on x86 C calling convention where second argument is put into %rsi register
         let ptr = jump_address as *const ();
         let code: extern "C" fn(usize, usize) = unsafe { core::mem::transmute(ptr) };
-        (code)(0 /* dummy value */, &mut self.0 as *mut _ as usize);
+        (code)(0 /* dummy value */, params);
     }
 }
 
@@ -161,11 +341,20 @@ impl CmdLine {
         }
     }
 
-    const fn len(&self) -> u32 {
-        self.length as u32
-    }
+    // Anything that doesn't fit in max_len bytes is dropped
+    fn append(&mut self, args: &[u8], max_len: usize) {
+        let max_len = core::cmp::min(max_len, CMDLINE_MAX_LEN as usize - 1);
+        let space = max_len.saturating_sub(self.length + 1);
+        let args = if args.len() > space {
+            log!("Truncating command line to {} bytes", max_len);
+            &args[..space]
+        } else {
+            args
+        };
+        if args.is_empty() {
+            return;
+        }
 
-    fn append(&mut self, args: &[u8]) {
         let bytes = self.region.as_bytes();
         bytes[self.length] = b' ';
         self.length += 1;
@@ -175,3 +364,83 @@ impl CmdLine {
         bytes[self.length] = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Kernel};
+    use crate::boot::{E820Entry, Info};
+
+    // 512MiB of RAM from 0
+    struct TestInfo {}
+
+    impl Info for TestInfo {
+        fn name(&self) -> &str {
+            "Test"
+        }
+        fn rsdp_addr(&self) -> u64 {
+            0
+        }
+        fn cmdline(&self) -> &[u8] {
+            b""
+        }
+        fn num_entries(&self) -> u8 {
+            1
+        }
+        fn entry(&self, _idx: u8) -> E820Entry {
+            E820Entry {
+                addr: 0,
+                size: 0x2000_0000,
+                entry_type: E820Entry::RAM_TYPE,
+            }
+        }
+    }
+
+    fn kernel(version: u16, relocatable: u8, pref_address: u64, kernel_alignment: u32) -> Kernel {
+        let mut kernel = Kernel::new(&TestInfo {});
+        kernel.0.hdr.version = version;
+        kernel.0.hdr.code32_start = 0x10_0000;
+        kernel.0.hdr.relocatable_kernel = relocatable;
+        kernel.0.hdr.kernel_alignment = kernel_alignment;
+        kernel.0.hdr.min_alignment = 21;
+        kernel.0.hdr.pref_address = pref_address;
+        kernel.0.hdr.init_size = 0x100_0000;
+        kernel
+    }
+
+    #[test]
+    fn test_load_address() {
+        // Where it prefers to be, when that is free
+        let k = kernel(0x20f, 1, 0x100_0000, 0x20_0000);
+        assert_eq!(k.load_address(0x80_0000).ok(), Some(0x100_0000));
+        // Otherwise past the firmware at its alignment
+        let k = kernel(0x20f, 1, 0x10_0000, 0x100_0000);
+        assert_eq!(k.load_address(0x80_0000).ok(), Some(0x100_0000));
+        let k = kernel(0x20f, 1, 0x10_0000, 0x20_0000);
+        assert_eq!(k.load_address(0x80_0000).ok(), Some(0x20_0000));
+        // Or at its minimum alignment if nothing fits at the other
+        let k = kernel(0x20f, 1, 0x1f80_0000, 0x4000_0000);
+        assert_eq!(k.load_address(0x80_0000).ok(), Some(0x20_0000));
+        // The file can be bigger than init_size says
+        let k = kernel(0x20f, 1, 0x1f80_0000, 0x20_0000);
+        assert!(matches!(
+            k.load_address(0x2000_0000),
+            Err(Error::NoKernelMemory)
+        ));
+
+        // Before 2.10 code32_start is where it goes, and one that isn't
+        // relocatable can't go anywhere else
+        let k = kernel(0x204, 0, 0, 0);
+        assert!(matches!(
+            k.load_address(0x80_0000),
+            Err(Error::NotRelocatable)
+        ));
+        let mut k = kernel(0x204, 0, 0, 0);
+        k.0.hdr.code32_start = 0x40_0000;
+        assert_eq!(k.load_address(0x80_0000).ok(), Some(0x40_0000));
+        let k = kernel(0x20f, 0, 0x10_0000, 0x20_0000);
+        assert!(matches!(
+            k.load_address(0x80_0000),
+            Err(Error::NotRelocatable)
+        ));
+    }
+}
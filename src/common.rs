@@ -93,9 +93,64 @@ pub fn ucs2_to_ascii(input: *const u16, output: &mut [u8]) {
 }
 
 pub fn ascii_to_ucs2(input: &str, output: &mut [u16]) {
-    assert!(output.len() >= input.len() * 2);
+    assert!(output.len() >= input.len());
 
     for (i, c) in input.bytes().enumerate() {
         output[i] = u16::from(c);
     }
 }
+
+// The CRC32 of each byte on its own, worked out a bit at a time
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// CRC32 (IEEE 802.3, as used by GPT and UEFI) of data, continuing from a
+// previous result so that the input can be processed in pieces. Start with 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in data {
+        crc = CRC32_TABLE[usize::from(crc as u8 ^ *b)] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_crc32() {
+        assert_eq!(super::crc32(0, b""), 0);
+        assert_eq!(super::crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            super::crc32(super::crc32(0, b"1234"), b"56789"),
+            0xcbf4_3926
+        );
+        assert_eq!(super::crc32(0, b"a"), 0xe8b7_be43);
+        assert_eq!(
+            super::crc32(0, b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        assert_eq!(super::crc32(0, &[0; 32]), 0x190a_55ad);
+        assert_eq!(super::crc32(0, &[0xff; 32]), 0xff6c_ab0b);
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(super::crc32(0, &bytes), 0x2905_8c73);
+    }
+}
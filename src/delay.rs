@@ -2,21 +2,135 @@
 // Copyright (C) 2021 Akira Moroo
 // Copyright (C) 2018 Google LLC
 
+use core::sync::atomic::{AtomicU64, Ordering};
+
 #[cfg(target_arch = "x86_64")]
-use core::arch::x86_64::_rdtsc;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use x86_64::instructions::port::{Port, PortWriteOnly};
 
-const NSECS_PER_SEC: u64 = 1000000000;
-const CPU_KHZ_DEFAULT: u64 = 200;
 const PAUSE_THRESHOLD_TICKS: u64 = 150;
+// How long relax() waits between checks
+const POLL_INTERVAL_NS: u64 = 5000;
+// Assumed TSC frequency when it can't be found or measured
+const TSC_KHZ_FALLBACK: u64 = 1_000_000;
+
+// The PIT counts at 1.193182MHz, channel 2 can be gated and read back
+// through the system control port
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_COMMAND_PORT: u16 = 0x43;
+const SYSTEM_CONTROL_PORT: u16 = 0x61;
+const PIT_CALIBRATION_MS: u64 = 10;
+
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+// The TSC frequency advertised by the CPU or the hypervisor
+fn cpuid_tsc_khz() -> Option<u64> {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0x15 {
+        // TSC to crystal clock ratio and the crystal frequency in Hz
+        let leaf = unsafe { __cpuid(0x15) };
+        if let Some(khz) = crystal_tsc_khz(leaf.eax, leaf.ebx, leaf.ecx) {
+            return Some(khz);
+        }
+    }
+
+    // Hypervisor timing leaf, with the TSC frequency in kHz
+    let max_hypervisor_leaf = unsafe { __cpuid(0x4000_0000) }.eax;
+    if (0x4000_0010..0x5000_0000).contains(&max_hypervisor_leaf) {
+        let khz = unsafe { __cpuid(0x4000_0010) }.eax;
+        if khz != 0 {
+            return Some(u64::from(khz));
+        }
+    }
+    None
+}
+
+// The TSC frequency from the crystal frequency in Hz and the TSC to crystal
+// ratio, any of which can be left as 0 for unknown
+fn crystal_tsc_khz(denominator: u32, numerator: u32, crystal_hz: u32) -> Option<u64> {
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;
+    }
+    Some(u64::from(crystal_hz) * u64::from(numerator) / u64::from(denominator) / 1000)
+}
+
+// The TSC frequency from the ticks counted over the PIT calibration period
+fn pit_ticks_khz(ticks: u64) -> Option<u64> {
+    let khz = ticks / PIT_CALIBRATION_MS;
+    // Without a PIT the port reads back all ones straight away
+    if khz < 1000 {
+        return None;
+    }
+    Some(khz)
+}
+
+// Counts TSC ticks while PIT channel 2 counts down from a known value
+fn pit_tsc_khz() -> Option<u64> {
+    let count = PIT_HZ * PIT_CALIBRATION_MS / 1000;
+    let mut control = Port::<u8>::new(SYSTEM_CONTROL_PORT);
+    let mut command = PortWriteOnly::<u8>::new(PIT_COMMAND_PORT);
+    let mut channel = PortWriteOnly::<u8>::new(PIT_CHANNEL_2_PORT);
+    let (start, end) = unsafe {
+        // Gate channel 2 on with the speaker off
+        let value = control.read();
+        control.write((value & !0x02) | 0x01);
+        // Channel 2, low then high byte, mode 0 (interrupt on terminal count)
+        command.write(0xb0);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let start = _rdtsc();
+        // OUT2 goes high when the count reaches zero, give up after a
+        // few seconds of even a fast TSC
+        while control.read() & 0x20 == 0 {
+            if _rdtsc() - start > 10_000_000_000 {
+                return None;
+            }
+        }
+        (start, _rdtsc())
+    };
+    pit_ticks_khz(end - start)
+}
+
+// Works out the TSC frequency, which is otherwise done on first use
+pub fn init() {
+    tsc_khz();
+}
+
+// Frequency of the TSC in kHz, found on first use
+pub fn tsc_khz() -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    if khz != 0 {
+        return khz;
+    }
+    let khz = cpuid_tsc_khz()
+        .or_else(pit_tsc_khz)
+        .unwrap_or(TSC_KHZ_FALLBACK);
+    log!("TSC frequency: {} kHz", khz);
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    khz
+}
+
+fn ticks_to_ns(ticks: u64, khz: u64) -> u64 {
+    (u128::from(ticks) * 1_000_000 / u128::from(khz)) as u64
+}
+
+fn ns_to_ticks(ns: u64, khz: u64) -> u64 {
+    (u128::from(ns) * u128::from(khz) / 1_000_000) as u64
+}
+
+// Nanoseconds since the TSC was reset
+pub fn now_ns() -> u64 {
+    ticks_to_ns(unsafe { _rdtsc() }, tsc_khz())
+}
 
 pub fn ndelay(ns: u64) {
-    let delta = ns * CPU_KHZ_DEFAULT / NSECS_PER_SEC;
-    let mut pause_delta = 0;
+    let delta = ns_to_ticks(ns, tsc_khz());
+    let pause_delta = delta.saturating_sub(PAUSE_THRESHOLD_TICKS);
     unsafe {
         let start = _rdtsc();
-        if delta > PAUSE_THRESHOLD_TICKS {
-            pause_delta = delta - PAUSE_THRESHOLD_TICKS;
-        }
         while _rdtsc() - start < pause_delta {
             asm!("pause");
         }
@@ -25,40 +139,66 @@ pub fn ndelay(ns: u64) {
 }
 
 pub fn udelay(us: u64) {
-    for _i in 0..us as usize {
-        ndelay(1000)
-    }
+    ndelay(us.saturating_mul(1000))
 }
 
-#[allow(dead_code)]
 pub fn mdelay(ms: u64) {
-    for _i in 0..ms as usize {
-        udelay(1000)
-    }
+    ndelay(ms.saturating_mul(1_000_000))
 }
 
-#[allow(dead_code)]
+// For between checks of a device or the time, pausing all along so that a
+// hypervisor can see the spinning and give the CPU to something else. HLT
+// isn't of use as the firmware runs with interrupts off, so nothing would
+// wake it. Whatever is waited on can be done before this returns, or not
+// yet done after it, so callers always check again.
+pub fn relax() {
+    ndelay(POLL_INTERVAL_NS)
+}
+
+// Waits for up to ms for cond to turn false, returning whether it's still true
 pub fn wait_while<F>(ms: u64, mut cond: F) -> bool
 where
     F: FnMut() -> bool,
 {
-    let mut us = ms * 1000;
-    while cond() && us > 0 {
-        udelay(1);
-        us -= 1;
+    let deadline = now_ns().saturating_add(ms.saturating_mul(1_000_000));
+    while cond() && now_ns() < deadline {
+        relax();
     }
     cond()
 }
 
-#[allow(dead_code)]
 pub fn wait_until<F>(ms: u64, mut cond: F) -> bool
 where
     F: FnMut() -> bool,
 {
-    let mut us = ms * 1000;
-    while !cond() && us > 0 {
-        udelay(1);
-        us -= 1;
+    !wait_while(ms, || !cond())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crystal_tsc_khz, ns_to_ticks, pit_ticks_khz, ticks_to_ns};
+
+    #[test]
+    fn test_calibration() {
+        // A 24MHz crystal with a TSC at 125/2 times that is 1.5GHz
+        assert_eq!(crystal_tsc_khz(2, 125, 24_000_000), Some(1_500_000));
+        assert_eq!(crystal_tsc_khz(0, 125, 24_000_000), None);
+        assert_eq!(crystal_tsc_khz(2, 125, 0), None);
+        // 2.4GHz counted over 10ms
+        assert_eq!(pit_ticks_khz(24_000_000), Some(2_400_000));
+        assert_eq!(pit_ticks_khz(100), None);
+    }
+
+    #[test]
+    fn test_conversion() {
+        let khz = 2_400_000;
+        assert_eq!(ns_to_ticks(1, khz), 2);
+        assert_eq!(ns_to_ticks(1000, khz), 2400);
+        assert_eq!(ns_to_ticks(1_000_000_000, khz), 2_400_000_000);
+        assert_eq!(ticks_to_ns(2400, khz), 1000);
+        assert_eq!(ticks_to_ns(2_400_000_000, khz), 1_000_000_000);
+        // Without overflowing after years of uptime
+        assert_eq!(ticks_to_ns(u64::MAX, 1_000_000), u64::MAX);
+        assert_eq!(ns_to_ticks(u64::MAX / 1000, 1000), u64::MAX / 1_000_000);
     }
-    cond()
 }
@@ -0,0 +1,371 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// A DHCP client (RFC 2131) for the address, and where to boot from, of a
+// network boot. Only the initial DISCOVER, OFFER, REQUEST and ACK exchange
+// is done, the lease is never renewed.
+
+use core::fmt;
+
+use crate::{
+    delay,
+    ip::{Endpoint, Interface, Ipv4Address, MacAddress, BROADCAST_MAC, MAX_UDP_PAYLOAD},
+};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+// Message fields
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Servers reply by broadcast, as we can't receive anything else without an
+// address
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const FILE_OFFSET: usize = 108;
+const FILE_SIZE: usize = 128;
+const OPTIONS_OFFSET: usize = 240;
+// The smallest message servers and relays have to take
+const MIN_MESSAGE_SIZE: usize = 300;
+
+// Options
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_VENDOR_CLASS: u8 = 60;
+const OPTION_TFTP_SERVER: u8 = 66;
+const OPTION_BOOT_FILE: u8 = 67;
+const OPTION_CLIENT_ARCH: u8 = 93;
+const OPTION_END: u8 = 255;
+
+// Message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+// How servers tell a PXE client that boots x86-64 EFI images apart
+const VENDOR_CLASS: &[u8] = b"PXEClient";
+const CLIENT_ARCH_EFI_X64: u16 = 7;
+
+// Each message is sent this many times, waiting twice as long after each
+const ATTEMPTS: usize = 3;
+const INITIAL_TIMEOUT_MS: u64 = 500;
+
+const MAX_BOOT_FILE: usize = 255;
+
+/// The address that was handed out and where to boot from
+pub struct Lease {
+    pub address: Ipv4Address,
+    pub subnet_mask: Option<Ipv4Address>,
+    pub router: Option<Ipv4Address>,
+    // The server that handed out the address
+    pub server: Ipv4Address,
+    // Where the boot file is, siaddr unless the TFTP server option says
+    pub next_server: Ipv4Address,
+    boot_file: [u8; MAX_BOOT_FILE],
+    boot_file_len: usize,
+}
+
+impl Lease {
+    /// The file to boot, which is empty if the server didn't name one
+    pub fn boot_file(&self) -> &[u8] {
+        &self.boot_file[..self.boot_file_len]
+    }
+
+    fn set_boot_file(&mut self, name: &[u8]) {
+        // Names can be padded out with NULs
+        let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        self.boot_file[..len].copy_from_slice(&name[..len]);
+        self.boot_file_len = len;
+    }
+}
+
+impl fmt::Display for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        if let Some(mask) = self.subnet_mask {
+            write!(f, " mask {}", mask)?;
+        }
+        if let Some(router) = self.router {
+            write!(f, " router {}", router)?;
+        }
+        write!(f, " from {}", self.server)?;
+        if !self.boot_file().is_empty() {
+            let name = core::str::from_utf8(self.boot_file()).unwrap_or("?");
+            write!(f, ", boot file {} on {}", name, self.next_server)?;
+        }
+        Ok(())
+    }
+}
+
+fn ipv4(data: &[u8]) -> Ipv4Address {
+    let mut address = [0; 4];
+    address.copy_from_slice(&data[..4]);
+    Ipv4Address(address)
+}
+
+// Builds a DISCOVER, or a REQUEST for the offered address, returning its
+// length
+fn message(
+    data: &mut [u8],
+    xid: u32,
+    mac: &MacAddress,
+    message_type: u8,
+    offer: Option<&Lease>,
+) -> usize {
+    data[..MIN_MESSAGE_SIZE].fill(0);
+    data[0..4].copy_from_slice(&[BOOTREQUEST, HTYPE_ETHERNET, 6, 0]);
+    data[4..8].copy_from_slice(&xid.to_be_bytes());
+    data[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    data[28..34].copy_from_slice(mac);
+    data[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut length = OPTIONS_OFFSET;
+    let mut option = |code: u8, value: &[u8]| {
+        data[length] = code;
+        data[length + 1] = value.len() as u8;
+        data[length + 2..length + 2 + value.len()].copy_from_slice(value);
+        length += 2 + value.len();
+    };
+    option(OPTION_MESSAGE_TYPE, &[message_type]);
+    if let Some(offer) = offer {
+        option(OPTION_REQUESTED_ADDRESS, &offer.address.0);
+        option(OPTION_SERVER_ID, &offer.server.0);
+    }
+    option(
+        OPTION_PARAMETER_LIST,
+        &[
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_SERVER_ID,
+            OPTION_TFTP_SERVER,
+            OPTION_BOOT_FILE,
+        ],
+    );
+    option(OPTION_VENDOR_CLASS, VENDOR_CLASS);
+    option(OPTION_CLIENT_ARCH, &CLIENT_ARCH_EFI_X64.to_be_bytes());
+    data[length] = OPTION_END;
+    core::cmp::max(length + 1, MIN_MESSAGE_SIZE)
+}
+
+// The message type and what the server's reply to us says
+fn parse(data: &[u8], xid: u32, mac: &MacAddress) -> Option<(u8, Lease)> {
+    if data.len() < OPTIONS_OFFSET
+        || data[0] != BOOTREPLY
+        || data[4..8] != xid.to_be_bytes()
+        || data[28..34] != mac[..]
+        || data[236..240] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut lease = Lease {
+        address: ipv4(&data[16..20]),
+        subnet_mask: None,
+        router: None,
+        server: Ipv4Address::UNSPECIFIED,
+        next_server: ipv4(&data[20..24]),
+        boot_file: [0; MAX_BOOT_FILE],
+        boot_file_len: 0,
+    };
+    lease.set_boot_file(&data[FILE_OFFSET..FILE_OFFSET + FILE_SIZE]);
+
+    let mut message_type = None;
+    let mut options = &data[OPTIONS_OFFSET..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        if rest.len() < usize::from(len) {
+            return None;
+        }
+        let (value, rest) = rest.split_at(usize::from(len));
+        match code {
+            OPTION_MESSAGE_TYPE if len == 1 => message_type = Some(value[0]),
+            OPTION_SUBNET_MASK if len == 4 => lease.subnet_mask = Some(ipv4(value)),
+            // The first of the routers is the one to use
+            OPTION_ROUTER if len >= 4 => lease.router = Some(ipv4(value)),
+            OPTION_SERVER_ID if len == 4 => lease.server = ipv4(value),
+            // This is a name, of which we can only use addresses
+            OPTION_TFTP_SERVER => {
+                let len = value.iter().position(|c| *c == 0).unwrap_or(value.len());
+                if let Some(address) = Ipv4Address::parse(&value[..len]) {
+                    lease.next_server = address;
+                }
+            }
+            OPTION_BOOT_FILE => lease.set_boot_file(value),
+            _ => {}
+        }
+        options = rest;
+    }
+    Some((message_type?, lease))
+}
+
+// Broadcasts the message until the reply of the type comes in
+fn exchange(
+    interface: &Interface,
+    xid: u32,
+    message_type: u8,
+    offer: Option<&Lease>,
+    reply_type: u8,
+) -> Option<Lease> {
+    let mac = interface.mac();
+    let mut request = [0; MAX_UDP_PAYLOAD];
+    let length = message(&mut request, xid, &mac, message_type, offer);
+    let servers = Endpoint {
+        mac: BROADCAST_MAC,
+        ip: Ipv4Address::BROADCAST,
+        port: SERVER_PORT,
+    };
+
+    let mut reply = [0; MAX_UDP_PAYLOAD];
+    let mut timeout_ms = INITIAL_TIMEOUT_MS;
+    for _ in 0..ATTEMPTS {
+        interface.send_udp(CLIENT_PORT, &servers, &request[..length]);
+        let deadline = delay::now_ns() + timeout_ms * 1_000_000;
+        while let Some((_, len)) = interface.recv_udp(CLIENT_PORT, deadline, &mut reply) {
+            match parse(&reply[..len], xid, &mac) {
+                Some((t, lease)) if t == reply_type => return Some(lease),
+                Some((DHCPNAK, lease)) => {
+                    log!("DHCP server {} refused the address", lease.server);
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        timeout_ms *= 2;
+    }
+    None
+}
+
+/// Gets an address for the interface, giving up after a few seconds
+pub fn request_lease(interface: &Interface) -> Option<Lease> {
+    // Different for every boot and every machine
+    let mac = interface.mac();
+    let xid = delay::now_ns() as u32 ^ u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]);
+
+    let offer = exchange(interface, xid, DHCPDISCOVER, None, DHCPOFFER)?;
+    exchange(interface, xid, DHCPREQUEST, Some(&offer), DHCPACK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message, parse, DHCPACK, DHCPDISCOVER, DHCPOFFER, DHCPREQUEST};
+    use crate::ip::Ipv4Address;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+    // A reply to the request, with the options given
+    fn reply(xid: u32, siaddr: [u8; 4], file: &[u8], options: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 240];
+        data[..4].copy_from_slice(&[2, 1, 6, 0]);
+        data[4..8].copy_from_slice(&xid.to_be_bytes());
+        data[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        data[20..24].copy_from_slice(&siaddr);
+        data[28..34].copy_from_slice(&MAC);
+        data[108..108 + file.len()].copy_from_slice(file);
+        data[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        data.extend_from_slice(options);
+        data
+    }
+
+    #[test]
+    fn test_message() {
+        let mut data = [0; 1024];
+        let length = message(&mut data, 0x1234_5678, &MAC, DHCPDISCOVER, None);
+        assert_eq!(length, 300);
+        assert_eq!(
+            &data[..12],
+            &[1, 1, 6, 0, 0x12, 0x34, 0x56, 0x78, 0, 0, 0x80, 0]
+        );
+        assert_eq!(&data[28..34], &MAC);
+        assert_eq!(&data[236..243], &[99, 130, 83, 99, 53, 1, 1]);
+        assert_eq!(&data[243..250], &[55, 5, 1, 3, 54, 66, 67]);
+
+        let (_, offer) = parse(
+            &reply(
+                0x1234_5678,
+                [0; 4],
+                b"",
+                &[53, 1, 2, 54, 4, 10, 0, 2, 2, 255],
+            ),
+            0x1234_5678,
+            &MAC,
+        )
+        .unwrap();
+        let length = message(&mut data, 0x1234_5678, &MAC, DHCPREQUEST, Some(&offer));
+        assert_eq!(
+            &data[240..255],
+            &[53, 1, 3, 50, 4, 10, 0, 2, 15, 54, 4, 10, 0, 2, 2]
+        );
+        assert_eq!(data[length - 1], 0);
+        let end = data[..length].iter().rposition(|b| *b != 0).unwrap();
+        assert_eq!(data[end], 255);
+    }
+
+    #[test]
+    fn test_parse() {
+        let xid = 0xcafe;
+        // The boot file and TFTP server from the header
+        let data = reply(
+            xid,
+            [10, 0, 2, 4],
+            b"pxelinux.0",
+            &[
+                53, 1, 2, 0, 1, 4, 255, 255, 255, 0, 3, 8, 10, 0, 2, 2, 10, 0, 2, 3, 54, 4, 10, 0,
+                2, 2, 99, 0, 255, 53, 1, 5,
+            ],
+        );
+        let (message_type, lease) = parse(&data, xid, &MAC).unwrap();
+        assert_eq!(message_type, DHCPOFFER);
+        assert_eq!(lease.address, Ipv4Address([10, 0, 2, 15]));
+        assert_eq!(lease.subnet_mask, Some(Ipv4Address([255, 255, 255, 0])));
+        assert_eq!(lease.router, Some(Ipv4Address([10, 0, 2, 2])));
+        assert_eq!(lease.server, Ipv4Address([10, 0, 2, 2]));
+        assert_eq!(lease.next_server, Ipv4Address([10, 0, 2, 4]));
+        assert_eq!(lease.boot_file(), b"pxelinux.0");
+        assert_eq!(
+            format!("{}", lease),
+            "10.0.2.15 mask 255.255.255.0 router 10.0.2.2 from 10.0.2.2, \
+             boot file pxelinux.0 on 10.0.2.4"
+        );
+
+        // Options 66 and 67 take the place of the header's fields
+        let data = reply(
+            xid,
+            [10, 0, 2, 4],
+            b"pxelinux.0",
+            &[
+                53, 1, 5, 66, 8, b'1', b'0', b'.', b'0', b'.', b'2', b'.', b'5', 67, 12, b'E',
+                b'F', b'I', b'/', b'B', b'O', b'O', b'T', b'.', b'E', b'F', b'I', 255,
+            ],
+        );
+        let (message_type, lease) = parse(&data, xid, &MAC).unwrap();
+        assert_eq!(message_type, DHCPACK);
+        assert_eq!(lease.next_server, Ipv4Address([10, 0, 2, 5]));
+        assert_eq!(lease.boot_file(), b"EFI/BOOT.EFI");
+        assert_eq!(lease.router, None);
+        assert_eq!(
+            format!("{}", lease).split(',').next(),
+            Some("10.0.2.15 from 0.0.0.0")
+        );
+
+        // Replies to someone else, or cut short
+        assert!(parse(&data, xid + 1, &MAC).is_none());
+        assert!(parse(&data, xid, &[0; 6]).is_none());
+        assert!(parse(&data[..250], xid, &MAC).is_none());
+        assert!(parse(&reply(xid, [0; 4], b"", &[255]), xid, &MAC).is_none());
+    }
+}
@@ -170,6 +170,9 @@ impl Allocator {
         if new == MAX_ALLOCATIONS {
             return None;
         }
+        // There's another descriptor in the map, even if the allocation it's
+        // for fails after this
+        self.key += 1;
 
         // Copy fields from one being split into new half with exception of pages.
         self.allocations[new].in_use = true;
@@ -213,12 +216,11 @@ impl Allocator {
             return (Status::OUT_OF_RESOURCES, 0);
         }
 
-        self.key += 1;
-
         let dest = dest.unwrap();
 
         // Identical special case
         if self.allocations[dest].descriptor.number_of_pages == page_count {
+            self.key += 1;
             self.allocations[dest].descriptor.r#type = memory_type as u32;
             return (
                 Status::SUCCESS,
@@ -273,6 +275,7 @@ impl Allocator {
             }
         }
 
+        self.key += 1;
         self.allocations[assigned].descriptor.r#type = memory_type as u32;
         self.allocations[assigned].descriptor.attribute |= match memory_type {
             efi::RUNTIME_SERVICES_CODE | efi::RUNTIME_SERVICES_DATA => r_efi::efi::MEMORY_RUNTIME,
@@ -326,7 +329,11 @@ impl Allocator {
             let a = &mut self.allocations[cur.unwrap()];
 
             if address == a.descriptor.physical_start {
+                // Images load_image() put here had their sections protected
+                #[cfg(all(feature = "section-protection", not(test)))]
+                crate::paging::unprotect(address, a.descriptor.number_of_pages * PAGE_SIZE);
                 a.descriptor.r#type = efi::CONVENTIONAL_MEMORY as u32;
+                self.key += 1;
                 self.merge_free_memory();
                 return Status::SUCCESS;
             }
@@ -816,4 +823,110 @@ mod tests {
 
         assert_eq!(count, 4);
     }
+
+    #[test]
+    fn test_allocate_high_memory() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+
+        // Use up everything between 1MiB and 128MiB
+        assert_eq!(
+            allocator.allocate_pages(
+                efi::ALLOCATE_ADDRESS,
+                efi::LOADER_DATA,
+                127 * 1024 * 1024 / super::PAGE_SIZE,
+                1024 * 1024
+            ),
+            (Status::SUCCESS, 1024 * 1024)
+        );
+
+        // Not even 1MiB is left below 4GiB but generic requests use the RAM above
+        assert_eq!(
+            allocator.allocate_pages(
+                efi::ALLOCATE_MAX_ADDRESS,
+                efi::LOADER_DATA,
+                1024 * 1024 / super::PAGE_SIZE,
+                4096 * 1024 * 1024 - 1
+            ),
+            (Status::OUT_OF_RESOURCES, 0)
+        );
+        assert_eq!(
+            allocator.allocate_pages(efi::ALLOCATE_ANY_PAGES, efi::LOADER_DATA, 1, 0),
+            (Status::SUCCESS, 4096 * 1024 * 1024)
+        );
+        assert_eq!(
+            allocator.allocate_pages(
+                efi::ALLOCATE_ANY_PAGES,
+                efi::LOADER_DATA,
+                2048 * 1024 * 1024 / super::PAGE_SIZE,
+                0
+            ),
+            (Status::SUCCESS, 4096 * 1024 * 1024 + super::PAGE_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_map_key() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+        let key = allocator.get_map_key();
+
+        // Failed requests leave the map alone, everything else changes it
+        assert_eq!(
+            allocator.allocate_pages(efi::ALLOCATE_ADDRESS, efi::LOADER_DATA, 1, 0x9f_0000_0000),
+            (Status::OUT_OF_RESOURCES, 0)
+        );
+        assert_eq!(allocator.free_pages(0x1234_5000), Status::NOT_FOUND);
+        assert_eq!(allocator.get_map_key(), key);
+
+        let (status, address) =
+            allocator.allocate_pages(efi::ALLOCATE_ANY_PAGES, efi::LOADER_DATA, 16, 0);
+        assert_eq!(status, Status::SUCCESS);
+        assert!(allocator.get_map_key() > key);
+        let key = allocator.get_map_key();
+        assert_eq!(allocator.free_pages(address), Status::SUCCESS);
+        assert!(allocator.get_map_key() > key);
+    }
+
+    #[test]
+    fn test_descriptors_do_not_overlap() {
+        let mut allocator = Allocator::new();
+
+        add_initial_allocations(&mut allocator);
+        let mut addresses = Vec::new();
+        for (i, memory_type) in [efi::LOADER_CODE, efi::LOADER_DATA, efi::BOOT_SERVICES_DATA]
+            .iter()
+            .cycle()
+            .take(30)
+            .enumerate()
+        {
+            let (status, address) =
+                allocator.allocate_pages(efi::ALLOCATE_ANY_PAGES, *memory_type, i as u64 + 1, 0);
+            assert_eq!(status, Status::SUCCESS);
+            addresses.push(address);
+        }
+        for address in addresses.iter().step_by(3) {
+            assert_eq!(allocator.free_pages(*address), Status::SUCCESS);
+        }
+
+        let mut descriptors: [super::MemoryDescriptor; super::MAX_ALLOCATIONS] =
+            unsafe { std::mem::zeroed() };
+        let count = allocator.get_descriptors(&mut descriptors);
+        let total: u64 = descriptors[..count].iter().map(|d| d.number_of_pages).sum();
+        for pair in descriptors[..count].windows(2) {
+            assert!(pair[0].number_of_pages > 0);
+            assert!(
+                pair[0].physical_start + pair[0].number_of_pages * super::PAGE_SIZE
+                    <= pair[1].physical_start
+            );
+        }
+        // No memory was lost or gained
+        let initial: u64 = [0x9f000, 127 << 20, 512 << 20, 4 << 30]
+            .iter()
+            .map(|size| size / super::PAGE_SIZE)
+            .sum();
+        assert_eq!(total, initial);
+    }
 }
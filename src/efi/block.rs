@@ -20,6 +20,8 @@ use r_efi::{
     protocols::device_path::Protocol as DevicePathProtocol,
 };
 
+use crate::part::{HardDrive, PartitionSignature};
+
 pub const PROTOCOL_GUID: Guid = Guid::from_fields(
     0x964e_5b21,
     0x6459,
@@ -29,6 +31,15 @@ pub const PROTOCOL_GUID: Guid = Guid::from_fields(
     &[0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
 );
 
+pub const DISK_IO_PROTOCOL_GUID: Guid = Guid::from_fields(
+    0xce34_5171,
+    0xba0b,
+    0x11d2,
+    0x8e,
+    0x4f,
+    &[0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+);
+
 #[repr(packed)]
 pub struct HardDiskDevicePathProtocol {
     pub device_path: DevicePathProtocol,
@@ -86,12 +97,32 @@ pub struct BlockIoProtocol {
     ) -> Status},
 }
 
+#[repr(C)]
+pub struct DiskIoProtocol {
+    revision: u64,
+    read_disk: eficall! {fn(
+        *mut DiskIoProtocol,
+        u32,
+        u64,
+        usize,
+        *mut c_void
+    ) -> Status},
+    write_disk: eficall! {fn(
+        *mut DiskIoProtocol,
+        u32,
+        u64,
+        usize,
+        *mut c_void
+    ) -> Status},
+}
+
 #[repr(C)]
 pub struct BlockWrapper<'a> {
     hw: super::HandleWrapper,
-    block: *const crate::block::VirtioBlockDevice<'a>,
+    block: *const crate::block::CachedBlock<'a, dyn crate::block::BlockDevice + 'a>,
     media: BlockIoMedia,
     pub proto: BlockIoProtocol,
+    pub disk_io: DiskIoProtocol,
     // The ordering of these paths are very important, along with the C
     // representation as the device path "flows" from the first.
     pub controller_path: ControllerDevicePathProtocol,
@@ -105,12 +136,60 @@ pub struct BlockWrappers<'a> {
 }
 
 pub extern "win64" fn reset(_: *mut BlockIoProtocol, _: bool) -> Status {
-    Status::UNSUPPORTED
+    Status::SUCCESS
+}
+
+impl<'a> BlockWrapper<'a> {
+    // Checks a request against the media, returning the number of blocks
+    fn check_request(
+        &self,
+        media_id: u32,
+        start: u64,
+        size: usize,
+        buffer: *mut c_void,
+    ) -> Result<u64, Status> {
+        if media_id != self.media.media_id {
+            return Err(Status::MEDIA_CHANGED);
+        }
+        if size % self.media.block_size as usize != 0 {
+            return Err(Status::BAD_BUFFER_SIZE);
+        }
+        let blocks = (size / self.media.block_size as usize) as u64;
+        if blocks == 0 {
+            return Ok(0);
+        }
+        if buffer.is_null()
+            || start > self.media.last_block
+            || blocks > self.media.last_block - start + 1
+        {
+            return Err(Status::INVALID_PARAMETER);
+        }
+        Ok(blocks)
+    }
+
+    fn read_block(&self, lba: u64, data: &mut [u8]) -> Result<(), Status> {
+        use crate::block::SectorRead;
+        let block = unsafe { &*self.block };
+        block
+            .read(self.start_lba + lba, data)
+            .map_err(|_| Status::DEVICE_ERROR)
+    }
+
+    fn write_block(&self, lba: u64, data: &mut [u8]) -> Result<(), Status> {
+        use crate::block::SectorWrite;
+        let block = unsafe { &*self.block };
+        block
+            .write(self.start_lba + lba, data)
+            .map_err(|e| match e {
+                crate::block::Error::BlockReadOnly => Status::WRITE_PROTECTED,
+                _ => Status::DEVICE_ERROR,
+            })
+    }
 }
 
 pub extern "win64" fn read_blocks(
     proto: *mut BlockIoProtocol,
-    _: u32,
+    media_id: u32,
     start: u64,
     size: usize,
     buffer: *mut c_void,
@@ -118,19 +197,17 @@ pub extern "win64" fn read_blocks(
     let wrapper = container_of!(proto, BlockWrapper, proto);
     let wrapper = unsafe { &*wrapper };
 
-    let blocks = (size / 512) as usize;
+    let blocks = match wrapper.check_request(media_id, start, size, buffer) {
+        Ok(blocks) => blocks,
+        Err(status) => return status,
+    };
     let mut region = crate::mem::MemoryRegion::new(buffer as u64, size as u64);
 
     for i in 0..blocks {
-        use crate::block::SectorRead;
-        let data = region.as_mut_slice(i as u64 * 512, 512);
-        let block = unsafe { &*wrapper.block };
-        match block.read(wrapper.start_lba + start + i as u64, data) {
-            Ok(()) => continue,
-            Err(_) => {
-                return Status::DEVICE_ERROR;
-            }
-        };
+        let data = region.as_mut_slice(i * 512, 512);
+        if let Err(status) = wrapper.read_block(start + i, data) {
+            return status;
+        }
     }
 
     Status::SUCCESS
@@ -138,7 +215,7 @@ pub extern "win64" fn read_blocks(
 
 pub extern "win64" fn write_blocks(
     proto: *mut BlockIoProtocol,
-    _: u32,
+    media_id: u32,
     start: u64,
     size: usize,
     buffer: *mut c_void,
@@ -146,19 +223,20 @@ pub extern "win64" fn write_blocks(
     let wrapper = container_of!(proto, BlockWrapper, proto);
     let wrapper = unsafe { &*wrapper };
 
-    let blocks = (size / 512) as usize;
+    if wrapper.media.read_only {
+        return Status::WRITE_PROTECTED;
+    }
+    let blocks = match wrapper.check_request(media_id, start, size, buffer) {
+        Ok(blocks) => blocks,
+        Err(status) => return status,
+    };
     let mut region = crate::mem::MemoryRegion::new(buffer as u64, size as u64);
 
     for i in 0..blocks {
-        use crate::block::SectorWrite;
-        let data = region.as_mut_slice(i as u64 * 512, 512);
-        let block = unsafe { &*wrapper.block };
-        match block.write(wrapper.start_lba + start + i as u64, data) {
-            Ok(()) => continue,
-            Err(_) => {
-                return Status::DEVICE_ERROR;
-            }
-        };
+        let data = region.as_mut_slice(i * 512, 512);
+        if let Err(status) = wrapper.write_block(start + i, data) {
+            return status;
+        }
     }
 
     Status::SUCCESS
@@ -175,16 +253,91 @@ pub extern "win64" fn flush_blocks(proto: *mut BlockIoProtocol) -> Status {
     }
 }
 
+// Disk I/O works in bytes: whole blocks are transferred directly and the
+// partial ones at either end go through a block sized buffer
+fn disk_io(
+    wrapper: &BlockWrapper,
+    media_id: u32,
+    offset: u64,
+    size: usize,
+    buffer: *mut c_void,
+    write: bool,
+) -> Status {
+    if media_id != wrapper.media.media_id {
+        return Status::MEDIA_CHANGED;
+    }
+    if write && wrapper.media.read_only {
+        return Status::WRITE_PROTECTED;
+    }
+    if size == 0 {
+        return Status::SUCCESS;
+    }
+    let disk_size = (wrapper.media.last_block + 1) * 512;
+    if buffer.is_null() || offset > disk_size || size as u64 > disk_size - offset {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, size) };
+    let mut done = 0;
+    while done < size {
+        let position = offset + done as u64;
+        let lba = position / 512;
+        let start = (position % 512) as usize;
+        let bytes = core::cmp::min(512 - start, size - done);
+        let buffer = &mut buffer[done..done + bytes];
+
+        let result = if bytes == 512 && write {
+            wrapper.write_block(lba, buffer)
+        } else if bytes == 512 {
+            wrapper.read_block(lba, buffer)
+        } else {
+            let mut data = [0; 512];
+            wrapper.read_block(lba, &mut data).and_then(|_| {
+                if write {
+                    data[start..start + bytes].copy_from_slice(buffer);
+                    wrapper.write_block(lba, &mut data)
+                } else {
+                    buffer.copy_from_slice(&data[start..start + bytes]);
+                    Ok(())
+                }
+            })
+        };
+        if let Err(status) = result {
+            return status;
+        }
+        done += bytes;
+    }
+
+    Status::SUCCESS
+}
+
+pub extern "win64" fn read_disk(
+    proto: *mut DiskIoProtocol,
+    media_id: u32,
+    offset: u64,
+    size: usize,
+    buffer: *mut c_void,
+) -> Status {
+    let wrapper = container_of!(proto, BlockWrapper, disk_io);
+    disk_io(unsafe { &*wrapper }, media_id, offset, size, buffer, false)
+}
+
+pub extern "win64" fn write_disk(
+    proto: *mut DiskIoProtocol,
+    media_id: u32,
+    offset: u64,
+    size: usize,
+    buffer: *mut c_void,
+) -> Status {
+    let wrapper = container_of!(proto, BlockWrapper, disk_io);
+    disk_io(unsafe { &*wrapper }, media_id, offset, size, buffer, true)
+}
+
 impl<'a> BlockWrapper<'a> {
     pub fn new(
-        block: *const crate::block::VirtioBlockDevice,
-        partition_number: u32,
-        start_lba: u64,
-        last_lba: u64,
-        uuid: [u8; 16],
-    ) -> *mut BlockWrapper {
-        let last_block = unsafe { (*block).get_capacity() } - 1;
-
+        block: *const crate::block::CachedBlock<'a, dyn crate::block::BlockDevice + 'a>,
+        partition: Option<&HardDrive>,
+    ) -> *mut BlockWrapper<'a> {
         let size = core::mem::size_of::<BlockWrapper>();
         let (_status, new_address) = super::ALLOCATOR.borrow_mut().allocate_pages(
             efi::ALLOCATE_ANY_PAGES,
@@ -196,132 +349,345 @@ impl<'a> BlockWrapper<'a> {
         let bw = new_address as *mut BlockWrapper;
 
         unsafe {
-            *bw = BlockWrapper {
-                hw: super::HandleWrapper {
-                    handle_type: super::HandleType::Block,
-                },
-                block,
-                media: BlockIoMedia {
-                    media_id: 0,
-                    removable_media: false,
-                    media_present: true,
-                    logical_partition: false,
-                    read_only: true,
-                    write_caching: false,
-                    block_size: 512,
-                    io_align: 0,
-                    last_block,
-                },
-                proto: BlockIoProtocol {
-                    revision: 0x0001_0000, // EFI_BLOCK_IO_PROTOCOL_REVISION
-                    media: core::ptr::null(),
-                    reset,
-                    read_blocks,
-                    write_blocks,
-                    flush_blocks,
-                },
-                start_lba,
-                controller_path: ControllerDevicePathProtocol {
-                    device_path: DevicePathProtocol {
-                        r#type: 1,
-                        sub_type: 5,
-                        length: [8, 0],
-                    },
-                    controller: 0,
-                },
-                // full disk vs partition
-                disk_paths: if partition_number == 0 {
-                    [
-                        HardDiskDevicePathProtocol {
-                            device_path: DevicePathProtocol {
-                                r#type: r_efi::protocols::device_path::TYPE_END,
-                                sub_type: 0xff, // End of full path
-                                length: [4, 0],
-                            },
-                            partition_number: 0,
-                            partition_format: 0x0,
-                            partition_start: 0,
-                            partition_size: 0,
-                            partition_signature: [0; 16],
-                            signature_type: 0,
-                        },
-                        HardDiskDevicePathProtocol {
-                            device_path: DevicePathProtocol {
-                                r#type: r_efi::protocols::device_path::TYPE_END,
-                                sub_type: 0xff, // End of full path
-                                length: [4, 0],
-                            },
-                            partition_number: 0,
-                            partition_format: 0x0,
-                            partition_start: 0,
-                            partition_size: 0,
-                            partition_signature: [0; 16],
-                            signature_type: 0,
-                        },
-                    ]
-                } else {
-                    [
-                        HardDiskDevicePathProtocol {
-                            device_path: DevicePathProtocol {
-                                r#type: r_efi::protocols::device_path::TYPE_MEDIA,
-                                sub_type: 1,
-                                length: [42, 0],
-                            },
-                            partition_number,
-                            partition_format: 0x02, // GPT
-                            partition_start: start_lba,
-                            partition_size: last_lba - start_lba + 1,
-                            partition_signature: uuid,
-                            signature_type: 0x02,
-                        },
-                        HardDiskDevicePathProtocol {
-                            device_path: DevicePathProtocol {
-                                r#type: r_efi::protocols::device_path::TYPE_END,
-                                sub_type: 0xff, // End of full path
-                                length: [4, 0],
-                            },
-                            partition_number: 0,
-                            partition_format: 0x0,
-                            partition_start: 0,
-                            partition_size: 0,
-                            partition_signature: [0; 16],
-                            signature_type: 0,
-                        },
-                    ]
-                },
-            };
-
+            *bw = BlockWrapper::create(block, partition);
             (*bw).proto.media = &(*bw).media;
         }
         bw
     }
+
+    // The wrapper, but for the protocol's pointer to the media, which can
+    // only be set once it is where it stays
+    fn create(
+        block: *const crate::block::CachedBlock<'a, dyn crate::block::BlockDevice + 'a>,
+        partition: Option<&HardDrive>,
+    ) -> BlockWrapper<'a> {
+        let (last_block, read_only) = unsafe {
+            let device = (*block).device();
+            (device.get_capacity() - 1, device.is_read_only())
+        };
+        // Partitions are presented as disks of their own
+        let (start_lba, last_block) = match partition {
+            None => (0, last_block),
+            Some(p) => (p.first_lba, p.last_lba - p.first_lba),
+        };
+
+        BlockWrapper {
+            hw: super::HandleWrapper {
+                handle_type: super::HandleType::Block,
+            },
+            block,
+            media: BlockIoMedia {
+                media_id: 0,
+                removable_media: false,
+                media_present: true,
+                logical_partition: partition.is_some(),
+                read_only,
+                write_caching: false,
+                block_size: 512,
+                io_align: 0,
+                last_block,
+            },
+            proto: BlockIoProtocol {
+                revision: 0x0001_0000, // EFI_BLOCK_IO_PROTOCOL_REVISION
+                media: core::ptr::null(),
+                reset,
+                read_blocks,
+                write_blocks,
+                flush_blocks,
+            },
+            disk_io: DiskIoProtocol {
+                revision: 0x0001_0000, // EFI_DISK_IO_PROTOCOL_REVISION
+                read_disk,
+                write_disk,
+            },
+            start_lba,
+            controller_path: ControllerDevicePathProtocol {
+                device_path: DevicePathProtocol {
+                    r#type: 1,
+                    sub_type: 5,
+                    length: [8, 0],
+                },
+                controller: 0,
+            },
+            // full disk vs partition
+            disk_paths: match partition {
+                None => [end_of_path(), end_of_path()],
+                Some(p) => [hard_disk_path(p), end_of_path()],
+            },
+        }
+    }
 }
 
+fn end_of_path() -> HardDiskDevicePathProtocol {
+    HardDiskDevicePathProtocol {
+        device_path: DevicePathProtocol {
+            r#type: r_efi::protocols::device_path::TYPE_END,
+            sub_type: 0xff, // End of full path
+            length: [4, 0],
+        },
+        partition_number: 0,
+        partition_format: 0x0,
+        partition_start: 0,
+        partition_size: 0,
+        partition_signature: [0; 16],
+        signature_type: 0,
+    }
+}
+
+// The LBAs are in the 512 byte blocks the Block I/O protocol gives
+fn hard_disk_path(p: &HardDrive) -> HardDiskDevicePathProtocol {
+    let (partition_format, signature_type, partition_signature) = match p.signature {
+        PartitionSignature::Mbr(signature) => {
+            let mut partition_signature = [0; 16];
+            partition_signature[..4].copy_from_slice(&signature.to_le_bytes());
+            (0x01, 0x01, partition_signature)
+        }
+        PartitionSignature::Gpt(guid) => (0x02, 0x02, guid),
+    };
+    HardDiskDevicePathProtocol {
+        device_path: DevicePathProtocol {
+            r#type: r_efi::protocols::device_path::TYPE_MEDIA,
+            sub_type: 1,
+            length: [42, 0],
+        },
+        partition_number: p.number,
+        partition_start: p.first_lba,
+        partition_size: p.last_lba - p.first_lba + 1,
+        partition_signature,
+        partition_format,
+        signature_type,
+    }
+}
+
+// Returns the id of the partition starting at boot_lba, the one that was
+// booted from
 #[allow(clippy::transmute_ptr_to_ptr)]
 pub fn populate_block_wrappers(
     wrappers: &mut BlockWrappers,
-    block: *const crate::block::VirtioBlockDevice,
+    block: *const crate::block::CachedBlock<'_, dyn crate::block::BlockDevice + '_>,
+    boot_lba: u64,
 ) -> Option<u32> {
-    let mut parts: [crate::part::PartitionEntry; 16] = unsafe { core::mem::zeroed() };
+    let mut parts = [HardDrive {
+        number: 0,
+        first_lba: 0,
+        last_lba: 0,
+        signature: PartitionSignature::Gpt([0; 16]),
+    }; 16];
 
-    wrappers.wrappers[0] =
-        BlockWrapper::new(unsafe { core::mem::transmute(block) }, 0, 0, 0, [0; 16]);
+    wrappers.wrappers[0] = BlockWrapper::new(unsafe { core::mem::transmute(block) }, None);
 
     let mut efi_part_id = None;
-    let part_count = crate::part::get_partitions(unsafe { &*block }, &mut parts).unwrap();
+    let part_count = match crate::part::get_hard_drives(unsafe { &*block }, &mut parts) {
+        Ok(part_count) => part_count,
+        Err(e) => {
+            log!("Error reading partitions: {:?}", e);
+            0
+        }
+    };
     for i in 0..part_count {
-        let p = parts[i as usize];
-        wrappers.wrappers[i as usize + 1] = BlockWrapper::new(
-            unsafe { core::mem::transmute(block) },
-            i + 1,
-            p.first_lba,
-            p.last_lba,
-            p.guid,
-        );
-        if p.is_efi_partition() {
+        let p = &parts[i as usize];
+        wrappers.wrappers[i as usize + 1] =
+            BlockWrapper::new(unsafe { core::mem::transmute(block) }, Some(p));
+        if p.first_lba == boot_lba {
             efi_part_id = Some(i + 1);
         }
     }
     wrappers.count = part_count as usize + 1;
     efi_part_id
 }
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::null_mut;
+
+    use r_efi::efi::Status;
+
+    use super::{disk_io, hard_disk_path, BlockWrapper};
+    use crate::block::{BlockDevice, CachedBlock, SectorRead};
+    use crate::part::tests::{gpt_disk, mbr_disk, MemDisk};
+
+    // Eight blocks, each byte telling where it is
+    fn pattern_disk() -> MemDisk {
+        MemDisk::new((0..8 * 512).map(|i| (i % 251) as u8).collect())
+    }
+
+    fn disk_contents(disk: &MemDisk) -> Vec<u8> {
+        let mut data = vec![0; disk.len() as usize * 512];
+        for (lba, block) in data.chunks_mut(512).enumerate() {
+            disk.read(lba as u64, block).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_disk_io_unaligned() {
+        let disk = pattern_disk();
+        let expected = disk_contents(&disk);
+        let device: &dyn BlockDevice = &disk;
+        let block = CachedBlock::new(device);
+        let wrapper = BlockWrapper::create(&block, None);
+
+        // Starting and ending part way through blocks, and within one block
+        for (offset, size) in &[(500, 600), (1, 1), (512, 1024), (100, 7 * 512 + 400)] {
+            let mut buffer = vec![0u8; *size];
+            assert_eq!(
+                disk_io(
+                    &wrapper,
+                    0,
+                    *offset,
+                    *size,
+                    buffer.as_mut_ptr() as *mut _,
+                    false
+                ),
+                Status::SUCCESS
+            );
+            assert_eq!(
+                buffer[..],
+                expected[*offset as usize..*offset as usize + size]
+            );
+        }
+
+        // What is around what is written is left alone
+        let mut expected = expected;
+        for (offset, size) in &[(510, 516), (2048, 512), (3000, 3)] {
+            let mut buffer = vec![0xaau8; *size];
+            assert_eq!(
+                disk_io(
+                    &wrapper,
+                    0,
+                    *offset,
+                    *size,
+                    buffer.as_mut_ptr() as *mut _,
+                    true
+                ),
+                Status::SUCCESS
+            );
+            expected[*offset as usize..*offset as usize + size].fill(0xaa);
+            assert_eq!(disk_contents(&disk), expected);
+        }
+
+        // Reads see what was written, rather than what was cached before
+        let mut buffer = [0u8; 4];
+        assert_eq!(
+            disk_io(&wrapper, 0, 508, 4, buffer.as_mut_ptr() as *mut _, false),
+            Status::SUCCESS
+        );
+        assert_eq!(buffer, [expected[508], expected[509], 0xaa, 0xaa]);
+    }
+
+    #[test]
+    fn test_disk_io_bounds() {
+        let disk = pattern_disk();
+        let device: &dyn BlockDevice = &disk;
+        let block = CachedBlock::new(device);
+        let wrapper = BlockWrapper::create(&block, None);
+        let mut buffer = [0u8; 1024];
+        let buffer = buffer.as_mut_ptr() as *mut _;
+
+        let size = 8 * 512;
+        for (media_id, offset, length, status) in &[
+            // Up to the end of the disk, and nothing at all from anywhere
+            (0, size - 1024, 1024, Status::SUCCESS),
+            (0, size, 0, Status::SUCCESS),
+            (0, size + 1, 0, Status::SUCCESS),
+            // Past it
+            (0, size - 1023, 1024, Status::INVALID_PARAMETER),
+            (0, size + 1, 1, Status::INVALID_PARAMETER),
+            (0, u64::MAX, 2, Status::INVALID_PARAMETER),
+            (1, 0, 1, Status::MEDIA_CHANGED),
+        ] {
+            for write in &[false, true] {
+                assert_eq!(
+                    disk_io(&wrapper, *media_id, *offset, *length, buffer, *write),
+                    *status
+                );
+            }
+        }
+        assert_eq!(
+            disk_io(&wrapper, 0, 0, 1, null_mut(), false),
+            Status::INVALID_PARAMETER
+        );
+    }
+
+    // Offsets are from the start of the partition, which is as big as the
+    // disk can be
+    #[test]
+    fn test_disk_io_partition() {
+        let disk = pattern_disk();
+        let expected = disk_contents(&disk);
+        let device: &dyn BlockDevice = &disk;
+        let block = CachedBlock::new(device);
+        let partition = crate::part::HardDrive {
+            number: 1,
+            first_lba: 2,
+            last_lba: 5,
+            signature: crate::part::PartitionSignature::Mbr(0),
+        };
+        let wrapper = BlockWrapper::create(&block, Some(&partition));
+
+        let mut buffer = [0u8; 600];
+        assert_eq!(
+            disk_io(&wrapper, 0, 10, 600, buffer.as_mut_ptr() as *mut _, false),
+            Status::SUCCESS
+        );
+        assert_eq!(buffer[..], expected[1024 + 10..1024 + 610]);
+        assert_eq!(
+            disk_io(
+                &wrapper,
+                0,
+                4 * 512 - 1,
+                2,
+                buffer.as_mut_ptr() as *mut _,
+                false
+            ),
+            Status::INVALID_PARAMETER
+        );
+    }
+
+    fn hard_drives(disk: &MemDisk) -> Vec<crate::part::HardDrive> {
+        let mut drives = [crate::part::HardDrive {
+            number: 0,
+            first_lba: 0,
+            last_lba: 0,
+            signature: crate::part::PartitionSignature::Mbr(0),
+        }; 16];
+        let count = crate::part::get_hard_drives(disk, &mut drives).unwrap();
+        drives[..count as usize].to_vec()
+    }
+
+    #[test]
+    fn test_hard_disk_path() {
+        // The second entry is unused, so the ESP is the third partition
+        let parts: [([u8; 16], u64, &[u16]); 3] = [
+            ([0x11; 16], 64, &[]),
+            ([0; 16], 0, &[]),
+            ([0x22; 16], 128, &[]),
+        ];
+        let drives = hard_drives(&MemDisk::new(gpt_disk(&parts)));
+        assert_eq!(drives.len(), 2);
+        let node = hard_disk_path(&drives[1]);
+        assert_eq!(node.device_path.r#type, 4);
+        assert_eq!(node.device_path.sub_type, 1);
+        assert_eq!(node.device_path.length, [42, 0]);
+        assert_eq!({ node.partition_number }, 3);
+        assert_eq!({ node.partition_start }, 128);
+        assert_eq!({ node.partition_size }, 8);
+        // The partition's own GUID, as the entry has it
+        let mut guid = [0; 16];
+        guid[0] = 3;
+        assert_eq!(node.partition_signature, guid);
+        assert_eq!(node.partition_format, 0x02);
+        assert_eq!(node.signature_type, 0x02);
+
+        // MBR partitions by their entry, with the disk signature
+        let drives = hard_drives(&mbr_disk(&[(0, 0, 0), (0xef, 4096, 2048)]));
+        let node = hard_disk_path(&drives[0]);
+        assert_eq!({ node.partition_number }, 2);
+        assert_eq!({ node.partition_start }, 4096);
+        assert_eq!({ node.partition_size }, 2048);
+        assert_eq!(node.partition_signature[..4], 0x1234_5678u32.to_le_bytes());
+        assert_eq!(node.partition_signature[4..], [0; 12]);
+        assert_eq!(node.partition_format, 0x01);
+        assert_eq!(node.signature_type, 0x01);
+    }
+}
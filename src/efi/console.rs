@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::ffi::c_void;
+
+use atomic_refcell::AtomicRefCell;
 use r_efi::{
     efi::{Boolean, Char16, Event, Handle, Status},
     protocols::{
@@ -32,15 +35,179 @@ pub const STDERR_HANDLE: Handle = &HandleWrapper {
     handle_type: HandleType::None,
 } as *const _ as Handle;
 
+// Scan codes of the keys without a character
+const SCAN_NULL: u16 = 0x00;
+const SCAN_UP: u16 = 0x01;
+const SCAN_DOWN: u16 = 0x02;
+const SCAN_RIGHT: u16 = 0x03;
+const SCAN_LEFT: u16 = 0x04;
+const SCAN_HOME: u16 = 0x05;
+const SCAN_END: u16 = 0x06;
+const SCAN_INSERT: u16 = 0x07;
+const SCAN_DELETE: u16 = 0x08;
+const SCAN_PAGE_UP: u16 = 0x09;
+const SCAN_PAGE_DOWN: u16 = 0x0a;
+const SCAN_ESC: u16 = 0x17;
+
+const CHAR_BACKSPACE: Char16 = 0x08;
+const CHAR_CARRIAGE_RETURN: Char16 = 0x0d;
+
+const ESC: u8 = 0x1b;
+// How long the rest of an escape sequence can take to come in before the ESC
+// is taken as a key press of its own
+const ESCAPE_TIMEOUT_NS: u64 = 50_000_000;
+
+enum Decoded {
+    // A key and the number of bytes it took
+    Key(InputKey, usize),
+    // An escape sequence that could still be completed
+    Partial,
+    Empty,
+}
+
+fn key(scan_code: u16, unicode_char: Char16) -> InputKey {
+    InputKey {
+        scan_code,
+        unicode_char,
+    }
+}
+
+// Turns what a terminal sends into EFI keys, the escape sequences being the
+// VT100 ones for the cursor and editing keys. The ESC of any other sequence
+// is a key of its own, followed by the rest as characters.
+fn decode(bytes: &[u8]) -> Decoded {
+    let first = match bytes.first() {
+        Some(b) => *b,
+        None => return Decoded::Empty,
+    };
+    if first != ESC {
+        let c = match first {
+            b'\n' => CHAR_CARRIAGE_RETURN,
+            0x7f => CHAR_BACKSPACE,
+            c => Char16::from(c),
+        };
+        return Decoded::Key(key(SCAN_NULL, c), 1);
+    }
+
+    // ESC O is what the cursor keys send in application mode
+    match bytes.get(1) {
+        Some(b'[') | Some(b'O') => {}
+        Some(_) => return Decoded::Key(key(SCAN_ESC, 0), 1),
+        None => return Decoded::Partial,
+    }
+    let scan_code = match bytes.get(2) {
+        Some(b'A') => SCAN_UP,
+        Some(b'B') => SCAN_DOWN,
+        Some(b'C') => SCAN_RIGHT,
+        Some(b'D') => SCAN_LEFT,
+        Some(b'H') => SCAN_HOME,
+        Some(b'F') => SCAN_END,
+        // ESC [ n ~
+        Some(n @ b'1'..=b'6') if bytes[1] == b'[' => {
+            match bytes.get(3) {
+                Some(b'~') => {}
+                Some(_) => return Decoded::Key(key(SCAN_ESC, 0), 1),
+                None => return Decoded::Partial,
+            }
+            let scan_code = match n {
+                b'1' => SCAN_HOME,
+                b'2' => SCAN_INSERT,
+                b'3' => SCAN_DELETE,
+                b'4' => SCAN_END,
+                b'5' => SCAN_PAGE_UP,
+                _ => SCAN_PAGE_DOWN,
+            };
+            return Decoded::Key(key(scan_code, 0), 4);
+        }
+        Some(_) => return Decoded::Key(key(SCAN_ESC, 0), 1),
+        None => return Decoded::Partial,
+    };
+    Decoded::Key(key(scan_code, 0), 3)
+}
+
+// Bytes from the serial port that haven't been read as keys yet
+struct Input {
+    bytes: [u8; 8],
+    len: usize,
+    // When the escape sequence at the start of bytes was first seen
+    escape_start: Option<u64>,
+}
+
+impl Input {
+    const fn new() -> Input {
+        Input {
+            bytes: [0; 8],
+            len: 0,
+            escape_start: None,
+        }
+    }
+
+    // The next key, which is only taken off the input if consume is set
+    fn next_key(&mut self, consume: bool) -> Option<InputKey> {
+        while self.len < self.bytes.len() {
+            match crate::serial::read_byte() {
+                Some(b) => {
+                    self.bytes[self.len] = b;
+                    self.len += 1;
+                }
+                None => break,
+            }
+        }
+
+        let (key, used) = match decode(&self.bytes[..self.len]) {
+            Decoded::Key(key, used) => (key, used),
+            Decoded::Partial => {
+                let now = crate::delay::now_ns();
+                match self.escape_start {
+                    Some(start) if now - start >= ESCAPE_TIMEOUT_NS => (key(SCAN_ESC, 0), 1),
+                    Some(_) => return None,
+                    None => {
+                        self.escape_start = Some(now);
+                        return None;
+                    }
+                }
+            }
+            Decoded::Empty => return None,
+        };
+        if consume {
+            self.bytes.copy_within(used..self.len, 0);
+            self.len -= used;
+            self.escape_start = None;
+        }
+        Some(key)
+    }
+}
+
+static INPUT: AtomicRefCell<Input> = AtomicRefCell::new(Input::new());
+
 pub extern "win64" fn stdin_reset(_: *mut SimpleTextInputProtocol, _: Boolean) -> Status {
-    Status::UNSUPPORTED
+    while crate::serial::read_byte().is_some() {}
+    *INPUT.borrow_mut() = Input::new();
+    Status::SUCCESS
 }
 
 pub extern "win64" fn stdin_read_key_stroke(
     _: *mut SimpleTextInputProtocol,
-    _: *mut InputKey,
+    key: *mut InputKey,
 ) -> Status {
-    Status::NOT_READY
+    if key.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    match INPUT.borrow_mut().next_key(true) {
+        Some(k) => {
+            unsafe { *key = k };
+            Status::SUCCESS
+        }
+        None => Status::NOT_READY,
+    }
+}
+
+// The notification function of the WaitForKey event, which is run when the
+// event is checked or waited on
+pub extern "win64" fn stdin_wait_for_key(event: Event, _: *mut c_void) {
+    if INPUT.borrow_mut().next_key(false).is_some() {
+        super::signal_event(event);
+    }
 }
 
 pub extern "win64" fn stdout_reset(_: *mut SimpleTextOutputProtocol, _: Boolean) -> Status {
@@ -155,3 +322,59 @@ pub const STDOUT: SimpleTextOutputProtocol = SimpleTextOutputProtocol {
     enable_cursor: stdout_enable_cursor,
     mode: &STDOUT_OUTPUT_MODE as *const SimpleTextOutputMode as *mut SimpleTextOutputMode,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, Decoded};
+
+    // The scan codes and characters of the keys in bytes
+    fn keys(bytes: &[u8]) -> Vec<(u16, u16)> {
+        let mut keys = Vec::new();
+        let mut bytes = bytes;
+        while let Decoded::Key(key, used) = decode(bytes) {
+            keys.push((key.scan_code, key.unicode_char));
+            bytes = &bytes[used..];
+        }
+        keys
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(keys(b"a1 "), [(0, 0x61), (0, 0x31), (0, 0x20)]);
+        // Enter and backspace, however the terminal sends them
+        assert_eq!(
+            keys(b"\r\n\x08\x7f"),
+            [(0, 0x0d), (0, 0x0d), (0, 0x08), (0, 0x08)]
+        );
+        assert_eq!(
+            keys(b"\x1b[A\x1b[B\x1b[C\x1b[D\x1bOA\x1b[H\x1b[4~"),
+            [
+                (0x01, 0),
+                (0x02, 0),
+                (0x03, 0),
+                (0x04, 0),
+                (0x01, 0),
+                (0x05, 0),
+                (0x06, 0)
+            ]
+        );
+        assert_eq!(
+            keys(b"\x1b[2~\x1b[3~\x1b[5~\x1b[6~"),
+            [(0x07, 0), (0x08, 0), (0x09, 0), (0x0a, 0)]
+        );
+        // An ESC that isn't starting a sequence, and a sequence that isn't known
+        assert_eq!(
+            keys(b"\x1bx\x1b[Z"),
+            [(0x17, 0), (0, 0x78), (0x17, 0), (0, 0x5b), (0, 0x5a)]
+        );
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        assert!(matches!(decode(b""), Decoded::Empty));
+        for bytes in &[&b"\x1b"[..], b"\x1b[", b"\x1bO", b"\x1b[3"] {
+            assert!(matches!(decode(bytes), Decoded::Partial));
+        }
+        assert!(matches!(decode(b"\x1b[3~"), Decoded::Key(_, 4)));
+    }
+}
@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The Device Path To Text protocol, which loaders such as GRUB use to show
+// device paths. The nodes the firmware makes are written in the forms the
+// UEFI specification gives them, anything else as Path(type,subtype,data).
+
+#[cfg(not(test))]
+extern crate alloc;
+
+#[cfg(not(test))]
+use alloc::vec::Vec;
+
+use core::{ffi::c_void, fmt::Write};
+
+use r_efi::{
+    efi::{self, Boolean, Char16, Status},
+    protocols::{
+        device_path::{self, Protocol as DevicePathProtocol},
+        device_path_to_text,
+    },
+};
+
+pub const PROTOCOL_GUID: efi::Guid = device_path_to_text::PROTOCOL_GUID;
+
+pub static mut PROTOCOL: device_path_to_text::Protocol = device_path_to_text::Protocol {
+    convert_device_node_to_text,
+    convert_device_path_to_text,
+};
+
+const SUB_TYPE_END_INSTANCE: u8 = 0x01;
+const SUB_TYPE_END_ENTIRE: u8 = 0xff;
+
+// EISA IDs of the PCI and PCI Express root bridges
+const PNP0A03: u32 = 0x0a03_41d0;
+const PNP0A08: u32 = 0x0a08_41d0;
+
+// UCS-2 text, as it's handed back
+struct Text(Vec<u16>);
+
+impl Write for Text {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.extend(s.encode_utf16());
+        Ok(())
+    }
+}
+
+fn le_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn le_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn le_u64(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+// With its first three fields little-endian, as GPT keeps them
+fn write_guid(text: &mut Text, guid: &[u8]) -> core::fmt::Result {
+    write!(
+        text,
+        "{:08x}-{:04x}-{:04x}-",
+        le_u32(&guid[0..4]),
+        le_u16(&guid[4..6]),
+        le_u16(&guid[6..8])
+    )?;
+    for (i, b) in guid[8..16].iter().enumerate() {
+        if i == 2 {
+            text.write_char('-')?;
+        }
+        write!(text, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
+// A node from its type, subtype and what follows the header
+fn write_node(text: &mut Text, kind: u8, sub_type: u8, data: &[u8]) -> core::fmt::Result {
+    match (kind, sub_type) {
+        (device_path::TYPE_HARDWARE, 1) if data.len() >= 2 => {
+            write!(text, "Pci(0x{:x},0x{:x})", data[1], data[0])
+        }
+        (device_path::TYPE_HARDWARE, 5) if data.len() >= 4 => {
+            write!(text, "Ctrl(0x{:x})", le_u32(data))
+        }
+        (device_path::TYPE_ACPI, 1) if data.len() >= 8 => {
+            let (hid, uid) = (le_u32(&data[0..4]), le_u32(&data[4..8]));
+            match hid {
+                PNP0A03 => write!(text, "PciRoot(0x{:x})", uid),
+                PNP0A08 => write!(text, "PcieRoot(0x{:x})", uid),
+                _ if hid & 0xffff == 0x41d0 => {
+                    write!(text, "Acpi(PNP{:04X},0x{:x})", hid >> 16, uid)
+                }
+                _ => write!(text, "Acpi(0x{:08x},0x{:x})", hid, uid),
+            }
+        }
+        // Only Ethernet and IEEE 802 addresses fill less than the field
+        (device_path::TYPE_MESSAGING, 11) if data.len() >= 33 => {
+            let if_type = data[32];
+            let size = if if_type <= 1 { 6 } else { 32 };
+            text.write_str("MAC(")?;
+            for b in &data[..size] {
+                write!(text, "{:02x}", b)?;
+            }
+            write!(text, ",0x{:x})", if_type)
+        }
+        (device_path::TYPE_MEDIA, 1) if data.len() >= 38 => {
+            write!(text, "HD({},", le_u32(&data[0..4]))?;
+            match data[37] {
+                0x01 => write!(text, "MBR,0x{:08x},", le_u32(&data[20..24]))?,
+                0x02 => {
+                    text.write_str("GPT,")?;
+                    write_guid(text, &data[20..36])?;
+                    text.write_char(',')?;
+                }
+                signature_type => write!(text, "{},0,", signature_type)?,
+            }
+            write!(
+                text,
+                "0x{:x},0x{:x})",
+                le_u64(&data[4..12]),
+                le_u64(&data[12..20])
+            )
+        }
+        // The name as it is, up to any NUL
+        (device_path::TYPE_MEDIA, 4) => {
+            text.0
+                .extend(data.chunks_exact(2).map(le_u16).take_while(|c| *c != 0));
+            Ok(())
+        }
+        _ => {
+            write!(text, "Path({},{},", kind, sub_type)?;
+            for b in data {
+                write!(text, "{:02x}", b)?;
+            }
+            text.write_char(')')
+        }
+    }
+}
+
+// The header of the node at the start of path, and its length if it fits
+fn node_header(path: &[u8]) -> Option<(u8, u8, usize)> {
+    let header = path.get(..4)?;
+    let length = usize::from(le_u16(&header[2..4]));
+    if length < 4 || length > path.len() {
+        return None;
+    }
+    Some((header[0], header[1], length))
+}
+
+fn node_text(node: &[u8]) -> Vec<u16> {
+    let mut text = Text(Vec::new());
+    if let Some((kind, sub_type, length)) = node_header(node) {
+        write_node(&mut text, kind, sub_type, &node[4..length]).unwrap();
+    }
+    text.0
+}
+
+// The nodes are separated by '/' and instances by ',', with the path ending
+// at its end node or at anything that doesn't fit
+fn path_text(path: &[u8]) -> Vec<u16> {
+    let mut text = Text(Vec::new());
+    let mut offset = 0;
+    let mut separator = None;
+    while let Some((kind, sub_type, length)) = node_header(&path[offset..]) {
+        if kind == device_path::TYPE_END {
+            if sub_type != SUB_TYPE_END_INSTANCE {
+                break;
+            }
+            separator = Some(',');
+        } else {
+            if let Some(c) = separator {
+                text.write_char(c).unwrap();
+            }
+            write_node(
+                &mut text,
+                kind,
+                sub_type,
+                &path[offset + 4..offset + length],
+            )
+            .unwrap();
+            separator = Some('/');
+        }
+        offset += length;
+    }
+    text.0
+}
+
+// How much of path the nodes of prefix, a whole path of its own, take up if
+// path starts with them
+pub fn prefix_length(path: &[u8], prefix: &[u8]) -> Option<usize> {
+    let mut length = 0;
+    while let Some((kind, _, node_length)) = node_header(&prefix[length..]) {
+        if kind == device_path::TYPE_END {
+            break;
+        }
+        length += node_length;
+    }
+    if path.get(..length)? == &prefix[..length] {
+        Some(length)
+    } else {
+        None
+    }
+}
+
+// The bytes of the node, or of the whole path with its end node
+unsafe fn node_bytes<'a>(node: *const DevicePathProtocol) -> &'a [u8] {
+    let length = core::cmp::max(usize::from(u16::from_le_bytes((*node).length)), 4);
+    core::slice::from_raw_parts(node as *const u8, length)
+}
+
+pub unsafe fn path_bytes<'a>(path: *const DevicePathProtocol) -> &'a [u8] {
+    let mut length = 0;
+    loop {
+        let node = node_bytes((path as *const u8).add(length) as *const DevicePathProtocol);
+        length += node.len();
+        if node[0] == device_path::TYPE_END && node[1] == SUB_TYPE_END_ENTIRE {
+            break;
+        }
+    }
+    core::slice::from_raw_parts(path as *const u8, length)
+}
+
+// NUL terminated, in pool memory that the caller frees
+fn allocate_text(text: &[u16]) -> *mut Char16 {
+    let mut out = core::ptr::null_mut();
+    let status = super::allocate_pool(
+        efi::BOOT_SERVICES_DATA,
+        (text.len() + 1) * 2,
+        &mut out as *mut *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        return core::ptr::null_mut();
+    }
+    let out = out as *mut Char16;
+    unsafe {
+        core::ptr::copy_nonoverlapping(text.as_ptr(), out, text.len());
+        *out.add(text.len()) = 0;
+    }
+    out
+}
+
+// The short forms are always used, whether or not display only text or
+// shortcuts are asked for
+pub extern "win64" fn convert_device_node_to_text(
+    node: *mut DevicePathProtocol,
+    _: Boolean,
+    _: Boolean,
+) -> *mut Char16 {
+    if node.is_null() {
+        return core::ptr::null_mut();
+    }
+    allocate_text(&node_text(unsafe { node_bytes(node) }))
+}
+
+pub extern "win64" fn convert_device_path_to_text(
+    path: *mut DevicePathProtocol,
+    _: Boolean,
+    _: Boolean,
+) -> *mut Char16 {
+    if path.is_null() {
+        return core::ptr::null_mut();
+    }
+    allocate_text(&path_text(unsafe { path_bytes(path) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{node_text, path_text, prefix_length};
+
+    fn text(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn test_path_text() {
+        let mut path = vec![4, 1, 42, 0];
+        path.extend_from_slice(&1u32.to_le_bytes());
+        path.extend_from_slice(&0x800u64.to_le_bytes());
+        path.extend_from_slice(&0x10_0000u64.to_le_bytes());
+        path.extend_from_slice(&[
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ]);
+        path.extend_from_slice(&[0x02, 0x02]);
+        let name = "\\EFI\\BOOT\\BOOTX64.EFI";
+        path.extend_from_slice(&[4, 4, (4 + 2 * (name.len() + 1)) as u8, 0]);
+        for c in name.encode_utf16().chain(Some(0)) {
+            path.extend_from_slice(&c.to_le_bytes());
+        }
+        path.extend_from_slice(&[0x7f, 0xff, 4, 0]);
+
+        assert_eq!(
+            path_text(&path),
+            text(
+                "HD(1,GPT,c12a7328-f81f-11d2-ba4b-00a0c93ec93b,0x800,0x100000)/\\EFI\\BOOT\\BOOTX64.EFI"
+            )
+        );
+        assert_eq!(node_text(&path[42..]), text(name));
+
+        // Another instance, and nothing after the end
+        let mut paths = vec![2, 1, 12, 0, 0xd0, 0x41, 0x03, 0x0a, 0, 0, 0, 0];
+        paths.extend_from_slice(&[1, 1, 6, 0, 1, 2]);
+        paths.extend_from_slice(&[0x7f, 0x01, 4, 0]);
+        paths.extend_from_slice(&[1, 5, 8, 0, 0, 0, 0, 0]);
+        paths.extend_from_slice(&[0x7f, 0xff, 4, 0]);
+        paths.extend_from_slice(&path);
+        assert_eq!(
+            path_text(&paths),
+            text("PciRoot(0x0)/Pci(0x2,0x1),Ctrl(0x0)")
+        );
+
+        // Unknown nodes are written out raw, and ones too short dropped
+        assert_eq!(
+            node_text(&[3, 0x7e, 6, 0, 0xab, 0xcd]),
+            text("Path(3,126,abcd)")
+        );
+        assert_eq!(path_text(&[1, 1, 8, 0, 1]), text(""));
+    }
+
+    #[test]
+    fn test_prefix_length() {
+        let root = [2, 1, 12, 0, 0xd0, 0x41, 0x03, 0x0a, 0, 0, 0, 0];
+        let pci = [1, 1, 6, 0, 0, 3];
+        let end = [0x7f, 0xff, 4, 0];
+        let disk = [&root[..], &pci, &end].concat();
+        let file = [&root[..], &pci, &[4, 4, 6, 0, 0x41, 0], &end].concat();
+
+        assert_eq!(prefix_length(&file, &disk), Some(18));
+        assert_eq!(prefix_length(&disk, &disk), Some(18));
+        assert_eq!(prefix_length(&disk, &file), None);
+        assert_eq!(prefix_length(&file, &end), Some(0));
+
+        let other = [&root[..], &[1, 1, 6, 0, 0, 4], &end].concat();
+        assert_eq!(prefix_length(&file, &other), None);
+    }
+}
@@ -0,0 +1,566 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::ffi::c_void;
+
+use atomic_refcell::AtomicRefCell;
+use r_efi::efi::{self, Event, EventNotify, Status, TimerDelay, Tpl};
+
+const MAX_EVENTS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Timer {
+    None,
+    Relative,
+    // With the period in 100ns units
+    Periodic(u64),
+}
+
+#[derive(Clone, Copy)]
+struct Descriptor {
+    in_use: bool,
+    event_type: u32,
+    tpl: Tpl,
+    notify: Option<EventNotify>,
+    context: usize,
+    signaled: bool,
+    notify_pending: bool,
+    timer: Timer,
+    // When the timer next fires, in 100ns units
+    deadline: u64,
+}
+
+impl Descriptor {
+    const EMPTY: Descriptor = Descriptor {
+        in_use: false,
+        event_type: 0,
+        tpl: 0,
+        notify: None,
+        context: 0,
+        signaled: false,
+        notify_pending: false,
+        timer: Timer::None,
+        deadline: 0,
+    };
+}
+
+// A notification function to call, taken off the queue by next_notification()
+// which raises the TPL to that of the event until the function returns
+pub struct Notification {
+    notify: EventNotify,
+    event: Event,
+    context: *mut c_void,
+    previous_tpl: Tpl,
+}
+
+// There is no preemption: timers are checked whenever an application calls
+// back into the firmware and notification functions run from those calls.
+// Notifications at or below the current TPL wait until it is lowered.
+pub struct Events {
+    events: [Descriptor; MAX_EVENTS],
+    tpl: Tpl,
+    // When the watchdog timer expires, in 100ns units
+    watchdog: Option<u64>,
+}
+
+// Event handles are the index into the table plus one, so that they are
+// never null
+fn handle(index: usize) -> Event {
+    (index + 1) as Event
+}
+
+impl Events {
+    pub const fn new() -> Self {
+        Self {
+            events: [Descriptor::EMPTY; MAX_EVENTS],
+            tpl: efi::TPL_APPLICATION,
+            watchdog: None,
+        }
+    }
+
+    pub fn tpl(&self) -> Tpl {
+        self.tpl
+    }
+
+    // Returns the previous TPL. Lowering the TPL this way isn't allowed so
+    // such requests are ignored.
+    pub fn raise_tpl(&mut self, tpl: Tpl) -> Tpl {
+        let previous = self.tpl;
+        if tpl > previous {
+            self.tpl = tpl;
+        }
+        previous
+    }
+
+    // Pending notifications above the restored TPL can then be dispatched
+    pub fn restore_tpl(&mut self, tpl: Tpl) {
+        if tpl < self.tpl {
+            self.tpl = tpl;
+        }
+    }
+
+    fn find(&self, event: Event) -> Option<usize> {
+        let index = (event as usize).checked_sub(1)?;
+        match self.events.get(index) {
+            Some(d) if d.in_use => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn create(
+        &mut self,
+        event_type: u32,
+        tpl: Tpl,
+        notify: Option<EventNotify>,
+        context: *mut c_void,
+    ) -> Result<Event, Status> {
+        const KNOWN_TYPES: u32 = efi::EVT_TIMER
+            | efi::EVT_RUNTIME
+            | efi::EVT_NOTIFY_WAIT
+            | efi::EVT_NOTIFY_SIGNAL
+            | efi::EVT_SIGNAL_EXIT_BOOT_SERVICES
+            | efi::EVT_SIGNAL_VIRTUAL_ADDRESS_CHANGE;
+
+        if event_type & !KNOWN_TYPES != 0 {
+            return Err(Status::INVALID_PARAMETER);
+        }
+        let notify_types = event_type & (efi::EVT_NOTIFY_WAIT | efi::EVT_NOTIFY_SIGNAL);
+        if notify_types == efi::EVT_NOTIFY_WAIT | efi::EVT_NOTIFY_SIGNAL {
+            return Err(Status::INVALID_PARAMETER);
+        }
+        if notify_types != 0
+            && (notify.is_none() || tpl <= efi::TPL_APPLICATION || tpl > efi::TPL_HIGH_LEVEL)
+        {
+            return Err(Status::INVALID_PARAMETER);
+        }
+
+        let index = match self.events.iter().position(|d| !d.in_use) {
+            Some(index) => index,
+            None => return Err(Status::OUT_OF_RESOURCES),
+        };
+        self.events[index] = Descriptor {
+            in_use: true,
+            event_type,
+            tpl,
+            notify: if notify_types != 0 { notify } else { None },
+            context: context as usize,
+            ..Descriptor::EMPTY
+        };
+        Ok(handle(index))
+    }
+
+    pub fn close(&mut self, event: Event) -> Status {
+        match self.find(event) {
+            Some(index) => {
+                self.events[index] = Descriptor::EMPTY;
+                Status::SUCCESS
+            }
+            None => Status::INVALID_PARAMETER,
+        }
+    }
+
+    fn signal_index(&mut self, index: usize) {
+        let d = &mut self.events[index];
+        if d.signaled {
+            return;
+        }
+        d.signaled = true;
+        if d.event_type & efi::EVT_NOTIFY_SIGNAL != 0 {
+            d.notify_pending = true;
+        }
+    }
+
+    pub fn signal(&mut self, event: Event) -> Status {
+        match self.find(event) {
+            Some(index) => {
+                self.signal_index(index);
+                Status::SUCCESS
+            }
+            None => Status::INVALID_PARAMETER,
+        }
+    }
+
+    // Signals all the events of the given type, e.g. those to be notified of
+    // ExitBootServices()
+    pub fn signal_type(&mut self, event_type: u32) {
+        for index in 0..MAX_EVENTS {
+            let d = &self.events[index];
+            if d.in_use && d.event_type == event_type {
+                self.signal_index(index);
+            }
+        }
+    }
+
+    // Times are in 100ns units
+    pub fn set_timer(&mut self, event: Event, delay: TimerDelay, time: u64, now: u64) -> Status {
+        let index = match self.find(event) {
+            Some(index) => index,
+            None => return Status::INVALID_PARAMETER,
+        };
+        let d = &mut self.events[index];
+        if d.event_type & efi::EVT_TIMER == 0 {
+            return Status::INVALID_PARAMETER;
+        }
+
+        d.timer = match delay {
+            efi::TIMER_CANCEL => Timer::None,
+            efi::TIMER_RELATIVE => Timer::Relative,
+            efi::TIMER_PERIODIC => Timer::Periodic(time),
+            _ => return Status::INVALID_PARAMETER,
+        };
+        d.deadline = now.saturating_add(time);
+        Status::SUCCESS
+    }
+
+    // Signals the timers that have expired by now
+    pub fn poll(&mut self, now: u64) {
+        for index in 0..MAX_EVENTS {
+            let d = &mut self.events[index];
+            if !d.in_use || d.timer == Timer::None || d.deadline > now {
+                continue;
+            }
+            match d.timer {
+                Timer::Periodic(period) => {
+                    // Skip any periods that were missed rather than firing
+                    // repeatedly to catch up
+                    d.deadline = d.deadline.saturating_add(period);
+                    if d.deadline <= now {
+                        d.deadline = now.saturating_add(period);
+                    }
+                }
+                _ => d.timer = Timer::None,
+            }
+            self.signal_index(index);
+        }
+    }
+
+    // Arms the watchdog to expire after the given number of seconds, or
+    // disables it if that is zero
+    pub fn set_watchdog(&mut self, seconds: u64, now: u64) {
+        self.watchdog = if seconds == 0 {
+            None
+        } else {
+            Some(now.saturating_add(seconds.saturating_mul(10_000_000)))
+        };
+    }
+
+    pub fn watchdog_expired(&self, now: u64) -> bool {
+        match self.watchdog {
+            Some(deadline) => deadline <= now,
+            None => false,
+        }
+    }
+
+    // Clears the signal state, if it was set
+    pub fn take_signal(&mut self, event: Event) -> Status {
+        let index = match self.find(event) {
+            Some(index) => index,
+            None => return Status::INVALID_PARAMETER,
+        };
+        let d = &mut self.events[index];
+        if d.signaled {
+            d.signaled = false;
+            Status::SUCCESS
+        } else {
+            Status::NOT_READY
+        }
+    }
+
+    // CheckEvent(): a wait event that isn't signaled gets its notification
+    // function queued, which may signal it
+    pub fn check(&mut self, event: Event) -> Status {
+        let index = match self.find(event) {
+            Some(index) => index,
+            None => return Status::INVALID_PARAMETER,
+        };
+        let d = &mut self.events[index];
+        if d.event_type & efi::EVT_NOTIFY_SIGNAL != 0 {
+            return Status::INVALID_PARAMETER;
+        }
+        if d.signaled {
+            d.signaled = false;
+            return Status::SUCCESS;
+        }
+        if d.event_type & efi::EVT_NOTIFY_WAIT != 0 {
+            d.notify_pending = true;
+        }
+        Status::NOT_READY
+    }
+
+    pub fn next_notification(&mut self) -> Option<Notification> {
+        // Higher priority notifications go first
+        let tpl = self.tpl;
+        let (index, _) = self
+            .events
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.in_use && d.notify_pending && d.tpl > tpl)
+            .max_by_key(|(_, d)| d.tpl)?;
+        let d = &mut self.events[index];
+        d.notify_pending = false;
+        // Signal events are reset as their notification is run
+        if d.event_type & efi::EVT_NOTIFY_SIGNAL != 0 {
+            d.signaled = false;
+        }
+        let notification = Notification {
+            notify: d.notify?,
+            event: handle(index),
+            context: d.context as *mut c_void,
+            previous_tpl: tpl,
+        };
+        self.tpl = d.tpl;
+        Some(notification)
+    }
+}
+
+// Runs the queued notification functions above the current TPL. The events
+// aren't borrowed while they run as they are free to call back into the
+// event services.
+pub fn dispatch(events: &AtomicRefCell<Events>) {
+    loop {
+        let notification = events.borrow_mut().next_notification();
+        match notification {
+            Some(n) => {
+                (n.notify)(n.event, n.context);
+                events.borrow_mut().restore_tpl(n.previous_tpl);
+            }
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ffi::c_void;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use atomic_refcell::AtomicRefCell;
+    use r_efi::efi::{self, Event, Status};
+
+    use super::{dispatch, Events};
+
+    static NOTIFY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    extern "win64" fn count_notify(_: Event, context: *mut c_void) {
+        NOTIFY_COUNT.fetch_add(context as usize, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_create_close() {
+        let mut events = Events::new();
+        assert_eq!(
+            events
+                .create(
+                    efi::EVT_NOTIFY_SIGNAL | efi::EVT_NOTIFY_WAIT,
+                    efi::TPL_CALLBACK,
+                    Some(count_notify),
+                    core::ptr::null_mut()
+                )
+                .err(),
+            Some(Status::INVALID_PARAMETER)
+        );
+        // Notification functions must run above TPL_APPLICATION
+        assert_eq!(
+            events
+                .create(
+                    efi::EVT_NOTIFY_SIGNAL,
+                    efi::TPL_APPLICATION,
+                    Some(count_notify),
+                    core::ptr::null_mut()
+                )
+                .err(),
+            Some(Status::INVALID_PARAMETER)
+        );
+
+        let event = events
+            .create(efi::EVT_TIMER, 0, None, core::ptr::null_mut())
+            .unwrap();
+        assert!(!event.is_null());
+        assert_eq!(events.close(event), Status::SUCCESS);
+        assert_eq!(events.close(event), Status::INVALID_PARAMETER);
+        assert_eq!(events.signal(event), Status::INVALID_PARAMETER);
+        assert_eq!(
+            events.close(core::ptr::null_mut()),
+            Status::INVALID_PARAMETER
+        );
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut events = Events::new();
+        let relative = events
+            .create(efi::EVT_TIMER, 0, None, core::ptr::null_mut())
+            .unwrap();
+        let periodic = events
+            .create(efi::EVT_TIMER, 0, None, core::ptr::null_mut())
+            .unwrap();
+        let plain = events.create(0, 0, None, core::ptr::null_mut()).unwrap();
+
+        assert_eq!(
+            events.set_timer(plain, efi::TIMER_RELATIVE, 10, 0),
+            Status::INVALID_PARAMETER
+        );
+        assert_eq!(
+            events.set_timer(relative, efi::TIMER_RELATIVE, 100, 1000),
+            Status::SUCCESS
+        );
+        assert_eq!(
+            events.set_timer(periodic, efi::TIMER_PERIODIC, 50, 1000),
+            Status::SUCCESS
+        );
+
+        events.poll(1049);
+        assert_eq!(events.check(relative), Status::NOT_READY);
+        assert_eq!(events.check(periodic), Status::NOT_READY);
+
+        events.poll(1050);
+        assert_eq!(events.check(relative), Status::NOT_READY);
+        assert_eq!(events.check(periodic), Status::SUCCESS);
+        assert_eq!(events.check(periodic), Status::NOT_READY);
+
+        events.poll(1100);
+        assert_eq!(events.check(relative), Status::SUCCESS);
+        assert_eq!(events.check(periodic), Status::SUCCESS);
+
+        // The relative timer only fires once, and a missed period doesn't
+        // make the periodic one fire repeatedly
+        events.poll(1300);
+        assert_eq!(events.check(relative), Status::NOT_READY);
+        assert_eq!(events.check(periodic), Status::SUCCESS);
+        events.poll(1300);
+        assert_eq!(events.check(periodic), Status::NOT_READY);
+
+        assert_eq!(
+            events.set_timer(periodic, efi::TIMER_CANCEL, 0, 1300),
+            Status::SUCCESS
+        );
+        events.poll(10000);
+        assert_eq!(events.check(periodic), Status::NOT_READY);
+    }
+
+    #[test]
+    fn test_notify() {
+        static EVENTS: AtomicRefCell<Events> = AtomicRefCell::new(Events::new());
+
+        let signal = EVENTS
+            .borrow_mut()
+            .create(
+                efi::EVT_TIMER | efi::EVT_NOTIFY_SIGNAL,
+                efi::TPL_CALLBACK,
+                Some(count_notify),
+                1 as *mut c_void,
+            )
+            .unwrap();
+        let wait = EVENTS
+            .borrow_mut()
+            .create(
+                efi::EVT_NOTIFY_WAIT,
+                efi::TPL_CALLBACK,
+                Some(count_notify),
+                100 as *mut c_void,
+            )
+            .unwrap();
+
+        let start = NOTIFY_COUNT.load(Ordering::SeqCst);
+        EVENTS
+            .borrow_mut()
+            .set_timer(signal, efi::TIMER_RELATIVE, 10, 0);
+        EVENTS.borrow_mut().poll(10);
+        dispatch(&EVENTS);
+        assert_eq!(NOTIFY_COUNT.load(Ordering::SeqCst) - start, 1);
+        // Signal events can't be checked
+        assert_eq!(EVENTS.borrow_mut().check(signal), Status::INVALID_PARAMETER);
+
+        // Checking a wait event runs its notification function
+        assert_eq!(EVENTS.borrow_mut().check(wait), Status::NOT_READY);
+        dispatch(&EVENTS);
+        assert_eq!(NOTIFY_COUNT.load(Ordering::SeqCst) - start, 101);
+        assert_eq!(EVENTS.borrow_mut().signal(wait), Status::SUCCESS);
+        assert_eq!(EVENTS.borrow_mut().check(wait), Status::SUCCESS);
+        dispatch(&EVENTS);
+        assert_eq!(NOTIFY_COUNT.load(Ordering::SeqCst) - start, 101);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut events = Events::new();
+        assert!(!events.watchdog_expired(u64::MAX));
+
+        // An application spinning through the boot services for two seconds
+        // trips a one second watchdog half way through
+        events.set_watchdog(1, 1000);
+        let expired = (0..20)
+            .map(|i| 1000 + i * 1_000_000)
+            .find(|now| events.watchdog_expired(*now));
+        assert_eq!(expired, Some(10_001_000));
+
+        // Re-arming pushes the deadline back, zero disables it
+        events.set_watchdog(5, 10_001_000);
+        assert!(!events.watchdog_expired(50_000_999));
+        assert!(events.watchdog_expired(60_001_000));
+        events.set_watchdog(0, 10_001_000);
+        assert!(!events.watchdog_expired(u64::MAX));
+    }
+
+    #[test]
+    fn test_tpl() {
+        static EVENTS: AtomicRefCell<Events> = AtomicRefCell::new(Events::new());
+        static ORDER: AtomicUsize = AtomicUsize::new(0);
+
+        // Records the TPL each notification runs at, in the order they run
+        extern "win64" fn record_tpl(_: Event, context: *mut c_void) {
+            let tpl = EVENTS.borrow().tpl();
+            let previous = ORDER.load(Ordering::SeqCst);
+            ORDER.store(previous * 100 + tpl, Ordering::SeqCst);
+            unsafe { *(context as *mut usize) += 1 };
+        }
+
+        let mut callback_count = 0usize;
+        let mut notify_count = 0usize;
+        let callback = EVENTS
+            .borrow_mut()
+            .create(
+                efi::EVT_NOTIFY_SIGNAL,
+                efi::TPL_CALLBACK,
+                Some(record_tpl),
+                &mut callback_count as *mut _ as *mut c_void,
+            )
+            .unwrap();
+        let notify = EVENTS
+            .borrow_mut()
+            .create(
+                efi::EVT_NOTIFY_SIGNAL,
+                efi::TPL_NOTIFY,
+                Some(record_tpl),
+                &mut notify_count as *mut _ as *mut c_void,
+            )
+            .unwrap();
+
+        let previous = EVENTS.borrow_mut().raise_tpl(efi::TPL_NOTIFY);
+        assert_eq!(previous, efi::TPL_APPLICATION);
+        EVENTS.borrow_mut().signal(callback);
+        EVENTS.borrow_mut().signal(notify);
+        dispatch(&EVENTS);
+        assert_eq!((callback_count, notify_count), (0, 0));
+
+        // Lowering to TPL_CALLBACK still holds back the TPL_CALLBACK event
+        EVENTS.borrow_mut().restore_tpl(efi::TPL_CALLBACK);
+        dispatch(&EVENTS);
+        assert_eq!((callback_count, notify_count), (0, 1));
+
+        EVENTS.borrow_mut().restore_tpl(efi::TPL_APPLICATION);
+        dispatch(&EVENTS);
+        assert_eq!((callback_count, notify_count), (1, 1));
+        assert_eq!(ORDER.load(Ordering::SeqCst), 1608);
+        assert_eq!(EVENTS.borrow().tpl(), efi::TPL_APPLICATION);
+
+        // Both pending at once: the higher TPL runs first
+        ORDER.store(0, Ordering::SeqCst);
+        EVENTS.borrow_mut().raise_tpl(efi::TPL_HIGH_LEVEL);
+        EVENTS.borrow_mut().signal(callback);
+        EVENTS.borrow_mut().signal(notify);
+        EVENTS.borrow_mut().restore_tpl(efi::TPL_APPLICATION);
+        dispatch(&EVENTS);
+        assert_eq!((callback_count, notify_count), (2, 2));
+        assert_eq!(ORDER.load(Ordering::SeqCst), 1608);
+    }
+}
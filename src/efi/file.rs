@@ -17,26 +17,22 @@ use core::ffi::c_void;
 use r_efi::{
     efi::{self, Char16, Guid, Status},
     protocols::{
-        device_path::Protocol as DevicePathProtocol, file::Protocol as FileProtocol,
-        simple_file_system::Protocol as SimpleFileSystemProtocol,
+        file::Protocol as FileProtocol, simple_file_system::Protocol as SimpleFileSystemProtocol,
     },
 };
 
-#[repr(C)]
-pub struct FileDevicePathProtocol {
-    pub device_path: DevicePathProtocol,
-    pub filename: [u16; 64],
-}
-
 pub extern "win64" fn filesystem_open_volume(
     fs_proto: *mut SimpleFileSystemProtocol,
     file: *mut *mut FileProtocol,
 ) -> Status {
     let wrapper = container_of!(fs_proto, FileSystemWrapper, proto);
     let wrapper = unsafe { &*wrapper };
-    let root = wrapper.fs.root().unwrap();
+    let root = match wrapper.fs.root() {
+        Ok(root) => root,
+        Err(_) => return Status::DEVICE_ERROR,
+    };
 
-    if let Some(fw) = wrapper.create_file(root.into()) {
+    if let Some(fw) = wrapper.create_file(root.into(), "") {
         unsafe {
             *file = &mut (*fw).proto;
         }
@@ -50,17 +46,25 @@ pub extern "win64" fn open(
     file_in: *mut FileProtocol,
     file_out: *mut *mut FileProtocol,
     path_in: *mut Char16,
-    _: u64,
+    mode: u64,
     _: u64,
 ) -> Status {
+    // The filesystem is read-only
+    if mode & (r_efi::protocols::file::MODE_WRITE | r_efi::protocols::file::MODE_CREATE) != 0 {
+        return Status::UNSUPPORTED;
+    }
+
     let wrapper = container_of!(file_in, FileWrapper, proto);
     let wrapper = unsafe { &*wrapper };
 
     let mut path = [0; 256];
     crate::common::ucs2_to_ascii(path_in, &mut path[0..255]);
-    let path = unsafe { core::str::from_utf8_unchecked(&path) };
+    let path = crate::common::ascii_strip(&path);
 
-    let root = wrapper.fs.root().unwrap();
+    let root = match wrapper.fs.root() {
+        Ok(root) => root,
+        Err(_) => return Status::DEVICE_ERROR,
+    };
     let dir = if crate::fat::is_absolute_path(path) {
         &root
     } else {
@@ -73,10 +77,13 @@ pub extern "win64" fn open(
         }
     };
 
+    // The name reported by GetInfo() is the last component of the path
+    let name = path.rsplit(|c| c == '/' || c == '\\').next().unwrap_or("");
+
     match dir.open(path) {
         Ok(f) => {
             let fs_wrapper = unsafe { &(*wrapper.fs_wrapper) };
-            if let Some(file_out_wrapper) = fs_wrapper.create_file(f) {
+            if let Some(file_out_wrapper) = fs_wrapper.create_file(f, name) {
                 unsafe {
                     *file_out = &mut (*file_out_wrapper).proto;
                 }
@@ -92,115 +99,216 @@ pub extern "win64" fn open(
 
 pub extern "win64" fn close(proto: *mut FileProtocol) -> Status {
     let wrapper = container_of!(proto, FileWrapper, proto);
-    super::ALLOCATOR
-        .borrow_mut()
-        .free_pages(&wrapper as *const _ as u64)
+    super::ALLOCATOR.borrow_mut().free_pages(wrapper as u64)
 }
 
-pub extern "win64" fn delete(_: *mut FileProtocol) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn delete(proto: *mut FileProtocol) -> Status {
+    // Delete() always closes the file, even when it can't be deleted
+    close(proto);
+    Status::WARN_DELETE_FAILURE
 }
 
-pub extern "win64" fn read(file: *mut FileProtocol, size: *mut usize, buf: *mut c_void) -> Status {
-    use crate::fat::Read;
-    let wrapper = container_of_mut!(file, FileWrapper, proto);
-    if let crate::fat::Node::Directory(d) = unsafe { &mut (*wrapper).node } {
-        let (node, name) = match d.next_node() {
-            Ok(node) => node,
-            Err(crate::fat::Error::EndOfFile) => {
-                unsafe { *size = 0 };
-                return Status::SUCCESS;
-            }
-            Err(_) => return Status::DEVICE_ERROR,
-        };
+// A time the directory entry didn't record is left as all zeros
+fn efi_time(timestamp: Option<crate::fat::Timestamp>) -> r_efi::system::Time {
+    let mut time: r_efi::system::Time = unsafe { core::mem::zeroed() };
+    if let Some(t) = timestamp {
+        time.year = t.year;
+        time.month = t.month;
+        time.day = t.day;
+        time.hour = t.hour;
+        time.minute = t.minute;
+        time.second = t.second;
+        time.nanosecond = t.nanosecond;
+        time.timezone = r_efi::system::UNSPECIFIED_TIMEZONE;
+    }
+    time
+}
 
-        if unsafe { *size } < core::mem::size_of::<FileInfo>() {
-            unsafe { *size = core::mem::size_of::<FileInfo>() };
-            return Status::BUFFER_TOO_SMALL;
-        }
+// The FAT attribute bits have the same values as the EFI ones
+fn efi_attribute(attributes: u8) -> u64 {
+    u64::from(
+        attributes
+            & (crate::fat::ATTR_READ_ONLY
+                | crate::fat::ATTR_HIDDEN
+                | crate::fat::ATTR_SYSTEM
+                | crate::fat::ATTR_DIRECTORY
+                | crate::fat::ATTR_ARCHIVE),
+    )
+}
 
-        let attribute = match &node {
-            crate::fat::Node::Directory(_) => r_efi::protocols::file::DIRECTORY,
-            crate::fat::Node::File(_) => r_efi::protocols::file::ARCHIVE,
-        };
+// Fills in the part of info that fits size, returning the size of the whole
+// entry: the fixed fields followed by the null terminated name
+fn fill_info(
+    info: *mut FileInfo,
+    size: usize,
+    file_size: u64,
+    attribute: u64,
+    times: &crate::fat::Times,
+    name: &[u16],
+) -> Result<usize, usize> {
+    let name_len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    let info_size = FILE_NAME_OFFSET + 2 * (name_len + 1);
+    if size < info_size {
+        return Err(info_size);
+    }
 
-        let info = buf as *mut FileInfo;
+    unsafe {
+        (*info).size = info_size as u64;
+        (*info).file_size = file_size;
+        (*info).physical_size = file_size;
+        (*info).create_time = efi_time(times.created);
+        (*info).last_access_time = efi_time(times.accessed);
+        (*info).modification_time = efi_time(times.modified);
+        (*info).attribute = attribute;
+        let file_name = core::slice::from_raw_parts_mut(
+            (info as *mut u8).add(FILE_NAME_OFFSET) as *mut u16,
+            name_len + 1,
+        );
+        file_name[..name_len].copy_from_slice(&name[..name_len]);
+        file_name[name_len] = 0;
+    }
+    Ok(info_size)
+}
 
-        let name = crate::common::ascii_strip(&name);
-        unsafe {
-            (*info).size = core::mem::size_of::<FileInfo>() as u64;
-            (*info).file_size = node.get_size().into();
-            (*info).physical_size = node.get_size().into();
-            (*info).attribute = attribute;
-            crate::common::ascii_to_ucs2(name, &mut (*info).file_name);
+fn read_directory(d: &mut crate::fat::Directory, size: *mut usize, buf: *mut c_void) -> Status {
+    // Work on a copy so that the entry is only consumed once it is returned
+    let mut next = *d;
+    let de = match next.next_entry() {
+        Ok(de) => de,
+        Err(crate::fat::Error::EndOfFile) => {
+            unsafe { *size = 0 };
+            return Status::SUCCESS;
         }
+        Err(_) => return Status::DEVICE_ERROR,
+    };
 
-        return Status::SUCCESS;
-    }
+    let mut short_name = [0; 12];
+    let name = match de.long_name() {
+        Some(long_name) => long_name,
+        None => {
+            let name = de.short_name();
+            let name = crate::common::ascii_strip(&name);
+            crate::common::ascii_to_ucs2(name, &mut short_name);
+            &short_name[..name.len()]
+        }
+    };
 
-    if unsafe { *size } < unsafe { (*wrapper).node.get_size() as usize } {
-        unsafe { *size = (*wrapper).node.get_size() as usize };
-        return Status::BUFFER_TOO_SMALL;
+    match fill_info(
+        buf as *mut FileInfo,
+        unsafe { *size },
+        de.size().into(),
+        efi_attribute(de.attributes()),
+        &de.times(),
+        name,
+    ) {
+        Ok(info_size) => {
+            *d = next;
+            unsafe { *size = info_size };
+            Status::SUCCESS
+        }
+        Err(info_size) => {
+            unsafe { *size = info_size };
+            Status::BUFFER_TOO_SMALL
+        }
     }
+}
 
-    let mut current_offset = 0;
-    let mut bytes_remaining = unsafe { *size };
-
-    loop {
-        let buf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, *size) };
+pub extern "win64" fn read(file: *mut FileProtocol, size: *mut usize, buf: *mut c_void) -> Status {
+    use crate::fat::Read;
+    let wrapper = container_of_mut!(file, FileWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if let crate::fat::Node::Directory(d) = &mut wrapper.node {
+        return read_directory(d, size, buf);
+    }
 
-        let mut data: [u8; 512] = [0; 512];
-        unsafe {
-            match (*wrapper).node.read(&mut data) {
-                Ok(bytes_read) => {
-                    buf[current_offset..current_offset + bytes_read as usize]
-                        .copy_from_slice(&data[0..bytes_read as usize]);
-                    current_offset += bytes_read as usize;
-
-                    if bytes_remaining <= bytes_read as usize {
-                        *size = current_offset;
-                        return Status::SUCCESS;
-                    }
-                    bytes_remaining -= bytes_read as usize;
-                }
-                Err(_) => {
+    let file_size = u64::from(wrapper.node.get_size());
+    if wrapper.position > file_size {
+        return Status::DEVICE_ERROR;
+    }
+    let wanted = core::cmp::min(unsafe { *size } as u64, file_size - wrapper.position) as usize;
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, wanted) };
+
+    // The FAT driver reads whole sectors, so keep the last one around for
+    // reads that aren't sector aligned
+    let mut copied = 0;
+    while copied < wanted {
+        let sector = wrapper.position & !511;
+        if wrapper.sector != Some(sector) {
+            if wrapper.node_position != sector {
+                if wrapper.node.seek(sector as u32).is_err() {
                     return Status::DEVICE_ERROR;
                 }
+                wrapper.node_position = sector;
             }
+            if wrapper.node.read(&mut wrapper.data).is_err() {
+                return Status::DEVICE_ERROR;
+            }
+            wrapper.node_position += 512;
+            wrapper.sector = Some(sector);
         }
+
+        let offset = (wrapper.position - sector) as usize;
+        let bytes = core::cmp::min(512 - offset, wanted - copied);
+        buf[copied..copied + bytes].copy_from_slice(&wrapper.data[offset..offset + bytes]);
+        copied += bytes;
+        wrapper.position += bytes as u64;
     }
+
+    unsafe { *size = copied };
+    Status::SUCCESS
 }
 
 pub extern "win64" fn write(_: *mut FileProtocol, _: *mut usize, _: *mut c_void) -> Status {
     Status::UNSUPPORTED
 }
 
-pub extern "win64" fn get_position(_: *mut FileProtocol, _: *mut u64) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn get_position(file: *mut FileProtocol, position: *mut u64) -> Status {
+    let wrapper = container_of!(file, FileWrapper, proto);
+    if let crate::fat::Node::Directory(_) = unsafe { &(*wrapper).node } {
+        return Status::UNSUPPORTED;
+    }
+    unsafe { *position = (*wrapper).position };
+    Status::SUCCESS
 }
 
 pub extern "win64" fn set_position(file: *mut FileProtocol, position: u64) -> Status {
-    // Seeking to end of file is not supported
-    if position == 0xFFFFFFFFFFFFFFFF {
-        return Status::UNSUPPORTED;
-    }
     use crate::fat::Read;
     let wrapper = container_of_mut!(file, FileWrapper, proto);
-    match unsafe { (*wrapper).node.seek(position as u32) } {
-        Err(crate::fat::Error::Unsupported) => Status::UNSUPPORTED,
-        Err(_) => Status::DEVICE_ERROR,
-        Ok(()) => Status::SUCCESS,
+    let wrapper = unsafe { &mut *wrapper };
+    match &mut wrapper.node {
+        // Only rewinding is allowed on directories
+        crate::fat::Node::Directory(d) => {
+            if position != 0 {
+                return Status::UNSUPPORTED;
+            }
+            match d.seek(0) {
+                Ok(()) => Status::SUCCESS,
+                Err(_) => Status::DEVICE_ERROR,
+            }
+        }
+        crate::fat::Node::File(f) => {
+            // Seeking past the end is allowed, reads from there fail
+            wrapper.position = if position == 0xFFFF_FFFF_FFFF_FFFF {
+                u64::from(f.get_size())
+            } else {
+                position
+            };
+            Status::SUCCESS
+        }
     }
 }
 
+// The fixed part of EFI_FILE_INFO, the null terminated name follows it
+const FILE_NAME_OFFSET: usize = 80;
+
 #[repr(C)]
 struct FileInfo {
     size: u64,
     file_size: u64,
     physical_size: u64,
-    _create_time: r_efi::system::Time,
-    _last_access_time: r_efi::system::Time,
-    _modification_time: r_efi::system::Time,
+    create_time: r_efi::system::Time,
+    last_access_time: r_efi::system::Time,
+    modification_time: r_efi::system::Time,
     attribute: u64,
     file_name: [Char16; 256],
 }
@@ -211,30 +319,40 @@ pub extern "win64" fn get_info(
     info_size: *mut usize,
     info: *mut c_void,
 ) -> Status {
-    if unsafe { *guid } == r_efi::protocols::file::INFO_ID {
-        if unsafe { *info_size } < core::mem::size_of::<FileInfo>() {
-            unsafe { *info_size = core::mem::size_of::<FileInfo>() };
-            Status::BUFFER_TOO_SMALL
-        } else {
-            let info = info as *mut FileInfo;
-
-            let wrapper = container_of!(file, FileWrapper, proto);
-            let attribute = match unsafe { &(*wrapper).node } {
-                crate::fat::Node::Directory(_) => r_efi::protocols::file::DIRECTORY,
-                crate::fat::Node::File(_) => r_efi::protocols::file::ARCHIVE,
-            };
-            use crate::fat::Read;
-            unsafe {
-                (*info).size = core::mem::size_of::<FileInfo>() as u64;
-                (*info).file_size = (*wrapper).node.get_size().into();
-                (*info).physical_size = (*wrapper).node.get_size().into();
-                (*info).attribute = attribute;
-            }
+    if unsafe { *guid } != r_efi::protocols::file::INFO_ID {
+        return Status::UNSUPPORTED;
+    }
 
-            Status::SUCCESS
+    use crate::fat::Read;
+    let wrapper = container_of!(file, FileWrapper, proto);
+    let wrapper = unsafe { &*wrapper };
+    let (file_size, attribute, times) = match &wrapper.node {
+        crate::fat::Node::Directory(_) => (
+            0,
+            r_efi::protocols::file::DIRECTORY,
+            crate::fat::Times::default(),
+        ),
+        crate::fat::Node::File(f) => (
+            f.get_size().into(),
+            efi_attribute(f.attributes()),
+            f.times(),
+        ),
+    };
+    let attribute = attribute | r_efi::protocols::file::READ_ONLY;
+
+    match fill_info(
+        info as *mut FileInfo,
+        unsafe { *info_size },
+        file_size,
+        attribute,
+        &times,
+        &wrapper.name,
+    ) {
+        Ok(_) => Status::SUCCESS,
+        Err(size) => {
+            unsafe { *info_size = size };
+            Status::BUFFER_TOO_SMALL
         }
-    } else {
-        Status::UNSUPPORTED
     }
 }
 
@@ -256,6 +374,14 @@ struct FileWrapper<'a> {
     proto: FileProtocol,
     node: crate::fat::Node<'a>,
     fs_wrapper: *const FileSystemWrapper<'a>,
+    name: [Char16; 256],
+    // The file position in EFI terms, which need not be sector aligned
+    position: u64,
+    // Where node will read from next
+    node_position: u64,
+    // The last sector read, and its offset in the file
+    data: [u8; 512],
+    sector: Option<u64>,
 }
 
 #[repr(C)]
@@ -267,7 +393,7 @@ pub struct FileSystemWrapper<'a> {
 }
 
 impl<'a> FileSystemWrapper<'a> {
-    fn create_file(&self, node: crate::fat::Node<'a>) -> Option<*mut FileWrapper> {
+    fn create_file(&self, node: crate::fat::Node<'a>, name: &str) -> Option<*mut FileWrapper> {
         let size = core::mem::size_of::<FileWrapper>();
         let (status, new_address) = super::ALLOCATOR.borrow_mut().allocate_pages(
             efi::ALLOCATE_ANY_PAGES,
@@ -279,9 +405,16 @@ impl<'a> FileSystemWrapper<'a> {
         if status == Status::SUCCESS {
             let fw = new_address as *mut FileWrapper;
             unsafe {
+                // The memory is uninitialised so write the node without
+                // dropping whatever was there
+                core::ptr::write(&mut (*fw).node, node);
                 (*fw).fs = self.fs;
                 (*fw).fs_wrapper = self;
-                (*fw).node = node;
+                (*fw).name = [0; 256];
+                crate::common::ascii_to_ucs2(name, &mut (*fw).name);
+                (*fw).position = 0;
+                (*fw).node_position = 0;
+                (*fw).sector = None;
                 (*fw).proto.revision = r_efi::protocols::file::REVISION;
                 (*fw).proto.open = open;
                 (*fw).proto.close = close;
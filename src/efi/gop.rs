@@ -0,0 +1,608 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::{ffi::c_void, mem::size_of};
+
+use r_efi::{
+    efi::{self, Status},
+    protocols::graphics_output::{
+        self, BltOperation, BltPixel, Mode, ModeInformation, PixelBitmask,
+        Protocol as GraphicsOutputProtocol,
+    },
+};
+
+use crate::{
+    bochs::BochsDisplay,
+    boot::{Framebuffer, PixelFormat},
+};
+
+pub use graphics_output::PROTOCOL_GUID;
+
+// Resolutions offered when the display can change mode
+const BOCHS_MODES: [(u32, u32); 6] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 1024),
+    (1920, 1080),
+];
+const BOCHS_DEFAULT_MODE: u32 = 2;
+
+#[derive(Clone, Copy)]
+enum Display {
+    // Set up before we were started, the mode can't be changed
+    Fixed(Framebuffer),
+    Bochs(BochsDisplay),
+}
+
+impl Display {
+    fn mode_count(&self) -> u32 {
+        match self {
+            Display::Fixed(_) => 1,
+            Display::Bochs(d) => BOCHS_MODES
+                .iter()
+                .take_while(|(w, h)| u64::from(*w) * u64::from(*h) * 4 <= d.vram_size())
+                .count() as u32,
+        }
+    }
+
+    fn resolution(&self, mode: u32) -> Option<(u32, u32)> {
+        if mode >= self.mode_count() {
+            return None;
+        }
+        match self {
+            Display::Fixed(fb) => Some((fb.width, fb.height)),
+            Display::Bochs(_) => Some(BOCHS_MODES[mode as usize]),
+        }
+    }
+
+    fn set_mode(&self, mode: u32) -> Option<Framebuffer> {
+        let (width, height) = self.resolution(mode)?;
+        match self {
+            Display::Fixed(fb) => Some(*fb),
+            Display::Bochs(d) => Some(d.set_mode(width, height)),
+        }
+    }
+}
+
+fn mode_information(fb: &Framebuffer) -> ModeInformation {
+    ModeInformation {
+        version: 0,
+        horizontal_resolution: fb.width,
+        vertical_resolution: fb.height,
+        pixel_format: match fb.format {
+            PixelFormat::Rgbx => graphics_output::PIXEL_RED_GREEN_BLUE_RESERVED_8_BIT_PER_COLOR,
+            PixelFormat::Bgrx => graphics_output::PIXEL_BLUE_GREEN_RED_RESERVED_8_BIT_PER_COLOR,
+        },
+        pixel_information: PixelBitmask {
+            red_mask: 0,
+            green_mask: 0,
+            blue_mask: 0,
+            reserved_mask: 0,
+        },
+        pixels_per_scan_line: fb.stride,
+    }
+}
+
+#[repr(C)]
+pub struct GraphicsWrapper {
+    hw: super::HandleWrapper,
+    pub proto: GraphicsOutputProtocol,
+    mode: Mode,
+    info: ModeInformation,
+    display: Display,
+    framebuffer: Framebuffer,
+}
+
+impl GraphicsWrapper {
+    fn new(display: Display) -> GraphicsWrapper {
+        let framebuffer = Framebuffer {
+            base: 0,
+            width: 0,
+            height: 0,
+            stride: 0,
+            format: PixelFormat::Bgrx,
+        };
+        GraphicsWrapper {
+            hw: super::HandleWrapper {
+                handle_type: super::HandleType::Graphics,
+            },
+            proto: GraphicsOutputProtocol {
+                query_mode,
+                set_mode,
+                blt,
+                mode: core::ptr::null_mut(),
+            },
+            mode: Mode {
+                max_mode: display.mode_count(),
+                mode: 0,
+                info: core::ptr::null_mut(),
+                size_of_info: size_of::<ModeInformation>(),
+                frame_buffer_base: 0,
+                frame_buffer_size: 0,
+            },
+            info: mode_information(&framebuffer),
+            display,
+            framebuffer,
+        }
+    }
+
+    // Points the protocol at the mode information, once the wrapper is in its
+    // final location
+    fn fixup(&mut self) {
+        self.mode.info = &mut self.info;
+        self.proto.mode = &mut self.mode;
+    }
+
+    fn switch_mode(&mut self, mode: u32) -> Status {
+        let fb = match self.display.set_mode(mode) {
+            Some(fb) => fb,
+            None => return Status::UNSUPPORTED,
+        };
+
+        self.framebuffer = fb;
+        self.info = mode_information(&fb);
+        self.mode.mode = mode;
+        self.mode.frame_buffer_base = fb.base;
+        self.mode.frame_buffer_size = fb.stride as usize * fb.height as usize * 4;
+
+        // The mode change leaves the screen black
+        let black = BltPixel {
+            blue: 0,
+            green: 0,
+            red: 0,
+            reserved: 0,
+        };
+        self.fill(&black, 0, 0, fb.width as usize, fb.height as usize);
+
+        Status::SUCCESS
+    }
+
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u32 {
+        let offset = y * self.framebuffer.stride as usize + x;
+        unsafe { (self.framebuffer.base as *mut u32).add(offset) }
+    }
+
+    fn to_video(&self, p: &BltPixel) -> u32 {
+        let (first, last) = match self.framebuffer.format {
+            PixelFormat::Rgbx => (p.red, p.blue),
+            PixelFormat::Bgrx => (p.blue, p.red),
+        };
+        u32::from(first) | u32::from(p.green) << 8 | u32::from(last) << 16
+    }
+
+    fn from_video(&self, v: u32) -> BltPixel {
+        let (first, last) = (v as u8, (v >> 16) as u8);
+        let (red, blue) = match self.framebuffer.format {
+            PixelFormat::Rgbx => (first, last),
+            PixelFormat::Bgrx => (last, first),
+        };
+        BltPixel {
+            blue,
+            green: (v >> 8) as u8,
+            red,
+            reserved: 0,
+        }
+    }
+
+    fn contains(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
+        match (x.checked_add(width), y.checked_add(height)) {
+            (Some(right), Some(bottom)) => {
+                right <= self.framebuffer.width as usize
+                    && bottom <= self.framebuffer.height as usize
+            }
+            _ => false,
+        }
+    }
+
+    fn fill(&self, p: &BltPixel, x: usize, y: usize, width: usize, height: usize) {
+        let v = self.to_video(p);
+        for row in y..y + height {
+            let line = self.pixel_ptr(x, row);
+            for i in 0..width {
+                unsafe { line.add(i).write_volatile(v) };
+            }
+        }
+    }
+}
+
+pub extern "win64" fn query_mode(
+    proto: *mut GraphicsOutputProtocol,
+    mode: u32,
+    size_of_info: *mut usize,
+    info: *mut *mut ModeInformation,
+) -> Status {
+    let wrapper = container_of!(proto, GraphicsWrapper, proto);
+    let wrapper = unsafe { &*wrapper };
+
+    let (width, height) = match wrapper.display.resolution(mode) {
+        Some(r) => r,
+        None => return Status::INVALID_PARAMETER,
+    };
+
+    // All the modes share a pixel format, only the geometry differs
+    let mut mode_info = wrapper.info;
+    mode_info.horizontal_resolution = width;
+    mode_info.vertical_resolution = height;
+    if let Display::Bochs(_) = wrapper.display {
+        mode_info.pixels_per_scan_line = width;
+    }
+
+    // The caller frees the information with FreePool()
+    let mut buffer = core::ptr::null_mut();
+    let status = super::allocate_pool(
+        efi::BOOT_SERVICES_DATA,
+        size_of::<ModeInformation>(),
+        &mut buffer as *mut *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        return status;
+    }
+
+    unsafe {
+        *(buffer as *mut ModeInformation) = mode_info;
+        *info = buffer as *mut ModeInformation;
+        *size_of_info = size_of::<ModeInformation>();
+    }
+
+    Status::SUCCESS
+}
+
+pub extern "win64" fn set_mode(proto: *mut GraphicsOutputProtocol, mode: u32) -> Status {
+    let wrapper = container_of_mut!(proto, GraphicsWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    wrapper.switch_mode(mode)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub extern "win64" fn blt(
+    proto: *mut GraphicsOutputProtocol,
+    buffer: *mut BltPixel,
+    operation: BltOperation,
+    source_x: usize,
+    source_y: usize,
+    dest_x: usize,
+    dest_y: usize,
+    width: usize,
+    height: usize,
+    delta: usize,
+) -> Status {
+    let wrapper = container_of!(proto, GraphicsWrapper, proto);
+    let wrapper = unsafe { &*wrapper };
+
+    if width == 0 || height == 0 {
+        return Status::INVALID_PARAMETER;
+    }
+    // Only copying within the screen does without the buffer
+    if buffer.is_null() && operation != graphics_output::BLT_VIDEO_TO_VIDEO {
+        return Status::INVALID_PARAMETER;
+    }
+
+    // Delta is the length of a row of the buffer in bytes, 0 when the buffer
+    // is exactly the width of the rectangle
+    let delta = if delta == 0 {
+        width
+    } else {
+        delta / size_of::<BltPixel>()
+    };
+
+    match operation {
+        graphics_output::BLT_VIDEO_FILL => {
+            if !wrapper.contains(dest_x, dest_y, width, height) {
+                return Status::INVALID_PARAMETER;
+            }
+            wrapper.fill(unsafe { &*buffer }, dest_x, dest_y, width, height);
+        }
+        graphics_output::BLT_BUFFER_TO_VIDEO => {
+            if !wrapper.contains(dest_x, dest_y, width, height) {
+                return Status::INVALID_PARAMETER;
+            }
+            for row in 0..height {
+                let src = unsafe { buffer.add((source_y + row) * delta + source_x) };
+                let dst = wrapper.pixel_ptr(dest_x, dest_y + row);
+                for i in 0..width {
+                    unsafe { dst.add(i).write_volatile(wrapper.to_video(&*src.add(i))) };
+                }
+            }
+        }
+        graphics_output::BLT_VIDEO_TO_BLT_BUFFER => {
+            if !wrapper.contains(source_x, source_y, width, height) {
+                return Status::INVALID_PARAMETER;
+            }
+            for row in 0..height {
+                let src = wrapper.pixel_ptr(source_x, source_y + row);
+                let dst = unsafe { buffer.add((dest_y + row) * delta + dest_x) };
+                for i in 0..width {
+                    unsafe { *dst.add(i) = wrapper.from_video(src.add(i).read_volatile()) };
+                }
+            }
+        }
+        graphics_output::BLT_VIDEO_TO_VIDEO => {
+            if !wrapper.contains(source_x, source_y, width, height)
+                || !wrapper.contains(dest_x, dest_y, width, height)
+            {
+                return Status::INVALID_PARAMETER;
+            }
+            // Copy rows in the order that won't overwrite those still to be
+            // read if the rectangles overlap
+            let copy_row = |row| unsafe {
+                core::ptr::copy(
+                    wrapper.pixel_ptr(source_x, source_y + row),
+                    wrapper.pixel_ptr(dest_x, dest_y + row),
+                    width,
+                )
+            };
+            if dest_y > source_y {
+                (0..height).rev().for_each(copy_row);
+            } else {
+                (0..height).for_each(copy_row);
+            }
+        }
+        _ => return Status::INVALID_PARAMETER,
+    }
+
+    Status::SUCCESS
+}
+
+// The Bochs VGA adapter, if there is one, and the mode to start it in
+fn bochs_display() -> Option<(Display, u32)> {
+    let display = Display::Bochs(BochsDisplay::probe()?);
+    let count = display.mode_count();
+    if count == 0 {
+        log!("Not enough video memory for any mode");
+        return None;
+    }
+    Some((display, core::cmp::min(BOCHS_DEFAULT_MODE, count - 1)))
+}
+
+// Sets the Bochs VGA adapter to the mode GOP would start it in, for a kernel
+// that is booted without GOP
+pub fn bochs_framebuffer() -> Option<Framebuffer> {
+    let (display, mode) = bochs_display()?;
+    display.set_mode(mode)
+}
+
+// Finds a display, preferring a framebuffer that was set up for us over
+// programming the Bochs VGA adapter
+pub fn new_graphics_wrapper(info: &dyn crate::boot::Info) -> Option<*mut GraphicsWrapper> {
+    let (display, initial_mode) = match info.framebuffer() {
+        Some(fb) => (Display::Fixed(fb), 0),
+        None => bochs_display()?,
+    };
+
+    let size = size_of::<GraphicsWrapper>();
+    let (status, new_address) = super::ALLOCATOR.borrow_mut().allocate_pages(
+        efi::ALLOCATE_ANY_PAGES,
+        efi::LOADER_DATA,
+        ((size + super::PAGE_SIZE as usize - 1) / super::PAGE_SIZE as usize) as u64,
+        0_u64,
+    );
+    if status != Status::SUCCESS {
+        return None;
+    }
+
+    let gw = new_address as *mut GraphicsWrapper;
+    unsafe {
+        *gw = GraphicsWrapper::new(display);
+        (*gw).fixup();
+        if (*gw).switch_mode(initial_mode) != Status::SUCCESS {
+            return None;
+        }
+        let (width, height) = (
+            (*gw).info.horizontal_resolution,
+            (*gw).info.vertical_resolution,
+        );
+        log!("Graphics output: {}x{}", width, height);
+    }
+    Some(gw)
+}
+
+#[cfg(test)]
+mod tests {
+    use r_efi::{efi::Status, protocols::graphics_output};
+
+    use super::{Display, GraphicsWrapper};
+    use crate::boot::{Framebuffer, PixelFormat};
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 4;
+    const STRIDE: usize = 10;
+
+    fn pixel(red: u8, green: u8, blue: u8) -> graphics_output::BltPixel {
+        graphics_output::BltPixel {
+            blue,
+            green,
+            red,
+            reserved: 0,
+        }
+    }
+
+    // A wrapper around a framebuffer in memory
+    fn wrapper(memory: &mut [u32], format: PixelFormat) -> Box<GraphicsWrapper> {
+        let fb = Framebuffer {
+            base: memory.as_mut_ptr() as u64,
+            width: WIDTH as u32,
+            height: HEIGHT as u32,
+            stride: STRIDE as u32,
+            format,
+        };
+        let mut gw = Box::new(GraphicsWrapper::new(Display::Fixed(fb)));
+        gw.fixup();
+        assert_eq!(gw.switch_mode(0), Status::SUCCESS);
+        gw
+    }
+
+    #[test]
+    fn test_mode() {
+        let mut memory = vec![0xffff_ffffu32; STRIDE * HEIGHT];
+        let mut gw = wrapper(&mut memory, PixelFormat::Rgbx);
+
+        let mode = unsafe { &*gw.proto.mode };
+        assert_eq!(mode.max_mode, 1);
+        assert_eq!(mode.frame_buffer_base, memory.as_ptr() as u64);
+        assert_eq!(mode.frame_buffer_size, STRIDE * HEIGHT * 4);
+        let info = unsafe { &*mode.info };
+        assert_eq!(info.horizontal_resolution, WIDTH as u32);
+        assert_eq!(info.pixels_per_scan_line, STRIDE as u32);
+        assert_eq!(
+            info.pixel_format,
+            graphics_output::PIXEL_RED_GREEN_BLUE_RESERVED_8_BIT_PER_COLOR
+        );
+
+        // Setting the mode clears the screen, but not beyond the edge
+        for row in memory.chunks(STRIDE) {
+            assert!(row[..WIDTH].iter().all(|p| *p == 0));
+            assert!(row[WIDTH..].iter().all(|p| *p == 0xffff_ffff));
+        }
+
+        assert_eq!(super::set_mode(&mut gw.proto, 1), Status::UNSUPPORTED);
+    }
+
+    #[test]
+    fn test_blt_video_fill() {
+        let mut memory = vec![0u32; STRIDE * HEIGHT];
+        let mut gw = wrapper(&mut memory, PixelFormat::Bgrx);
+
+        let mut p = pixel(0x11, 0x22, 0x33);
+        let status = super::blt(
+            &mut gw.proto,
+            &mut p,
+            graphics_output::BLT_VIDEO_FILL,
+            0,
+            0,
+            1,
+            2,
+            3,
+            2,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        for y in 0..HEIGHT {
+            for x in 0..STRIDE {
+                let inside = (1..4).contains(&x) && (2..4).contains(&y);
+                let expected = if inside { 0x0011_2233 } else { 0 };
+                assert_eq!(memory[y * STRIDE + x], expected, "({}, {})", x, y);
+            }
+        }
+
+        // Off the edge of the screen
+        let status = super::blt(
+            &mut gw.proto,
+            &mut p,
+            graphics_output::BLT_VIDEO_FILL,
+            0,
+            0,
+            6,
+            0,
+            3,
+            1,
+            0,
+        );
+        assert_eq!(status, Status::INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_blt_buffer_to_video() {
+        let mut memory = vec![0u32; STRIDE * HEIGHT];
+        let mut gw = wrapper(&mut memory, PixelFormat::Rgbx);
+
+        // A 4x2 buffer, of which the right 3x2 is copied
+        let mut buffer: Vec<_> = (0..8).map(|i| pixel(i, 0x80, 0xf0)).collect();
+        let status = super::blt(
+            &mut gw.proto,
+            buffer.as_mut_ptr(),
+            graphics_output::BLT_BUFFER_TO_VIDEO,
+            1,
+            0,
+            5,
+            1,
+            3,
+            2,
+            4 * core::mem::size_of::<graphics_output::BltPixel>(),
+        );
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(memory[STRIDE + 4], 0);
+        assert_eq!(memory[STRIDE + 5], 0x00f0_8001);
+        assert_eq!(memory[STRIDE + 7], 0x00f0_8003);
+        assert_eq!(memory[2 * STRIDE + 5], 0x00f0_8005);
+        assert_eq!(memory[2 * STRIDE + 7], 0x00f0_8007);
+        assert_eq!(memory[2 * STRIDE + 8], 0);
+
+        // And back again
+        let mut readback = vec![pixel(0, 0, 0); 6];
+        let status = super::blt(
+            &mut gw.proto,
+            readback.as_mut_ptr(),
+            graphics_output::BLT_VIDEO_TO_BLT_BUFFER,
+            5,
+            1,
+            0,
+            0,
+            3,
+            2,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(readback[0].red, 1);
+        assert_eq!(readback[5].red, 7);
+        assert_eq!(readback[5].green, 0x80);
+        assert_eq!(readback[5].blue, 0xf0);
+    }
+
+    #[test]
+    fn test_blt_null_buffer() {
+        let mut memory = vec![0u32; STRIDE * HEIGHT];
+        let mut gw = wrapper(&mut memory, PixelFormat::Bgrx);
+
+        for operation in &[
+            graphics_output::BLT_VIDEO_FILL,
+            graphics_output::BLT_BUFFER_TO_VIDEO,
+            graphics_output::BLT_VIDEO_TO_BLT_BUFFER,
+        ] {
+            let status = super::blt(
+                &mut gw.proto,
+                core::ptr::null_mut(),
+                *operation,
+                0,
+                0,
+                0,
+                0,
+                1,
+                1,
+                0,
+            );
+            assert_eq!(status, Status::INVALID_PARAMETER);
+        }
+        assert!(memory.iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn test_blt_video_to_video() {
+        let mut memory = vec![0u32; STRIDE * HEIGHT];
+        let mut gw = wrapper(&mut memory, PixelFormat::Bgrx);
+        for (i, p) in memory.iter_mut().enumerate() {
+            *p = i as u32;
+        }
+
+        // Overlapping move down and to the right
+        let status = super::blt(
+            &mut gw.proto,
+            core::ptr::null_mut(),
+            graphics_output::BLT_VIDEO_TO_VIDEO,
+            0,
+            0,
+            1,
+            1,
+            4,
+            3,
+            0,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        for y in 0..3 {
+            for x in 0..4 {
+                let expected = (y * STRIDE + x) as u32;
+                assert_eq!(memory[(y + 1) * STRIDE + x + 1], expected);
+            }
+        }
+    }
+}
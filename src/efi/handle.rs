@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The handle database: which protocols are installed on which handles, with
+// the interface of each. A handle is a pointer to something of ours, such as
+// a BlockWrapper, or a HandleWrapper allocated for an application that asked
+// for a new handle, and is only valid while something is installed on it.
+
+use core::ffi::c_void;
+
+use r_efi::{
+    efi::{Guid, Handle, Status},
+    protocols::device_path::{self, Protocol as DevicePathProtocol},
+};
+
+const MAX_PROTOCOLS: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Protocol {
+    handle: usize,
+    guid: Guid,
+    interface: usize,
+}
+
+impl Protocol {
+    const EMPTY: Protocol = Protocol {
+        handle: 0,
+        guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
+        interface: 0,
+    };
+}
+
+// Kept in the order they were installed, so handles are found in the order
+// they first had something installed on them
+pub struct Handles {
+    protocols: [Protocol; MAX_PROTOCOLS],
+    count: usize,
+}
+
+impl Handles {
+    pub const fn new() -> Self {
+        Self {
+            protocols: [Protocol::EMPTY; MAX_PROTOCOLS],
+            count: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    fn installed(&self) -> &[Protocol] {
+        &self.protocols[..self.count]
+    }
+
+    fn position(&self, handle: Handle, guid: &Guid) -> Option<usize> {
+        self.installed()
+            .iter()
+            .position(|p| p.handle == handle as usize && p.guid == *guid)
+    }
+
+    // Each protocol can only be installed once on a handle
+    pub fn install(&mut self, handle: Handle, guid: &Guid, interface: *mut c_void) -> Status {
+        if handle.is_null() || self.position(handle, guid).is_some() {
+            return Status::INVALID_PARAMETER;
+        }
+        if self.count == MAX_PROTOCOLS {
+            return Status::OUT_OF_RESOURCES;
+        }
+        self.protocols[self.count] = Protocol {
+            handle: handle as usize,
+            guid: *guid,
+            interface: interface as usize,
+        };
+        self.count += 1;
+        Status::SUCCESS
+    }
+
+    // Only if it's the interface that was installed
+    pub fn uninstall(&mut self, handle: Handle, guid: &Guid, interface: *mut c_void) -> Status {
+        match self.position(handle, guid) {
+            Some(i) if self.protocols[i].interface == interface as usize => {
+                self.protocols.copy_within(i + 1..self.count, i);
+                self.count -= 1;
+                Status::SUCCESS
+            }
+            _ => Status::NOT_FOUND,
+        }
+    }
+
+    pub fn reinstall(
+        &mut self,
+        handle: Handle,
+        guid: &Guid,
+        old: *mut c_void,
+        new: *mut c_void,
+    ) -> Status {
+        match self.position(handle, guid) {
+            Some(i) if self.protocols[i].interface == old as usize => {
+                self.protocols[i].interface = new as usize;
+                Status::SUCCESS
+            }
+            _ => Status::NOT_FOUND,
+        }
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.installed().iter().any(|p| p.handle == handle as usize)
+    }
+
+    pub fn get(&self, handle: Handle, guid: &Guid) -> Option<*mut c_void> {
+        self.position(handle, guid)
+            .map(|i| self.protocols[i].interface as *mut c_void)
+    }
+
+    // The first interface installed for the protocol, on whichever handle
+    pub fn find(&self, guid: &Guid) -> Option<*mut c_void> {
+        self.installed()
+            .iter()
+            .find(|p| p.guid == *guid)
+            .map(|p| p.interface as *mut c_void)
+    }
+
+    // Every handle with the protocol, or every handle if none is given, each
+    // of them once
+    pub fn handles<'a>(&'a self, guid: Option<&'a Guid>) -> impl Iterator<Item = Handle> + 'a {
+        let installed = self.installed();
+        installed
+            .iter()
+            .enumerate()
+            .filter(move |(i, p)| match guid {
+                Some(guid) => p.guid == *guid,
+                None => !installed[..*i].iter().any(|q| q.handle == p.handle),
+            })
+            .map(|(_, p)| p.handle as Handle)
+    }
+
+    // The handle with the protocol whose device path is the closest to path,
+    // the longest one that it starts with, and how many bytes of path that
+    // takes up. The device paths installed have to be valid.
+    pub unsafe fn locate_device_path(&self, guid: &Guid, path: &[u8]) -> Option<(Handle, usize)> {
+        let mut closest: Option<(Handle, usize)> = None;
+        for handle in self.handles(Some(guid)) {
+            let own = match self.get(handle, &device_path::PROTOCOL_GUID) {
+                Some(own) => super::device_path::path_bytes(own as *const DevicePathProtocol),
+                None => continue,
+            };
+            match super::device_path::prefix_length(path, own) {
+                Some(length) if closest.map_or(true, |(_, l)| length > l) => {
+                    closest = Some((handle, length))
+                }
+                _ => {}
+            }
+        }
+        closest
+    }
+
+    // What is installed on the handle
+    pub fn protocols(&self, handle: Handle) -> impl Iterator<Item = &Guid> {
+        self.installed()
+            .iter()
+            .filter(move |p| p.handle == handle as usize)
+            .map(|p| &p.guid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ffi::c_void;
+
+    use r_efi::efi::{Handle, Status};
+
+    use super::Handles;
+    use crate::efi::block;
+
+    fn pointer(value: usize) -> *mut c_void {
+        value as *mut c_void
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut handles = Box::new(Handles::new());
+        let (disk, part): (Handle, Handle) = (pointer(0x1000), pointer(0x2000));
+        let device_path = r_efi::protocols::device_path::PROTOCOL_GUID;
+
+        // Two Block I/O handles, both of them found by the GUID
+        for (handle, interface) in &[(disk, 0x1100), (part, 0x2100)] {
+            assert_eq!(
+                handles.install(*handle, &block::PROTOCOL_GUID, pointer(*interface)),
+                Status::SUCCESS
+            );
+            assert_eq!(
+                handles.install(*handle, &device_path, pointer(*interface + 0x10)),
+                Status::SUCCESS
+            );
+        }
+        assert_eq!(
+            handles
+                .handles(Some(&block::PROTOCOL_GUID))
+                .collect::<Vec<_>>(),
+            vec![disk, part]
+        );
+        assert_eq!(handles.handles(None).collect::<Vec<_>>(), vec![disk, part]);
+        assert_eq!(
+            handles.handles(Some(&block::DISK_IO_PROTOCOL_GUID)).count(),
+            0
+        );
+        assert_eq!(
+            handles.get(part, &block::PROTOCOL_GUID),
+            Some(pointer(0x2100))
+        );
+        assert_eq!(handles.find(&device_path), Some(pointer(0x1110)));
+        assert_eq!(
+            handles.protocols(disk).collect::<Vec<_>>(),
+            vec![&block::PROTOCOL_GUID, &device_path]
+        );
+
+        // Once per handle, and never on null
+        assert_eq!(
+            handles.install(disk, &block::PROTOCOL_GUID, pointer(0x1200)),
+            Status::INVALID_PARAMETER
+        );
+        assert_eq!(
+            handles.install(
+                core::ptr::null_mut(),
+                &block::PROTOCOL_GUID,
+                pointer(0x1200)
+            ),
+            Status::INVALID_PARAMETER
+        );
+
+        assert_eq!(
+            handles.reinstall(
+                disk,
+                &block::PROTOCOL_GUID,
+                pointer(0x1100),
+                pointer(0x1300)
+            ),
+            Status::SUCCESS
+        );
+        assert_eq!(
+            handles.uninstall(disk, &block::PROTOCOL_GUID, pointer(0x1100)),
+            Status::NOT_FOUND
+        );
+        assert_eq!(
+            handles.uninstall(disk, &block::PROTOCOL_GUID, pointer(0x1300)),
+            Status::SUCCESS
+        );
+        assert_eq!(
+            handles
+                .handles(Some(&block::PROTOCOL_GUID))
+                .collect::<Vec<_>>(),
+            vec![part]
+        );
+
+        // Gone once nothing is left on it
+        assert!(handles.contains(disk));
+        assert_eq!(
+            handles.uninstall(disk, &device_path, pointer(0x1110)),
+            Status::SUCCESS
+        );
+        assert!(!handles.contains(disk));
+        assert_eq!(handles.handles(None).collect::<Vec<_>>(), vec![part]);
+    }
+
+    // A node for the partition, as BlockWrapper has them
+    fn hard_drive(number: u8) -> Vec<u8> {
+        let mut node = vec![4, 1, 42, 0, number, 0, 0, 0];
+        node.resize(42, 0);
+        node
+    }
+
+    #[test]
+    fn test_locate_device_path() {
+        let controller = [1, 1, 6, 0, 0, 3];
+        let end = [0x7f, 0xff, 4, 0];
+        let disk_path = [&controller[..], &end].concat();
+        let part_paths: Vec<Vec<u8>> = (1..=2)
+            .map(|number| [&controller[..], &hard_drive(number), &end].concat())
+            .collect();
+
+        // The disk and both of its partitions have Block I/O and their
+        // device paths, and there is another device with only a device path
+        let mut handles = Box::new(Handles::new());
+        let device_path = r_efi::protocols::device_path::PROTOCOL_GUID;
+        let (disk, parts, other): (Handle, [Handle; 2], Handle) = (
+            pointer(0x1000),
+            [pointer(0x2000), pointer(0x3000)],
+            pointer(0x4000),
+        );
+        let paths = [&disk_path, &part_paths[0], &part_paths[1]];
+        for (handle, path) in [disk, parts[0], parts[1]].iter().zip(&paths) {
+            handles.install(*handle, &block::PROTOCOL_GUID, pointer(0x10));
+            handles.install(*handle, &device_path, path.as_ptr() as *mut c_void);
+        }
+        let other_path = [&[1, 1, 6, 0, 0, 4][..], &end].concat();
+        handles.install(other, &device_path, other_path.as_ptr() as *mut c_void);
+
+        // A file on the second partition
+        let file = [&part_paths[1][..6 + 42], &[4, 4, 6, 0, 0x41, 0], &end].concat();
+        assert_eq!(
+            unsafe { handles.locate_device_path(&block::PROTOCOL_GUID, &file) },
+            Some((parts[1], 6 + 42))
+        );
+        assert_eq!(
+            unsafe { handles.locate_device_path(&block::PROTOCOL_GUID, &part_paths[0]) },
+            Some((parts[0], 6 + 42))
+        );
+
+        // A partition without a handle of its own is on the disk
+        let third = [&controller[..], &hard_drive(3), &end].concat();
+        assert_eq!(
+            unsafe { handles.locate_device_path(&block::PROTOCOL_GUID, &third) },
+            Some((disk, 6))
+        );
+
+        // The other device has no Block I/O
+        assert_eq!(
+            unsafe { handles.locate_device_path(&block::PROTOCOL_GUID, &other_path) },
+            None
+        );
+        assert_eq!(
+            unsafe { handles.locate_device_path(&device_path, &other_path) },
+            Some((other, 6))
+        );
+    }
+}
@@ -0,0 +1,345 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Picks the loader to start from the BootOrder and Boot#### variables a
+// previous boot saved, before there's a heap for the variables to go in.
+
+use r_efi::efi;
+
+use crate::fat;
+
+use super::var;
+
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+// Big enough for any store file made for the firmware
+const STORE_BUFFER_SIZE: usize = 64 * 1024;
+
+static mut STORE_BUFFER: [u8; STORE_BUFFER_SIZE] = [0; STORE_BUFFER_SIZE];
+
+// An EFI_LOAD_OPTION, as kept in a Boot#### variable
+struct LoadOption<'a> {
+    attributes: u32,
+    file_path_list: &'a [u8],
+}
+
+fn parse_load_option(data: &[u8]) -> Option<LoadOption> {
+    let header = data.get(..6)?;
+    let attributes = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let file_path_list_length = usize::from(u16::from_le_bytes([header[4], header[5]]));
+    // The description is a NUL terminated UCS-2 string
+    let description = data[6..].chunks_exact(2).position(|c| c == [0, 0])?;
+    let start = 6 + (description + 1) * 2;
+    let file_path_list = data.get(start..start + file_path_list_length)?;
+    Some(LoadOption {
+        attributes,
+        file_path_list,
+    })
+}
+
+// The type, subtype and contents of each node of the first device path in
+// the list, stopping at any that doesn't fit
+fn device_path_nodes(list: &[u8]) -> impl Iterator<Item = (u8, u8, &[u8])> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        let header = list.get(offset..offset + 4)?;
+        let length = usize::from(u16::from_le_bytes([header[2], header[3]]));
+        if header[0] == r_efi::protocols::device_path::TYPE_END
+            || length < 4
+            || offset + length > list.len()
+        {
+            return None;
+        }
+        let node = &list[offset + 4..offset + length];
+        offset += length;
+        Some((header[0], header[1], node))
+    })
+}
+
+// The file the option loads, written into buffer, and the first LBA of the
+// partition it's on if the device path says
+fn option_path<'a>(option: &LoadOption, buffer: &'a mut [u8]) -> Option<(&'a str, Option<u64>)> {
+    let mut partition_start = None;
+    let mut length = 0;
+    for (kind, sub_type, node) in device_path_nodes(option.file_path_list) {
+        match (kind, sub_type) {
+            // Hard drive media path
+            (r_efi::protocols::device_path::TYPE_MEDIA, 1) if node.len() >= 38 => {
+                let mut start = [0; 8];
+                start.copy_from_slice(&node[4..12]);
+                partition_start = Some(u64::from_le_bytes(start));
+            }
+            // File path media path, which can be split across several nodes
+            (r_efi::protocols::device_path::TYPE_MEDIA, 4) => {
+                let chars = node
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0);
+                for c in chars {
+                    if c > 0x7f || length == buffer.len() {
+                        return None;
+                    }
+                    buffer[length] = c as u8;
+                    length += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    if length == 0 {
+        return None;
+    }
+    let path = core::str::from_utf8(&buffer[..length]).ok()?;
+    Some((path, partition_start))
+}
+
+// "Boot" followed by the option number as 4 upper case hex digits
+fn option_name(number: u16, name: &mut [u8; 8]) -> &str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    name[..4].copy_from_slice(b"Boot");
+    for i in 0..4 {
+        name[4 + i] = HEX[usize::from(number >> (12 - i * 4) & 0xf)];
+    }
+    // Safe as it is all ASCII
+    unsafe { core::str::from_utf8_unchecked(name) }
+}
+
+// Goes through BootOrder for the active options on the partition starting at
+// partition_start whose file exists, giving found the name and path of each
+// until it returns true. The length of that option's path, which is left in
+// buffer, is returned.
+fn find_option<'a, L, E, F>(
+    lookup: L,
+    partition_start: u64,
+    exists: E,
+    buffer: &mut [u8],
+    mut found: F,
+) -> Option<usize>
+where
+    L: Fn(&str) -> Option<&'a [u8]>,
+    E: Fn(&str) -> bool,
+    F: FnMut(&str, &str) -> bool,
+{
+    let order = lookup("BootOrder")?;
+    for number in order
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+    {
+        let mut name = [0; 8];
+        let name = option_name(number, &mut name);
+        let option = match lookup(name).and_then(parse_load_option) {
+            Some(option) => option,
+            None => {
+                log!("Ignoring missing or invalid {}", name);
+                continue;
+            }
+        };
+        if option.attributes & LOAD_OPTION_ACTIVE == 0 {
+            continue;
+        }
+        match option_path(&option, buffer) {
+            Some((path, start)) if start.map_or(true, |s| s == partition_start) => {
+                if !exists(path) {
+                    log!("Not using {}: {} not found", name, path);
+                } else if found(name, path) {
+                    return Some(path.len());
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// The first of those options
+fn choose_option<'a, 'b, L, E>(
+    lookup: L,
+    partition_start: u64,
+    exists: E,
+    buffer: &'b mut [u8],
+) -> Option<&'b str>
+where
+    L: Fn(&str) -> Option<&'a [u8]>,
+    E: Fn(&str) -> bool,
+{
+    let length = find_option(lookup, partition_start, exists, buffer, |name, path| {
+        log!("Using {}: {}", name, path);
+        true
+    })?;
+    core::str::from_utf8(&buffer[..length]).ok()
+}
+
+// The variable store on the filesystem, if there is one that can be used
+fn read_store(fs: &fat::Filesystem) -> Option<&'static [u8]> {
+    match var::read_store(fs, unsafe { &mut STORE_BUFFER }) {
+        Ok(store) => Some(store),
+        Err(var::StoreError::FileError(fat::Error::NotFound)) => None,
+        Err(e) => {
+            log!("Not using boot options: {:?}", e);
+            None
+        }
+    }
+}
+
+fn lookup<'a>(store: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    var::find_in_store(store, name, &efi::GLOBAL_VARIABLE_GUID)
+        .ok()
+        .flatten()
+}
+
+fn exists(fs: &fat::Filesystem, path: &str) -> bool {
+    matches!(fs.open(path), Ok(fat::Node::File(_)))
+}
+
+// The path of the loader that the boot options pick from the filesystem, or
+// None to use the default removable media path
+pub fn boot_option_path<'a>(
+    fs: &fat::Filesystem,
+    partition_start: u64,
+    buffer: &'a mut [u8],
+) -> Option<&'a str> {
+    let store = read_store(fs)?;
+    choose_option(
+        |name| lookup(store, name),
+        partition_start,
+        |path| exists(fs, path),
+        buffer,
+    )
+}
+
+// The path of each of the loaders the boot options have on the filesystem,
+// in BootOrder's order
+#[cfg(feature = "boot-menu")]
+pub fn boot_option_paths<F: FnMut(&str)>(fs: &fat::Filesystem, partition_start: u64, mut each: F) {
+    let store = match read_store(fs) {
+        Some(store) => store,
+        None => return,
+    };
+    let mut buffer = [0; 256];
+    find_option(
+        |name| lookup(store, name),
+        partition_start,
+        |path| exists(fs, path),
+        &mut buffer,
+        |_, path| {
+            each(path);
+            false
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_option, find_option, option_name, parse_load_option};
+
+    fn ucs2(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain(core::iter::once(0))
+            .flat_map(|c| c.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    // A load option for the file on the partition starting at start
+    fn load_option(attributes: u32, start: Option<u64>, path: &str) -> Vec<u8> {
+        let mut list = Vec::new();
+        if let Some(start) = start {
+            list.extend_from_slice(&[4, 1, 42, 0]);
+            list.extend_from_slice(&1u32.to_le_bytes());
+            list.extend_from_slice(&start.to_le_bytes());
+            list.extend_from_slice(&2048u64.to_le_bytes());
+            list.extend_from_slice(&[0x42; 16]);
+            list.extend_from_slice(&[2, 2]);
+        }
+        let path = ucs2(path);
+        list.extend_from_slice(&[4, 4]);
+        list.extend_from_slice(&(path.len() as u16 + 4).to_le_bytes());
+        list.extend_from_slice(&path);
+        list.extend_from_slice(&[0x7f, 0xff, 4, 0]);
+
+        let mut data = attributes.to_le_bytes().to_vec();
+        data.extend_from_slice(&(list.len() as u16).to_le_bytes());
+        data.extend_from_slice(&ucs2("Linux"));
+        data.extend_from_slice(&list);
+        data
+    }
+
+    #[test]
+    fn test_option_name() {
+        let mut name = [0; 8];
+        assert_eq!(option_name(0, &mut name), "Boot0000");
+        assert_eq!(option_name(0xa1, &mut name), "Boot00A1");
+        assert_eq!(option_name(0xbeef, &mut name), "BootBEEF");
+    }
+
+    #[test]
+    fn test_choose_option() {
+        let grub = load_option(1, Some(2048), "\\EFI\\fedora\\grubx64.efi");
+        let shim = load_option(1, None, "\\EFI\\fedora\\shimx64.efi");
+        let inactive = load_option(0, Some(2048), "\\EFI\\BOOT\\BOOTX64.EFI");
+        let elsewhere = load_option(1, Some(4096), "\\EFI\\BOOT\\BOOTX64.EFI");
+        let files = ["\\EFI\\fedora\\grubx64.efi", "\\EFI\\BOOT\\BOOTX64.EFI"];
+        let exists = |path: &str| files.contains(&path);
+
+        let choose = |order: &[u8]| {
+            let variables: [(&str, &[u8]); 6] = [
+                ("BootOrder", order),
+                ("Boot0001", &grub),
+                ("Boot0002", &shim),
+                ("Boot0003", &inactive),
+                ("Boot0004", &[1, 0, 0]),
+                ("Boot000A", &elsewhere),
+            ];
+            let lookup = |name: &str| {
+                variables
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, value)| *value)
+            };
+            let mut buffer = [0; 256];
+            choose_option(lookup, 2048, exists, &mut buffer).map(String::from)
+        };
+
+        // No BootOrder so the default path is used
+        let mut buffer = [0; 256];
+        assert_eq!(choose_option(|_| None, 2048, exists, &mut buffer), None);
+
+        // BootOrder points at grub
+        assert_eq!(
+            choose(&[1, 0, 2, 0]).as_deref(),
+            Some("\\EFI\\fedora\\grubx64.efi")
+        );
+
+        // Skipping the missing, invalid, inactive, other partition and
+        // absent file options
+        assert_eq!(
+            choose(&[5, 0, 4, 0, 3, 0, 0xa, 0, 2, 0, 1, 0]).as_deref(),
+            Some("\\EFI\\fedora\\grubx64.efi")
+        );
+        assert_eq!(choose(&[2, 0, 3, 0]), None);
+        assert_eq!(choose(&[]), None);
+
+        // Every option that could be used, in order
+        let bootx64 = load_option(1, Some(2048), "\\EFI\\BOOT\\BOOTX64.EFI");
+        let variables: [(&str, &[u8]); 4] = [
+            ("BootOrder", &[3, 0, 2, 0, 1, 0, 0xb, 0]),
+            ("Boot0001", &grub),
+            ("Boot0002", &shim),
+            ("Boot000B", &bootx64),
+        ];
+        let lookup = |name: &str| {
+            variables
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, value)| *value)
+        };
+        let mut paths = Vec::new();
+        let mut buffer = [0; 256];
+        let found = find_option(lookup, 2048, exists, &mut buffer, |_, path| {
+            paths.push(path.to_string());
+            false
+        });
+        assert_eq!(found, None);
+        assert_eq!(paths, files);
+
+        assert!(parse_load_option(&grub[..grub.len() - 1]).is_none());
+    }
+}
@@ -34,23 +34,49 @@ use r_efi::{
 };
 
 use crate::boot;
+use crate::reset;
 use crate::rtc;
 
 mod alloc;
 mod block;
 mod console;
+mod device_path;
+mod event;
 mod file;
+#[cfg(feature = "gop")]
+mod gop;
+mod handle;
+mod load_option;
+mod monotonic;
+mod pool;
+#[cfg(feature = "hash-allowlist")]
+mod secure_boot;
+#[cfg(feature = "network")]
+mod snp;
 mod var;
 
 use alloc::Allocator;
+use event::Events;
+use handle::Handles;
+use pool::Pool;
 use var::VariableAllocator;
 
+#[cfg(feature = "gop")]
+pub use gop::bochs_framebuffer;
+pub use load_option::boot_option_path;
+#[cfg(feature = "boot-menu")]
+pub use load_option::boot_option_paths;
+
 #[derive(Copy, Clone, PartialEq)]
 enum HandleType {
     None,
     Block,
     FileSystem,
     LoadedImage,
+    #[cfg(feature = "gop")]
+    Graphics,
+    #[cfg(feature = "network")]
+    Network,
 }
 
 #[repr(C)]
@@ -61,6 +87,8 @@ struct HandleWrapper {
 
 pub static ALLOCATOR: AtomicRefCell<Allocator> = AtomicRefCell::new(Allocator::new());
 
+static POOL: AtomicRefCell<Pool> = AtomicRefCell::new(Pool::new());
+
 #[cfg(not(test))]
 #[global_allocator]
 pub static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
@@ -74,6 +102,14 @@ fn heap_alloc_error_handler(layout: heap_alloc::Layout) -> ! {
 pub static VARIABLES: AtomicRefCell<VariableAllocator> =
     AtomicRefCell::new(VariableAllocator::new());
 
+static EVENTS: AtomicRefCell<Events> = AtomicRefCell::new(Events::new());
+
+static HANDLES: AtomicRefCell<Handles> = AtomicRefCell::new(Handles::new());
+
+// The ESP that non-volatile variables are saved to, while the application
+// booted from it runs and until ExitBootServices()
+static mut VARIABLE_STORE: *const crate::fat::Filesystem<'static> = core::ptr::null();
+
 static mut RS: efi::RuntimeServices = efi::RuntimeServices {
     hdr: efi::TableHeader {
         signature: efi::RUNTIME_SERVICES_SIGNATURE,
@@ -152,15 +188,20 @@ static mut BS: efi::BootServices = efi::BootServices {
     reserved: null_mut(),
 };
 
+const FIRMWARE_VENDOR: &str = "rust-hypervisor-firmware";
+
+// FIRMWARE_VENDOR in UCS-2, NUL terminated, for the system table
+static mut FIRMWARE_VENDOR_UCS2: [u16; FIRMWARE_VENDOR.len() + 1] = [0; FIRMWARE_VENDOR.len() + 1];
+
 static mut ST: efi::SystemTable = efi::SystemTable {
     hdr: efi::TableHeader {
         signature: efi::SYSTEM_TABLE_SIGNATURE,
-        revision: (2 << 16) | (80),
+        revision: efi::SYSTEM_TABLE_REVISION,
         header_size: size_of::<efi::SystemTable>() as u32,
         crc32: 0, // TODO
         reserved: 0,
     },
-    firmware_vendor: null_mut(), // TODO,
+    firmware_vendor: null_mut(),
     firmware_revision: 0,
     console_in_handle: console::STDIN_HANDLE,
     con_in: null_mut(),
@@ -179,6 +220,19 @@ static mut BLOCK_WRAPPERS: block::BlockWrappers = block::BlockWrappers {
     count: 0,
 };
 
+static MONOTONIC_COUNT: monotonic::Counter = monotonic::Counter::new();
+
+#[cfg(feature = "network")]
+static mut NETWORK_WRAPPER: *mut snp::SnpWrapper = null_mut();
+
+const MAX_CONFIGURATION_TABLES: usize = 8;
+const EMPTY_CONFIGURATION_TABLE: efi::ConfigurationTable = efi::ConfigurationTable {
+    vendor_guid: Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
+    vendor_table: null_mut(),
+};
+static mut CONFIGURATION_TABLES: [efi::ConfigurationTable; MAX_CONFIGURATION_TABLES] =
+    [EMPTY_CONFIGURATION_TABLE; MAX_CONFIGURATION_TABLES];
+
 fn convert_internal_pointer(descriptors: &[alloc::MemoryDescriptor], ptr: u64) -> Option<u64> {
     for descriptor in descriptors.iter() {
         let start = descriptor.physical_start;
@@ -220,7 +274,7 @@ pub extern "win64" fn not_available() -> Status {
     Status::UNSUPPORTED
 }
 
-pub extern "win64" fn get_time(time: *mut Time, _: *mut TimeCapabilities) -> Status {
+pub extern "win64" fn get_time(time: *mut Time, capabilities: *mut TimeCapabilities) -> Status {
     if time.is_null() {
         return Status::INVALID_PARAMETER;
     }
@@ -242,15 +296,48 @@ pub extern "win64" fn get_time(time: *mut Time, _: *mut TimeCapabilities) -> Sta
         (*time).minute = minute;
         (*time).second = second;
         (*time).nanosecond = 0;
-        (*time).timezone = 0;
+        (*time).timezone = efi::UNSPECIFIED_TIMEZONE;
         (*time).daylight = 0;
     }
 
+    if !capabilities.is_null() {
+        // The CMOS clock counts seconds and is good to about 50ppm
+        unsafe {
+            (*capabilities).resolution = 1;
+            (*capabilities).accuracy = 50_000_000;
+            (*capabilities).sets_to_zero = Boolean::FALSE;
+        }
+    }
+
     Status::SUCCESS
 }
 
-pub extern "win64" fn set_time(_: *mut Time) -> Status {
-    Status::DEVICE_ERROR
+pub extern "win64" fn set_time(time: *mut Time) -> Status {
+    if time.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let time = unsafe { &*time };
+
+    // Only years the two digit CMOS year can hold
+    if !(2000..2100).contains(&time.year)
+        || !(1..=12).contains(&time.month)
+        || !(1..=31).contains(&time.day)
+        || time.hour > 23
+        || time.minute > 59
+        || time.second > 59
+        || time.nanosecond > 999_999_999
+        || (time.timezone != efi::UNSPECIFIED_TIMEZONE && !(-1440..=1440).contains(&time.timezone))
+    {
+        return Status::INVALID_PARAMETER;
+    }
+
+    if rtc::write_date((time.year - 2000) as u8, time.month, time.day).is_err()
+        || rtc::write_time(time.hour, time.minute, time.second).is_err()
+    {
+        return Status::DEVICE_ERROR;
+    }
+
+    Status::SUCCESS
 }
 
 pub extern "win64" fn get_wakeup_time(_: *mut Boolean, _: *mut Boolean, _: *mut Time) -> Status {
@@ -295,21 +382,19 @@ pub extern "win64" fn get_variable(
     data_size: *mut usize,
     data: *mut c_void,
 ) -> Status {
-    if cfg!(feature = "efi-var") {
-        VARIABLES
-            .borrow_mut()
-            .get(variable_name, vendor_guid, attributes, data_size, data)
-    } else {
-        Status::NOT_FOUND
-    }
+    VARIABLES
+        .borrow_mut()
+        .get(variable_name, vendor_guid, attributes, data_size, data)
 }
 
 pub extern "win64" fn get_next_variable_name(
-    _: *mut usize,
-    _: *mut Char16,
-    _: *mut Guid,
+    variable_name_size: *mut usize,
+    variable_name: *mut Char16,
+    vendor_guid: *mut Guid,
 ) -> Status {
-    Status::NOT_FOUND
+    VARIABLES
+        .borrow()
+        .get_next(variable_name_size, variable_name, vendor_guid)
 }
 
 pub extern "win64" fn set_variable(
@@ -319,21 +404,42 @@ pub extern "win64" fn set_variable(
     data_size: usize,
     data: *mut c_void,
 ) -> Status {
-    if cfg!(feature = "efi-var") {
+    let status =
         VARIABLES
             .borrow_mut()
-            .set(variable_name, vendor_guid, attributes, data_size, data)
-    } else {
-        Status::UNSUPPORTED
+            .set(variable_name, vendor_guid, attributes, data_size, data);
+
+    // Deleting may have removed a non-volatile variable too
+    let persist = attributes & efi::VARIABLE_NON_VOLATILE != 0
+        || attributes & !efi::VARIABLE_APPEND_WRITE == 0;
+    let store = unsafe { VARIABLE_STORE };
+    if status == Status::SUCCESS && persist && !store.is_null() {
+        if let Err(e) = VARIABLES.borrow().save(unsafe { &*store }) {
+            log!("Failed to save variables: {:?}", e);
+        }
     }
+    status
 }
 
-pub extern "win64" fn get_next_high_mono_count(_: *mut u32) -> Status {
-    Status::DEVICE_ERROR
+pub extern "win64" fn get_next_high_mono_count(count: *mut u32) -> Status {
+    if count.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    match MONOTONIC_COUNT.next_high() {
+        Some(high) => {
+            unsafe { *count = high };
+            Status::SUCCESS
+        }
+        None => Status::DEVICE_ERROR,
+    }
 }
 
-pub extern "win64" fn reset_system(_: ResetType, _: Status, _: usize, _: *mut c_void) {
-    // Don't do anything to force the kernel to use ACPI for shutdown and triple-fault for reset
+pub extern "win64" fn reset_system(reset_type: ResetType, _: Status, _: usize, _: *mut c_void) {
+    match reset_type {
+        efi::RESET_SHUTDOWN => reset::shutdown(),
+        efi::RESET_WARM => reset::reset(false),
+        _ => reset::reset(true),
+    }
 }
 
 pub extern "win64" fn update_capsule(
@@ -367,11 +473,21 @@ pub extern "win64" fn query_variable_info(
     Status::SUCCESS
 }
 
-pub extern "win64" fn raise_tpl(_: Tpl) -> Tpl {
-    0
+// Nothing interrupts code at TPL_HIGH_LEVEL, the timer included
+pub extern "win64" fn raise_tpl(new_tpl: Tpl) -> Tpl {
+    if new_tpl >= efi::TPL_HIGH_LEVEL {
+        crate::interrupts::mask_timer();
+    }
+    EVENTS.borrow_mut().raise_tpl(new_tpl)
 }
 
-pub extern "win64" fn restore_tpl(_: Tpl) {}
+pub extern "win64" fn restore_tpl(old_tpl: Tpl) {
+    EVENTS.borrow_mut().restore_tpl(old_tpl);
+    if old_tpl < efi::TPL_HIGH_LEVEL {
+        crate::interrupts::unmask_timer();
+    }
+    poll_events();
+}
 
 pub extern "win64" fn allocate_pages(
     allocate_type: AllocateType,
@@ -432,7 +548,7 @@ pub extern "win64" fn get_memory_map(
         return Status::BUFFER_TOO_SMALL;
     }
 
-    if key.is_null() {
+    if out.is_null() || key.is_null() {
         return Status::INVALID_PARAMETER;
     }
 
@@ -453,93 +569,210 @@ pub extern "win64" fn allocate_pool(
     size: usize,
     address: *mut *mut c_void,
 ) -> Status {
-    let (status, new_address) = ALLOCATOR.borrow_mut().allocate_pages(
-        efi::ALLOCATE_ANY_PAGES,
-        memory_type,
-        ((size + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize) as u64,
-        address as u64,
-    );
+    if address.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
 
-    if status == Status::SUCCESS {
-        unsafe {
-            *address = new_address as *mut c_void;
+    match POOL
+        .borrow_mut()
+        .allocate(&mut ALLOCATOR.borrow_mut(), memory_type, size)
+    {
+        Ok(new_address) => {
+            unsafe {
+                *address = new_address as *mut c_void;
+            }
+            Status::SUCCESS
         }
+        Err(status) => status,
     }
-
-    status
 }
 
 pub extern "win64" fn free_pool(ptr: *mut c_void) -> Status {
-    ALLOCATOR.borrow_mut().free_pages(ptr as u64)
+    POOL.borrow_mut()
+        .free(&mut ALLOCATOR.borrow_mut(), ptr as u64)
+}
+
+// Current time for the event timers, in 100ns units
+fn event_time() -> u64 {
+    crate::delay::now_ns() / 100
+}
+
+// Fires any expired timers and runs the notification functions, called from
+// the services an application uses while waiting
+fn poll_events() {
+    crate::watchdog::check();
+    EVENTS.borrow_mut().poll(event_time());
+    check_watchdog();
+    event::dispatch(&EVENTS);
+}
+
+// Resets the machine once the watchdog set with SetWatchdogTimer() has
+// expired. This is also called from the timer interrupt, which can come
+// while the events are being changed, and then leaves it to the next one.
+pub fn check_watchdog() {
+    let expired = match EVENTS.try_borrow() {
+        Ok(events) => events.watchdog_expired(event_time()),
+        Err(_) => false,
+    };
+    if expired {
+        log_unlocked!("Watchdog timer expired, resetting");
+        reset::reset(true);
+    }
 }
 
 pub extern "win64" fn create_event(
-    _: u32,
-    _: Tpl,
-    _: EventNotify,
-    _: *mut c_void,
-    _: *mut Event,
+    event_type: u32,
+    notify_tpl: Tpl,
+    notify_function: EventNotify,
+    notify_context: *mut c_void,
+    event: *mut Event,
 ) -> Status {
-    Status::UNSUPPORTED
+    if event.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    // Applications pass NULL when there is no notification function
+    let notify_function: Option<EventNotify> = unsafe { transmute(notify_function) };
+    match EVENTS
+        .borrow_mut()
+        .create(event_type, notify_tpl, notify_function, notify_context)
+    {
+        Ok(e) => {
+            unsafe { *event = e };
+            Status::SUCCESS
+        }
+        Err(status) => status,
+    }
 }
 
-pub extern "win64" fn set_timer(_: Event, _: TimerDelay, _: u64) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn set_timer(event: Event, delay: TimerDelay, trigger_time: u64) -> Status {
+    EVENTS
+        .borrow_mut()
+        .set_timer(event, delay, trigger_time, event_time())
 }
 
-pub extern "win64" fn wait_for_event(_: usize, _: *mut Event, _: *mut usize) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn wait_for_event(
+    number_of_events: usize,
+    events: *mut Event,
+    index: *mut usize,
+) -> Status {
+    if number_of_events == 0 || events.is_null() || index.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    // Nothing could be signaled while waiting above TPL_APPLICATION
+    if EVENTS.borrow().tpl() != efi::TPL_APPLICATION {
+        return Status::UNSUPPORTED;
+    }
+    let events = unsafe { core::slice::from_raw_parts(events, number_of_events) };
+    loop {
+        for (i, event) in events.iter().enumerate() {
+            let status = check_event(*event);
+            if status != Status::NOT_READY {
+                unsafe { *index = i };
+                return status;
+            }
+        }
+        crate::delay::relax();
+    }
 }
 
-pub extern "win64" fn signal_event(_: Event) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn signal_event(event: Event) -> Status {
+    let status = EVENTS.borrow_mut().signal(event);
+    event::dispatch(&EVENTS);
+    status
 }
 
-pub extern "win64" fn close_event(_: Event) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn close_event(event: Event) -> Status {
+    EVENTS.borrow_mut().close(event)
 }
 
-pub extern "win64" fn check_event(_: Event) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn check_event(event: Event) -> Status {
+    poll_events();
+    let status = EVENTS.borrow_mut().check(event);
+    if status != Status::NOT_READY {
+        return status;
+    }
+    // The notification function of a wait event may have signaled it
+    event::dispatch(&EVENTS);
+    EVENTS.borrow_mut().take_signal(event)
 }
 
-const SHIM_LOCK_PROTOCOL_GUID: Guid = Guid::from_fields(
-    0x605d_ab50,
-    0xe046,
-    0x4300,
-    0xab,
-    0xb6,
-    &[0x3d, 0xd8, 0x10, 0xdd, 0x8b, 0x23],
-);
+// A handle of its own for what is installed on it, as asked for with a null
+// handle
+fn new_handle() -> Option<Handle> {
+    let mut handle = null_mut();
+    let status = allocate_pool(
+        efi::BOOT_SERVICES_DATA,
+        size_of::<HandleWrapper>(),
+        &mut handle as *mut *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        return None;
+    }
+    unsafe {
+        *(handle as *mut HandleWrapper) = HandleWrapper {
+            handle_type: HandleType::None,
+        };
+    }
+    Some(handle)
+}
+
+// For what the firmware installs itself, which can only fail once the
+// database is full
+fn install(handle: Handle, guid: Guid, interface: *mut c_void) {
+    let status = HANDLES.borrow_mut().install(handle, &guid, interface);
+    if status != Status::SUCCESS {
+        log!("Failed to install protocol: {:?}", status);
+    }
+}
 
 pub extern "win64" fn install_protocol_interface(
-    _: *mut Handle,
+    handle: *mut Handle,
     guid: *mut Guid,
-    _: InterfaceType,
-    _: *mut c_void,
+    interface_type: InterfaceType,
+    interface: *mut c_void,
 ) -> Status {
-    if unsafe { *guid } == SHIM_LOCK_PROTOCOL_GUID {
-        Status::SUCCESS
-    } else {
-        Status::UNSUPPORTED
+    if handle.is_null() || guid.is_null() || interface_type != efi::NATIVE_INTERFACE {
+        return Status::INVALID_PARAMETER;
+    }
+    // Other than a new one, the handle has to have something installed on it
+    if unsafe { *handle }.is_null() {
+        match new_handle() {
+            Some(new) => unsafe { *handle = new },
+            None => return Status::OUT_OF_RESOURCES,
+        }
+    } else if !HANDLES.borrow().contains(unsafe { *handle }) {
+        return Status::INVALID_PARAMETER;
     }
+    HANDLES
+        .borrow_mut()
+        .install(unsafe { *handle }, unsafe { &*guid }, interface)
 }
 
 pub extern "win64" fn reinstall_protocol_interface(
-    _: Handle,
-    _: *mut Guid,
-    _: *mut c_void,
-    _: *mut c_void,
+    handle: Handle,
+    guid: *mut Guid,
+    old_interface: *mut c_void,
+    new_interface: *mut c_void,
 ) -> Status {
-    Status::NOT_FOUND
+    if guid.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    HANDLES
+        .borrow_mut()
+        .reinstall(handle, unsafe { &*guid }, old_interface, new_interface)
 }
 
 pub extern "win64" fn uninstall_protocol_interface(
-    _: Handle,
-    _: *mut Guid,
-    _: *mut c_void,
+    handle: Handle,
+    guid: *mut Guid,
+    interface: *mut c_void,
 ) -> Status {
-    Status::NOT_FOUND
+    if guid.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    HANDLES
+        .borrow_mut()
+        .uninstall(handle, unsafe { &*guid }, interface)
 }
 
 pub extern "win64" fn handle_protocol(
@@ -547,7 +780,14 @@ pub extern "win64" fn handle_protocol(
     guid: *mut Guid,
     out: *mut *mut c_void,
 ) -> Status {
-    open_protocol(handle, guid, out, null_mut(), null_mut(), 0)
+    open_protocol(
+        handle,
+        guid,
+        out,
+        null_mut(),
+        null_mut(),
+        efi::OPEN_PROTOCOL_BY_HANDLE_PROTOCOL,
+    )
 }
 
 pub extern "win64" fn register_protocol_notify(
@@ -558,103 +798,230 @@ pub extern "win64" fn register_protocol_notify(
     Status::UNSUPPORTED
 }
 
+// The protocol to look for, or None for all handles. Waiting for protocols
+// to be installed isn't supported, so there is nothing to find by the key
+// that RegisterProtocolNotify() would have given.
+fn search_guid<'a>(
+    search_type: LocateSearchType,
+    guid: *mut Guid,
+) -> Result<Option<&'a Guid>, Status> {
+    match search_type {
+        efi::ALL_HANDLES => Ok(None),
+        efi::BY_REGISTER_NOTIFY => Err(Status::NOT_FOUND),
+        efi::BY_PROTOCOL if !guid.is_null() => Ok(Some(unsafe { &*guid })),
+        _ => Err(Status::INVALID_PARAMETER),
+    }
+}
+
 pub extern "win64" fn locate_handle(
-    _: LocateSearchType,
+    search_type: LocateSearchType,
     guid: *mut Guid,
     _: *mut c_void,
     size: *mut usize,
     handles: *mut Handle,
 ) -> Status {
-    if unsafe { *guid } == block::PROTOCOL_GUID {
-        let count = unsafe { BLOCK_WRAPPERS.count };
-        if unsafe { *size } < size_of::<Handle>() * count {
-            unsafe { *size = size_of::<Handle>() * count };
-            return Status::BUFFER_TOO_SMALL;
-        }
-
-        let handles =
-            unsafe { core::slice::from_raw_parts_mut(handles, *size / size_of::<Handle>()) };
-
-        let wrappers_as_handles: &[Handle] = unsafe {
-            core::slice::from_raw_parts_mut(
-                BLOCK_WRAPPERS.wrappers.as_mut_ptr() as *mut *mut block::BlockWrapper
-                    as *mut Handle,
-                count,
-            )
-        };
-
-        handles[0..count].copy_from_slice(wrappers_as_handles);
-
-        unsafe { *size = size_of::<Handle>() * count };
+    let guid = match search_guid(search_type, guid) {
+        Ok(guid) => guid,
+        Err(status) => return status,
+    };
+    if size.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
 
-        return Status::SUCCESS;
+    let database = HANDLES.borrow();
+    let count = database.handles(guid).count();
+    if count == 0 {
+        return Status::NOT_FOUND;
+    }
+    let needed = size_of::<Handle>() * count;
+    if unsafe { *size } < needed {
+        unsafe { *size = needed };
+        return Status::BUFFER_TOO_SMALL;
+    }
+    if handles.is_null() {
+        return Status::INVALID_PARAMETER;
     }
 
-    Status::UNSUPPORTED
+    for (i, handle) in database.handles(guid).enumerate() {
+        unsafe { *handles.add(i) = handle };
+    }
+    unsafe { *size = needed };
+    Status::SUCCESS
 }
 
+// Finds the handle with the protocol that is closest to the device path, and
+// moves the path on past what the handle's own device path covers
 pub extern "win64" fn locate_device_path(
-    _: *mut Guid,
-    _: *mut *mut DevicePathProtocol,
-    _: *mut *mut c_void,
+    guid: *mut Guid,
+    device_path: *mut *mut DevicePathProtocol,
+    device: *mut Handle,
 ) -> Status {
-    Status::NOT_FOUND
+    if guid.is_null() || device_path.is_null() || unsafe { *device_path }.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let (handle, length) = unsafe {
+        let path = device_path::path_bytes(*device_path);
+        match HANDLES.borrow().locate_device_path(&*guid, path) {
+            Some(closest) => closest,
+            None => return Status::NOT_FOUND,
+        }
+    };
+    if device.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    unsafe {
+        *device = handle;
+        *device_path = (*device_path as *mut u8).add(length) as *mut DevicePathProtocol;
+    }
+    Status::SUCCESS
 }
 
 pub extern "win64" fn install_configuration_table(_: *mut Guid, _: *mut c_void) -> Status {
     Status::UNSUPPORTED
 }
 
+// Lets the PE loader read an image that is in memory, as given to
+// LoadImage() or downloaded
+pub struct BufferFile<'a> {
+    data: &'a [u8],
+    position: u32,
+}
+
+impl<'a> BufferFile<'a> {
+    pub fn new(data: &'a [u8]) -> BufferFile<'a> {
+        BufferFile { data, position: 0 }
+    }
+}
+
+impl<'a> crate::fat::Read for BufferFile<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, crate::fat::Error> {
+        let size = self.get_size();
+        if self.position >= size {
+            return Err(crate::fat::Error::EndOfFile);
+        }
+        let bytes = core::cmp::min(data.len() as u32, size - self.position);
+        let start = self.position as usize;
+        data[..bytes as usize].copy_from_slice(&self.data[start..start + bytes as usize]);
+        self.position += bytes;
+        Ok(bytes)
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), crate::fat::Error> {
+        if position >= self.get_size() {
+            return Err(crate::fat::Error::EndOfFile);
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    fn get_size(&self) -> u32 {
+        self.data.len() as u32
+    }
+}
+
+// An image's Authenticode hash, and the digest it is measured with. That is
+// the hash of the whole file if it couldn't be parsed for an Authenticode
+// one, so nothing is started unmeasured.
+fn inspect_image(
+    file: &mut dyn crate::fat::Read,
+) -> (
+    Result<crate::pe::Authenticode, crate::pe::Error>,
+    Option<[u8; 32]>,
+) {
+    let authenticode = crate::pe::Loader::new(file).authenticode();
+    let digest = match &authenticode {
+        Ok(authenticode) => Some(authenticode.hash),
+        Err(_) => file.read_all(&mut |_, _| {}).ok(),
+    };
+    (authenticode, digest)
+}
+
 pub extern "win64" fn load_image(
     _boot_policy: Boolean,
     parent_image_handle: Handle,
     device_path: *mut DevicePathProtocol,
-    _source_buffer: *mut c_void,
-    _source_size: usize,
+    source_buffer: *mut c_void,
+    source_size: usize,
     image_handle: *mut Handle,
 ) -> Status {
-    use crate::fat::Read;
+    if parent_image_handle.is_null() || image_handle.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
 
     let mut path = [0_u8; 256];
-    let device_path = unsafe { &*device_path };
-    extract_path(device_path, &mut path);
+    if !device_path.is_null() {
+        extract_path(unsafe { &*device_path }, &mut path);
+    }
     let path = crate::common::ascii_strip(&path);
 
-    let li = parent_image_handle as *const LoadedImageWrapper;
-    let dh = unsafe { (*li).proto.device_handle };
-    let wrapped_fs_ref = unsafe { &*(dh as *const file::FileSystemWrapper) };
-    let mut file = match wrapped_fs_ref.fs.open(path) {
-        Ok(file) => file,
-        Err(_) => return Status::DEVICE_ERROR,
+    let parent = match loaded_image(parent_image_handle) {
+        Some(image) => image,
+        None => return Status::INVALID_PARAMETER,
     };
+    // Images that weren't loaded from the ESP have no device to load from
+    let dh = unsafe { (*parent).proto.device_handle };
+
+    let mut buffer_file;
+    let mut fs_file;
+    let file: &mut dyn crate::fat::Read = if !source_buffer.is_null() {
+        let data = unsafe { core::slice::from_raw_parts(source_buffer as *const u8, source_size) };
+        buffer_file = BufferFile::new(data);
+        &mut buffer_file
+    } else {
+        let wrapped_fs_ref = match file_system(dh) {
+            Some(fs) if !path.is_empty() => fs,
+            _ => return Status::NOT_FOUND,
+        };
+        fs_file = match wrapped_fs_ref.fs.open(path) {
+            Ok(file) => file,
+            Err(_) => return Status::NOT_FOUND,
+        };
+        &mut fs_file
+    };
+
+    #[cfg_attr(not(feature = "hash-allowlist"), allow(unused_variables))]
+    let (authenticode, digest) = inspect_image(file);
+    #[cfg(feature = "hash-allowlist")]
+    if let Err(status) = secure_boot::verify(&authenticode) {
+        return status;
+    }
+    match digest {
+        Some(digest) => {
+            crate::tpm::measure(crate::tpm::PCR_BOOT_APPLICATION, &digest, path.as_bytes())
+        }
+        None => return Status::LOAD_ERROR,
+    }
 
-    let file_size = (file.get_size() as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut l = crate::pe::Loader::new(file);
+    let pages = match l.image_size() {
+        Ok(size) => (size + PAGE_SIZE - 1) / PAGE_SIZE,
+        Err(_) => return Status::LOAD_ERROR,
+    };
     // Get free pages address
-    let load_addr =
-        match ALLOCATOR
-            .borrow_mut()
-            .find_free_pages(efi::ALLOCATE_ANY_PAGES, file_size, 0)
-        {
-            Some(a) => a,
-            None => return Status::OUT_OF_RESOURCES,
-        };
+    let load_addr = match ALLOCATOR
+        .borrow_mut()
+        .find_free_pages(efi::ALLOCATE_ANY_PAGES, pages, 0)
+    {
+        Some(a) => a,
+        None => return Status::OUT_OF_RESOURCES,
+    };
 
-    let mut l = crate::pe::Loader::new(&mut file);
     let (entry_addr, load_addr, load_size) = match l.load(load_addr) {
         Ok(load_info) => load_info,
-        Err(_) => return Status::DEVICE_ERROR,
+        Err(crate::pe::Error::FileError) => return Status::DEVICE_ERROR,
+        Err(_) => return Status::LOAD_ERROR,
     };
     ALLOCATOR.borrow_mut().allocate_pages(
         efi::ALLOCATE_ADDRESS,
         efi::LOADER_CODE,
-        file_size,
+        pages,
         load_addr,
     );
 
     let image = new_image_handle(
         path,
         parent_image_handle,
-        wrapped_fs_ref as *const _ as Handle,
+        dh,
         load_addr,
         load_size,
         entry_addr,
@@ -665,42 +1032,157 @@ pub extern "win64" fn load_image(
     Status::SUCCESS
 }
 
+extern "sysv64" {
+    // In asm/efi.s
+    fn efi_start_image(
+        entry: u64,
+        handle: Handle,
+        system_table: *mut efi::SystemTable,
+        stack: *mut u64,
+    ) -> Status;
+    fn efi_exit_image(stack: u64, status: Status) -> !;
+}
+
+fn file_system(handle: Handle) -> Option<&'static file::FileSystemWrapper<'static>> {
+    if !HANDLES.borrow().contains(handle) {
+        return None;
+    }
+    if unsafe { (*(handle as *const HandleWrapper)).handle_type } != HandleType::FileSystem {
+        return None;
+    }
+    Some(unsafe { &*(handle as *const file::FileSystemWrapper) })
+}
+
+fn loaded_image(handle: Handle) -> Option<*mut LoadedImageWrapper> {
+    if !HANDLES.borrow().contains(handle) {
+        return None;
+    }
+    let image = handle as *mut LoadedImageWrapper;
+    if unsafe { (*image).hw.handle_type } != HandleType::LoadedImage {
+        return None;
+    }
+    Some(image)
+}
+
 pub extern "win64" fn start_image(
     image_handle: Handle,
-    _: *mut usize,
-    _: *mut *mut Char16,
+    exit_data_size: *mut usize,
+    exit_data: *mut *mut Char16,
 ) -> Status {
-    let wrapped_handle = image_handle as *const LoadedImageWrapper;
-    let address = unsafe { (*wrapped_handle).entry_point };
-    let ptr = address as *const ();
-    let code: extern "win64" fn(Handle, *mut efi::SystemTable) -> Status =
-        unsafe { core::mem::transmute(ptr) };
-    (code)(image_handle, unsafe { &mut ST })
+    let image = match loaded_image(image_handle) {
+        Some(image) => image,
+        None => return Status::INVALID_PARAMETER,
+    };
+    // Already running
+    if unsafe { (*image).exit_stack } != 0 {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let status = unsafe {
+        (*image).exit_data_size = 0;
+        (*image).exit_data = null_mut();
+        efi_start_image(
+            (*image).entry_point,
+            image_handle,
+            &mut ST,
+            &mut (*image).exit_stack,
+        )
+    };
+
+    unsafe {
+        (*image).exit_stack = 0;
+        if !exit_data_size.is_null() {
+            *exit_data_size = (*image).exit_data_size;
+        }
+        if !exit_data.is_null() {
+            *exit_data = (*image).exit_data;
+        }
+    }
+    status
 }
 
-pub extern "win64" fn exit(_: Handle, _: Status, _: usize, _: *mut Char16) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn exit(
+    image_handle: Handle,
+    status: Status,
+    exit_data_size: usize,
+    exit_data: *mut Char16,
+) -> Status {
+    let image = match loaded_image(image_handle) {
+        Some(image) => image,
+        None => return Status::INVALID_PARAMETER,
+    };
+    let stack = unsafe { (*image).exit_stack };
+    // Not started
+    if stack == 0 {
+        return Status::INVALID_PARAMETER;
+    }
+
+    unsafe {
+        (*image).exit_data_size = exit_data_size;
+        (*image).exit_data = exit_data;
+        efi_exit_image(stack, status)
+    }
 }
 
 pub extern "win64" fn unload_image(_: Handle) -> Status {
     Status::UNSUPPORTED
 }
 
-pub extern "win64" fn exit_boot_services(_: Handle, _: usize) -> Status {
+pub extern "win64" fn exit_boot_services(_: Handle, key: usize) -> Status {
+    // The loader must have the current memory map. If not it gets it again
+    // and tries again, so nothing is done until it does.
+    if key != ALLOCATOR.borrow().get_map_key() {
+        return Status::INVALID_PARAMETER;
+    }
+    EVENTS
+        .borrow_mut()
+        .signal_type(efi::EVT_SIGNAL_EXIT_BOOT_SERVICES);
+    event::dispatch(&EVENTS);
+    EVENTS.borrow_mut().set_watchdog(0, 0);
+    crate::watchdog::disarm();
+    // The disk belongs to the OS from now on
+    unsafe { VARIABLE_STORE = core::ptr::null() };
+    #[cfg(feature = "network")]
+    unsafe {
+        if let Some(sw) = NETWORK_WRAPPER.as_mut() {
+            sw.exit_boot_services();
+        }
+    }
     Status::SUCCESS
 }
 
-pub extern "win64" fn get_next_monotonic_count(_: *mut u64) -> Status {
-    Status::DEVICE_ERROR
+pub extern "win64" fn get_next_monotonic_count(count: *mut u64) -> Status {
+    if count.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    match MONOTONIC_COUNT.next() {
+        Some(next) => {
+            unsafe { *count = next };
+            Status::SUCCESS
+        }
+        None => Status::DEVICE_ERROR,
+    }
 }
 
 pub extern "win64" fn stall(microseconds: usize) -> Status {
     crate::delay::udelay(microseconds as u64);
+    poll_events();
     Status::SUCCESS
 }
 
-pub extern "win64" fn set_watchdog_timer(_: usize, _: u64, _: usize, _: *mut Char16) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn set_watchdog_timer(
+    timeout: usize,
+    _: u64,
+    data_size: usize,
+    data: *mut Char16,
+) -> Status {
+    if data_size != 0 && data.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    EVENTS
+        .borrow_mut()
+        .set_watchdog(timeout as u64, event_time());
+    Status::SUCCESS
 }
 
 pub extern "win64" fn connect_controller(
@@ -722,61 +1204,26 @@ pub extern "win64" fn open_protocol(
     out: *mut *mut c_void,
     _: Handle,
     _: Handle,
-    _: u32,
+    attributes: u32,
 ) -> Status {
-    let hw = handle as *const HandleWrapper;
-    let handle_type = unsafe { (*hw).handle_type };
-    if unsafe { *guid } == r_efi::protocols::loaded_image::PROTOCOL_GUID
-        && handle_type == HandleType::LoadedImage
-    {
-        unsafe {
-            *out = &mut (*(handle as *mut LoadedImageWrapper)).proto as *mut _ as *mut c_void;
-        }
-        return Status::SUCCESS;
-    }
-
-    if unsafe { *guid } == r_efi::protocols::simple_file_system::PROTOCOL_GUID
-        && handle_type == HandleType::FileSystem
-    {
-        unsafe {
-            *out = &mut (*(handle as *mut file::FileSystemWrapper)).proto as *mut _ as *mut c_void;
-        }
-        return Status::SUCCESS;
+    // Only testing for the protocol doesn't need anywhere to put it
+    if guid.is_null() || (out.is_null() && attributes != efi::OPEN_PROTOCOL_TEST_PROTOCOL) {
+        return Status::INVALID_PARAMETER;
     }
 
-    if unsafe { *guid } == r_efi::protocols::device_path::PROTOCOL_GUID
-        && handle_type == HandleType::Block
-    {
-        unsafe {
-            *out = &mut (*(handle as *mut block::BlockWrapper)).controller_path as *mut _
-                as *mut c_void;
-        }
-
-        return Status::SUCCESS;
+    let database = HANDLES.borrow();
+    if !database.contains(handle) {
+        return Status::INVALID_PARAMETER;
     }
-
-    if unsafe { *guid } == r_efi::protocols::device_path::PROTOCOL_GUID
-        && handle_type == HandleType::FileSystem
-    {
-        unsafe {
-            if let Some(block_part_id) = (*(handle as *mut file::FileSystemWrapper)).block_part_id {
-                *out = (&mut (*(BLOCK_WRAPPERS.wrappers[block_part_id as usize])).controller_path)
-                    as *mut _ as *mut c_void;
-
-                return Status::SUCCESS;
+    match database.get(handle, unsafe { &*guid }) {
+        Some(interface) => {
+            if !out.is_null() {
+                unsafe { *out = interface };
             }
+            Status::SUCCESS
         }
+        None => Status::UNSUPPORTED,
     }
-
-    if unsafe { *guid } == block::PROTOCOL_GUID && handle_type == HandleType::Block {
-        unsafe {
-            *out = &mut (*(handle as *mut block::BlockWrapper)).proto as *mut _ as *mut c_void;
-        }
-
-        return Status::SUCCESS;
-    }
-
-    Status::UNSUPPORTED
 }
 
 pub extern "win64" fn close_protocol(_: Handle, _: *mut Guid, _: Handle, _: Handle) -> Status {
@@ -792,26 +1239,97 @@ pub extern "win64" fn open_protocol_information(
     Status::UNSUPPORTED
 }
 
+// The caller frees the buffer
 pub extern "win64" fn protocols_per_handle(
-    _: Handle,
-    _: *mut *mut *mut Guid,
-    _: *mut usize,
+    handle: Handle,
+    buffer: *mut *mut *mut Guid,
+    count: *mut usize,
 ) -> Status {
-    Status::UNSUPPORTED
+    if buffer.is_null() || count.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let database = HANDLES.borrow();
+    if !database.contains(handle) {
+        return Status::INVALID_PARAMETER;
+    }
+    let protocols = database.protocols(handle).count();
+    let mut out = null_mut();
+    let status = allocate_pool(
+        efi::BOOT_SERVICES_DATA,
+        size_of::<*mut Guid>() * protocols,
+        &mut out as *mut *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        return status;
+    }
+    let out = out as *mut *mut Guid;
+    for (i, guid) in database.protocols(handle).enumerate() {
+        unsafe { *out.add(i) = guid as *const _ as *mut Guid };
+    }
+    unsafe {
+        *buffer = out;
+        *count = protocols;
+    }
+    Status::SUCCESS
 }
 
+// As with protocols_per_handle() the caller frees the buffer
 pub extern "win64" fn locate_handle_buffer(
-    _: LocateSearchType,
-    _: *mut Guid,
+    search_type: LocateSearchType,
+    guid: *mut Guid,
     _: *mut c_void,
-    _: *mut usize,
-    _: *mut *mut Handle,
+    count: *mut usize,
+    buffer: *mut *mut Handle,
 ) -> Status {
-    Status::UNSUPPORTED
+    let guid = match search_guid(search_type, guid) {
+        Ok(guid) => guid,
+        Err(status) => return status,
+    };
+    if count.is_null() || buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+
+    let database = HANDLES.borrow();
+    let handles = database.handles(guid).count();
+    if handles == 0 {
+        return Status::NOT_FOUND;
+    }
+    let mut out = null_mut();
+    let status = allocate_pool(
+        efi::BOOT_SERVICES_DATA,
+        size_of::<Handle>() * handles,
+        &mut out as *mut *mut c_void,
+    );
+    if status != Status::SUCCESS {
+        return status;
+    }
+    let out = out as *mut Handle;
+    for (i, handle) in database.handles(guid).enumerate() {
+        unsafe { *out.add(i) = handle };
+    }
+    unsafe {
+        *buffer = out;
+        *count = handles;
+    }
+    Status::SUCCESS
 }
 
-pub extern "win64" fn locate_protocol(_: *mut Guid, _: *mut c_void, _: *mut *mut c_void) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn locate_protocol(
+    guid: *mut Guid,
+    _: *mut c_void,
+    out: *mut *mut c_void,
+) -> Status {
+    if guid.is_null() || out.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    match HANDLES.borrow().find(unsafe { &*guid }) {
+        Some(interface) => {
+            unsafe { *out = interface };
+            Status::SUCCESS
+        }
+        None => Status::NOT_FOUND,
+    }
 }
 
 pub extern "win64" fn install_multiple_protocol_interfaces(
@@ -830,23 +1348,62 @@ pub extern "win64" fn uninstall_multiple_protocol_interfaces(
     Status::UNSUPPORTED
 }
 
-pub extern "win64" fn calculate_crc32(_: *mut c_void, _: usize, _: *mut u32) -> Status {
-    Status::UNSUPPORTED
+pub extern "win64" fn calculate_crc32(
+    data: *mut c_void,
+    data_size: usize,
+    crc32: *mut u32,
+) -> Status {
+    if data.is_null() || data_size == 0 || crc32.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let data = unsafe { core::slice::from_raw_parts(data as *const u8, data_size) };
+    unsafe { *crc32 = crate::common::crc32(0, data) };
+    Status::SUCCESS
 }
 
-pub extern "win64" fn copy_mem(_: *mut c_void, _: *mut c_void, _: usize) {}
+pub extern "win64" fn copy_mem(destination: *mut c_void, source: *mut c_void, length: usize) {
+    unsafe { crate::mem::copy(destination as *mut u8, source as *const u8, length) }
+}
 
-pub extern "win64" fn set_mem(_: *mut c_void, _: usize, _: u8) {}
+pub extern "win64" fn set_mem(buffer: *mut c_void, size: usize, value: u8) {
+    unsafe { crate::mem::fill(buffer as *mut u8, size, value) }
+}
+
+const EVENT_GROUP_EXIT_BOOT_SERVICES: Guid = Guid::from_fields(
+    0x27ab_f055,
+    0xb1b8,
+    0x4c26,
+    0x80,
+    0x48,
+    &[0x74, 0x8f, 0x37, 0xba, 0xa2, 0xdf],
+);
 
 pub extern "win64" fn create_event_ex(
-    _: u32,
-    _: Tpl,
-    _: EventNotify,
-    _: *const c_void,
-    _: *const Guid,
-    _: *mut Event,
+    event_type: u32,
+    notify_tpl: Tpl,
+    notify_function: EventNotify,
+    notify_context: *const c_void,
+    event_group: *const Guid,
+    event: *mut Event,
 ) -> Status {
-    Status::UNSUPPORTED
+    // Only the ExitBootServices() group is ever signaled, members of other
+    // groups behave as ordinary events
+    let event_type =
+        if !event_group.is_null() && unsafe { *event_group } == EVENT_GROUP_EXIT_BOOT_SERVICES {
+            if event_type & !efi::EVT_NOTIFY_SIGNAL != 0 {
+                return Status::INVALID_PARAMETER;
+            }
+            efi::EVT_SIGNAL_EXIT_BOOT_SERVICES
+        } else {
+            event_type
+        };
+    create_event(
+        event_type,
+        notify_tpl,
+        notify_function,
+        notify_context as *mut c_void,
+        event,
+    )
 }
 
 extern "win64" fn image_unload(_: Handle) -> Status {
@@ -863,7 +1420,7 @@ fn extract_path(device_path: &DevicePathProtocol, path: &mut [u8]) {
             return;
         }
         if dp.r#type == r_efi::protocols::device_path::TYPE_END && dp.sub_type == 0xff {
-            panic!("Failed to extract path");
+            return;
         }
         let len = unsafe { core::mem::transmute::<[u8; 2], u16>(dp.length) };
         dp = unsafe { &*((dp as *const _ as u64 + len as u64) as *const _) };
@@ -885,17 +1442,75 @@ const PAGE_SIZE: u64 = 4096;
 const HEAP_SIZE: usize = 256 * 1024 * 1024;
 
 // Populate allocator from E820, fixed ranges for the firmware and the loaded binary.
-fn populate_allocator(info: &dyn boot::Info, image_address: u64, image_size: u64) {
-    for i in 0..info.num_entries() {
-        let entry = info.entry(i);
-        if entry.entry_type == boot::E820Entry::RAM_TYPE {
-            ALLOCATOR.borrow_mut().add_initial_allocation(
-                efi::CONVENTIONAL_MEMORY,
-                entry.size / PAGE_SIZE,
-                entry.addr,
-                efi::MEMORY_WB,
-            );
+fn populate_allocator(
+    info: &dyn boot::Info,
+    image_address: u64,
+    image_size: u64,
+    reserved: &[(u64, u64)],
+) {
+    // The allocator wants the regions in order and without overlaps. RAM is
+    // trimmed to whole pages while anything else grows to cover its pages.
+    // RAM that couldn't be identity mapped is left out, as nothing could use
+    // what is allocated there.
+    let mapped_size = crate::paging::mapped_size();
+    let mut unmapped_ram = 0;
+    let mut regions = [(0, 0, efi::RESERVED_MEMORY_TYPE); 128];
+    let count = core::cmp::min(usize::from(info.num_entries()), regions.len());
+    for (i, region) in regions.iter_mut().take(count).enumerate() {
+        let entry = info.entry(i as u8);
+        let end = entry.addr + entry.size;
+        *region = match entry.entry_type {
+            boot::E820Entry::RAM_TYPE => {
+                unmapped_ram += end.saturating_sub(core::cmp::max(entry.addr, mapped_size));
+                (
+                    (entry.addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+                    core::cmp::min(end, mapped_size) & !(PAGE_SIZE - 1),
+                    efi::CONVENTIONAL_MEMORY,
+                )
+            }
+            t => (
+                entry.addr & !(PAGE_SIZE - 1),
+                (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+                match t {
+                    boot::E820Entry::ACPI_TYPE => efi::ACPI_RECLAIM_MEMORY,
+                    boot::E820Entry::NVS_TYPE => efi::ACPI_MEMORY_NVS,
+                    _ => efi::RESERVED_MEMORY_TYPE,
+                },
+            ),
+        };
+    }
+    let regions = &mut regions[..count];
+    regions.sort_unstable_by_key(|(start, _, _)| *start);
+
+    let mut previous_end = 0;
+    let (mut ram, mut high_ram) = (0, 0);
+    for (start, end, memory_type) in regions.iter() {
+        let start = core::cmp::max(*start, previous_end);
+        if *end <= start {
+            continue;
         }
+        if *memory_type == efi::CONVENTIONAL_MEMORY {
+            ram += end - start;
+            high_ram += end.saturating_sub(core::cmp::max(start, 1 << 32));
+        }
+        ALLOCATOR.borrow_mut().add_initial_allocation(
+            *memory_type,
+            (end - start) / PAGE_SIZE,
+            start,
+            efi::MEMORY_WB,
+        );
+        previous_end = *end;
+    }
+    log!(
+        "{} MiB of RAM, {} MiB above 4 GiB",
+        ram >> 20,
+        high_ram >> 20
+    );
+    if unmapped_ram != 0 {
+        log!(
+            "Leaving out {} MiB of RAM that isn't identity mapped",
+            unmapped_ram >> 20
+        );
     }
 
     let ram_min = unsafe { &RAM_MIN as *const _ as u64 };
@@ -935,6 +1550,18 @@ fn populate_allocator(info: &dyn boot::Info, image_address: u64, image_size: u64
         image_address,
     );
 
+    // And anything else it needs left alone
+    for (address, size) in reserved.iter().filter(|(_, size)| *size != 0) {
+        let start = address & !(PAGE_SIZE - 1);
+        let end = (address + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        ALLOCATOR.borrow_mut().allocate_pages(
+            efi::ALLOCATE_ADDRESS,
+            efi::LOADER_DATA,
+            (end - start) / PAGE_SIZE,
+            start,
+        );
+    }
+
     // Initialize heap allocator
     init_heap_allocator(HEAP_SIZE);
 }
@@ -961,9 +1588,50 @@ struct LoadedImageWrapper {
     hw: HandleWrapper,
     proto: LoadedImageProtocol,
     entry_point: u64,
+    // Set while the image is running, for Exit() to return to StartImage()
+    exit_stack: u64,
+    exit_data_size: usize,
+    exit_data: *mut Char16,
 }
 
-type DevicePaths = [file::FileDevicePathProtocol; 2];
+// Builds the FilePath for a loaded image: a file path media node holding the
+// name of the file on its device, followed by the end node. Nodes are found
+// by their lengths so the end node has to come straight after the name.
+fn new_file_path(path: &str) -> *mut DevicePathProtocol {
+    let header_size = size_of::<DevicePathProtocol>();
+    let node_size = header_size + 2 * (path.len() + 1);
+
+    let mut file_path = null_mut();
+    let status = allocate_pool(
+        efi::LOADER_DATA,
+        node_size + header_size,
+        &mut file_path as *mut *mut c_void,
+    );
+    assert!(status == Status::SUCCESS);
+
+    let node = file_path as *mut DevicePathProtocol;
+    unsafe {
+        *node = DevicePathProtocol {
+            r#type: r_efi::protocols::device_path::TYPE_MEDIA,
+            sub_type: 4, // Media Path type file
+            length: (node_size as u16).to_le_bytes(),
+        };
+        let name = core::slice::from_raw_parts_mut(
+            (file_path as *mut u8).add(header_size) as *mut u16,
+            path.len() + 1,
+        );
+        for (i, c) in path.bytes().enumerate() {
+            name[i] = u16::from(c);
+        }
+        name[path.len()] = 0;
+        *((file_path as *mut u8).add(node_size) as *mut DevicePathProtocol) = DevicePathProtocol {
+            r#type: r_efi::protocols::device_path::TYPE_END,
+            sub_type: 0xff, // End of full path
+            length: [4, 0],
+        };
+    }
+    node
+}
 
 fn new_image_handle(
     path: &str,
@@ -973,37 +1641,10 @@ fn new_image_handle(
     load_size: u64,
     entry_addr: u64,
 ) -> *mut LoadedImageWrapper {
-    let mut file_paths = null_mut();
-    let status = allocate_pool(
-        efi::LOADER_DATA,
-        size_of::<DevicePaths>(),
-        &mut file_paths as *mut *mut c_void,
-    );
-    assert!(status == Status::SUCCESS);
-    let file_paths = unsafe { &mut *(file_paths as *mut DevicePaths) };
-    *file_paths = [
-        file::FileDevicePathProtocol {
-            device_path: DevicePathProtocol {
-                r#type: r_efi::protocols::device_path::TYPE_MEDIA,
-                sub_type: 4, // Media Path type file
-                length: [132, 0],
-            },
-            filename: [0; 64],
-        },
-        file::FileDevicePathProtocol {
-            device_path: DevicePathProtocol {
-                r#type: r_efi::protocols::device_path::TYPE_END,
-                sub_type: 0xff, // End of full path
-                length: [4, 0],
-            },
-            filename: [0; 64],
-        },
-    ];
-
-    crate::common::ascii_to_ucs2(path, &mut file_paths[0].filename);
+    let file_path = new_file_path(path);
 
     let mut image = null_mut();
-    allocate_pool(
+    let status = allocate_pool(
         efi::LOADER_DATA,
         size_of::<LoadedImageWrapper>(),
         &mut image as *mut *mut c_void,
@@ -1019,7 +1660,7 @@ fn new_image_handle(
             parent_handle,
             system_table: unsafe { &mut ST },
             device_handle,
-            file_path: &mut file_paths[0].device_path, // Pointer to first path entry
+            file_path,
             load_options_size: 0,
             load_options: null_mut(),
             image_base: load_addr as *mut _,
@@ -1030,36 +1671,77 @@ fn new_image_handle(
             reserved: null_mut(),
         },
         entry_point: entry_addr,
+        exit_stack: 0,
+        exit_data_size: 0,
+        exit_data: null_mut(),
     };
+    install(
+        image as *mut _ as Handle,
+        r_efi::protocols::loaded_image::PROTOCOL_GUID,
+        &mut image.proto as *mut _ as *mut c_void,
+    );
     image
 }
 
-pub fn efi_exec(
-    address: u64,
-    loaded_address: u64,
-    loaded_size: u64,
-    info: &dyn boot::Info,
-    fs: &crate::fat::Filesystem,
-    block: *const crate::block::VirtioBlockDevice,
-) {
-    let vendor_data = 0u32;
-    let acpi_rsdp_ptr = info.rsdp_addr();
-
-    let mut ct = if acpi_rsdp_ptr != 0 {
-        efi::ConfigurationTable {
-            vendor_guid: Guid::from_fields(
-                0x8868_e871,
-                0xe4f1,
-                0x11d3,
-                0xbc,
-                0x22,
-                &[0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
-            ),
-            vendor_table: acpi_rsdp_ptr as u64 as *mut _,
+// Where Linux looks for the TPM event log, when there is no TCG2 protocol to
+// get it from
+const LINUX_EFI_TPM_EVENT_LOG_GUID: Guid = Guid::from_fields(
+    0xb779_9cb0,
+    0xeca2,
+    0x4943,
+    0x96,
+    0x67,
+    &[0x1f, 0xae, 0x07, 0xb7, 0x47, 0xfa],
+);
+
+unsafe fn add_configuration_table(guid: Guid, table: *mut c_void) {
+    let count = ST.number_of_table_entries;
+    assert!(count < MAX_CONFIGURATION_TABLES);
+    CONFIGURATION_TABLES[count] = efi::ConfigurationTable {
+        vendor_guid: guid,
+        vendor_table: table,
+    };
+    ST.number_of_table_entries = count + 1;
+}
+
+// Points the configuration table at the tables the VMM provided
+unsafe fn populate_configuration_tables(info: &dyn boot::Info) {
+    static VENDOR_DATA: u32 = 0;
+
+    ST.configuration_table = CONFIGURATION_TABLES.as_mut_ptr();
+    ST.number_of_table_entries = 0;
+
+    let rsdp = info.rsdp_addr();
+    if rsdp != 0 {
+        // The revision tells apart an ACPI 1.0 RSDP from the extended one
+        let revision = crate::mem::MemoryRegion::new(rsdp, 20).read_u8(15);
+        if revision >= 2 {
+            add_configuration_table(efi::ACPI_20_TABLE_GUID, rsdp as *mut _);
         }
-    } else {
-        efi::ConfigurationTable {
-            vendor_guid: Guid::from_fields(
+        add_configuration_table(efi::ACPI_TABLE_GUID, rsdp as *mut _);
+        log!("ACPI RSDP revision {} at {:#x}", revision, rsdp);
+    }
+
+    if let Ok(smbios) = crate::smbios::entry_point() {
+        let guid = if smbios.is_64bit {
+            efi::SMBIOS3_TABLE_GUID
+        } else {
+            efi::SMBIOS_TABLE_GUID
+        };
+        add_configuration_table(guid, smbios.address as *mut _);
+    }
+
+    if crate::tpm::present() {
+        add_configuration_table(
+            LINUX_EFI_TPM_EVENT_LOG_GUID,
+            crate::tpm::event_log() as *mut _,
+        );
+    }
+
+    // Loaders expect at least one entry
+    if ST.number_of_table_entries == 0 {
+        add_configuration_table(
+            Guid::from_fields(
                 0x678a_9665,
                 0x9957,
                 0x4e7c,
@@ -1067,11 +1749,83 @@ pub fn efi_exec(
                 0x27,
                 &[0x34, 0xc9, 0x46, 0x3d, 0xd2, 0xac],
             ),
-            vendor_table: &vendor_data as *const _ as *mut _,
-        }
-    };
+            &VENDOR_DATA as *const _ as *mut _,
+        );
+    }
+}
+
+// An image that has been loaded into memory and is ready to start
+struct Image<'a> {
+    path: &'a str,
+    address: u64,
+    size: u64,
+    entry: u64,
+}
+
+// Where the image was loaded from: its ESP, which also holds the variables,
+// an ISO9660 filesystem, which has nowhere to keep them, or a buffer in memory
+// that was downloaded with the network device
+enum Source<'a, 'b> {
+    Disk(
+        &'a crate::fat::Filesystem<'b>,
+        *const crate::block::CachedBlock<'b, dyn crate::block::BlockDevice + 'b>,
+    ),
+    Iso9660(
+        &'a crate::iso9660::Filesystem<'b>,
+        *const crate::block::CachedBlock<'b, dyn crate::block::BlockDevice + 'b>,
+    ),
+    #[cfg(feature = "network")]
+    #[cfg_attr(not(feature = "net-boot"), allow(dead_code))]
+    Network(&'a [u8], &'a crate::net::VirtioNetDevice<'b>),
+}
+
+// Each disk and partition has Block I/O and Disk I/O, and its device path
+unsafe fn install_block_wrappers() {
+    for &wrapper in &BLOCK_WRAPPERS.wrappers[..BLOCK_WRAPPERS.count] {
+        let handle = wrapper as Handle;
+        install(
+            handle,
+            block::PROTOCOL_GUID,
+            &mut (*wrapper).proto as *mut _ as *mut c_void,
+        );
+        install(
+            handle,
+            block::DISK_IO_PROTOCOL_GUID,
+            &mut (*wrapper).disk_io as *mut _ as *mut c_void,
+        );
+        install(
+            handle,
+            r_efi::protocols::device_path::PROTOCOL_GUID,
+            &mut (*wrapper).controller_path as *mut _ as *mut c_void,
+        );
+    }
+}
+
+// Sets up the EFI environment for the image and then calls start with the
+// image's handle and the system table
+fn efi_run<F>(
+    image: &Image,
+    reserved: &[(u64, u64)],
+    info: &dyn boot::Info,
+    source: Source,
+    start: F,
+) where
+    F: FnOnce(Handle, &mut efi::SystemTable),
+{
+    unsafe { populate_configuration_tables(info) };
+    reset::init(info.rsdp_addr());
+    HANDLES.borrow_mut().clear();
 
     let mut stdin = console::STDIN;
+    stdin.wait_for_key = EVENTS
+        .borrow_mut()
+        .create(
+            efi::EVT_NOTIFY_WAIT,
+            efi::TPL_NOTIFY,
+            Some(console::stdin_wait_for_key),
+            null_mut(),
+        )
+        .unwrap();
     let mut stdout = console::STDOUT;
     let mut st = unsafe { &mut ST };
     st.con_in = &mut stdin;
@@ -1079,26 +1833,533 @@ pub fn efi_exec(
     st.std_err = &mut stdout;
     st.runtime_services = unsafe { &mut RS };
     st.boot_services = unsafe { &mut BS };
-    st.number_of_table_entries = 1;
-    st.configuration_table = &mut ct;
+    unsafe {
+        crate::common::ascii_to_ucs2(FIRMWARE_VENDOR, &mut FIRMWARE_VENDOR_UCS2);
+        st.firmware_vendor = FIRMWARE_VENDOR_UCS2.as_mut_ptr();
+    }
+    st.firmware_revision = firmware_revision(env!("CARGO_PKG_VERSION"));
 
-    populate_allocator(info, loaded_address, loaded_size);
+    populate_allocator(info, image.address, image.size, reserved);
 
-    let efi_part_id = unsafe { block::populate_block_wrappers(&mut BLOCK_WRAPPERS, block) };
+    install(
+        st.console_in_handle,
+        r_efi::protocols::simple_text_input::PROTOCOL_GUID,
+        &mut stdin as *mut _ as *mut c_void,
+    );
+    for handle in &[st.console_out_handle, st.standard_error_handle] {
+        install(
+            *handle,
+            r_efi::protocols::simple_text_output::PROTOCOL_GUID,
+            &mut stdout as *mut _ as *mut c_void,
+        );
+    }
+    if let Some(handle) = new_handle() {
+        install(handle, device_path::PROTOCOL_GUID, unsafe {
+            &mut device_path::PROTOCOL as *mut _ as *mut c_void
+        });
+    }
 
-    let wrapped_fs = file::FileSystemWrapper::new(fs, efi_part_id);
+    VARIABLES.borrow_mut().add_defaults();
+    match source {
+        Source::Disk(fs, _) => match VARIABLES.borrow_mut().load(fs) {
+            Ok(()) => log!("Loaded variables from the ESP"),
+            Err(e) => log!("Not loading variables: {:?}", e),
+        },
+        Source::Iso9660(..) => {}
+        #[cfg(feature = "network")]
+        Source::Network(..) => {}
+    }
 
-    let image = new_image_handle(
-        "\\EFI\\BOOT\\BOOTX64.EFI",
+    // What the firmware loaded itself goes through the same checks as what
+    // is loaded with LoadImage()
+    #[cfg(feature = "hash-allowlist")]
+    secure_boot::init();
+    let inspected = match source {
+        Source::Disk(fs, _) => fs
+            .open(image.path)
+            .ok()
+            .map(|mut file| inspect_image(&mut file)),
+        Source::Iso9660(fs, _) => fs
+            .open(image.path)
+            .ok()
+            .map(|mut file| inspect_image(&mut file)),
+        #[cfg(feature = "network")]
+        Source::Network(data, _) => Some(inspect_image(&mut BufferFile::new(data))),
+    };
+    #[cfg(feature = "hash-allowlist")]
+    {
+        let verified = match &inspected {
+            Some((authenticode, _)) => secure_boot::verify(authenticode),
+            None if secure_boot::enabled() => Err(Status::ACCESS_DENIED),
+            None => Ok(()),
+        };
+        if let Err(status) = verified {
+            log!("Not starting {}: {:?}", image.path, status);
+            return;
+        }
+    }
+    match &inspected {
+        Some((_, Some(digest))) => crate::tpm::measure(
+            crate::tpm::PCR_BOOT_APPLICATION,
+            digest,
+            image.path.as_bytes(),
+        ),
+        _ => {
+            log!("Not starting {}: it couldn't be measured", image.path);
+            return;
+        }
+    }
+
+    let mut wrapped_fs = match source {
+        Source::Disk(fs, block) => {
+            let efi_part_id =
+                unsafe { block::populate_block_wrappers(&mut BLOCK_WRAPPERS, block, fs.start()) };
+            unsafe { install_block_wrappers() };
+            Some(file::FileSystemWrapper::new(fs, efi_part_id))
+        }
+        // GRUB and the like read the filesystem themselves through Block I/O
+        Source::Iso9660(_, block) => {
+            unsafe { block::populate_block_wrappers(&mut BLOCK_WRAPPERS, block, 0) };
+            unsafe { install_block_wrappers() };
+            None
+        }
+        #[cfg(feature = "network")]
+        Source::Network(..) => None,
+    };
+    if let Some(wrapped_fs) = &mut wrapped_fs {
+        let handle = wrapped_fs as *mut _ as Handle;
+        install(
+            handle,
+            r_efi::protocols::simple_file_system::PROTOCOL_GUID,
+            &mut wrapped_fs.proto as *mut _ as *mut c_void,
+        );
+        // The ESP's device path is that of its partition
+        if let Some(block_part_id) = wrapped_fs.block_part_id {
+            install(
+                handle,
+                r_efi::protocols::device_path::PROTOCOL_GUID,
+                unsafe {
+                    &mut (*(BLOCK_WRAPPERS.wrappers[block_part_id as usize])).controller_path
+                        as *mut _ as *mut c_void
+                },
+            );
+        }
+    }
+
+    #[cfg(feature = "gop")]
+    if let Some(gw) = gop::new_graphics_wrapper(info) {
+        install(gw as Handle, gop::PROTOCOL_GUID, unsafe {
+            &mut (*gw).proto as *mut _ as *mut c_void
+        });
+    }
+
+    // An image from the network can carry on using the device it came from
+    #[cfg(feature = "network")]
+    if let Source::Network(_, device) = source {
+        if let Some(sw) = snp::new_snp_wrapper(device) {
+            unsafe {
+                NETWORK_WRAPPER = sw;
+                install(
+                    sw as Handle,
+                    snp::PROTOCOL_GUID,
+                    &mut (*sw).proto as *mut _ as *mut c_void,
+                );
+                install(
+                    sw as Handle,
+                    r_efi::protocols::device_path::PROTOCOL_GUID,
+                    &mut (*sw).device_path as *mut _ as *mut c_void,
+                );
+            }
+        }
+    }
+
+    let device_handle = match (&wrapped_fs, &source) {
+        (Some(wrapped_fs), _) => wrapped_fs as *const _ as Handle,
+        // That of the whole disk
+        (None, Source::Iso9660(..)) => unsafe { BLOCK_WRAPPERS.wrappers[0] as Handle },
+        #[cfg(feature = "network")]
+        (None, _) => unsafe { NETWORK_WRAPPER as Handle },
+        #[cfg(not(feature = "network"))]
+        (None, _) => null_mut(),
+    };
+
+    let handle = new_image_handle(
+        image.path,
         0 as Handle,
-        &wrapped_fs as *const _ as Handle,
-        loaded_address,
-        loaded_size,
+        device_handle,
+        image.address,
+        image.size,
+        image.entry,
+    );
+
+    // Variables are saved to the ESP only while the application runs, as the
+    // filesystem goes away once it returns, or sooner with ExitBootServices()
+    if let Source::Disk(fs, _) = source {
+        unsafe { VARIABLE_STORE = transmute(fs) };
+    }
+    start((handle as *const _) as Handle, &mut *st);
+    unsafe { VARIABLE_STORE = core::ptr::null() };
+}
+
+// The crate version, major.minor.patch with 16, 8 and 8 bits, and anything
+// after the patch number such as "-rc1" left out
+fn firmware_revision(version: &str) -> u32 {
+    let mut parts = version
+        .split(|c| c == '.' || c == '-' || c == '+')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let mut part = |max| core::cmp::min(parts.next().unwrap_or(0), max);
+    part(0xffff) << 16 | part(0xff) << 8 | part(0xff)
+}
+
+pub fn efi_exec(
+    address: u64,
+    loaded_address: u64,
+    loaded_size: u64,
+    info: &dyn boot::Info,
+    path: &str,
+    fs: &crate::fat::Filesystem,
+    block: *const crate::block::CachedBlock<'_, dyn crate::block::BlockDevice + '_>,
+) {
+    let image = Image {
+        path,
+        address: loaded_address,
+        size: loaded_size,
+        entry: address,
+    };
+    efi_run(&image, &[], info, Source::Disk(fs, block), |handle, _| {
+        let status = start_image(handle, null_mut(), null_mut());
+        log!("EFI application exited: {:?}", status);
+    });
+}
+
+// Starts an image loaded from an ISO9660 filesystem on the block device
+pub fn efi_exec_iso9660(
+    address: u64,
+    loaded_address: u64,
+    loaded_size: u64,
+    info: &dyn boot::Info,
+    path: &str,
+    fs: &crate::iso9660::Filesystem,
+    block: *const crate::block::CachedBlock<'_, dyn crate::block::BlockDevice + '_>,
+) {
+    let image = Image {
+        path,
+        address: loaded_address,
+        size: loaded_size,
+        entry: address,
+    };
+    efi_run(
+        &image,
+        &[],
+        info,
+        Source::Iso9660(fs, block),
+        |handle, _| {
+            let status = start_image(handle, null_mut(), null_mut());
+            log!("EFI application exited: {:?}", status);
+        },
+    );
+}
+
+// Starts an image that was downloaded with the network device, with data
+// what it was loaded from
+#[cfg(feature = "network")]
+#[cfg_attr(not(feature = "net-boot"), allow(dead_code))]
+pub fn efi_exec_buffer(
+    address: u64,
+    loaded_address: u64,
+    loaded_size: u64,
+    info: &dyn boot::Info,
+    path: &str,
+    data: &[u8],
+    device: &crate::net::VirtioNetDevice,
+) {
+    let image = Image {
+        path,
+        address: loaded_address,
+        size: loaded_size,
+        entry: address,
+    };
+    efi_run(
+        &image,
+        &[],
+        info,
+        Source::Network(data, device),
+        |handle, _| {
+            let status = start_image(handle, null_mut(), null_mut());
+            log!("EFI application exited: {:?}", status);
+        },
+    );
+}
+
+// Starts a Linux kernel through its EFI handover entry
+pub fn efi_handover(
+    kernel: &mut crate::bzimage::Kernel,
+    info: &dyn boot::Info,
+    path: &str,
+    fs: &crate::fat::Filesystem,
+    block: *const crate::block::CachedBlock<'_, dyn crate::block::BlockDevice + '_>,
+) {
+    let regions = kernel.regions();
+    let (address, size) = regions[0];
+    let image = Image {
+        path,
         address,
+        size,
+        entry: address,
+    };
+    efi_run(
+        &image,
+        &regions[1..],
+        info,
+        Source::Disk(fs, block),
+        |handle, st| {
+            kernel.efi_handover(handle, st as *mut _ as *mut c_void);
+        },
     );
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{mem::size_of, ptr::null_mut};
+    use r_efi::efi::{self, Status};
+
+    use super::alloc::MemoryDescriptor;
+
+    #[test]
+    fn test_firmware_revision() {
+        assert_eq!(super::firmware_revision("0.3.0"), 0x0000_0300);
+        assert_eq!(super::firmware_revision("1.12.3-rc1"), 0x0001_0c03);
+        assert_eq!(super::firmware_revision("2.0"), 0x0002_0000);
+        assert_eq!(super::firmware_revision("0.300.1"), 0x0000_ff01);
+    }
 
-    let ptr = address as *const ();
-    let code: extern "win64" fn(Handle, *mut efi::SystemTable) -> Status =
-        unsafe { core::mem::transmute(ptr) };
-    (code)((image as *const _) as Handle, &mut *st);
+    // What the Linux EFI stub does: it gets the memory map, allocates memory
+    // and tries to exit boot services with the key it got, which has to fail
+    // so that it gets the map again
+    #[test]
+    fn test_exit_boot_services_map_key() {
+        super::ALLOCATOR.borrow_mut().add_initial_allocation(
+            efi::CONVENTIONAL_MEMORY,
+            1024,
+            0x1000_0000,
+            0,
+        );
+        let mut map = [MemoryDescriptor {
+            r#type: 0,
+            physical_start: 0,
+            virtual_start: 0,
+            number_of_pages: 0,
+            attribute: 0,
+        }; 16];
+        let get_map = |out: *mut MemoryDescriptor| {
+            let mut size = size_of::<[MemoryDescriptor; 16]>();
+            let (mut key, mut descriptor_size, mut version) = (0, 0, 0);
+            let status = super::get_memory_map(
+                &mut size,
+                out as *mut _,
+                &mut key,
+                &mut descriptor_size,
+                &mut version,
+            );
+            (status, key)
+        };
+        assert_eq!(get_map(null_mut()).0, Status::INVALID_PARAMETER);
+        let (status, key) = get_map(map.as_mut_ptr());
+        assert_eq!(status, Status::SUCCESS);
+
+        let mut address = 0;
+        assert_eq!(
+            super::allocate_pages(efi::ALLOCATE_ANY_PAGES, efi::LOADER_DATA, 4, &mut address),
+            Status::SUCCESS
+        );
+        assert_eq!(
+            super::exit_boot_services(null_mut(), key),
+            Status::INVALID_PARAMETER
+        );
+
+        // Getting the map again without changing it gives a key that works
+        let (status, key) = get_map(map.as_mut_ptr());
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(get_map(map.as_mut_ptr()).1, key);
+        assert_eq!(super::exit_boot_services(null_mut(), key), Status::SUCCESS);
+    }
+
+    #[test]
+    fn test_calculate_crc32() {
+        let mut data = *b"123456789";
+        let mut crc = 0;
+        let calculate = super::calculate_crc32;
+        assert_eq!(
+            calculate(data.as_mut_ptr() as *mut _, data.len(), &mut crc),
+            Status::SUCCESS
+        );
+        assert_eq!(crc, 0xcbf4_3926);
+        assert_eq!(
+            calculate(core::ptr::null_mut(), data.len(), &mut crc),
+            Status::INVALID_PARAMETER
+        );
+        assert_eq!(
+            calculate(data.as_mut_ptr() as *mut _, 0, &mut crc),
+            Status::INVALID_PARAMETER
+        );
+        assert_eq!(
+            calculate(
+                data.as_mut_ptr() as *mut _,
+                data.len(),
+                core::ptr::null_mut()
+            ),
+            Status::INVALID_PARAMETER
+        );
+    }
+
+    // Images without an Authenticode hash are still measured
+    #[test]
+    fn test_inspect_image() {
+        let data = [0x55; 600];
+        let (authenticode, digest) = super::inspect_image(&mut super::BufferFile::new(&data));
+        assert!(authenticode.is_err());
+        let mut hash = crate::sha256::Sha256::new();
+        hash.update(&data);
+        assert_eq!(digest, Some(hash.finish()));
+    }
+
+    // Handles that aren't in the database are turned down before anything
+    // is read through them
+    #[test]
+    fn test_load_image_invalid_parent() {
+        let mut parent = [0u8; 64];
+        let mut image = null_mut();
+        let mut data = [0u8; 64];
+        assert_eq!(
+            super::load_image(
+                efi::Boolean::FALSE,
+                parent.as_mut_ptr() as efi::Handle,
+                null_mut(),
+                data.as_mut_ptr() as *mut _,
+                data.len(),
+                &mut image,
+            ),
+            Status::INVALID_PARAMETER
+        );
+        assert!(image.is_null());
+    }
+
+    // Only new handles and those with something installed on them can have
+    // protocols installed
+    #[test]
+    fn test_install_protocol_unknown_handle() {
+        let mut protocol = 0u8;
+        let mut handle = &mut protocol as *mut _ as efi::Handle;
+        let mut guid = super::block::PROTOCOL_GUID;
+        assert_eq!(
+            super::install_protocol_interface(
+                &mut handle,
+                &mut guid,
+                efi::NATIVE_INTERFACE,
+                &mut protocol as *mut _ as *mut _,
+            ),
+            Status::INVALID_PARAMETER
+        );
+        assert!(!super::HANDLES.borrow().contains(handle));
+    }
+
+    // A protocol installed in the handle database, which is taken out again
+    // when this goes, so that a failing test doesn't leave it there for the
+    // others to find
+    struct Installed {
+        handle: efi::Handle,
+        guid: efi::Guid,
+        interface: *mut core::ffi::c_void,
+    }
+
+    impl Installed {
+        fn new(handle: efi::Handle, guid: efi::Guid, interface: *mut core::ffi::c_void) -> Self {
+            super::install(handle, guid, interface);
+            Installed {
+                handle,
+                guid,
+                interface,
+            }
+        }
+    }
+
+    impl Drop for Installed {
+        fn drop(&mut self) {
+            super::HANDLES
+                .borrow_mut()
+                .uninstall(self.handle, &self.guid, self.interface);
+        }
+    }
+
+    // How GRUB finds the disks: every handle with Block I/O, then the
+    // protocol on each of them. The handles are installed as the firmware's
+    // own are.
+    #[test]
+    fn test_locate_block_handles() {
+        let mut protocols = [0u8; 2];
+        let handles: [efi::Handle; 2] = [
+            &mut protocols[0] as *mut _ as efi::Handle,
+            &mut protocols[1] as *mut _ as efi::Handle,
+        ];
+        let mut guid = super::block::PROTOCOL_GUID;
+        let installed: Vec<Installed> = handles
+            .iter()
+            .zip(protocols.iter_mut())
+            .map(|(handle, protocol)| Installed::new(*handle, guid, protocol as *mut _ as *mut _))
+            .collect();
+
+        let mut found: [efi::Handle; 2] = [null_mut(); 2];
+        let mut size = 0;
+        assert_eq!(
+            super::locate_handle(
+                efi::BY_PROTOCOL,
+                &mut guid,
+                null_mut(),
+                &mut size,
+                null_mut()
+            ),
+            Status::BUFFER_TOO_SMALL
+        );
+        assert_eq!(size, size_of::<[efi::Handle; 2]>());
+        assert_eq!(
+            super::locate_handle(
+                efi::BY_PROTOCOL,
+                &mut guid,
+                null_mut(),
+                &mut size,
+                found.as_mut_ptr()
+            ),
+            Status::SUCCESS
+        );
+        assert_eq!(found, handles);
+
+        for (handle, protocol) in handles.iter().zip(protocols.iter_mut()) {
+            let mut interface = null_mut();
+            assert_eq!(
+                super::handle_protocol(*handle, &mut guid, &mut interface),
+                Status::SUCCESS
+            );
+            assert_eq!(interface, protocol as *mut _ as *mut _);
+        }
+        let mut disk_io = super::block::DISK_IO_PROTOCOL_GUID;
+        let mut interface = null_mut();
+        assert_eq!(
+            super::handle_protocol(handles[0], &mut disk_io, &mut interface),
+            Status::UNSUPPORTED
+        );
+        assert_eq!(
+            super::locate_handle(
+                efi::BY_PROTOCOL,
+                &mut disk_io,
+                null_mut(),
+                &mut size,
+                found.as_mut_ptr()
+            ),
+            Status::NOT_FOUND
+        );
+
+        drop(installed);
+        assert!(!super::HANDLES.borrow().contains(handles[0]));
+        assert!(!super::HANDLES.borrow().contains(handles[1]));
+    }
 }
@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// The monotonic count behind GetNextMonotonicCount() and
+/// GetNextHighMonotonicCount(). The low 32 bits go up with each count and
+/// carry into the high 32 bits, which go up on their own with each high
+/// count. There is nowhere to keep the high bits across boots, so they start
+/// at 0 every time.
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// The next count, or None once all 64 bits have been used up
+    pub fn next(&self) -> Option<u64> {
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_add(1)
+            })
+            .ok()
+    }
+
+    /// Moves the high 32 bits on by one, starting the low ones over, and
+    /// returns them, or None if they can't go any higher
+    pub fn next_high(&self) -> Option<u32> {
+        let old = self
+            .0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                let high = (count >> 32).checked_add(1).filter(|h| *h <= 0xffff_ffff)?;
+                Some(high << 32)
+            })
+            .ok()?;
+        Some(((old >> 32) + 1) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicU64;
+
+    use super::Counter;
+
+    #[test]
+    fn test_counter() {
+        let counter = Counter::new();
+        let counts: Vec<u64> = (0..4).map(|_| counter.next().unwrap()).collect();
+        assert!(counts.windows(2).all(|w| w[0] < w[1]));
+
+        // The high count starts the low bits over, which are still above
+        // any count before
+        assert_eq!(counter.next_high(), Some(1));
+        assert_eq!(counter.next(), Some(0x1_0000_0000));
+        assert_eq!(counter.next(), Some(0x1_0000_0001));
+        assert_eq!(counter.next_high(), Some(2));
+    }
+
+    #[test]
+    fn test_wrap() {
+        // The low bits carry into the high ones
+        let counter = Counter(AtomicU64::new(0xffff_fffe));
+        assert_eq!(counter.next(), Some(0xffff_fffe));
+        assert_eq!(counter.next(), Some(0xffff_ffff));
+        assert_eq!(counter.next(), Some(0x1_0000_0000));
+        assert_eq!(counter.next_high(), Some(2));
+
+        // Until there are no more counts
+        let counter = Counter(AtomicU64::new(u64::MAX - 1));
+        assert_eq!(counter.next(), Some(u64::MAX - 1));
+        assert_eq!(counter.next(), None);
+        assert_eq!(counter.next(), None);
+
+        let counter = Counter(AtomicU64::new(0xffff_fffe_0000_0005));
+        assert_eq!(counter.next_high(), Some(0xffff_ffff));
+        assert_eq!(counter.next_high(), None);
+        assert_eq!(counter.next(), Some(0xffff_ffff_0000_0000));
+    }
+}
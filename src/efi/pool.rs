@@ -0,0 +1,373 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::mem::size_of;
+
+use r_efi::efi::{self, MemoryType, Status};
+
+use super::alloc::Allocator;
+
+const PAGE_SIZE: u64 = 4096;
+// Pages are taken from the page allocator this many at a time, bigger
+// allocations get a chunk of their own
+const CHUNK_PAGES: u64 = 16;
+// Pool memory has to be 8 byte aligned, blocks are kept 16 byte aligned
+const BLOCK_ALIGN: u64 = 16;
+
+// At the start of each chunk of pages, which are all of one memory type so
+// that the memory map shows what the pool is used for
+#[repr(C)]
+struct Chunk {
+    next: u64,
+    memory_type: MemoryType,
+    pages: u64,
+    // Free blocks in address order, adjacent ones are always merged
+    free: u64,
+    // Bytes in allocated blocks, the chunk is freed when this reaches zero
+    used: u64,
+}
+
+// In front of every block: free blocks link to the next free block and
+// allocated ones to their chunk
+#[repr(C)]
+struct Block {
+    size: u64,
+    link: u64,
+}
+
+const CHUNK_HEADER_SIZE: u64 = round_up(size_of::<Chunk>() as u64);
+const BLOCK_HEADER_SIZE: u64 = size_of::<Block>() as u64;
+// Splitting off anything smaller than this isn't worth it
+const MIN_BLOCK_SIZE: u64 = BLOCK_HEADER_SIZE + BLOCK_ALIGN;
+
+const fn round_up(size: u64) -> u64 {
+    (size + BLOCK_ALIGN - 1) & !(BLOCK_ALIGN - 1)
+}
+
+// The size of the block for an allocation, with its header, and the pages of
+// a chunk that has room for it. None if either can't be represented.
+fn block_size(size: usize) -> Option<(u64, u64)> {
+    let size = core::cmp::max(size as u64, 1).checked_add(BLOCK_ALIGN - 1)? & !(BLOCK_ALIGN - 1);
+    let size = size.checked_add(BLOCK_HEADER_SIZE)?;
+    let pages = (CHUNK_HEADER_SIZE + PAGE_SIZE - 1).checked_add(size)? / PAGE_SIZE;
+    Some((size, core::cmp::max(CHUNK_PAGES, pages)))
+}
+
+// SAFETY: Chunks and blocks are only ever at addresses handed out by the page
+// allocator, which are identity mapped and owned by the pool.
+unsafe fn chunk<'a>(address: u64) -> &'a mut Chunk {
+    &mut *(address as *mut Chunk)
+}
+
+unsafe fn block<'a>(address: u64) -> &'a mut Block {
+    &mut *(address as *mut Block)
+}
+
+pub struct Pool {
+    chunks: u64,
+}
+
+impl Pool {
+    pub const fn new() -> Pool {
+        Pool { chunks: 0 }
+    }
+
+    pub fn allocate(
+        &mut self,
+        allocator: &mut Allocator,
+        memory_type: MemoryType,
+        size: usize,
+    ) -> Result<u64, Status> {
+        let (size, pages) = block_size(size).ok_or(Status::OUT_OF_RESOURCES)?;
+
+        let mut cur = self.chunks;
+        while cur != 0 {
+            let c = unsafe { chunk(cur) };
+            if c.memory_type == memory_type {
+                if let Some(address) = Self::allocate_from(cur, size) {
+                    return Ok(address);
+                }
+            }
+            cur = c.next;
+        }
+
+        let (status, address) =
+            allocator.allocate_pages(efi::ALLOCATE_ANY_PAGES, memory_type, pages, 0);
+        if status != Status::SUCCESS {
+            return Err(status);
+        }
+
+        let first_block = address + CHUNK_HEADER_SIZE;
+        unsafe {
+            *block(first_block) = Block {
+                size: pages * PAGE_SIZE - CHUNK_HEADER_SIZE,
+                link: 0,
+            };
+            *chunk(address) = Chunk {
+                next: self.chunks,
+                memory_type,
+                pages,
+                free: first_block,
+                used: 0,
+            };
+        }
+        self.chunks = address;
+
+        Ok(Self::allocate_from(address, size).unwrap())
+    }
+
+    // First fit from the chunk's free list
+    fn allocate_from(chunk_address: u64, size: u64) -> Option<u64> {
+        let c = unsafe { chunk(chunk_address) };
+        let mut prev = 0;
+        let mut cur = c.free;
+        while cur != 0 {
+            let b = unsafe { block(cur) };
+            if b.size >= size {
+                let next = if b.size - size >= MIN_BLOCK_SIZE {
+                    let rest = cur + size;
+                    unsafe {
+                        *block(rest) = Block {
+                            size: b.size - size,
+                            link: b.link,
+                        };
+                    }
+                    b.size = size;
+                    rest
+                } else {
+                    b.link
+                };
+                if prev == 0 {
+                    c.free = next;
+                } else {
+                    unsafe { block(prev) }.link = next;
+                }
+
+                b.link = chunk_address;
+                c.used += b.size;
+                return Some(cur + BLOCK_HEADER_SIZE);
+            }
+            prev = cur;
+            cur = b.link;
+        }
+        None
+    }
+
+    pub fn free(&mut self, allocator: &mut Allocator, address: u64) -> Status {
+        if address < BLOCK_HEADER_SIZE || address % BLOCK_ALIGN != 0 {
+            return Status::INVALID_PARAMETER;
+        }
+        let block_address = address - BLOCK_HEADER_SIZE;
+        let chunk_address = unsafe { block(block_address) }.link;
+
+        // Find the chunk, which also catches blocks that are already free as
+        // they never link to the start of a chunk
+        let mut prev_chunk = 0;
+        let mut cur = self.chunks;
+        loop {
+            if cur == 0 {
+                return Status::INVALID_PARAMETER;
+            }
+            if cur == chunk_address {
+                break;
+            }
+            prev_chunk = cur;
+            cur = unsafe { chunk(cur) }.next;
+        }
+        let c = unsafe { chunk(chunk_address) };
+        if block_address < chunk_address + CHUNK_HEADER_SIZE
+            || block_address >= chunk_address + c.pages * PAGE_SIZE
+        {
+            return Status::INVALID_PARAMETER;
+        }
+
+        let b = unsafe { block(block_address) };
+        c.used -= b.size;
+        if c.used == 0 {
+            if prev_chunk == 0 {
+                self.chunks = c.next;
+            } else {
+                unsafe { chunk(prev_chunk) }.next = c.next;
+            }
+            return allocator.free_pages(chunk_address);
+        }
+
+        // Put the block back in address order, merging it with its neighbours
+        let mut prev = 0;
+        let mut next = c.free;
+        while next != 0 && next < block_address {
+            prev = next;
+            next = unsafe { block(next) }.link;
+        }
+        b.link = next;
+        if next != 0 && block_address + b.size == next {
+            let n = unsafe { block(next) };
+            b.size += n.size;
+            b.link = n.link;
+        }
+        if prev == 0 {
+            c.free = block_address;
+        } else {
+            let p = unsafe { block(prev) };
+            if prev + p.size == block_address {
+                p.size += b.size;
+                p.link = b.link;
+            } else {
+                p.link = block_address;
+            }
+        }
+
+        Status::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Allocator, Pool, PAGE_SIZE};
+    use r_efi::efi::{self, Status};
+
+    const MEMORY_SIZE: usize = 8 << 20;
+
+    // An allocator handing out pages from a buffer, which has to outlive it
+    fn allocator(memory: &mut [u8]) -> Allocator {
+        let start = (memory.as_ptr() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let mut allocator = Allocator::new();
+        allocator.add_initial_allocation(
+            efi::CONVENTIONAL_MEMORY,
+            MEMORY_SIZE as u64 / PAGE_SIZE - 1,
+            start,
+            0,
+        );
+        allocator
+    }
+
+    // All the pages are back with the page allocator
+    fn assert_all_free(allocator: &Allocator) {
+        let mut descriptors: [super::super::alloc::MemoryDescriptor; 1] =
+            unsafe { std::mem::zeroed() };
+        assert_eq!(allocator.get_descriptor_count(), 1);
+        allocator.get_descriptors(&mut descriptors);
+        assert_eq!(descriptors[0].r#type, efi::CONVENTIONAL_MEMORY as u32);
+    }
+
+    #[test]
+    fn test_allocate_free() {
+        let mut memory = vec![0u8; MEMORY_SIZE];
+        let mut allocator = allocator(&mut memory);
+        let mut pool = Pool::new();
+
+        let a = pool.allocate(&mut allocator, efi::LOADER_DATA, 1).unwrap();
+        let b = pool
+            .allocate(&mut allocator, efi::LOADER_DATA, 100)
+            .unwrap();
+        let c = pool
+            .allocate(&mut allocator, efi::BOOT_SERVICES_DATA, 100)
+            .unwrap();
+        let d = pool
+            .allocate(&mut allocator, efi::LOADER_DATA, 256 * 1024)
+            .unwrap();
+        for address in &[a, b, c, d] {
+            assert_eq!(address % 8, 0);
+        }
+        // Small allocations of the same type share pages
+        assert_eq!(a & !(PAGE_SIZE - 1), b & !(PAGE_SIZE - 1));
+        assert_ne!(a & !(PAGE_SIZE - 1), c & !(PAGE_SIZE - 1));
+        unsafe { std::ptr::write_bytes(d as *mut u8, 0xaa, 256 * 1024) };
+
+        // The memory map shows the pool's memory types
+        assert_eq!(allocator.get_descriptor_count(), 4);
+
+        assert_eq!(pool.free(&mut allocator, b), Status::SUCCESS);
+        assert_eq!(pool.free(&mut allocator, b), Status::INVALID_PARAMETER);
+        assert_eq!(pool.free(&mut allocator, a + 16), Status::INVALID_PARAMETER);
+        for address in &[a, c, d] {
+            assert_eq!(pool.free(&mut allocator, *address), Status::SUCCESS);
+        }
+        assert_all_free(&allocator);
+    }
+
+    #[test]
+    fn test_allocate_too_large() {
+        let mut memory = vec![0u8; MEMORY_SIZE];
+        let mut allocator = allocator(&mut memory);
+        let mut pool = Pool::new();
+
+        for size in &[usize::MAX, usize::MAX - 8, MEMORY_SIZE] {
+            assert_eq!(
+                pool.allocate(&mut allocator, efi::LOADER_DATA, *size),
+                Err(Status::OUT_OF_RESOURCES)
+            );
+        }
+        assert_all_free(&allocator);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut memory = vec![0u8; MEMORY_SIZE];
+        let mut allocator = allocator(&mut memory);
+        let mut pool = Pool::new();
+
+        let keep = pool.allocate(&mut allocator, efi::LOADER_DATA, 16).unwrap();
+        let blocks: Vec<u64> = (0..3)
+            .map(|_| {
+                pool.allocate(&mut allocator, efi::LOADER_DATA, 1000)
+                    .unwrap()
+            })
+            .collect();
+        let after = pool.allocate(&mut allocator, efi::LOADER_DATA, 16).unwrap();
+
+        for address in &[blocks[0], blocks[2], blocks[1]] {
+            assert_eq!(pool.free(&mut allocator, *address), Status::SUCCESS);
+        }
+        // The three free blocks were merged back into one
+        assert_eq!(
+            pool.allocate(&mut allocator, efi::LOADER_DATA, 3000),
+            Ok(blocks[0])
+        );
+
+        assert_eq!(pool.free(&mut allocator, keep), Status::SUCCESS);
+        assert_eq!(pool.free(&mut allocator, after), Status::SUCCESS);
+        assert_eq!(pool.free(&mut allocator, blocks[0]), Status::SUCCESS);
+        assert_all_free(&allocator);
+    }
+
+    #[test]
+    fn test_stress() {
+        let mut memory = vec![0u8; MEMORY_SIZE];
+        let mut allocator = allocator(&mut memory);
+        let mut pool = Pool::new();
+
+        // Far more memory than there is gets allocated and freed, with each
+        // block filled with its own pattern to catch any overlaps
+        let mut seed: u64 = 1;
+        let mut random = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as usize
+        };
+        let mut live: Vec<(u64, usize, u8)> = Vec::new();
+        for i in 0..20000 {
+            if live.len() < 64 && random() % 3 != 0 {
+                let size = match random() % 8 {
+                    0 => random() % (64 * 1024),
+                    _ => random() % 2048,
+                };
+                let memory_type = if random() % 4 == 0 {
+                    efi::BOOT_SERVICES_DATA
+                } else {
+                    efi::LOADER_DATA
+                };
+                let address = pool.allocate(&mut allocator, memory_type, size).unwrap();
+                unsafe { std::ptr::write_bytes(address as *mut u8, i as u8, size) };
+                live.push((address, size, i as u8));
+            } else if !live.is_empty() {
+                let (address, size, pattern) = live.swap_remove(random() % live.len());
+                let data = unsafe { std::slice::from_raw_parts(address as *const u8, size) };
+                assert!(data.iter().all(|b| *b == pattern));
+                assert_eq!(pool.free(&mut allocator, address), Status::SUCCESS);
+            }
+        }
+        for (address, _, _) in live {
+            assert_eq!(pool.free(&mut allocator, address), Status::SUCCESS);
+        }
+        assert_all_free(&allocator);
+    }
+}
@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Checks images against the signature database in db before they are loaded.
+// This is a hash allowlist rather than Secure Boot: only the SHA-256 hashes in
+// db allow an image to run. An embedded signature is only checked to be over
+// the image, the PKCS#7 signer isn't verified against the certificates in db.
+// As authenticated writes aren't checked either, db and dbx can't be set
+// through SetVariable().
+
+use r_efi::efi::{self, Guid, Status};
+
+use super::VARIABLES;
+use crate::pe;
+
+pub const IMAGE_SECURITY_DATABASE_GUID: Guid = Guid::from_fields(
+    0xd719_b2cb,
+    0x3d3a,
+    0x4596,
+    0xa3,
+    0xbc,
+    &[0xda, 0xd0, 0x0e, 0x67, 0x65, 0x6f],
+);
+
+const CERT_SHA256_GUID: Guid = Guid::from_fields(
+    0xc1c4_1626,
+    0x504c,
+    0x4092,
+    0xac,
+    0xa9,
+    &[0x41, 0xf9, 0x36, 0x93, 0x43, 0x28],
+);
+
+// EFI_SIGNATURE_LIST: type, list size, header size and signature size
+const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+// EFI_SIGNATURE_DATA: the owner's GUID then the signature
+const SIGNATURE_OWNER_SIZE: usize = 16;
+
+#[derive(Debug)]
+enum Failure {
+    Image(pe::Error),
+    // The image's signature is over something else
+    Tampered,
+    // The image's hash isn't in db
+    NotAllowed,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes) as usize
+}
+
+// Whether one of the EFI_SIGNATURE_LISTs in db has the SHA-256 hash
+fn allowed(db: &[u8], hash: &[u8; 32]) -> bool {
+    let mut lists = db;
+    while lists.len() >= SIGNATURE_LIST_HEADER_SIZE {
+        let list_size = read_u32(lists, 16);
+        let header_size = read_u32(lists, 20);
+        let signature_size = read_u32(lists, 24);
+        if list_size < SIGNATURE_LIST_HEADER_SIZE + header_size || list_size > lists.len() {
+            return false;
+        }
+        if lists[..16] == CERT_SHA256_GUID.as_bytes()[..]
+            && signature_size == SIGNATURE_OWNER_SIZE + hash.len()
+            && lists[SIGNATURE_LIST_HEADER_SIZE + header_size..list_size]
+                .chunks_exact(signature_size)
+                .any(|s| s[SIGNATURE_OWNER_SIZE..] == hash[..])
+        {
+            return true;
+        }
+        lists = &lists[list_size..];
+    }
+    false
+}
+
+fn check(authenticode: &Result<pe::Authenticode, pe::Error>, db: &[u8]) -> Result<(), Failure> {
+    let authenticode = authenticode.as_ref().map_err(|e| Failure::Image(*e))?;
+    match authenticode.signed_hash {
+        Some(hash) if hash != authenticode.hash => Err(Failure::Tampered),
+        _ if allowed(db, &authenticode.hash) => Ok(()),
+        _ => Err(Failure::NotAllowed),
+    }
+}
+
+// Secure Boot is on once there is a db to check images against, which can
+// only have come from the variable store
+pub fn init() {
+    let mut variables = VARIABLES.borrow_mut();
+    let enabled = variables
+        .data("db", &IMAGE_SECURITY_DATABASE_GUID)
+        .is_some();
+    variables.set_secure_boot(enabled);
+}
+
+pub fn enabled() -> bool {
+    VARIABLES
+        .borrow()
+        .data("SecureBoot", &efi::GLOBAL_VARIABLE_GUID)
+        == Some(&[1][..])
+}
+
+// Checks an image's Authenticode hash against db, if there is one. Images
+// that fail are only refused with SecureBoot set.
+pub fn verify(authenticode: &Result<pe::Authenticode, pe::Error>) -> Result<(), Status> {
+    let failure = match VARIABLES.borrow().data("db", &IMAGE_SECURITY_DATABASE_GUID) {
+        Some(db) => check(authenticode, db),
+        None => return Ok(()),
+    };
+    match failure {
+        Ok(()) => Ok(()),
+        Err(failure) => {
+            log!("Image failed verification: {:?}", failure);
+            if enabled() {
+                Err(Status::ACCESS_DENIED)
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allowed, check, Failure, CERT_SHA256_GUID};
+    use crate::pe::tests::{image, sign, TestFile};
+    use crate::pe::Loader;
+    use crate::sha256::Sha256;
+
+    // An EFI_SIGNATURE_LIST of the signatures, each with an owner
+    fn signature_list(signature_type: &[u8; 16], signatures: &[&[u8]]) -> Vec<u8> {
+        let signature_size = 16 + signatures[0].len();
+        let mut list = signature_type.to_vec();
+        let list_size = 28 + signature_size * signatures.len();
+        for size in &[list_size, 0, signature_size] {
+            list.extend_from_slice(&(*size as u32).to_le_bytes());
+        }
+        for signature in signatures {
+            list.extend_from_slice(&[0x55; 16]);
+            list.extend_from_slice(signature);
+        }
+        list
+    }
+
+    #[test]
+    fn test_allowed() {
+        let mut h = Sha256::new();
+        h.update(b"image");
+        let hash = h.finish();
+
+        // A certificate ahead of the hashes
+        let mut db = signature_list(&[0xaa; 16], &[&[0xbb; 100]]);
+        assert!(!allowed(&db, &hash));
+        db.extend(signature_list(
+            CERT_SHA256_GUID.as_bytes(),
+            &[&[0; 32], &hash],
+        ));
+        assert!(allowed(&db, &hash));
+        assert!(!allowed(&db, &[1; 32]));
+        // A list that claims to be longer than db
+        assert!(!allowed(&db[..db.len() - 1], &hash));
+    }
+
+    #[test]
+    fn test_check() {
+        let authenticode = |data: &[u8]| Loader::new(&mut TestFile::new(data)).authenticode();
+        let mut data = image();
+        let hash = authenticode(&data).unwrap().hash;
+        let db = signature_list(CERT_SHA256_GUID.as_bytes(), &[&hash]);
+
+        assert!(check(&authenticode(&data), &db).is_ok());
+        assert!(matches!(
+            check(&authenticode(&data), &db[..0]),
+            Err(Failure::NotAllowed)
+        ));
+
+        sign(&mut data, &hash);
+        assert!(check(&authenticode(&data), &db).is_ok());
+        data[0x300] ^= 1;
+        assert!(matches!(
+            check(&authenticode(&data), &db),
+            Err(Failure::Tampered)
+        ));
+        assert!(matches!(
+            check(&authenticode(&data[..0x100]), &db),
+            Err(Failure::Image(_))
+        ));
+    }
+}
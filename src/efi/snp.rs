@@ -0,0 +1,665 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The Simple Network Protocol over the virtio-net device an image was
+// downloaded with, so that it can do its own networking. The device is
+// already running by then, so starting and initializing it only changes the
+// state that is reported.
+
+use core::{ffi::c_void, mem::size_of};
+
+use r_efi::{
+    efi::{self, Boolean, Event, IpAddress, MacAddress, Status},
+    protocols::{
+        device_path::Protocol as DevicePathProtocol,
+        simple_network::{self, Mode, Protocol as SimpleNetworkProtocol, Statistics},
+    },
+};
+
+use crate::net::{VirtioNetDevice, MAX_FRAME_SIZE};
+
+pub use simple_network::PROTOCOL_GUID;
+
+// The Ethernet header: destination, source and protocol
+const MEDIA_HEADER_SIZE: usize = 14;
+const IF_TYPE_ETHERNET: u8 = 1;
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+// Buffers that have been sent, for GetStatus() to hand back
+const MAX_RECYCLED: usize = 16;
+
+#[repr(packed)]
+pub struct MacDevicePathProtocol {
+    pub device_path: DevicePathProtocol,
+    pub mac: MacAddress,
+    pub if_type: u8,
+    pub end: DevicePathProtocol,
+}
+
+#[repr(C)]
+pub struct SnpWrapper {
+    hw: super::HandleWrapper,
+    pub proto: SimpleNetworkProtocol,
+    mode: Mode,
+    pub device_path: MacDevicePathProtocol,
+    device: *const VirtioNetDevice<'static>,
+    recycled: [*mut c_void; MAX_RECYCLED],
+    recycled_count: usize,
+}
+
+fn mac_address(mac: &[u8]) -> MacAddress {
+    let mut address = MacAddress { addr: [0; 32] };
+    address.addr[..mac.len()].copy_from_slice(mac);
+    address
+}
+
+impl SnpWrapper {
+    fn new(device: *const VirtioNetDevice<'static>, mac: [u8; 6]) -> SnpWrapper {
+        SnpWrapper {
+            hw: super::HandleWrapper {
+                handle_type: super::HandleType::Network,
+            },
+            proto: SimpleNetworkProtocol {
+                revision: simple_network::REVISION,
+                start,
+                stop,
+                initialize,
+                reset,
+                shutdown,
+                receive_filters,
+                station_address,
+                statistics,
+                mcast_ip_to_mac,
+                nv_data,
+                get_status,
+                transmit,
+                receive,
+                wait_for_packet: core::ptr::null_mut(),
+                mode: core::ptr::null_mut(),
+            },
+            mode: Mode {
+                state: simple_network::STOPPED,
+                hw_address_size: 6,
+                media_header_size: MEDIA_HEADER_SIZE as u32,
+                max_packet_size: (MAX_FRAME_SIZE - MEDIA_HEADER_SIZE) as u32,
+                nvram_size: 0,
+                nvram_access_size: 0,
+                // The device doesn't filter what it receives
+                receive_filter_mask: simple_network::RECEIVE_UNICAST
+                    | simple_network::RECEIVE_MULTICAST
+                    | simple_network::RECEIVE_BROADCAST
+                    | simple_network::RECEIVE_PROMISCUOUS
+                    | simple_network::RECEIVE_PROMISCUOUS_MULTICAST,
+                receive_filter_setting: simple_network::RECEIVE_UNICAST
+                    | simple_network::RECEIVE_BROADCAST,
+                max_mcast_filter_count: simple_network::MAX_MCAST_FILTER_CNT as u32,
+                mcast_filter_count: 0,
+                mcast_filter: [mac_address(&[]); simple_network::MAX_MCAST_FILTER_CNT],
+                current_address: mac_address(&mac),
+                broadcast_address: mac_address(&BROADCAST),
+                permanent_address: mac_address(&mac),
+                if_type: IF_TYPE_ETHERNET,
+                mac_address_changeable: Boolean::FALSE,
+                multiple_tx_supported: Boolean::FALSE,
+                media_present_supported: Boolean::FALSE,
+                media_present: Boolean::TRUE,
+            },
+            device_path: MacDevicePathProtocol {
+                device_path: DevicePathProtocol {
+                    r#type: r_efi::protocols::device_path::TYPE_MESSAGING,
+                    sub_type: 11, // MAC address
+                    length: [37, 0],
+                },
+                mac: mac_address(&mac),
+                if_type: IF_TYPE_ETHERNET,
+                end: DevicePathProtocol {
+                    r#type: r_efi::protocols::device_path::TYPE_END,
+                    sub_type: 0xff, // End of full path
+                    length: [4, 0],
+                },
+            },
+            device,
+            recycled: [core::ptr::null_mut(); MAX_RECYCLED],
+            recycled_count: 0,
+        }
+    }
+
+    // Points the protocol at the mode, once the wrapper is in its final
+    // location
+    fn fixup(&mut self) {
+        self.proto.mode = &mut self.mode;
+    }
+
+    fn device(&self) -> &VirtioNetDevice<'static> {
+        unsafe { &*self.device }
+    }
+
+    // At ExitBootServices, when the memory the device receives frames into
+    // becomes the OS's, the device is reset and the interface stopped
+    pub fn exit_boot_services(&mut self) {
+        self.device().reset();
+        self.mode.state = simple_network::STOPPED;
+    }
+
+    // What calls that need the interface to be initialized fail with
+    // otherwise
+    fn check_initialized(&self) -> Result<(), Status> {
+        match self.mode.state {
+            simple_network::INITIALIZED => Ok(()),
+            simple_network::STOPPED => Err(Status::NOT_STARTED),
+            _ => Err(Status::DEVICE_ERROR),
+        }
+    }
+
+    fn send(
+        &mut self,
+        header_size: usize,
+        buffer: &mut [u8],
+        source: *const MacAddress,
+        destination: *const MacAddress,
+        protocol: *const u16,
+    ) -> Status {
+        if let Err(status) = self.check_initialized() {
+            return status;
+        }
+        if buffer.len() < header_size {
+            return Status::BUFFER_TOO_SMALL;
+        }
+        if header_size != 0 {
+            if header_size != MEDIA_HEADER_SIZE || destination.is_null() || protocol.is_null() {
+                return Status::INVALID_PARAMETER;
+            }
+            let source = if source.is_null() {
+                self.mode.current_address
+            } else {
+                unsafe { *source }
+            };
+            buffer[0..6].copy_from_slice(unsafe { &(*destination).addr[..6] });
+            buffer[6..12].copy_from_slice(&source.addr[..6]);
+            buffer[12..14].copy_from_slice(&unsafe { *protocol }.to_be_bytes());
+        }
+        if self.recycled_count == MAX_RECYCLED {
+            return Status::NOT_READY;
+        }
+        match self.device().send(buffer) {
+            Ok(()) => {}
+            Err(crate::net::Error::FrameTooLarge) => return Status::INVALID_PARAMETER,
+            Err(crate::net::Error::Timeout) => return Status::DEVICE_ERROR,
+        }
+        // The frame has gone by the time the device is done with it
+        self.recycled[self.recycled_count] = buffer.as_mut_ptr() as *mut c_void;
+        self.recycled_count += 1;
+        Status::SUCCESS
+    }
+
+    // Takes the next frame into the buffer, returning its length, or how big
+    // the buffer has to be for it
+    fn recv(&self, buffer: &mut [u8]) -> Result<usize, (Status, usize)> {
+        self.check_initialized().map_err(|status| (status, 0))?;
+        let length = match self.device().peek() {
+            Some(length) => length,
+            None => return Err((Status::NOT_READY, 0)),
+        };
+        if length > buffer.len() {
+            return Err((Status::BUFFER_TOO_SMALL, length));
+        }
+        Ok(self.device().recv(buffer).unwrap_or(0))
+    }
+
+    fn next_recycled(&mut self) -> *mut c_void {
+        if self.recycled_count == 0 {
+            return core::ptr::null_mut();
+        }
+        let buffer = self.recycled[0];
+        self.recycled.copy_within(1.., 0);
+        self.recycled_count -= 1;
+        buffer
+    }
+}
+
+pub extern "win64" fn start(proto: *mut SimpleNetworkProtocol) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if wrapper.mode.state != simple_network::STOPPED {
+        return Status::ALREADY_STARTED;
+    }
+    wrapper.mode.state = simple_network::STARTED;
+    Status::SUCCESS
+}
+
+pub extern "win64" fn stop(proto: *mut SimpleNetworkProtocol) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if wrapper.mode.state == simple_network::STOPPED {
+        return Status::NOT_STARTED;
+    }
+    wrapper.mode.state = simple_network::STOPPED;
+    Status::SUCCESS
+}
+
+pub extern "win64" fn initialize(proto: *mut SimpleNetworkProtocol, _: usize, _: usize) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if wrapper.mode.state == simple_network::STOPPED {
+        return Status::NOT_STARTED;
+    }
+    wrapper.mode.state = simple_network::INITIALIZED;
+    Status::SUCCESS
+}
+
+pub extern "win64" fn reset(proto: *mut SimpleNetworkProtocol, _: Boolean) -> Status {
+    let wrapper = container_of!(proto, SnpWrapper, proto);
+    match unsafe { (*wrapper).check_initialized() } {
+        Ok(()) => Status::SUCCESS,
+        Err(status) => status,
+    }
+}
+
+pub extern "win64" fn shutdown(proto: *mut SimpleNetworkProtocol) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if let Err(status) = wrapper.check_initialized() {
+        return status;
+    }
+    wrapper.mode.state = simple_network::STARTED;
+    Status::SUCCESS
+}
+
+// Only the settings are kept: everything the device receives is passed on
+pub extern "win64" fn receive_filters(
+    proto: *mut SimpleNetworkProtocol,
+    enable: u32,
+    disable: u32,
+    reset_mcast_filter: Boolean,
+    mcast_filter_count: usize,
+    mcast_filter: *mut MacAddress,
+) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if let Err(status) = wrapper.check_initialized() {
+        return status;
+    }
+    let mode = &mut wrapper.mode;
+    if (enable | disable) & !mode.receive_filter_mask != 0
+        || mcast_filter_count > simple_network::MAX_MCAST_FILTER_CNT
+        || (mcast_filter_count != 0 && mcast_filter.is_null())
+    {
+        return Status::INVALID_PARAMETER;
+    }
+    mode.receive_filter_setting = (mode.receive_filter_setting | enable) & !disable;
+    if bool::from(reset_mcast_filter) {
+        mode.mcast_filter_count = 0;
+    } else if mcast_filter_count != 0 {
+        let filters = unsafe { core::slice::from_raw_parts(mcast_filter, mcast_filter_count) };
+        mode.mcast_filter[..mcast_filter_count].copy_from_slice(filters);
+        mode.mcast_filter_count = mcast_filter_count as u32;
+    }
+    Status::SUCCESS
+}
+
+pub extern "win64" fn station_address(
+    _: *mut SimpleNetworkProtocol,
+    _: Boolean,
+    _: *mut MacAddress,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+pub extern "win64" fn statistics(
+    _: *mut SimpleNetworkProtocol,
+    _: Boolean,
+    _: *mut usize,
+    _: *mut Statistics,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+// The multicast MAC address the IP multicast address is sent to
+pub extern "win64" fn mcast_ip_to_mac(
+    proto: *mut SimpleNetworkProtocol,
+    ipv6: Boolean,
+    ip: *mut IpAddress,
+    mac: *mut MacAddress,
+) -> Status {
+    let wrapper = container_of!(proto, SnpWrapper, proto);
+    if let Err(status) = unsafe { (*wrapper).check_initialized() } {
+        return status;
+    }
+    if ip.is_null() || mac.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let address = if bool::from(ipv6) {
+        let ip = unsafe { (*ip).v6.addr };
+        mac_address(&[0x33, 0x33, ip[12], ip[13], ip[14], ip[15]])
+    } else {
+        let ip = unsafe { (*ip).v4.addr };
+        if ip[0] & 0xf0 != 0xe0 {
+            return Status::INVALID_PARAMETER;
+        }
+        mac_address(&[0x01, 0x00, 0x5e, ip[1] & 0x7f, ip[2], ip[3]])
+    };
+    unsafe { *mac = address };
+    Status::SUCCESS
+}
+
+pub extern "win64" fn nv_data(
+    _: *mut SimpleNetworkProtocol,
+    _: Boolean,
+    _: usize,
+    _: usize,
+    _: *mut c_void,
+) -> Status {
+    Status::UNSUPPORTED
+}
+
+pub extern "win64" fn get_status(
+    proto: *mut SimpleNetworkProtocol,
+    interrupt_status: *mut u32,
+    tx_buf: *mut *mut c_void,
+) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if let Err(status) = wrapper.check_initialized() {
+        return status;
+    }
+    if !interrupt_status.is_null() {
+        let mut status = 0;
+        if wrapper.device().peek().is_some() {
+            status |= simple_network::RECEIVE_INTERRUPT;
+        }
+        if wrapper.recycled_count != 0 {
+            status |= simple_network::TRANSMIT_INTERRUPT;
+        }
+        unsafe { *interrupt_status = status };
+    }
+    if !tx_buf.is_null() {
+        unsafe { *tx_buf = wrapper.next_recycled() };
+    }
+    Status::SUCCESS
+}
+
+pub extern "win64" fn transmit(
+    proto: *mut SimpleNetworkProtocol,
+    header_size: usize,
+    buffer_size: usize,
+    buffer: *mut c_void,
+    source: *mut MacAddress,
+    destination: *mut MacAddress,
+    protocol: *mut u16,
+) -> Status {
+    let wrapper = container_of_mut!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &mut *wrapper };
+    if buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, buffer_size) };
+    wrapper.send(header_size, buffer, source, destination, protocol)
+}
+
+pub extern "win64" fn receive(
+    proto: *mut SimpleNetworkProtocol,
+    header_size: *mut usize,
+    buffer_size: *mut usize,
+    buffer: *mut c_void,
+    source: *mut MacAddress,
+    destination: *mut MacAddress,
+    protocol: *mut u16,
+) -> Status {
+    let wrapper = container_of!(proto, SnpWrapper, proto);
+    let wrapper = unsafe { &*wrapper };
+    if buffer_size.is_null() || buffer.is_null() {
+        return Status::INVALID_PARAMETER;
+    }
+    let buffer = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, *buffer_size) };
+    let length = match wrapper.recv(buffer) {
+        Ok(length) => length,
+        Err((Status::BUFFER_TOO_SMALL, length)) => {
+            unsafe { *buffer_size = length };
+            return Status::BUFFER_TOO_SMALL;
+        }
+        Err((status, _)) => return status,
+    };
+    unsafe { *buffer_size = length };
+    if length < MEDIA_HEADER_SIZE {
+        return Status::SUCCESS;
+    }
+    unsafe {
+        if !header_size.is_null() {
+            *header_size = MEDIA_HEADER_SIZE;
+        }
+        if !destination.is_null() {
+            *destination = mac_address(&buffer[0..6]);
+        }
+        if !source.is_null() {
+            *source = mac_address(&buffer[6..12]);
+        }
+        if !protocol.is_null() {
+            *protocol = u16::from_be_bytes([buffer[12], buffer[13]]);
+        }
+    }
+    Status::SUCCESS
+}
+
+// The notification function of the WaitForPacket event, which is run when
+// the event is checked or waited on
+pub extern "win64" fn wait_for_packet(event: Event, context: *mut c_void) {
+    let wrapper = context as *const SnpWrapper;
+    if unsafe { (*wrapper).device().peek() }.is_some() {
+        super::signal_event(event);
+    }
+}
+
+// Wraps the device in its final location, with the event for waiting for
+// packets
+pub fn new_snp_wrapper(device: &VirtioNetDevice) -> Option<*mut SnpWrapper> {
+    let size = size_of::<SnpWrapper>();
+    let (status, new_address) = super::ALLOCATOR.borrow_mut().allocate_pages(
+        efi::ALLOCATE_ANY_PAGES,
+        efi::LOADER_DATA,
+        ((size + super::PAGE_SIZE as usize - 1) / super::PAGE_SIZE as usize) as u64,
+        0_u64,
+    );
+    if status != Status::SUCCESS {
+        return None;
+    }
+
+    let sw = new_address as *mut SnpWrapper;
+    unsafe {
+        *sw = SnpWrapper::new(core::mem::transmute(device), device.mac());
+        (*sw).fixup();
+        (*sw).proto.wait_for_packet = super::EVENTS
+            .borrow_mut()
+            .create(
+                efi::EVT_NOTIFY_WAIT,
+                efi::TPL_NOTIFY,
+                Some(wait_for_packet),
+                sw as *mut c_void,
+            )
+            .ok()?;
+    }
+    Some(sw)
+}
+
+#[cfg(test)]
+mod tests {
+    use r_efi::{
+        efi::{Boolean, IpAddress, Ipv4Address, MacAddress, Status},
+        protocols::simple_network,
+    };
+
+    use super::SnpWrapper;
+    use crate::net::{tests::LoopbackTransport, VirtioNetDevice};
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn wrapper(device: &VirtioNetDevice) -> Box<SnpWrapper> {
+        let mut sw = Box::new(SnpWrapper::new(
+            unsafe { core::mem::transmute(device) },
+            device.mac(),
+        ));
+        sw.fixup();
+        sw
+    }
+
+    #[test]
+    fn test_state() {
+        let mut transport = LoopbackTransport::new();
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().unwrap();
+        let mut sw = wrapper(&device);
+        let proto = &mut sw.proto as *mut _;
+
+        assert_eq!(super::initialize(proto, 0, 0), Status::NOT_STARTED);
+        assert_eq!(super::reset(proto, Boolean::FALSE), Status::NOT_STARTED);
+        assert_eq!(super::start(proto), Status::SUCCESS);
+        assert_eq!(super::start(proto), Status::ALREADY_STARTED);
+        assert_eq!(super::reset(proto, Boolean::FALSE), Status::DEVICE_ERROR);
+        assert_eq!(super::initialize(proto, 0, 0), Status::SUCCESS);
+        assert_eq!(sw.mode.state, simple_network::INITIALIZED);
+        assert_eq!(super::shutdown(proto), Status::SUCCESS);
+        assert_eq!(super::stop(proto), Status::SUCCESS);
+        assert_eq!(super::stop(proto), Status::NOT_STARTED);
+
+        let mode = unsafe { &*sw.proto.mode };
+        assert_eq!(&mode.current_address.addr[..6], &MAC);
+        assert_eq!(mode.max_packet_size, 1500);
+    }
+
+    #[test]
+    fn test_exit_boot_services() {
+        let mut transport = LoopbackTransport::new();
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().unwrap();
+        let mut sw = wrapper(&device);
+        let proto = &mut sw.proto as *mut _;
+        super::start(proto);
+        super::initialize(proto, 0, 0);
+
+        sw.exit_boot_services();
+        assert_eq!(sw.mode.state, simple_network::STOPPED);
+        let mut frame = [0xaa_u8; 60];
+        let status = super::transmit(
+            proto,
+            0,
+            frame.len(),
+            frame.as_mut_ptr() as *mut _,
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+        );
+        assert_eq!(status, Status::NOT_STARTED);
+    }
+
+    #[test]
+    fn test_transmit_receive() {
+        let mut transport = LoopbackTransport::new();
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().unwrap();
+        let mut sw = wrapper(&device);
+        let proto = &mut sw.proto as *mut _;
+        super::start(proto);
+        super::initialize(proto, 0, 0);
+
+        // The header is filled in from the addresses and protocol
+        let mut frame = [0xaa_u8; 60];
+        let mut destination = MacAddress { addr: [0; 32] };
+        destination.addr[..6].copy_from_slice(&[0xff; 6]);
+        let mut protocol = 0x0806;
+        let status = super::transmit(
+            proto,
+            14,
+            frame.len(),
+            frame.as_mut_ptr() as *mut _,
+            core::ptr::null_mut(),
+            &mut destination,
+            &mut protocol,
+        );
+        assert_eq!(status, Status::SUCCESS);
+
+        let mut interrupts = 0;
+        let mut tx_buf = core::ptr::null_mut();
+        assert_eq!(
+            super::get_status(proto, &mut interrupts, &mut tx_buf),
+            Status::SUCCESS
+        );
+        assert_eq!(
+            interrupts,
+            simple_network::RECEIVE_INTERRUPT | simple_network::TRANSMIT_INTERRUPT
+        );
+        assert_eq!(tx_buf, frame.as_mut_ptr() as *mut _);
+        super::get_status(proto, &mut interrupts, &mut tx_buf);
+        assert!(tx_buf.is_null());
+
+        // Too small a buffer is told how big it has to be
+        let mut buffer = [0u8; 64];
+        let mut size = 10;
+        let status = super::receive(
+            proto,
+            core::ptr::null_mut(),
+            &mut size,
+            buffer.as_mut_ptr() as *mut _,
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+        );
+        assert_eq!((status, size), (Status::BUFFER_TOO_SMALL, 60));
+
+        let mut header_size = 0;
+        let mut size = buffer.len();
+        let mut source = MacAddress { addr: [0; 32] };
+        let mut protocol = 0;
+        let status = super::receive(
+            proto,
+            &mut header_size,
+            &mut size,
+            buffer.as_mut_ptr() as *mut _,
+            &mut source,
+            core::ptr::null_mut(),
+            &mut protocol,
+        );
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!((header_size, size, protocol), (14, 60, 0x0806));
+        assert_eq!(&source.addr[..6], &MAC);
+        assert_eq!(&buffer[..6], &[0xff; 6]);
+        assert_eq!(buffer[14..60], [0xaa; 46]);
+
+        let status = super::receive(
+            proto,
+            core::ptr::null_mut(),
+            &mut size,
+            buffer.as_mut_ptr() as *mut _,
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+        );
+        assert_eq!(status, Status::NOT_READY);
+    }
+
+    #[test]
+    fn test_mcast_ip_to_mac() {
+        let mut transport = LoopbackTransport::new();
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().unwrap();
+        let mut sw = wrapper(&device);
+        let proto = &mut sw.proto as *mut _;
+        super::start(proto);
+        super::initialize(proto, 0, 0);
+
+        let mut ip = IpAddress {
+            v4: Ipv4Address {
+                addr: [224, 0x81, 2, 3],
+            },
+        };
+        let mut mac = MacAddress { addr: [0; 32] };
+        assert_eq!(
+            super::mcast_ip_to_mac(proto, Boolean::FALSE, &mut ip, &mut mac),
+            Status::SUCCESS
+        );
+        assert_eq!(&mac.addr[..6], &[0x01, 0x00, 0x5e, 0x01, 2, 3]);
+
+        ip.v4.addr = [10, 0, 2, 2];
+        assert_eq!(
+            super::mcast_ip_to_mac(proto, Boolean::FALSE, &mut ip, &mut mac),
+            Status::INVALID_PARAMETER
+        );
+    }
+}
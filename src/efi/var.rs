@@ -9,12 +9,45 @@ use alloc::vec::Vec;
 
 use r_efi::efi;
 
+use crate::{
+    block::SectorWrite,
+    fat::{self, Read, Write},
+};
+
+// Where the non-volatile variables are kept on the ESP. The FAT code can only
+// overwrite existing files so this needs to be created ahead of time and be
+// large enough to hold all the variables.
+const STORE_PATH: &str = "/EFI/rhfw/varstore.bin";
+const STORE_MAGIC: [u8; 8] = *b"RHFWVARS";
+// magic, payload length and payload CRC
+const STORE_HEADER_SIZE: usize = 16;
+// name length, data length, attributes and GUID
+const STORE_RECORD_SIZE: usize = 28;
+
+#[derive(Debug)]
+pub enum StoreError {
+    FileError(fat::Error),
+    BadMagic,
+    Truncated,
+    CrcMismatch,
+    InvalidRecord,
+    TooLarge,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
 #[derive(Debug)]
 struct Descriptor {
     name: Vec<u16>,
     guid: efi::Guid,
     attr: u32,
     data: Vec<u8>,
+    // Provided by the firmware and can't be changed by SetVariable()
+    read_only: bool,
 }
 
 impl Descriptor {
@@ -24,10 +57,148 @@ impl Descriptor {
             guid: efi::Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]),
             attr: 0,
             data: Vec::new(),
+            read_only: false,
         }
     }
 }
 
+// Length of a NUL terminated UCS-2 string, not counting the terminator
+fn name_length(name: *const u16) -> usize {
+    let mut len = 0;
+    while unsafe { *name.add(len) } != 0 {
+        len += 1;
+    }
+    len
+}
+
+fn open_store<'a>(fs: &'a fat::Filesystem) -> Result<fat::File<'a>, StoreError> {
+    match fs.open(STORE_PATH) {
+        Ok(fat::Node::File(f)) => Ok(f),
+        Ok(fat::Node::Directory(_)) => Err(StoreError::FileError(fat::Error::NotFound)),
+        Err(e) => Err(StoreError::FileError(e)),
+    }
+}
+
+// The records of a store file once its header has been checked
+fn payload(data: &[u8]) -> Result<&[u8], StoreError> {
+    if data.len() < STORE_HEADER_SIZE {
+        return Err(StoreError::Truncated);
+    }
+    if data[0..8] != STORE_MAGIC {
+        return Err(StoreError::BadMagic);
+    }
+    let len = read_u32(data, 8) as usize;
+    if len > data.len() - STORE_HEADER_SIZE {
+        return Err(StoreError::Truncated);
+    }
+    let payload = &data[STORE_HEADER_SIZE..STORE_HEADER_SIZE + len];
+    if crate::common::crc32(0, payload) != read_u32(data, 12) {
+        return Err(StoreError::CrcMismatch);
+    }
+    Ok(payload)
+}
+
+// A variable as it is kept in the store file, with the name still as little
+// endian UCS-2 bytes including the terminator
+struct Record<'a> {
+    name: &'a [u8],
+    guid: efi::Guid,
+    attr: u32,
+    data: &'a [u8],
+}
+
+// The records in the payload, stopping after the first bad one
+fn records(payload: &[u8]) -> impl Iterator<Item = Result<Record, StoreError>> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        if offset == payload.len() {
+            return None;
+        }
+        let record = parse_record(&payload[offset..]);
+        offset = match record {
+            Ok((_, length)) => offset + length,
+            Err(_) => payload.len(),
+        };
+        Some(record.map(|(record, _)| record))
+    })
+}
+
+// The record at the start of data and how long it is
+fn parse_record(data: &[u8]) -> Result<(Record, usize), StoreError> {
+    if data.len() < STORE_RECORD_SIZE {
+        return Err(StoreError::InvalidRecord);
+    }
+    let name_len = read_u32(data, 0) as usize;
+    let data_len = read_u32(data, 4) as usize;
+    let attr = read_u32(data, 8);
+    let mut guid = [0; 16];
+    guid.copy_from_slice(&data[12..STORE_RECORD_SIZE]);
+    let rest = &data[STORE_RECORD_SIZE..];
+
+    if name_len < 2
+        || data_len == 0
+        || attr & efi::VARIABLE_NON_VOLATILE == 0
+        || rest.len() / 2 < name_len
+        || rest.len() - name_len * 2 < data_len
+    {
+        return Err(StoreError::InvalidRecord);
+    }
+    let name = &rest[..name_len * 2];
+    let nul = name
+        .chunks_exact(2)
+        .position(|c| c == [0, 0])
+        .ok_or(StoreError::InvalidRecord)?;
+    if nul != name_len - 1 {
+        return Err(StoreError::InvalidRecord);
+    }
+
+    let record = Record {
+        name,
+        guid: efi::Guid::from_bytes(&guid),
+        attr,
+        data: &rest[name_len * 2..name_len * 2 + data_len],
+    };
+    Ok((record, STORE_RECORD_SIZE + name_len * 2 + data_len))
+}
+
+// Looks a variable up in a store file that has been read into memory, for
+// use before there is a heap for the VariableAllocator
+pub fn find_in_store<'a>(
+    data: &'a [u8],
+    name: &str,
+    guid: &efi::Guid,
+) -> Result<Option<&'a [u8]>, StoreError> {
+    for record in records(payload(data)?) {
+        let record = record?;
+        let matches = record
+            .name
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .eq(name.encode_utf16().chain(core::iter::once(0)));
+        if matches && &record.guid == guid {
+            return Ok(Some(record.data));
+        }
+    }
+    Ok(None)
+}
+
+// Reads the store file into buffer
+pub fn read_store<'a>(fs: &fat::Filesystem, buffer: &'a mut [u8]) -> Result<&'a [u8], StoreError> {
+    let mut file = open_store(fs)?;
+    let size = file.get_size() as usize;
+    if size > buffer.len() {
+        return Err(StoreError::TooLarge);
+    }
+    let mut sector = [0; 512];
+    let mut offset = 0;
+    while offset < size {
+        let bytes = file.read(&mut sector).map_err(StoreError::FileError)? as usize;
+        buffer[offset..offset + bytes].copy_from_slice(&sector[..bytes]);
+        offset += bytes;
+    }
+    Ok(&buffer[..size])
+}
+
 pub struct VariableAllocator {
     allocations: Vec<Descriptor>,
 }
@@ -43,21 +214,160 @@ impl VariableAllocator {
         if name.is_null() || guid.is_null() {
             return None;
         }
-        let len = crate::common::ucs2_as_ascii_length(name);
+        let len = name_length(name);
         if len == 0 {
             return None;
         }
 
-        let s = unsafe { core::slice::from_raw_parts(name as *const u16, len + 1) };
-        let mut name: Vec<u16> = Vec::new();
-        name.extend_from_slice(s);
+        let name = unsafe { core::slice::from_raw_parts(name, len + 1) };
         let guid = unsafe { &*guid };
-        for i in 0..self.allocations.len() {
-            if name == self.allocations[i].name && guid == &self.allocations[i].guid {
-                return Some(i);
+        self.allocations
+            .iter()
+            .position(|a| a.name == name && &a.guid == guid)
+    }
+
+    // Adds a variable that the firmware provides, in place of any there is
+    fn insert(&mut self, name: &str, attr: u32, data: &[u8], read_only: bool) {
+        let mut a = Descriptor::new();
+        a.name.extend(name.encode_utf16());
+        a.name.push(0);
+        a.guid = efi::GLOBAL_VARIABLE_GUID;
+        a.attr = attr;
+        a.data.extend_from_slice(data);
+        a.read_only = read_only;
+        match self.find(a.name.as_ptr(), &a.guid) {
+            Some(index) => self.allocations[index] = a,
+            None => self.allocations.push(a),
+        }
+    }
+
+    // The data of a variable, for the firmware's own use
+    #[cfg_attr(not(feature = "hash-allowlist"), allow(dead_code))]
+    pub fn data(&self, name: &str, guid: &efi::Guid) -> Option<&[u8]> {
+        self.allocations
+            .iter()
+            .find(|a| {
+                &a.guid == guid
+                    && a.name
+                        .iter()
+                        .copied()
+                        .eq(name.encode_utf16().chain(core::iter::once(0)))
+            })
+            .map(|a| a.data.as_slice())
+    }
+
+    // SecureBoot is read-only to everything else
+    #[cfg(feature = "hash-allowlist")]
+    pub fn set_secure_boot(&mut self, enabled: bool) {
+        let name: Vec<u16> = "SecureBoot\0".encode_utf16().collect();
+        if let Some(index) = self.find(name.as_ptr(), &efi::GLOBAL_VARIABLE_GUID) {
+            let data = &mut self.allocations[index].data;
+            data.clear();
+            data.push(enabled as u8);
+        }
+    }
+
+    // The variables that must exist before any image is started
+    pub fn add_defaults(&mut self) {
+        let attr = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
+        self.insert("SecureBoot", attr, &[0], true);
+        self.insert("PlatformLangCodes", attr, b"en-US\0", true);
+        self.insert(
+            "PlatformLang",
+            attr | efi::VARIABLE_NON_VOLATILE,
+            b"en-US\0",
+            false,
+        );
+    }
+
+    // Serializes the non-volatile variables in the store file format
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&STORE_MAGIC);
+        data.extend_from_slice(&[0; 8]);
+        for a in &self.allocations {
+            if a.attr & efi::VARIABLE_NON_VOLATILE == 0 || a.read_only {
+                continue;
+            }
+            data.extend_from_slice(&(a.name.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(a.data.len() as u32).to_le_bytes());
+            data.extend_from_slice(&a.attr.to_le_bytes());
+            data.extend_from_slice(a.guid.as_bytes());
+            for c in &a.name {
+                data.extend_from_slice(&c.to_le_bytes());
+            }
+            data.extend_from_slice(&a.data);
+        }
+
+        let len = (data.len() - STORE_HEADER_SIZE) as u32;
+        let crc = crate::common::crc32(0, &data[STORE_HEADER_SIZE..]);
+        data[8..12].copy_from_slice(&len.to_le_bytes());
+        data[12..16].copy_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    // Adds the variables from a store file, replacing any existing ones. The
+    // whole file is checked first so nothing changes if any of it is bad.
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), StoreError> {
+        let mut loaded = Vec::new();
+        for record in records(payload(data)?) {
+            let record = record?;
+            let mut a = Descriptor::new();
+            a.name.extend(
+                record
+                    .name
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]])),
+            );
+            a.guid = record.guid;
+            a.attr = record.attr;
+            a.data.extend_from_slice(record.data);
+            loaded.push(a);
+        }
+
+        for a in loaded {
+            match self.find(a.name.as_ptr(), &a.guid) {
+                // The firmware provided values always win
+                Some(index) if self.allocations[index].read_only => {}
+                Some(index) => self.allocations[index] = a,
+                None => self.allocations.push(a),
+            }
+        }
+        Ok(())
+    }
+
+    // Loads the non-volatile variables saved by a previous boot
+    pub fn load(&mut self, fs: &fat::Filesystem) -> Result<(), StoreError> {
+        let mut file = open_store(fs)?;
+
+        let mut data = Vec::new();
+        let mut sector = [0; 512];
+        loop {
+            match file.read(&mut sector) {
+                Ok(bytes) => data.extend_from_slice(&sector[..bytes as usize]),
+                Err(fat::Error::EndOfFile) => break,
+                Err(e) => return Err(StoreError::FileError(e)),
             }
         }
-        None
+        self.deserialize(&data)
+    }
+
+    // Writes the non-volatile variables back to the store file
+    pub fn save(&self, fs: &fat::Filesystem) -> Result<(), StoreError> {
+        let mut file = open_store(fs)?;
+
+        let data = self.serialize();
+        if data.len() > file.get_size() as usize {
+            return Err(StoreError::TooLarge);
+        }
+        for chunk in data.chunks(512) {
+            let mut sector = [0; 512];
+            sector[..chunk.len()].copy_from_slice(chunk);
+            file.write(&mut sector).map_err(StoreError::FileError)?;
+        }
+        // Nothing is saved until it has reached the disk
+        fs.flush()
+            .map_err(|_| StoreError::FileError(fat::Error::BlockError))
     }
 
     pub fn get(
@@ -82,6 +392,9 @@ impl VariableAllocator {
                 return efi::Status::BUFFER_TOO_SMALL;
             }
         }
+        if data.is_null() {
+            return efi::Status::INVALID_PARAMETER;
+        }
 
         assert!(!a.data.is_empty());
         unsafe {
@@ -108,14 +421,25 @@ impl VariableAllocator {
         if name.is_null() || guid.is_null() {
             return efi::Status::INVALID_PARAMETER;
         }
-        let len = crate::common::ucs2_as_ascii_length(name);
+        let len = name_length(name);
         if len == 0 {
             return efi::Status::INVALID_PARAMETER;
         }
+        // Anything available at runtime must be available to boot services
+        if attr & efi::VARIABLE_RUNTIME_ACCESS != 0 && attr & efi::VARIABLE_BOOTSERVICE_ACCESS == 0
+        {
+            return efi::Status::INVALID_PARAMETER;
+        }
+        // Without checking authenticated writes the signature databases can
+        // only come from the store file, or anything could allow itself
+        #[cfg(feature = "hash-allowlist")]
+        if unsafe { *guid } == super::secure_boot::IMAGE_SECURITY_DATABASE_GUID {
+            return efi::Status::WRITE_PROTECTED;
+        }
         let index = self.find(name, guid);
         if index == None {
             // new variable
-            if size == 0 {
+            if size == 0 || attr & !efi::VARIABLE_APPEND_WRITE == 0 {
                 return efi::Status::NOT_FOUND;
             }
             if data.is_null() {
@@ -134,6 +458,10 @@ impl VariableAllocator {
             return efi::Status::SUCCESS;
         }
 
+        if self.allocations[index.unwrap()].read_only {
+            return efi::Status::WRITE_PROTECTED;
+        }
+
         if attr & efi::VARIABLE_APPEND_WRITE != 0 {
             // append to existing variable
             if size == 0 {
@@ -167,11 +495,53 @@ impl VariableAllocator {
 
         efi::Status::SUCCESS
     }
+
+    // Enumerates the variables: an empty name gets the first one, otherwise
+    // name and guid are replaced by those of the variable that follows.
+    pub fn get_next(
+        &self,
+        size: *mut usize,
+        name: *mut efi::Char16,
+        guid: *mut efi::Guid,
+    ) -> efi::Status {
+        if size.is_null() || name.is_null() || guid.is_null() {
+            return efi::Status::INVALID_PARAMETER;
+        }
+
+        let next = if unsafe { *name } == 0 {
+            0
+        } else {
+            match self.find(name, guid) {
+                Some(index) => index + 1,
+                None => return efi::Status::INVALID_PARAMETER,
+            }
+        };
+
+        let a = match self.allocations.get(next) {
+            Some(a) => a,
+            None => return efi::Status::NOT_FOUND,
+        };
+
+        let name_size = a.name.len() * core::mem::size_of::<efi::Char16>();
+        unsafe {
+            if *size < name_size {
+                *size = name_size;
+                return efi::Status::BUFFER_TOO_SMALL;
+            }
+
+            let name = core::slice::from_raw_parts_mut(name, a.name.len());
+            name.copy_from_slice(&a.name);
+            *guid = a.guid;
+            *size = name_size;
+        }
+
+        efi::Status::SUCCESS
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::VariableAllocator;
+    use super::{StoreError, VariableAllocator, STORE_HEADER_SIZE, STORE_RECORD_SIZE};
     use r_efi::efi;
 
     const NAME: [efi::Char16; 5] = [116, 101, 115, 116, 0];
@@ -179,6 +549,10 @@ mod tests {
     const ATTR: u32 = efi::VARIABLE_BOOTSERVICE_ACCESS | efi::VARIABLE_RUNTIME_ACCESS;
 
     fn set_initial_variable(allocator: &mut VariableAllocator, data: &[u8]) {
+        set_initial_variable_at(allocator, data, 0);
+    }
+
+    fn set_initial_variable_at(allocator: &mut VariableAllocator, data: &[u8], index: usize) {
         let status = allocator.set(
             NAME.as_ptr(),
             &GUID,
@@ -188,10 +562,10 @@ mod tests {
         );
 
         assert_eq!(status, efi::Status::SUCCESS);
-        assert_eq!(allocator.allocations[0].name, NAME);
-        assert_eq!(allocator.allocations[0].guid, GUID);
-        assert_eq!(allocator.allocations[0].attr, ATTR);
-        assert_eq!(allocator.allocations[0].data, data);
+        assert_eq!(allocator.allocations[index].name, NAME);
+        assert_eq!(allocator.allocations[index].guid, GUID);
+        assert_eq!(allocator.allocations[index].attr, ATTR);
+        assert_eq!(allocator.allocations[index].data, data);
     }
 
     #[test]
@@ -294,6 +668,292 @@ mod tests {
         assert!(allocator.allocations.is_empty());
     }
 
+    #[test]
+    fn test_defaults() {
+        let mut allocator = VariableAllocator::new();
+        allocator.add_defaults();
+        // As for each application started, which mustn't add them again
+        allocator.add_defaults();
+        assert_eq!(allocator.allocations.len(), 3);
+
+        let name: Vec<u16> = "SecureBoot\0".encode_utf16().collect();
+        let mut data = [0xffu8; 1];
+        let mut size = data.len();
+        let mut attr = 0;
+        let status = allocator.get(
+            name.as_ptr(),
+            &efi::GLOBAL_VARIABLE_GUID,
+            &mut attr,
+            &mut size,
+            data.as_mut_ptr() as *mut core::ffi::c_void,
+        );
+        assert_eq!(status, efi::Status::SUCCESS);
+        assert_eq!(data, [0]);
+        assert_eq!(attr & efi::VARIABLE_NON_VOLATILE, 0);
+
+        // Read-only
+        let status = allocator.set(
+            name.as_ptr(),
+            &efi::GLOBAL_VARIABLE_GUID,
+            attr,
+            1,
+            [1u8].as_ptr() as *const core::ffi::c_void,
+        );
+        assert_eq!(status, efi::Status::WRITE_PROTECTED);
+        let status = allocator.set(
+            name.as_ptr(),
+            &efi::GLOBAL_VARIABLE_GUID,
+            0,
+            0,
+            core::ptr::null(),
+        );
+        assert_eq!(status, efi::Status::WRITE_PROTECTED);
+
+        // But the language can be changed
+        let name: Vec<u16> = "PlatformLang\0".encode_utf16().collect();
+        let data = b"fr-FR\0";
+        let status = allocator.set(
+            name.as_ptr(),
+            &efi::GLOBAL_VARIABLE_GUID,
+            efi::VARIABLE_NON_VOLATILE | ATTR,
+            data.len(),
+            data.as_ptr() as *const core::ffi::c_void,
+        );
+        assert_eq!(status, efi::Status::SUCCESS);
+    }
+
+    #[cfg(feature = "hash-allowlist")]
+    #[test]
+    fn test_signature_databases() {
+        use super::super::secure_boot::IMAGE_SECURITY_DATABASE_GUID;
+        use super::Descriptor;
+
+        let mut allocator = VariableAllocator::new();
+        let name: Vec<u16> = "db\0".encode_utf16().collect();
+        let data = [1u8];
+        let set = |allocator: &mut VariableAllocator, attr| {
+            allocator.set(
+                name.as_ptr(),
+                &IMAGE_SECURITY_DATABASE_GUID,
+                attr,
+                data.len(),
+                data.as_ptr() as *const core::ffi::c_void,
+            )
+        };
+        assert_eq!(set(&mut allocator, ATTR), efi::Status::WRITE_PROTECTED);
+        assert!(allocator.allocations.is_empty());
+
+        // Nor can one loaded from the store be changed
+        let mut a = Descriptor::new();
+        a.name = name.clone();
+        a.guid = IMAGE_SECURITY_DATABASE_GUID;
+        a.attr = efi::VARIABLE_NON_VOLATILE | ATTR;
+        a.data.push(0);
+        allocator.allocations.push(a);
+        let attr = efi::VARIABLE_NON_VOLATILE | ATTR;
+        assert_eq!(
+            set(&mut allocator, attr | efi::VARIABLE_APPEND_WRITE),
+            efi::Status::WRITE_PROTECTED
+        );
+        assert_eq!(set(&mut allocator, 0), efi::Status::WRITE_PROTECTED);
+        assert_eq!(
+            allocator.data("db", &IMAGE_SECURITY_DATABASE_GUID),
+            Some(&[0][..])
+        );
+    }
+
+    #[test]
+    fn test_invalid_attributes() {
+        let mut allocator = VariableAllocator::new();
+        let data = [1u8];
+        let status = allocator.set(
+            NAME.as_ptr(),
+            &GUID,
+            efi::VARIABLE_RUNTIME_ACCESS,
+            data.len(),
+            data.as_ptr() as *const core::ffi::c_void,
+        );
+        assert_eq!(status, efi::Status::INVALID_PARAMETER);
+
+        // Deleting something that doesn't exist
+        let status = allocator.set(
+            NAME.as_ptr(),
+            &GUID,
+            0,
+            data.len(),
+            data.as_ptr() as *const core::ffi::c_void,
+        );
+        assert_eq!(status, efi::Status::NOT_FOUND);
+        assert!(allocator.allocations.is_empty());
+    }
+
+    #[test]
+    fn test_get_next() {
+        let mut allocator = VariableAllocator::new();
+        allocator.add_defaults();
+        set_initial_variable_at(&mut allocator, &[1, 2, 3], 3);
+
+        let mut names = Vec::new();
+        let mut name = [0u16; 32];
+        let mut guid = efi::Guid::from_fields(0, 0, 0, 0, 0, &[0; 6]);
+        loop {
+            let mut size = core::mem::size_of_val(&name);
+            let status = allocator.get_next(&mut size, name.as_mut_ptr(), &mut guid);
+            if status == efi::Status::NOT_FOUND {
+                break;
+            }
+            assert_eq!(status, efi::Status::SUCCESS);
+            let len = size / 2 - 1;
+            assert_eq!(name[len], 0);
+            names.push((String::from_utf16(&name[..len]).unwrap(), guid));
+        }
+        assert_eq!(
+            names,
+            [
+                ("SecureBoot".to_string(), efi::GLOBAL_VARIABLE_GUID),
+                ("PlatformLangCodes".to_string(), efi::GLOBAL_VARIABLE_GUID),
+                ("PlatformLang".to_string(), efi::GLOBAL_VARIABLE_GUID),
+                ("test".to_string(), GUID),
+            ]
+        );
+
+        // The buffer has to hold the next name, "SecureBoot" and the NUL
+        let mut name = [0u16; 4];
+        let mut size = core::mem::size_of_val(&name);
+        let status = allocator.get_next(&mut size, name.as_mut_ptr(), &mut guid);
+        assert_eq!(status, efi::Status::BUFFER_TOO_SMALL);
+        assert_eq!(size, 22);
+        assert_eq!(name, [0; 4]);
+
+        // Unknown current variable
+        let mut size = core::mem::size_of_val(&NAME);
+        let mut name = NAME;
+        let mut guid = efi::GLOBAL_VARIABLE_GUID;
+        let status = allocator.get_next(&mut size, name.as_mut_ptr(), &mut guid);
+        assert_eq!(status, efi::Status::INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let mut allocator = VariableAllocator::new();
+        allocator.add_defaults();
+        set_initial_variable_at(&mut allocator, &[1, 2, 3], 3);
+        let data = b"fr-FR\0";
+        let status = allocator.set(
+            NAME.as_ptr(),
+            &efi::GLOBAL_VARIABLE_GUID,
+            efi::VARIABLE_NON_VOLATILE | ATTR,
+            data.len(),
+            data.as_ptr() as *const core::ffi::c_void,
+        );
+        assert_eq!(status, efi::Status::SUCCESS);
+        let store = allocator.serialize();
+
+        // Only the writable non-volatile variables are saved
+        let mut loaded = VariableAllocator::new();
+        loaded.deserialize(&store).unwrap();
+        assert_eq!(loaded.allocations.len(), 2);
+        assert_eq!(loaded.allocations[0].data, b"en-US\0");
+        assert_eq!(loaded.allocations[1].name, NAME);
+        assert_eq!(loaded.allocations[1].guid, efi::GLOBAL_VARIABLE_GUID);
+        assert_eq!(loaded.allocations[1].data, data);
+
+        // Loaded values replace the defaults, and padding is ignored
+        let mut loaded = VariableAllocator::new();
+        loaded.add_defaults();
+        let mut changed = store;
+        let lang: Vec<u8> = "PlatformLang\0"
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes().to_vec())
+            .collect();
+        let offset = STORE_HEADER_SIZE + STORE_RECORD_SIZE + lang.len();
+        changed[offset..offset + 5].copy_from_slice(b"de-DE");
+        let crc = crate::common::crc32(0, &changed[STORE_HEADER_SIZE..]);
+        changed[12..16].copy_from_slice(&crc.to_le_bytes());
+        changed.resize(1024, 0);
+        loaded.deserialize(&changed).unwrap();
+        assert_eq!(loaded.allocations.len(), 4);
+        assert_eq!(loaded.allocations[2].data, b"de-DE\0");
+    }
+
+    #[test]
+    fn test_store_save() {
+        use crate::fat::tests::{Dir, ImageBuilder};
+
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT16);
+        let efi_dir = builder.add_dir(Dir::Root, b"EFI        ");
+        let rhfw = builder.add_dir(efi_dir, b"RHFW       ");
+        builder.add_file(rhfw, b"VARSTOREBIN", &[0; 1024]);
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.set_writer(&disk);
+        fs.init().unwrap();
+
+        let mut allocator = VariableAllocator::new();
+        allocator.add_defaults();
+        allocator.save(&fs).unwrap();
+        assert_eq!(disk.flushes.get(), 1);
+
+        let mut loaded = VariableAllocator::new();
+        loaded.load(&fs).unwrap();
+        assert_eq!(loaded.allocations[0].data, b"en-US\0");
+    }
+
+    #[test]
+    fn test_find_in_store() {
+        let mut allocator = VariableAllocator::new();
+        allocator.add_defaults();
+        let store = allocator.serialize();
+
+        let find = |name| super::find_in_store(&store, name, &efi::GLOBAL_VARIABLE_GUID);
+        assert_eq!(find("PlatformLang").unwrap(), Some(&b"en-US\0"[..]));
+        // Not saved as it is provided by the firmware
+        assert_eq!(find("SecureBoot").unwrap(), None);
+        assert_eq!(find("PlatformLan").unwrap(), None);
+        assert_eq!(
+            super::find_in_store(&store, "PlatformLang", &GUID).unwrap(),
+            None
+        );
+        assert!(matches!(
+            super::find_in_store(&store[..20], "PlatformLang", &GUID),
+            Err(StoreError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_store_corrupt() {
+        let mut allocator = VariableAllocator::new();
+        allocator.add_defaults();
+        let store = allocator.serialize();
+
+        let mut loaded = VariableAllocator::new();
+        assert!(matches!(
+            loaded.deserialize(&store[..store.len() - 1]),
+            Err(StoreError::Truncated)
+        ));
+        assert!(matches!(
+            loaded.deserialize(&[0; 512]),
+            Err(StoreError::BadMagic)
+        ));
+        let mut corrupt = store.clone();
+        corrupt[STORE_HEADER_SIZE + 30] ^= 1;
+        assert!(matches!(
+            loaded.deserialize(&corrupt),
+            Err(StoreError::CrcMismatch)
+        ));
+
+        // A record claiming more data than there is
+        let mut corrupt = store;
+        corrupt[STORE_HEADER_SIZE + 4] = 0xff;
+        let crc = crate::common::crc32(0, &corrupt[STORE_HEADER_SIZE..]);
+        corrupt[12..16].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            loaded.deserialize(&corrupt),
+            Err(StoreError::InvalidRecord)
+        ));
+        assert!(loaded.allocations.is_empty());
+    }
+
     #[test]
     fn test_get() {
         let mut allocator = VariableAllocator::new();
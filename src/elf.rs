@@ -0,0 +1,587 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots ELF kernels with a PVH entry point, such as a Linux vmlinux built with
+// CONFIG_PVH. The segments are loaded at their physical addresses and the
+// kernel is started in 32-bit protected mode at the entry point in its Xen ELF
+// note, with the PVH start info in %ebx, so everything has to be in the first
+// 4GiB.
+
+use core::{
+    cmp::{max, min},
+    convert::TryFrom,
+};
+
+use crate::{
+    boot::{Cmdline, Info, MemoryMap},
+    fat::{self, Read},
+    mem::MemoryRegion,
+    pvh::{BootParams, XEN_ELFNOTE_PHYS32_ENTRY},
+    sha256::Sha256,
+    tpm,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    FileError(fat::Error),
+    MagicMissing,
+    // Not a little endian i386 or x86-64 executable
+    NotSupported,
+    InvalidHeader,
+    // There is no Xen ELF note with the PVH entry point
+    NoEntry,
+    NoSegments,
+    // A segment isn't all in RAM below 4GiB or would overwrite the firmware
+    InvalidAddress,
+    NoInitrdMemory,
+}
+
+impl From<fat::Error> for Error {
+    fn from(e: fat::Error) -> Error {
+        Error::FileError(e)
+    }
+}
+
+const ELF_CLASS_32: u8 = 1;
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+// The header of a 64-bit file, that of a 32-bit one being shorter
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PROGRAM_HEADER_SIZE_32: usize = 32;
+const MAX_PROGRAM_HEADERS: usize = 32;
+
+// Program header types
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+// Only this much of each note segment is looked through
+const NOTES_SIZE: usize = 4096;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// Whether the file is 32 or 64-bit, its entry point and the offset of the
+// program headers and how many there are
+#[derive(Debug, PartialEq)]
+struct FileHeader {
+    class: u8,
+    entry: u64,
+    offset: u32,
+    count: usize,
+}
+
+impl FileHeader {
+    fn program_header_size(&self) -> usize {
+        match self.class {
+            ELF_CLASS_32 => PROGRAM_HEADER_SIZE_32,
+            _ => PROGRAM_HEADER_SIZE,
+        }
+    }
+}
+
+fn parse_header(data: &[u8]) -> Result<FileHeader, Error> {
+    if data.len() < HEADER_SIZE || !data.starts_with(b"\x7fELF") {
+        return Err(Error::MagicMissing);
+    }
+    let class = data[4];
+    let machine = match class {
+        ELF_CLASS_32 => EM_386,
+        ELF_CLASS_64 => EM_X86_64,
+        _ => return Err(Error::NotSupported),
+    };
+    if data[5] != ELF_DATA_LSB || read_u16(data, 16) != ET_EXEC || read_u16(data, 18) != machine {
+        return Err(Error::NotSupported);
+    }
+    let (entry, offset, entry_size, count) = match class {
+        ELF_CLASS_32 => (
+            u64::from(read_u32(data, 24)),
+            u64::from(read_u32(data, 28)),
+            read_u16(data, 42),
+            read_u16(data, 44),
+        ),
+        _ => (
+            read_u64(data, 24),
+            read_u64(data, 32),
+            read_u16(data, 54),
+            read_u16(data, 56),
+        ),
+    };
+    let header = FileHeader {
+        class,
+        entry,
+        offset: offset as u32,
+        count: usize::from(count),
+    };
+    if usize::from(entry_size) != header.program_header_size()
+        || header.count > MAX_PROGRAM_HEADERS
+        || offset > u64::from(u32::MAX)
+    {
+        return Err(Error::InvalidHeader);
+    }
+    Ok(header)
+}
+
+#[derive(Debug, PartialEq)]
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    fn parse(data: &[u8], class: u8) -> ProgramHeader {
+        match class {
+            ELF_CLASS_32 => ProgramHeader {
+                kind: read_u32(data, 0),
+                offset: u64::from(read_u32(data, 4)),
+                paddr: u64::from(read_u32(data, 12)),
+                filesz: u64::from(read_u32(data, 16)),
+                memsz: u64::from(read_u32(data, 20)),
+                align: u64::from(read_u32(data, 28)),
+            },
+            _ => ProgramHeader {
+                kind: read_u32(data, 0),
+                offset: read_u64(data, 8),
+                paddr: read_u64(data, 24),
+                filesz: read_u64(data, 32),
+                memsz: read_u64(data, 40),
+                align: read_u64(data, 48),
+            },
+        }
+    }
+
+    fn is_load(&self) -> bool {
+        self.kind == PT_LOAD && self.memsz != 0
+    }
+
+    // Where the segment is in the file, which has to have all of it
+    fn file_range(&self, file_size: u32) -> Result<(u32, usize), Error> {
+        match self.offset.checked_add(self.filesz) {
+            Some(end) if end <= u64::from(file_size) => {
+                Ok((self.offset as u32, self.filesz as usize))
+            }
+            _ => Err(Error::InvalidHeader),
+        }
+    }
+
+    // Where the segment goes in memory
+    fn memory_range(&self) -> Result<(u64, u64), Error> {
+        match self.paddr.checked_add(self.memsz) {
+            Some(end) if self.filesz <= self.memsz => Ok((self.paddr, end)),
+            _ => Err(Error::InvalidHeader),
+        }
+    }
+}
+
+// The file and program headers, as they were read from the file
+struct Headers {
+    file: FileHeader,
+    header: [u8; HEADER_SIZE],
+    data: [u8; MAX_PROGRAM_HEADERS * PROGRAM_HEADER_SIZE],
+}
+
+impl Headers {
+    fn read(f: &mut dyn Read) -> Result<Headers, Error> {
+        let file_size = f.get_size();
+        let mut header = [0; HEADER_SIZE];
+        if (file_size as usize) < HEADER_SIZE {
+            return Err(Error::MagicMissing);
+        }
+        f.read_at(0, &mut header)?;
+        let file = parse_header(&header)?;
+        let mut headers = Headers {
+            file,
+            header,
+            data: [0; MAX_PROGRAM_HEADERS * PROGRAM_HEADER_SIZE],
+        };
+        let size = headers.size();
+        if u64::from(headers.file.offset) + size as u64 > u64::from(file_size) {
+            return Err(Error::InvalidHeader);
+        }
+        f.read_at(headers.file.offset, &mut headers.data[..size])?;
+        Ok(headers)
+    }
+
+    fn size(&self) -> usize {
+        self.file.count * self.file.program_header_size()
+    }
+
+    fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + Clone + '_ {
+        let class = self.file.class;
+        self.data[..self.size()]
+            .chunks_exact(self.file.program_header_size())
+            .map(move |data| ProgramHeader::parse(data, class))
+    }
+
+    // Loads the segments at their physical addresses, returning the memory
+    // from the start of the lowest to the end of the highest and the SHA-256
+    // hash of the whole file as it was read. What else was read from the
+    // file has to read the same as it did.
+    fn load(
+        &self,
+        f: &mut dyn Read,
+        memory: &MemoryMap,
+        read: (u32, &[u8]),
+    ) -> Result<((u64, u64), [u8; 32]), Error> {
+        let file_size = f.get_size();
+        // Nothing is loaded until all of it is known to fit
+        let mut region: Option<(u64, u64)> = None;
+        for header in self.program_headers().filter(ProgramHeader::is_load) {
+            header.file_range(file_size)?;
+            let (start, end) = header.memory_range()?;
+            if !memory.usable((start, end)) {
+                return Err(Error::InvalidAddress);
+            }
+            region = Some(region.map_or((start, end), |r| (min(r.0, start), max(r.1, end))));
+        }
+        let region = region.ok_or(Error::NoSegments)?;
+
+        for header in self.program_headers().filter(ProgramHeader::is_load) {
+            MemoryRegion::new(header.paddr + header.filesz, header.memsz - header.filesz)
+                .as_bytes()
+                .fill(0);
+        }
+        // What is loaded is what is hashed, and the headers it was worked out
+        // from have to read the same as they did
+        let segments = self
+            .program_headers()
+            .filter(ProgramHeader::is_load)
+            .map(|h| (h.offset as u32, h.filesz as usize, h.paddr));
+        let read: [(u32, &[u8]); 3] = [
+            (0, &self.header),
+            (self.file.offset, &self.data[..self.size()]),
+            read,
+        ];
+        let digest = fat::load_parts(f, segments, &read)?;
+        Ok((region, digest))
+    }
+}
+
+// An i386 or x86-64 executable loaded by its program headers, as Multiboot2
+// kernels without the address tag are
+#[cfg(feature = "multiboot")]
+pub struct Executable {
+    pub entry: u64,
+    // The memory from the start of the lowest segment to the end of the
+    // highest
+    pub region: (u64, u64),
+    // The SHA-256 hash of the whole file as it was read
+    pub digest: [u8; 32],
+}
+
+// What else was read from the file has to read the same as it did
+#[cfg(feature = "multiboot")]
+pub fn load_executable(
+    f: &mut dyn Read,
+    memory: &MemoryMap,
+    read: (u32, &[u8]),
+) -> Result<Executable, Error> {
+    let headers = Headers::read(f)?;
+    let (region, digest) = headers.load(f, memory, read)?;
+    Ok(Executable {
+        entry: headers.file.entry,
+        region,
+        digest,
+    })
+}
+
+// The PVH entry point in a note segment. The name and description of each
+// note are padded to 4 bytes, or to 8 if the segment is 8 byte aligned.
+fn find_entry(notes: &[u8], align: usize) -> Option<u32> {
+    let pad = |size: usize| (size + align - 1) & !(align - 1);
+    let mut offset = 0;
+    while offset + 12 <= notes.len() {
+        let name_size = read_u32(notes, offset) as usize;
+        let desc_size = read_u32(notes, offset + 4) as usize;
+        let kind = read_u32(notes, offset + 8);
+        let name = offset + 12;
+        let desc = pad(name + name_size);
+        if desc + desc_size > notes.len() {
+            return None;
+        }
+        if &notes[name..name + name_size] == b"Xen\0" && kind == XEN_ELFNOTE_PHYS32_ENTRY {
+            return match desc_size {
+                4 => Some(read_u32(notes, desc)),
+                8 => u32::try_from(read_u64(notes, desc)).ok(),
+                _ => None,
+            };
+        }
+        offset = pad(desc + desc_size);
+    }
+    None
+}
+
+// The start info is kept with the firmware, which the kernel can't be loaded
+// over
+static mut BOOT_PARAMS: BootParams = BootParams::new();
+
+extern "sysv64" {
+    // In asm/start32.s
+    fn start32(entry: u64, ebx: u64, eax: u64) -> !;
+}
+
+pub struct Kernel {
+    entry: u32,
+    // The memory from the start of the lowest segment to the end of the
+    // highest
+    region: (u64, u64),
+    initrd: Option<(u64, u64)>,
+    cmdline: Cmdline,
+    rsdp_addr: u64,
+    memory: MemoryMap,
+}
+
+impl Kernel {
+    pub fn new(info: &dyn Info) -> Self {
+        Self {
+            entry: 0,
+            region: (0, 0),
+            initrd: None,
+            cmdline: Cmdline::new(),
+            rsdp_addr: info.rsdp_addr(),
+            memory: MemoryMap::new(info),
+        }
+    }
+
+    // Returns the SHA-256 hash of the whole file as it was read in loading it
+    pub fn load_kernel(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        let file_size = f.get_size();
+        let headers = Headers::read(f)?;
+
+        let mut entry = None;
+        let mut notes = [0; NOTES_SIZE];
+        let mut notes_read = (0, 0);
+        for header in headers.program_headers().filter(|h| h.kind == PT_NOTE) {
+            let (offset, size) = header.file_range(file_size)?;
+            let size = min(size, NOTES_SIZE);
+            f.read_at(offset, &mut notes[..size])?;
+            notes_read = (offset, size);
+            let align = if header.align == 8 { 8 } else { 4 };
+            entry = find_entry(&notes[..size], align);
+            if entry.is_some() {
+                break;
+            }
+        }
+        let entry = entry.ok_or(Error::NoEntry)?;
+
+        let (notes_offset, notes_size) = notes_read;
+        let (region, digest) =
+            headers.load(f, &self.memory, (notes_offset, &notes[..notes_size]))?;
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"kernel");
+
+        self.entry = entry;
+        self.region = region;
+        log!("Loaded ELF kernel at {:#x}", region.0);
+        Ok(digest)
+    }
+
+    // Returns the SHA-256 hash of the initrd as it was loaded
+    pub fn load_initrd(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        let size = f.get_size() as u64;
+        let addr = self
+            .memory
+            .highest_fit(size, self.region)
+            .ok_or(Error::NoInitrdMemory)?;
+
+        let mut region = MemoryRegion::new(addr, size);
+        f.seek(0)?;
+        f.load_file(&mut region)?;
+        let mut hash = Sha256::new();
+        hash.update(region.as_bytes());
+        let digest = hash.finish();
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"initrd");
+
+        self.initrd = Some((addr, addr + size));
+        log!("Loaded {} byte initrd at {:#x}", size, addr);
+        Ok(digest)
+    }
+
+    pub fn append_cmdline(&mut self, addition: &[u8]) {
+        self.cmdline.append(addition);
+    }
+
+    pub fn cmdline(&self) -> &[u8] {
+        self.cmdline.as_bytes()
+    }
+
+    pub fn boot(&mut self) {
+        self.cmdline.measure();
+
+        let start_info =
+            unsafe { BOOT_PARAMS.fill(&self.memory, &self.cmdline, self.rsdp_addr, self.initrd) };
+        unsafe { start32(u64::from(self.entry), start_info, 0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_entry, parse_header, Error, FileHeader, ProgramHeader};
+
+    fn header(class: u8, machine: u16, phnum: u16) -> Vec<u8> {
+        let mut data = vec![0; 64];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[4] = class;
+        data[5] = 1;
+        data[16..18].copy_from_slice(&2u16.to_le_bytes());
+        data[18..20].copy_from_slice(&machine.to_le_bytes());
+        if class == 1 {
+            data[24..28].copy_from_slice(&0x10_000cu32.to_le_bytes());
+            data[28..32].copy_from_slice(&52u32.to_le_bytes());
+            data[42..44].copy_from_slice(&32u16.to_le_bytes());
+            data[44..46].copy_from_slice(&phnum.to_le_bytes());
+        } else {
+            data[24..32].copy_from_slice(&0x100_0000u64.to_le_bytes());
+            data[32..40].copy_from_slice(&64u64.to_le_bytes());
+            data[54..56].copy_from_slice(&56u16.to_le_bytes());
+            data[56..58].copy_from_slice(&phnum.to_le_bytes());
+        }
+        data
+    }
+
+    fn note(name: &[u8], kind: u32, desc: &[u8], align: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        data.extend_from_slice(&kind.to_le_bytes());
+        data.extend_from_slice(name);
+        data.resize((data.len() + align - 1) & !(align - 1), 0);
+        data.extend_from_slice(desc);
+        data.resize((data.len() + align - 1) & !(align - 1), 0);
+        data
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header(&header(2, 62, 3)).unwrap(),
+            FileHeader {
+                class: 2,
+                entry: 0x100_0000,
+                offset: 64,
+                count: 3,
+            }
+        );
+        assert_eq!(
+            parse_header(&header(1, 3, 2)).unwrap(),
+            FileHeader {
+                class: 1,
+                entry: 0x10_000c,
+                offset: 52,
+                count: 2,
+            }
+        );
+        assert!(matches!(
+            parse_header(&header(1, 62, 3)),
+            Err(Error::NotSupported)
+        ));
+        assert!(matches!(
+            parse_header(&header(2, 3, 3)),
+            Err(Error::NotSupported)
+        ));
+        assert!(matches!(
+            parse_header(&header(2, 183, 3)),
+            Err(Error::NotSupported)
+        ));
+        assert!(matches!(
+            parse_header(&header(2, 62, 33)),
+            Err(Error::InvalidHeader)
+        ));
+        assert!(matches!(parse_header(b"MZ\0\0"), Err(Error::MagicMissing)));
+    }
+
+    #[test]
+    fn test_program_header() {
+        let mut data = vec![0; 56];
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        data[16..24].copy_from_slice(&0xffff_ffff_8100_0000u64.to_le_bytes());
+        data[24..32].copy_from_slice(&0x100_0000u64.to_le_bytes());
+        data[32..40].copy_from_slice(&0x1000u64.to_le_bytes());
+        data[40..48].copy_from_slice(&0x3000u64.to_le_bytes());
+        data[48..56].copy_from_slice(&0x20_0000u64.to_le_bytes());
+        let header = ProgramHeader::parse(&data, 2);
+        assert_eq!(
+            header,
+            ProgramHeader {
+                kind: 1,
+                offset: 0x20_0000,
+                paddr: 0x100_0000,
+                filesz: 0x1000,
+                memsz: 0x3000,
+                align: 0x20_0000,
+            }
+        );
+        assert!(header.is_load());
+        assert_eq!(header.file_range(0x20_1000).unwrap(), (0x20_0000, 0x1000));
+        assert!(header.file_range(0x20_0fff).is_err());
+        assert_eq!(header.memory_range().unwrap(), (0x100_0000, 0x100_3000));
+
+        // What is in the file has to fit in memory
+        let header = ProgramHeader {
+            filesz: 0x4000,
+            ..header
+        };
+        assert!(header.memory_range().is_err());
+
+        // The same fields are 32-bit in a 32-bit file, p_flags moving
+        let mut data = vec![0; 32];
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data[4..8].copy_from_slice(&0x1000u32.to_le_bytes());
+        data[8..12].copy_from_slice(&0xc010_0000u32.to_le_bytes());
+        data[12..16].copy_from_slice(&0x10_0000u32.to_le_bytes());
+        data[16..20].copy_from_slice(&0x2000u32.to_le_bytes());
+        data[20..24].copy_from_slice(&0x5000u32.to_le_bytes());
+        data[24..28].copy_from_slice(&5u32.to_le_bytes());
+        data[28..32].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(
+            ProgramHeader::parse(&data, 1),
+            ProgramHeader {
+                kind: 1,
+                offset: 0x1000,
+                paddr: 0x10_0000,
+                filesz: 0x2000,
+                memsz: 0x5000,
+                align: 0x1000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_find_entry() {
+        // The way Linux has it, after other notes and with an 8 byte address
+        let mut notes = note(b"GNU\0", 3, &[0xcc; 20], 4);
+        notes.extend(note(b"Xen\0", 17, &[0xcc; 4], 4));
+        notes.extend(note(b"Xen\0", 18, &0x100_0190u64.to_le_bytes(), 4));
+        assert_eq!(find_entry(&notes, 4), Some(0x100_0190));
+
+        let mut notes = note(b"Linux\0", 18, &[0xcc; 4], 8);
+        notes.extend(note(b"Xen\0", 18, &0x20_0000u32.to_le_bytes(), 8));
+        assert_eq!(find_entry(&notes, 8), Some(0x20_0000));
+        assert_eq!(find_entry(&notes, 4), None);
+
+        // It has to be 32-bit
+        let notes = note(b"Xen\0", 18, &0x1_0000_0000u64.to_le_bytes(), 4);
+        assert_eq!(find_entry(&notes, 4), None);
+        assert_eq!(find_entry(&notes[..notes.len() - 1], 4), None);
+    }
+}
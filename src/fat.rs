@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{block::SectorRead, mem::MemoryRegion};
+use crate::{
+    block::{SectorRead, SectorWrite},
+    mem::MemoryRegion,
+    sha256::Sha256,
+};
 use core::convert::TryFrom;
 
 #[repr(packed)]
@@ -33,6 +37,18 @@ struct Header {
     sectors: u32,
 }
 
+// The extended header FAT12 and FAT16 have after the common one
+#[repr(packed)]
+struct Fat16Header {
+    _header: Header,
+    _drive_no: u8,
+    _nt_flags: u8,
+    signature: u8,
+    _serial: u32,
+    volume: [u8; 11],
+    _id: [u8; 8],
+}
+
 #[repr(packed)]
 struct Fat32Header {
     _header: Header,
@@ -45,9 +61,9 @@ struct Fat32Header {
     _reserved: [u8; 12],
     _drive_no: u8,
     _nt_flags: u8,
-    _signature: u8,
+    signature: u8,
     _serial: u32,
-    _volume: [u8; 11],
+    volume: [u8; 11],
     _id: [u8; 8],
 }
 
@@ -55,20 +71,92 @@ struct Fat32Header {
 struct FatDirectory {
     name: [u8; 11],
     flags: u8,
-    _unused1: [u8; 8],
+    _nt_reserved: u8,
+    create_time_tenths: u8,
+    create_time: u16,
+    create_date: u16,
+    access_date: u16,
     cluster_high: u16,
-    _unused2: [u8; 4],
+    modify_time: u16,
+    modify_date: u16,
     cluster_low: u16,
     size: u32,
 }
 
+// The attribute bits
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+// A time from a directory entry, which has no time zone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl Timestamp {
+    // The date has the years from 1980 in bits 9-15, the month in bits 5-8
+    // and the day in bits 0-4. The time has the hours in bits 11-15, the
+    // minutes in bits 5-10 and the seconds divided by 2 in bits 0-4, with the
+    // creation time also having the 10ms units in a byte of their own. A date
+    // of 0 means the time wasn't recorded.
+    fn from_fat(date: u16, time: u16, tenths: u8) -> Option<Timestamp> {
+        let timestamp = Timestamp {
+            year: 1980 + (date >> 9),
+            month: (date >> 5 & 0xf) as u8,
+            day: (date & 0x1f) as u8,
+            hour: (time >> 11) as u8,
+            minute: (time >> 5 & 0x3f) as u8,
+            second: (time & 0x1f) as u8 * 2 + tenths / 100,
+            nanosecond: u32::from(tenths % 100) * 10_000_000,
+        };
+        if !(1..=12).contains(&timestamp.month)
+            || timestamp.day == 0
+            || timestamp.hour > 23
+            || timestamp.minute > 59
+            || timestamp.second > 59
+            || tenths > 199
+        {
+            return None;
+        }
+        Some(timestamp)
+    }
+}
+
+// When a file was created, last modified and last accessed, where recorded.
+// Only the date of the last access is kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Times {
+    pub created: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+    pub accessed: Option<Timestamp>,
+}
+
+impl Times {
+    fn from_entry(d: &FatDirectory) -> Times {
+        Times {
+            created: Timestamp::from_fat(d.create_date, d.create_time, d.create_time_tenths),
+            modified: Timestamp::from_fat(d.modify_date, d.modify_time, 0),
+            accessed: Timestamp::from_fat(d.access_date, 0, 0),
+        }
+    }
+}
+
 #[repr(packed)]
 struct FatLongNameEntry {
     seq: u8,
     name: [u16; 5],
     _attr: u8,
     r#_type: u8,
-    _checksum: u8,
+    checksum: u8,
     name2: [u16; 6],
     _cluster: u16,
     name3: [u16; 2],
@@ -76,14 +164,70 @@ struct FatLongNameEntry {
 
 pub struct DirectoryEntry {
     name: [u8; 11],
-    long_name: [u8; 255],
+    long_name: [u16; 255],
     file_type: FileType,
+    attributes: u8,
     size: u32,
     cluster: u32,
+    times: Times,
 }
 
-#[derive(Debug, PartialEq)]
-enum FatType {
+impl DirectoryEntry {
+    // Returns the 8.3 name as "NAME.EXT", padded with zeros
+    pub fn short_name(&self) -> [u8; 12] {
+        let mut output = [0u8; 12];
+        let base = self.name[..8].iter().take_while(|c| **c != b' ');
+        let ext = self.name[8..].iter().take_while(|c| **c != b' ');
+        let mut len = 0;
+        for c in base {
+            output[len] = *c;
+            len += 1;
+        }
+        for (i, c) in ext.enumerate() {
+            if i == 0 {
+                output[len] = b'.';
+                len += 1;
+            }
+            output[len] = *c;
+            len += 1;
+        }
+        output
+    }
+
+    // Returns the UTF-16 name from the LFN entries, if there were any
+    pub fn long_name(&self) -> Option<&[u16]> {
+        let len = self
+            .long_name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or_else(|| self.long_name.len());
+        if len == 0 {
+            None
+        } else {
+            Some(&self.long_name[..len])
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    // Raw FAT attribute byte (read-only, hidden, system, directory, archive)
+    pub fn attributes(&self) -> u8 {
+        self.attributes
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn times(&self) -> Times {
+        self.times
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
     Unknown,
     FAT12,
     FAT16,
@@ -92,12 +236,13 @@ enum FatType {
 
 pub struct Filesystem<'a> {
     device: &'a dyn SectorRead,
+    // Only present when the filesystem may be written to
+    writer: Option<&'a dyn SectorWrite>,
     start: u64,
     last: u64,
     bytes_per_sector: u32,
     sectors: u32,
     fat_type: FatType,
-    clusters: u32,
     sectors_per_fat: u32,
     sectors_per_cluster: u32,
     fat_count: u32,
@@ -105,9 +250,10 @@ pub struct Filesystem<'a> {
     first_fat_sector: u32,
     first_data_sector: u32,
     data_sector_count: u32,
-    #[allow(unused)]
     data_cluster_count: u32,
     root_cluster: u32, // FAT32 only
+    // Padded with spaces, and all spaces when there isn't one
+    volume_label: [u8; 11],
 }
 
 #[derive(Debug, PartialEq)]
@@ -117,6 +263,14 @@ pub enum Error {
     NotFound,
     EndOfFile,
     InvalidOffset,
+    ReadOnly,
+    // The disk ends before the filesystem or a file on it does
+    Truncated,
+    BadSignature,
+    // A cluster or sector number that isn't in the filesystem
+    OutOfRange,
+    // The file didn't read the same the second time
+    Changed,
 }
 
 #[derive(Debug, PartialEq)]
@@ -171,6 +325,8 @@ pub struct File<'a> {
     sector_offset: u64,
     size: u32,
     position: u32,
+    attributes: u8,
+    times: Times,
 }
 
 #[derive(Copy, Clone)]
@@ -181,17 +337,36 @@ pub struct Directory<'a> {
     offset: usize,
 }
 
-fn ucs2_to_ascii(input: &[u16]) -> [u8; 255] {
-    let mut output: [u8; 255] = [0; 255];
-    let mut i: usize = 0;
-    while i < output.len() {
-        output[i] = (input[i] & 0xffu16) as u8;
-        if output[i] == 0 {
-            break;
+pub struct DirIterator<'a> {
+    directory: Directory<'a>,
+    done: bool,
+}
+
+impl<'a> Iterator for DirIterator<'a> {
+    type Item = Result<DirectoryEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.directory.next_entry() {
+            Ok(de) => Some(Ok(de)),
+            Err(Error::EndOfFile) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
         }
-        i += 1;
     }
-    output
+}
+
+// Checksum of the 8.3 name that each LFN entry records for its short entry
+fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, c| (sum >> 1 | sum << 7).wrapping_add(*c))
 }
 
 pub fn is_absolute_path(path: &str) -> bool {
@@ -201,33 +376,50 @@ pub fn is_absolute_path(path: &str) -> bool {
     false
 }
 
-fn name_to_str(input: &str, output: &mut [u8]) {
-    let pat: &[_] = &[' ', '\0'];
-    let input = input.trim_matches(pat);
-    let len = crate::common::ascii_length(input);
-    assert!(len <= output.len());
-    if input == "." || input == ".." || len > 12 {
-        output[..len].clone_from_slice(input.as_bytes());
-        return;
-    }
-
-    let mut i = 0;
-    for b in output.iter_mut() {
-        *b = match input.as_bytes()[i] {
-            b'\0' => break,
-            b' ' => {
-                i = 8;
-                b'.'
+// Rewrites the path with "/" between its components, leaving out any that
+// are empty or "." and going back a component for each "..". An absolute
+// path can't go above the root, but a relative one can start with ".." to be
+// resolved against the directory it is relative to.
+fn normalize_path<'b>(path: &str, buffer: &'b mut [u8; 256]) -> Result<&'b str, Error> {
+    let path = &path[..crate::common::ascii_length(path)];
+    // Each component is stored with the "/" before it
+    let mut length = 0;
+    // Where the components that ".." can go back over start
+    let mut base = 0;
+    for component in path.split(|c| c == '/' || c == '\\') {
+        match component {
+            "" | "." => {}
+            ".." if length > base => {
+                length = buffer[base..length]
+                    .iter()
+                    .rposition(|c| *c == b'/')
+                    .map_or(base, |i| base + i);
             }
-            c => {
-                i += 1;
-                c
+            ".." if is_absolute_path(path) => return Err(Error::NotFound),
+            _ => {
+                if length + 1 + component.len() > buffer.len() {
+                    return Err(Error::NotFound);
+                }
+                buffer[length] = b'/';
+                buffer[length + 1..length + 1 + component.len()]
+                    .copy_from_slice(component.as_bytes());
+                length += 1 + component.len();
+                if component == ".." {
+                    base = length;
+                }
             }
-        };
-        if i >= len {
-            break;
         }
     }
+    let normalized = if is_absolute_path(path) {
+        if length == 0 {
+            buffer[0] = b'/';
+            length = 1;
+        }
+        &buffer[..length]
+    } else {
+        &buffer[length.min(1)..length]
+    };
+    Ok(core::str::from_utf8(normalized).unwrap())
 }
 
 impl<'a> Read for Node<'a> {
@@ -255,6 +447,9 @@ impl<'a> Directory<'a> {
     // Returns and then increments to point to the next one, may return EndOfFile if this is the last entry
     pub fn next_entry(&mut self) -> Result<DirectoryEntry, Error> {
         let mut long_entry = [0u16; 260];
+        // Checksum and sequence number expected for the next LFN entry, None
+        // if there is no valid sequence in progress.
+        let mut lfn_state: Option<(u8, u8)> = None;
         loop {
             let sector = if self.cluster.is_some() {
                 if self.sector >= self.filesystem.sectors_per_cluster {
@@ -272,8 +467,12 @@ impl<'a> Directory<'a> {
                 self.sector
                     + self
                         .filesystem
-                        .first_sector_of_cluster(self.cluster.unwrap())
+                        .first_sector_of_cluster(self.cluster.unwrap())?
             } else {
+                // The fixed FAT12/FAT16 root directory ends where the data starts
+                if self.sector >= self.filesystem.first_data_sector {
+                    return Err(Error::EndOfFile);
+                }
                 self.sector
             };
 
@@ -299,13 +498,28 @@ impl<'a> Directory<'a> {
                 }
                 // Directory unused
                 if d.name[0] == 0xe5 {
+                    lfn_state = None;
                     continue;
                 }
                 // LFN entry
                 if d.flags == 0x0f {
                     // DOS starts sequences as 1. LFN entries come in reverse order before
-                    // actual entry so populate the slice using the sequence.
-                    let lfn_seq = ((lfns[i].seq & 0x1f) as usize) - 1;
+                    // actual entry so populate the slice using the sequence. The first
+                    // entry of a sequence has 0x40 set to mark it as the last part.
+                    let seq = lfns[i].seq & 0x1f;
+                    let checksum = lfns[i].checksum;
+                    if lfns[i].seq & 0x40 == 0x40 {
+                        long_entry = [0u16; 260];
+                        lfn_state = Some((checksum, seq));
+                    }
+                    if seq == 0 || seq > 20 || lfn_state != Some((checksum, seq)) {
+                        // Orphaned or corrupt entry so discard the sequence
+                        lfn_state = None;
+                        continue;
+                    }
+                    lfn_state = Some((checksum, seq - 1));
+
+                    let lfn_seq = (seq as usize) - 1;
                     let lfn_block = &mut long_entry[lfn_seq * 13..(lfn_seq + 1) * 13];
 
                     // Need explicit copy to avoid borrowing packed structure
@@ -323,17 +537,31 @@ impl<'a> Directory<'a> {
 
                     continue;
                 }
+                // Volume label
+                if d.flags & 0x08 == 0x08 {
+                    lfn_state = None;
+                    continue;
+                }
+
+                // The long name only belongs to this entry if the sequence
+                // completed and was written for this short name.
+                let mut long_name = [0u16; 255];
+                if lfn_state == Some((lfn_checksum(&d.name), 0)) {
+                    long_name.copy_from_slice(&long_entry[..255]);
+                }
 
                 let entry = DirectoryEntry {
                     name: d.name,
-                    file_type: if d.flags & 0x10 == 0x10 {
+                    file_type: if d.flags & ATTR_DIRECTORY == ATTR_DIRECTORY {
                         FileType::Directory
                     } else {
                         FileType::File
                     },
+                    attributes: d.flags,
                     cluster: (u32::from(d.cluster_high)) << 16 | u32::from(d.cluster_low),
                     size: d.size,
-                    long_name: ucs2_to_ascii(&long_entry[..]),
+                    long_name,
+                    times: Times::from_entry(d),
                 };
 
                 self.offset = i + 1;
@@ -344,28 +572,8 @@ impl<'a> Directory<'a> {
         }
     }
 
-    pub fn next_node(&mut self) -> Result<(Node, [u8; 11]), Error> {
-        let de = self.next_entry()?;
-        let mut name = [0_u8; 11];
-        name_to_str(core::str::from_utf8(&de.name).unwrap(), &mut name);
-
-        match de.file_type {
-            FileType::Directory => Ok((
-                self.filesystem.get_directory(de.cluster).unwrap().into(),
-                name,
-            )),
-            FileType::File => Ok((
-                self.filesystem
-                    .get_file(de.cluster, de.size)
-                    .unwrap()
-                    .into(),
-                name,
-            )),
-        }
-    }
-
     pub fn open(&self, path: &str) -> Result<Node, Error> {
-        let root = self.filesystem.root().unwrap();
+        let root = self.filesystem.root()?;
         let dir = if is_absolute_path(path) { &root } else { self };
         self.filesystem.open_from(dir, path)
     }
@@ -379,6 +587,12 @@ impl<'a> Directory<'a> {
     }
 }
 
+pub trait Write {
+    // Overwrites the sector at the current position, without changing the
+    // size of the file
+    fn write(&mut self, data: &mut [u8]) -> Result<u32, Error>;
+}
+
 pub trait Read {
     fn read(&mut self, data: &mut [u8]) -> Result<u32, Error>;
     fn seek(&mut self, offset: u32) -> Result<(), Error>;
@@ -401,6 +615,95 @@ pub trait Read {
         last.copy_from_slice(&dst[..bytes]);
         Ok(())
     }
+
+    // Reads the file from the offset into data. Files can only seek to the
+    // start of a sector, so the sector the offset is in is read from its start.
+    fn read_at(&mut self, offset: u32, data: &mut [u8]) -> Result<(), Error> {
+        let mut sector = [0; 512];
+        self.seek(offset & !511)?;
+        let mut skip = (offset % 512) as usize;
+        let mut done = 0;
+        while done < data.len() {
+            self.read(&mut sector)?;
+            let length = core::cmp::min(512 - skip, data.len() - done);
+            data[done..done + length].copy_from_slice(&sector[skip..skip + length]);
+            done += length;
+            skip = 0;
+        }
+        Ok(())
+    }
+
+    // Reads all of the file once, from the start, handing each sector to the
+    // function with its offset. The hash returned is of all of it, so it is of
+    // what was actually read.
+    fn read_all(&mut self, visit: &mut dyn FnMut(u32, &[u8])) -> Result<[u8; 32], Error> {
+        let mut hash = Sha256::new();
+        // Empty files can't be seeked in
+        if self.get_size() == 0 {
+            return Ok(hash.finish());
+        }
+        let mut sector = [0; 512];
+        let mut position = 0;
+        self.seek(0)?;
+        while position < self.get_size() {
+            let bytes = self.read(&mut sector)?;
+            hash.update(&sector[..bytes as usize]);
+            visit(position, &sector[..bytes as usize]);
+            position += bytes;
+        }
+        Ok(hash.finish())
+    }
+}
+
+// Where the bytes at the position in a file overlap the range of the file at
+// the offset, as the range of those bytes and where it starts in the other
+fn overlap(
+    position: u32,
+    bytes: usize,
+    offset: u32,
+    length: usize,
+) -> Option<(core::ops::Range<usize>, usize)> {
+    let start = core::cmp::max(u64::from(position), u64::from(offset));
+    let end = core::cmp::min(
+        u64::from(position) + bytes as u64,
+        u64::from(offset) + length as u64,
+    );
+    if start >= end {
+        return None;
+    }
+    let from = (start - u64::from(position)) as usize;
+    let to = (end - u64::from(position)) as usize;
+    Some((from..to, (start - u64::from(offset)) as usize))
+}
+
+// Loads the parts of the file, given as their offset and length in it and the
+// address they go at, reading all of the file once and checking that what was
+// read of it before, to work out where they go, reads the same again. The
+// hash returned is of the whole file as it was read.
+pub fn load_parts(
+    f: &mut dyn Read,
+    parts: impl Iterator<Item = (u32, usize, u64)> + Clone,
+    read: &[(u32, &[u8])],
+) -> Result<[u8; 32], Error> {
+    let mut changed = false;
+    let digest = f.read_all(&mut |position, bytes| {
+        for (offset, length, address) in parts.clone() {
+            if let Some((range, start)) = overlap(position, bytes.len(), offset, length) {
+                MemoryRegion::new(address + start as u64, range.len() as u64)
+                    .as_bytes()
+                    .copy_from_slice(&bytes[range]);
+            }
+        }
+        for (offset, data) in read {
+            if let Some((range, start)) = overlap(position, bytes.len(), *offset, data.len()) {
+                changed |= bytes[range.clone()] != data[start..start + range.len()];
+            }
+        }
+    })?;
+    if changed {
+        return Err(Error::Changed);
+    }
+    Ok(digest)
 }
 
 impl<'a> Read for File<'a> {
@@ -423,7 +726,9 @@ impl<'a> Read for File<'a> {
             }
         }
 
-        let cluster_start = self.filesystem.first_sector_of_cluster(self.active_cluster);
+        let cluster_start = self
+            .filesystem
+            .first_sector_of_cluster(self.active_cluster)?;
 
         match self
             .filesystem
@@ -453,36 +758,150 @@ impl<'a> Read for File<'a> {
             return Err(Error::EndOfFile);
         }
 
-        // Beyond, reset to zero and come back
-        if position < self.position {
-            self.position = 0;
-            self.sector_offset = 0;
-            self.active_cluster = self.start_cluster;
-        }
+        // Which cluster of the file we're in now, where a sector_offset of
+        // sectors_per_cluster is the end of it before moving on to the next
+        let sectors_per_cluster = u64::from(self.filesystem.sectors_per_cluster);
+        let sector = u64::from((self.position + 511) / 512);
+        let mut index = (sector - self.sector_offset) / sectors_per_cluster;
+        let mut cluster = self.active_cluster;
 
-        // Like read but without reading, follow cluster chain if we reach end of cluster
-        while self.position != position {
-            if self.sector_offset == u64::from(self.filesystem.sectors_per_cluster) {
-                match self.filesystem.next_cluster(self.active_cluster) {
-                    Err(e) => {
-                        return Err(e);
-                    }
-                    Ok(cluster) => {
-                        self.active_cluster = cluster;
-                        self.sector_offset = 0;
-                    }
-                }
-            }
+        let target = u64::from(position / 512);
+        let target_index = target / sectors_per_cluster;
 
-            self.sector_offset += 1;
-            self.position += 512;
+        // Behind the current cluster, start again from the first one
+        if target_index < index {
+            index = 0;
+            cluster = self.start_cluster;
         }
 
+        // Only follow the cluster chain for the clusters in between
+        while index < target_index {
+            cluster = self.filesystem.next_cluster(cluster)?;
+            index += 1;
+        }
+
+        self.active_cluster = cluster;
+        self.sector_offset = target % sectors_per_cluster;
+        self.position = position;
         Ok(())
     }
+
     fn get_size(&self) -> u32 {
         self.size
     }
+
+    // Loads the remainder of the file with as few requests as the cluster
+    // chain allows
+    fn load_file(&mut self, mem: &mut MemoryRegion) -> Result<(), Error> {
+        let dst = mem.as_bytes();
+        let whole = dst.len() - dst.len() % 512;
+        let mut offset = 0;
+        while offset < whole {
+            offset += self.read_contiguous(&mut dst[offset..whole])? as usize;
+        }
+        let last = &mut dst[whole..];
+        if last.is_empty() {
+            return Ok(());
+        }
+        // Use tmp buffer for last, partial sector
+        let mut tmp = [0; 512];
+        let bytes = self.read(&mut tmp)? as usize;
+        assert_eq!(bytes, last.len());
+        last.copy_from_slice(&tmp[..bytes]);
+        Ok(())
+    }
+}
+
+impl<'a> Write for File<'a> {
+    fn write(&mut self, data: &mut [u8]) -> Result<u32, Error> {
+        assert_eq!(data.len(), 512);
+
+        if self.position >= self.size {
+            return Err(Error::EndOfFile);
+        }
+
+        if self.sector_offset == u64::from(self.filesystem.sectors_per_cluster) {
+            self.active_cluster = self.filesystem.next_cluster(self.active_cluster)?;
+            self.sector_offset = 0;
+        }
+
+        let cluster_start = self
+            .filesystem
+            .first_sector_of_cluster(self.active_cluster)?;
+
+        match SectorWrite::write(
+            self.filesystem,
+            u64::from(cluster_start) + self.sector_offset,
+            data,
+        ) {
+            Err(crate::block::Error::BlockNotSupported)
+            | Err(crate::block::Error::BlockReadOnly) => Err(Error::ReadOnly),
+            Err(_) => Err(Error::BlockError),
+            Ok(()) => {
+                self.sector_offset += 1;
+                let bytes_written = core::cmp::min(512, self.size - self.position);
+                self.position += bytes_written;
+                Ok(bytes_written)
+            }
+        }
+    }
+}
+
+impl<'a> File<'a> {
+    // Raw FAT attribute byte, as for DirectoryEntry
+    pub fn attributes(&self) -> u8 {
+        self.attributes
+    }
+
+    pub fn times(&self) -> Times {
+        self.times
+    }
+
+    // Reads whole sectors into data in a single request, stopping early at
+    // the end of the file or where the cluster chain stops being contiguous.
+    fn read_contiguous(&mut self, data: &mut [u8]) -> Result<u32, Error> {
+        assert_eq!(data.len() % 512, 0);
+
+        if self.position >= self.size {
+            return Err(Error::EndOfFile);
+        }
+
+        let sectors_per_cluster = u64::from(self.filesystem.sectors_per_cluster);
+        if self.sector_offset == sectors_per_cluster {
+            self.active_cluster = self.filesystem.next_cluster(self.active_cluster)?;
+            self.sector_offset = 0;
+        }
+
+        let start = u64::from(
+            self.filesystem
+                .first_sector_of_cluster(self.active_cluster)?,
+        ) + self.sector_offset;
+        let remaining = u64::from((self.size - self.position + 511) / 512);
+        let wanted = core::cmp::min((data.len() / 512) as u64, remaining);
+
+        let mut sectors = core::cmp::min(sectors_per_cluster - self.sector_offset, wanted);
+        self.sector_offset += sectors;
+        while sectors < wanted {
+            match self.filesystem.next_cluster(self.active_cluster) {
+                Ok(cluster) if cluster == self.active_cluster + 1 => {
+                    self.active_cluster = cluster;
+                    self.sector_offset = core::cmp::min(sectors_per_cluster, wanted - sectors);
+                    sectors += self.sector_offset;
+                }
+                Ok(_) | Err(Error::EndOfFile) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let len = sectors as usize * 512;
+        if self.filesystem.read_multi(start, &mut data[..len]).is_err() {
+            return Err(Error::BlockError);
+        }
+
+        let bytes = core::cmp::min(len as u32, self.size - self.position);
+        self.position += bytes;
+        Ok(bytes)
+    }
 }
 
 impl<'a> SectorRead for Filesystem<'a> {
@@ -493,6 +912,35 @@ impl<'a> SectorRead for Filesystem<'a> {
             self.device.read(self.start + sector, data)
         }
     }
+
+    fn read_multi(&self, sector: u64, data: &mut [u8]) -> Result<(), crate::block::Error> {
+        if self.start + sector + (data.len() / 512) as u64 > self.last + 1 {
+            Err(crate::block::Error::BlockIOError)
+        } else {
+            self.device.read_multi(self.start + sector, data)
+        }
+    }
+
+    fn block_size(&self) -> u32 {
+        self.device.block_size()
+    }
+}
+
+impl<'a> SectorWrite for Filesystem<'a> {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), crate::block::Error> {
+        match self.writer {
+            None => Err(crate::block::Error::BlockNotSupported),
+            Some(_) if self.start + sector > self.last => Err(crate::block::Error::BlockIOError),
+            Some(writer) => writer.write(self.start + sector, data),
+        }
+    }
+
+    fn flush(&self) -> Result<(), crate::block::Error> {
+        match self.writer {
+            None => Err(crate::block::Error::BlockNotSupported),
+            Some(writer) => writer.flush(),
+        }
+    }
 }
 
 // Do a case-insensitive match on the name with the 8.3 format that you get from FAT.
@@ -504,6 +952,12 @@ fn compare_short_name(name: &str, de: &DirectoryEntry) -> bool {
         return false;
     }
 
+    let mut short_name = [b' '; 11];
+    if name == "." || name == ".." {
+        short_name[..name.len()].copy_from_slice(name.as_bytes());
+        return short_name == de.name;
+    }
+
     let mut i = 0;
     for a in name.as_bytes().iter() {
         // Handle cases which are 11 long but not 8.3 (e.g "loader.conf")
@@ -511,40 +965,55 @@ fn compare_short_name(name: &str, de: &DirectoryEntry) -> bool {
             return false;
         }
 
-        if *a == b'\0' {
-            break;
-        }
-
         // Jump to the extension
         if *a == b'.' {
+            if i > 8 {
+                return false;
+            }
             i = 8;
             continue;
         }
 
-        let b = de.name[i];
-        if a.to_ascii_uppercase() != b.to_ascii_uppercase() {
-            return false;
-        }
-
+        short_name[i] = *a;
         i += 1;
     }
-    true
+    short_name.eq_ignore_ascii_case(&de.name)
+}
+
+// Do a case-insensitive match on the name with the UTF-16 long name from the
+// LFN entries, if there was one.
+fn compare_long_name(name: &str, de: &DirectoryEntry) -> bool {
+    let name = name.trim_matches(char::from(0));
+    let long_name = match de.long_name() {
+        Some(long_name) => long_name,
+        None => return false,
+    };
+
+    let mut long_name = core::char::decode_utf16(long_name.iter().copied());
+    let mut name = name.chars();
+    loop {
+        match (name.next(), long_name.next()) {
+            (None, None) => return true,
+            (Some(a), Some(Ok(b))) if a.eq_ignore_ascii_case(&b) => {}
+            _ => return false,
+        }
+    }
 }
 
 fn compare_name(name: &str, de: &DirectoryEntry) -> bool {
-    compare_short_name(name, de) || &de.long_name[0..name.len()] == name.as_bytes()
+    compare_long_name(name, de) || compare_short_name(name, de)
 }
 
 impl<'a> Filesystem<'a> {
     pub fn new(device: &'a dyn SectorRead, start: u64, last: u64) -> Filesystem {
         Filesystem {
             device,
+            writer: None,
             start,
             last,
             bytes_per_sector: 0,
             sectors: 0,
             fat_type: FatType::Unknown,
-            clusters: 0,
             sectors_per_fat: 0,
             sectors_per_cluster: 0,
             fat_count: 0,
@@ -554,10 +1023,37 @@ impl<'a> Filesystem<'a> {
             data_sector_count: 0,
             data_cluster_count: 0,
             root_cluster: 0,
+            volume_label: [b' '; 11],
+        }
+    }
+
+    // Allows existing files to be overwritten through the given device, which
+    // must be the same one that is read from
+    pub fn set_writer(&mut self, writer: &'a dyn SectorWrite) {
+        self.writer = Some(writer);
+    }
+
+    // The first sector of the filesystem on the device
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    // The label in the boot sector, if it has one
+    pub fn volume_label(&self) -> Option<&str> {
+        let label = core::str::from_utf8(&self.volume_label).ok()?.trim_end();
+        if label.is_empty() {
+            None
+        } else {
+            Some(label)
         }
     }
 
     pub fn init(&mut self) -> Result<(), Error> {
+        // Cluster count thresholds that define the FAT type
         const FAT12_MAX: u32 = 0xff5;
         const FAT16_MAX: u32 = 0xfff5;
 
@@ -567,23 +1063,73 @@ impl<'a> Filesystem<'a> {
             Err(_) => return Err(Error::BlockError),
         };
 
+        if data[510..512] != [0x55, 0xaa] {
+            return Err(Error::BadSignature);
+        }
+
         let h = unsafe { &*(data.as_ptr() as *const Header) };
 
         self.bytes_per_sector = u32::from(h.bytes_per_sector);
         self.fat_count = u32::from(h.fat_count);
         self.sectors_per_cluster = u32::from(h.sectors_per_cluster);
 
-        self.sectors = if h.legacy_sectors == 0 {
+        // The filesystem's sectors can't be smaller than the disk's blocks
+        if !self.bytes_per_sector.is_power_of_two()
+            || self.bytes_per_sector < core::cmp::max(512, self.device.block_size())
+            || self.bytes_per_sector > 4096
+            || self.sectors_per_cluster == 0
+        {
+            return Err(Error::Unsupported);
+        }
+
+        // Everything is counted in 512 byte sectors from here on, however
+        // big the filesystem's are
+        let scale = self.bytes_per_sector / 512;
+        let to_sectors = |count: u32| count.checked_mul(scale).ok_or(Error::OutOfRange);
+
+        self.sectors_per_cluster = to_sectors(self.sectors_per_cluster)?;
+        self.sectors = to_sectors(if h.legacy_sectors == 0 {
             h.sectors
         } else {
             u32::from(h.legacy_sectors)
-        };
+        })?;
 
-        self.clusters = self.sectors / u32::from(h.sectors_per_cluster);
+        // FAT32 has no fixed root directory and keeps its FAT size in the
+        // extended header, so these are zero there.
+        self.root_dir_sectors = to_sectors(
+            ((u32::from(h.root_dir_count) * 32) + self.bytes_per_sector - 1)
+                / self.bytes_per_sector,
+        )?;
+
+        self.sectors_per_fat = to_sectors(if h.legacy_sectors_per_fat == 0 {
+            let h32 = unsafe { &*(data.as_ptr() as *const Fat32Header) };
+            h32.sectors_per_fat
+        } else {
+            u32::from(h.legacy_sectors_per_fat)
+        })?;
+
+        // All of the filesystem has to be on the disk or partition
+        if self.start + u64::from(self.sectors) > self.last + 1 {
+            return Err(Error::Truncated);
+        }
 
-        self.fat_type = if self.clusters < FAT12_MAX {
+        self.first_fat_sector = to_sectors(u32::from(h.reserved_sectors))?;
+        self.first_data_sector = self
+            .fat_count
+            .checked_mul(self.sectors_per_fat)
+            .and_then(|s| s.checked_add(self.first_fat_sector))
+            .and_then(|s| s.checked_add(self.root_dir_sectors))
+            .ok_or(Error::OutOfRange)?;
+        if self.first_data_sector >= self.sectors {
+            return Err(Error::Unsupported);
+        }
+        self.data_sector_count = self.sectors - self.first_data_sector;
+        self.data_cluster_count = self.data_sector_count / self.sectors_per_cluster;
+
+        // The FAT type is determined solely by the count of data clusters
+        self.fat_type = if self.data_cluster_count < FAT12_MAX {
             FatType::FAT12
-        } else if self.clusters < FAT16_MAX {
+        } else if self.data_cluster_count < FAT16_MAX {
             FatType::FAT16
         } else {
             FatType::FAT32
@@ -591,43 +1137,53 @@ impl<'a> Filesystem<'a> {
 
         if self.fat_type == FatType::FAT32 {
             let h32 = unsafe { &*(data.as_ptr() as *const Fat32Header) };
-            self.sectors_per_fat = h32.sectors_per_fat;
             self.root_cluster = h32.root_cluster;
-        } else {
-            self.sectors_per_fat = u32::from(h.legacy_sectors_per_fat);
+            if !self.is_data_cluster(self.root_cluster) {
+                return Err(Error::OutOfRange);
+            }
         }
 
-        if self.fat_type == FatType::FAT12 || self.fat_type == FatType::FAT16 {
-            self.root_dir_sectors = ((u32::from(h.root_dir_count * 32)) + self.bytes_per_sector
-                - 1)
-                / self.bytes_per_sector;
+        // The label is only there with the 0x29 extended boot signature
+        let (signature, volume) = if self.fat_type == FatType::FAT32 {
+            let h32 = unsafe { &*(data.as_ptr() as *const Fat32Header) };
+            (h32.signature, h32.volume)
+        } else {
+            let h16 = unsafe { &*(data.as_ptr() as *const Fat16Header) };
+            (h16.signature, h16.volume)
+        };
+        if signature == 0x29 {
+            self.volume_label = volume;
         }
 
-        self.first_fat_sector = u32::from(h.reserved_sectors);
-        self.first_data_sector =
-            self.first_fat_sector + (self.fat_count * self.sectors_per_fat) + self.root_dir_sectors;
-        self.data_sector_count = self.sectors - self.first_data_sector;
-        self.data_cluster_count = self.data_sector_count / self.bytes_per_sector;
-
         Ok(())
     }
 
     fn next_cluster(&self, cluster: u32) -> Result<u32, Error> {
+        if !self.is_data_cluster(cluster) {
+            return Err(Error::OutOfRange);
+        }
         match self.fat_type {
             FatType::FAT12 => {
-                let mut data: [u8; 512] = [0; 512];
+                let mut data: [u8; 1024] = [0; 1024];
 
                 let fat_offset = cluster + (cluster / 2); // equivalent of x 1.5
-                let fat_sector = self.first_fat_sector + (fat_offset / self.bytes_per_sector);
-                let offset = fat_offset % self.bytes_per_sector;
+                let fat_sector = self.first_fat_sector + (fat_offset / 512);
+                let offset = (fat_offset % 512) as usize;
 
-                match self.read(u64::from(fat_sector), &mut data) {
+                match self.read(u64::from(fat_sector), &mut data[..512]) {
                     Ok(_) => {}
                     Err(_) => return Err(Error::BlockError),
                 };
 
-                let next_cluster_raw =
-                    unsafe { *((data.as_ptr() as u64 + u64::from(offset)) as *const u16) };
+                // 12-bit entries can straddle a sector boundary
+                if offset == 511 {
+                    match self.read(u64::from(fat_sector) + 1, &mut data[512..]) {
+                        Ok(_) => {}
+                        Err(_) => return Err(Error::BlockError),
+                    };
+                }
+
+                let next_cluster_raw = u16::from_le_bytes([data[offset], data[offset + 1]]);
 
                 let next_cluster = if cluster % 2 == 0 {
                     next_cluster_raw & 0xfff
@@ -644,8 +1200,8 @@ impl<'a> Filesystem<'a> {
                 let fat: [u16; 512 / 2] = [0; 512 / 2];
 
                 let fat_offset = cluster * 2;
-                let fat_sector = self.first_fat_sector + (fat_offset / self.bytes_per_sector);
-                let offset = fat_offset % self.bytes_per_sector;
+                let fat_sector = self.first_fat_sector + (fat_offset / 512);
+                let offset = fat_offset % 512;
 
                 let data = unsafe { core::slice::from_raw_parts_mut(fat.as_ptr() as *mut u8, 512) };
                 match self.read(u64::from(fat_sector), data) {
@@ -665,8 +1221,8 @@ impl<'a> Filesystem<'a> {
                 let fat: [u32; 512 / 4] = [0; 512 / 4];
 
                 let fat_offset = cluster * 4;
-                let fat_sector = self.first_fat_sector + (fat_offset / self.bytes_per_sector);
-                let offset = fat_offset % self.bytes_per_sector;
+                let fat_sector = self.first_fat_sector + (fat_offset / 512);
+                let offset = fat_offset % 512;
 
                 let data = unsafe { core::slice::from_raw_parts_mut(fat.as_ptr() as *mut u8, 512) };
 
@@ -688,8 +1244,16 @@ impl<'a> Filesystem<'a> {
         }
     }
 
-    fn first_sector_of_cluster(&self, cluster: u32) -> u32 {
-        ((cluster - 2) * self.sectors_per_cluster) + self.first_data_sector
+    // Data clusters are numbered from 2
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.data_cluster_count
+    }
+
+    fn first_sector_of_cluster(&self, cluster: u32) -> Result<u32, Error> {
+        if !self.is_data_cluster(cluster) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(((cluster - 2) * self.sectors_per_cluster) + self.first_data_sector)
     }
 
     pub fn root(&self) -> Result<Directory, Error> {
@@ -713,14 +1277,16 @@ impl<'a> Filesystem<'a> {
         }
     }
 
-    fn get_file(&self, cluster: u32, size: u32) -> Result<File, Error> {
+    fn get_file(&self, de: &DirectoryEntry) -> Result<File, Error> {
         Ok(File {
             filesystem: self,
-            start_cluster: cluster,
-            active_cluster: cluster,
+            start_cluster: de.cluster,
+            active_cluster: de.cluster,
             sector_offset: 0,
-            size,
+            size: de.size,
             position: 0,
+            attributes: de.attributes,
+            times: de.times,
         })
     }
 
@@ -736,79 +1302,362 @@ impl<'a> Filesystem<'a> {
     pub fn open(&self, path: &str) -> Result<Node, Error> {
         // path must be absolute path
         assert!(is_absolute_path(path));
-        self.open_from(&self.root().unwrap(), path)
+        self.open_from(&self.root()?, path)
     }
 
-    fn open_from(&self, from: &Directory, path: &str) -> Result<Node, Error> {
-        let len = crate::common::ascii_length(path);
-        assert!(len < 256);
-        let mut p = [0_u8; 256];
-        let mut residual = if !is_absolute_path(path) {
-            p[0] = b'/';
-            p[1..1 + len].clone_from_slice(path[..len].as_bytes());
-            core::str::from_utf8(&p).unwrap()
+    // Iterates over the entries of the directory at the absolute path
+    #[cfg_attr(not(any(feature = "boot-menu", test)), allow(dead_code))]
+    pub fn read_dir(&self, path: &str) -> Result<DirIterator, Error> {
+        let directory = if path.trim_matches(|c| c == '/' || c == '\\').is_empty() {
+            self.root()?
         } else {
-            path
+            match self.open(path)? {
+                Node::Directory(d) => d,
+                Node::File(_) => return Err(Error::NotFound),
+            }
         };
+        Ok(DirIterator {
+            directory,
+            done: false,
+        })
+    }
+
+    fn open_from<'b>(&'b self, from: &Directory<'b>, path: &str) -> Result<Node<'b>, Error> {
+        let mut buffer = [0_u8; 256];
+        let path = normalize_path(path, &mut buffer)?;
 
         let mut current_dir = *from;
-        loop {
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(name) = components.next() {
             current_dir.seek(0)?;
-
-            // sub is the directory or file name
-            // residual is what is left
-            let sub = match &residual[1..]
-                .find('/')
-                .or_else(|| (&residual[1..]).find('\\'))
-            {
-                None => {
-                    let sub = &residual[1..];
-                    residual = "";
-                    sub
+            let de = loop {
+                match current_dir.next_entry() {
+                    Err(Error::EndOfFile) => return Err(Error::NotFound),
+                    Err(e) => return Err(e),
+                    Ok(de) if compare_name(name, &de) => break de,
+                    Ok(_) => {}
                 }
-                Some(x) => {
-                    // +1 due to above find working on substring
-                    let sub = &residual[1..=*x];
-                    residual = &residual[(*x + 1)..];
-                    sub
+            };
+            if !de.is_directory() {
+                // Only the last component can be a file
+                if components.peek().is_some() {
+                    return Err(Error::NotFound);
                 }
+                return Ok(self.get_file(&de).unwrap().into());
+            }
+            // The ".." entry in a directory of the root has no cluster
+            current_dir = if de.cluster == 0 {
+                self.root()?
+            } else {
+                self.get_directory(de.cluster).unwrap()
+            };
+        }
+        current_dir.seek(0)?;
+        Ok(current_dir.into())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+
+    use super::Read;
+    use crate::part::tests::{FakeDisk, MemDisk};
+    use core::convert::TryInto;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Dir {
+        Root,
+        Cluster(u32),
+    }
+
+    /// Builds small FAT images in memory, using one sector per cluster so
+    /// that the FAT type is picked by the total number of sectors.
+    pub struct ImageBuilder {
+        data: Vec<u8>,
+        fat_type: super::FatType,
+        sectors_per_fat: u32,
+        root_entries: u32,
+        first_data_sector: u32,
+        next_free_cluster: u32,
+        dirs: HashMap<Dir, (Vec<u32>, usize)>,
+    }
+
+    impl ImageBuilder {
+        const RESERVED_SECTORS: u32 = 32;
+        const FAT_COUNT: u32 = 2;
+
+        pub fn new(fat_type: super::FatType) -> ImageBuilder {
+            let (sectors, root_entries) = match fat_type {
+                super::FatType::FAT12 => (2048, 512),
+                super::FatType::FAT16 => (16384, 512),
+                _ => (70000, 0),
+            };
+            let entry_bits = match fat_type {
+                super::FatType::FAT12 => 12,
+                super::FatType::FAT16 => 16,
+                _ => 32,
+            };
+            let sectors_per_fat = ((sectors + 2) * entry_bits / 8 + 511) / 512;
+            let root_dir_sectors = root_entries * 32 / 512;
+            let first_data_sector =
+                Self::RESERVED_SECTORS + Self::FAT_COUNT * sectors_per_fat + root_dir_sectors;
+
+            let mut builder = ImageBuilder {
+                data: vec![0; sectors as usize * 512],
+                fat_type,
+                sectors_per_fat,
+                root_entries,
+                first_data_sector,
+                next_free_cluster: 2,
+                dirs: HashMap::new(),
             };
 
-            if sub.is_empty() {
-                return Err(Error::NotFound);
+            let b = &mut builder.data;
+            b[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+            b[3..11].copy_from_slice(b"MSWIN4.1");
+            b[11..13].copy_from_slice(&512u16.to_le_bytes());
+            b[13] = 1; // sectors per cluster
+            b[14..16].copy_from_slice(&(Self::RESERVED_SECTORS as u16).to_le_bytes());
+            b[16] = Self::FAT_COUNT as u8;
+            b[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+            if sectors < 0x10000 {
+                b[19..21].copy_from_slice(&(sectors as u16).to_le_bytes());
+            } else {
+                b[32..36].copy_from_slice(&(sectors as u32).to_le_bytes());
+            }
+            b[21] = 0xf8; // media type
+            b[510] = 0x55;
+            b[511] = 0xaa;
+
+            builder.set_fat(0, 0x0fff_fff8);
+            builder.set_fat(1, 0x0fff_ffff);
+
+            if builder.fat_type == super::FatType::FAT32 {
+                let root = builder.allocate_clusters(1)[0];
+                builder.dirs.insert(Dir::Root, (vec![root], 0));
+                let b = &mut builder.data;
+                b[36..40].copy_from_slice(&sectors_per_fat.to_le_bytes());
+                b[44..48].copy_from_slice(&root.to_le_bytes());
+            } else {
+                builder.dirs.insert(Dir::Root, (vec![], 0));
+                let b = &mut builder.data;
+                b[22..24].copy_from_slice(&(sectors_per_fat as u16).to_le_bytes());
             }
 
-            loop {
-                match current_dir.next_entry() {
-                    Err(Error::EndOfFile) => return Err(Error::NotFound),
-                    Err(e) => return Err(e),
-                    Ok(de) => {
-                        if compare_name(sub, &de) {
-                            match de.file_type {
-                                FileType::Directory => {
-                                    if residual.is_empty() {
-                                        return Ok(self.get_directory(de.cluster).unwrap().into());
-                                    }
-                                    current_dir = self.get_directory(de.cluster).unwrap();
-                                    break;
-                                }
-                                FileType::File => {
-                                    return Ok(self.get_file(de.cluster, de.size).unwrap().into())
-                                }
-                            }
+            builder
+        }
+
+        pub fn disk(self) -> MemDisk {
+            MemDisk::new(self.data)
+        }
+
+        fn set_fat(&mut self, cluster: u32, value: u32) {
+            for fat in 0..Self::FAT_COUNT {
+                let fat_start =
+                    ((Self::RESERVED_SECTORS + fat * self.sectors_per_fat) * 512) as usize;
+                let b = &mut self.data[fat_start..];
+                match self.fat_type {
+                    super::FatType::FAT12 => {
+                        let offset = (cluster + cluster / 2) as usize;
+                        let value = (value & 0xfff) as u16;
+                        let mut raw = u16::from_le_bytes([b[offset], b[offset + 1]]);
+                        if cluster % 2 == 0 {
+                            raw = (raw & 0xf000) | value;
+                        } else {
+                            raw = (raw & 0x000f) | (value << 4);
                         }
+                        b[offset..offset + 2].copy_from_slice(&raw.to_le_bytes());
+                    }
+                    super::FatType::FAT16 => {
+                        let offset = cluster as usize * 2;
+                        b[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
                     }
+                    _ => {
+                        let offset = cluster as usize * 4;
+                        b[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        /// Allocates a chain of clusters, linking them in the FAT
+        fn allocate_clusters(&mut self, count: usize) -> Vec<u32> {
+            let clusters: Vec<u32> = (0..count as u32)
+                .map(|i| self.next_free_cluster + i)
+                .collect();
+            self.next_free_cluster += count as u32;
+            for (i, c) in clusters.iter().enumerate() {
+                let next = clusters.get(i + 1).copied().unwrap_or(0x0fff_ffff);
+                self.set_fat(*c, next);
+            }
+            clusters
+        }
+
+        fn cluster_offset(&self, cluster: u32) -> usize {
+            ((cluster - 2 + self.first_data_sector) * 512) as usize
+        }
+
+        pub fn push_entry(&mut self, dir: Dir, entry: &[u8; 32]) {
+            let (clusters, count) = self.dirs.get(&dir).cloned().unwrap();
+            let offset = if dir == Dir::Root && self.fat_type != super::FatType::FAT32 {
+                assert!((count as u32) < self.root_entries);
+                let root_start =
+                    (Self::RESERVED_SECTORS + Self::FAT_COUNT * self.sectors_per_fat) * 512;
+                root_start as usize + count * 32
+            } else {
+                let mut clusters = clusters;
+                if count == clusters.len() * 16 {
+                    let new = self.allocate_clusters(1)[0];
+                    self.set_fat(*clusters.last().unwrap(), new);
+                    clusters.push(new);
+                    self.dirs.get_mut(&dir).unwrap().0 = clusters.clone();
                 }
+                self.cluster_offset(clusters[count / 16]) + (count % 16) * 32
+            };
+            self.data[offset..offset + 32].copy_from_slice(entry);
+            self.dirs.get_mut(&dir).unwrap().1 += 1;
+        }
+
+        /// Writes the LFN entries for a name, to be followed by its short entry
+        pub fn push_long_name(&mut self, dir: Dir, name: &str, checksum: u8) {
+            let mut chars: Vec<u16> = name.encode_utf16().collect();
+            let count = (chars.len() + 12) / 13;
+            if chars.len() % 13 != 0 {
+                chars.push(0);
+            }
+            chars.resize(count * 13, 0xffff);
+
+            for seq in (1..=count).rev() {
+                let part = &chars[(seq - 1) * 13..seq * 13];
+                let mut e = [0u8; 32];
+                e[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+                e[11] = 0x0f;
+                e[13] = checksum;
+                for (j, c) in part.iter().enumerate() {
+                    let offset = match j {
+                        0..=4 => 1 + j * 2,
+                        5..=10 => 14 + (j - 5) * 2,
+                        _ => 28 + (j - 11) * 2,
+                    };
+                    e[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+                }
+                self.push_entry(dir, &e);
+            }
+        }
+
+        fn short_entry(name: &[u8; 11], flags: u8, cluster: u32, size: u32) -> [u8; 32] {
+            let mut e = [0u8; 32];
+            e[0..11].copy_from_slice(name);
+            e[11] = flags;
+            e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+            e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+            e[28..32].copy_from_slice(&size.to_le_bytes());
+            e
+        }
+
+        pub fn add_dir(&mut self, parent: Dir, name: &[u8; 11]) -> Dir {
+            let cluster = self.allocate_clusters(1)[0];
+            let dir = Dir::Cluster(cluster);
+            self.dirs.insert(dir, (vec![cluster], 0));
+
+            let parent_cluster = match parent {
+                Dir::Root => 0,
+                Dir::Cluster(c) => c,
+            };
+            self.push_entry(dir, &Self::short_entry(b".          ", 0x10, cluster, 0));
+            self.push_entry(
+                dir,
+                &Self::short_entry(b"..         ", 0x10, parent_cluster, 0),
+            );
+            self.push_entry(parent, &Self::short_entry(name, 0x10, cluster, 0));
+            dir
+        }
+
+        pub fn add_file(&mut self, parent: Dir, name: &[u8; 11], contents: &[u8]) {
+            let count = (contents.len() + 511) / 512;
+            let clusters = self.allocate_clusters(count);
+            self.write_file(parent, name, contents, &clusters);
+        }
+
+        /// Adds a file in the clusters given, counted from the first free
+        /// one and in the order given, leaving those in between free
+        pub fn add_fragmented_file(
+            &mut self,
+            parent: Dir,
+            name: &[u8; 11],
+            contents: &[u8],
+            clusters: &[u32],
+        ) {
+            assert_eq!(clusters.len(), (contents.len() + 511) / 512);
+            let clusters: Vec<u32> = clusters
+                .iter()
+                .map(|c| self.next_free_cluster + c)
+                .collect();
+            if let Some(last) = clusters.iter().max() {
+                self.next_free_cluster = last + 1;
+            }
+            for (i, c) in clusters.iter().enumerate() {
+                let next = clusters.get(i + 1).copied().unwrap_or(0x0fff_ffff);
+                self.set_fat(*c, next);
+            }
+            self.write_file(parent, name, contents, &clusters);
+        }
+
+        fn write_file(&mut self, parent: Dir, name: &[u8; 11], contents: &[u8], clusters: &[u32]) {
+            for (chunk, cluster) in contents.chunks(512).zip(clusters.iter()) {
+                let offset = self.cluster_offset(*cluster);
+                self.data[offset..offset + chunk.len()].copy_from_slice(chunk);
             }
+            let first = clusters.first().copied().unwrap_or(0);
+            let entry = Self::short_entry(name, 0x20, first, contents.len() as u32);
+            self.push_entry(parent, &entry);
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::Read;
-    use crate::part::tests::FakeDisk;
-    use core::convert::TryInto;
+    fn read_all(f: &mut crate::fat::File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        loop {
+            let mut data: [u8; 512] = [0; 512];
+            match f.read(&mut data) {
+                Ok(bytes) => contents.extend_from_slice(&data[..bytes as usize]),
+                Err(super::Error::EndOfFile) => break,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        contents
+    }
+
+    #[test]
+    fn test_fat_type_detection() {
+        let types = [
+            super::FatType::FAT12,
+            super::FatType::FAT16,
+            super::FatType::FAT32,
+        ];
+
+        for fat_type in &types {
+            // Large enough for the FAT12 chain to cross a FAT sector boundary
+            let contents: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+
+            let mut builder = ImageBuilder::new(*fat_type);
+            let dir = builder.add_dir(Dir::Root, b"EFI        ");
+            builder.add_file(dir, b"BOOTX64 EFI", &contents);
+            let disk = builder.disk();
+
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+            fs.init().expect("Error initialising filesystem");
+            assert_eq!(&fs.fat_type, fat_type);
+
+            let mut f: crate::fat::File = fs
+                .open("/EFI/BOOTX64.EFI")
+                .expect("Error opening file")
+                .try_into()
+                .unwrap();
+            assert_eq!(f.get_size() as usize, contents.len());
+            assert_eq!(read_all(&mut f), contents);
+        }
+    }
 
     #[test]
     fn test_fat_file_reads() {
@@ -929,11 +1778,154 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_normalize_path() {
+        let normalize = |path| {
+            let mut buffer = [0; 256];
+            super::normalize_path(path, &mut buffer).map(String::from)
+        };
+        assert_eq!(
+            normalize("/EFI//BOOT/./../BOOT/BOOTX64.EFI").unwrap(),
+            "/EFI/BOOT/BOOTX64.EFI"
+        );
+        assert_eq!(
+            normalize("\\EFI\\BOOT\\BOOTX64.EFI\0\0").unwrap(),
+            "/EFI/BOOT/BOOTX64.EFI"
+        );
+        assert_eq!(normalize("/EFI/..").unwrap(), "/");
+        assert_eq!(normalize("\\").unwrap(), "/");
+        assert_eq!(normalize(".").unwrap(), "");
+        assert_eq!(normalize("a/../../b/./c/..").unwrap(), "../b");
+        assert_eq!(normalize("../..\\a").unwrap(), "../../a");
+        assert_eq!(normalize("/EFI/../.."), Err(super::Error::NotFound));
+        assert_eq!(normalize(&"/a".repeat(129)), Err(super::Error::NotFound));
+    }
+
+    #[test]
+    fn test_fat_open_normalized() {
+        for fat_type in &[super::FatType::FAT16, super::FatType::FAT32] {
+            let mut builder = ImageBuilder::new(*fat_type);
+            let efi = builder.add_dir(Dir::Root, b"EFI        ");
+            let boot = builder.add_dir(efi, b"BOOT       ");
+            builder.add_file(boot, b"BOOTX64 EFI", b"boot");
+            builder.add_file(Dir::Root, b"KERNEL     ", b"kernel");
+            let disk = builder.disk();
+
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+            fs.init().expect("Error initialising filesystem");
+
+            let mut f: crate::fat::File = fs
+                .open("/EFI//BOOT/./../BOOT/BOOTX64.EFI")
+                .expect("Error opening file")
+                .try_into()
+                .unwrap();
+            assert_eq!(read_all(&mut f), b"boot");
+            assert!(matches!(fs.open("/"), Ok(super::Node::Directory(_))));
+            assert!(fs.open("/EFI/../..").is_err());
+            assert!(fs.open("/KERNEL/BOOTX64.EFI").is_err());
+
+            // Relative to a directory, going up through its ".." entries
+            let dir: crate::fat::Directory = fs.open("\\EFI\\BOOT").unwrap().try_into().unwrap();
+            let mut f: crate::fat::File = dir
+                .open("../../KERNEL")
+                .expect("Error opening file")
+                .try_into()
+                .unwrap();
+            assert_eq!(read_all(&mut f), b"kernel");
+            assert!(dir.open("../../../KERNEL").is_err());
+        }
+    }
+
+    #[test]
+    fn test_timestamp_from_fat() {
+        // 2025-01-01 12:00:00
+        let date = 45 << 9 | 1 << 5 | 1;
+        let time = 12 << 11;
+        assert_eq!(
+            super::Timestamp::from_fat(date, time, 150),
+            Some(super::Timestamp {
+                year: 2025,
+                month: 1,
+                day: 1,
+                hour: 12,
+                minute: 0,
+                second: 1,
+                nanosecond: 500_000_000,
+            })
+        );
+        // 23:59:58, the last time there is
+        let t = super::Timestamp::from_fat(date, 23 << 11 | 59 << 5 | 29, 0).unwrap();
+        assert_eq!((t.hour, t.minute, t.second), (23, 59, 58));
+        assert_eq!(super::Timestamp::from_fat(0, time, 0), None);
+        assert_eq!(
+            super::Timestamp::from_fat(45 << 9 | 13 << 5 | 1, 0, 0),
+            None
+        );
+        assert_eq!(super::Timestamp::from_fat(date, 24 << 11, 0), None);
+        assert_eq!(super::Timestamp::from_fat(date, time, 200), None);
+    }
+
+    #[test]
+    fn test_fat_file_attributes_and_times() {
+        let mut builder = ImageBuilder::new(super::FatType::FAT16);
+        let mut entry = ImageBuilder::short_entry(b"README  TXT", 0x21, 0, 0);
+        // Created 2021-05-06 10:30:20.5, modified 2021-05-07 08:15:00 and
+        // accessed on 2021-05-08
+        entry[13] = 50;
+        entry[14..16].copy_from_slice(&(10u16 << 11 | 30 << 5 | 10).to_le_bytes());
+        entry[16..18].copy_from_slice(&(41u16 << 9 | 5 << 5 | 6).to_le_bytes());
+        entry[18..20].copy_from_slice(&(41u16 << 9 | 5 << 5 | 8).to_le_bytes());
+        entry[22..24].copy_from_slice(&(8u16 << 11 | 15 << 5).to_le_bytes());
+        entry[24..26].copy_from_slice(&(41u16 << 9 | 5 << 5 | 7).to_le_bytes());
+        builder.push_entry(Dir::Root, &entry);
+        builder.add_file(Dir::Root, b"KERNEL     ", b"kernel");
+        let disk = builder.disk();
+
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+
+        let mut root = fs.root().unwrap();
+        let de = root.next_entry().unwrap();
+        assert_eq!(de.attributes(), super::ATTR_READ_ONLY | super::ATTR_ARCHIVE);
+        let times = de.times();
+        let created = times.created.unwrap();
+        assert_eq!((created.year, created.month, created.day), (2021, 5, 6));
+        assert_eq!(
+            (
+                created.hour,
+                created.minute,
+                created.second,
+                created.nanosecond
+            ),
+            (10, 30, 20, 500_000_000)
+        );
+        let modified = times.modified.unwrap();
+        assert_eq!(
+            (
+                modified.day,
+                modified.hour,
+                modified.minute,
+                modified.second
+            ),
+            (7, 8, 15, 0)
+        );
+        assert_eq!(times.accessed.unwrap().day, 8);
+
+        let f: crate::fat::File = fs.open("/README.TXT").unwrap().try_into().unwrap();
+        assert_eq!(f.attributes(), super::ATTR_READ_ONLY | super::ATTR_ARCHIVE);
+        assert_eq!(f.times(), times);
+
+        // Nothing recorded
+        let f: crate::fat::File = fs.open("/KERNEL").unwrap().try_into().unwrap();
+        assert_eq!(f.attributes(), super::ATTR_ARCHIVE);
+        assert_eq!(f.times(), super::Times::default());
+    }
+
     #[test]
     fn test_fat_init() {
         let d = FakeDisk::new("clear-28660-kvm.img");
-        match crate::part::find_efi_partition(&d) {
-            Ok((start, end)) => {
+        match crate::part::find_efi_partition(&d, crate::part::PartitionSelector::First) {
+            Ok((start, end, _)) => {
                 let mut f = crate::fat::Filesystem::new(&d, start, end);
                 match f.init() {
                     Ok(()) => {
@@ -950,8 +1942,8 @@ mod tests {
     #[test]
     fn test_fat_open() {
         let d = FakeDisk::new("clear-28660-kvm.img");
-        match crate::part::find_efi_partition(&d) {
-            Ok((start, end)) => {
+        match crate::part::find_efi_partition(&d, crate::part::PartitionSelector::First) {
+            Ok((start, end, _)) => {
                 let mut f = crate::fat::Filesystem::new(&d, start, end);
                 match f.init() {
                     Ok(()) => {
@@ -1033,6 +2025,419 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fat_read_dir() {
+        let types = [
+            super::FatType::FAT12,
+            super::FatType::FAT16,
+            super::FatType::FAT32,
+        ];
+
+        for fat_type in &types {
+            let mut builder = ImageBuilder::new(*fat_type);
+            let label = ImageBuilder::short_entry(b"VOLUME     ", 0x08, 0, 0);
+            builder.push_entry(Dir::Root, &label);
+            let dir = builder.add_dir(Dir::Root, b"DIR        ");
+            builder.add_dir(dir, b"SUBDIR     ");
+            let mut deleted = ImageBuilder::short_entry(b"DELETED TXT", 0x20, 0, 0);
+            deleted[0] = 0xe5;
+            builder.push_entry(dir, &deleted);
+            builder.add_file(dir, b"A       TXT", &[0xaa; 100]);
+            // Push the directory past its first cluster
+            for _ in 0..16 {
+                builder.push_entry(dir, &deleted);
+            }
+            builder.push_long_name(dir, "Second File.txt", super::lfn_checksum(b"SECOND~1TXT"));
+            builder.add_file(dir, b"SECOND~1TXT", &[0x55; 1000]);
+            let disk = builder.disk();
+
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+            fs.init().expect("Error initialising filesystem");
+
+            let root: Vec<_> = fs.read_dir("/").unwrap().map(|de| de.unwrap()).collect();
+            assert_eq!(root.len(), 1);
+            assert_eq!(crate::common::ascii_strip(&root[0].short_name()), "DIR");
+
+            let entries: Vec<_> = fs.read_dir("/DIR").unwrap().map(|de| de.unwrap()).collect();
+            let names: Vec<_> = entries
+                .iter()
+                .map(|de| match de.long_name() {
+                    Some(long_name) => String::from_utf16(long_name).unwrap(),
+                    None => crate::common::ascii_strip(&de.short_name()).to_string(),
+                })
+                .collect();
+            assert_eq!(names, [".", "..", "SUBDIR", "A.TXT", "Second File.txt"]);
+
+            let dirs: Vec<_> = entries.iter().map(|de| de.is_directory()).collect();
+            assert_eq!(dirs, [true, true, true, false, false]);
+            assert_eq!(entries[3].size(), 100);
+            assert_eq!(entries[3].attributes(), 0x20);
+            assert_eq!(entries[4].size(), 1000);
+
+            assert!(fs.read_dir("/DIR/A.TXT").is_err());
+            assert!(fs.read_dir("/MISSING").is_err());
+        }
+    }
+
+    #[test]
+    fn test_fat_load_file_contiguous() {
+        let contents: Vec<u8> = (0..100 * 1024 + 100).map(|i| (i % 253) as u8).collect();
+        let mut builder = ImageBuilder::new(super::FatType::FAT32);
+        builder.add_file(Dir::Root, b"KERNEL     ", &contents);
+        let disk = builder.disk();
+        let cache = crate::block::CachedBlock::new(&disk);
+
+        let mut fs = crate::fat::Filesystem::new(&cache, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+        let mut f: crate::fat::File = fs.open("/KERNEL").unwrap().try_into().unwrap();
+
+        let requests = disk.requests.get();
+        let mut buffer = vec![0u8; contents.len()];
+        let mut region =
+            crate::mem::MemoryRegion::new(buffer.as_mut_ptr() as u64, buffer.len() as u64);
+        f.load_file(&mut region).unwrap();
+        assert_eq!(buffer, contents);
+
+        // Two FAT sectors, one bulk request and then the final partial sector
+        assert_eq!(disk.requests.get() - requests, 4);
+    }
+
+    #[test]
+    fn test_fat_fragmented_file() {
+        let contents: Vec<u8> = (0..20 * 512 + 100).map(|i| (i % 251) as u8).collect();
+        // Runs of clusters with gaps between them, one of them going back
+        // to before the run ahead of it in the file
+        let runs = [(0, 3), (4, 1), (10, 5), (6, 3), (16, 4), (21, 5)];
+        let clusters: Vec<u32> = runs
+            .iter()
+            .flat_map(|(start, length)| *start..*start + *length)
+            .collect();
+
+        for fat_type in &[
+            super::FatType::FAT12,
+            super::FatType::FAT16,
+            super::FatType::FAT32,
+        ] {
+            let mut builder = ImageBuilder::new(*fat_type);
+            let efi = builder.add_dir(Dir::Root, b"EFI        ");
+            let boot = builder.add_dir(efi, b"BOOT       ");
+            builder.add_fragmented_file(boot, b"BOOTX64 EFI", &contents, &clusters);
+            let disk = builder.disk();
+            let cache = crate::block::CachedBlock::new(&disk);
+            let mut fs = crate::fat::Filesystem::new(&cache, 0, disk.len() - 1);
+            fs.init().expect("Error initialising filesystem");
+            let open = || -> crate::fat::File {
+                fs.open("/EFI/BOOT/BOOTX64.EFI")
+                    .unwrap()
+                    .try_into()
+                    .unwrap()
+            };
+
+            assert_eq!(read_all(&mut open()), contents);
+
+            let mut f = open();
+            let requests = disk.requests.get();
+            let mut buffer = vec![0u8; contents.len()];
+            let mut region =
+                crate::mem::MemoryRegion::new(buffer.as_mut_ptr() as u64, buffer.len() as u64);
+            f.load_file(&mut region).unwrap();
+            assert_eq!(buffer, contents);
+            // A request for each run, the last of them ending before the
+            // final partial sector, which comes from the cache
+            if *fat_type == super::FatType::FAT32 {
+                assert_eq!(disk.requests.get() - requests, runs.len());
+            }
+
+            // Across the places where the file jumps forward and back
+            let mut f = open();
+            for offset in &[3 * 512 - 10, 4 * 512 - 1, 9 * 512 + 7, 12 * 512 - 3] {
+                let mut data = [0; 20];
+                f.read_at(*offset, &mut data).unwrap();
+                assert_eq!(
+                    &data[..],
+                    &contents[*offset as usize..*offset as usize + 20]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fat_malformed() {
+        let image = || {
+            let mut builder = ImageBuilder::new(super::FatType::FAT16);
+            builder.add_file(Dir::Root, b"KERNEL     ", &[0x4b; 2048]);
+            builder
+        };
+        let init = |data: Vec<u8>| {
+            let disk = MemDisk::new(data);
+            let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+            fs.init()
+        };
+
+        let data = image().data;
+        assert_eq!(
+            init(data[..8192 * 512].to_vec()),
+            Err(super::Error::Truncated)
+        );
+        assert_eq!(init(data[..512].to_vec()), Err(super::Error::Truncated));
+        let mut bad = data.clone();
+        bad[510] = 0;
+        assert_eq!(init(bad), Err(super::Error::BadSignature));
+        let mut bad = data;
+        bad[13] = 0;
+        assert_eq!(init(bad), Err(super::Error::Unsupported));
+
+        // A chain that goes through a reserved cluster, the file being the
+        // first thing to be given clusters
+        let mut builder = image();
+        builder.set_fat(3, 1);
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().unwrap();
+        let mut f: crate::fat::File = fs.open("/KERNEL").unwrap().try_into().unwrap();
+        let mut data = [0; 512];
+        assert_eq!(f.read(&mut data), Ok(512));
+        assert_eq!(f.read(&mut data), Ok(512));
+        assert_eq!(f.read(&mut data), Err(super::Error::OutOfRange));
+        assert_eq!(f.seek(1536), Err(super::Error::OutOfRange));
+
+        // An entry whose cluster is past the end
+        let mut builder = image();
+        let entry = ImageBuilder::short_entry(b"BAD     BIN", 0x20, 0xfff0, 1024);
+        builder.push_entry(Dir::Root, &entry);
+        let entry = ImageBuilder::short_entry(b"BAD        ", 0x10, 1, 0);
+        builder.push_entry(Dir::Root, &entry);
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().unwrap();
+        let mut f: crate::fat::File = fs.open("/BAD.BIN").unwrap().try_into().unwrap();
+        assert_eq!(f.read(&mut data), Err(super::Error::OutOfRange));
+        assert!(matches!(
+            fs.open("/BAD/KERNEL"),
+            Err(super::Error::OutOfRange)
+        ));
+    }
+
+    #[test]
+    fn test_fat_volume_label() {
+        for (fat_type, offset) in &[(super::FatType::FAT12, 38), (super::FatType::FAT32, 66)] {
+            let init = |signature, label: &[u8; 11]| {
+                let mut builder = ImageBuilder::new(*fat_type);
+                builder.data[*offset] = signature;
+                builder.data[*offset + 5..*offset + 16].copy_from_slice(label);
+                let disk = builder.disk();
+                let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+                fs.init().unwrap();
+                assert_eq!(fs.fat_type(), *fat_type);
+                fs.volume_label().map(String::from)
+            };
+            assert_eq!(init(0x29, b"ESP        ").as_deref(), Some("ESP"));
+            assert_eq!(init(0x29, b"           "), None);
+            // Older boot sectors don't have the label
+            assert_eq!(init(0x28, b"ESP        "), None);
+        }
+    }
+
+    #[test]
+    fn test_fat_sector_size() {
+        // FAT12 with a 4096 byte sector per cluster: the boot sector, the
+        // FAT, the root directory and then the data
+        let contents: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut data = vec![0; 64 * 4096];
+        data[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        data[11..13].copy_from_slice(&4096u16.to_le_bytes());
+        data[13] = 1; // sectors per cluster
+        data[14..16].copy_from_slice(&1u16.to_le_bytes());
+        data[16] = 1; // FAT count
+        data[17..19].copy_from_slice(&128u16.to_le_bytes());
+        data[19..21].copy_from_slice(&64u16.to_le_bytes());
+        data[21] = 0xf8; // media type
+        data[22..24].copy_from_slice(&1u16.to_le_bytes());
+        data[510] = 0x55;
+        data[511] = 0xaa;
+        // The file is in clusters 2 and 3
+        data[4096..4096 + 6].copy_from_slice(&[0xf8, 0xff, 0xff, 0x03, 0xf0, 0xff]);
+        let entry = &mut data[2 * 4096..2 * 4096 + 32];
+        entry[0..11].copy_from_slice(b"HELLO   TXT");
+        entry[11] = 0x20;
+        entry[26..28].copy_from_slice(&2u16.to_le_bytes());
+        entry[28..32].copy_from_slice(&5000u32.to_le_bytes());
+        data[3 * 4096..3 * 4096 + 5000].copy_from_slice(&contents);
+
+        let mut disk = MemDisk::new(data);
+        disk.block_size = 4096;
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+        assert_eq!(fs.fat_type(), super::FatType::FAT12);
+        let mut f: crate::fat::File = fs.open("/hello.txt").unwrap().try_into().unwrap();
+        assert_eq!(f.get_size(), 5000);
+        let mut read = vec![0; 5000];
+        f.read_at(0, &mut read).unwrap();
+        assert_eq!(read, contents);
+
+        // Sectors smaller than the disk's blocks
+        let mut disk = ImageBuilder::new(super::FatType::FAT12).disk();
+        disk.block_size = 4096;
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        assert_eq!(fs.init(), Err(super::Error::Unsupported));
+    }
+
+    #[test]
+    fn test_fat_seek_lookups() {
+        // 4MiB, with one sector per cluster
+        let clusters = 8192;
+        let contents: Vec<u8> = (0..clusters * 512).map(|i| (i / 512) as u8).collect();
+        let mut builder = ImageBuilder::new(super::FatType::FAT16);
+        builder.add_file(Dir::Root, b"VMLINUZ    ", &contents);
+        let disk = builder.disk();
+
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+        let mut f: crate::fat::File = fs.open("/VMLINUZ").unwrap().try_into().unwrap();
+
+        let fat = u64::from(ImageBuilder::RESERVED_SECTORS)
+            ..u64::from(ImageBuilder::RESERVED_SECTORS + fs.sectors_per_fat);
+        let lookups = || {
+            disk.reads
+                .borrow()
+                .iter()
+                .filter(|s| fat.contains(s))
+                .count()
+        };
+
+        // Reading every sector through its own seek follows each link in
+        // the cluster chain once
+        let before = lookups();
+        let mut data = [0; 512];
+        for i in 0..clusters {
+            f.read_at(i as u32 * 512, &mut data).unwrap();
+            assert_eq!(data[0], i as u8);
+        }
+        assert_eq!(lookups() - before, clusters - 1);
+
+        // Seeking back starts again from the start of the chain, and seeking
+        // forward only walks the clusters skipped
+        let before = lookups();
+        f.seek(2048 * 512).unwrap();
+        f.seek(3072 * 512).unwrap();
+        f.read(&mut data).unwrap();
+        assert_eq!(data[0], (3072 % 256) as u8);
+        assert_eq!(lookups() - before, 3072);
+
+        // Staying within a cluster needs no lookups
+        let before = lookups();
+        f.seek(3072 * 512).unwrap();
+        f.read(&mut data).unwrap();
+        assert_eq!(lookups(), before);
+    }
+
+    #[test]
+    fn test_fat_cached_lookup() {
+        let mut builder = ImageBuilder::new(super::FatType::FAT16);
+        let efi = builder.add_dir(Dir::Root, b"EFI        ");
+        let boot = builder.add_dir(efi, b"BOOT       ");
+        builder.add_file(boot, b"A       TXT", &[0xa; 2048]);
+        builder.add_file(boot, b"B       TXT", &[0xb; 2048]);
+        let disk = builder.disk();
+        let cache = crate::block::CachedBlock::new(&disk);
+
+        let mut fs = crate::fat::Filesystem::new(&cache, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+        for (path, byte) in &[("/EFI/BOOT/A.TXT", 0xa), ("/EFI/BOOT/B.TXT", 0xb)] {
+            let mut f: crate::fat::File = fs.open(path).unwrap().try_into().unwrap();
+            assert!(read_all(&mut f).iter().all(|b| b == byte));
+        }
+
+        let first_fat_sector = u64::from(ImageBuilder::RESERVED_SECTORS);
+        let reads = disk.reads.borrow();
+        assert_eq!(reads.iter().filter(|s| **s == first_fat_sector).count(), 1);
+        assert!(cache.hits() > 0);
+    }
+
+    #[test]
+    fn test_fat_long_name_resolution() {
+        let mut builder = ImageBuilder::new(super::FatType::FAT16);
+        let efi = builder.add_dir(Dir::Root, b"EFI        ");
+        let boot = builder.add_dir(efi, b"BOOT       ");
+
+        // Short name deliberately mangled, only the long name matches
+        builder.push_long_name(boot, "BOOTX64.EFI", super::lfn_checksum(b"BOOTX6~1EFI"));
+        builder.add_file(boot, b"BOOTX6~1EFI", b"long");
+        // Long name whose checksum does not match the short entry
+        builder.push_long_name(boot, "stale.efi", super::lfn_checksum(b"OTHER   EFI"));
+        builder.add_file(boot, b"STALE~1 EFI", b"stale");
+        // Long enough to need several LFN entries
+        let long = "a rather long file name for testing.conf";
+        builder.push_long_name(boot, long, super::lfn_checksum(b"ARATHE~1CON"));
+        builder.add_file(boot, b"ARATHE~1CON", b"multi");
+        let disk = builder.disk();
+
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+
+        for (path, contents) in &[
+            ("/EFI/BOOT/BOOTX64.EFI", &b"long"[..]),
+            ("/efi/boot/bootx64.efi", b"long"),
+            ("/EFI/BOOT/BOOTX6~1.EFI", b"long"),
+            (
+                "/EFI/BOOT/A RATHER LONG FILE NAME FOR TESTING.CONF",
+                b"multi",
+            ),
+            ("/EFI/BOOT/STALE~1.EFI", b"stale"),
+        ] {
+            let mut f: crate::fat::File = fs
+                .open(path)
+                .expect("Error opening file")
+                .try_into()
+                .unwrap();
+            assert_eq!(&read_all(&mut f)[..], *contents);
+        }
+
+        assert!(fs.open("/EFI/BOOT/stale.efi").is_err());
+        assert!(fs.open("/EFI/BOOT/BOOTX64.EF").is_err());
+        assert!(fs.open("/EFI/BOOT/BOOTX64.EFIX").is_err());
+    }
+
+    #[test]
+    fn test_fat_write() {
+        use super::Write;
+
+        let contents: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let mut image = ImageBuilder::new(super::FatType::FAT16);
+        image.add_file(Dir::Root, b"STORE   BIN", &contents);
+        let d = image.disk();
+        let len = d.len();
+
+        // Without a writer the filesystem is read-only
+        let mut fs = crate::fat::Filesystem::new(&d, 0, len - 1);
+        fs.init().unwrap();
+        let mut f: crate::fat::File = fs.open("/store.bin").unwrap().try_into().unwrap();
+        assert_eq!(f.write(&mut [0xaa; 512]), Err(super::Error::ReadOnly));
+
+        let mut fs = crate::fat::Filesystem::new(&d, 0, len - 1);
+        fs.set_writer(&d);
+        fs.init().unwrap();
+        let mut f: crate::fat::File = fs.open("/store.bin").unwrap().try_into().unwrap();
+        f.seek(512).unwrap();
+        assert_eq!(f.write(&mut [0xaa; 512]), Ok(512));
+        assert_eq!(f.write(&mut [0xbb; 512]), Ok(476));
+        // The file can't grow
+        assert_eq!(f.write(&mut [0xcc; 512]), Err(super::Error::EndOfFile));
+
+        let mut f: crate::fat::File = fs.open("/store.bin").unwrap().try_into().unwrap();
+        let written = read_all(&mut f);
+        assert_eq!(written.len(), contents.len());
+        assert_eq!(written[..512], contents[..512]);
+        assert!(written[512..1024].iter().all(|b| *b == 0xaa));
+        assert!(written[1024..].iter().all(|b| *b == 0xbb));
+    }
+
+    #[test]
+    fn test_lfn_checksum() {
+        assert_eq!(super::lfn_checksum(b"BOOTX64 EFI"), 0x1d);
+        assert_eq!(super::lfn_checksum(b"LOADER  CON"), 0x6e);
+    }
+
     #[test]
     fn test_compare_short_name() {
         let mut de: super::DirectoryEntry = unsafe { std::mem::zeroed() };
@@ -1040,21 +2445,54 @@ mod tests {
         assert!(super::compare_short_name("X.abc", &de));
         de.name.copy_from_slice(b"ABCDEFGHIJK");
         assert!(super::compare_short_name("abcdefgh.ijk", &de));
+        assert!(!super::compare_short_name("abcdefg.ijk", &de));
+        de.name.copy_from_slice(b"STALE~1 EFI");
+        assert!(!super::compare_short_name("stale.efi", &de));
+        de.name.copy_from_slice(b"..         ");
+        assert!(super::compare_short_name("..", &de));
+        assert!(!super::compare_short_name(".", &de));
+    }
+
+    #[test]
+    fn test_short_name() {
+        let mut de: super::DirectoryEntry = unsafe { std::mem::zeroed() };
+        de.name.copy_from_slice(b"X       ABC");
+        assert_eq!(crate::common::ascii_strip(&de.short_name()), "X.ABC");
+        de.name.copy_from_slice(b".          ");
+        assert_eq!(crate::common::ascii_strip(&de.short_name()), ".");
+        de.name.copy_from_slice(b"..         ");
+        assert_eq!(crate::common::ascii_strip(&de.short_name()), "..");
+        de.name.copy_from_slice(b"ABCDEFGHIJK");
+        assert_eq!(crate::common::ascii_strip(&de.short_name()), "ABCDEFGH.IJK");
+        de.name.copy_from_slice(b"README     ");
+        assert_eq!(crate::common::ascii_strip(&de.short_name()), "README");
     }
 
     #[test]
-    fn test_name_to_str() {
-        let mut s = [0_u8; 11];
-        super::name_to_str("X       ABC", &mut s);
-        assert_eq!(crate::common::ascii_strip(&s), "X.ABC");
-        let mut s = [0_u8; 11];
-        super::name_to_str(".", &mut s);
-        assert_eq!(crate::common::ascii_strip(&s), ".");
-        let mut s = [0_u8; 11];
-        super::name_to_str("..", &mut s);
-        assert_eq!(crate::common::ascii_strip(&s), "..");
-        let mut s = [0_u8; 11];
-        super::name_to_str("ABCDEFGHIJK", &mut s);
-        assert_eq!(crate::common::ascii_strip(&s), "ABCDEFGHIJK");
+    fn test_load_parts() {
+        let data: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+        let mut memory = vec![0_u8; 700];
+        let address = memory.as_mut_ptr() as u64;
+        // Across the first sector boundary, and to the end of the file
+        let parts = [(500, 100, address), (1000, 500, address + 200)];
+        let read: [(u32, &[u8]); 1] = [(0, &data[..64])];
+
+        let mut f = crate::efi::BufferFile::new(&data);
+        let digest = super::load_parts(&mut f, parts.iter().copied(), &read).unwrap();
+        let mut hash = crate::sha256::Sha256::new();
+        hash.update(&data);
+        assert_eq!(digest, hash.finish());
+        assert_eq!(memory[..100], data[500..600]);
+        assert_eq!(memory[100..200], [0; 100][..]);
+        assert_eq!(memory[200..], data[1000..]);
+
+        // What was read before has to read the same again
+        let mut changed = data.clone();
+        changed[10] ^= 1;
+        let mut f = crate::efi::BufferFile::new(&changed);
+        assert_eq!(
+            super::load_parts(&mut f, parts.iter().copied(), &read),
+            Err(super::Error::Changed)
+        );
     }
 }
@@ -0,0 +1,303 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// QEMU's fw_cfg interface, through which it hands the firmware the -initrd
+// it was given and any files added with -fw_cfg, such as a kernel to boot.
+// An item is selected by its key and then read in order from the start,
+// either a byte at a time from the data port or with DMA where QEMU has that.
+
+use core::sync::atomic::{fence, Ordering};
+
+use atomic_refcell::AtomicRefCell;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+
+use crate::{
+    boot::{self, Cmdline, E820Entry, Framebuffer},
+    fat,
+};
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+// The DMA address is written big-endian, high half first, as the write to
+// the low half starts the transfer
+const DMA_ADDRESS_HIGH_PORT: u16 = 0x514;
+const DMA_ADDRESS_LOW_PORT: u16 = 0x518;
+
+const SIGNATURE_KEY: u16 = 0x00;
+const FEATURES_KEY: u16 = 0x01;
+const INITRD_SIZE_KEY: u16 = 0x0b;
+const INITRD_DATA_KEY: u16 = 0x12;
+const FILE_DIR_KEY: u16 = 0x19;
+
+const FEATURE_DMA: u32 = 1 << 1;
+
+const DMA_CONTROL_ERROR: u32 = 1 << 0;
+const DMA_CONTROL_READ: u32 = 1 << 1;
+const DMA_CONTROL_SKIP: u32 = 1 << 2;
+const DMA_CONTROL_SELECT: u32 = 1 << 3;
+
+const FILE_NAME_SIZE: usize = 56;
+const FILE_ENTRY_SIZE: usize = 8 + FILE_NAME_SIZE;
+
+// Added to the command line, as with -fw_cfg name=...,string=...
+const CMDLINE_FILE: &str = "opt/org.rust-hypervisor-firmware/cmdline";
+// Booted in place of what is on the disks, as with -fw_cfg name=...,file=...
+const KERNEL_FILE: &str = "opt/org.rust-hypervisor-firmware/kernel";
+// Used when there is no -initrd
+const INITRD_FILE: &str = "opt/org.rust-hypervisor-firmware/initrd";
+
+// The item selected and how far into it the next read starts, so that
+// reading a file in order doesn't go back to its start each time
+static POSITION: AtomicRefCell<Option<(u16, u32)>> = AtomicRefCell::new(None);
+
+// All big-endian
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct FwCfg {
+    dma: bool,
+}
+
+impl FwCfg {
+    // The interface, if it's there
+    pub fn probe() -> Option<FwCfg> {
+        let fw_cfg = FwCfg { dma: false };
+        let mut signature = [0; 4];
+        fw_cfg.read(SIGNATURE_KEY, 0, &mut signature);
+        if &signature != b"QEMU" {
+            return None;
+        }
+        let mut features = [0; 4];
+        fw_cfg.read(FEATURES_KEY, 0, &mut features);
+        Some(FwCfg {
+            dma: u32::from_le_bytes(features) & FEATURE_DMA != 0,
+        })
+    }
+
+    // Reads the item from offset onwards into data, carrying on from the
+    // last read where that was of the same item and not past offset
+    fn read(&self, key: u16, offset: u32, data: &mut [u8]) {
+        let mut position = POSITION.borrow_mut();
+        let (select, skip) = match *position {
+            Some((k, p)) if k == key && p <= offset => (false, offset - p),
+            _ => (true, offset),
+        };
+        if self.dma {
+            self.dma_read(key, select, skip, data);
+        } else {
+            port_read(key, select, skip, data);
+        }
+        *position = Some((key, offset + data.len() as u32));
+    }
+
+    fn dma_read(&self, key: u16, select: bool, skip: u32, data: &mut [u8]) {
+        let mut control = if select {
+            u32::from(key) << 16 | DMA_CONTROL_SELECT
+        } else {
+            0
+        };
+        if skip != 0 {
+            self.dma_transfer(control | DMA_CONTROL_SKIP, skip, 0);
+            control = 0;
+        }
+        self.dma_transfer(
+            control | DMA_CONTROL_READ,
+            data.len() as u32,
+            data.as_mut_ptr() as u64,
+        );
+    }
+
+    fn dma_transfer(&self, control: u32, length: u32, address: u64) {
+        let access = DmaAccess {
+            control: control.to_be(),
+            length: length.to_be(),
+            address: address.to_be(),
+        };
+        let access_address = &access as *const _ as u64;
+        fence(Ordering::Release);
+        unsafe {
+            PortWriteOnly::<u32>::new(DMA_ADDRESS_HIGH_PORT)
+                .write(((access_address >> 32) as u32).to_be());
+            PortWriteOnly::<u32>::new(DMA_ADDRESS_LOW_PORT).write((access_address as u32).to_be());
+        }
+        // QEMU is done by the time the write returns, but it could take
+        // longer elsewhere. All that's left set at the end is the error bit.
+        let control = || u32::from_be(unsafe { core::ptr::read_volatile(&access.control) });
+        while control() & !DMA_CONTROL_ERROR != 0 {
+            crate::delay::relax();
+        }
+        fence(Ordering::Acquire);
+        if control() & DMA_CONTROL_ERROR != 0 {
+            log!("fw_cfg DMA transfer failed");
+        }
+    }
+
+    // The file added under the name
+    pub fn file(&self, name: &str) -> Option<File> {
+        let mut count = [0; 4];
+        self.read(FILE_DIR_KEY, 0, &mut count);
+        let mut entry = [0; FILE_ENTRY_SIZE];
+        for i in 0..u32::from_be_bytes(count) {
+            self.read(FILE_DIR_KEY, 4 + i * FILE_ENTRY_SIZE as u32, &mut entry);
+            let (size, key, entry_name) = parse_file_entry(&entry);
+            if entry_name == name.as_bytes() {
+                return Some(File::new(*self, key, size));
+            }
+        }
+        None
+    }
+
+    // The -kernel given to QEMU is the firmware, so it's a file
+    pub fn kernel(&self) -> Option<File> {
+        self.file(KERNEL_FILE)
+    }
+
+    // What was given with -initrd, or as a file
+    pub fn initrd(&self) -> Option<File> {
+        let mut size = [0; 4];
+        self.read(INITRD_SIZE_KEY, 0, &mut size);
+        match u32::from_le_bytes(size) {
+            0 => self.file(INITRD_FILE),
+            size => Some(File::new(*self, INITRD_DATA_KEY, size)),
+        }
+    }
+}
+
+fn port_read(key: u16, select: bool, skip: u32, data: &mut [u8]) {
+    if select {
+        unsafe { PortWriteOnly::<u16>::new(SELECTOR_PORT).write(key) };
+    }
+    let mut port = Port::<u8>::new(DATA_PORT);
+    for _ in 0..skip {
+        unsafe { port.read() };
+    }
+    for b in data {
+        *b = unsafe { port.read() };
+    }
+}
+
+// The size, key and name of an entry in the file directory
+fn parse_file_entry(entry: &[u8; FILE_ENTRY_SIZE]) -> (u32, u16, &[u8]) {
+    let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+    let key = u16::from_be_bytes([entry[4], entry[5]]);
+    let name = &entry[8..];
+    let length = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    (size, key, &name[..length])
+}
+
+// An item, read like a file
+pub struct File {
+    fw_cfg: FwCfg,
+    key: u16,
+    size: u32,
+    position: u32,
+}
+
+impl File {
+    fn new(fw_cfg: FwCfg, key: u16, size: u32) -> File {
+        File {
+            fw_cfg,
+            key,
+            size,
+            position: 0,
+        }
+    }
+}
+
+impl fat::Read for File {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fat::Error> {
+        if self.position >= self.size {
+            return Err(fat::Error::EndOfFile);
+        }
+        let bytes = core::cmp::min(data.len() as u32, self.size - self.position);
+        self.fw_cfg
+            .read(self.key, self.position, &mut data[..bytes as usize]);
+        self.position += bytes;
+        Ok(bytes)
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fat::Error> {
+        if position >= self.size {
+            return Err(fat::Error::EndOfFile);
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    fn get_size(&self) -> u32 {
+        self.size
+    }
+}
+
+// The boot protocol's information, with the command line file added to its
+// command line
+pub struct Info<'a> {
+    info: &'a dyn boot::Info,
+    cmdline: Cmdline,
+}
+
+impl<'a> Info<'a> {
+    pub fn new(info: &'a dyn boot::Info) -> Info<'a> {
+        let mut cmdline = Cmdline::new();
+        cmdline.append(info.cmdline());
+        if let Some(mut f) = FwCfg::probe().and_then(|fw_cfg| fw_cfg.file(CMDLINE_FILE)) {
+            let mut data = [0; boot::CMDLINE_MAX_LEN];
+            let length = core::cmp::min(f.size as usize, data.len());
+            if f.size > 0 && fat::Read::read(&mut f, &mut data[..length]).is_ok() {
+                // As with -fw_cfg ...,file=, there can be a newline at the end
+                let length = data[..length]
+                    .iter()
+                    .position(|c| *c == 0 || *c == b'\n')
+                    .unwrap_or(length);
+                cmdline.append(&data[..length]);
+            }
+        }
+        Info { info, cmdline }
+    }
+}
+
+impl<'a> boot::Info for Info<'a> {
+    fn name(&self) -> &str {
+        self.info.name()
+    }
+    fn rsdp_addr(&self) -> u64 {
+        self.info.rsdp_addr()
+    }
+    fn cmdline(&self) -> &[u8] {
+        self.cmdline.as_bytes()
+    }
+    fn num_entries(&self) -> u8 {
+        self.info.num_entries()
+    }
+    fn entry(&self, idx: u8) -> E820Entry {
+        self.info.entry(idx)
+    }
+    fn framebuffer(&self) -> Option<Framebuffer> {
+        self.info.framebuffer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_file_entry, FILE_ENTRY_SIZE};
+
+    #[test]
+    fn test_parse_file_entry() {
+        let mut entry = [0; FILE_ENTRY_SIZE];
+        entry[..4].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        entry[4..6].copy_from_slice(&0x0020u16.to_be_bytes());
+        entry[8..16].copy_from_slice(b"etc/e820");
+        assert_eq!(
+            parse_file_entry(&entry),
+            (0x1234_5678, 0x0020, &b"etc/e820"[..])
+        );
+
+        // A name that fills the whole field has no NUL
+        entry[8..].fill(b'a');
+        assert_eq!(parse_file_entry(&entry).2.len(), 56);
+    }
+}
@@ -1,5 +1,11 @@
 use core::mem::size_of;
 
+use x86_64::{
+    instructions::tables::load_tss,
+    structures::{gdt::SegmentSelector, tss::TaskStateSegment},
+    PrivilegeLevel,
+};
+
 bitflags::bitflags! {
     // An extension of x86_64::structures::gdt::DescriptorFlags
     struct Descriptor: u64 {
@@ -27,6 +33,12 @@ bitflags::bitflags! {
         // BIT32 must be 0, all other bits (not yet mentioned) are ignored.
         const CODE64 = Self::COMMON.bits | Self::READABLE.bits | Self::EXECUTABLE.bits | Self::BIT64.bits;
         const DATA64 = Self::COMMON.bits | Self::WRITABLE.bits | Self::BIT64.bits;
+        // Flat 4GiB segments for what is started in 32-bit protected mode
+        const LIMIT_4G = Self::LIMIT_0_15.bits | Self::LIMIT_16_19.bits | Self::GRANULARITY.bits;
+        const CODE32 = Self::COMMON.bits | Self::READABLE.bits | Self::EXECUTABLE.bits | Self::BIT32.bits | Self::LIMIT_4G.bits;
+        const DATA32 = Self::COMMON.bits | Self::WRITABLE.bits | Self::BIT32.bits | Self::LIMIT_4G.bits;
+        // A system segment of type 9, an available 64-bit TSS
+        const TSS64 = Self::ACCESSED.bits | Self::EXECUTABLE.bits | Self::PRESENT.bits;
     }
 }
 
@@ -50,5 +62,33 @@ impl Pointer {
 
 // Our 64-bit GDT lives in RAM, so it can be accessed like any other global.
 #[no_mangle]
-static GDT64_PTR: Pointer = Pointer::new(&GDT64);
-static GDT64: [Descriptor; 3] = [Descriptor::empty(), Descriptor::CODE64, Descriptor::DATA64];
+static GDT64_PTR: Pointer = Pointer::new(unsafe { &GDT64 });
+// The 32-bit segments, 0x18 and 0x20, are only used by asm/start32.s. The
+// TSS's descriptor at 0x28 takes up the last two entries and is filled in by
+// set_tss(), as its base isn't known until then.
+static mut GDT64: [Descriptor; 7] = [
+    Descriptor::empty(),
+    Descriptor::CODE64,
+    Descriptor::DATA64,
+    Descriptor::CODE32,
+    Descriptor::DATA32,
+    Descriptor::empty(),
+    Descriptor::empty(),
+];
+const TSS_INDEX: u16 = 5;
+
+// Points the GDT's TSS descriptor at the TSS and loads it into the task
+// register, which is only needed for its interrupt stacks. Loading it marks
+// it busy, so this can only be done once.
+pub fn set_tss(tss: &'static TaskStateSegment) {
+    let base = tss as *const _ as u64;
+    let limit = size_of::<TaskStateSegment>() as u64 - 1;
+    let low = Descriptor::TSS64.bits | limit | (base & 0xff_ffff) << 16 | (base >> 24 & 0xff) << 56;
+    // SAFETY: The TSS descriptor isn't used by anything else, and is only
+    // written to before it is loaded
+    unsafe {
+        GDT64[TSS_INDEX as usize] = Descriptor::from_bits_truncate(low);
+        GDT64[TSS_INDEX as usize + 1] = Descriptor::from_bits_truncate(base >> 32);
+        load_tss(SegmentSelector::new(TSS_INDEX, PrivilegeLevel::Ring0));
+    }
+}
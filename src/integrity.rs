@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Checks the kernel, initrd and command line against the SHA-256 hashes
+// listed in a manifest on the filesystem, as written by sha256sum. It's a
+// lighter gate than Secure Boot: without the manifest nothing is checked,
+// but with it anything that isn't listed with the right hash isn't booted.
+
+use crate::{
+    fat::{self, Read},
+    sha256::Sha256,
+};
+
+pub const MANIFEST_PATH: &str = "/EFI/rhfw/sha256sums";
+
+// What the command line, as the kernel is given it, is listed as
+const CMDLINE_NAME: &str = "cmdline";
+
+const MAX_MANIFEST_SIZE: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    FileError(fat::Error),
+    TooLarge,
+    NotListed,
+    Mismatch,
+}
+
+impl From<fat::Error> for Error {
+    fn from(e: fat::Error) -> Error {
+        Error::FileError(e)
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_digest(hex: &[u8]) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(digest)
+}
+
+// Paths on the filesystem are the same whichever slashes they use, with or
+// without the leading one, and in any case
+fn same_path(a: &str, b: &str) -> bool {
+    let leading = |c| c == '/' || c == '\\';
+    let a = a.trim_start_matches(leading).as_bytes();
+    let b = b.trim_start_matches(leading).as_bytes();
+    let normalize = |c: &u8| match c {
+        b'\\' => b'/',
+        c => c.to_ascii_lowercase(),
+    };
+    a.len() == b.len() && a.iter().map(normalize).eq(b.iter().map(normalize))
+}
+
+pub struct Manifest {
+    data: [u8; MAX_MANIFEST_SIZE],
+    length: usize,
+}
+
+impl Manifest {
+    fn new(contents: &[u8]) -> Result<Manifest, Error> {
+        if contents.len() > MAX_MANIFEST_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut data = [0; MAX_MANIFEST_SIZE];
+        data[..contents.len()].copy_from_slice(contents);
+        Ok(Manifest {
+            data,
+            length: contents.len(),
+        })
+    }
+
+    // The manifest on the filesystem, if there is one
+    pub fn load(fs: &fat::Filesystem) -> Result<Option<Manifest>, Error> {
+        let mut f = match fs.open(MANIFEST_PATH) {
+            Ok(fat::Node::File(f)) => f,
+            Ok(_) | Err(fat::Error::NotFound) => return Ok(None),
+            Err(e) => return Err(Error::FileError(e)),
+        };
+        let length = f.get_size() as usize;
+        if length > MAX_MANIFEST_SIZE {
+            return Err(Error::TooLarge);
+        }
+        let mut contents = [0; MAX_MANIFEST_SIZE];
+        f.read_at(0, &mut contents[..length])?;
+        log!("Checking what is booted against {}", MANIFEST_PATH);
+        Manifest::new(&contents[..length]).map(Some)
+    }
+
+    // The hash listed for the name. Lines are the hash, then a space and
+    // either another space or a '*' for binary mode, then the name.
+    fn expected(&self, name: &str) -> Option<[u8; 32]> {
+        self.data[..self.length]
+            .split(|c| *c == b'\n')
+            .filter_map(|line| {
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                let hex = line.get(..64)?;
+                let listed = match line.get(64..66)? {
+                    b"  " | b" *" => core::str::from_utf8(&line[66..]).ok()?,
+                    _ => return None,
+                };
+                Some((parse_digest(hex)?, listed))
+            })
+            .find(|(_, listed)| same_path(listed, name))
+            .map(|(digest, _)| digest)
+    }
+
+    // Checks the hash of what was loaded, so that it is of what is booted
+    // rather than of reading the file again
+    pub fn check(&self, name: &str, digest: &[u8; 32]) -> Result<(), Error> {
+        match self.expected(name) {
+            None => {
+                log!("Refusing to boot: {} isn't in {}", name, MANIFEST_PATH);
+                Err(Error::NotListed)
+            }
+            Some(expected) if expected != *digest => {
+                log!("Refusing to boot: {} doesn't match its SHA-256 hash", name);
+                Err(Error::Mismatch)
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    pub fn check_data(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let mut hash = Sha256::new();
+        hash.update(data);
+        self.check(name, &hash.finish())
+    }
+
+    pub fn check_cmdline(&self, cmdline: &[u8]) -> Result<(), Error> {
+        self.check_data(CMDLINE_NAME, cmdline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Manifest};
+    use crate::fat::{
+        tests::{Dir, ImageBuilder},
+        Read,
+    };
+    use crate::sha256::Sha256;
+    use core::convert::TryInto;
+
+    fn hex(data: &[u8]) -> String {
+        let mut hash = Sha256::new();
+        hash.update(data);
+        hash.finish().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_manifest() {
+        let kernel: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+        let initrd = vec![0x42; 1024];
+        let cmdline = b"console=ttyS0 root=/dev/vda1";
+        let manifest = format!(
+            "{}  vmlinuz\n{} *boot/INITRD.IMG\r\nnot a hash  empty\n{}  cmdline\n",
+            hex(&kernel),
+            hex(&initrd).to_uppercase(),
+            hex(cmdline),
+        );
+        let manifest = Manifest::new(manifest.as_bytes()).unwrap();
+
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT16);
+        builder.add_file(Dir::Root, b"VMLINUZ    ", &kernel);
+        let boot = builder.add_dir(Dir::Root, b"BOOT       ");
+        builder.add_file(boot, b"INITRD  IMG", &initrd);
+        // The same file with one byte changed
+        let mut tampered = kernel;
+        tampered[1000] ^= 1;
+        builder.add_file(boot, b"VMLINUZ    ", &tampered);
+        builder.add_file(Dir::Root, b"EMPTY      ", &[]);
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().unwrap();
+        let digest = |path| {
+            let mut f: crate::fat::File = fs.open(path).unwrap().try_into().unwrap();
+            f.read_all(&mut |_, _| {}).unwrap()
+        };
+
+        assert_eq!(manifest.check("/vmlinuz", &digest("/VMLINUZ")), Ok(()));
+        assert_eq!(
+            manifest.check("\\boot\\initrd.img", &digest("/boot/initrd.img")),
+            Ok(())
+        );
+        assert_eq!(
+            manifest.check("/vmlinuz", &digest("/boot/vmlinuz")),
+            Err(Error::Mismatch)
+        );
+        assert_eq!(
+            manifest.check("/boot/vmlinuz", &digest("/boot/vmlinuz")),
+            Err(Error::NotListed)
+        );
+        assert_eq!(
+            manifest.check("/empty", &digest("/empty")),
+            Err(Error::NotListed)
+        );
+
+        assert_eq!(manifest.check_cmdline(cmdline), Ok(()));
+        assert_eq!(
+            manifest.check_cmdline(b"console=ttyS0 root=/dev/vda1 init=/bin/sh"),
+            Err(Error::Mismatch)
+        );
+        assert!(Manifest::new(&[b'\n'; 4097]).is_err());
+    }
+}
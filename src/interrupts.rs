@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// An IDT for the CPU exceptions, so that a fault in the firmware, or in an EFI
+// application running on it, is reported over serial rather than ending in a
+// triple fault and a reset: which exception it was, its error code, what a
+// page fault was accessing and the registers. It then stops the way a panic
+// does, halting unless rhfw.panic says otherwise.
+//
+// It also has the local APIC timer's interrupt, which is where the watchdogs
+// are checked from until what is booted takes over.
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::{hlt, interrupts, port::Port, tables::lidt},
+    structures::{tss::TaskStateSegment, DescriptorTablePointer},
+    VirtAddr,
+};
+
+use crate::{apic, gdt};
+
+const EXCEPTIONS: usize = 32;
+// The timer comes after the exceptions, spurious interrupts at the end of
+// the IDT
+const TIMER_VECTOR: u8 = 32;
+const SPURIOUS_VECTOR: u8 = 47;
+const VECTORS: usize = SPURIOUS_VECTOR as usize + 1;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+
+const PIC1_DATA_PORT: u16 = 0x21;
+const PIC2_DATA_PORT: u16 = 0xa1;
+
+// A double fault is handled on a stack of its own, so that it can still be
+// reported when it was the stack that faulted
+const DOUBLE_FAULT_IST: u8 = 1;
+const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024;
+
+// Present, ring 0 and a 64-bit interrupt gate, in our code segment
+const INTERRUPT_GATE: u8 = 0x8e;
+const CODE_SEGMENT: u16 = 0x08;
+
+#[cfg(feature = "log-serial")]
+const NAMES: [&str; EXCEPTIONS] = [
+    "Divide error",
+    "Debug",
+    "Non-maskable interrupt",
+    "Breakpoint",
+    "Overflow",
+    "BOUND range exceeded",
+    "Invalid opcode",
+    "Device not available",
+    "Double fault",
+    "Coprocessor segment overrun",
+    "Invalid TSS",
+    "Segment not present",
+    "Stack-segment fault",
+    "General protection fault",
+    "Page fault",
+    "Reserved",
+    "x87 floating-point exception",
+    "Alignment check",
+    "Machine check",
+    "SIMD floating-point exception",
+    "Virtualization exception",
+    "Control protection exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor injection exception",
+    "VMM communication exception",
+    "Security exception",
+    "Reserved",
+];
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    attributes: u8,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl Gate {
+    const EMPTY: Gate = Gate {
+        offset_low: 0,
+        selector: 0,
+        ist: 0,
+        attributes: 0,
+        offset_middle: 0,
+        offset_high: 0,
+        reserved: 0,
+    };
+
+    fn new(handler: u64, ist: u8) -> Self {
+        Self {
+            offset_low: handler as u16,
+            selector: CODE_SEGMENT,
+            ist,
+            attributes: INTERRUPT_GATE,
+            offset_middle: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+static mut IDT: [Gate; VECTORS] = [Gate::EMPTY; VECTORS];
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; DOUBLE_FAULT_STACK_SIZE]);
+
+// Set by the first exception, so that one taken while reporting it only halts
+static HANDLING: AtomicBool = AtomicBool::new(false);
+// Whether interrupts are on for the timer, so that they are only turned
+// back on after masking it if there is one
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+// What the CPU and the stubs in asm/interrupts.s push, from the bottom up
+#[repr(C)]
+struct Frame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    vector: u64,
+    error_code: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+// Sets up the IDT with the stubs in asm/interrupts.s. Only the boot CPU ever
+// runs the firmware, so this is done once.
+#[cfg(not(test))]
+pub fn init() {
+    extern "C" {
+        #[link_name = "exception_stubs"]
+        static STUBS: [u64; EXCEPTIONS];
+        fn timer_stub();
+        fn spurious_stub();
+    }
+    // SAFETY: Nothing has been set up to use the IDT or the TSS yet
+    unsafe {
+        let stack_top = DOUBLE_FAULT_STACK.0.as_ptr() as u64 + DOUBLE_FAULT_STACK_SIZE as u64;
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST as usize - 1] = VirtAddr::new(stack_top);
+        gdt::set_tss(&TSS);
+
+        for (vector, gate) in IDT[..EXCEPTIONS].iter_mut().enumerate() {
+            let ist = if vector as u64 == DOUBLE_FAULT {
+                DOUBLE_FAULT_IST
+            } else {
+                0
+            };
+            *gate = Gate::new(STUBS[vector], ist);
+        }
+        IDT[TIMER_VECTOR as usize] = Gate::new(timer_stub as usize as u64, 0);
+        IDT[SPURIOUS_VECTOR as usize] = Gate::new(spurious_stub as usize as u64, 0);
+        lidt(&DescriptorTablePointer {
+            limit: (size_of::<[Gate; VECTORS]>() - 1) as u16,
+            base: VirtAddr::new(IDT.as_ptr() as u64),
+        });
+    }
+}
+
+// Tests aren't linked with asm/interrupts.s, and can't load an IDT anyway
+#[cfg(test)]
+pub fn init() {}
+
+// Starts the timer interrupt, with interrupts enabled from then on. The
+// legacy PICs are masked so that only the local APIC interrupts.
+pub fn start_timer() {
+    unsafe {
+        Port::<u8>::new(PIC1_DATA_PORT).write(0xff);
+        Port::<u8>::new(PIC2_DATA_PORT).write(0xff);
+    }
+    if apic::start_timer(TIMER_VECTOR, SPURIOUS_VECTOR) {
+        TIMER_RUNNING.store(true, Ordering::SeqCst);
+        interrupts::enable();
+    } else {
+        log!("No local APIC timer, watchdogs are only checked while waiting");
+    }
+}
+
+// Called before handing over, as what is booted expects interrupts off
+pub fn stop_timer() {
+    interrupts::disable();
+    TIMER_RUNNING.store(false, Ordering::SeqCst);
+    apic::stop_timer();
+}
+
+// Keeps the timer interrupt from coming until unmask_timer()
+pub fn mask_timer() {
+    interrupts::disable();
+}
+
+pub fn unmask_timer() {
+    if TIMER_RUNNING.load(Ordering::SeqCst) {
+        interrupts::enable();
+    }
+}
+
+#[no_mangle]
+extern "C" fn timer_interrupt() {
+    crate::watchdog::check();
+    crate::efi::check_watchdog();
+    apic::end_of_interrupt();
+}
+
+// Where the last page fault was
+#[cfg(feature = "log-serial")]
+fn cr2() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack)) };
+    cr2
+}
+
+// The access a page fault's error code describes, and what was wrong with
+// the page
+fn page_fault_cause(error_code: u64) -> (&'static str, &'static str) {
+    let access = if error_code & (1 << 4) != 0 {
+        "Instruction fetch"
+    } else if error_code & (1 << 1) != 0 {
+        "Write"
+    } else {
+        "Read"
+    };
+    let problem = if error_code & (1 << 3) != 0 {
+        "a reserved bit set in its page table entry"
+    } else if error_code & 1 != 0 {
+        "a page it isn't allowed on"
+    } else {
+        "an unmapped page"
+    };
+    (access, problem)
+}
+
+fn halt() -> ! {
+    loop {
+        hlt()
+    }
+}
+
+#[no_mangle]
+extern "C" fn exception(frame: &Frame) -> ! {
+    if HANDLING.swap(true, Ordering::SeqCst) {
+        halt()
+    }
+
+    log!(
+        "EXCEPTION: {} (vector {}), error code {:#x}",
+        NAMES[frame.vector as usize % EXCEPTIONS],
+        frame.vector,
+        frame.error_code
+    );
+    if frame.vector == PAGE_FAULT {
+        let (access, problem) = page_fault_cause(frame.error_code);
+        log!("{} of {:#x} on {}", access, cr2(), problem);
+    }
+    log!(
+        "RIP: {:#018x} CS: {:#06x} RFLAGS: {:#010x} RSP: {:#018x} SS: {:#06x}",
+        frame.rip,
+        frame.cs,
+        frame.rflags,
+        frame.rsp,
+        frame.ss
+    );
+    log!(
+        "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x} RDX: {:#018x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx,
+        frame.rdx
+    );
+    log!(
+        "RSI: {:#018x} RDI: {:#018x} RBP: {:#018x} R8:  {:#018x}",
+        frame.rsi,
+        frame.rdi,
+        frame.rbp,
+        frame.r8
+    );
+    log!(
+        "R9:  {:#018x} R10: {:#018x} R11: {:#018x} R12: {:#018x}",
+        frame.r9,
+        frame.r10,
+        frame.r11,
+        frame.r12
+    );
+    log!(
+        "R13: {:#018x} R14: {:#018x} R15: {:#018x}",
+        frame.r13,
+        frame.r14,
+        frame.r15
+    );
+    crate::panic::finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem::size_of;
+
+    use super::{page_fault_cause, Frame, Gate};
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(size_of::<Gate>(), 16);
+        // 15 registers pushed by the stubs, the vector and error code, and
+        // what the CPU pushes
+        assert_eq!(size_of::<Frame>(), (15 + 2 + 5) * 8);
+
+        let gate = Gate::new(0x1234_5678_9abc_def0, 1);
+        assert_eq!(
+            (gate.offset_low, gate.offset_middle, gate.offset_high),
+            (0xdef0, 0x9abc, 0x1234_5678)
+        );
+        assert_eq!((gate.selector, gate.ist, gate.attributes), (0x08, 1, 0x8e));
+    }
+
+    #[test]
+    fn test_page_fault_cause() {
+        assert_eq!(page_fault_cause(0), ("Read", "an unmapped page"));
+        assert_eq!(
+            page_fault_cause(0b11),
+            ("Write", "a page it isn't allowed on")
+        );
+        assert_eq!(
+            page_fault_cause(0b1_0001),
+            ("Instruction fetch", "a page it isn't allowed on")
+        );
+        assert_eq!(
+            page_fault_cause(0b1001),
+            ("Read", "a reserved bit set in its page table entry")
+        );
+    }
+}
@@ -0,0 +1,526 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Just enough Ethernet, ARP, IPv4 and UDP to talk to the servers a network
+// boot needs: no fragments, IP options or anything but UDP
+
+use core::fmt;
+
+use crate::{
+    delay,
+    net::{VirtioNetDevice, MAX_FRAME_SIZE},
+};
+
+pub type MacAddress = [u8; 6];
+
+pub const BROADCAST_MAC: MacAddress = [0xff; 6];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERNET_HEADER_SIZE: usize = 14;
+const IPV4_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+const IP_PROTOCOL_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+// An ARP packet for Ethernet and IPv4, padded to the smallest frame
+const ARP_SIZE: usize = 28;
+const MIN_FRAME_SIZE: usize = 60;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+// Requests are sent this many times, waiting this long for each reply
+const ARP_ATTEMPTS: usize = 3;
+const ARP_TIMEOUT_MS: u64 = 500;
+/// The most a UDP datagram sent or received can carry
+pub const MAX_UDP_PAYLOAD: usize =
+    MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE - IPV4_HEADER_SIZE - UDP_HEADER_SIZE;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+
+    // From the dotted decimal form, e.g. 10.0.2.2
+    pub fn parse(s: &[u8]) -> Option<Ipv4Address> {
+        let mut address = [0; 4];
+        let mut parts = s.split(|c| *c == b'.');
+        for byte in address.iter_mut() {
+            let part = parts.next()?;
+            if part.is_empty() || part.len() > 3 || !part.iter().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let value = part
+                .iter()
+                .fold(0u16, |value, c| value * 10 + u16::from(c - b'0'));
+            if value > 255 {
+                return None;
+            }
+            *byte = value as u8;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Ipv4Address(address))
+    }
+}
+
+impl Ipv4Address {
+    fn on_subnet(&self, other: Ipv4Address, mask: Ipv4Address) -> bool {
+        (0..4).all(|i| self.0[i] & mask.0[i] == other.0[i] & mask.0[i])
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+    }
+}
+
+/// One end of a UDP exchange
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Endpoint {
+    pub mac: MacAddress,
+    pub ip: Ipv4Address,
+    pub port: u16,
+}
+
+/// A UDP datagram taken out of a frame
+pub struct Datagram<'a> {
+    pub source: Endpoint,
+    pub destination: Ipv4Address,
+    pub port: u16,
+    pub payload: &'a [u8],
+}
+
+/// An ARP request or reply taken out of a frame
+#[derive(Debug, PartialEq)]
+pub struct Arp {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Address,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Address,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Builds the Ethernet frame of an ARP packet, returning its length.
+/// Requests go to everyone, replies to who asked.
+pub fn arp_frame(frame: &mut [u8], arp: &Arp) -> usize {
+    let destination = match arp.operation {
+        ARP_REQUEST => BROADCAST_MAC,
+        _ => arp.target_mac,
+    };
+    frame[..MIN_FRAME_SIZE].fill(0);
+    frame[0..6].copy_from_slice(&destination);
+    frame[6..12].copy_from_slice(&arp.sender_mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+
+    let packet = &mut frame[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + ARP_SIZE];
+    // Ethernet hardware addresses of 6 bytes, IPv4 addresses of 4
+    packet[0..6].copy_from_slice(&[0, 1, 8, 0, 6, 4]);
+    packet[6..8].copy_from_slice(&arp.operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&arp.sender_mac);
+    packet[14..18].copy_from_slice(&arp.sender_ip.0);
+    packet[18..24].copy_from_slice(&arp.target_mac);
+    packet[24..28].copy_from_slice(&arp.target_ip.0);
+    MIN_FRAME_SIZE
+}
+
+/// The ARP packet in the frame, if it holds one for Ethernet and IPv4
+pub fn parse_arp(frame: &[u8]) -> Option<Arp> {
+    if frame.len() < ETHERNET_HEADER_SIZE + ARP_SIZE
+        || read_u16(frame, 12) != ETHERTYPE_ARP
+        || frame[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + 6] != [0, 1, 8, 0, 6, 4]
+    {
+        return None;
+    }
+    let packet = &frame[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + ARP_SIZE];
+    let mut arp = Arp {
+        operation: read_u16(packet, 6),
+        sender_mac: [0; 6],
+        sender_ip: Ipv4Address::UNSPECIFIED,
+        target_mac: [0; 6],
+        target_ip: Ipv4Address::UNSPECIFIED,
+    };
+    arp.sender_mac.copy_from_slice(&packet[8..14]);
+    arp.sender_ip.0.copy_from_slice(&packet[14..18]);
+    arp.target_mac.copy_from_slice(&packet[18..24]);
+    arp.target_ip.0.copy_from_slice(&packet[24..28]);
+    Some(arp)
+}
+
+// The ones' complement sum of the data, in 16 bit words, carrying on from
+// an earlier sum
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    for word in data.chunks(2) {
+        let high = u32::from(word[0]) << 8;
+        sum += high | word.get(1).map_or(0, |low| u32::from(*low));
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// The UDP checksum covers a pseudo header of the addresses, the protocol
+// and the length, then the datagram itself
+fn udp_checksum_sum(source: &[u8], destination: &[u8], udp: &[u8]) -> u32 {
+    let mut sum = checksum_add(0, source);
+    sum = checksum_add(sum, destination);
+    sum = checksum_add(sum, &[0, IP_PROTOCOL_UDP]);
+    sum = checksum_add(sum, &(udp.len() as u16).to_be_bytes());
+    checksum_add(sum, udp)
+}
+
+/// Builds the Ethernet frame of a UDP datagram, returning its length
+pub fn udp_frame(
+    frame: &mut [u8],
+    source: &Endpoint,
+    destination: &Endpoint,
+    payload: &[u8],
+) -> usize {
+    assert!(payload.len() <= MAX_UDP_PAYLOAD);
+    let udp_length = UDP_HEADER_SIZE + payload.len();
+    let ip_length = IPV4_HEADER_SIZE + udp_length;
+
+    frame[0..6].copy_from_slice(&destination.mac);
+    frame[6..12].copy_from_slice(&source.mac);
+    frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+    let ip = &mut frame[ETHERNET_HEADER_SIZE..ETHERNET_HEADER_SIZE + ip_length];
+    // Version 4 with a header of 5 words, don't fragment
+    ip[0..12].copy_from_slice(&[
+        0x45,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0x40,
+        0,
+        DEFAULT_TTL,
+        IP_PROTOCOL_UDP,
+        0,
+        0,
+    ]);
+    ip[2..4].copy_from_slice(&(ip_length as u16).to_be_bytes());
+    ip[12..16].copy_from_slice(&source.ip.0);
+    ip[16..20].copy_from_slice(&destination.ip.0);
+    let checksum = checksum_finish(checksum_add(0, &ip[..IPV4_HEADER_SIZE]));
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let udp = &mut ip[IPV4_HEADER_SIZE..];
+    udp[0..2].copy_from_slice(&source.port.to_be_bytes());
+    udp[2..4].copy_from_slice(&destination.port.to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_length as u16).to_be_bytes());
+    udp[6..8].copy_from_slice(&[0, 0]);
+    udp[UDP_HEADER_SIZE..].copy_from_slice(payload);
+    // All zeros would mean there is no checksum
+    let checksum = match checksum_finish(udp_checksum_sum(&source.ip.0, &destination.ip.0, udp)) {
+        0 => 0xffff,
+        c => c,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    ETHERNET_HEADER_SIZE + ip_length
+}
+
+/// The UDP datagram in the frame, if it holds an intact one
+pub fn parse_udp(frame: &[u8]) -> Option<Datagram> {
+    if frame.len() < ETHERNET_HEADER_SIZE + IPV4_HEADER_SIZE + UDP_HEADER_SIZE
+        || read_u16(frame, 12) != ETHERTYPE_IPV4
+    {
+        return None;
+    }
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&frame[6..12]);
+
+    let ip = &frame[ETHERNET_HEADER_SIZE..];
+    let ip_length = usize::from(read_u16(ip, 2));
+    // Only the plain header, and no fragments
+    if ip[0] != 0x45
+        || ip[9] != IP_PROTOCOL_UDP
+        || read_u16(ip, 6) & 0x3fff != 0
+        || ip_length < IPV4_HEADER_SIZE + UDP_HEADER_SIZE
+        || ip_length > ip.len()
+        || checksum_finish(checksum_add(0, &ip[..IPV4_HEADER_SIZE])) != 0
+    {
+        return None;
+    }
+    let mut source_ip = [0; 4];
+    source_ip.copy_from_slice(&ip[12..16]);
+    let mut destination_ip = [0; 4];
+    destination_ip.copy_from_slice(&ip[16..20]);
+
+    let udp = &ip[IPV4_HEADER_SIZE..ip_length];
+    let udp_length = usize::from(read_u16(udp, 4));
+    if udp_length < UDP_HEADER_SIZE || udp_length > udp.len() {
+        return None;
+    }
+    let udp = &udp[..udp_length];
+    if read_u16(udp, 6) != 0
+        && checksum_finish(udp_checksum_sum(&source_ip, &destination_ip, udp)) != 0
+    {
+        return None;
+    }
+
+    Some(Datagram {
+        source: Endpoint {
+            mac,
+            ip: Ipv4Address(source_ip),
+            port: read_u16(udp, 0),
+        },
+        destination: Ipv4Address(destination_ip),
+        port: read_u16(udp, 2),
+        payload: &udp[UDP_HEADER_SIZE..],
+    })
+}
+
+/// Sends and receives UDP datagrams on a network device, as the address
+/// given, which is unspecified until DHCP has found one. Anything off the
+/// subnet goes through the router.
+pub struct Interface<'a, 'b> {
+    device: &'a VirtioNetDevice<'b>,
+    pub ip: Ipv4Address,
+    pub subnet_mask: Ipv4Address,
+    pub router: Option<Ipv4Address>,
+}
+
+impl<'a, 'b> Interface<'a, 'b> {
+    pub fn new(device: &'a VirtioNetDevice<'b>) -> Interface<'a, 'b> {
+        Interface {
+            device,
+            ip: Ipv4Address::UNSPECIFIED,
+            subnet_mask: Ipv4Address::UNSPECIFIED,
+            router: None,
+        }
+    }
+
+    pub fn mac(&self) -> MacAddress {
+        self.device.mac()
+    }
+
+    // Answers who has our address, so that servers can send to us
+    fn handle_arp(&self, frame: &[u8]) {
+        let request = match parse_arp(frame) {
+            Some(arp) if arp.operation == ARP_REQUEST => arp,
+            _ => return,
+        };
+        if self.ip == Ipv4Address::UNSPECIFIED || request.target_ip != self.ip {
+            return;
+        }
+        let reply = Arp {
+            operation: ARP_REPLY,
+            sender_mac: self.mac(),
+            sender_ip: self.ip,
+            target_mac: request.sender_mac,
+            target_ip: request.sender_ip,
+        };
+        let mut frame = [0; MIN_FRAME_SIZE];
+        let length = arp_frame(&mut frame, &reply);
+        self.send(&frame[..length]);
+    }
+
+    // A frame the device doesn't send is as good as lost on the way, which
+    // whatever waits for a reply already copes with. Frames are never too
+    // big as what goes in them is limited.
+    fn send(&self, frame: &[u8]) {
+        self.device.send(frame).ok();
+    }
+
+    /// The MAC address to send to for the address, found with ARP, which is
+    /// the router's if the address is off the subnet
+    pub fn resolve(&self, ip: Ipv4Address) -> Option<MacAddress> {
+        let next_hop = match self.router {
+            Some(router) if !ip.on_subnet(self.ip, self.subnet_mask) => router,
+            _ => ip,
+        };
+        let request = Arp {
+            operation: ARP_REQUEST,
+            sender_mac: self.mac(),
+            sender_ip: self.ip,
+            target_mac: [0; 6],
+            target_ip: next_hop,
+        };
+        let mut frame = [0; MAX_FRAME_SIZE];
+        for _ in 0..ARP_ATTEMPTS {
+            let length = arp_frame(&mut frame, &request);
+            self.send(&frame[..length]);
+            let deadline = delay::now_ns() + ARP_TIMEOUT_MS * 1_000_000;
+            while delay::now_ns() < deadline {
+                let length = match self.device.recv(&mut frame) {
+                    Some(length) => length,
+                    None => {
+                        delay::relax();
+                        continue;
+                    }
+                };
+                match parse_arp(&frame[..length]) {
+                    Some(reply) if reply.operation == ARP_REPLY && reply.sender_ip == next_hop => {
+                        return Some(reply.sender_mac)
+                    }
+                    _ => self.handle_arp(&frame[..length]),
+                }
+            }
+        }
+        None
+    }
+
+    pub fn send_udp(&self, port: u16, destination: &Endpoint, payload: &[u8]) {
+        let source = Endpoint {
+            mac: self.mac(),
+            ip: self.ip,
+            port,
+        };
+        let mut frame = [0; MAX_FRAME_SIZE];
+        let length = udp_frame(&mut frame, &source, destination, payload);
+        self.send(&frame[..length]);
+    }
+
+    /// Waits until the deadline, as from delay::now_ns(), for a datagram to
+    /// the port, returning where it came from and the length of its payload
+    /// copied into buf
+    pub fn recv_udp(&self, port: u16, deadline: u64, buf: &mut [u8]) -> Option<(Endpoint, usize)> {
+        let mut frame = [0; MAX_FRAME_SIZE];
+        while delay::now_ns() < deadline {
+            let length = match self.device.recv(&mut frame) {
+                Some(length) => length,
+                None => {
+                    delay::relax();
+                    continue;
+                }
+            };
+            let datagram = match parse_udp(&frame[..length]) {
+                Some(datagram) => datagram,
+                None => {
+                    self.handle_arp(&frame[..length]);
+                    continue;
+                }
+            };
+            let for_us = datagram.destination == self.ip
+                || datagram.destination == Ipv4Address::BROADCAST
+                || self.ip == Ipv4Address::UNSPECIFIED;
+            if datagram.port != port || !for_us {
+                continue;
+            }
+            let length = core::cmp::min(datagram.payload.len(), buf.len());
+            buf[..length].copy_from_slice(&datagram.payload[..length]);
+            return Some((datagram.source, length));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{arp_frame, parse_arp, parse_udp, udp_frame, Arp, Endpoint, Ipv4Address};
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            Ipv4Address::parse(b"10.0.2.2"),
+            Some(Ipv4Address([10, 0, 2, 2]))
+        );
+        assert_eq!(
+            Ipv4Address::parse(b"255.255.255.0"),
+            Some(Ipv4Address([255, 255, 255, 0]))
+        );
+        for s in &[
+            "",
+            "10.0.2",
+            "10.0.2.2.1",
+            "10.0.2.256",
+            "10..2.2",
+            "a.b.c.d",
+        ] {
+            assert_eq!(Ipv4Address::parse(s.as_bytes()), None);
+        }
+        assert_eq!(format!("{}", Ipv4Address([192, 168, 0, 1])), "192.168.0.1");
+    }
+
+    #[test]
+    fn test_udp_frame() {
+        let source = Endpoint {
+            mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            ip: Ipv4Address([10, 0, 2, 15]),
+            port: 1234,
+        };
+        let destination = Endpoint {
+            mac: [0x52, 0x55, 10, 0, 2, 2],
+            ip: Ipv4Address([10, 0, 2, 2]),
+            port: 69,
+        };
+        let mut frame = [0; 128];
+        let length = udp_frame(&mut frame, &source, &destination, b"hello");
+        assert_eq!(length, 14 + 20 + 8 + 5);
+        assert_eq!(&frame[14..24], &[0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, 17]);
+        assert_eq!(&frame[24..26], &[0x22, 0xbc]);
+        assert_eq!(&frame[34..40], &[0x04, 0xd2, 0, 69, 0, 13]);
+
+        let datagram = parse_udp(&frame[..length]).unwrap();
+        assert_eq!(datagram.source, source);
+        assert_eq!(datagram.destination, destination.ip);
+        assert_eq!(datagram.port, 69);
+        assert_eq!(datagram.payload, b"hello");
+
+        // Padded out to the minimum frame size
+        assert!(parse_udp(&frame[..60]).is_some());
+        // Corrupted headers and payloads are dropped
+        for offset in &[12, 16, 30, 41, 46] {
+            let mut corrupt = frame;
+            corrupt[*offset] ^= 1;
+            assert!(parse_udp(&corrupt[..length]).is_none(), "{}", offset);
+        }
+        assert!(parse_udp(&frame[..length - 1]).is_none());
+    }
+
+    #[test]
+    fn test_arp() {
+        let request = Arp {
+            operation: 1,
+            sender_mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            sender_ip: Ipv4Address([10, 0, 2, 15]),
+            target_mac: [0; 6],
+            target_ip: Ipv4Address([10, 0, 2, 2]),
+        };
+        let mut frame = [0xaa; 64];
+        let length = arp_frame(&mut frame, &request);
+        assert_eq!(length, 60);
+        assert_eq!(&frame[..6], &[0xff; 6]);
+        assert_eq!(&frame[12..22], &[8, 6, 0, 1, 8, 0, 6, 4, 0, 1]);
+        assert_eq!(&frame[42..60], &[0; 18]);
+        assert_eq!(parse_arp(&frame[..length]), Some(request));
+
+        let reply = Arp {
+            operation: 2,
+            sender_mac: [0x52, 0x55, 10, 0, 2, 2],
+            sender_ip: Ipv4Address([10, 0, 2, 2]),
+            target_mac: [0x52, 0x54, 0, 0x12, 0x34, 0x56],
+            target_ip: Ipv4Address([10, 0, 2, 15]),
+        };
+        arp_frame(&mut frame, &reply);
+        assert_eq!(&frame[..6], &reply.target_mac);
+        assert_eq!(parse_arp(&frame[..length]), Some(reply));
+
+        assert!(parse_arp(&frame[..41]).is_none());
+        frame[13] = 0;
+        assert!(parse_arp(&frame[..length]).is_none());
+    }
+
+    #[test]
+    fn test_on_subnet() {
+        let mask = Ipv4Address([255, 255, 255, 0]);
+        let ip = Ipv4Address([10, 0, 2, 15]);
+        assert!(Ipv4Address([10, 0, 2, 4]).on_subnet(ip, mask));
+        assert!(!Ipv4Address([10, 0, 3, 4]).on_subnet(ip, mask));
+        assert!(Ipv4Address([10, 0, 3, 4]).on_subnet(ip, Ipv4Address::UNSPECIFIED));
+    }
+}
@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Read-only ISO9660, as on CD images, for booting installers attached as an
+// ISO rather than a disk. Only the names in the primary volume descriptor's
+// directories are looked at, not Joliet or Rock Ridge ones, which leaves
+// what are usually upper case 8.3 names such as \EFI\BOOT\BOOTX64.EFI.
+
+use crate::{block::SectorRead, fat};
+
+// The logical block size of every ISO9660 filesystem we can read, of which
+// the first 16 are left for the system area
+const BLOCK_SIZE: usize = 2048;
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / 512) as u64;
+const FIRST_DESCRIPTOR: u32 = 16;
+// How far to look for the primary volume descriptor
+const MAX_DESCRIPTORS: u32 = 32;
+
+const SIGNATURE: &[u8] = b"CD001";
+const PRIMARY_VOLUME_DESCRIPTOR: u8 = 1;
+const TERMINATOR: u8 = 255;
+
+const ROOT_RECORD_OFFSET: usize = 156;
+const RECORD_NAME_OFFSET: usize = 33;
+const FLAG_DIRECTORY: u8 = 1 << 1;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    BlockError,
+    BadSignature,
+    NotFound,
+    // A logical block size other than 2048 bytes
+    Unsupported,
+    // A directory record that runs past the end of its block
+    Truncated,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    // In logical blocks
+    extent: u32,
+    size: u32,
+    directory: bool,
+}
+
+impl Record {
+    fn parse(record: &[u8]) -> Result<Record, Error> {
+        if record.len() < RECORD_NAME_OFFSET {
+            return Err(Error::Truncated);
+        }
+        Ok(Record {
+            extent: u32::from_le_bytes([record[2], record[3], record[4], record[5]]),
+            size: u32::from_le_bytes([record[10], record[11], record[12], record[13]]),
+            directory: record[25] & FLAG_DIRECTORY != 0,
+        })
+    }
+}
+
+fn read_block(
+    device: &dyn SectorRead,
+    block: u32,
+    data: &mut [u8; BLOCK_SIZE],
+) -> Result<(), Error> {
+    device
+        .read_multi(u64::from(block) * SECTORS_PER_BLOCK, data)
+        .map_err(|_| Error::BlockError)
+}
+
+// Whether there is a volume descriptor where the first one goes
+pub fn is_iso9660(device: &dyn SectorRead) -> bool {
+    let mut data = [0; 512];
+    device
+        .read(u64::from(FIRST_DESCRIPTOR) * SECTORS_PER_BLOCK, &mut data)
+        .is_ok()
+        && &data[1..6] == SIGNATURE
+}
+
+// A name on the disk matches one in a path without its ";1" version number,
+// and without the '.' left at the end of a name with no extension, ignoring
+// case
+fn name_matches(name: &[u8], wanted: &str) -> bool {
+    let name = match name.iter().position(|c| *c == b';') {
+        Some(version) => &name[..version],
+        None => name,
+    };
+    let name = name.strip_suffix(b".").unwrap_or(name);
+    name.eq_ignore_ascii_case(wanted.as_bytes())
+}
+
+pub struct Filesystem<'a> {
+    device: &'a dyn SectorRead,
+    root: Record,
+}
+
+pub struct File<'a> {
+    device: &'a dyn SectorRead,
+    // In 512 byte sectors
+    start: u64,
+    size: u32,
+    position: u32,
+}
+
+impl<'a> Filesystem<'a> {
+    pub fn new(device: &'a dyn SectorRead) -> Result<Filesystem<'a>, Error> {
+        let mut data = [0; BLOCK_SIZE];
+        for block in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+            read_block(device, block, &mut data)?;
+            if &data[1..6] != SIGNATURE {
+                return Err(Error::BadSignature);
+            }
+            match data[0] {
+                PRIMARY_VOLUME_DESCRIPTOR => {
+                    if u16::from_le_bytes([data[128], data[129]]) as usize != BLOCK_SIZE {
+                        return Err(Error::Unsupported);
+                    }
+                    let root = Record::parse(&data[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34])?;
+                    return Ok(Filesystem { device, root });
+                }
+                TERMINATOR => break,
+                _ => {}
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    // The record in the directory with that name. Records don't cross from
+    // one block to the next, with what is left of a block after the last one
+    // filled with 0s.
+    fn find(&self, dir: Record, wanted: &str) -> Result<Record, Error> {
+        let mut data = [0; BLOCK_SIZE];
+        let blocks = (dir.size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        for block in 0..blocks as u32 {
+            read_block(self.device, dir.extent + block, &mut data)?;
+            let mut offset = 0;
+            while offset < BLOCK_SIZE && data[offset] != 0 {
+                let length = data[offset] as usize;
+                let record = data.get(offset..offset + length).ok_or(Error::Truncated)?;
+                let name_length = *record.get(32).ok_or(Error::Truncated)? as usize;
+                let name = record
+                    .get(RECORD_NAME_OFFSET..RECORD_NAME_OFFSET + name_length)
+                    .ok_or(Error::Truncated)?;
+                // Leaving out the directory itself and its parent
+                if name != [0] && name != [1] && name_matches(name, wanted) {
+                    return Record::parse(record);
+                }
+                offset += length;
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    // Opens a file from a path from the root, split with '\' or '/'
+    pub fn open(&self, path: &str) -> Result<File<'a>, Error> {
+        let mut record = self.root;
+        for name in path
+            .split(|c| c == '\\' || c == '/')
+            .filter(|n| !n.is_empty())
+        {
+            if !record.directory {
+                return Err(Error::NotFound);
+            }
+            record = self.find(record, name)?;
+        }
+        if record.directory {
+            return Err(Error::NotFound);
+        }
+        Ok(File {
+            device: self.device,
+            start: u64::from(record.extent) * SECTORS_PER_BLOCK,
+            size: record.size,
+            position: 0,
+        })
+    }
+}
+
+// The extent of a file is contiguous, so it is read a sector at a time
+// straight from the device, the same way as a FAT file
+impl<'a> fat::Read for File<'a> {
+    fn read(&mut self, data: &mut [u8]) -> Result<u32, fat::Error> {
+        assert_eq!(data.len(), 512);
+
+        if self.position >= self.size {
+            return Err(fat::Error::EndOfFile);
+        }
+        self.device
+            .read(self.start + u64::from(self.position / 512), data)
+            .map_err(|_| fat::Error::BlockError)?;
+        let bytes = core::cmp::min(512, self.size - self.position);
+        self.position += bytes;
+        Ok(bytes)
+    }
+
+    fn seek(&mut self, position: u32) -> Result<(), fat::Error> {
+        if position % 512 != 0 {
+            return Err(fat::Error::InvalidOffset);
+        }
+        if position >= self.size {
+            return Err(fat::Error::EndOfFile);
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    fn get_size(&self) -> u32 {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_iso9660, name_matches, Error, Filesystem, BLOCK_SIZE};
+    use crate::{fat::Read, part::tests::MemDisk};
+
+    const FILE_SIZE: usize = 3000;
+
+    // A record for what is at extent, returning its length
+    fn write_record(record: &mut [u8], extent: u32, size: u32, flags: u8, name: &[u8]) -> usize {
+        let length = (33 + name.len() + 1) & !1;
+        record[0] = length as u8;
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = flags;
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+        length
+    }
+
+    // Adds a record after the last one in the directory in block dir
+    fn add_record(image: &mut [u8], dir: usize, extent: u32, size: u32, flags: u8, name: &[u8]) {
+        let block = &mut image[dir * BLOCK_SIZE..(dir + 1) * BLOCK_SIZE];
+        let mut offset = 0;
+        while block[offset] != 0 {
+            offset += block[offset] as usize;
+        }
+        write_record(&mut block[offset..], extent, size, flags, name);
+    }
+
+    // Blocks 16 and 17 for the volume descriptors, then the root, \EFI and
+    // \EFI\BOOT directories and \EFI\BOOT\BOOTX64.EFI
+    fn iso_image() -> Vec<u8> {
+        let mut image = vec![0; 24 * BLOCK_SIZE];
+        let pvd = &mut image[16 * BLOCK_SIZE..17 * BLOCK_SIZE];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[6] = 1;
+        pvd[128..130].copy_from_slice(&2048u16.to_le_bytes());
+        // The root record in the descriptor, then the directories
+        write_record(&mut pvd[156..190], 18, BLOCK_SIZE as u32, 2, &[0]);
+        let terminator = &mut image[17 * BLOCK_SIZE..18 * BLOCK_SIZE];
+        terminator[0] = 255;
+        terminator[1..6].copy_from_slice(b"CD001");
+
+        add_record(&mut image, 18, 18, BLOCK_SIZE as u32, 2, &[0]);
+        add_record(&mut image, 18, 18, BLOCK_SIZE as u32, 2, &[1]);
+        add_record(&mut image, 18, 0, 0, 0, b"README.TXT;1");
+        add_record(&mut image, 18, 19, BLOCK_SIZE as u32, 2, b"EFI");
+        add_record(&mut image, 19, 19, BLOCK_SIZE as u32, 2, &[0]);
+        add_record(&mut image, 19, 18, BLOCK_SIZE as u32, 2, &[1]);
+        add_record(&mut image, 19, 20, BLOCK_SIZE as u32, 2, b"BOOT");
+        add_record(&mut image, 20, 20, BLOCK_SIZE as u32, 2, &[0]);
+        add_record(&mut image, 20, 19, BLOCK_SIZE as u32, 2, &[1]);
+        add_record(&mut image, 20, 21, FILE_SIZE as u32, 0, b"BOOTX64.EFI;1");
+
+        for (i, b) in image[21 * BLOCK_SIZE..21 * BLOCK_SIZE + FILE_SIZE]
+            .iter_mut()
+            .enumerate()
+        {
+            *b = (i % 251) as u8;
+        }
+        image
+    }
+
+    #[test]
+    fn test_name_matches() {
+        assert!(name_matches(b"BOOTX64.EFI;1", "bootx64.efi"));
+        assert!(name_matches(b"README.;1", "README"));
+        assert!(name_matches(b"EFI", "EFI"));
+        assert!(!name_matches(b"BOOTX64.EFI;1", "BOOTX64"));
+    }
+
+    #[test]
+    fn test_read_file() {
+        let disk = MemDisk::new(iso_image());
+        assert!(is_iso9660(&disk));
+        let fs = Filesystem::new(&disk).unwrap();
+
+        let mut file = fs.open("\\EFI\\BOOT\\BOOTX64.EFI").unwrap();
+        assert_eq!(file.get_size(), FILE_SIZE as u32);
+        let mut data = Vec::new();
+        let mut sector = [0; 512];
+        while let Ok(bytes) = file.read(&mut sector) {
+            data.extend_from_slice(&sector[..bytes as usize]);
+        }
+        assert_eq!(data.len(), FILE_SIZE);
+        assert!(data.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+
+        file.seek(2560).unwrap();
+        assert_eq!(file.read(&mut sector), Ok(440));
+        assert_eq!(sector[0], (2560 % 251) as u8);
+
+        assert!(fs.open("/efi/boot/bootx64.efi").is_ok());
+        assert!(fs.open("\\README.TXT").is_ok());
+        assert!(matches!(fs.open("\\EFI\\BOOT"), Err(Error::NotFound)));
+        assert!(matches!(
+            fs.open("\\EFI\\ubuntu\\shimx64.efi"),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(fs.open("\\README.TXT\\x"), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn test_not_iso9660() {
+        let disk = MemDisk::new(vec![0; 24 * BLOCK_SIZE]);
+        assert!(!is_iso9660(&disk));
+        assert!(matches!(Filesystem::new(&disk), Err(Error::BadSignature)));
+
+        let mut image = iso_image();
+        image[16 * BLOCK_SIZE + 128..16 * BLOCK_SIZE + 130].copy_from_slice(&512u16.to_le_bytes());
+        let disk = MemDisk::new(image);
+        assert!(matches!(Filesystem::new(&disk), Err(Error::Unsupported)));
+    }
+}
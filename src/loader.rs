@@ -13,12 +13,16 @@
 // limitations under the License.
 
 use crate::{
-    boot,
-    bzimage::{self, Kernel},
+    boot, bzimage,
     common::ascii_strip,
+    elf,
     fat::{self, Read},
+    fw_cfg, integrity, raw, summary, watchdog,
 };
 
+#[cfg(feature = "multiboot")]
+use crate::multiboot2;
+
 pub struct LoaderConfig {
     pub bzimage_path: [u8; 260],
     pub initrd_path: [u8; 260],
@@ -29,6 +33,13 @@ pub struct LoaderConfig {
 pub enum Error {
     FileError(fat::Error),
     BzImageError(bzimage::Error),
+    #[cfg(feature = "multiboot")]
+    Multiboot2Error(multiboot2::Error),
+    ElfError(elf::Error),
+    IntegrityError(integrity::Error),
+    RawError(raw::Error),
+    UnknownFormat,
+    UnsupportedFormat(Format),
 }
 
 impl From<fat::Error> for Error {
@@ -43,6 +54,155 @@ impl From<bzimage::Error> for Error {
     }
 }
 
+#[cfg(feature = "multiboot")]
+impl From<multiboot2::Error> for Error {
+    fn from(e: multiboot2::Error) -> Error {
+        Error::Multiboot2Error(e)
+    }
+}
+
+impl From<elf::Error> for Error {
+    fn from(e: elf::Error) -> Error {
+        Error::ElfError(e)
+    }
+}
+
+impl From<integrity::Error> for Error {
+    fn from(e: integrity::Error) -> Error {
+        Error::IntegrityError(e)
+    }
+}
+
+impl From<raw::Error> for Error {
+    fn from(e: raw::Error) -> Error {
+        Error::RawError(e)
+    }
+}
+
+// A kernel from a loader entry, booted with whichever protocol it supports.
+// There is only ever the one, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum Kernel {
+    BzImage(bzimage::Kernel),
+    #[cfg(feature = "multiboot")]
+    Multiboot2(multiboot2::Kernel),
+    Pvh(elf::Kernel),
+    Raw(raw::Kernel),
+}
+
+impl Kernel {
+    // Returns the SHA-256 hash of the initrd as it was loaded
+    fn load_initrd(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        Ok(match self {
+            Kernel::BzImage(kernel) => kernel.load_initrd(f)?,
+            #[cfg(feature = "multiboot")]
+            Kernel::Multiboot2(kernel) => kernel.load_initrd(f)?,
+            Kernel::Pvh(kernel) => kernel.load_initrd(f)?,
+            Kernel::Raw(kernel) => kernel.load_initrd(f)?,
+        })
+    }
+
+    fn append_cmdline(&mut self, addition: &[u8]) {
+        match self {
+            Kernel::BzImage(kernel) => kernel.append_cmdline(addition),
+            #[cfg(feature = "multiboot")]
+            Kernel::Multiboot2(kernel) => kernel.append_cmdline(addition),
+            Kernel::Pvh(kernel) => kernel.append_cmdline(addition),
+            Kernel::Raw(kernel) => kernel.append_cmdline(addition),
+        }
+    }
+
+    fn cmdline(&self) -> &[u8] {
+        match self {
+            Kernel::BzImage(kernel) => kernel.cmdline(),
+            #[cfg(feature = "multiboot")]
+            Kernel::Multiboot2(kernel) => kernel.cmdline(),
+            Kernel::Pvh(kernel) => kernel.cmdline(),
+            Kernel::Raw(kernel) => kernel.cmdline(),
+        }
+    }
+
+    pub fn boot(&mut self) {
+        watchdog::disarm();
+        match self {
+            Kernel::BzImage(kernel) => kernel.boot(),
+            #[cfg(feature = "multiboot")]
+            Kernel::Multiboot2(kernel) => kernel.boot(),
+            Kernel::Pvh(kernel) => kernel.boot(),
+            Kernel::Raw(kernel) => kernel.boot(),
+        }
+    }
+}
+
+// What a file holds, going by the magic numbers at its start
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    BzImage,
+    #[cfg(feature = "multiboot")]
+    Multiboot2,
+    Pe,
+    Elf,
+}
+
+// How much of a file the formats are told apart by, as the Multiboot2
+// header can be anywhere in it. Otherwise it's up to the end of the bzImage
+// header's magic number.
+#[cfg(feature = "multiboot")]
+const SNIFF_SIZE: usize = multiboot2::SEARCH_SIZE;
+#[cfg(not(feature = "multiboot"))]
+const SNIFF_SIZE: usize = 0x206;
+
+// A bzImage with an EFI stub is also a PE image, and a Multiboot2 kernel
+// can be a PE or ELF image too, so those two are checked for first
+fn sniff(data: &[u8]) -> Option<Format> {
+    if data.len() >= 0x206 && data[0x1fe..0x200] == [0x55, 0xaa] && &data[0x202..0x206] == b"HdrS" {
+        return Some(Format::BzImage);
+    }
+    #[cfg(feature = "multiboot")]
+    if multiboot2::has_header(data) {
+        return Some(Format::Multiboot2);
+    }
+    if data.starts_with(b"MZ") {
+        Some(Format::Pe)
+    } else if data.starts_with(b"\x7fELF") {
+        Some(Format::Elf)
+    } else {
+        None
+    }
+}
+
+pub fn format(f: &mut dyn Read) -> Result<Format, Error> {
+    let mut data = [0; SNIFF_SIZE];
+    let length = core::cmp::min(f.get_size() as usize, SNIFF_SIZE);
+    f.read_at(0, &mut data[..length])?;
+    sniff(&data[..length]).ok_or(Error::UnknownFormat)
+}
+
+// Loads the kernel in the file to be booted with the protocol its format
+// has, with the SHA-256 hash of the file as it was loaded. EFI applications
+// are started from the EFI environment instead.
+pub fn load(f: &mut dyn Read, info: &dyn boot::Info) -> Result<(Kernel, [u8; 32]), Error> {
+    match format(f)? {
+        Format::BzImage => {
+            let mut kernel = bzimage::Kernel::new(info);
+            let digest = kernel.load_kernel(f)?;
+            Ok((Kernel::BzImage(kernel), digest))
+        }
+        #[cfg(feature = "multiboot")]
+        Format::Multiboot2 => {
+            let mut kernel = multiboot2::Kernel::new(info);
+            let digest = kernel.load_kernel(f)?;
+            Ok((Kernel::Multiboot2(kernel), digest))
+        }
+        Format::Elf => {
+            let mut kernel = elf::Kernel::new(info);
+            let digest = kernel.load_kernel(f)?;
+            Ok((Kernel::Pvh(kernel), digest))
+        }
+        format => Err(Error::UnsupportedFormat(format)),
+    }
+}
+
 fn default_entry_file(f: &mut fat::File) -> Result<[u8; 260], fat::Error> {
     let mut data = [0; 4096];
     assert!(f.get_size() as usize <= data.len());
@@ -106,7 +266,48 @@ fn parse_entry(f: &mut fat::File) -> Result<LoaderConfig, fat::Error> {
     Ok(loader_config)
 }
 
+// Reads the first line of the file, anything after 4KiB is ignored
+fn first_line(f: &mut fat::File) -> Result<[u8; 4096], fat::Error> {
+    let mut data = [0; 4096];
+    let mut offset = 0;
+    while offset < data.len() {
+        match f.read(&mut data[offset..offset + 512]) {
+            Err(fat::Error::EndOfFile) => break,
+            Err(e) => return Err(e),
+            Ok(_) => {
+                offset += 512;
+            }
+        }
+    }
+
+    let mut line = [0; 4096];
+    let len = data
+        .iter()
+        .position(|&c| c == b'\n' || c == b'\r' || c == 0)
+        .unwrap_or_else(|| data.len());
+    line[0..len].copy_from_slice(&data[0..len]);
+    Ok(line)
+}
+
+const CMDLINE_PATH: &str = "/EFI/rhfw/cmdline";
+
+// The command line from CMDLINE_PATH, if that exists
+pub fn cmdline_file(fs: &fat::Filesystem) -> Result<Option<[u8; 4096]>, fat::Error> {
+    match fs.open(CMDLINE_PATH) {
+        Ok(fat::Node::File(mut f)) => Ok(Some(first_line(&mut f)?)),
+        Ok(_) | Err(fat::Error::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Says how to boot an entry's kernel that is in no format the loader knows
+const RAW_CONFIG_PATH: &str = "/EFI/rhfw/rawboot.conf";
+
 const ENTRY_DIRECTORY: &str = "/loader/entries/";
+// Used when the entry doesn't name an initrd
+const DEFAULT_INITRD_PATH: &str = "/initrd.img";
+// What an initrd given to QEMU with -initrd is listed as in the manifest
+const FW_CFG_INITRD_NAME: &str = "fw_cfg:initrd";
 
 fn default_entry_path(fs: &fat::Filesystem) -> Result<[u8; 260], fat::Error> {
     let mut f = match fs.open("/loader/loader.conf")? {
@@ -124,6 +325,133 @@ fn default_entry_path(fs: &fat::Filesystem) -> Result<[u8; 260], fat::Error> {
     Ok(entry_path)
 }
 
+// Checks the hash of a file, as it was loaded, against the manifest, if
+// there is one
+fn check_file(
+    manifest: &Option<integrity::Manifest>,
+    path: &str,
+    digest: &[u8; 32],
+) -> Result<(), Error> {
+    match manifest {
+        Some(manifest) => Ok(manifest.check(path, digest)?),
+        None => Ok(()),
+    }
+}
+
+// QEMU's -initrd, which takes the place of any on the filesystem. It's
+// listed in the manifest as FW_CFG_INITRD_NAME.
+fn fw_cfg_initrd() -> Option<fw_cfg::File> {
+    let f = fw_cfg::FwCfg::probe().and_then(|fw_cfg| fw_cfg.initrd())?;
+    summary::file(FW_CFG_INITRD_NAME, f.get_size());
+    Some(f)
+}
+
+// The raw boot config on the filesystem, if there is one. Where the kernel
+// goes and what it's given is as much a part of what is booted as the
+// kernel, so it has to be in the manifest too.
+fn raw_config(
+    fs: &fat::Filesystem,
+    manifest: &Option<integrity::Manifest>,
+) -> Result<Option<raw::Config>, Error> {
+    let mut f = match fs.open(RAW_CONFIG_PATH) {
+        Ok(fat::Node::File(f)) => f,
+        Ok(_) | Err(fat::Error::NotFound) => return Ok(None),
+        Err(e) => return Err(Error::FileError(e)),
+    };
+    summary::file(RAW_CONFIG_PATH, f.get_size());
+    let length = f.get_size() as usize;
+    if length > raw::MAX_CONFIG_SIZE {
+        return Err(Error::RawError(raw::Error::InvalidConfig));
+    }
+    let mut data = [0; raw::MAX_CONFIG_SIZE];
+    f.read_at(0, &mut data[..length])?;
+    if let Some(manifest) = manifest {
+        manifest.check_data(RAW_CONFIG_PATH, &data[..length])?;
+    }
+    Ok(Some(raw::Config::parse(&data[..length])?))
+}
+
+fn check_cmdline(manifest: &Option<integrity::Manifest>, cmdline: &[u8]) -> Result<(), Error> {
+    match manifest {
+        Some(manifest) => Ok(manifest.check_cmdline(cmdline)?),
+        None => Ok(()),
+    }
+}
+
+// Loads the bzImage at the path as a kernel to be started through the EFI
+// handover entry, if it has that entry
+pub fn load_efi_stub(
+    fs: &fat::Filesystem,
+    f: &mut dyn Read,
+    path: &str,
+    info: &dyn boot::Info,
+) -> Result<Option<bzimage::Kernel>, Error> {
+    if boot::Header::from_file(f)?.efi_handover_offset().is_none() {
+        return Ok(None);
+    }
+
+    let manifest = integrity::Manifest::load(fs)?;
+    let mut kernel = bzimage::Kernel::new(info);
+    check_file(&manifest, path, &kernel.load_kernel(f)?)?;
+
+    if let Some(mut initrd_file) = fw_cfg_initrd() {
+        let digest = kernel.load_initrd(&mut initrd_file)?;
+        check_file(&manifest, FW_CFG_INITRD_NAME, &digest)?;
+    } else {
+        match fs.open(DEFAULT_INITRD_PATH) {
+            Ok(mut initrd_file) => {
+                summary::file(DEFAULT_INITRD_PATH, initrd_file.get_size());
+                let digest = kernel.load_initrd(&mut initrd_file)?;
+                check_file(&manifest, DEFAULT_INITRD_PATH, &digest)?;
+            }
+            Err(fat::Error::NotFound) => {}
+            Err(e) => return Err(Error::FileError(e)),
+        }
+    }
+
+    kernel.append_cmdline(info.cmdline());
+    if let Some(cmdline) = cmdline_file(fs)? {
+        kernel.append_cmdline(ascii_strip(&cmdline).trim().as_bytes());
+    }
+    check_cmdline(&manifest, kernel.cmdline())?;
+
+    Ok(Some(kernel))
+}
+
+// The kernel handed over through fw_cfg, if there is one, with its initrd
+// and the command line. There is no manifest to check them against.
+pub fn load_fw_cfg(info: &dyn boot::Info) -> Result<Option<Kernel>, Error> {
+    let mut f = match fw_cfg::FwCfg::probe().and_then(|fw_cfg| fw_cfg.kernel()) {
+        Some(f) => f,
+        None => return Ok(None),
+    };
+    summary::file("fw_cfg:kernel", f.get_size());
+    let (mut kernel, _) = load(&mut f, info)?;
+    if let Some(mut initrd_file) = fw_cfg_initrd() {
+        kernel.load_initrd(&mut initrd_file)?;
+    }
+    kernel.append_cmdline(info.cmdline());
+    Ok(Some(kernel))
+}
+
+// The kernel the no-disk feature builds in
+#[cfg(feature = "no-disk")]
+static EMBEDDED_KERNEL: &[u8] = include_bytes!(env!("RHFW_KERNEL"));
+
+// The built-in kernel, with the initrd and command line as for one from
+// fw_cfg
+#[cfg(feature = "no-disk")]
+pub fn load_embedded(info: &dyn boot::Info) -> Result<Kernel, Error> {
+    let mut f = crate::efi::BufferFile::new(EMBEDDED_KERNEL);
+    summary::file("embedded:kernel", f.get_size());
+    let (mut kernel, _) = load(&mut f, info)?;
+    if let Some(mut initrd_file) = fw_cfg_initrd() {
+        kernel.load_initrd(&mut initrd_file)?;
+    }
+    kernel.append_cmdline(info.cmdline());
+    Ok(kernel)
+}
+
 pub fn load_default_entry(fs: &fat::Filesystem, info: &dyn boot::Info) -> Result<Kernel, Error> {
     let default_entry_path = default_entry_path(&fs)?;
     let default_entry_path = ascii_strip(&default_entry_path);
@@ -138,32 +466,136 @@ pub fn load_default_entry(fs: &fat::Filesystem, info: &dyn boot::Info) -> Result
     let initrd_path = ascii_strip(&entry.initrd_path);
     let cmdline = ascii_strip(&entry.cmdline);
 
-    let mut kernel = Kernel::new(info);
-
+    let manifest = integrity::Manifest::load(fs)?;
     let mut bzimage_file = fs.open(bzimage_path)?;
-    kernel.load_kernel(&mut bzimage_file)?;
+    summary::file(bzimage_path, bzimage_file.get_size());
+    let (mut kernel, digest) = match load(&mut bzimage_file, info) {
+        Err(Error::UnknownFormat) => match raw_config(fs, &manifest)? {
+            Some(config) => {
+                let mut kernel = raw::Kernel::new(info, config);
+                let digest = kernel.load_kernel(&mut bzimage_file)?;
+                (Kernel::Raw(kernel), digest)
+            }
+            None => return Err(Error::UnknownFormat),
+        },
+        result => result?,
+    };
+    check_file(&manifest, bzimage_path, &digest)?;
 
-    if !initrd_path.is_empty() {
+    if let Some(mut initrd_file) = fw_cfg_initrd() {
+        let digest = kernel.load_initrd(&mut initrd_file)?;
+        check_file(&manifest, FW_CFG_INITRD_NAME, &digest)?;
+    } else if !initrd_path.is_empty() {
         let mut initrd_file = fs.open(initrd_path)?;
-        kernel.load_initrd(&mut initrd_file)?;
+        summary::file(initrd_path, initrd_file.get_size());
+        let digest = kernel.load_initrd(&mut initrd_file)?;
+        check_file(&manifest, initrd_path, &digest)?;
+    } else {
+        match fs.open(DEFAULT_INITRD_PATH) {
+            Ok(mut initrd_file) => {
+                summary::file(DEFAULT_INITRD_PATH, initrd_file.get_size());
+                let digest = kernel.load_initrd(&mut initrd_file)?;
+                check_file(&manifest, DEFAULT_INITRD_PATH, &digest)?;
+            }
+            Err(fat::Error::NotFound) => {}
+            Err(e) => return Err(Error::FileError(e)),
+        }
     }
 
     kernel.append_cmdline(info.cmdline());
-    kernel.append_cmdline(cmdline.as_bytes());
+    // The command line file takes the place of the entry's options
+    match cmdline_file(fs)? {
+        Some(cmdline) => kernel.append_cmdline(ascii_strip(&cmdline).trim().as_bytes()),
+        None => kernel.append_cmdline(cmdline.as_bytes()),
+    }
+    check_cmdline(&manifest, kernel.cmdline())?;
 
     Ok(kernel)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::fat::tests::{Dir, ImageBuilder};
     use crate::fat::Read;
     use crate::part::tests::FakeDisk;
     use core::convert::TryInto;
 
+    // The start of a file with the magic number at the offset
+    fn fixture(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 0x400];
+        data[offset..offset + magic.len()].copy_from_slice(magic);
+        data
+    }
+
+    #[test]
+    fn test_format() {
+        use super::{sniff, Format};
+
+        let mut bzimage = fixture(0x1fe, b"\x55\xaa\xeb\x66HdrS");
+        assert_eq!(sniff(&bzimage), Some(Format::BzImage));
+        // With an EFI stub
+        bzimage[0..2].copy_from_slice(b"MZ");
+        assert_eq!(sniff(&bzimage), Some(Format::BzImage));
+        // Without the boot flag it's just a PE image
+        bzimage[0x1fe] = 0;
+        assert_eq!(sniff(&bzimage), Some(Format::Pe));
+
+        assert_eq!(sniff(&fixture(0, b"\x7fELF\x02\x01")), Some(Format::Elf));
+        assert_eq!(sniff(b"MZ"), Some(Format::Pe));
+        assert_eq!(sniff(&fixture(0, b"\x1f\x8b")), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    #[cfg(feature = "multiboot")]
+    fn test_format_multiboot2() {
+        use super::{sniff, Format};
+
+        let magic: u32 = 0xe852_50d6;
+        let mut header = Vec::new();
+        for field in &[magic, 0, 24, 0u32.wrapping_sub(magic + 24), 0, 8] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        let mut multiboot2 = fixture(0x100, &header);
+        assert_eq!(sniff(&multiboot2), Some(Format::Multiboot2));
+        multiboot2[0..4].copy_from_slice(b"\x7fELF");
+        assert_eq!(sniff(&multiboot2), Some(Format::Multiboot2));
+    }
+
+    #[test]
+    fn test_cmdline_file() {
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT16);
+        let efi = builder.add_dir(Dir::Root, b"EFI        ");
+        let rhfw = builder.add_dir(efi, b"RHFW       ");
+        builder.add_file(
+            rhfw,
+            b"CMDLINE    ",
+            b"console=ttyS0 root=/dev/vda1\nignored\n",
+        );
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+
+        let cmdline = super::cmdline_file(&fs).unwrap().unwrap();
+        assert_eq!(super::ascii_strip(&cmdline), "console=ttyS0 root=/dev/vda1");
+    }
+
+    #[test]
+    fn test_cmdline_file_missing() {
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT16);
+        builder.add_dir(Dir::Root, b"EFI        ");
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+
+        assert!(super::cmdline_file(&fs).unwrap().is_none());
+    }
+
     #[test]
     fn test_default_entry() {
         let d = FakeDisk::new("clear-28660-kvm.img");
-        let (start, end) = crate::part::find_efi_partition(&d).unwrap();
+        let (start, end, _) =
+            crate::part::find_efi_partition(&d, crate::part::PartitionSelector::First).unwrap();
         let mut fs = crate::fat::Filesystem::new(&d, start, end);
         fs.init().expect("Error initialising filesystem");
 
@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The CPUs and interrupt controllers described by the ACPI MADT ("APIC"
+// table). We only ever run on the boot CPU, but the others are counted for
+// whatever gets booted.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::acpi;
+
+// Where the entries start, after the local APIC address and flags
+const ENTRIES_START: usize = 44;
+
+// Entry types
+const LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_X2APIC: u8 = 9;
+
+const LOCAL_APIC_ENABLED: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+pub struct Madt<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Madt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Madt<'a>, acpi::Error> {
+        if data.len() < ENTRIES_START {
+            return Err(acpi::Error::InvalidLength);
+        }
+        if &data[0..4] != b"APIC" {
+            return Err(acpi::Error::InvalidSignature);
+        }
+        Ok(Madt { data })
+    }
+
+    // The type and contents of each entry, stopping at any that doesn't fit
+    fn entries(&self) -> impl Iterator<Item = (u8, &'a [u8])> {
+        let data = self.data;
+        let mut offset = ENTRIES_START;
+        core::iter::from_fn(move || {
+            if offset + 2 > data.len() {
+                return None;
+            }
+            let length = usize::from(data[offset + 1]);
+            if length < 2 || offset + length > data.len() {
+                return None;
+            }
+            let entry = &data[offset..offset + length];
+            offset += length;
+            Some((entry[0], entry))
+        })
+    }
+
+    // The 32-bit address in the header, unless an entry overrides it. Only
+    // logged.
+    #[cfg_attr(not(feature = "log-serial"), allow(dead_code))]
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find(|(kind, entry)| *kind == LOCAL_APIC_ADDRESS_OVERRIDE && entry.len() >= 12)
+            .map_or(u64::from(read_u32(self.data, 36)), |(_, entry)| {
+                read_u64(entry, 4)
+            })
+    }
+
+    // Both the xAPIC and x2APIC entries
+    pub fn local_apics(&self) -> impl Iterator<Item = LocalApic> + 'a {
+        self.entries().filter_map(|(kind, entry)| match kind {
+            LOCAL_APIC if entry.len() >= 8 => Some(LocalApic {
+                processor_uid: u32::from(entry[2]),
+                apic_id: u32::from(entry[3]),
+                enabled: read_u32(entry, 4) & LOCAL_APIC_ENABLED != 0,
+            }),
+            LOCAL_X2APIC if entry.len() >= 16 => Some(LocalApic {
+                processor_uid: read_u32(entry, 12),
+                apic_id: read_u32(entry, 4),
+                enabled: read_u32(entry, 8) & LOCAL_APIC_ENABLED != 0,
+            }),
+            _ => None,
+        })
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApic> + 'a {
+        self.entries().filter_map(|(kind, entry)| match kind {
+            IO_APIC if entry.len() >= 12 => Some(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            _ => None,
+        })
+    }
+}
+
+// The one we're running on, until the MADT says otherwise
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+// The number of enabled CPUs
+#[cfg_attr(not(feature = "log-serial"), allow(dead_code))]
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+fn find_madt(rsdp: u64) -> Result<Madt<'static>, acpi::Error> {
+    Madt::new(acpi::table(acpi::find_table(rsdp, b"APIC")?)?)
+}
+
+pub fn init(rsdp: u64) {
+    if rsdp == 0 {
+        return;
+    }
+    let madt = match find_madt(rsdp) {
+        Ok(madt) => madt,
+        Err(acpi::Error::NotFound) => return,
+        Err(e) => {
+            log!("Error reading MADT: {:?}", e);
+            return;
+        }
+    };
+    let count = madt.local_apics().filter(|apic| apic.enabled).count();
+    if count != 0 {
+        CPU_COUNT.store(count, Ordering::SeqCst);
+    }
+    log!(
+        "Found {} CPUs, with local APICs at {:#x}",
+        cpu_count(),
+        madt.local_apic_address()
+    );
+    for io_apic in madt.io_apics() {
+        log!(
+            "I/O APIC {} at {:#x} for GSIs from {}",
+            io_apic.id,
+            io_apic.address,
+            io_apic.gsi_base
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoApic, LocalApic, Madt};
+
+    fn make_madt(entries: &[&[u8]]) -> Vec<u8> {
+        let mut data = vec![0u8; 36];
+        data[0..4].copy_from_slice(b"APIC");
+        data.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        for entry in entries {
+            data.extend_from_slice(entry);
+        }
+        let length = data.len() as u32;
+        data[4..8].copy_from_slice(&length.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_madt() {
+        let data = make_madt(&[
+            &[0, 8, 0, 0, 1, 0, 0, 0],
+            &[1, 12, 2, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0],
+            &[0, 8, 1, 1, 0, 0, 0, 0],
+        ]);
+        let madt = Madt::new(&data).unwrap();
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+        let apics: Vec<LocalApic> = madt.local_apics().collect();
+        assert_eq!(
+            apics,
+            [
+                LocalApic {
+                    processor_uid: 0,
+                    apic_id: 0,
+                    enabled: true,
+                },
+                LocalApic {
+                    processor_uid: 1,
+                    apic_id: 1,
+                    enabled: false,
+                },
+            ]
+        );
+        assert_eq!(madt.local_apics().filter(|apic| apic.enabled).count(), 1);
+        let io_apics: Vec<IoApic> = madt.io_apics().collect();
+        assert_eq!(
+            io_apics,
+            [IoApic {
+                id: 2,
+                address: 0xfec0_0000,
+                gsi_base: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_madt_x2apic_and_override() {
+        let data = make_madt(&[
+            &[9, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 0x10, 0, 0, 0],
+            &[5, 12, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0],
+            // Cut short, so it and anything after it are ignored
+            &[0, 8, 2, 2, 1, 0],
+        ]);
+        let madt = Madt::new(&data).unwrap();
+        assert_eq!(madt.local_apic_address(), 0x1_0000_0000);
+        let apics: Vec<LocalApic> = madt.local_apics().collect();
+        assert_eq!(
+            apics,
+            [LocalApic {
+                processor_uid: 0x10,
+                apic_id: 0x100,
+                enabled: true,
+            }]
+        );
+
+        assert!(Madt::new(&data[..40]).is_err());
+        assert!(Madt::new(&make_madt(&[])[..]).is_ok());
+    }
+}
@@ -22,10 +22,9 @@
 
 use core::panic::PanicInfo;
 
-use x86_64::{
-    instructions::hlt,
-    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
-};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+use crate::fat::Read;
 
 #[macro_use]
 mod serial;
@@ -33,41 +32,74 @@ mod serial;
 #[macro_use]
 mod common;
 
-#[cfg(not(test))]
+mod acpi;
+mod apic;
 mod asm;
 mod block;
+#[cfg(feature = "gop")]
+mod bochs;
 mod boot;
 mod bzimage;
 mod coreboot;
 mod delay;
+#[cfg(feature = "net-boot")]
+mod dhcp;
 mod efi;
+mod elf;
 mod fat;
+mod fw_cfg;
 mod gdt;
 #[cfg(all(test, feature = "integration_tests"))]
 mod integration;
+mod integrity;
+mod interrupts;
+#[cfg(feature = "net-boot")]
+mod ip;
+mod iso9660;
 mod loader;
+mod madt;
 mod mem;
+#[cfg(feature = "boot-menu")]
+mod menu;
+mod mmio;
+#[cfg(feature = "multiboot")]
+mod multiboot2;
+#[cfg(feature = "network")]
+mod net;
+#[cfg(feature = "nvme")]
+mod nvme;
 mod paging;
+mod panic;
 mod part;
 mod pci;
 mod pe;
+#[cfg(any(target_arch = "aarch64", test))]
+mod pl011;
 mod pvh;
+mod raw;
+mod reset;
 mod rtc;
+mod sha256;
+#[cfg(feature = "diag-shell")]
+mod shell;
+mod smbios;
+mod summary;
+#[cfg(feature = "net-boot")]
+mod tftp;
+mod tpm;
 mod virtio;
+mod watchdog;
 
 #[cfg(all(not(test), feature = "log-panic"))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    log!("PANIC: {}", info);
-    loop {
-        hlt()
-    }
+    panic::handle(info)
 }
 
 #[cfg(all(not(test), not(feature = "log-panic")))]
 #[panic_handler]
 fn panic(_: &PanicInfo) -> ! {
-    loop {}
+    panic::finish()
 }
 
 // Enable SSE2 for XMM registers (needed for EFI calling)
@@ -82,53 +114,455 @@ fn enable_sse() {
     unsafe { Cr4::write(cr4) };
 }
 
-const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
-const VIRTIO_PCI_BLOCK_DEVICE_ID: u16 = 0x1042;
+#[cfg(feature = "network")]
+const VIRTIO_PCI_NET_DEVICE_ID: u16 = 0x1041;
+#[cfg(feature = "network")]
+const VIRTIO_PCI_TRANSITIONAL_NET_DEVICE_ID: u16 = 0x1000;
+
+// The removable media path, used when no boot option picks another loader
+const DEFAULT_EFI_PATH: &str = "\\EFI\\BOOT\\BOOTX64.EFI";
 
-fn boot_from_device(device: &mut block::VirtioBlockDevice, info: &dyn boot::Info) -> bool {
+// Where images booted over the network are downloaded to, out of the way
+// of where they are loaded, and the most that is downloaded
+#[cfg(feature = "net-boot")]
+const DOWNLOAD_ADDRESS: u64 = 0x400_0000;
+#[cfg(feature = "net-boot")]
+const MAX_DOWNLOAD_SIZE: u64 = 0x1000_0000;
+
+#[cfg(feature = "network")]
+fn boot_from_net(device: &mut net::VirtioNetDevice, info: &dyn boot::Info) -> bool {
     if let Err(err) = device.init() {
-        log!("Error configuring block device: {:?}", err);
+        log!("Error configuring net device: {:?}", err);
         return false;
     }
+    let mac = device.mac();
     log!(
-        "Virtio block device configured. Capacity: {} sectors",
-        device.get_capacity()
+        "Virtio net device configured. MAC: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mac[0],
+        mac[1],
+        mac[2],
+        mac[3],
+        mac[4],
+        mac[5]
     );
+    net_boot(device, info)
+}
+
+// Without net-boot the device is only set up to see that it works, and reset
+// again once dropped
+#[cfg(all(feature = "network", not(feature = "net-boot")))]
+fn net_boot(_: &net::VirtioNetDevice, _: &dyn boot::Info) -> bool {
+    false
+}
+
+// The RAM from DOWNLOAD_ADDRESS on, up to MAX_DOWNLOAD_SIZE of it
+#[cfg(feature = "net-boot")]
+fn download_region(info: &dyn boot::Info) -> Option<mem::MemoryRegion> {
+    let entry = (0..info.num_entries()).map(|i| info.entry(i)).find(|e| {
+        e.entry_type == boot::E820Entry::RAM_TYPE
+            && e.addr <= DOWNLOAD_ADDRESS
+            && DOWNLOAD_ADDRESS < e.addr + e.size
+    })?;
+    // We can only write to memory that is identity mapped
+    let end = core::cmp::min(entry.addr + entry.size, paging::mapped_size());
+    let end = core::cmp::min(end, DOWNLOAD_ADDRESS + MAX_DOWNLOAD_SIZE);
+    Some(mem::MemoryRegion::new(
+        DOWNLOAD_ADDRESS,
+        end - DOWNLOAD_ADDRESS,
+    ))
+}
 
-    let (start, end) = match part::find_efi_partition(device) {
-        Ok(p) => p,
+// Downloads the boot file DHCP names and runs it as an EFI application, as
+// PXE would
+#[cfg(feature = "net-boot")]
+fn net_boot(device: &net::VirtioNetDevice, info: &dyn boot::Info) -> bool {
+    let mut interface = ip::Interface::new(device);
+    let lease = match dhcp::request_lease(&interface) {
+        Some(lease) => lease,
+        None => {
+            log!("No DHCP lease");
+            return false;
+        }
+    };
+    log!("DHCP lease: {}", lease);
+    interface.ip = lease.address;
+    interface.subnet_mask = lease.subnet_mask.unwrap_or(ip::Ipv4Address::UNSPECIFIED);
+    interface.router = lease.router;
+
+    let name = match core::str::from_utf8(lease.boot_file()) {
+        Ok("") => {
+            log!("No boot file to download");
+            return false;
+        }
+        Ok(name) => name,
+        Err(_) => {
+            log!("Boot file name isn't valid UTF-8");
+            return false;
+        }
+    };
+    let mut region = match download_region(info) {
+        Some(region) => region,
+        None => {
+            log!("No memory to download {} to", name);
+            return false;
+        }
+    };
+    let size = match tftp::download(
+        &interface,
+        lease.next_server,
+        lease.boot_file(),
+        region.as_bytes(),
+    ) {
+        Ok(size) => size,
+        Err(err) => {
+            log!("Failed to download {}: {:?}", name, err);
+            return false;
+        }
+    };
+    log!(
+        "Downloaded {} ({} bytes) from {}",
+        name,
+        size,
+        lease.next_server
+    );
+
+    let data = &region.as_bytes()[..size];
+    let mut file = efi::BufferFile::new(data);
+    let mut l = pe::Loader::new(&mut file);
+    let load_addr = 0x20_0000;
+    match l.image_size() {
+        Ok(image_size) if load_addr + image_size <= DOWNLOAD_ADDRESS => {}
+        Ok(_) => {
+            log!("Executable is too big to load");
+            return false;
+        }
+        Err(err) => {
+            log!("Error loading executable: {:?}", err);
+            return false;
+        }
+    }
+    let (entry_addr, load_addr, size) = match l.load(load_addr) {
+        Ok(load_info) => load_info,
         Err(err) => {
-            log!("Failed to find EFI partition: {:?}", err);
+            log!("Error loading executable: {:?}", err);
             return false;
         }
     };
-    log!("Found EFI partition");
 
+    log!("Executable loaded");
+    efi::efi_exec_buffer(entry_addr, load_addr, size, info, name, data, device);
+    true
+}
+
+// Like QEMU's direct kernel boot
+fn boot_from_fw_cfg(info: &dyn boot::Info) -> bool {
+    match loader::load_fw_cfg(info) {
+        Ok(Some(mut kernel)) => {
+            log!("Jumping to kernel from fw_cfg");
+            kernel.boot();
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            log!("Error loading kernel from fw_cfg: {:?}", err);
+            false
+        }
+    }
+}
+
+// The kernel built in with the no-disk feature, which is only booted when
+// there are no disks
+#[cfg(feature = "no-disk")]
+fn boot_embedded(info: &dyn boot::Info) -> bool {
+    if !block::enumerate().as_slice().is_empty() {
+        return false;
+    }
+    log!("No disks, booting the built-in kernel");
+    match loader::load_embedded(info) {
+        Ok(mut kernel) => {
+            log!("Jumping to built-in kernel");
+            kernel.boot();
+            true
+        }
+        Err(err) => {
+            log!("Error loading built-in kernel: {:?}", err);
+            false
+        }
+    }
+}
+
+// Sets up the disk at location and calls f with it, if that works
+fn with_disk<F>(location: block::Location, f: F) -> bool
+where
+    F: FnOnce(&dyn block::BlockDevice) -> bool,
+{
+    match location {
+        block::Location::VirtioPci(bus, device, func) => {
+            let pci_device = pci::PciDevice::new(bus, device, func);
+            let mut pci_transport = pci::VirtioPciTransport::new(pci_device);
+            let mut device = block::VirtioBlockDevice::new(&mut pci_transport);
+            with_virtio(&mut device, f)
+        }
+        #[cfg(feature = "nvme")]
+        block::Location::Nvme(bus, device, func) => {
+            let mut device = nvme::NvmeDevice::new(pci::PciDevice::new(bus, device, func));
+            if let Err(err) = device.init() {
+                log!("Error configuring NVMe device: {:?}", err);
+                return false;
+            }
+            f(&device)
+        }
+        block::Location::VirtioMmio(address) => {
+            let mut mmio_transport = mmio::VirtioMmioTransport::at(address);
+            let mut device = block::VirtioBlockDevice::new(&mut mmio_transport);
+            with_virtio(&mut device, f)
+        }
+    }
+}
+
+fn with_virtio<F>(device: &mut block::VirtioBlockDevice, f: F) -> bool
+where
+    F: FnOnce(&dyn block::BlockDevice) -> bool,
+{
+    if let Err(err) = device.init() {
+        log!("Error configuring block device: {:?}", err);
+        return false;
+    }
+    f(device)
+}
+
+// Boots from the first disk that works out of those found, in the order
+// block::boot_order gives. Each disk is looked at first to see if it has an
+// EFI System partition.
+fn boot_from_disks(info: &dyn boot::Info) -> bool {
+    let disks = block::enumerate();
+    let disks = disks.as_slice();
+    if disks.is_empty() {
+        log!("No disks found");
+        return false;
+    }
+    let mut has_esp = [false; block::MAX_DISKS];
+    for (i, location) in disks.iter().enumerate() {
+        has_esp[i] = with_disk(*location, |device| {
+            let esp = part::has_efi_partition(&block::CachedBlock::new(device));
+            log!(
+                "Disk {}: {}, {} sectors{}",
+                i,
+                location,
+                device.get_capacity(),
+                if esp { ", EFI System partition" } else { "" }
+            );
+            esp
+        });
+    }
+
+    let preferred = block::boot_disk(info.cmdline());
+    let mut order = block::boot_order(&has_esp[..disks.len()], preferred);
+    order.any(|i| {
+        log!("Booting from disk {}", i);
+        with_disk(disks[i], |device| boot_from_device(device, info))
+    })
+}
+
+// The devices and disks for the diagnostic shell
+#[cfg(feature = "diag-shell")]
+struct Diagnostics(block::Disks);
+
+#[cfg(feature = "diag-shell")]
+impl shell::Machine for Diagnostics {
+    fn devices(&self, out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        let mut result = Ok(());
+        pci::scan(|bus, device, func| {
+            let (vendor_id, device_id) = pci::get_device_details(bus, device, func);
+            let (class, subclass) = pci::get_class_details(bus, device, func);
+            result = writeln!(
+                out,
+                "PCI {}:{}.{}: vendor={:04x} device={:04x} class={:02x}:{:02x}",
+                bus, device, func, vendor_id, device_id, class, subclass
+            );
+            result.is_err()
+        });
+        result?;
+        for (i, location) in self.0.as_slice().iter().enumerate() {
+            writeln!(out, "Disk {}: {}", i, location)?;
+        }
+        Ok(())
+    }
+
+    fn with_disk(&self, index: usize, f: &mut dyn FnMut(&dyn block::SectorRead, u64)) -> bool {
+        match self.0.as_slice().get(index) {
+            Some(location) => with_disk(*location, |device| {
+                f(&block::CachedBlock::new(device), device.get_capacity());
+                true
+            }),
+            None => false,
+        }
+    }
+}
+
+// Tries the EFI partition first and then, as a fallback, the other partitions
+// on the disk
+fn boot_from_device(device: &dyn block::BlockDevice, info: &dyn boot::Info) -> bool {
+    let device = block::CachedBlock::new(device);
+
+    match part::with_partitions(
+        &device,
+        part::boot_partition(info.cmdline()),
+        |start, end, name| {
+            log!(
+                "Trying partition \"{}\" (LBA {}-{})",
+                name.as_str(),
+                start,
+                end
+            );
+            summary::partition(device.device().get_capacity(), start, end, name.as_str());
+            boot_from_partition(&device, start, end, info)
+        },
+    ) {
+        Ok(booted) => booted,
+        // Only an ISO attached as is, so that it's never booted in place of a
+        // partition that wasn't
+        Err(part::Error::BadSignature) if iso9660::is_iso9660(&device) => {
+            boot_from_iso9660(&device, info)
+        }
+        Err(err) => {
+            log!("Failed to read partitions: {:?}", err);
+            false
+        }
+    }
+}
+
+fn boot_from_iso9660(
+    device: &block::CachedBlock<dyn block::BlockDevice + '_>,
+    info: &dyn boot::Info,
+) -> bool {
+    let fs = match iso9660::Filesystem::new(device) {
+        Ok(fs) => fs,
+        Err(err) => {
+            log!("Failed to read ISO9660 filesystem: {:?}", err);
+            return false;
+        }
+    };
+    log!("ISO9660 filesystem ready");
+    // Nothing on an ISO is checked against a manifest, so one that has a
+    // manifest isn't booted
+    if fs.open(integrity::MANIFEST_PATH).is_ok() {
+        log!("Not booting ISO with {}", integrity::MANIFEST_PATH);
+        return false;
+    }
+
+    let path = DEFAULT_EFI_PATH;
+    let mut file = match fs.open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            log!("Failed to load EFI binary {}: {:?}", path, err);
+            return false;
+        }
+    };
+    log!("Found bootloader ({})", path);
+    summary::file(path, file.get_size());
+
+    let mut l = pe::Loader::new(&mut file);
+    let (entry_addr, load_addr, size) = match l.load(0x20_0000) {
+        Ok(load_info) => load_info,
+        Err(err) => {
+            log!("Error loading executable: {:?}", err);
+            return false;
+        }
+    };
+
+    log!("Executable loaded");
+    efi::efi_exec_iso9660(entry_addr, load_addr, size, info, path, &fs, device);
+    true
+}
+
+fn boot_from_partition(
+    device: &block::CachedBlock<dyn block::BlockDevice + '_>,
+    start: u64,
+    end: u64,
+    info: &dyn boot::Info,
+) -> bool {
     let mut f = fat::Filesystem::new(device, start, end);
+    f.set_writer(device);
     if let Err(err) = f.init() {
         log!("Failed to create filesystem: {:?}", err);
         return false;
     }
     log!("Filesystem ready");
+    summary::filesystem(&f);
+    // The command line file can set the boot timeout, what happens on a panic
+    // and the boot menu timeout too
+    if let Ok(Some(cmdline)) = loader::cmdline_file(&f) {
+        let cmdline = common::ascii_strip(&cmdline).as_bytes();
+        watchdog::configure(cmdline);
+        panic::configure(cmdline);
+        #[cfg(feature = "boot-menu")]
+        menu::configure(cmdline);
+    }
 
     match loader::load_default_entry(&f, info) {
         Ok(mut kernel) => {
+            log!(
+                "Block cache hits: {} device requests: {}",
+                device.hits(),
+                device.device().request_count()
+            );
             log!("Jumping to kernel");
             kernel.boot();
             return true;
         }
+        // What the manifest turned down isn't then booted some other way
+        Err(err @ loader::Error::IntegrityError(_)) => {
+            log!("Error loading default entry: {:?}", err);
+            return false;
+        }
         Err(err) => log!("Error loading default entry: {:?}", err),
     }
 
     log!("Using EFI boot.");
-    let mut file = match f.open("/EFI/BOOT/BOOTX64 EFI") {
+    // What the boot options from the variables pick, if there are any
+    let mut buffer = [0; 256];
+    let path = efi::boot_option_path(&f, start, &mut buffer).unwrap_or(DEFAULT_EFI_PATH);
+    #[cfg(feature = "boot-menu")]
+    let mut menu_buffer = [0; menu::MAX_PATH];
+    #[cfg(feature = "boot-menu")]
+    let path = menu::choose(&f, start, path, &mut menu_buffer);
+    let mut file = match f.open(path) {
         Ok(file) => file,
         Err(err) => {
-            log!("Failed to load default EFI binary: {:?}", err);
+            log!("Failed to load EFI binary {}: {:?}", path, err);
             return false;
         }
     };
-    log!("Found bootloader (BOOTX64.EFI)");
+    log!("Found bootloader ({})", path);
+    summary::file(path, file.get_size());
+
+    // A bzImage with an EFI stub is a PE image too, but is started through
+    // its EFI handover entry if it has one
+    match loader::format(&mut file) {
+        Ok(loader::Format::BzImage) => match loader::load_efi_stub(&f, &mut file, path, info) {
+            Ok(Some(mut kernel)) => {
+                log!("Found Linux kernel with EFI handover ({})", path);
+                efi::efi_handover(&mut kernel, info, path, &f, device);
+                return true;
+            }
+            Ok(None) => {}
+            Err(err @ loader::Error::IntegrityError(_)) => {
+                log!("Error loading EFI stub kernel: {:?}", err);
+                return false;
+            }
+            Err(err) => log!("Error loading EFI stub kernel: {:?}", err),
+        },
+        Ok(loader::Format::Pe) => {}
+        Ok(format) => {
+            log!("Not an EFI application ({}): {:?}", path, format);
+            return false;
+        }
+        Err(err) => {
+            log!("Not an EFI application ({}): {:?}", path, err);
+            return false;
+        }
+    }
 
     let mut l = pe::Loader::new(&mut file);
     let load_addr = 0x20_0000;
@@ -141,25 +575,49 @@ fn boot_from_device(device: &mut block::VirtioBlockDevice, info: &dyn boot::Info
     };
 
     log!("Executable loaded");
-    efi::efi_exec(entry_addr, load_addr, size, info, &f, device);
+    log!(
+        "Block cache hits: {} device requests: {}",
+        device.hits(),
+        device.device().request_count()
+    );
+    efi::efi_exec(entry_addr, load_addr, size, info, path, &f, device);
     true
 }
 
 #[no_mangle]
 #[cfg(not(feature = "coreboot"))]
-pub extern "C" fn rust64_start(rdi: &pvh::StartInfo) -> ! {
-    serial::PORT.borrow_mut().init();
+pub extern "C" fn rust64_start(start_info: u64, boot_params: u64) -> ! {
+    serial::init();
+    interrupts::init();
 
     enable_sse();
     paging::setup();
 
-    main(rdi)
+    // Started in 32-bit mode we have the PVH start info, but started in
+    // 64-bit mode either could be what we're given, so nothing is read
+    // before it's known to be mapped and its magic is checked
+    let mapped = |addr: u64, size: usize| {
+        addr.checked_add(size as u64)
+            .map_or(false, |end| end <= paging::mapped_size())
+    };
+    if mapped(start_info, core::mem::size_of::<pvh::StartInfo>()) {
+        if let Some(info) = unsafe { pvh::StartInfo::from_addr(start_info) } {
+            main(info)
+        }
+    }
+    if mapped(boot_params, core::mem::size_of::<boot::Params>()) {
+        if let Some(params) = unsafe { boot::Params::from_addr(boot_params) } {
+            main(params)
+        }
+    }
+    panic!("No PVH start info or Linux boot parameters to boot with");
 }
 
 #[no_mangle]
 #[cfg(feature = "coreboot")]
 pub extern "C" fn rust64_start() -> ! {
-    serial::PORT.borrow_mut().init();
+    serial::init();
+    interrupts::init();
 
     enable_sse();
     paging::setup();
@@ -170,20 +628,48 @@ pub extern "C" fn rust64_start() -> ! {
 }
 
 fn main(info: &dyn boot::Info) -> ! {
+    let info: &dyn boot::Info = &fw_cfg::Info::new(info);
+    serial::configure(info.cmdline());
     log!("\nBooting with {}", info.name());
+    delay::init();
+    watchdog::init(info.cmdline());
+    interrupts::start_timer();
+    panic::configure(info.cmdline());
+    #[cfg(feature = "boot-menu")]
+    menu::configure(info.cmdline());
+    #[cfg(feature = "fault-test")]
+    paging::touch_unmapped();
+    paging::map_ram(info);
+    tpm::init();
 
+    pci::init(info.rsdp_addr());
+    madt::init(info.rsdp_addr());
+    smbios::init();
+    pci::assign_bars(info);
     pci::print_bus();
+    summary::platform(info);
 
-    pci::with_devices(
-        VIRTIO_PCI_VENDOR_ID,
-        VIRTIO_PCI_BLOCK_DEVICE_ID,
-        |pci_device| {
+    boot_from_fw_cfg(info);
+    #[cfg(feature = "no-disk")]
+    boot_embedded(info);
+
+    #[cfg(feature = "network")]
+    for device_id in &[
+        VIRTIO_PCI_NET_DEVICE_ID,
+        VIRTIO_PCI_TRANSITIONAL_NET_DEVICE_ID,
+    ] {
+        pci::with_devices(virtio::VIRTIO_PCI_VENDOR_ID, *device_id, |pci_device| {
             let mut pci_transport = pci::VirtioPciTransport::new(pci_device);
-            block::VirtioBlockDevice::new(&mut pci_transport);
-            let mut device = block::VirtioBlockDevice::new(&mut pci_transport);
-            boot_from_device(&mut device, info)
-        },
-    );
+            let mut device = net::VirtioNetDevice::new(&mut pci_transport);
+            boot_from_net(&mut device, info)
+        });
+    }
+
+    boot_from_disks(info);
 
-    panic!("Unable to boot from any virtio-blk device")
+    // Rather than halting, that build lets the disks be looked at
+    #[cfg(feature = "diag-shell")]
+    shell::run(&Diagnostics(block::enumerate()));
+    #[cfg(not(feature = "diag-shell"))]
+    panic!("Unable to boot from any virtio-blk or NVMe device");
 }
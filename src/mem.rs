@@ -105,57 +105,241 @@ impl MemoryRegion {
         }
     }
 
-    /// Read a value at given offset with a mechanism suitable for MMIO
-    fn io_read<T>(&self, offset: u64) -> T {
-        assert!((offset + (core::mem::size_of::<T>() - 1) as u64) < self.length);
+    /// Panics unless a T at the offset is all within the region and aligned
+    /// for T, as a wrong offset into device registers would otherwise go
+    /// unnoticed
+    fn check_access<T>(&self, offset: u64) {
+        let size = core::mem::size_of::<T>() as u64;
+        assert!(
+            offset
+                .checked_add(size)
+                .map_or(false, |end| end <= self.length),
+            "{} byte access at {:#x} is outside the {:#x} byte region at {:#x}",
+            size,
+            offset,
+            self.length,
+            self.base
+        );
+        assert!(
+            (self.base + offset) % core::mem::align_of::<T>() as u64 == 0,
+            "{} byte access at {:#x} into the region at {:#x} isn't aligned",
+            size,
+            offset,
+            self.base
+        );
+    }
+
+    /// Read a T at given offset with a mechanism suitable for MMIO, which
+    /// has to be in bounds and aligned
+    pub fn read_at<T: Copy>(&self, offset: u64) -> T {
+        self.check_access::<T>(offset);
         unsafe { core::ptr::read_volatile((self.base + offset) as *const T) }
     }
 
+    /// Write a T at given offset with a mechanism suitable for MMIO, which
+    /// has to be in bounds and aligned
+    pub fn write_at<T: Copy>(&self, offset: u64, value: T) {
+        self.check_access::<T>(offset);
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut T, value) }
+    }
+
     /// Read a single byte at given offset with a mechanism suitable for MMIO
     pub fn io_read_u8(&self, offset: u64) -> u8 {
-        self.io_read(offset)
+        self.read_at(offset)
     }
 
     /// Read a single word at given offset with a mechanism suitable for MMIO
     pub fn io_read_u16(&self, offset: u64) -> u16 {
-        self.io_read(offset)
+        self.read_at(offset)
     }
 
     /// Read a single dword at given offset with a mechanism suitable for MMIO
     pub fn io_read_u32(&self, offset: u64) -> u32 {
-        self.io_read(offset)
+        self.read_at(offset)
     }
 
     /// Read a single qword at given offset with a mechanism suitable for MMIO
     pub fn io_read_u64(&self, offset: u64) -> u64 {
-        self.io_read(offset)
-    }
-
-    /// Write a value at given offset using a mechanism suitable for MMIO
-    fn io_write<T>(&self, offset: u64, value: T) {
-        assert!((offset + (core::mem::size_of::<T>() - 1) as u64) < self.length);
-        unsafe {
-            core::ptr::write_volatile((self.base + offset) as *mut T, value);
-        }
+        self.read_at(offset)
     }
 
     /// Write a single byte at given offset with a mechanism suitable for MMIO
     pub fn io_write_u8(&self, offset: u64, value: u8) {
-        self.io_write(offset, value)
+        self.write_at(offset, value)
     }
 
     /// Write a single word at given offset with a mechanism suitable for MMIO
     pub fn io_write_u16(&self, offset: u64, value: u16) {
-        self.io_write(offset, value)
+        self.write_at(offset, value)
     }
 
     /// Write a single dword at given offset with a mechanism suitable for MMIO
     pub fn io_write_u32(&self, offset: u64, value: u32) {
-        self.io_write(offset, value)
+        self.write_at(offset, value)
     }
 
     /// Write a single qword at given offset with a mechanism suitable for MMIO
     pub fn io_write_u64(&self, offset: u64, value: u64) {
-        self.io_write(offset, value)
+        self.write_at(offset, value)
+    }
+}
+
+/// Copies count bytes from src to dest, which may overlap, 8 bytes at a time
+/// and then the bytes left over. Overlapping ranges are copied from the end
+/// when dest is after src, so that nothing is overwritten before it's read.
+///
+/// # Safety
+///
+/// Both ranges have to be valid for count bytes.
+pub unsafe fn copy(dest: *mut u8, src: *const u8, count: usize) {
+    let words = count / 8;
+    let (dest_words, src_words) = (dest as *mut u64, src as *const u64);
+    if (dest as usize).wrapping_sub(src as usize) >= count {
+        for i in 0..words {
+            dest_words
+                .add(i)
+                .write_unaligned(src_words.add(i).read_unaligned());
+        }
+        for i in words * 8..count {
+            *dest.add(i) = *src.add(i);
+        }
+    } else {
+        for i in (words * 8..count).rev() {
+            *dest.add(i) = *src.add(i);
+        }
+        for i in (0..words).rev() {
+            dest_words
+                .add(i)
+                .write_unaligned(src_words.add(i).read_unaligned());
+        }
+    }
+}
+
+/// Sets count bytes from dest to value, 8 bytes at a time and then the bytes
+/// left over
+///
+/// # Safety
+///
+/// The range has to be valid for count bytes.
+pub unsafe fn fill(dest: *mut u8, count: usize, value: u8) {
+    let words = count / 8;
+    let word = u64::from_ne_bytes([value; 8]);
+    for i in 0..words {
+        (dest as *mut u64).add(i).write_unaligned(word);
+    }
+    for i in words * 8..count {
+        *dest.add(i) = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy, fill, MemoryRegion};
+
+    #[test]
+    fn test_access() {
+        let mut data = [0u64; 4];
+        let region = MemoryRegion::new(data.as_mut_ptr() as u64, 32);
+        region.write_at::<u32>(0x1c, 0x1234_5678);
+        region.write_at::<u16>(0x02, 0xabcd);
+        region.write_at::<u8>(0x1f, 0x42);
+        assert_eq!(region.read_at::<u64>(0x18), 0x4234_5678_0000_0000);
+        assert_eq!(region.read_at::<u16>(0x1c), 0x5678);
+        assert_eq!(region.read_at::<u8>(0x03), 0xab);
+        assert_eq!(data[0], 0xabcd_0000);
+    }
+
+    #[test]
+    #[should_panic(expected = "4 byte access at 0x1e is outside the 0x20 byte region")]
+    fn test_access_past_end() {
+        let mut data = [0u64; 4];
+        let region = MemoryRegion::new(data.as_mut_ptr() as u64, 32);
+        region.read_at::<u32>(0x1e);
+    }
+
+    #[test]
+    #[should_panic(expected = "is outside")]
+    fn test_access_overflow() {
+        let region = MemoryRegion::new(0x1000, 32);
+        region.write_at::<u32>(u64::MAX - 1, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't aligned")]
+    fn test_access_unaligned() {
+        let mut data = [0u64; 4];
+        let region = MemoryRegion::new(data.as_mut_ptr() as u64, 32);
+        region.write_at::<u32>(0x6, 0);
+    }
+
+    #[test]
+    fn test_copy() {
+        let pattern: Vec<u8> = (0..64).map(|i| i as u8).collect();
+        for count in &[0, 1, 7, 8, 9, 23, 40] {
+            for (from, to) in &[(0, 20), (20, 0), (8, 11), (11, 8), (3, 3), (0, 1), (1, 0)] {
+                let mut data = pattern.clone();
+                unsafe { copy(data.as_mut_ptr().add(*to), data.as_ptr().add(*from), *count) };
+                let mut expected = pattern.clone();
+                expected.copy_within(*from..*from + *count, *to);
+                assert_eq!(data, expected, "{} bytes from {} to {}", count, from, to);
+            }
+        }
+
+        // Between separate buffers, unaligned
+        let mut dest = [0u8; 30];
+        unsafe { copy(dest.as_mut_ptr().add(1), pattern.as_ptr().add(5), 27) };
+        assert_eq!(dest[0], 0);
+        assert_eq!(dest[1..28], pattern[5..32]);
+        assert_eq!(dest[28..], [0, 0]);
+    }
+
+    #[test]
+    fn test_fill() {
+        for count in &[0, 1, 7, 8, 13, 32] {
+            let mut data = [0x11u8; 40];
+            unsafe { fill(data.as_mut_ptr().add(3), *count, 0xa5) };
+            assert!(data[..3].iter().all(|b| *b == 0x11));
+            assert!(data[3..3 + count].iter().all(|b| *b == 0xa5));
+            assert!(data[3 + count..].iter().all(|b| *b == 0x11));
+        }
+    }
+
+    // Overlapping by less than a word either way, so each word read overlaps
+    // the one written before it
+    #[test]
+    fn test_copy_overlapping() {
+        let pattern: Vec<u8> = (0..48).map(|i| (i * 7 + 1) as u8).collect();
+        for distance in 1..=9 {
+            for count in &[1, 8, 15, 16, 17, 30] {
+                for (from, to) in &[(4, 4 + distance), (4 + distance, 4)] {
+                    let mut data = pattern.clone();
+                    unsafe { copy(data.as_mut_ptr().add(*to), data.as_ptr().add(*from), *count) };
+                    let mut expected = pattern.clone();
+                    expected.copy_within(*from..*from + *count, *to);
+                    assert_eq!(data, expected, "{} bytes from {} to {}", count, from, to);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill_unaligned() {
+        // Nothing is written for an empty range
+        unsafe { fill(core::ptr::NonNull::dangling().as_ptr(), 0, 0xff) };
+
+        for offset in 0..8 {
+            for count in &[0, 1, 2, 9, 15, 17] {
+                let mut data = [0x11u8; 32];
+                unsafe { fill(data.as_mut_ptr().add(offset), *count, 0x5a) };
+                for (i, b) in data.iter().enumerate() {
+                    let expected = if i >= offset && i < offset + count {
+                        0x5a
+                    } else {
+                        0x11
+                    };
+                    assert_eq!(*b, expected, "{} bytes at {}", count, offset);
+                }
+            }
+        }
     }
 }
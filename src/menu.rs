@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// A menu over serial of the EFI applications an ESP could be booted with:
+// the one the firmware picks, then the others the boot options name and those
+// in the directories of \EFI, such as \EFI\BOOT\BOOTX64.EFI. Each has a number
+// to pick it with, and the first one is booted once rhfw.menu_timeout=<seconds>
+// runs out, 5 unless set, or Enter is pressed. A timeout of 0 turns it off.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{common, delay, efi, fat, serial};
+
+const OPTION: &[u8] = b"rhfw.menu_timeout=";
+const DEFAULT_TIMEOUT: u64 = 5;
+
+// As they are picked with a single digit
+const MAX_ENTRIES: usize = 9;
+pub const MAX_PATH: usize = 128;
+
+static TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT);
+
+// The number of seconds in the last valid option
+fn parse_timeout(cmdline: &[u8]) -> Option<u64> {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .filter_map(|arg| arg.strip_prefix(OPTION))
+        .filter_map(|value| core::str::from_utf8(value).ok()?.parse().ok())
+        .last()
+}
+
+// A timeout on this command line takes the place of any earlier one
+pub fn configure(cmdline: &[u8]) {
+    if let Some(seconds) = parse_timeout(cmdline) {
+        log!("Boot menu timeout: {} seconds", seconds);
+        TIMEOUT.store(seconds, Ordering::Relaxed);
+    }
+}
+
+struct Entries {
+    paths: [[u8; MAX_PATH]; MAX_ENTRIES],
+    lengths: [usize; MAX_ENTRIES],
+    count: usize,
+}
+
+impl Entries {
+    fn new() -> Self {
+        Self {
+            paths: [[0; MAX_PATH]; MAX_ENTRIES],
+            lengths: [0; MAX_ENTRIES],
+            count: 0,
+        }
+    }
+
+    // Leaves out a path that is there already, FAT names being case
+    // insensitive, and any that doesn't fit
+    fn add(&mut self, path: &str) {
+        if self.count == MAX_ENTRIES
+            || path.len() > MAX_PATH
+            || self.iter().any(|p| p.eq_ignore_ascii_case(path))
+        {
+            return;
+        }
+        self.paths[self.count][..path.len()].copy_from_slice(path.as_bytes());
+        self.lengths[self.count] = path.len();
+        self.count += 1;
+    }
+
+    // Only ever filled from a str
+    fn get(&self, index: usize) -> &str {
+        core::str::from_utf8(&self.paths[index][..self.lengths[index]]).unwrap_or("")
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &str> {
+        (0..self.count).map(move |i| self.get(i))
+    }
+}
+
+// The parts one after the other, if they fit
+fn join<'a>(parts: &[&str], buffer: &'a mut [u8; MAX_PATH]) -> Option<&'a str> {
+    let mut length = 0;
+    for part in parts {
+        buffer
+            .get_mut(length..length + part.len())?
+            .copy_from_slice(part.as_bytes());
+        length += part.len();
+    }
+    core::str::from_utf8(&buffer[..length]).ok()
+}
+
+// Its long name, unless there is none or it isn't ASCII, in which case its
+// 8.3 name
+fn entry_name<'a>(de: &fat::DirectoryEntry, buffer: &'a mut [u8; 255]) -> &'a str {
+    let length = match de.long_name() {
+        Some(long_name) if long_name.iter().all(|c| *c < 0x80) => {
+            for (b, c) in buffer.iter_mut().zip(long_name) {
+                *b = *c as u8;
+            }
+            long_name.len()
+        }
+        _ => {
+            let short_name = de.short_name();
+            let name = common::ascii_strip(&short_name);
+            buffer[..name.len()].copy_from_slice(name.as_bytes());
+            name.len()
+        }
+    };
+    core::str::from_utf8(&buffer[..length]).unwrap_or("")
+}
+
+// The .efi files in each of the directories of \EFI
+fn add_applications(fs: &fat::Filesystem, entries: &mut Entries) {
+    let dirs = match fs.read_dir("\\EFI") {
+        Ok(dirs) => dirs,
+        Err(_) => return,
+    };
+    for dir in dirs.filter_map(Result::ok).filter(|de| de.is_directory()) {
+        let mut name = [0; 255];
+        let dir_name = entry_name(&dir, &mut name);
+        if dir_name.starts_with('.') {
+            continue;
+        }
+        let mut dir_path = [0; MAX_PATH];
+        let files = match join(&["\\EFI\\", dir_name], &mut dir_path).map(|p| fs.read_dir(p)) {
+            Some(Ok(files)) => files,
+            _ => continue,
+        };
+        for file in files.filter_map(Result::ok).filter(|de| !de.is_directory()) {
+            let mut name = [0; 255];
+            let file_name = entry_name(&file, &mut name);
+            let is_efi = file_name.len() > 4
+                && file_name.as_bytes()[file_name.len() - 4..].eq_ignore_ascii_case(b".efi");
+            let mut path = [0; MAX_PATH];
+            if let (true, Some(path)) = (
+                is_efi,
+                join(&["\\EFI\\", dir_name, "\\", file_name], &mut path),
+            ) {
+                entries.add(path);
+            }
+        }
+    }
+}
+
+// The entry a key picks, a digit the one with that number and Enter the first
+fn selection(key: u8, count: usize) -> Option<usize> {
+    match key {
+        b'\r' | b'\n' => Some(0),
+        b'1'..=b'9' if usize::from(key - b'1') < count => Some(usize::from(key - b'1')),
+        _ => None,
+    }
+}
+
+// The entry picked before the timeout runs out, or otherwise the first
+fn wait_for_selection(count: usize, timeout: u64) -> usize {
+    for remaining in (1..=timeout).rev() {
+        log!("Booting 1 in {}s, press 1-{} to choose", remaining, count);
+        let mut picked = None;
+        delay::wait_until(1000, || {
+            picked = serial::read_byte().and_then(|key| selection(key, count));
+            picked.is_some()
+        });
+        if let Some(picked) = picked {
+            return picked;
+        }
+    }
+    0
+}
+
+// What to boot from the filesystem on the partition starting at
+// partition_start, which is default unless another entry is picked. That one
+// is copied into buffer.
+pub fn choose<'a>(
+    fs: &fat::Filesystem,
+    partition_start: u64,
+    default: &'a str,
+    buffer: &'a mut [u8; MAX_PATH],
+) -> &'a str {
+    let timeout = TIMEOUT.load(Ordering::Relaxed);
+    if timeout == 0 {
+        return default;
+    }
+
+    let mut entries = Entries::new();
+    entries.add(default);
+    efi::boot_option_paths(fs, partition_start, |path| entries.add(path));
+    add_applications(fs, &mut entries);
+    if entries.count < 2 {
+        return default;
+    }
+
+    log!("Boot menu:");
+    for (i, path) in entries.iter().enumerate() {
+        log!("  {}. {}", i + 1, path);
+    }
+    let picked = wait_for_selection(entries.count, timeout);
+    if picked == 0 {
+        return default;
+    }
+    let path = entries.get(picked);
+    log!("Picked {}", path);
+    join(&[path], buffer).unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_applications, join, parse_timeout, selection, Entries, MAX_ENTRIES};
+    use crate::fat::tests::{Dir, ImageBuilder};
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout(b"rhfw.menu_timeout=10 quiet"), Some(10));
+        assert_eq!(
+            parse_timeout(b"rhfw.menu_timeout=0 rhfw.menu_timeout=x"),
+            Some(0)
+        );
+        assert_eq!(parse_timeout(b"rhfw.boot_timeout=10"), None);
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut entries = Entries::new();
+        entries.add("\\EFI\\BOOT\\BOOTX64.EFI");
+        entries.add("\\EFI\\ubuntu\\shimx64.efi");
+        entries.add("\\efi\\boot\\bootx64.efi");
+        entries.add(&"\\a".repeat(65));
+        assert_eq!(
+            entries.iter().collect::<Vec<_>>(),
+            vec!["\\EFI\\BOOT\\BOOTX64.EFI", "\\EFI\\ubuntu\\shimx64.efi"]
+        );
+
+        for i in 0..MAX_ENTRIES {
+            entries.add(&format!("\\{}.efi", i));
+        }
+        assert_eq!(entries.count, MAX_ENTRIES);
+        assert_eq!(entries.get(MAX_ENTRIES - 1), "\\6.efi");
+
+        let mut buffer = [0; super::MAX_PATH];
+        assert_eq!(join(&["\\EFI\\", "BOOT"], &mut buffer), Some("\\EFI\\BOOT"));
+        assert_eq!(join(&[&"a".repeat(129)], &mut buffer), None);
+    }
+
+    #[test]
+    fn test_selection() {
+        assert_eq!(selection(b'\r', 3), Some(0));
+        assert_eq!(selection(b'1', 3), Some(0));
+        assert_eq!(selection(b'3', 3), Some(2));
+        assert_eq!(selection(b'4', 3), None);
+        assert_eq!(selection(b'0', 3), None);
+        assert_eq!(selection(b'q', 3), None);
+    }
+
+    #[test]
+    fn test_add_applications() {
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT16);
+        let efi = builder.add_dir(Dir::Root, b"EFI        ");
+        let boot = builder.add_dir(efi, b"BOOT       ");
+        builder.add_file(boot, b"BOOTX64 EFI", b"MZ");
+        let ubuntu = builder.add_dir(efi, b"UBUNTU     ");
+        builder.add_file(ubuntu, b"SHIMX64 EFI", b"MZ");
+        builder.add_file(ubuntu, b"GRUB    CFG", b"set");
+        builder.add_dir(ubuntu, b"FW      EFI");
+        builder.add_file(Dir::Root, b"OTHER   EFI", b"MZ");
+        let disk = builder.disk();
+        let mut fs = crate::fat::Filesystem::new(&disk, 0, disk.len() - 1);
+        fs.init().expect("Error initialising filesystem");
+
+        let mut entries = Entries::new();
+        entries.add("\\EFI\\UBUNTU\\SHIMX64.EFI");
+        add_applications(&fs, &mut entries);
+        assert_eq!(
+            entries.iter().collect::<Vec<_>>(),
+            vec!["\\EFI\\UBUNTU\\SHIMX64.EFI", "\\EFI\\BOOT\\BOOTX64.EFI"]
+        );
+    }
+}
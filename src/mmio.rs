@@ -0,0 +1,303 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::cell::Cell;
+
+use crate::{
+    mem,
+    virtio::{Error as VirtioError, VirtioTransport},
+};
+
+const VIRTIO_MMIO_MAGIC: u32 = 0x7472_6976; // "virt"
+const VIRTIO_MMIO_VERSION: u32 = 2;
+const VIRTIO_MMIO_LEGACY_VERSION: u32 = 1;
+const VIRTIO_MMIO_SIZE: u64 = 0x200;
+
+// The page size given to a legacy device, which the queue has to start on a
+// multiple of. The drivers' queues are 64 byte aligned.
+const LEGACY_PAGE_SIZE: u32 = 64;
+
+// Calls found with the address of each slot in the range that holds a virtio
+// device of the given type
+pub fn find_devices<F>(base: u64, count: u64, device_type: u32, mut found: F)
+where
+    F: FnMut(u64),
+{
+    for i in 0..count {
+        let address = base + i * VIRTIO_MMIO_SIZE;
+        let region = mem::MemoryRegion::new(address, VIRTIO_MMIO_SIZE);
+        // magic: 0x000, device_id: 0x008
+        if region.read_at::<u32>(0x000) == VIRTIO_MMIO_MAGIC
+            && region.read_at::<u32>(0x008) == device_type
+        {
+            found(address);
+        }
+    }
+}
+
+pub struct VirtioMmioTransport {
+    region: mem::MemoryRegion,
+    version: u32,
+    // Where the queue being set up is, as a legacy device is only told once
+    // it's all known
+    queue_size: Cell<u16>,
+    descriptors: Cell<u64>,
+    avail: Cell<u64>,
+    used: Cell<u64>,
+}
+
+impl VirtioMmioTransport {
+    pub fn new(region: mem::MemoryRegion) -> VirtioMmioTransport {
+        VirtioMmioTransport {
+            region,
+            version: VIRTIO_MMIO_VERSION,
+            queue_size: Cell::new(0),
+            descriptors: Cell::new(0),
+            avail: Cell::new(0),
+            used: Cell::new(0),
+        }
+    }
+
+    /// The transport in the slot at address
+    pub fn at(address: u64) -> VirtioMmioTransport {
+        Self::new(mem::MemoryRegion::new(address, VIRTIO_MMIO_SIZE))
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.region.write_at::<u32>(offset, value as u32);
+        self.region
+            .write_at::<u32>(offset + 4, (value >> 32) as u32);
+    }
+
+    // Tells a legacy device where the queue is. It only takes the one
+    // address, with the available ring straight after the descriptors and the
+    // used ring after that at the alignment it is given, so the alignment is
+    // worked out from where the used ring is. Devices disagree on whether
+    // the available ring's used_event counts, so it has to fit either way.
+    fn set_legacy_queue(&self) -> Result<(), VirtioError> {
+        let size = u64::from(self.queue_size.get());
+        let descriptors = self.descriptors.get();
+        let avail = self.avail.get();
+        let used = self.used.get();
+        let align_up = |addr: u64, align: u64| (addr + align - 1) & !(align - 1);
+        let align = (1..=12).map(|shift| 1 << shift).find(|&align| {
+            align_up(avail + 4 + 2 * size, align) == used
+                && align_up(avail + 6 + 2 * size, align) == used
+        });
+        let align = match align {
+            Some(align)
+                if avail == descriptors + 16 * size
+                    && descriptors % u64::from(LEGACY_PAGE_SIZE) == 0 =>
+            {
+                align
+            }
+            _ => {
+                log!("Queue can't be laid out for a legacy virtio-mmio device");
+                return Err(VirtioError::VirtioUnsupportedQueueLayout);
+            }
+        };
+        // queue_align: 0x03c, queue_pfn: 0x040
+        self.region.write_at::<u32>(0x03c, align as u32);
+        self.region
+            .write_at::<u32>(0x040, (descriptors / u64::from(LEGACY_PAGE_SIZE)) as u32);
+        Ok(())
+    }
+}
+
+// Registers:
+/// le32 magic_value;               // 0x000 // read-only
+/// le32 version;                   // 0x004 // read-only
+/// le32 device_id;                 // 0x008 // read-only
+/// le32 vendor_id;                 // 0x00c // read-only
+/// le32 device_features;           // 0x010 // read-only
+/// le32 device_features_sel;       // 0x014 // write-only
+/// le32 driver_features;           // 0x020 // write-only
+/// le32 driver_features_sel;       // 0x024 // write-only
+/// le32 queue_sel;                 // 0x030 // write-only
+/// le32 queue_num_max;             // 0x034 // read-only
+/// le32 queue_num;                 // 0x038 // write-only
+/// le32 queue_ready;               // 0x044 // read-write
+/// le32 queue_notify;              // 0x050 // write-only
+/// le32 status;                    // 0x070 // read-write
+/// le64 queue_desc;                // 0x080 // write-only
+/// le64 queue_driver;              // 0x090 // write-only
+/// le64 queue_device;              // 0x0a0 // write-only
+/// le32 config_generation;         // 0x0fc // read-only
+/// device specific configuration   // 0x100
+///
+/// The legacy interface, version 1, has these in place of queue_ready and
+/// the queue addresses:
+/// le32 guest_page_size;           // 0x028 // write-only
+/// le32 queue_align;               // 0x03c // write-only
+/// le32 queue_pfn;                 // 0x040 // read-write
+impl VirtioTransport for VirtioMmioTransport {
+    fn init(&mut self, device_type: u32) -> Result<(), VirtioError> {
+        // magic: 0x000
+        if self.region.read_at::<u32>(0x000) != VIRTIO_MMIO_MAGIC {
+            return Err(VirtioError::VirtioUnsupportedDevice);
+        }
+
+        // version: 0x004, the legacy interface is version 1
+        self.version = self.region.read_at::<u32>(0x004);
+        if self.version != VIRTIO_MMIO_VERSION && self.version != VIRTIO_MMIO_LEGACY_VERSION {
+            return Err(VirtioError::VirtioUnsupportedDevice);
+        }
+
+        // device_id: 0x008
+        if self.region.read_at::<u32>(0x008) != device_type {
+            return Err(VirtioError::VirtioUnsupportedDevice);
+        }
+
+        if self.legacy() {
+            // guest_page_size: 0x028
+            self.region.write_at::<u32>(0x028, LEGACY_PAGE_SIZE);
+        }
+        Ok(())
+    }
+
+    fn legacy(&self) -> bool {
+        self.version == VIRTIO_MMIO_LEGACY_VERSION
+    }
+
+    fn get_status(&self) -> u32 {
+        // status: 0x070
+        self.region.read_at::<u32>(0x070)
+    }
+
+    fn set_status(&self, value: u32) {
+        // status: 0x070
+        self.region.write_at::<u32>(0x070, value);
+    }
+
+    fn add_status(&self, value: u32) {
+        self.set_status(self.get_status() | value);
+    }
+
+    fn reset(&self) {
+        self.set_status(0);
+    }
+
+    fn get_features(&self) -> u64 {
+        // device_features_sel: 0x014
+        self.region.write_at::<u32>(0x014, 0);
+        // device_features: 0x010
+        let mut device_features: u64 = u64::from(self.region.read_at::<u32>(0x010));
+        // device_features_sel: 0x014
+        self.region.write_at::<u32>(0x014, 1);
+        // device_features: 0x010
+        device_features |= u64::from(self.region.read_at::<u32>(0x010)) << 32;
+
+        device_features
+    }
+
+    fn set_features(&self, features: u64) {
+        // driver_features_sel: 0x024
+        self.region.write_at::<u32>(0x024, 0);
+        // driver_features: 0x020
+        self.region.write_at::<u32>(0x020, features as u32);
+        // driver_features_sel: 0x024
+        self.region.write_at::<u32>(0x024, 1);
+        // driver_features: 0x020
+        self.region.write_at::<u32>(0x020, (features >> 32) as u32);
+    }
+
+    fn set_queue(&self, queue: u16) {
+        // queue_sel: 0x030
+        self.region.write_at::<u32>(0x030, u32::from(queue));
+    }
+
+    fn get_queue_max_size(&self) -> u16 {
+        // queue_num_max: 0x034
+        self.region.read_at::<u32>(0x034) as u16
+    }
+
+    fn set_queue_size(&self, queue_size: u16) {
+        // queue_num: 0x038
+        self.region.write_at::<u32>(0x038, u32::from(queue_size));
+        self.queue_size.set(queue_size);
+    }
+
+    fn set_descriptors_address(&self, addr: u64) {
+        self.descriptors.set(addr);
+        if !self.legacy() {
+            // queue_desc: 0x080
+            self.write_u64(0x080, addr);
+        }
+    }
+
+    fn set_avail_ring(&self, addr: u64) {
+        self.avail.set(addr);
+        if !self.legacy() {
+            // queue_driver: 0x090
+            self.write_u64(0x090, addr);
+        }
+    }
+
+    fn set_used_ring(&self, addr: u64) {
+        self.used.set(addr);
+        if !self.legacy() {
+            // queue_device: 0x0a0
+            self.write_u64(0x0a0, addr);
+        }
+    }
+
+    fn set_queue_enable(&self) -> Result<(), VirtioError> {
+        if self.legacy() {
+            return self.set_legacy_queue();
+        }
+        // queue_ready: 0x044
+        self.region.write_at::<u32>(0x044, 0x1);
+        Ok(())
+    }
+
+    fn notify_queue(&self, queue: u16) {
+        // queue_notify: 0x050
+        self.region.write_at::<u32>(0x050, u32::from(queue));
+    }
+
+    fn read_device_config(&self, offset: u64) -> u32 {
+        // device specific configuration: 0x100
+        self.region.read_at::<u32>(0x100 + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtioMmioTransport;
+    use crate::{mem::MemoryRegion, virtio::VirtioTransport};
+
+    #[test]
+    fn test_legacy_queue() {
+        let mut registers = [0_u32; 0x80];
+        registers[0] = super::VIRTIO_MMIO_MAGIC;
+        registers[1] = 1;
+        registers[2] = 2;
+        let mut transport = VirtioMmioTransport::new(MemoryRegion::from_bytes(unsafe {
+            core::slice::from_raw_parts_mut(registers.as_mut_ptr() as *mut u8, 0x200)
+        }));
+        transport.init(2).unwrap();
+        assert!(transport.legacy());
+        // guest_page_size
+        assert_eq!(transport.region.read_at::<u32>(0x028), 64);
+
+        // As the drivers lay out a queue of 16: the available ring right
+        // after the descriptors, and the used ring 4 byte aligned after it
+        transport.set_queue_size(16);
+        transport.set_descriptors_address(0x1_0000);
+        transport.set_avail_ring(0x1_0100);
+        transport.set_used_ring(0x1_0128);
+        transport.set_queue_enable().unwrap();
+        // queue_align and queue_pfn, with no queue_ready
+        assert_eq!(transport.region.read_at::<u32>(0x03c), 8);
+        assert_eq!(transport.region.read_at::<u32>(0x040), 0x1_0000 / 64);
+        assert_eq!(transport.region.read_at::<u32>(0x044), 0);
+
+        // A queue that isn't laid out that way isn't set up at all
+        transport.region.write_at::<u32>(0x040, 0);
+        transport.set_avail_ring(0x2_0000);
+        assert!(matches!(
+            transport.set_queue_enable(),
+            Err(crate::virtio::Error::VirtioUnsupportedQueueLayout)
+        ));
+        assert_eq!(transport.region.read_at::<u32>(0x040), 0);
+    }
+}
@@ -0,0 +1,741 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots kernels with a Multiboot2 header, which says where the kernel wants
+// to be loaded and what it needs to be told. The kernel is started in 32-bit
+// protected mode with a pointer to the boot information, so everything below
+// has to be in the first 4GiB.
+
+use core::{cmp::min, convert::TryFrom};
+
+use crate::{
+    boot::{Cmdline, E820Entry, Framebuffer, Info, MemoryMap, PixelFormat},
+    elf,
+    fat::{self, Read},
+    mem::MemoryRegion,
+    sha256::Sha256,
+    tpm,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    FileError(fat::Error),
+    // Without the address tag the kernel is an ELF file, loaded as one
+    ElfError(elf::Error),
+    MagicMissing,
+    InvalidHeader,
+    NoEntry,
+    // A header tag or boot information the kernel can't do without
+    UnsupportedTag(u16),
+    UnsupportedRequest(u32),
+    // The kernel isn't all in RAM below 4GiB or would overwrite the firmware
+    InvalidAddress,
+    NoModuleMemory,
+}
+
+impl From<fat::Error> for Error {
+    fn from(e: fat::Error) -> Error {
+        Error::FileError(e)
+    }
+}
+
+impl From<elf::Error> for Error {
+    fn from(e: elf::Error) -> Error {
+        Error::ElfError(e)
+    }
+}
+
+const HEADER_MAGIC: u32 = 0xe852_50d6;
+// What the kernel is started with in %eax
+const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+const ARCHITECTURE_I386: u32 = 0;
+// The header has to be in this much of the start of the file
+pub const SEARCH_SIZE: usize = 32 * 1024;
+
+// Header tags
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+const HEADER_TAG_RELOCATABLE: u16 = 10;
+// The kernel can be booted without what the tag asks for
+const HEADER_TAG_OPTIONAL: u16 = 1;
+
+// Boot information tags
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+const TAG_LOAD_BASE_ADDR: u32 = 21;
+
+const SUPPORTED_REQUESTS: &[u32] = &[
+    TAG_CMDLINE,
+    TAG_BOOT_LOADER_NAME,
+    TAG_MODULE,
+    TAG_BASIC_MEMINFO,
+    TAG_MMAP,
+    TAG_FRAMEBUFFER,
+    TAG_ACPI_OLD,
+    TAG_ACPI_NEW,
+    TAG_LOAD_BASE_ADDR,
+];
+
+const MMAP_ENTRY_SIZE: u32 = 24;
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+// The offset of the header, which is 8 byte aligned and has a checksum
+// making its first 4 fields add up to 0
+fn find_header(data: &[u8]) -> Option<usize> {
+    let data = &data[..min(data.len(), SEARCH_SIZE)];
+    (0..data.len().saturating_sub(15))
+        .step_by(8)
+        .find(|&offset| {
+            let magic = read_u32(data, offset);
+            let architecture = read_u32(data, offset + 4);
+            let length = read_u32(data, offset + 8);
+            let checksum = read_u32(data, offset + 12);
+            magic == HEADER_MAGIC
+                && architecture == ARCHITECTURE_I386
+                && magic
+                    .wrapping_add(architecture)
+                    .wrapping_add(length)
+                    .wrapping_add(checksum)
+                    == 0
+        })
+}
+
+// Whether the start of a file has a Multiboot2 header
+pub fn has_header(data: &[u8]) -> bool {
+    find_header(data).is_some()
+}
+
+// The address tag: where the header is to end up and what around it is
+// loaded from the file
+#[derive(Debug, PartialEq)]
+struct Address {
+    header_addr: u32,
+    load_addr: u32,
+    // 0 if the rest of the file is loaded
+    load_end_addr: u32,
+    // 0 if there is no bss
+    bss_end_addr: u32,
+}
+
+impl Address {
+    // The offset in the file of what is loaded, how much of it there is and
+    // where the bss ends
+    fn layout(&self, header_offset: usize, file_size: usize) -> Result<(u32, u64, u64), Error> {
+        let before_header = self
+            .header_addr
+            .checked_sub(self.load_addr)
+            .ok_or(Error::InvalidHeader)?;
+        let offset = (header_offset as u32)
+            .checked_sub(before_header)
+            .ok_or(Error::InvalidHeader)?;
+        let available = file_size as u64 - u64::from(offset);
+        let size = match self.load_end_addr {
+            0 => available,
+            end => u64::from(
+                end.checked_sub(self.load_addr)
+                    .ok_or(Error::InvalidHeader)?,
+            ),
+        };
+        if size > available {
+            return Err(Error::InvalidHeader);
+        }
+        let end = core::cmp::max(
+            u64::from(self.load_addr) + size,
+            u64::from(self.bss_end_addr),
+        );
+        Ok((offset, size, end))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    address: Option<Address>,
+    entry_addr: Option<u32>,
+}
+
+// Goes through the tags of the header at the offset, failing if it asks for
+// something we can't do
+fn parse_header(data: &[u8], offset: usize) -> Result<Header, Error> {
+    let length = read_u32(data, offset + 8) as usize;
+    if length < 16 || offset + length > data.len() {
+        return Err(Error::InvalidHeader);
+    }
+    let end = offset + length;
+
+    let mut header = Header {
+        address: None,
+        entry_addr: None,
+    };
+    let mut tag = offset + 16;
+    while tag + 8 <= end {
+        let tag_type = read_u16(data, tag);
+        let optional = read_u16(data, tag + 2) & HEADER_TAG_OPTIONAL != 0;
+        let size = read_u32(data, tag + 4) as usize;
+        if size < 8 || tag + size > end {
+            return Err(Error::InvalidHeader);
+        }
+        match tag_type {
+            HEADER_TAG_END => break,
+            HEADER_TAG_INFORMATION_REQUEST => {
+                for request in (tag + 8..tag + size - 3).step_by(4) {
+                    let request = read_u32(data, request);
+                    if !optional && !SUPPORTED_REQUESTS.contains(&request) {
+                        return Err(Error::UnsupportedRequest(request));
+                    }
+                }
+            }
+            HEADER_TAG_ADDRESS if size >= 24 => {
+                header.address = Some(Address {
+                    header_addr: read_u32(data, tag + 8),
+                    load_addr: read_u32(data, tag + 12),
+                    load_end_addr: read_u32(data, tag + 16),
+                    bss_end_addr: read_u32(data, tag + 20),
+                })
+            }
+            HEADER_TAG_ENTRY_ADDRESS if size >= 12 => {
+                header.entry_addr = Some(read_u32(data, tag + 8))
+            }
+            HEADER_TAG_ADDRESS | HEADER_TAG_ENTRY_ADDRESS => return Err(Error::InvalidHeader),
+            // The console and framebuffer are left as they are, modules are
+            // always page aligned and the kernel is loaded where it asks to be
+            HEADER_TAG_CONSOLE_FLAGS
+            | HEADER_TAG_FRAMEBUFFER
+            | HEADER_TAG_MODULE_ALIGN
+            | HEADER_TAG_RELOCATABLE => {}
+            _ if optional => {}
+            t => return Err(Error::UnsupportedTag(t)),
+        }
+        // Tags are 8 byte aligned
+        tag += (size + 7) & !7;
+    }
+    Ok(header)
+}
+
+// Builds the boot information a tag at a time, after the total size and a
+// reserved field
+struct InfoWriter<'a> {
+    data: &'a mut [u8],
+    length: usize,
+    tag: usize,
+}
+
+impl<'a> InfoWriter<'a> {
+    fn new(data: &'a mut [u8]) -> InfoWriter<'a> {
+        InfoWriter {
+            data,
+            length: 8,
+            tag: 8,
+        }
+    }
+
+    fn begin(&mut self, tag_type: u32) {
+        self.tag = self.length;
+        self.length += 8;
+        self.data[self.tag..self.tag + 4].copy_from_slice(&tag_type.to_le_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.data[self.length..self.length + bytes.len()].copy_from_slice(bytes);
+        self.length += bytes.len();
+    }
+
+    // Fills in the tag's size, padding it out to 8 bytes
+    fn end(&mut self) {
+        let size = (self.length - self.tag) as u32;
+        self.data[self.tag + 4..self.tag + 8].copy_from_slice(&size.to_le_bytes());
+        let padded = (self.length + 7) & !7;
+        self.data[self.length..padded].fill(0);
+        self.length = padded;
+    }
+
+    fn tag(&mut self, tag_type: u32, parts: &[&[u8]]) {
+        self.begin(tag_type);
+        for part in parts {
+            self.write(part);
+        }
+        self.end();
+    }
+
+    // Adds the end tag, returning the total size
+    fn finish(mut self) -> usize {
+        self.tag(TAG_END, &[]);
+        let length = self.length as u32;
+        self.data[0..4].copy_from_slice(&length.to_le_bytes());
+        self.data[4..8].fill(0);
+        self.length
+    }
+}
+
+const INFO_SIZE: usize = 16 * 1024;
+
+// The boot information is kept with the firmware, which the kernel can't be
+// loaded over
+#[repr(C, align(8))]
+struct BootInfo([u8; INFO_SIZE]);
+
+static mut BOOT_INFO: BootInfo = BootInfo([0; INFO_SIZE]);
+
+extern "sysv64" {
+    // In asm/start32.s
+    fn start32(entry: u64, ebx: u64, eax: u64) -> !;
+}
+
+pub struct Kernel {
+    entry: u32,
+    load_addr: u32,
+    // The memory from the start of the kernel to the end of its bss
+    region: (u64, u64),
+    module: Option<(u64, u64)>,
+    cmdline: Cmdline,
+    rsdp_addr: u64,
+    framebuffer: Option<Framebuffer>,
+    memory: MemoryMap,
+}
+
+impl Kernel {
+    pub fn new(info: &dyn Info) -> Self {
+        Self {
+            entry: 0,
+            load_addr: 0,
+            region: (0, 0),
+            module: None,
+            cmdline: Cmdline::new(),
+            rsdp_addr: info.rsdp_addr(),
+            framebuffer: info.framebuffer(),
+            memory: MemoryMap::new(info),
+        }
+    }
+
+    // Returns the SHA-256 hash of the whole file as it was read in loading it
+    pub fn load_kernel(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        let file_size = f.get_size() as usize;
+        let mut data = [0; SEARCH_SIZE];
+        let length = min(file_size, SEARCH_SIZE);
+        f.read_at(0, &mut data[..length])?;
+        let offset = find_header(&data[..length]).ok_or(Error::MagicMissing)?;
+        let header = parse_header(&data[..length], offset)?;
+
+        let (entry, region, digest) = match header.address {
+            Some(address) => {
+                let entry = header.entry_addr.ok_or(Error::NoEntry)?;
+                let (file_offset, size, end) = address.layout(offset, file_size)?;
+                let start = u64::from(address.load_addr);
+                if !self.memory.usable((start, end)) {
+                    return Err(Error::InvalidAddress);
+                }
+
+                // What is loaded is what is hashed, and the header it was
+                // worked out from has to read the same as it did
+                let parts = core::iter::once((file_offset, size as usize, start));
+                let digest = fat::load_parts(f, parts, &[(0, &data[..length])])?;
+                MemoryRegion::new(start + size, end - start - size)
+                    .as_bytes()
+                    .fill(0);
+                (entry, (start, end), digest)
+            }
+            // The segments go where the program headers say, and the entry
+            // tag takes the place of the ELF entry point
+            None => {
+                let executable = elf::load_executable(f, &self.memory, (0, &data[..length]))?;
+                let entry = match header.entry_addr {
+                    Some(entry) => entry,
+                    None => u32::try_from(executable.entry).map_err(|_| Error::NoEntry)?,
+                };
+                (entry, executable.region, executable.digest)
+            }
+        };
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"kernel");
+
+        self.entry = entry;
+        // Which is below 4GiB, as all the memory used is
+        self.load_addr = region.0 as u32;
+        self.region = region;
+        log!("Loaded Multiboot2 kernel at {:#x}", region.0);
+        Ok(digest)
+    }
+
+    // The initrd is passed on as the one module
+    // Returns the SHA-256 hash of the module as it was loaded
+    pub fn load_initrd(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        let size = f.get_size() as u64;
+        let addr = self
+            .memory
+            .highest_fit(size, self.region)
+            .ok_or(Error::NoModuleMemory)?;
+
+        let mut region = MemoryRegion::new(addr, size);
+        f.seek(0)?;
+        f.load_file(&mut region)?;
+        let mut hash = Sha256::new();
+        hash.update(region.as_bytes());
+        let digest = hash.finish();
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"initrd");
+
+        self.module = Some((addr, addr + size));
+        log!("Loaded {} byte module at {:#x}", size, addr);
+        Ok(digest)
+    }
+
+    pub fn append_cmdline(&mut self, addition: &[u8]) {
+        self.cmdline.append(addition);
+    }
+
+    pub fn cmdline(&self) -> &[u8] {
+        self.cmdline.as_bytes()
+    }
+
+    fn write_memory_info(&self, writer: &mut InfoWriter) {
+        // The KiB of RAM from 0 and from 1MiB
+        let ram_from = |addr: u64| {
+            self.memory
+                .entries()
+                .iter()
+                .find(|entry| entry.entry_type == E820Entry::RAM_TYPE && entry.addr == addr)
+                .map_or(0, |entry| entry.size / 1024)
+        };
+        let mem_lower = min(ram_from(0), 640) as u32;
+        let mem_upper = min(ram_from(0x10_0000), u64::from(u32::MAX)) as u32;
+        writer.tag(
+            TAG_BASIC_MEMINFO,
+            &[&mem_lower.to_le_bytes(), &mem_upper.to_le_bytes()],
+        );
+
+        writer.begin(TAG_MMAP);
+        writer.write(&MMAP_ENTRY_SIZE.to_le_bytes());
+        writer.write(&0u32.to_le_bytes());
+        for entry in self.memory.entries() {
+            let (addr, size, entry_type) = (entry.addr, entry.size, entry.entry_type);
+            writer.write(&addr.to_le_bytes());
+            writer.write(&size.to_le_bytes());
+            writer.write(&entry_type.to_le_bytes());
+            writer.write(&0u32.to_le_bytes());
+        }
+        writer.end();
+    }
+
+    fn write_framebuffer(&self, writer: &mut InfoWriter, fb: &Framebuffer) {
+        // The bit positions of red, green and blue in each pixel
+        let (red, blue) = match fb.format {
+            PixelFormat::Rgbx => (0, 16),
+            PixelFormat::Bgrx => (16, 0),
+        };
+        writer.begin(TAG_FRAMEBUFFER);
+        writer.write(&fb.base.to_le_bytes());
+        writer.write(&(fb.stride * 4).to_le_bytes());
+        writer.write(&fb.width.to_le_bytes());
+        writer.write(&fb.height.to_le_bytes());
+        writer.write(&[32, FRAMEBUFFER_TYPE_RGB, 0, 0]);
+        writer.write(&[red, 8, 8, 8, blue, 8]);
+        writer.end();
+    }
+
+    // The RSDP is copied in, as the old one and, from ACPI 2.0, the new one
+    fn write_acpi(&self, writer: &mut InfoWriter) {
+        if self.rsdp_addr == 0 {
+            return;
+        }
+        let rsdp = MemoryRegion::new(self.rsdp_addr, 36);
+        let bytes = |length: u64| unsafe {
+            core::slice::from_raw_parts(self.rsdp_addr as *const u8, length as usize)
+        };
+        writer.tag(TAG_ACPI_OLD, &[bytes(20)]);
+        if rsdp.read_u8(15) >= 2 {
+            let length = core::cmp::max(u64::from(rsdp.read_u32(20)), 36);
+            writer.tag(TAG_ACPI_NEW, &[bytes(length)]);
+        }
+    }
+
+    // Writes the boot information into data, returning its size
+    fn write_info(&self, data: &mut [u8]) -> usize {
+        let mut writer = InfoWriter::new(data);
+        writer.tag(TAG_CMDLINE, &[self.cmdline.as_bytes_with_nul()]);
+        writer.tag(TAG_BOOT_LOADER_NAME, &[b"rust-hypervisor-firmware\0"]);
+        writer.tag(TAG_LOAD_BASE_ADDR, &[&self.load_addr.to_le_bytes()]);
+        if let Some((start, end)) = self.module {
+            // The module has no command line
+            writer.tag(
+                TAG_MODULE,
+                &[
+                    &(start as u32).to_le_bytes(),
+                    &(end as u32).to_le_bytes(),
+                    &[0],
+                ],
+            );
+        }
+        self.write_memory_info(&mut writer);
+        if let Some(fb) = &self.framebuffer {
+            self.write_framebuffer(&mut writer, fb);
+        }
+        self.write_acpi(&mut writer);
+        writer.finish()
+    }
+
+    pub fn boot(&mut self) {
+        self.cmdline.measure();
+
+        let info = unsafe { &mut BOOT_INFO.0 };
+        self.write_info(info);
+        unsafe {
+            start32(
+                u64::from(self.entry),
+                info.as_ptr() as u64,
+                u64::from(BOOTLOADER_MAGIC),
+            )
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_header, parse_header, read_u32, Address, Error, Header, InfoWriter, Kernel};
+    use crate::boot::{E820Entry, Framebuffer, Info, PixelFormat};
+
+    // A header with the tags, which are padded to 8 bytes
+    fn header(tags: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for tag in tags {
+            body.extend_from_slice(tag);
+            body.resize((body.len() + 7) & !7, 0);
+        }
+        body.extend_from_slice(&[0, 0, 0, 0, 8, 0, 0, 0]);
+        let length = 16 + body.len() as u32;
+        let mut data = Vec::new();
+        for field in &[
+            0xe852_50d6,
+            0,
+            length,
+            0u32.wrapping_sub(0xe852_50d6 + length),
+        ] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&body);
+        data
+    }
+
+    fn tag(tag_type: u16, flags: u16, fields: &[u32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&tag_type.to_le_bytes());
+        data.extend_from_slice(&flags.to_le_bytes());
+        data.extend_from_slice(&(8 + 4 * fields.len() as u32).to_le_bytes());
+        for field in fields {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_find_header() {
+        let mut data = vec![0xcc; 0x1000];
+        assert_eq!(find_header(&data), None);
+        let h = header(&[]);
+        data[0x808..0x808 + h.len()].copy_from_slice(&h);
+        assert_eq!(find_header(&data), Some(0x808));
+        // Only at 8 byte alignment, with the right checksum
+        data[0x808..0x808 + h.len()].fill(0xcc);
+        data[0x804..0x804 + h.len()].copy_from_slice(&h);
+        assert_eq!(find_header(&data), None);
+        data[0x800..0x800 + h.len()].copy_from_slice(&h);
+        data[0x80c] ^= 1;
+        assert_eq!(find_header(&data), None);
+        // Nor past the first 32KiB
+        let mut data = vec![0; 0x9000];
+        data[0x8000..0x8000 + h.len()].copy_from_slice(&h);
+        assert_eq!(find_header(&data), None);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let data = header(&[
+            &tag(1, 0, &[1, 6, 14]),
+            &tag(2, 0, &[0x40_0000, 0x40_0000, 0, 0x50_0000]),
+            &tag(3, 0, &[0x40_0040]),
+            &tag(6, 0, &[]),
+        ]);
+        assert_eq!(
+            parse_header(&data, 0).unwrap(),
+            Header {
+                address: Some(Address {
+                    header_addr: 0x40_0000,
+                    load_addr: 0x40_0000,
+                    load_end_addr: 0,
+                    bss_end_addr: 0x50_0000,
+                }),
+                entry_addr: Some(0x40_0040),
+            }
+        );
+
+        // Asking for what we don't have is only fine if it's optional
+        let data = header(&[&tag(1, 0, &[1, 9])]);
+        assert!(matches!(
+            parse_header(&data, 0),
+            Err(Error::UnsupportedRequest(9))
+        ));
+        let data = header(&[&tag(1, 1, &[1, 9])]);
+        assert!(parse_header(&data, 0).is_ok());
+        let data = header(&[&tag(9, 0, &[0x40_0000])]);
+        assert!(matches!(
+            parse_header(&data, 0),
+            Err(Error::UnsupportedTag(9))
+        ));
+        let data = header(&[&tag(9, 1, &[0x40_0000])]);
+        assert!(parse_header(&data, 0).is_ok());
+
+        let mut data = header(&[&tag(3, 0, &[0x40_0040])]);
+        data[20] = 0xff;
+        assert!(matches!(parse_header(&data, 0), Err(Error::InvalidHeader)));
+    }
+
+    #[test]
+    fn test_layout() {
+        // The header is 0x100 into the file, which is loaded from its start
+        let address = Address {
+            header_addr: 0x40_0100,
+            load_addr: 0x40_0000,
+            load_end_addr: 0,
+            bss_end_addr: 0,
+        };
+        assert_eq!(
+            address.layout(0x100, 0x3000).unwrap(),
+            (0, 0x3000, 0x40_3000)
+        );
+        // Or from the header on, stopping after 0x1000 bytes
+        let address = Address {
+            header_addr: 0x40_0000,
+            load_addr: 0x40_0000,
+            load_end_addr: 0x40_1000,
+            bss_end_addr: 0x40_2000,
+        };
+        assert_eq!(
+            address.layout(0x100, 0x3000).unwrap(),
+            (0x100, 0x1000, 0x40_2000)
+        );
+        assert!(address.layout(0x100, 0x1000).is_err());
+        let address = Address {
+            header_addr: 0x40_0200,
+            load_addr: 0x40_0000,
+            load_end_addr: 0,
+            bss_end_addr: 0,
+        };
+        assert!(address.layout(0x100, 0x1000).is_err());
+    }
+
+    struct TestInfo {}
+
+    impl Info for TestInfo {
+        fn name(&self) -> &str {
+            "Test"
+        }
+        fn rsdp_addr(&self) -> u64 {
+            0
+        }
+        fn cmdline(&self) -> &[u8] {
+            b""
+        }
+        fn num_entries(&self) -> u8 {
+            2
+        }
+        fn entry(&self, idx: u8) -> E820Entry {
+            [
+                E820Entry {
+                    addr: 0,
+                    size: 0x9f000,
+                    entry_type: E820Entry::RAM_TYPE,
+                },
+                E820Entry {
+                    addr: 0x10_0000,
+                    size: 0x3ff0_0000,
+                    entry_type: E820Entry::RAM_TYPE,
+                },
+            ][idx as usize]
+        }
+        fn framebuffer(&self) -> Option<Framebuffer> {
+            Some(Framebuffer {
+                base: 0x8000_0000,
+                width: 1280,
+                height: 800,
+                stride: 1280,
+                format: PixelFormat::Bgrx,
+            })
+        }
+    }
+
+    // The tags of the boot information, by type
+    fn tags(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut tags = Vec::new();
+        let mut offset = 8;
+        while offset < read_u32(data, 0) as usize {
+            let size = read_u32(data, offset + 4) as usize;
+            tags.push((read_u32(data, offset), &data[offset + 8..offset + size]));
+            offset += (size + 7) & !7;
+        }
+        tags
+    }
+
+    #[test]
+    fn test_info() {
+        let mut kernel = Kernel::new(&TestInfo {});
+        kernel.append_cmdline(b"console=ttyS0");
+        kernel.append_cmdline(b"");
+        kernel.append_cmdline(b"quiet");
+        kernel.module = Some((0x3000_0000, 0x3000_1000));
+        kernel.load_addr = 0x40_0000;
+
+        let mut data = [0xcc; 1024];
+        let length = kernel.write_info(&mut data);
+        assert_eq!(read_u32(&data, 0) as usize, length);
+        assert_eq!(length % 8, 0);
+        let tags = tags(&data[..length]);
+        let types: Vec<u32> = tags.iter().map(|(t, _)| *t).collect();
+        assert_eq!(types, [1, 2, 21, 3, 4, 6, 8, 0]);
+        assert_eq!(tags[0].1, b"console=ttyS0 quiet\0");
+        assert_eq!(tags[2].1, 0x40_0000u32.to_le_bytes());
+        assert_eq!(tags[3].1, b"\0\0\0\x30\0\x10\0\x30\0");
+        // 636KiB below 1MiB, 1023MiB above it
+        assert_eq!(tags[4].1, [0x7c, 2, 0, 0, 0, 0xfc, 0xf, 0]);
+        let mmap = tags[5].1;
+        assert_eq!(mmap.len(), 8 + 2 * 24);
+        assert_eq!(read_u32(mmap, 0), 24);
+        assert_eq!(&mmap[32..40], &0x10_0000u64.to_le_bytes());
+        assert_eq!(read_u32(mmap, 48), E820Entry::RAM_TYPE);
+        let fb = tags[6].1;
+        assert_eq!(fb.len(), 30);
+        assert_eq!(read_u32(fb, 8), 1280 * 4);
+        assert_eq!(&fb[20..], &[32, 1, 0, 0, 16, 8, 8, 8, 0, 8]);
+    }
+
+    #[test]
+    fn test_writer_padding() {
+        let mut data = [0xcc; 64];
+        let mut writer = InfoWriter::new(&mut data);
+        writer.tag(1, &[b"abc\0"]);
+        assert_eq!(writer.finish(), 32);
+        assert_eq!(
+            &data[..32],
+            &[
+                32, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 12, 0, 0, 0, b'a', b'b', b'c', 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 8, 0, 0, 0
+            ]
+        );
+    }
+}
@@ -0,0 +1,533 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::cell::RefCell;
+
+use crate::{
+    delay,
+    virtio::{self, AvailRing, Desc, Error as VirtioError, UsedRing, VirtioTransport, QUEUE_SIZE},
+};
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+// Receive buffers kept posted to the device
+const RX_BUFFERS: usize = 8;
+// The virtio_net_hdr every frame is preceded by, with num_buffers as it
+// always has that with VIRTIO_F_VERSION_1, and legacy devices are only used
+// with VIRTIO_NET_F_MRG_RXBUF for it
+const HEADER_SIZE: usize = 12;
+/// An Ethernet frame without the FCS, which the device deals with
+pub const MAX_FRAME_SIZE: usize = 1514;
+const BUFFER_SIZE: usize = HEADER_SIZE + MAX_FRAME_SIZE;
+// How long the device gets to be done with a frame we send
+const SEND_TIMEOUT_MS: u64 = 100;
+
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+#[repr(align(64))]
+#[derive(Default)]
+struct Queue {
+    descriptors: [Desc; QUEUE_SIZE],
+    avail: AvailRing,
+    used: UsedRing,
+    // How far through the used ring we have got
+    last_used: u16,
+}
+
+impl Queue {
+    fn program(&self, transport: &dyn VirtioTransport, queue: u16) -> Result<(), VirtioError> {
+        transport.set_queue(queue);
+        if transport.get_queue_max_size() < QUEUE_SIZE as u16 {
+            return Err(VirtioError::VirtioQueueTooSmall);
+        }
+        transport.set_queue_size(QUEUE_SIZE as u16);
+        transport.set_descriptors_address(self.descriptors.as_ptr() as u64);
+        transport.set_avail_ring((&self.avail as *const _) as u64);
+        transport.set_used_ring((&self.used as *const _) as u64);
+        transport.set_queue_enable()
+    }
+
+    // Hands the chain starting at the descriptor to the device
+    fn push(&mut self, head: u16) {
+        let avail_index = self.avail.idx;
+        self.avail.ring[(avail_index % QUEUE_SIZE as u16) as usize] = head;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+        self.avail.idx = avail_index.wrapping_add(1);
+    }
+
+    // The next descriptor the device is done with and how much it wrote,
+    // which is left in the ring
+    fn peek(&self) -> Option<(u16, usize)> {
+        // The device updates the ring behind the compiler's back
+        if unsafe { core::ptr::read_volatile(&self.used.idx) } == self.last_used {
+            return None;
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        let elem = &self.used.ring[(self.last_used % QUEUE_SIZE as u16) as usize];
+        Some((elem.id as u16, elem.len as usize))
+    }
+
+    fn pop(&mut self) -> Option<(u16, usize)> {
+        let used = self.peek()?;
+        self.last_used = self.last_used.wrapping_add(1);
+        Some(used)
+    }
+}
+
+#[repr(C)]
+#[repr(align(64))]
+struct DriverState {
+    rx: Queue,
+    tx: Queue,
+    rx_buffers: [[u8; BUFFER_SIZE]; RX_BUFFERS],
+    tx_buffer: [u8; BUFFER_SIZE],
+}
+
+impl Default for DriverState {
+    fn default() -> Self {
+        DriverState {
+            rx: Queue::default(),
+            tx: Queue::default(),
+            rx_buffers: [[0; BUFFER_SIZE]; RX_BUFFERS],
+            tx_buffer: [0; BUFFER_SIZE],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    FrameTooLarge,
+    // The device didn't send the frame in time, or still hasn't sent the
+    // last one that timed out
+    Timeout,
+}
+
+/// Device driver for virtio net over any transport, sending and receiving
+/// one Ethernet frame at a time
+pub struct VirtioNetDevice<'a> {
+    transport: &'a mut dyn VirtioTransport,
+    state: RefCell<DriverState>,
+    mac: [u8; 6],
+}
+
+impl<'a> VirtioNetDevice<'a> {
+    pub fn new(transport: &'a mut dyn VirtioTransport) -> VirtioNetDevice<'a> {
+        VirtioNetDevice {
+            transport,
+            state: RefCell::new(DriverState::default()),
+            mac: [0; 6],
+        }
+    }
+
+    // Resets the device and agrees on the features to use with it
+    fn handshake(&self) -> Result<(), VirtioError> {
+        const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+        const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+        const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+
+        const VIRTIO_STATUS_RESET: u32 = 0;
+        const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+        const VIRTIO_STATUS_DRIVER: u32 = 2;
+        const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        self.transport.set_status(VIRTIO_STATUS_RESET);
+        self.transport.add_status(VIRTIO_STATUS_ACKNOWLEDGE);
+        self.transport.add_status(VIRTIO_STATUS_DRIVER);
+
+        let device_features = self.transport.get_features();
+        // A legacy device's header only has num_buffers with mergeable
+        // receive buffers, which as each buffer takes a whole frame are
+        // never merged
+        let features = match device_features & VIRTIO_F_VERSION_1 {
+            0 if self.transport.legacy() && device_features & VIRTIO_NET_F_MRG_RXBUF != 0 => {
+                VIRTIO_NET_F_MAC | VIRTIO_NET_F_MRG_RXBUF
+            }
+            0 => {
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(VirtioError::VirtioLegacyOnly);
+            }
+            _ => VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC,
+        };
+        // Without an address from the device there is nothing to put in the
+        // frames we send
+        if device_features & VIRTIO_NET_F_MAC != VIRTIO_NET_F_MAC {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(VirtioError::VirtioFeatureNegotiationFailed);
+        }
+        self.transport.set_features(features);
+        // A legacy device has no say in the features
+        if self.transport.legacy() {
+            return Ok(());
+        }
+
+        self.transport.add_status(VIRTIO_STATUS_FEATURES_OK);
+        if self.transport.get_status() & VIRTIO_STATUS_FEATURES_OK != VIRTIO_STATUS_FEATURES_OK {
+            self.transport.add_status(VIRTIO_STATUS_FAILED);
+            return Err(VirtioError::VirtioFeatureNegotiationFailed);
+        }
+        Ok(())
+    }
+
+    pub fn init(&mut self) -> Result<(), VirtioError> {
+        const VIRTIO_SUBSYSTEM_NET: u32 = 0x1;
+
+        const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        self.transport.init(VIRTIO_SUBSYSTEM_NET)?;
+        // The device may not be ready to agree straight away
+        virtio::retry_handshake(|| self.handshake())?;
+
+        // mac: 0x0, followed by the status
+        let low = self.transport.read_device_config(0).to_le_bytes();
+        let high = self.transport.read_device_config(4).to_le_bytes();
+        self.mac[..4].copy_from_slice(&low);
+        self.mac[4..].copy_from_slice(&high[..2]);
+
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        for (queue, index) in [(&state.rx, RX_QUEUE), (&state.tx, TX_QUEUE)].iter() {
+            if let Err(e) = queue.program(self.transport, *index) {
+                self.transport.add_status(VIRTIO_STATUS_FAILED);
+                return Err(e);
+            }
+        }
+
+        // All of the receive buffers start off with the device, each in a
+        // descriptor of its own
+        for (i, buffer) in state.rx_buffers.iter().enumerate() {
+            let d = &mut state.rx.descriptors[i];
+            d.addr = buffer.as_ptr() as u64;
+            d.length = BUFFER_SIZE as u32;
+            d.flags = VIRTQ_DESC_F_WRITE;
+            state.rx.push(i as u16);
+        }
+        let d = &mut state.tx.descriptors[0];
+        d.addr = state.tx_buffer.as_ptr() as u64;
+
+        self.transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+        self.transport.notify_queue(RX_QUEUE);
+
+        Ok(())
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Sends the Ethernet frame, waiting for the device to be done with it
+    pub fn send(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(Error::FrameTooLarge);
+        }
+        let mut state = self.state.borrow_mut();
+        // The buffer can't be used again while the device has it
+        if state.tx.avail.idx != state.tx.last_used && state.tx.pop().is_none() {
+            return Err(Error::Timeout);
+        }
+        state.tx_buffer[..HEADER_SIZE].fill(0);
+        state.tx_buffer[HEADER_SIZE..HEADER_SIZE + frame.len()].copy_from_slice(frame);
+        state.tx.descriptors[0].length = (HEADER_SIZE + frame.len()) as u32;
+        state.tx.push(0);
+        self.transport.notify_queue(TX_QUEUE);
+
+        if !delay::wait_until(SEND_TIMEOUT_MS, || state.tx.peek().is_some()) {
+            return Err(Error::Timeout);
+        }
+        state.tx.pop();
+        Ok(())
+    }
+
+    /// The length of the next received frame, if one has come in, which is
+    /// left for recv()
+    pub fn peek(&self) -> Option<usize> {
+        let (_, len) = self.state.borrow().rx.peek()?;
+        Some(len.saturating_sub(HEADER_SIZE))
+    }
+
+    /// Copies the next received frame into buf, returning its length, if
+    /// one has come in. The frame is cut short if buf is too small.
+    pub fn recv(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut state = self.state.borrow_mut();
+        let (id, len) = state.rx.pop()?;
+        let buffer = &state.rx_buffers[id as usize];
+        let frame = &buffer[HEADER_SIZE..core::cmp::max(len, HEADER_SIZE)];
+        let len = core::cmp::min(frame.len(), buf.len());
+        buf[..len].copy_from_slice(&frame[..len]);
+
+        // The buffer goes straight back to the device
+        state.rx.push(id);
+        self.transport.notify_queue(RX_QUEUE);
+        Some(len)
+    }
+
+    /// Stops the device using the buffers it has, once whatever it was
+    /// used for takes over the memory without taking over the device
+    pub fn reset(&self) {
+        self.transport.reset();
+    }
+}
+
+// The device is reset when we are done with it, as otherwise it would carry
+// on receiving frames into memory that is no longer ours
+impl<'a> Drop for VirtioNetDevice<'a> {
+    fn drop(&mut self) {
+        self.transport.reset();
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::{Error, VirtioNetDevice, HEADER_SIZE, MAX_FRAME_SIZE, RX_BUFFERS};
+    use crate::virtio::{AvailRing, Desc, Error as VirtioError, UsedRing, VirtioTransport};
+
+    const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+    const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+    #[derive(Default)]
+    struct FakeQueue {
+        size: Cell<u16>,
+        descriptors: Cell<u64>,
+        avail: Cell<u64>,
+        used: Cell<u64>,
+        last_avail: Cell<u16>,
+    }
+
+    impl FakeQueue {
+        fn avail(&self) -> &AvailRing {
+            unsafe { &*(self.avail.get() as *const AvailRing) }
+        }
+
+        // The next descriptor the driver has made available
+        fn take(&self) -> Option<(u16, &mut Desc)> {
+            if self.last_avail.get() == self.avail().idx {
+                return None;
+            }
+            let head = self.avail().ring[(self.last_avail.get() % self.size.get()) as usize];
+            self.last_avail.set(self.last_avail.get().wrapping_add(1));
+            let descriptors = self.descriptors.get() as *mut Desc;
+            Some((head, unsafe { &mut *descriptors.add(head as usize) }))
+        }
+
+        fn complete(&self, head: u16, len: u32) {
+            let used = unsafe { &mut *(self.used.get() as *mut UsedRing) };
+            let elem = &mut used.ring[(used.idx % self.size.get()) as usize];
+            elem.id = u32::from(head);
+            elem.len = len;
+            used.idx = used.idx.wrapping_add(1);
+        }
+    }
+
+    /// Emulates a virtio net device whose frames come straight back, as long
+    /// as there are receive buffers for them
+    pub struct LoopbackTransport {
+        device_features: u64,
+        driver_features: Cell<u64>,
+        status: Cell<u32>,
+        selected: Cell<usize>,
+        queues: [FakeQueue; 2],
+        frames: RefCell<Vec<Vec<u8>>>,
+        // Whether frames to send are left with the device
+        stalled: Cell<bool>,
+    }
+
+    impl LoopbackTransport {
+        pub fn new() -> LoopbackTransport {
+            LoopbackTransport {
+                device_features: VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC,
+                driver_features: Cell::new(0),
+                status: Cell::new(0),
+                selected: Cell::new(0),
+                queues: Default::default(),
+                frames: RefCell::new(Vec::new()),
+                stalled: Cell::new(false),
+            }
+        }
+
+        fn queue(&self) -> &FakeQueue {
+            &self.queues[self.selected.get()]
+        }
+
+        // Delivers the frames that have been sent to whatever receive
+        // buffers there are
+        fn deliver(&self) {
+            let rx = &self.queues[0];
+            let mut frames = self.frames.borrow_mut();
+            while !frames.is_empty() {
+                let (head, d) = match rx.take() {
+                    Some(buffer) => buffer,
+                    None => return,
+                };
+                let frame = frames.remove(0);
+                let buffer =
+                    unsafe { std::slice::from_raw_parts_mut(d.addr as *mut u8, d.length as usize) };
+                buffer[..HEADER_SIZE].fill(0);
+                buffer[HEADER_SIZE..HEADER_SIZE + frame.len()].copy_from_slice(&frame);
+                rx.complete(head, (HEADER_SIZE + frame.len()) as u32);
+            }
+        }
+    }
+
+    impl VirtioTransport for LoopbackTransport {
+        fn init(&mut self, device_type: u32) -> Result<(), VirtioError> {
+            assert_eq!(device_type, 1);
+            Ok(())
+        }
+        fn get_status(&self) -> u32 {
+            self.status.get()
+        }
+        fn set_status(&self, status: u32) {
+            self.status.set(status)
+        }
+        fn add_status(&self, status: u32) {
+            self.status.set(self.status.get() | status)
+        }
+        fn reset(&self) {
+            self.status.set(0)
+        }
+        fn get_features(&self) -> u64 {
+            self.device_features
+        }
+        fn set_features(&self, features: u64) {
+            self.driver_features.set(features)
+        }
+        fn set_queue(&self, queue: u16) {
+            self.selected.set(queue as usize)
+        }
+        fn get_queue_max_size(&self) -> u16 {
+            256
+        }
+        fn set_queue_size(&self, queue_size: u16) {
+            self.queue().size.set(queue_size)
+        }
+        fn set_descriptors_address(&self, address: u64) {
+            self.queue().descriptors.set(address)
+        }
+        fn set_avail_ring(&self, address: u64) {
+            self.queue().avail.set(address)
+        }
+        fn set_used_ring(&self, address: u64) {
+            self.queue().used.set(address)
+        }
+        fn set_queue_enable(&self) -> Result<(), VirtioError> {
+            Ok(())
+        }
+        fn notify_queue(&self, queue: u16) {
+            if queue == 1 && !self.stalled.get() {
+                let tx = &self.queues[1];
+                while let Some((head, d)) = tx.take() {
+                    let buffer = unsafe {
+                        std::slice::from_raw_parts(d.addr as *const u8, d.length as usize)
+                    };
+                    assert!(buffer[..HEADER_SIZE].iter().all(|b| *b == 0));
+                    self.frames
+                        .borrow_mut()
+                        .push(buffer[HEADER_SIZE..].to_vec());
+                    tx.complete(head, 0);
+                }
+            }
+            self.deliver();
+        }
+        fn read_device_config(&self, offset: u64) -> u32 {
+            match offset {
+                0 => 0x1200_5452,
+                4 => 0x0001_5634,
+                _ => 0,
+            }
+        }
+    }
+
+    // An ARP request from 10.0.2.15 for 10.0.2.2
+    fn arp_request(mac: &[u8; 6]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(mac);
+        frame.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0, 6, 4, 0, 1]);
+        frame.extend_from_slice(mac);
+        frame.extend_from_slice(&[10, 0, 2, 15, 0, 0, 0, 0, 0, 0, 10, 0, 2, 2]);
+        frame
+    }
+
+    #[test]
+    fn test_send_recv() {
+        let mut transport = LoopbackTransport::new();
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_eq!(device.mac(), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+        let mut buf = [0; MAX_FRAME_SIZE];
+        assert_eq!(device.recv(&mut buf), None);
+        assert_eq!(device.peek(), None);
+        let frame = arp_request(&device.mac());
+        device.send(&frame).unwrap();
+        assert_eq!(device.peek(), Some(frame.len()));
+        assert_eq!(device.peek(), Some(frame.len()));
+        assert_eq!(device.recv(&mut buf), Some(frame.len()));
+        assert_eq!(&buf[..frame.len()], &frame[..]);
+        assert_eq!(device.recv(&mut buf), None);
+
+        // More frames than there are buffers for, which wait for them to
+        // be handed back. Too small a buffer gets the start of the frame.
+        for i in 0..RX_BUFFERS * 3 {
+            device.send(&[i as u8; 60]).unwrap();
+        }
+        for i in 0..RX_BUFFERS * 3 {
+            let mut small = [0; 16];
+            assert_eq!(device.recv(&mut small), Some(16));
+            assert_eq!(small, [i as u8; 16]);
+        }
+        assert_eq!(device.recv(&mut buf), None);
+
+        assert_eq!(
+            device.send(&[0; MAX_FRAME_SIZE + 1]),
+            Err(Error::FrameTooLarge)
+        );
+        device.send(&[0xaa; MAX_FRAME_SIZE]).unwrap();
+        assert_eq!(device.recv(&mut buf), Some(MAX_FRAME_SIZE));
+
+        drop(device);
+        assert_eq!(transport.get_status(), 0);
+        assert_eq!(
+            transport.driver_features.get(),
+            VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC
+        );
+    }
+
+    #[test]
+    fn test_no_mac() {
+        const VIRTIO_STATUS_FAILED: u32 = 128;
+
+        let mut transport = LoopbackTransport::new();
+        transport.device_features = VIRTIO_F_VERSION_1;
+        let mut device = VirtioNetDevice::new(&mut transport);
+        assert!(matches!(
+            device.init(),
+            Err(VirtioError::VirtioFeatureNegotiationFailed)
+        ));
+        assert_ne!(device.transport.get_status() & VIRTIO_STATUS_FAILED, 0);
+    }
+
+    #[test]
+    fn test_send_timeout() {
+        let mut transport = LoopbackTransport::new();
+        transport.stalled.set(true);
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        let frame = arp_request(&device.mac());
+        assert_eq!(device.send(&frame), Err(Error::Timeout));
+        // Which holds up the next one, however long it's waited for
+        assert_eq!(device.send(&frame), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut transport = LoopbackTransport::new();
+        let mut device = VirtioNetDevice::new(&mut transport);
+        device.init().expect("Error initialising device");
+        assert_ne!(device.transport.get_status(), 0);
+        device.reset();
+        assert_eq!(device.transport.get_status(), 0);
+    }
+}
@@ -0,0 +1,569 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use core::cell::Cell;
+
+use atomic_refcell::AtomicRefCell;
+
+use crate::{
+    block::{BlockDevice, Error as BlockError, SectorRead, SectorWrite},
+    delay, mem,
+    pci::PciDevice,
+};
+
+const QUEUE_SIZE: usize = 16;
+const PAGE_SIZE: u64 = 4096;
+// Largest transfer issued as a single command, which a PRP list covers
+const MAX_REQUEST_SECTORS: usize = 128;
+// Buffers that aren't dword aligned go through a page, a sector at a time
+const BOUNCE_SECTORS: usize = PAGE_SIZE as usize / 512;
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+
+const ADMIN_QUEUE_ID: u16 = 0;
+const IO_QUEUE_ID: u16 = 1;
+
+// Controller configuration: enable, with the NVM command set, 4KiB pages
+// and 64 byte submission and 16 byte completion entries (as powers of two)
+const CC_ENABLE: u32 = 1;
+const CC_IO_QUEUE_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+// Controller status
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+// Admin commands
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+// What Identify returns
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const QUEUE_PHYSICALLY_CONTIGUOUS: u32 = 1;
+
+// NVM commands
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// NVMe related errors
+#[derive(Debug)]
+pub enum Error {
+    NvmeNoRegisters,
+    NvmeUnsupportedPageSize,
+    NvmeQueueTooSmall,
+    NvmeControllerTimeout,
+    NvmeControllerFatal,
+    NvmeCommandTimeout,
+    NvmeCommandFailed(u16),
+    NvmeNoNamespace,
+    NvmeUnsupportedBlockSize(u64),
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// A submission queue entry
+struct Command {
+    opcode: u8,
+    flags: u8,
+    id: u16,
+    namespace: u32,
+    reserved: u64,
+    metadata: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+/// A completion queue entry
+struct Completion {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    id: u16,
+    // Phase tag in bit 0, status in bits 15-1
+    status: u16,
+}
+
+const EMPTY_COMMAND: Command = Command {
+    opcode: 0,
+    flags: 0,
+    id: 0,
+    namespace: 0,
+    reserved: 0,
+    metadata: 0,
+    prp1: 0,
+    prp2: 0,
+    cdw10: 0,
+    cdw11: 0,
+    cdw12: 0,
+    cdw13: 0,
+    cdw14: 0,
+    cdw15: 0,
+};
+
+const EMPTY_COMPLETION: Completion = Completion {
+    result: 0,
+    reserved: 0,
+    sq_head: 0,
+    sq_id: 0,
+    id: 0,
+    status: 0,
+};
+
+#[repr(C)]
+#[repr(align(4096))]
+/// Queues and PRP lists have to start on a page of their own
+struct Page<T>(T);
+
+/// A submission queue with the completion queue the controller answers it on
+struct Queue {
+    submissions: Page<[Command; QUEUE_SIZE]>,
+    completions: Page<[Completion; QUEUE_SIZE]>,
+    id: u16,
+    tail: u16,
+    head: u16,
+    // Phase tag the controller gives new completions, which flips every
+    // time it wraps around the completion queue
+    phase: u16,
+}
+
+impl Queue {
+    const fn new(id: u16) -> Queue {
+        Queue {
+            submissions: Page([EMPTY_COMMAND; QUEUE_SIZE]),
+            completions: Page([EMPTY_COMPLETION; QUEUE_SIZE]),
+            id,
+            tail: 0,
+            head: 0,
+            phase: 1,
+        }
+    }
+
+    // Back to how the controller expects a newly created queue to be
+    fn reset(&mut self) {
+        self.completions.0 = [EMPTY_COMPLETION; QUEUE_SIZE];
+        self.tail = 0;
+        self.head = 0;
+        self.phase = 1;
+    }
+}
+
+struct DriverState {
+    admin: Queue,
+    io: Queue,
+    prp_list: Page<[u64; PAGE_SIZE as usize / 8]>,
+    // Identify data, and where unaligned buffers are bounced through
+    buffer: Page<[u8; PAGE_SIZE as usize]>,
+}
+
+// The queues and buffers take several pages, so they are kept off the stack
+// and shared by the controllers, of which only one is in use at a time
+static STATE: AtomicRefCell<DriverState> = AtomicRefCell::new(DriverState {
+    admin: Queue::new(ADMIN_QUEUE_ID),
+    io: Queue::new(IO_QUEUE_ID),
+    prp_list: Page([0; PAGE_SIZE as usize / 8]),
+    buffer: Page([0; PAGE_SIZE as usize]),
+});
+
+/// Device driver for an NVMe controller, using its first active namespace
+pub struct NvmeDevice {
+    device: PciDevice,
+    registers: mem::MemoryRegion,
+    doorbell_stride: u64,
+    namespace: u32,
+    capacity: u64,
+    read_only: bool,
+    max_request_sectors: usize,
+    requests: Cell<u64>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// The Physical Region Page entries for a buffer: the first one can start
+// anywhere in a page and the rest are whole pages, which go in the list
+// once there are more than two
+fn prps(address: u64, length: u64, list: &mut [u64]) -> (u64, u64) {
+    let first_page = address & !(PAGE_SIZE - 1);
+    let pages = (address + length - first_page + PAGE_SIZE - 1) / PAGE_SIZE;
+    match pages {
+        1 => (address, 0),
+        2 => (address, first_page + PAGE_SIZE),
+        _ => {
+            assert!(pages as usize - 1 <= list.len());
+            for (i, entry) in list.iter_mut().take(pages as usize - 1).enumerate() {
+                *entry = first_page + (i as u64 + 1) * PAGE_SIZE;
+            }
+            (address, list.as_ptr() as u64)
+        }
+    }
+}
+
+impl NvmeDevice {
+    pub fn new(device: PciDevice) -> NvmeDevice {
+        NvmeDevice {
+            device,
+            registers: mem::MemoryRegion::default(),
+            doorbell_stride: 0,
+            namespace: 0,
+            capacity: 0,
+            read_only: false,
+            max_request_sectors: MAX_REQUEST_SECTORS,
+            requests: Cell::new(0),
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        self.device.init();
+
+        // The registers are at the start of BAR0, followed by the doorbells
+        // at 0x1000 that are spaced out by the stride in cap
+        let registers = self
+            .device
+            .bar_region(0, 0, 0x1000)
+            .ok_or(Error::NvmeNoRegisters)?;
+        // cap: 0x00
+        let cap =
+            u64::from(registers.io_read_u32(0x00)) | u64::from(registers.io_read_u32(0x04)) << 32;
+        // Doorbell stride in bits 35-32, as a power of two times 4 bytes
+        self.doorbell_stride = 4 << (cap >> 32 & 0xf);
+        // One doorbell for each of the two queues of the admin and I/O pairs
+        self.registers = self
+            .device
+            .bar_region(0, 0, (0x1000 + 4 * self.doorbell_stride) as u32)
+            .ok_or(Error::NvmeNoRegisters)?;
+
+        // Smallest page size in bits 51-48, as a power of two times 4KiB
+        if cap >> 48 & 0xf != 0 {
+            return Err(Error::NvmeUnsupportedPageSize);
+        }
+        // Largest queue, less one, in bits 15-0
+        if (cap & 0xffff) + 1 < QUEUE_SIZE as u64 {
+            return Err(Error::NvmeQueueTooSmall);
+        }
+        // How long the controller may take to become ready in bits 31-24,
+        // in 500ms units
+        let timeout = (cap >> 24 & 0xff) * 500;
+
+        // vs: 0x08
+        let version = self.registers.io_read_u32(0x08);
+        log!(
+            "NVMe controller version {}.{}",
+            version >> 16,
+            version >> 8 & 0xff
+        );
+
+        // The controller has to be disabled before the admin queue can be set
+        // cc: 0x14
+        self.registers.io_write_u32(0x14, 0);
+        let stopped = !delay::wait_while(timeout, || self.status() & CSTS_READY != 0);
+        if !stopped {
+            return Err(Error::NvmeControllerTimeout);
+        }
+
+        let mut state = STATE.borrow_mut();
+        let state = &mut *state;
+        state.admin.reset();
+        state.io.reset();
+
+        // aqa: 0x24, the sizes of both admin queues less one
+        let queue_size = QUEUE_SIZE as u32 - 1;
+        self.registers
+            .io_write_u32(0x24, queue_size << 16 | queue_size);
+        // asq: 0x28, acq: 0x30
+        self.write_u64(0x28, state.admin.submissions.0.as_ptr() as u64);
+        self.write_u64(0x30, state.admin.completions.0.as_ptr() as u64);
+
+        self.registers
+            .io_write_u32(0x14, CC_ENABLE | CC_IO_QUEUE_ENTRY_SIZES);
+        let ready = delay::wait_until(timeout, || self.status() & (CSTS_READY | CSTS_FATAL) != 0);
+        if !ready {
+            return Err(Error::NvmeControllerTimeout);
+        }
+        if self.status() & CSTS_FATAL != 0 {
+            return Err(Error::NvmeControllerFatal);
+        }
+
+        self.identify(state, IDENTIFY_CONTROLLER, 0)?;
+        // mn: 24, the model number padded with spaces
+        let model = core::str::from_utf8(&state.buffer.0[24..64]).unwrap_or("");
+        log!("NVMe controller: {}", model.trim_end());
+        // mdts: 77, the largest transfer as a power of two times the page
+        // size, with 0 meaning there's no limit
+        let mdts = state.buffer.0[77];
+        if mdts != 0 && mdts < 16 {
+            self.max_request_sectors =
+                core::cmp::min(MAX_REQUEST_SECTORS, (PAGE_SIZE << mdts) as usize / 512);
+        }
+
+        // Controllers before NVMe 1.1 can't list their namespaces
+        self.namespace = match self.identify(state, IDENTIFY_ACTIVE_NAMESPACES, 0) {
+            Ok(()) => read_u32(&state.buffer.0, 0),
+            Err(_) => 1,
+        };
+        if self.namespace == 0 {
+            return Err(Error::NvmeNoNamespace);
+        }
+
+        self.identify(state, IDENTIFY_NAMESPACE, self.namespace)?;
+        let data = &state.buffer.0;
+        // nsze: 0, the size in logical blocks
+        self.capacity = read_u64(data, 0);
+        if self.capacity == 0 {
+            return Err(Error::NvmeNoNamespace);
+        }
+        // flbas: 26, the format in use in bits 3-0, with the formats from
+        // 128 on and the block size as a power of two in their third byte
+        let format = usize::from(data[26] & 0xf);
+        let block_size = 1u64 << data[128 + 4 * format + 2];
+        if block_size != 512 {
+            return Err(Error::NvmeUnsupportedBlockSize(block_size));
+        }
+        // nsattr: 99, bit 0 is set if the namespace is write protected
+        self.read_only = data[99] & 1 != 0;
+        log!(
+            "NVMe namespace {}: {} blocks of {} bytes",
+            self.namespace,
+            self.capacity,
+            block_size
+        );
+
+        // The completion queue has to be there before its submission queue
+        let queue_size = (QUEUE_SIZE as u32 - 1) << 16 | u32::from(IO_QUEUE_ID);
+        let command = Command {
+            opcode: ADMIN_CREATE_IO_CQ,
+            prp1: state.io.completions.0.as_ptr() as u64,
+            cdw10: queue_size,
+            cdw11: QUEUE_PHYSICALLY_CONTIGUOUS,
+            ..Default::default()
+        };
+        self.submit(&mut state.admin, command)?;
+        let command = Command {
+            opcode: ADMIN_CREATE_IO_SQ,
+            prp1: state.io.submissions.0.as_ptr() as u64,
+            cdw10: queue_size,
+            cdw11: u32::from(IO_QUEUE_ID) << 16 | QUEUE_PHYSICALLY_CONTIGUOUS,
+            ..Default::default()
+        };
+        self.submit(&mut state.admin, command)?;
+
+        Ok(())
+    }
+
+    // csts: 0x1c
+    fn status(&self) -> u32 {
+        self.registers.io_read_u32(0x1c)
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.registers.io_write_u32(offset, value as u32);
+        self.registers
+            .io_write_u32(offset + 4, (value >> 32) as u32);
+    }
+
+    // Submission queue tail doorbells come first for each queue, followed by
+    // the completion queue head doorbell
+    fn doorbell(&self, queue: u16, completion: bool) -> u64 {
+        0x1000 + (2 * u64::from(queue) + completion as u64) * self.doorbell_stride
+    }
+
+    // Identify data ends up in the driver's buffer
+    fn identify(&self, state: &mut DriverState, cns: u32, namespace: u32) -> Result<(), Error> {
+        let command = Command {
+            opcode: ADMIN_IDENTIFY,
+            namespace,
+            prp1: state.buffer.0.as_ptr() as u64,
+            cdw10: cns,
+            ..Default::default()
+        };
+        self.submit(&mut state.admin, command).map(|_| ())
+    }
+
+    // Submits a command and polls for its completion, returning the command
+    // specific result
+    fn submit(&self, queue: &mut Queue, mut command: Command) -> Result<u32, Error> {
+        command.id = queue.tail;
+        unsafe {
+            core::ptr::write_volatile(&mut queue.submissions.0[usize::from(queue.tail)], command)
+        };
+        queue.tail = (queue.tail + 1) % QUEUE_SIZE as u16;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.registers
+            .io_write_u32(self.doorbell(queue.id, false), u32::from(queue.tail));
+
+        // The controller writes the completion behind the compiler's back
+        let completion = &queue.completions.0[usize::from(queue.head)] as *const Completion;
+        let phase = queue.phase;
+        let completed = delay::wait_until(COMMAND_TIMEOUT_MS, || {
+            (unsafe { core::ptr::read_volatile(&(*completion).status) } & 1) == phase
+        });
+        if !completed {
+            return Err(Error::NvmeCommandTimeout);
+        }
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+        let completion = unsafe { core::ptr::read_volatile(completion) };
+
+        queue.head = (queue.head + 1) % QUEUE_SIZE as u16;
+        if queue.head == 0 {
+            queue.phase ^= 1;
+        }
+        self.registers
+            .io_write_u32(self.doorbell(queue.id, true), u32::from(queue.head));
+
+        // Status code type in bits 11-9 and status code in bits 8-1
+        match completion.status >> 1 & 0x7ff {
+            0 => Ok(completion.result),
+            status => Err(Error::NvmeCommandFailed(status)),
+        }
+    }
+
+    fn request(&self, opcode: u8, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        let len = data.len();
+        assert!(len > 0 && len % 512 == 0 && len <= self.max_request_sectors * 512);
+
+        let mut state = STATE.borrow_mut();
+        let state = &mut *state;
+
+        // PRP entries have to be dword aligned
+        let bounce = data.as_ptr() as usize % 4 != 0;
+        let address = if bounce {
+            assert!(len <= BOUNCE_SECTORS * 512);
+            if opcode == IO_WRITE {
+                state.buffer.0[..len].copy_from_slice(data);
+            }
+            state.buffer.0.as_ptr() as u64
+        } else {
+            data.as_ptr() as u64
+        };
+        let (prp1, prp2) = prps(address, len as u64, &mut state.prp_list.0);
+
+        let command = Command {
+            opcode,
+            namespace: self.namespace,
+            prp1,
+            prp2,
+            cdw10: sector as u32,
+            cdw11: (sector >> 32) as u32,
+            // Number of blocks, less one
+            cdw12: (len / 512 - 1) as u32,
+            ..Default::default()
+        };
+        self.requests.set(self.requests.get() + 1);
+        if let Err(err) = self.submit(&mut state.io, command) {
+            log!("NVMe request failed: {:?}", err);
+            return Err(BlockError::BlockIOError);
+        }
+
+        if bounce && opcode == IO_READ {
+            data.copy_from_slice(&state.buffer.0[..len]);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for NvmeDevice {
+    fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Number of read, write and flush commands submitted to the I/O queue
+    fn request_count(&self) -> u64 {
+        self.requests.get()
+    }
+}
+
+impl SectorRead for NvmeDevice {
+    fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!(data.len(), 512);
+        self.request(IO_READ, sector, data)
+    }
+
+    fn read_multi(&self, start_sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!(data.len() % 512, 0);
+        let sectors = if data.as_ptr() as usize % 4 == 0 {
+            self.max_request_sectors
+        } else {
+            core::cmp::min(BOUNCE_SECTORS, self.max_request_sectors)
+        };
+        let mut sector = start_sector;
+        for chunk in data.chunks_mut(sectors * 512) {
+            self.request(IO_READ, sector, chunk)?;
+            sector += (chunk.len() / 512) as u64;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> Option<u64> {
+        Some(self.capacity)
+    }
+}
+
+impl SectorWrite for NvmeDevice {
+    fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), BlockError> {
+        assert_eq!(data.len(), 512);
+        self.request(IO_WRITE, sector, data)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = Command {
+            opcode: IO_FLUSH,
+            namespace: self.namespace,
+            ..Default::default()
+        };
+        self.requests.set(self.requests.get() + 1);
+        match self.submit(&mut STATE.borrow_mut().io, command) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                log!("NVMe flush failed: {:?}", err);
+                Err(BlockError::BlockIOError)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{prps, PAGE_SIZE};
+
+    #[test]
+    fn test_prps() {
+        let mut list = [0u64; 512];
+
+        // Within a page and across into a second one
+        assert_eq!(prps(0x10_0200, 512, &mut list), (0x10_0200, 0));
+        assert_eq!(prps(0x10_0e00, 1024, &mut list), (0x10_0e00, 0x10_1000));
+        assert_eq!(
+            prps(0x10_0000, 2 * PAGE_SIZE, &mut list),
+            (0x10_0000, 0x10_1000)
+        );
+
+        // 64KiB that isn't page aligned touches 17 pages
+        let (prp1, prp2) = prps(0x10_0004, 64 * 1024, &mut list);
+        assert_eq!(prp1, 0x10_0004);
+        assert_eq!(prp2, list.as_ptr() as u64);
+        for (i, entry) in list[..16].iter().enumerate() {
+            assert_eq!(*entry, 0x10_1000 + i as u64 * PAGE_SIZE);
+        }
+        assert_eq!(list[16], 0);
+    }
+}
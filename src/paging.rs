@@ -1,41 +1,67 @@
+#[cfg(feature = "section-protection")]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::__cpuid;
+
+#[cfg(feature = "section-protection")]
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{PageTableEntry, Size4KiB},
+};
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{PageSize, PageTable, PageTableFlags, PhysFrame, Size2MiB},
+    structures::paging::{PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB},
     PhysAddr,
 };
 
-// Amount of memory we identity map in setup(), max 512 GiB.
-const ADDRESS_SPACE_GIB: usize = 4;
+use crate::boot::{E820Entry, Info};
+
+// Amount of memory we identity map in setup() with 2 MiB pages.
+const LOW_ADDRESS_SPACE_GIB: usize = 4;
+// 2 MiB pages are used above that if the CPU lacks 1 GiB pages, which needs
+// an L2 table per GiB, so only this much can then be mapped.
+const SMALL_PAGE_ADDRESS_SPACE_GIB: usize = 8;
+// Each L3 table covers 512 GiB, the first one is set up by setup().
+const L3_TABLE_GIB: usize = 512;
+const HIGH_L3_TABLE_COUNT: usize = 3;
+// For splitting up the 2 MiB pages of regions given their own protection by
+// protect(), each covers 2 MiB
+#[cfg(feature = "section-protection")]
+const L1_TABLE_COUNT: usize = 16;
 const TABLE: PageTable = PageTable::new();
 
+// Everything below this is identity mapped
+static MAPPED_SIZE: AtomicU64 = AtomicU64::new(0);
+
 // Put the Page Tables in static muts to make linking easier
 #[no_mangle]
 static mut L4_TABLE: PageTable = PageTable::new();
 #[no_mangle]
 static mut L3_TABLE: PageTable = PageTable::new();
 #[no_mangle]
-static mut L2_TABLES: [PageTable; ADDRESS_SPACE_GIB] = [TABLE; ADDRESS_SPACE_GIB];
+static mut L2_TABLES: [PageTable; SMALL_PAGE_ADDRESS_SPACE_GIB] =
+    [TABLE; SMALL_PAGE_ADDRESS_SPACE_GIB];
+static mut HIGH_L3_TABLES: [PageTable; HIGH_L3_TABLE_COUNT] = [TABLE; HIGH_L3_TABLE_COUNT];
+#[cfg(feature = "section-protection")]
+static mut L1_TABLES: [PageTable; L1_TABLE_COUNT] = [TABLE; L1_TABLE_COUNT];
+#[cfg(feature = "section-protection")]
+static L1_TABLES_USED: AtomicUsize = AtomicUsize::new(0);
 
 pub fn setup() {
     // SAFETY: This function is idempontent and only writes to static memory and
     // CR3. Thus, it is safe to run multiple times or on multiple threads.
     let (l4, l3, l2s) = unsafe { (&mut L4_TABLE, &mut L3_TABLE, &mut L2_TABLES) };
-    log!("Setting up {} GiB identity mapping", ADDRESS_SPACE_GIB);
+    log!("Setting up {} GiB identity mapping", LOW_ADDRESS_SPACE_GIB);
     let pt_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
     // Setup Identity map using L2 huge pages
-    let mut next_addr = PhysAddr::new(0);
-    for l2 in l2s.iter_mut() {
-        for l2e in l2.iter_mut() {
-            l2e.set_addr(next_addr, pt_flags | PageTableFlags::HUGE_PAGE);
-            next_addr += Size2MiB::SIZE;
-        }
-    }
-
-    // Point L3 at L2s
-    for (i, l2) in l2s.iter().enumerate() {
-        l3[i].set_addr(phys_addr(l2), pt_flags);
-    }
+    map_small_pages(l3, &mut l2s[..LOW_ADDRESS_SPACE_GIB], 0);
 
     // Point L4 at L3
     l4[0].set_addr(phys_addr(l3), pt_flags);
@@ -46,10 +72,311 @@ pub fn setup() {
     if cr3_frame != l4_frame {
         unsafe { Cr3::write(l4_frame, cr3_flags) };
     }
+    MAPPED_SIZE.fetch_max(gib(LOW_ADDRESS_SPACE_GIB), Ordering::SeqCst);
     log!("Page tables setup");
 }
 
+// Extends the identity mapping from setup() to cover all of the RAM in the
+// memory map, so that memory above 4 GiB can be used.
+pub fn map_ram(info: &dyn Info) {
+    let ram_top = (0..info.num_entries())
+        .map(|i| info.entry(i))
+        .filter(|e| e.entry_type == E820Entry::RAM_TYPE)
+        .map(|e| e.addr + e.size)
+        .max()
+        .unwrap_or(0);
+    let ram_top_gib = ((ram_top + Size1GiB::SIZE - 1) / Size1GiB::SIZE) as usize;
+    if ram_top_gib <= LOW_ADDRESS_SPACE_GIB {
+        return;
+    }
+
+    let huge_pages = has_1gib_pages();
+    let mut top_gib = LOW_ADDRESS_SPACE_GIB;
+    while top_gib < ram_top_gib && map_gib(top_gib, huge_pages) {
+        top_gib += 1;
+    }
+    if top_gib < ram_top_gib {
+        log!("Only mapping the first {} GiB of RAM", top_gib);
+    }
+    MAPPED_SIZE.fetch_max(gib(top_gib), Ordering::SeqCst);
+    log!("Identity mapped {} GiB of address space", top_gib);
+}
+
+// Identity maps the GiBs covering a region, such as a BAR, returning false
+// if that wasn't possible
+pub fn map_region(address: u64, size: u64) -> bool {
+    if size == 0 {
+        return true;
+    }
+    let huge_pages = has_1gib_pages();
+    let first_gib = (address / Size1GiB::SIZE) as usize;
+    let last_gib = ((address + size - 1) / Size1GiB::SIZE) as usize;
+    (first_gib..=last_gib).all(|i| map_gib(i, huge_pages))
+}
+
+// Everything below this can be accessed once setup() has run, and any RAM
+// above 4 GiB once map_ram() has
+pub fn mapped_size() -> u64 {
+    MAPPED_SIZE.load(Ordering::SeqCst)
+}
+
+// Reads from the first address that is left unmapped before map_ram(), for
+// checking that the page fault is reported
+#[cfg(feature = "fault-test")]
+pub fn touch_unmapped() {
+    let address = mapped_size();
+    log!("Reading from unmapped address {:#x}", address);
+    unsafe { (address as *const u64).read_volatile() };
+}
+
+// Sets the access rights of the 4 KiB pages covering a region, splitting up
+// the 2 MiB pages it is mapped with. Pages are only made non-executable if
+// the CPU supports it, EFER.NXE being set for that. Returns false if the
+// region couldn't all be changed, which is the case with 1 GiB pages.
+#[cfg(feature = "section-protection")]
+pub fn protect(address: u64, size: u64, writable: bool, executable: bool) -> bool {
+    if size == 0 {
+        return true;
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    } else {
+        // Without CR0.WP the firmware could still write to read-only pages
+        let mut cr0 = Cr0::read();
+        cr0.insert(Cr0Flags::WRITE_PROTECT);
+        unsafe { Cr0::write(cr0) };
+    }
+    if !executable && enable_nx() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let protected = set_page_flags(address, size, flags);
+    tlb::flush_all();
+    protected
+}
+
+// Gives the pages of a freed region that protect() split off the access
+// rights the rest of memory has again, so whatever gets allocated there next
+// can be written and run.
+#[cfg(feature = "section-protection")]
+pub fn unprotect(address: u64, size: u64) {
+    if restore_page_flags(address, size) {
+        tlb::flush_all();
+    }
+}
+
+#[cfg(feature = "section-protection")]
+fn pages(address: u64, size: u64) -> impl Iterator<Item = u64> {
+    let first_page = address / Size4KiB::SIZE;
+    let last_page = (address + size - 1) / Size4KiB::SIZE;
+    (first_page..=last_page).map(|page| page * Size4KiB::SIZE)
+}
+
+#[cfg(feature = "section-protection")]
+fn set_page_flags(address: u64, size: u64, flags: PageTableFlags) -> bool {
+    pages(address, size).all(|page| match page_entry(page, true) {
+        Some(entry) => {
+            entry.set_flags(flags);
+            true
+        }
+        None => false,
+    })
+}
+
+// Returns whether any page had to be changed. 2 MiB pages are never split
+// for this, they have the default rights anyway.
+#[cfg(feature = "section-protection")]
+fn restore_page_flags(address: u64, size: u64) -> bool {
+    if size == 0 {
+        return false;
+    }
+    let mut changed = false;
+    for page in pages(address, size) {
+        if let Some(entry) = page_entry(page, false) {
+            let mut flags = entry.flags();
+            if !flags.contains(PageTableFlags::WRITABLE)
+                || flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                flags.insert(PageTableFlags::WRITABLE);
+                flags.remove(PageTableFlags::NO_EXECUTE);
+                entry.set_flags(flags);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// CPUID.80000001H:EDX.NX[bit 20], nothing before us sets EFER.NXE so this
+// does if the CPU has it
+#[cfg(feature = "section-protection")]
+fn enable_nx() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0001 || unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) == 0 {
+        return false;
+    }
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    true
+}
+
+// The 4 KiB page table entry mapping an address, if it is mapped with 4 KiB
+// or 2 MiB pages, the latter being split up if split is set
+#[cfg(feature = "section-protection")]
+fn page_entry(address: u64, split: bool) -> Option<&'static mut PageTableEntry> {
+    let index = |level: u64| ((address >> (12 + 9 * level)) & 0x1ff) as usize;
+    // SAFETY: The tables are all ones from this file, which only ever get
+    // more mappings, and are identity mapped
+    let l4 = unsafe { &mut L4_TABLE };
+    let l4e = &l4[index(3)];
+    if l4e.is_unused() {
+        return None;
+    }
+    let l3 = unsafe { &mut *(l4e.addr().as_u64() as *mut PageTable) };
+    let l3e = &l3[index(2)];
+    if l3e.is_unused() || l3e.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    let l2 = unsafe { &mut *(l3e.addr().as_u64() as *mut PageTable) };
+    let l2e = &mut l2[index(1)];
+    if l2e.is_unused() {
+        return None;
+    }
+    if l2e.flags().contains(PageTableFlags::HUGE_PAGE) {
+        if !split {
+            return None;
+        }
+        let l1 = next_l1_table()?;
+        let mut flags = l2e.flags();
+        flags.remove(PageTableFlags::HUGE_PAGE);
+        let mut next_addr = l2e.addr();
+        for l1e in l1.iter_mut() {
+            l1e.set_addr(next_addr, flags);
+            next_addr += Size4KiB::SIZE;
+        }
+        l2e.set_addr(
+            phys_addr(l1),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        );
+    }
+    let l1 = unsafe { &mut *(l2e.addr().as_u64() as *mut PageTable) };
+    Some(&mut l1[index(0)])
+}
+
+#[cfg(feature = "section-protection")]
+fn next_l1_table() -> Option<&'static mut PageTable> {
+    let index = L1_TABLES_USED.fetch_add(1, Ordering::SeqCst);
+    if index >= L1_TABLE_COUNT {
+        return None;
+    }
+    // SAFETY: Each table is only handed out once
+    Some(unsafe { &mut L1_TABLES[index] })
+}
+
+// Identity maps a single GiB unless it already is
+fn map_gib(index: usize, huge_pages: bool) -> bool {
+    // SAFETY: Only adds mappings for memory that isn't mapped yet, the
+    // existing ones don't change. Like setup(), it can be run multiple times.
+    let (l4, l3, high_l3s, l2s) = unsafe {
+        (
+            &mut L4_TABLE,
+            &mut L3_TABLE,
+            &mut HIGH_L3_TABLES,
+            &mut L2_TABLES,
+        )
+    };
+    let pt_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let table = index / L3_TABLE_GIB;
+    let l3 = match table {
+        0 => l3,
+        t if t <= HIGH_L3_TABLE_COUNT => {
+            let l3 = &mut high_l3s[t - 1];
+            if l4[t].is_unused() {
+                l4[t].set_addr(phys_addr(l3), pt_flags);
+            }
+            l3
+        }
+        _ => return false,
+    };
+    let l3e = &mut l3[index % L3_TABLE_GIB];
+    if !l3e.is_unused() {
+        return true;
+    }
+
+    if huge_pages {
+        l3e.set_addr(
+            PhysAddr::new(gib(index)),
+            pt_flags | PageTableFlags::HUGE_PAGE,
+        );
+        true
+    } else if index < SMALL_PAGE_ADDRESS_SPACE_GIB {
+        map_small_pages(l3, &mut l2s[index..index + 1], index);
+        true
+    } else {
+        false
+    }
+}
+
+// Identity maps a GiB with 2 MiB pages from each L2 table, starting at
+// first_gib, and points the L3 table at them
+fn map_small_pages(l3: &mut PageTable, l2s: &mut [PageTable], first_gib: usize) {
+    let pt_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut next_addr = PhysAddr::new(gib(first_gib));
+    for (i, l2) in l2s.iter_mut().enumerate() {
+        for l2e in l2.iter_mut() {
+            l2e.set_addr(next_addr, pt_flags | PageTableFlags::HUGE_PAGE);
+            next_addr += Size2MiB::SIZE;
+        }
+        l3[first_gib + i].set_addr(phys_addr(l2), pt_flags);
+    }
+}
+
+// CPUID.80000001H:EDX.Page1GB[bit 26]
+fn has_1gib_pages() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+fn gib(count: usize) -> u64 {
+    count as u64 * Size1GiB::SIZE
+}
+
 // Map a virtual address to a PhysAddr (assumes identity mapping)
 fn phys_addr<T>(virt_addr: *const T) -> PhysAddr {
     PhysAddr::new(virt_addr as u64)
 }
+
+#[cfg(all(test, feature = "section-protection"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unprotect() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { L4_TABLE[0].set_addr(phys_addr(&L3_TABLE), flags) };
+        assert!(map_gib(0, false));
+
+        let (address, size) = (0x20_1000, 0x2000);
+        assert!(set_page_flags(
+            address,
+            size,
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE
+        ));
+        assert!(!page_entry(address, false)
+            .unwrap()
+            .flags()
+            .contains(PageTableFlags::WRITABLE));
+        // The rest of the split 2 MiB page keeps its rights
+        assert_eq!(page_entry(0x20_0000, false).unwrap().flags(), flags);
+
+        assert!(restore_page_flags(address, size));
+        assert_eq!(page_entry(address, false).unwrap().flags(), flags);
+        assert_eq!(page_entry(address + 0x1000, false).unwrap().flags(), flags);
+        assert!(!restore_page_flags(address, size));
+
+        // Freeing memory doesn't split up pages
+        assert!(!restore_page_flags(0x40_0000, 0x1000));
+        assert!(page_entry(0x40_0000, false).is_none());
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// What a panic leaves behind: the message and where it was raised, then the
+// registers and the top of the stack, all logged without allocating. After
+// that the firmware halts, unless rhfw.panic=reset on the firmware's command
+// line or in the ESP's command line file has it reset, or rhfw.panic=exit has
+// it exit QEMU through an isa-debug-exit device (resetting if there is none),
+// so that a test run sees a failure rather than a hang.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(all(not(test), feature = "log-panic"))]
+use core::{panic::PanicInfo, sync::atomic::AtomicBool};
+
+use x86_64::instructions::hlt;
+
+use crate::reset;
+
+const OPTION: &[u8] = b"rhfw.panic=";
+
+// How much of the stack is dumped, in 64-bit words
+#[cfg(any(test, feature = "log-panic"))]
+const STACK_WORDS: u64 = 16;
+
+// isa-debug-exit has QEMU exit with (code << 1) | 1, so 3
+const EXIT_CODE: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Halt,
+    Reset,
+    Exit,
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(Action::Halt as u8);
+
+// Set by the first panic, so that one raised while handling it only halts
+#[cfg(all(not(test), feature = "log-panic"))]
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// The action in the last valid option
+fn parse_action(cmdline: &[u8]) -> Option<Action> {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .filter_map(|arg| arg.strip_prefix(OPTION))
+        .filter_map(|value| match value {
+            b"halt" => Some(Action::Halt),
+            b"reset" => Some(Action::Reset),
+            b"exit" => Some(Action::Exit),
+            _ => None,
+        })
+        .last()
+}
+
+// An option on this command line takes the place of any earlier one
+pub fn configure(cmdline: &[u8]) {
+    if let Some(action) = parse_action(cmdline) {
+        log!("On panic: {:?}", action);
+        ACTION.store(action as u8, Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(not(test), feature = "log-panic"))]
+struct Registers {
+    rip: u64,
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+// As they are in the panic handler rather than where the panic was raised,
+// which the PanicInfo gives the location of
+#[cfg(all(not(test), feature = "log-panic"))]
+#[inline(always)]
+fn registers() -> Registers {
+    let (rip, rsp, rbp, rflags): (u64, u64, u64, u64);
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    unsafe {
+        asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack));
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack));
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+        asm!("pushfq; pop {}", out(reg) rflags, options(nomem));
+        asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack));
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack));
+        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack));
+        asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack));
+    }
+    Registers {
+        rip,
+        rsp,
+        rbp,
+        rflags,
+        cr0,
+        cr2,
+        cr3,
+        cr4,
+    }
+}
+
+// The words to dump from rsp up, which go no further than the top of our
+// stack when rsp is in the firmware. On an EFI application's stack there is
+// no telling where the top is.
+#[cfg(any(test, feature = "log-panic"))]
+fn stack_words(rsp: u64, stack: (u64, u64)) -> u64 {
+    if rsp >= stack.0 && rsp < stack.1 {
+        core::cmp::min(STACK_WORDS, (stack.1 - rsp) / 8)
+    } else {
+        STACK_WORDS
+    }
+}
+
+#[cfg(all(not(test), feature = "log-panic"))]
+fn dump(r: &Registers) {
+    log_unlocked!("Registers in the panic handler:");
+    log_unlocked!(
+        "RIP: {:#018x} RSP: {:#018x} RBP: {:#018x} RFLAGS: {:#010x}",
+        r.rip,
+        r.rsp,
+        r.rbp,
+        r.rflags
+    );
+    log_unlocked!(
+        "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+        r.cr0,
+        r.cr2,
+        r.cr3,
+        r.cr4
+    );
+
+    let words = stack_words(r.rsp, crate::boot::firmware());
+    let stack = r.rsp as *const u64;
+    for row in (0..words).step_by(4) {
+        let mut line = [0u64; 4];
+        let count = core::cmp::min(4, words - row) as usize;
+        for (i, word) in line[..count].iter_mut().enumerate() {
+            *word = unsafe { stack.add(row as usize + i).read_volatile() };
+        }
+        match count {
+            1 => log_unlocked!("{:#018x}: {:016x}", r.rsp + row * 8, line[0]),
+            2 => log_unlocked!(
+                "{:#018x}: {:016x} {:016x}",
+                r.rsp + row * 8,
+                line[0],
+                line[1]
+            ),
+            3 => log_unlocked!(
+                "{:#018x}: {:016x} {:016x} {:016x}",
+                r.rsp + row * 8,
+                line[0],
+                line[1],
+                line[2]
+            ),
+            _ => log_unlocked!(
+                "{:#018x}: {:016x} {:016x} {:016x} {:016x}",
+                r.rsp + row * 8,
+                line[0],
+                line[1],
+                line[2],
+                line[3]
+            ),
+        }
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        hlt()
+    }
+}
+
+// Does what rhfw.panic asks for
+pub fn finish() -> ! {
+    match ACTION.load(Ordering::Relaxed) {
+        a if a == Action::Reset as u8 => reset::reset(false),
+        a if a == Action::Exit as u8 => {
+            reset::debug_exit(EXIT_CODE);
+            reset::reset(false)
+        }
+        _ => halt(),
+    }
+}
+
+#[cfg(all(not(test), feature = "log-panic"))]
+pub fn handle(info: &PanicInfo) -> ! {
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        halt()
+    }
+    let registers = registers();
+    // The panic could have been raised in the middle of a log!
+    log_unlocked!("PANIC: {}", info);
+    dump(&registers);
+    finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_action, stack_words, Action};
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(parse_action(b"rhfw.panic=reset"), Some(Action::Reset));
+        assert_eq!(
+            parse_action(b"rhfw.panic=exit quiet\trhfw.panic=halt rhfw.panic=x"),
+            Some(Action::Halt)
+        );
+        assert_eq!(parse_action(b"rhfw.panic="), None);
+        assert_eq!(parse_action(b"xrhfw.panic=exit"), None);
+    }
+
+    #[test]
+    fn test_stack_words() {
+        let stack = (0x1e_0000, 0x20_0000);
+        assert_eq!(stack_words(0x1f_0000, stack), 16);
+        assert_eq!(stack_words(0x1f_ffe8, stack), 3);
+        assert_eq!(stack_words(0x20_0000, stack), 16);
+        assert_eq!(stack_words(0x7000_0000, stack), 16);
+    }
+}
@@ -12,53 +12,204 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::block::SectorRead;
+use crate::{block::SectorRead, common::crc32};
 
 #[repr(packed)]
+#[derive(Clone, Copy)]
 /// GPT header
 struct Header {
     signature: u64,
     _revision: u32,
-    _header_size: u32,
-    _header_crc: u32,
+    header_size: u32,
+    header_crc: u32,
     _reserved: u32,
-    _current_lba: u64,
-    _backup_lba: u64,
+    current_lba: u64,
+    backup_lba: u64,
     first_usable_lba: u64,
     _last_usable_lba: u64,
     _disk_guid: [u8; 16],
     first_part_lba: u64,
     part_count: u32,
-    _part_entry_size: u32,
-    _part_crc: u32,
+    part_entry_size: u32,
+    part_crc: u32,
 }
 
+// "EFI PART"
+const GPT_SIGNATURE: u64 = 0x5452_4150_2049_4645;
+
+#[repr(packed)]
+#[derive(Clone, Copy)]
+/// Legacy MBR partition table entry
+struct MbrEntry {
+    _status: u8,
+    _first_chs: [u8; 3],
+    part_type: u8,
+    _last_chs: [u8; 3],
+    first_lba: u32,
+    sector_count: u32,
+}
+
+// The partition entries are read this many sectors at a time, which is all
+// of them for the usual 128
+const ENTRY_BATCH_SECTORS: usize = 32;
+
+const MBR_TYPE_FAT32_CHS: u8 = 0x0b;
+const MBR_TYPE_FAT32_LBA: u8 = 0x0c;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPE_EFI: u8 = 0xef;
+
+// GUID is C12A7328-F81F-11D2-BA4B-00A0C93EC93B in mixed-endian
+// 0-3, 4-5, 6-7 are LE, 8-19, and 10-15 are BE
+const EFI_PARTITION_GUID: [u8; 16] = [
+    0x28, 0x73, 0x2a, 0xc1, // LE C12A7328
+    0x1f, 0xf8, // LE F81F
+    0xd2, 0x11, // LE 11D2
+    0xba, 0x4b, // BE BA4B
+    0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b, // BE 00A0C93EC93B
+];
+
 #[repr(packed)]
 #[derive(Clone, Copy)]
 pub struct PartitionEntry {
     pub type_guid: [u8; 16],
     pub guid: [u8; 16],
+    // Once read from the disk these are in 512 byte sectors, whatever the
+    // size of the disk's blocks
     pub first_lba: u64,
     pub last_lba: u64,
     _flags: u64,
-    _partition_name: [u32; 18],
+    name: [u16; 36],
 }
 
 impl PartitionEntry {
     pub fn is_efi_partition(&self) -> bool {
-        // GUID is C12A7328-F81F-11D2-BA4B-00A0C93EC93B in mixed-endian
-        // 0-3, 4-5, 6-7 are LE, 8-19, and 10-15 are BE
-        self.type_guid
-            == [
-                0x28, 0x73, 0x2a, 0xc1, // LE C12A7328
-                0x1f, 0xf8, // LE F81F
-                0xd2, 0x11, // LE 11D2
-                0xba, 0x4b, // BE BA4B
-                0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b, // BE 00A0C93EC93B
-            ]
+        self.type_guid == EFI_PARTITION_GUID
+    }
+
+    /// The partition label, up to the first NUL
+    pub fn name(&self) -> PartitionName {
+        let name = self.name;
+        PartitionName::new(&name)
     }
 }
 
+/// What tells a partition apart in its EFI Hard Drive device path node
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionSignature {
+    // The disk's signature
+    Mbr(u32),
+    // The partition's own GUID
+    Gpt([u8; 16]),
+}
+
+/// A partition as its EFI Hard Drive device path node describes it: its
+/// number in the partition table, counting from 1, its sectors and its
+/// signature
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HardDrive {
+    pub number: u32,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub signature: PartitionSignature,
+}
+
+/// A partition label converted from UTF-16, with anything that can't be
+/// decoded replaced by U+FFFD
+#[derive(Clone, Copy)]
+pub struct PartitionName {
+    // Each UTF-16 code unit takes at most 3 bytes as UTF-8
+    data: [u8; 108],
+    len: usize,
+}
+
+impl PartitionName {
+    fn new(name: &[u16]) -> PartitionName {
+        let mut n = PartitionName {
+            data: [0; 108],
+            len: 0,
+        };
+        let name = name.iter().take_while(|c| **c != 0).copied();
+        for c in core::char::decode_utf16(name) {
+            let c = c.unwrap_or(core::char::REPLACEMENT_CHARACTER);
+            n.len += c.encode_utf8(&mut n.data[n.len..]).len();
+        }
+        n
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Safe as the data was built from whole chars
+        unsafe { core::str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+}
+
+impl PartialEq for PartitionName {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl core::fmt::Debug for PartitionName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// Chooses between EFI System partitions when a disk has more than one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionSelector {
+    /// The first EFI System partition on the disk
+    First,
+    /// The partition at this position in the list of used partitions
+    Index(usize),
+    /// The partition with this unique partition GUID
+    Guid([u8; 16]),
+}
+
+// Picks the EFI System partition to boot from, by its number or GUID
+const BOOT_PARTITION_OPTION: &[u8] = b"rhfw.boot_partition=";
+
+// A GUID written out like c12a7328-f81f-11d2-ba4b-00a0c93ec93b, in the mixed
+// endian byte order the GPT stores it in
+fn parse_guid(text: &str) -> Option<[u8; 16]> {
+    let mut groups = text.split('-');
+    let mut guid = [0; 16];
+    let mut offset = 0;
+    for &(len, little_endian) in &[(8, true), (4, true), (4, true), (4, false), (12, false)] {
+        let group = groups
+            .next()
+            .filter(|g| g.len() == len && g.bytes().all(|c| c.is_ascii_hexdigit()))?;
+        let bytes = &mut guid[offset..offset + len / 2];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&group[2 * i..2 * i + 2], 16).ok()?;
+        }
+        if little_endian {
+            bytes.reverse();
+        }
+        offset += len / 2;
+    }
+    match groups.next() {
+        Some(_) => None,
+        None => Some(guid),
+    }
+}
+
+/// The EFI System partition the last valid rhfw.boot_partition option picks,
+/// or the first one without the option
+pub fn boot_partition(cmdline: &[u8]) -> PartitionSelector {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .filter_map(|arg| arg.strip_prefix(BOOT_PARTITION_OPTION))
+        .filter_map(|value| {
+            let value = core::str::from_utf8(value).ok()?;
+            match value.parse() {
+                Ok(index) => Some(PartitionSelector::Index(index)),
+                Err(_) => parse_guid(value).map(PartitionSelector::Guid),
+            }
+        })
+        .last()
+        .unwrap_or(PartitionSelector::First)
+}
+
 #[derive(Debug)]
 pub enum Error {
     BlockError,
@@ -66,84 +217,508 @@ pub enum Error {
     ViolatesSpecification,
     ExceededPartitionCount,
     NoEFIPartition,
+    GptCrcMismatch,
+    // The disk ends before the partition table does
+    Truncated,
+    BadSignature,
+    // A partition that ends before it starts, or an LBA that can't be
+    // addressed
+    OutOfRange,
 }
 
-pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> Result<u32, Error> {
+// Reads the four primary partitions of a legacy MBR, if the disk has one that
+// isn't just protecting a GPT.
+fn get_mbr_entries(r: &dyn SectorRead) -> Result<([MbrEntry; 4], u32), Error> {
     let mut data: [u8; 512] = [0; 512];
-    match r.read(1, &mut data) {
+    match r.read(0, &mut data) {
         Ok(_) => {}
         Err(_) => return Err(Error::BlockError),
     };
 
+    if data[510..512] != [0x55, 0xaa] {
+        return Err(Error::BadSignature);
+    }
+
+    // Safe as the 4 entries at 0x1be end before the boot signature
+    let entries = unsafe { *(data[0x1be..].as_ptr() as *const [MbrEntry; 4]) };
+    if entries.iter().any(|e| e.part_type == MBR_TYPE_PROTECTIVE) {
+        return Err(Error::HeaderNotFound);
+    }
+
+    let mut signature = [0; 4];
+    signature.copy_from_slice(&data[0x1b8..0x1bc]);
+    Ok((entries, u32::from_le_bytes(signature)))
+}
+
+// As there is no per partition GUID on MBR disks one is made from the disk
+// signature and the index of the entry.
+fn mbr_guid(signature: u32, index: usize) -> [u8; 16] {
+    let mut guid = [0; 16];
+    guid[0..4].copy_from_slice(&signature.to_le_bytes());
+    guid[4] = index as u8 + 1;
+    guid
+}
+
+fn is_mbr_entry_valid(e: &MbrEntry) -> bool {
+    e.part_type != 0 && e.sector_count != 0
+}
+
+// Partition tables count in the disk's blocks, which can be several of the
+// 512 byte sectors it's read in
+fn sectors_per_block(r: &dyn SectorRead) -> u64 {
+    u64::from(r.block_size() / 512)
+}
+
+// The first and last sectors of the blocks from first to last
+fn block_range(r: &dyn SectorRead, first: u64, last: u64) -> (u64, u64) {
+    let scale = sectors_per_block(r);
+    (
+        first.saturating_mul(scale),
+        last.saturating_mul(scale).saturating_add(scale - 1),
+    )
+}
+
+fn mbr_range(r: &dyn SectorRead, e: &MbrEntry) -> (u64, u64) {
+    let first_lba = u64::from(e.first_lba);
+    block_range(r, first_lba, first_lba + u64::from(e.sector_count) - 1)
+}
+
+// Presents the MBR partitions in the same form as GPT ones, with the number
+// of each one's entry in numbers_out as far as it goes
+fn get_mbr_partitions(
+    r: &dyn SectorRead,
+    parts_out: &mut [PartitionEntry],
+    numbers_out: &mut [u32],
+) -> Result<u32, Error> {
+    let (entries, signature) = get_mbr_entries(r)?;
+
+    let mut current_part = 0u32;
+    for (i, e) in entries.iter().enumerate() {
+        if !is_mbr_entry_valid(e) {
+            continue;
+        }
+        if current_part as usize == parts_out.len() {
+            return Err(Error::ExceededPartitionCount);
+        }
+        let (first_lba, last_lba) = mbr_range(r, e);
+        parts_out[current_part as usize] = PartitionEntry {
+            type_guid: if e.part_type == MBR_TYPE_EFI {
+                EFI_PARTITION_GUID
+            } else {
+                [0; 16]
+            },
+            guid: mbr_guid(signature, i),
+            first_lba,
+            last_lba,
+            _flags: 0,
+            name: [0; 36],
+        };
+        if let Some(number) = numbers_out.get_mut(current_part as usize) {
+            *number = i as u32 + 1;
+        }
+        current_part += 1;
+    }
+
+    Ok(current_part)
+}
+
+// Finds an EFI System partition or failing that a FAT one on an MBR disk
+fn find_mbr_efi_partition(
+    r: &dyn SectorRead,
+    selector: PartitionSelector,
+) -> Result<(u64, u64, PartitionName), Error> {
+    let (entries, signature) = get_mbr_entries(r)?;
+
+    // MBR partitions have no label
+    let range = |e: &MbrEntry| {
+        let (first_lba, last_lba) = mbr_range(r, e);
+        (first_lba, last_lba, PartitionName::new(&[]))
+    };
+    let is_esp = |e: &MbrEntry| {
+        e.part_type == MBR_TYPE_EFI
+            || e.part_type == MBR_TYPE_FAT32_CHS
+            || e.part_type == MBR_TYPE_FAT32_LBA
+    };
+    let mut valid = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| is_mbr_entry_valid(e));
+
+    let found = match selector {
+        PartitionSelector::First => valid
+            .clone()
+            .find(|(_, e)| e.part_type == MBR_TYPE_EFI)
+            .or_else(|| valid.find(|(_, e)| is_esp(e))),
+        PartitionSelector::Index(index) => valid.nth(index),
+        PartitionSelector::Guid(guid) => valid.find(|(i, _)| mbr_guid(signature, *i) == guid),
+    };
+
+    match found {
+        Some((_, e)) if is_esp(e) => Ok(range(e)),
+        _ => Err(Error::NoEFIPartition),
+    }
+}
+
+#[cfg(test)]
+pub fn get_partitions(r: &dyn SectorRead, parts_out: &mut [PartitionEntry]) -> Result<u32, Error> {
+    match get_gpt_partitions(r, parts_out, &mut []) {
+        Err(Error::HeaderNotFound) => get_mbr_partitions(r, parts_out, &mut []),
+        result => result,
+    }
+}
+
+/// The partitions on the disk, as EFI Hard Drive device path nodes describe
+/// them
+pub fn get_hard_drives(r: &dyn SectorRead, drives_out: &mut [HardDrive]) -> Result<u32, Error> {
+    // Assume no more than 16 partitions on the disk
+    let mut parts: [PartitionEntry; 16] = unsafe { core::mem::zeroed() };
+    let mut numbers = [0; 16];
+    let (part_count, mbr) = match get_gpt_partitions(r, &mut parts, &mut numbers) {
+        Err(Error::HeaderNotFound) => (get_mbr_partitions(r, &mut parts, &mut numbers)?, true),
+        result => (result?, false),
+    };
+    if part_count as usize > drives_out.len() {
+        return Err(Error::ExceededPartitionCount);
+    }
+
+    for (i, p) in parts[..part_count as usize].iter().enumerate() {
+        drives_out[i] = HardDrive {
+            number: numbers[i],
+            first_lba: p.first_lba,
+            last_lba: p.last_lba,
+            // The GUID made for an MBR partition starts with the signature
+            signature: if mbr {
+                PartitionSignature::Mbr(u32::from_le_bytes([
+                    p.guid[0], p.guid[1], p.guid[2], p.guid[3],
+                ]))
+            } else {
+                PartitionSignature::Gpt(p.guid)
+            },
+        };
+    }
+    Ok(part_count)
+}
+
+// Reads the GPT header at lba, checking its CRC
+// Reads the header at the LBA, and with it as many of the sectors after it
+// as fit in data, which is where the entries usually are. Only the header is
+// read if the disk ends before them. Returns the header and how many sectors
+// were read.
+fn read_gpt_header(
+    r: &dyn SectorRead,
+    lba: u64,
+    data: &mut [u8],
+) -> Result<(Header, usize), Error> {
+    let sector = lba
+        .checked_mul(sectors_per_block(r))
+        .ok_or(Error::OutOfRange)?;
+    let mut count = data.len() / 512;
+    if r.read_multi(sector, &mut data[..count * 512]).is_err() {
+        count = 1;
+        match r.read(sector, &mut data[..512]) {
+            Ok(_) => {}
+            Err(_) => return Err(Error::BlockError),
+        };
+    }
+    let data = &mut data[..512];
+
     // Safe as sizeof header is less than 512 bytes (size of data)
-    let h = unsafe { &*(data.as_ptr() as *const Header) };
+    let h = unsafe { *(data.as_ptr() as *const Header) };
 
-    // GPT magic constant
-    if h.signature != 0x5452_4150_2049_4645u64 {
+    if h.signature != GPT_SIGNATURE {
         return Err(Error::HeaderNotFound);
     }
 
-    if h.first_usable_lba < 34 {
+    let header_size = h.header_size as usize;
+    if header_size < core::mem::size_of::<Header>() || header_size > data.len() {
         return Err(Error::ViolatesSpecification);
     }
 
-    let part_count = h.part_count;
-    let mut checked_part_count = 0;
+    // The CRC covers the header with the CRC field itself zeroed
+    data[16..20].copy_from_slice(&[0; 4]);
+    if crc32(0, &data[..header_size]) != h.header_crc {
+        return Err(Error::GptCrcMismatch);
+    }
+
+    // After the protective MBR, the header and at least 16KiB of entries
+    if h.current_lba != lba
+        || h.first_usable_lba < 2 + u64::from(16384 / r.block_size())
+        || h.part_entry_size as usize != core::mem::size_of::<PartitionEntry>()
+    {
+        return Err(Error::ViolatesSpecification);
+    }
 
-    let first_usable_lba = h.first_usable_lba;
-    let first_part_lba = h.first_part_lba;
+    Ok((h, count))
+}
 
+// Reads the partition entries described by the header, checking their CRC,
+// with the number of each one's entry in numbers_out as far as it goes.
+// Those in the sectors already read, from the first one given on, aren't
+// read again.
+fn read_gpt_entries(
+    r: &dyn SectorRead,
+    h: &Header,
+    read: (u64, &[u8]),
+    parts_out: &mut [PartitionEntry],
+    numbers_out: &mut [u32],
+) -> Result<u32, Error> {
+    let mut data = [0; ENTRY_BATCH_SECTORS * 512];
+    let entry_size = core::mem::size_of::<PartitionEntry>();
+    let entries_per_sector = 512 / entry_size;
+    let part_count = h.part_count as usize;
+    let sectors = (part_count + entries_per_sector - 1) / entries_per_sector;
+
+    let mut crc = 0;
     let mut current_part = 0u32;
+    let mut exceeded = false;
 
-    for lba in first_part_lba..first_usable_lba {
-        match r.read(lba, &mut data) {
-            Ok(_) => {}
-            Err(_) => return Err(Error::BlockError),
-        }
+    // The entries are read a sector at a time, whatever the block size
+    let first_sector = h
+        .first_part_lba
+        .checked_mul(sectors_per_block(r))
+        .ok_or(Error::OutOfRange)?;
+    for i in (0..sectors).step_by(ENTRY_BATCH_SECTORS) {
+        let sector = first_sector
+            .checked_add(i as u64)
+            .ok_or(Error::OutOfRange)?;
+        let batch = core::cmp::min(ENTRY_BATCH_SECTORS, sectors - i);
+        let (read_sector, read_data) = read;
+        let entries: &[u8] = match sector.checked_sub(read_sector) {
+            Some(offset) if (offset as usize + batch) * 512 <= read_data.len() => {
+                &read_data[offset as usize * 512..]
+            }
+            // The header was read so the entries it describes should be
+            // there too
+            _ => match r.read_multi(sector, &mut data[..batch * 512]) {
+                Ok(_) => &data,
+                Err(_) => return Err(Error::Truncated),
+            },
+        };
 
-        // Safe as size of partition struct * 4 is 512 bytes (size of data)
-        let parts =
-            unsafe { core::slice::from_raw_parts(data.as_ptr() as *const PartitionEntry, 4) };
+        let count = core::cmp::min(
+            batch * entries_per_sector,
+            part_count - i * entries_per_sector,
+        );
+        crc = crc32(crc, &entries[..count * entry_size]);
 
-        for p in parts {
+        // Safe as the entries read fit in entries
+        let parts = unsafe {
+            core::slice::from_raw_parts(entries.as_ptr() as *const PartitionEntry, count)
+        };
+
+        for (j, p) in parts.iter().enumerate() {
             if p.guid == [0; 16] {
                 continue;
             }
-            parts_out[current_part as usize] = *p;
+            if current_part as usize == parts_out.len() {
+                exceeded = true;
+                continue;
+            }
+            let mut p = *p;
+            let (first_lba, last_lba) = block_range(r, p.first_lba, p.last_lba);
+            p.first_lba = first_lba;
+            p.last_lba = last_lba;
+            parts_out[current_part as usize] = p;
+            if let Some(number) = numbers_out.get_mut(current_part as usize) {
+                *number = (i * entries_per_sector + j) as u32 + 1;
+            }
             current_part += 1;
         }
+    }
 
-        checked_part_count += 4;
-        if checked_part_count >= part_count {
-            break;
-        }
+    if crc != h.part_crc {
+        return Err(Error::GptCrcMismatch);
+    }
+    if exceeded {
+        return Err(Error::ExceededPartitionCount);
     }
 
     Ok(current_part)
 }
 
-/// Find EFI partition
-pub fn find_efi_partition(r: &dyn SectorRead) -> Result<(u64, u64), Error> {
+// The last LBA of the disk as covered by the protective MBR, where the backup
+// GPT header lives.
+fn protective_mbr_last_lba(r: &dyn SectorRead) -> Option<u64> {
+    let mut data: [u8; 512] = [0; 512];
+    r.read(0, &mut data).ok()?;
+
+    // Safe as the 4 entries at 0x1be end before the boot signature
+    let entries = unsafe { *(data[0x1be..].as_ptr() as *const [MbrEntry; 4]) };
+    entries
+        .iter()
+        .find(|e| {
+            e.part_type == MBR_TYPE_PROTECTIVE
+                && e.sector_count != 0
+                && e.sector_count != 0xffff_ffff
+        })
+        .map(|e| u64::from(e.first_lba) + u64::from(e.sector_count) - 1)
+}
+
+// Where the backup GPT header can be, in the order they are tried: where the
+// primary header says, if it can be trusted, the last LBA of the device, the
+// last LBA before the end of the device rounded down to 4KiB, where some tools
+// put it on 512e disks (512 byte blocks, 4KiB physical sectors), and the end
+// of the protective MBR. Those past the end of the device are left out.
+fn backup_gpt_lbas(r: &dyn SectorRead, primary: Option<u64>) -> [Option<u64>; 4] {
+    let sectors_per_block = sectors_per_block(r);
+    let last_block = |sectors: u64| (sectors / sectors_per_block).checked_sub(1);
+    let capacity = r.capacity();
+    let mut lbas = [
+        primary,
+        capacity.and_then(last_block),
+        capacity.and_then(|c| last_block(c / 8 * 8)),
+        protective_mbr_last_lba(r),
+    ];
+    for i in 0..lbas.len() {
+        let lba = lbas[i];
+        let past_end = match (lba, capacity.and_then(last_block)) {
+            (Some(lba), Some(last)) => lba > last,
+            _ => false,
+        };
+        if past_end || lbas[..i].contains(&lba) {
+            lbas[i] = None;
+        }
+    }
+    lbas
+}
+
+// Uses the primary GPT unless it fails its CRC checks, then the backup copy.
+fn get_gpt_partitions(
+    r: &dyn SectorRead,
+    parts_out: &mut [PartitionEntry],
+    numbers_out: &mut [u32],
+) -> Result<u32, Error> {
+    // The primary header and the entries after it are read at once, with
+    // 512 byte blocks
+    let mut data = [0; (1 + ENTRY_BATCH_SECTORS) * 512];
+    let sector = sectors_per_block(r);
+    let primary_backup_lba = match read_gpt_header(r, 1, &mut data) {
+        Ok((h, count)) => match read_gpt_entries(
+            r,
+            &h,
+            (sector, &data[..count * 512]),
+            parts_out,
+            numbers_out,
+        ) {
+            Err(Error::GptCrcMismatch) => Some(h.backup_lba),
+            result => {
+                log!("Using primary GPT");
+                return result;
+            }
+        },
+        // The header can't be trusted to say where the backup is
+        Err(Error::GptCrcMismatch) => None,
+        Err(e) => return Err(e),
+    };
+
+    // The first backup header found with its entries intact is used
+    let mut error = Error::GptCrcMismatch;
+    for lba in backup_gpt_lbas(r, primary_backup_lba).iter().flatten() {
+        log!("Primary GPT is corrupt, trying backup at LBA {}", lba);
+        let h = match read_gpt_header(r, *lba, &mut data[..512]) {
+            Ok((h, _)) => h,
+            Err(_) => continue,
+        };
+        match read_gpt_entries(r, &h, (0, &[]), parts_out, numbers_out) {
+            Ok(part_count) => {
+                log!("Using backup GPT");
+                return Ok(part_count);
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Find EFI partition, returning its LBA range and label. Booting goes
+/// through with_partitions instead, which reads the table only once.
+pub fn find_efi_partition(
+    r: &dyn SectorRead,
+    selector: PartitionSelector,
+) -> Result<(u64, u64, PartitionName), Error> {
     // Assume no more than 16 partitions on the disk
     let mut parts: [PartitionEntry; 16] = unsafe { core::mem::zeroed() };
 
-    let part_count = get_partitions(r, &mut parts)? as usize;
+    let part_count = match get_gpt_partitions(r, &mut parts, &mut []) {
+        Err(Error::HeaderNotFound) => return find_mbr_efi_partition(r, selector),
+        result => result? as usize,
+    };
+    select_gpt_partition(&parts[..part_count], selector)
+}
+
+// Picks the EFI partition from those read from a GPT
+fn select_gpt_partition(
+    parts: &[PartitionEntry],
+    selector: PartitionSelector,
+) -> Result<(u64, u64, PartitionName), Error> {
+    let mut parts = parts.iter();
+    let found = match selector {
+        PartitionSelector::First => parts.find(|p| p.is_efi_partition()),
+        PartitionSelector::Index(index) => parts.nth(index),
+        PartitionSelector::Guid(guid) => parts.find(|p| p.guid == guid),
+    };
 
-    for (checked_part_count, p) in (parts[0..part_count]).iter().enumerate() {
-        if p.is_efi_partition() {
-            return Ok((p.first_lba, p.last_lba));
+    match found {
+        Some(p) if p.is_efi_partition() && p.first_lba > p.last_lba => Err(Error::OutOfRange),
+        Some(p) if p.is_efi_partition() => Ok((p.first_lba, p.last_lba, p.name())),
+        _ => Err(Error::NoEFIPartition),
+    }
+}
+
+/// Calls per_partition with the LBA range and label of each partition until
+/// it returns true, starting with the EFI partition the selector picks and
+/// then trying the rest in the order they are on the disk
+pub fn with_partitions<F>(
+    r: &dyn SectorRead,
+    selector: PartitionSelector,
+    mut per_partition: F,
+) -> Result<bool, Error>
+where
+    F: FnMut(u64, u64, PartitionName) -> bool,
+{
+    // The partition table is only read once, as it takes more sectors than
+    // are cached
+    let mut parts: [PartitionEntry; 16] = unsafe { core::mem::zeroed() };
+    let (part_count, efi_partition) = match get_gpt_partitions(r, &mut parts, &mut []) {
+        Err(Error::HeaderNotFound) => (
+            get_mbr_partitions(r, &mut parts, &mut [])? as usize,
+            find_mbr_efi_partition(r, selector),
+        ),
+        result => {
+            let part_count = result? as usize;
+            (
+                part_count,
+                select_gpt_partition(&parts[..part_count], selector),
+            )
         }
-        if checked_part_count == part_count {
-            return Err(Error::ExceededPartitionCount);
+    };
+
+    let chosen = match efi_partition {
+        Ok((start, end, name)) => {
+            if per_partition(start, end, name) {
+                return Ok(true);
+            }
+            Some(start)
         }
-    }
+        Err(Error::NoEFIPartition) | Err(Error::OutOfRange) => None,
+        Err(e) => return Err(e),
+    };
+
+    Ok(parts[..part_count]
+        .iter()
+        .filter(|p| Some(p.first_lba) != chosen)
+        .any(|p| per_partition(p.first_lba, p.last_lba, p.name())))
+}
 
-    Err(Error::NoEFIPartition)
+/// Whether the disk has an EFI System partition, or with an MBR the FAT
+/// partition that stands in for one, as booting would pick first
+pub fn has_efi_partition(r: &dyn SectorRead) -> bool {
+    find_efi_partition(r, PartitionSelector::First).is_ok()
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::cell::RefCell;
+    use std::cell::{Cell, RefCell};
     use std::env;
     use std::fs;
     use std::fs::File;
@@ -153,7 +728,7 @@ pub mod tests {
     use std::io::SeekFrom;
 
     use crate::block;
-    use crate::block::SectorRead;
+    use crate::{block::SectorRead, common::crc32};
 
     pub struct FakeDisk {
         file: RefCell<File>,
@@ -188,13 +763,109 @@ pub mod tests {
             }
             Ok(())
         }
+
+        fn capacity(&self) -> Option<u64> {
+            Some(self.len() / 512)
+        }
+    }
+
+    /// A disk image held entirely in memory
+    pub struct MemDisk {
+        data: RefCell<Vec<u8>>,
+        // Sectors read from the disk, in order
+        pub reads: RefCell<Vec<u64>>,
+        pub requests: Cell<usize>,
+        pub flushes: Cell<usize>,
+        pub block_size: u32,
+    }
+
+    impl MemDisk {
+        pub fn new(data: Vec<u8>) -> MemDisk {
+            MemDisk {
+                data: RefCell::new(data),
+                reads: RefCell::new(Vec::new()),
+                requests: Cell::new(0),
+                flushes: Cell::new(0),
+                block_size: 512,
+            }
+        }
+
+        pub fn len(&self) -> u64 {
+            self.data.borrow().len() as u64 / 512
+        }
+    }
+
+    impl SectorRead for MemDisk {
+        fn read(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+            let start = sector as usize * 512;
+            let disk = self.data.borrow();
+            if start + data.len() > disk.len() {
+                return Err(block::Error::BlockIOError);
+            }
+            data.copy_from_slice(&disk[start..start + data.len()]);
+            self.reads
+                .borrow_mut()
+                .extend(sector..sector + data.len() as u64 / 512);
+            self.requests.set(self.requests.get() + 1);
+            Ok(())
+        }
+
+        fn read_multi(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+            self.read(sector, data)
+        }
+
+        fn block_size(&self) -> u32 {
+            self.block_size
+        }
+
+        fn capacity(&self) -> Option<u64> {
+            Some(self.len())
+        }
+    }
+
+    impl block::SectorWrite for MemDisk {
+        fn write(&self, sector: u64, data: &mut [u8]) -> Result<(), block::Error> {
+            let start = sector as usize * 512;
+            let mut disk = self.data.borrow_mut();
+            if start + data.len() > disk.len() {
+                return Err(block::Error::BlockIOError);
+            }
+            disk[start..start + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), block::Error> {
+            self.flushes.set(self.flushes.get() + 1);
+            Ok(())
+        }
+    }
+
+    impl block::BlockDevice for MemDisk {
+        fn get_capacity(&self) -> u64 {
+            self.len()
+        }
+
+        fn is_read_only(&self) -> bool {
+            false
+        }
+
+        fn request_count(&self) -> u64 {
+            self.requests.get() as u64
+        }
+    }
+
+    fn find_efi_range(
+        r: &dyn SectorRead,
+        selector: super::PartitionSelector,
+    ) -> Result<(u64, u64), super::Error> {
+        super::find_efi_partition(r, selector).map(|(start, end, _)| (start, end))
     }
 
     #[test]
     fn test_find_efi_partition() {
         let d = FakeDisk::new("clear-28660-kvm.img");
 
-        match super::find_efi_partition(&d) {
+        match find_efi_range(&d, super::PartitionSelector::First) {
             Ok((start, end)) => {
                 assert_eq!(start, 2048);
                 assert_eq!(end, 1_048_575);
@@ -202,4 +873,483 @@ pub mod tests {
             Err(e) => panic!("{:?}", e),
         }
     }
+
+    pub fn mbr_disk(entries: &[(u8, u32, u32)]) -> MemDisk {
+        let mut data = vec![0; 512 * 64];
+        data[0x1b8..0x1bc].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        for (i, (part_type, first_lba, sector_count)) in entries.iter().enumerate() {
+            let e = &mut data[0x1be + i * 16..0x1be + (i + 1) * 16];
+            e[4] = *part_type;
+            e[8..12].copy_from_slice(&first_lba.to_le_bytes());
+            e[12..16].copy_from_slice(&sector_count.to_le_bytes());
+        }
+        data[510] = 0x55;
+        data[511] = 0xaa;
+        MemDisk::new(data)
+    }
+
+    #[test]
+    fn test_find_mbr_efi_partition() {
+        let d = mbr_disk(&[(0x83, 2048, 100), (0xef, 4096, 2048), (0x0c, 8192, 8)]);
+        match find_efi_range(&d, super::PartitionSelector::First) {
+            Ok((start, end)) => {
+                assert_eq!(start, 4096);
+                assert_eq!(end, 6143);
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+
+        // No EFI System partition so use the FAT one
+        let d = mbr_disk(&[(0x83, 2048, 100), (0x0b, 8192, 8)]);
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (8192, 8199)
+        );
+
+        let d = mbr_disk(&[(0x83, 2048, 100)]);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::NoEFIPartition)
+        ));
+
+        // Protective MBR without a valid GPT header
+        let d = mbr_disk(&[(0xee, 1, 63)]);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::HeaderNotFound)
+        ));
+    }
+
+    const GPT_DISK_SECTORS: u64 = 256;
+
+    // A GPT disk with 128 partition entries and the given (type GUID,
+    // first LBA, label) partitions, each 8 sectors long. Those with a zero
+    // type GUID leave their entries unused.
+    pub fn gpt_disk(parts: &[([u8; 16], u64, &[u16])]) -> Vec<u8> {
+        let parts: Vec<([u8; 16], u64, u64, &[u16])> = parts
+            .iter()
+            .map(|(type_guid, first_lba, name)| (*type_guid, *first_lba, first_lba + 7, *name))
+            .collect();
+        sized_gpt_disk(GPT_DISK_SECTORS, &parts)
+    }
+
+    // A GPT disk of the given size with (type GUID, first LBA, last LBA,
+    // label) partitions
+    fn sized_gpt_disk(sectors: u64, parts: &[([u8; 16], u64, u64, &[u16])]) -> Vec<u8> {
+        block_gpt_disk(512, sectors, parts)
+    }
+
+    // As sized_gpt_disk, with the size and LBAs in blocks of block_size
+    fn block_gpt_disk(
+        block_size: usize,
+        blocks: u64,
+        parts: &[([u8; 16], u64, u64, &[u16])],
+    ) -> Vec<u8> {
+        let mut data = mbr_disk(&[(0xee, 1, blocks as u32 - 1)]).data.into_inner();
+        data.resize(blocks as usize * block_size, 0);
+
+        let mut entries = vec![0; 128 * 128];
+        for (i, (type_guid, first_lba, last_lba, name)) in parts.iter().enumerate() {
+            if *type_guid == [0; 16] {
+                continue;
+            }
+            let e = &mut entries[i * 128..(i + 1) * 128];
+            e[0..16].copy_from_slice(type_guid);
+            e[16] = i as u8 + 1;
+            e[32..40].copy_from_slice(&first_lba.to_le_bytes());
+            e[40..48].copy_from_slice(&last_lba.to_le_bytes());
+            for (j, c) in name.iter().enumerate() {
+                e[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let part_crc = crate::common::crc32(0, &entries);
+        let entry_blocks = (entries.len() / block_size) as u64;
+
+        let mut write_copy = |header_lba: u64, backup_lba: u64, first_part_lba: u64| {
+            let start = first_part_lba as usize * block_size;
+            data[start..start + entries.len()].copy_from_slice(&entries);
+
+            let mut h = [0; 92];
+            h[0..8].copy_from_slice(b"EFI PART");
+            h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            h[12..16].copy_from_slice(&92u32.to_le_bytes());
+            h[24..32].copy_from_slice(&header_lba.to_le_bytes());
+            h[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+            h[40..48].copy_from_slice(&(2 + entry_blocks).to_le_bytes());
+            h[48..56].copy_from_slice(&(blocks - 2 - entry_blocks).to_le_bytes());
+            h[72..80].copy_from_slice(&first_part_lba.to_le_bytes());
+            h[80..84].copy_from_slice(&128u32.to_le_bytes());
+            h[84..88].copy_from_slice(&128u32.to_le_bytes());
+            h[88..92].copy_from_slice(&part_crc.to_le_bytes());
+            let header_crc = crate::common::crc32(0, &h);
+            h[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+            let start = header_lba as usize * block_size;
+            data[start..start + h.len()].copy_from_slice(&h);
+        };
+        write_copy(1, blocks - 1, 2);
+        write_copy(blocks - 1, 1, blocks - 1 - entry_blocks);
+
+        data
+    }
+
+    #[test]
+    fn test_gpt_crc() {
+        let parts: [([u8; 16], u64, &[u16]); 2] =
+            [([0x11; 16], 64, &[]), (super::EFI_PARTITION_GUID, 128, &[])];
+
+        let d = MemDisk::new(gpt_disk(&parts));
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 135)
+        );
+
+        // Corrupt the primary header so that the backup at the end is used
+        let mut data = gpt_disk(&parts);
+        data[512 + 40] = 35;
+        let d = MemDisk::new(data);
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 135)
+        );
+        assert!(d.reads.borrow().contains(&(GPT_DISK_SECTORS - 1)));
+
+        // Corrupt the primary partition entries
+        let mut data = gpt_disk(&parts);
+        data[2 * 512 + 32] = 0x42;
+        let d = MemDisk::new(data);
+        let mut entries: [super::PartitionEntry; 16] = unsafe { std::mem::zeroed() };
+        assert_eq!(super::get_partitions(&d, &mut entries).unwrap(), 2);
+        assert_eq!({ entries[0].first_lba }, 64);
+
+        // Both copies corrupt
+        let mut data = gpt_disk(&parts);
+        data[512 + 40] = 35;
+        data[(GPT_DISK_SECTORS as usize - 33) * 512 + 32] = 0x42;
+        let d = MemDisk::new(data);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::GptCrcMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_partitions() {
+        let parts: [([u8; 16], u64, &[u16]); 1] = [(super::EFI_PARTITION_GUID, 64, &[])];
+
+        // Cut short after the primary header
+        let mut data = gpt_disk(&parts);
+        data.truncate(2 * 512);
+        let d = MemDisk::new(data);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::Truncated)
+        ));
+
+        // Nothing on the disk at all
+        let d = MemDisk::new(vec![0; 512 * 64]);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::BadSignature)
+        ));
+        let d = MemDisk::new(vec![]);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::BlockError)
+        ));
+
+        // A partition that ends before it starts
+        let mut data = gpt_disk(&parts);
+        let entries = 2 * 512;
+        data[entries + 40..entries + 48].copy_from_slice(&10u64.to_le_bytes());
+        let part_crc = crate::common::crc32(0, &data[entries..entries + 128 * 128]);
+        data[512 + 88..512 + 92].copy_from_slice(&part_crc.to_le_bytes());
+        data[512 + 16..512 + 20].copy_from_slice(&[0; 4]);
+        let header_crc = crate::common::crc32(0, &data[512..512 + 92]);
+        data[512 + 16..512 + 20].copy_from_slice(&header_crc.to_le_bytes());
+        let d = MemDisk::new(data);
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::OutOfRange)
+        ));
+
+        // More MBR partitions than there is room for
+        let d = mbr_disk(&[(0x83, 2048, 100), (0xef, 4096, 2048)]);
+        let mut parts: [super::PartitionEntry; 1] = unsafe { std::mem::zeroed() };
+        assert!(matches!(
+            super::get_partitions(&d, &mut parts),
+            Err(super::Error::ExceededPartitionCount)
+        ));
+    }
+
+    #[test]
+    fn test_select_efi_partition() {
+        use super::PartitionSelector;
+
+        let esp = super::EFI_PARTITION_GUID;
+        let d = MemDisk::new(gpt_disk(&[
+            ([0x11; 16], 64, &[]),
+            (esp, 128, &[]),
+            (esp, 192, &[]),
+        ]));
+        let find = |selector| find_efi_range(&d, selector);
+
+        assert_eq!(find(PartitionSelector::First).unwrap(), (128, 135));
+        assert_eq!(find(PartitionSelector::Index(1)).unwrap(), (128, 135));
+        assert_eq!(find(PartitionSelector::Index(2)).unwrap(), (192, 199));
+        let mut guid = [0; 16];
+        guid[0] = 3;
+        assert_eq!(find(PartitionSelector::Guid(guid)).unwrap(), (192, 199));
+
+        // Not an EFI System partition
+        assert!(matches!(
+            find(PartitionSelector::Index(0)),
+            Err(super::Error::NoEFIPartition)
+        ));
+        assert!(matches!(
+            find(PartitionSelector::Index(3)),
+            Err(super::Error::NoEFIPartition)
+        ));
+        assert!(matches!(
+            find(PartitionSelector::Guid([0x42; 16])),
+            Err(super::Error::NoEFIPartition)
+        ));
+
+        let d = mbr_disk(&[(0xef, 2048, 100), (0, 0, 0), (0xef, 4096, 2048)]);
+        let find = |selector| find_efi_range(&d, selector);
+        assert_eq!(find(PartitionSelector::First).unwrap(), (2048, 2147));
+        assert_eq!(find(PartitionSelector::Index(1)).unwrap(), (4096, 6143));
+        let mut guid = [0; 16];
+        guid[0..4].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        guid[4] = 3;
+        assert_eq!(find(PartitionSelector::Guid(guid)).unwrap(), (4096, 6143));
+    }
+
+    #[test]
+    fn test_with_partitions() {
+        use crate::fat::tests::{Dir, ImageBuilder};
+
+        // An empty FAT filesystem and then another one with a bootloader
+        let empty = ImageBuilder::new(crate::fat::FatType::FAT12).disk();
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT12);
+        let efi = builder.add_dir(Dir::Root, b"EFI        ");
+        let boot = builder.add_dir(efi, b"BOOT       ");
+        builder.add_file(boot, b"BOOTX64 EFI", b"MZ");
+        let bootable = builder.disk();
+
+        let size = empty.len();
+        let esp = super::EFI_PARTITION_GUID;
+        let mut data = sized_gpt_disk(
+            64 + 2 * size + 33,
+            &[
+                (esp, 64, 64 + size - 1, &[]),
+                (esp, 64 + size, 64 + 2 * size - 1, &[]),
+            ],
+        );
+        for (i, fs) in [empty, bootable].iter().enumerate() {
+            let start = (64 + i as u64 * size) as usize * 512;
+            let fs = fs.data.borrow();
+            data[start..start + fs.len()].copy_from_slice(&fs);
+        }
+        let d = MemDisk::new(data);
+
+        let mut tried = Vec::new();
+        let booted =
+            super::with_partitions(&d, super::PartitionSelector::First, |start, end, _| {
+                tried.push(start);
+                let mut fs = crate::fat::Filesystem::new(&d, start, end);
+                fs.init().is_ok() && fs.open("/EFI/BOOT/BOOTX64.EFI").is_ok()
+            });
+        assert!(booted.unwrap());
+        assert_eq!(tried, [64, 64 + size]);
+
+        // The chosen partition is tried first and only once
+        let requests = d.requests.get();
+        let mut tried = Vec::new();
+        let booted =
+            super::with_partitions(&d, super::PartitionSelector::Index(1), |start, _, _| {
+                tried.push(start);
+                false
+            });
+        assert!(!booted.unwrap());
+        assert_eq!(tried, [64 + size, 64]);
+        // The header and all the entries at once
+        assert_eq!(d.requests.get() - requests, 1);
+
+        // Other partitions are tried even without an EFI System partition
+        let d = mbr_disk(&[(0x83, 16, 8), (0x07, 32, 8)]);
+        let mut tried = Vec::new();
+        let booted = super::with_partitions(&d, super::PartitionSelector::First, |start, _, _| {
+            tried.push(start);
+            false
+        });
+        assert!(!booted.unwrap());
+        assert_eq!(tried, [16, 32]);
+
+        assert!(!super::has_efi_partition(&d));
+
+        let esp = super::EFI_PARTITION_GUID;
+        assert!(super::has_efi_partition(&MemDisk::new(gpt_disk(&[(
+            esp,
+            40,
+            &[]
+        )]))));
+        assert!(!super::has_efi_partition(&MemDisk::new(gpt_disk(&[(
+            [0x11; 16],
+            40,
+            &[]
+        )]))));
+        assert!(super::has_efi_partition(&mbr_disk(&[(0x0c, 8, 8)])));
+        // Such as a filesystem without a partition table
+        assert!(!super::has_efi_partition(&MemDisk::new(vec![0; 512 * 64])));
+    }
+
+    #[test]
+    fn test_block_size() {
+        let esp = super::EFI_PARTITION_GUID;
+        let parts: [([u8; 16], u64, u64, &[u16]); 2] =
+            [([0x11; 16], 8, 15, &[]), (esp, 16, 31, &[])];
+        let mut d = MemDisk::new(block_gpt_disk(4096, 64, &parts));
+        d.block_size = 4096;
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 255)
+        );
+        let mut entries: [super::PartitionEntry; 16] = unsafe { std::mem::zeroed() };
+        assert_eq!(super::get_partitions(&d, &mut entries).unwrap(), 2);
+        assert_eq!(
+            ({ entries[0].first_lba }, { entries[0].last_lba }),
+            (64, 127)
+        );
+
+        // The backup is found at the end of the disk in blocks
+        d.data.borrow_mut()[4096 + 40] = 7;
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 255)
+        );
+        assert!(d.reads.borrow().contains(&(63 * 8)));
+        assert!(!d.reads.borrow().iter().any(|s| *s >= 64 * 8));
+
+        // Read as if it had 512 byte blocks there's no GPT header at LBA 1
+        d.block_size = 512;
+        assert!(matches!(
+            find_efi_range(&d, super::PartitionSelector::First),
+            Err(super::Error::HeaderNotFound)
+        ));
+
+        let mut d = mbr_disk(&[(0xef, 16, 4)]);
+        d.block_size = 4096;
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 159)
+        );
+    }
+
+    // 512 byte blocks with 4KiB physical sectors, the disk having grown by a
+    // few sectors past where the backup was put: its last LBA before the end
+    // is rounded down to 4KiB, not at the end or the end of the protective
+    // MBR
+    #[test]
+    fn test_512e() {
+        let parts: [([u8; 16], u64, &[u16]); 2] =
+            [([0x11; 16], 64, &[]), (super::EFI_PARTITION_GUID, 128, &[])];
+        let mut data = gpt_disk(&parts);
+        data.resize((GPT_DISK_SECTORS as usize + 3) * 512, 0);
+        data[0x1be + 12..0x1be + 16].copy_from_slice(&(GPT_DISK_SECTORS as u32 + 2).to_le_bytes());
+        let d = MemDisk::new(data.clone());
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 135)
+        );
+
+        // Without the primary header only the device's size says where to
+        // look
+        data[512 + 40] = 35;
+        let d = MemDisk::new(data);
+        assert_eq!(
+            find_efi_range(&d, super::PartitionSelector::First).unwrap(),
+            (128, 135)
+        );
+        let reads = d.reads.borrow();
+        assert!(reads.contains(&(GPT_DISK_SECTORS + 2)));
+        assert!(reads.contains(&(GPT_DISK_SECTORS - 1)));
+        assert!(!reads.iter().any(|s| *s > GPT_DISK_SECTORS + 2));
+    }
+
+    #[test]
+    fn test_partition_name() {
+        let label: Vec<u16> = "EFI System".encode_utf16().collect();
+        let d = MemDisk::new(gpt_disk(&[(super::EFI_PARTITION_GUID, 64, &label)]));
+        let (_, _, name) = super::find_efi_partition(&d, super::PartitionSelector::First).unwrap();
+        assert_eq!(name.as_str(), "EFI System");
+
+        // Stops at the first NUL
+        let label: Vec<u16> = "Boot\0Junk".encode_utf16().collect();
+        assert_eq!(super::PartitionName::new(&label).as_str(), "Boot");
+
+        let label: Vec<u16> = "Système 💾".encode_utf16().collect();
+        assert_eq!(super::PartitionName::new(&label).as_str(), "Système 💾");
+
+        // Unpaired surrogates
+        assert_eq!(
+            super::PartitionName::new(&[0x41, 0xd800, 0x42, 0xdc00]).as_str(),
+            "A\u{fffd}B\u{fffd}"
+        );
+
+        // The longest label, all needing 3 bytes
+        let label = [0x20ac; 36];
+        assert_eq!(super::PartitionName::new(&label).as_str(), "€".repeat(36));
+
+        // MBR partitions have no label
+        let d = mbr_disk(&[(0xef, 4096, 2048)]);
+        let (_, _, name) = super::find_efi_partition(&d, super::PartitionSelector::First).unwrap();
+        assert_eq!(name.as_str(), "");
+    }
+
+    #[test]
+    fn test_boot_partition() {
+        use super::{boot_partition, PartitionSelector};
+
+        assert_eq!(boot_partition(b"quiet"), PartitionSelector::First);
+        assert_eq!(
+            boot_partition(b"rhfw.boot_partition=2 quiet"),
+            PartitionSelector::Index(2)
+        );
+        assert_eq!(
+            boot_partition(b"rhfw.boot_partition=c12a7328-f81f-11d2-ba4b-00a0c93ec93b"),
+            PartitionSelector::Guid(super::EFI_PARTITION_GUID)
+        );
+        assert_eq!(
+            boot_partition(b"rhfw.boot_partition=1 rhfw.boot_partition=x"),
+            PartitionSelector::Index(1)
+        );
+        for value in &[
+            "c12a7328-f81f-11d2-ba4b-00a0c93ec93",
+            "c12a7328-f81f-11d2-ba4b-00a0c93ec93b-00",
+            "c12a7328f81f-11d2-ba4b-00a0c93ec93b",
+            "+12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+            "-1",
+        ] {
+            let option = format!("rhfw.boot_partition={}", value);
+            assert_eq!(
+                boot_partition(option.as_bytes()),
+                PartitionSelector::First,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_mbr_partitions() {
+        let d = mbr_disk(&[(0x83, 2048, 100), (0, 0, 0), (0xef, 4096, 2048)]);
+        let mut parts: [super::PartitionEntry; 16] = unsafe { std::mem::zeroed() };
+        assert_eq!(super::get_partitions(&d, &mut parts).unwrap(), 2);
+        assert!(!parts[0].is_efi_partition());
+        assert!(parts[1].is_efi_partition());
+        assert_eq!({ parts[1].first_lba }, 4096);
+        assert_eq!({ parts[1].last_lba }, 6143);
+        assert_ne!(parts[0].guid, parts[1].guid);
+    }
 }
@@ -12,11 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::{cell::Cell, convert::TryFrom};
+
 use atomic_refcell::AtomicRefCell;
-use x86_64::instructions::port::{PortReadOnly, PortWriteOnly};
+use x86_64::instructions::port::{Port, PortWriteOnly};
 
 use crate::{
-    mem,
+    acpi,
+    boot::{self, E820Entry},
+    mem, paging,
     virtio::{Error as VirtioError, VirtioTransport},
 };
 
@@ -25,84 +29,443 @@ const MAX_FUNCTIONS: u8 = 8;
 
 const INVALID_VENDOR_ID: u16 = 0xffff;
 
+// Command register bits, the status register above it is write-one-to-clear
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+// Legacy configuration space, the rest is only reachable through ECAM
+const LEGACY_CONFIG_SIZE: u16 = 256;
+const CONFIG_SIZE: u16 = 4096;
+
 static PCI_CONFIG: AtomicRefCell<PciConfig> = AtomicRefCell::new(PciConfig::new());
 
+// The Enhanced Configuration Access Mechanism maps the configuration space of
+// every function on buses start_bus to end_bus into memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Ecam {
+    base: u64,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl Ecam {
+    // Where the configuration space of a function is, if it's on our buses
+    fn address(&self, bus: u8, device: u8, func: u8) -> Option<u64> {
+        if bus < self.start_bus || bus > self.end_bus {
+            return None;
+        }
+        let offset = u64::from(bus - self.start_bus) << 20 // bus bits 27-20
+            | u64::from(device) << 15 // slot/device bits 19-15
+            | u64::from(func) << 12; // function bits 14-12
+        Some(self.base + offset)
+    }
+
+    fn region(&self, bus: u8, device: u8, func: u8) -> Option<mem::MemoryRegion> {
+        self.address(bus, device, func)
+            .map(|address| mem::MemoryRegion::new(address, u64::from(CONFIG_SIZE)))
+    }
+}
+
 struct PciConfig {
     address_port: PortWriteOnly<u32>,
-    data_port: PortReadOnly<u32>,
+    data_port: Port<u32>,
+    ecam: Option<Ecam>,
 }
 
 impl PciConfig {
     const fn new() -> Self {
-        // We use the legacy, port-based Configuration Access Mechanism (CAM).
+        // Until init() finds ECAM, we use the legacy, port-based
+        // Configuration Access Mechanism (CAM).
         Self {
             address_port: PortWriteOnly::new(0xcf8),
-            data_port: PortReadOnly::new(0xcfc),
+            data_port: Port::new(0xcfc),
+            ecam: None,
         }
     }
 
-    fn read(&mut self, bus: u8, device: u8, func: u8, offset: u8) -> u32 {
-        assert_eq!(offset % 4, 0);
-        assert!(device < MAX_DEVICES);
-        assert!(func < MAX_FUNCTIONS);
-
+    fn select(&mut self, bus: u8, device: u8, func: u8, offset: u16) {
         let addr = u32::from(bus) << 16; // bus bits 23-16
         let addr = addr | u32::from(device) << 11; // slot/device bits 15-11
         let addr = addr | u32::from(func) << 8; // function bits 10-8
         let addr = addr | u32::from(offset & 0xfc); // register 7-0
         let addr = addr | 1u32 << 31; // enable bit 31
 
+        unsafe { self.address_port.write(addr) };
+    }
+
+    fn read(&mut self, bus: u8, device: u8, func: u8, offset: u16) -> u32 {
+        assert_eq!(offset % 4, 0);
+        assert!(device < MAX_DEVICES);
+        assert!(func < MAX_FUNCTIONS);
+        assert!(offset < CONFIG_SIZE);
+
+        if let Some(ecam) = self.ecam {
+            return match ecam.region(bus, device, func) {
+                Some(region) => region.read_at::<u32>(u64::from(offset)),
+                None => 0xffff_ffff,
+            };
+        }
+        // Extended configuration space reads as all ones without ECAM
+        if offset >= LEGACY_CONFIG_SIZE {
+            return 0xffff_ffff;
+        }
+
         // SAFETY: We have exclusive access to the ports, so the data read will
         // correspond to the address written.
-        unsafe {
-            self.address_port.write(addr);
-            self.data_port.read()
+        self.select(bus, device, func, offset);
+        unsafe { self.data_port.read() }
+    }
+
+    fn write(&mut self, bus: u8, device: u8, func: u8, offset: u16, value: u32) {
+        assert_eq!(offset % 4, 0);
+        assert!(device < MAX_DEVICES);
+        assert!(func < MAX_FUNCTIONS);
+        assert!(offset < CONFIG_SIZE);
+
+        if let Some(ecam) = self.ecam {
+            if let Some(region) = ecam.region(bus, device, func) {
+                region.write_at::<u32>(u64::from(offset), value);
+            }
+            return;
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return;
         }
+
+        // SAFETY: As for read(), the data goes to the address written.
+        self.select(bus, device, func, offset);
+        unsafe { self.data_port.write(value) }
     }
 }
 
-fn get_device_details(bus: u8, device: u8, func: u8) -> (u16, u16) {
+// The MCFG table lists an ECAM region for each PCI segment group, after a
+// reserved field following the header. Only segment 0 is used.
+fn parse_mcfg(mcfg: &[u8]) -> Option<Ecam> {
+    const ALLOCATIONS_OFFSET: usize = 44;
+    const ALLOCATION_SIZE: usize = 16;
+
+    mcfg.get(ALLOCATIONS_OFFSET..)?
+        .chunks_exact(ALLOCATION_SIZE)
+        .map(|entry| {
+            let mut base = [0; 8];
+            base.copy_from_slice(&entry[0..8]);
+            // base_address: 0, segment: 8, start_bus: 10, end_bus: 11
+            (
+                u16::from_le_bytes([entry[8], entry[9]]),
+                Ecam {
+                    base: u64::from_le_bytes(base),
+                    start_bus: entry[10],
+                    end_bus: entry[11],
+                },
+            )
+        })
+        .find(|(segment, ecam)| *segment == 0 && ecam.start_bus <= ecam.end_bus)
+        .map(|(_, ecam)| ecam)
+}
+
+// Switches to ECAM if the ACPI tables describe it, otherwise keeps using the
+// legacy I/O ports
+pub fn init(rsdp: u64) {
+    if rsdp == 0 {
+        return;
+    }
+    let mcfg = match acpi::find_table(rsdp, b"MCFG").and_then(acpi::table) {
+        Ok(mcfg) => mcfg,
+        Err(acpi::Error::NotFound) => return,
+        Err(e) => {
+            log!("Error reading MCFG table: {:?}", e);
+            return;
+        }
+    };
+    match parse_mcfg(mcfg) {
+        Some(ecam) => {
+            let size = (u64::from(ecam.end_bus - ecam.start_bus) + 1) << 20;
+            if !paging::map_region(ecam.base, size) {
+                log!("Unable to map ECAM at {:#x}", ecam.base);
+                return;
+            }
+            log!(
+                "Using ECAM at {:#x} for buses {}-{}",
+                ecam.base,
+                ecam.start_bus,
+                ecam.end_bus
+            );
+            PCI_CONFIG.borrow_mut().ecam = Some(ecam);
+        }
+        None => log!("No usable ECAM region in MCFG table"),
+    }
+}
+
+pub fn get_device_details(bus: u8, device: u8, func: u8) -> (u16, u16) {
     let data = PCI_CONFIG.borrow_mut().read(bus, device, func, 0);
     ((data & 0xffff) as u16, (data >> 16) as u16)
 }
 
-pub fn print_bus() {
+// The class and subclass codes, leaving out the programming interface
+pub fn get_class_details(bus: u8, device: u8, func: u8) -> (u8, u8) {
+    // revision: 0x08, prog if: 0x09, subclass: 0x0a, class: 0x0b
+    let data = PCI_CONFIG.borrow_mut().read(bus, device, func, 0x08);
+    ((data >> 24) as u8, (data >> 16) as u8)
+}
+
+// Header type register: bit 7 is set for multi-function devices and the
+// rest gives the layout, 1 being a PCI-to-PCI bridge
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+// Calls per_function for every function on the bus and on the buses behind
+// any bridges, until it returns true
+fn scan_bus<F>(bus: u8, per_function: &mut F) -> bool
+where
+    F: FnMut(u8, u8, u8) -> bool,
+{
     for device in 0..MAX_DEVICES {
-        let (vendor_id, device_id) = get_device_details(0, device, 0);
-        if vendor_id == INVALID_VENDOR_ID {
-            continue;
+        for func in 0..MAX_FUNCTIONS {
+            let (vendor_id, _) = get_device_details(bus, device, func);
+            if vendor_id == INVALID_VENDOR_ID {
+                // Function 0 has to be there for any of the others to be
+                if func == 0 {
+                    break;
+                }
+                continue;
+            }
+            if per_function(bus, device, func) {
+                return true;
+            }
+
+            let header = PciDevice::new(bus, device, func);
+            let header_type = header.read_u8(0x0e);
+            if header_type & !HEADER_TYPE_MULTI_FUNCTION == HEADER_TYPE_BRIDGE {
+                // primary bus: 0x18, secondary bus: 0x19, subordinate bus: 0x1a
+                let secondary_bus = header.read_u8(0x19);
+                if secondary_bus <= bus {
+                    log!(
+                        "Skipping unconfigured PCI bridge at {}:{}.{}",
+                        bus,
+                        device,
+                        func
+                    );
+                } else if scan_bus(secondary_bus, per_function) {
+                    return true;
+                }
+            }
+
+            if func == 0 && header_type & HEADER_TYPE_MULTI_FUNCTION == 0 {
+                break;
+            }
         }
+    }
+    false
+}
+
+/// Calls per_function with the bus, device and function numbers of every PCI
+/// function, including those behind bridges, until it returns true
+pub fn scan<F>(mut per_function: F)
+where
+    F: FnMut(u8, u8, u8) -> bool,
+{
+    scan_bus(0, &mut per_function);
+}
+
+pub fn print_bus() {
+    scan(|bus, device, func| {
+        let (vendor_id, device_id) = get_device_details(bus, device, func);
         log!(
-            "Found PCI device vendor={:x} device={:x} in slot={}",
+            "Found PCI device vendor={:x} device={:x} at {}:{}.{}",
             vendor_id,
             device_id,
-            device
+            bus,
+            device,
+            func
         );
-    }
+        false
+    });
 }
 
+#[cfg_attr(not(feature = "network"), allow(dead_code))]
 pub fn with_devices<F>(target_vendor_id: u16, target_device_id: u16, per_device: F)
 where
     F: Fn(PciDevice) -> bool,
 {
-    for device in 0..MAX_DEVICES {
-        let (vendor_id, device_id) = get_device_details(0, device, 0);
-        if vendor_id == target_vendor_id
+    scan(|bus, device, func| {
+        let (vendor_id, device_id) = get_device_details(bus, device, func);
+        vendor_id == target_vendor_id
             && device_id == target_device_id
-            && per_device(PciDevice::new(0, device, 0))
+            && per_device(PciDevice::new(bus, device, func))
+    });
+}
+
+// Where the MMIO window ends if the ECAM region isn't above its start: the
+// IOAPIC, HPET and local APIC pages
+const MMIO_WINDOW_LIMIT: u64 = 0xfec0_0000;
+const MAX_RESERVED_RANGES: usize = 64;
+
+// A memory BAR and the size of the range it decodes
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct MemoryBar {
+    index: u8,
+    is_64bit: bool,
+    address: u64,
+    size: u64,
+}
+
+// Hands out naturally aligned ranges of the MMIO window, avoiding the BARs
+// that are already assigned
+struct BarAllocator {
+    next: u64,
+    end: u64,
+    reserved: [(u64, u64); MAX_RESERVED_RANGES],
+    reserved_count: usize,
+}
+
+impl BarAllocator {
+    fn new(start: u64, end: u64) -> BarAllocator {
+        BarAllocator {
+            next: start,
+            end,
+            reserved: [(0, 0); MAX_RESERVED_RANGES],
+            reserved_count: 0,
+        }
+    }
+
+    fn reserve(&mut self, address: u64, size: u64) {
+        if self.reserved_count == MAX_RESERVED_RANGES {
+            log!("Too many BARs, {:#x} might get reused", address);
+            return;
+        }
+        self.reserved[self.reserved_count] = (address, address + size);
+        self.reserved_count += 1;
+    }
+
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        assert!(size.is_power_of_two());
+        let mut address = self.next.checked_add(size - 1)? & !(size - 1);
+        // Move past any reserved range the allocation would overlap, which
+        // can only happen a limited number of times
+        while let Some((_, end)) = self.reserved[..self.reserved_count]
+            .iter()
+            .find(|(start, end)| address < *end && *start < address + size)
         {
-            break;
+            address = end.checked_add(size - 1)? & !(size - 1);
         }
+        if address.checked_add(size)? > self.end {
+            return None;
+        }
+        self.next = address + size;
+        Some(address)
     }
 }
 
+// Where memory BARs the VMM left unassigned are put: the hole below 4GiB
+// from the end of the RAM and ACPI tables in the E820 map up to the ECAM
+// region, or up to the APIC pages if ECAM is below the hole or missing.
+// Reserved entries are left out, as VMMs list some of the APIC pages and
+// the like as reserved.
+fn mmio_window(entries: &[E820Entry], ecam_base: Option<u64>) -> (u64, u64) {
+    let start = entries
+        .iter()
+        .filter(|e| {
+            matches!(
+                e.entry_type,
+                E820Entry::RAM_TYPE | E820Entry::ACPI_TYPE | E820Entry::NVS_TYPE
+            ) && e.addr < 1 << 32
+        })
+        .map(|e| e.addr.saturating_add(e.size))
+        .max()
+        .unwrap_or(0);
+    let end = match ecam_base {
+        Some(base) if base > start => core::cmp::min(base, MMIO_WINDOW_LIMIT),
+        _ => MMIO_WINDOW_LIMIT,
+    };
+    (start, end)
+}
+
+impl BarAllocator {
+    fn reserve_assigned(&mut self, bars: &[Option<MemoryBar>]) {
+        for bar in bars.iter().flatten() {
+            if bar.address != 0 {
+                self.reserve(bar.address, bar.size);
+            }
+        }
+    }
+
+    // Addresses for the BARs left at 0, None for the others and those there
+    // is no room for
+    fn assign_zeroed(&mut self, bars: &[Option<MemoryBar>; 6]) -> [Option<u64>; 6] {
+        let mut addresses = [None; 6];
+        for (bar, address) in bars.iter().zip(addresses.iter_mut()) {
+            match bar {
+                Some(bar) if bar.address == 0 => *address = self.allocate(bar.size),
+                _ => {}
+            }
+        }
+        addresses
+    }
+}
+
+// Gives the memory BARs the VMM didn't assign an address in the MMIO window
+pub fn assign_bars(info: &dyn boot::Info) {
+    let ecam_base = PCI_CONFIG.borrow().ecam.map(|ecam| ecam.base);
+    let (start, end) = mmio_window(boot::MemoryMap::new(info).entries(), ecam_base);
+    let mut allocator = BarAllocator::new(start, end);
+    scan(|bus, device, func| {
+        allocator.reserve_assigned(&PciDevice::new(bus, device, func).memory_bars());
+        false
+    });
+
+    scan(|bus, device, func| {
+        let pci_device = PciDevice::new(bus, device, func);
+        let bars = pci_device.memory_bars();
+        let addresses = allocator.assign_zeroed(&bars);
+        for (bar, address) in bars.iter().zip(addresses.iter()) {
+            let bar = match bar {
+                Some(bar) if bar.address == 0 => bar,
+                _ => continue,
+            };
+            match *address {
+                Some(address) => {
+                    log!(
+                        "Assigning BAR {} of {}:{}.{} to {:#x} size {:#x}",
+                        bar.index,
+                        bus,
+                        device,
+                        func,
+                        address,
+                        bar.size
+                    );
+                    pci_device.set_bar_address(bar, address);
+                }
+                None => log!(
+                    "No room for BAR {} of {}:{}.{} size {:#x}",
+                    bar.index,
+                    bus,
+                    device,
+                    func,
+                    bar.size
+                ),
+            }
+        }
+        false
+    });
+}
+
+// The decoded size from the bits of a memory BAR that stay set when all ones
+// are written to it
+fn bar_size(mask: u64) -> u64 {
+    (!(mask & !0xf)).wrapping_add(1)
+}
+
 #[derive(Default)]
 pub struct PciDevice {
     bus: u8,
     device: u8,
     func: u8,
     bars: [PciBar; 6],
+    // Only logged
+    #[cfg_attr(not(feature = "log-serial"), allow(dead_code))]
     vendor_id: u16,
+    #[cfg_attr(not(feature = "log-serial"), allow(dead_code))]
     device_id: u16,
 }
 
@@ -127,7 +490,7 @@ struct PciBar {
 }
 
 impl PciDevice {
-    fn new(bus: u8, device: u8, func: u8) -> PciDevice {
+    pub fn new(bus: u8, device: u8, func: u8) -> PciDevice {
         PciDevice {
             bus,
             device,
@@ -156,10 +519,16 @@ impl PciDevice {
     fn read_u32(&self, offset: u8) -> u32 {
         PCI_CONFIG
             .borrow_mut()
-            .read(self.bus, self.device, self.func, offset)
+            .read(self.bus, self.device, self.func, u16::from(offset))
     }
 
-    fn init(&mut self) {
+    fn write_u32(&self, offset: u8, value: u32) {
+        PCI_CONFIG
+            .borrow_mut()
+            .write(self.bus, self.device, self.func, u16::from(offset), value)
+    }
+
+    pub fn init(&mut self) {
         let (vendor_id, device_id) = get_device_details(self.bus, self.device, self.func);
 
         self.vendor_id = vendor_id;
@@ -174,40 +543,45 @@ impl PciDevice {
             self.device_id
         );
 
-        let mut current_bar_offset = 0x10;
-        let mut current_bar = 0;
+        // Make sure the device responds to its memory BARs and can do DMA
+        let command = self.read_u16(0x04);
+        self.write_u32(
+            0x04,
+            u32::from(command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER),
+        );
 
-        //0x24 offset is last bar
-        while current_bar_offset < 0x24 {
+        // The six BARs are at 0x10 to 0x24
+        let mut current_bar = 0;
+        while current_bar < self.bars.len() {
+            let offset = 0x10 + 4 * current_bar as u8;
             #[allow(clippy::blacklisted_name)]
-            let bar = self.read_u32(current_bar_offset);
+            let bar = self.read_u32(offset);
 
             // lsb is 1 for I/O space bars
             if bar & 1 == 1 {
                 self.bars[current_bar].bar_type = PciBarType::IoSpace;
                 self.bars[current_bar].address = u64::from(bar & 0xffff_fffc);
             } else {
-                // bits 2-1 are the type 0 is 32-but, 2 is 64 bit
+                // bits 2-1 are the type: 0 is 32-bit (1 is the old below 1MiB
+                // type) and 2 is 64-bit, where the next BAR holds the upper
+                // half of the address and isn't a BAR of its own
                 match bar >> 1 & 3 {
-                    0 => {
+                    0 | 1 => {
                         self.bars[current_bar].bar_type = PciBarType::MemorySpace32;
                         self.bars[current_bar].address = u64::from(bar & 0xffff_fff0);
                     }
-                    2 => {
+                    2 if current_bar + 1 < self.bars.len() => {
+                        let high = self.read_u32(offset + 4);
                         self.bars[current_bar].bar_type = PciBarType::MemorySpace64;
-                        self.bars[current_bar].address = u64::from(bar & 0xffff_fff0);
-                        current_bar_offset += 4;
-
-                        #[allow(clippy::blacklisted_name)]
-                        let bar = self.read_u32(current_bar_offset);
-                        self.bars[current_bar].address += u64::from(bar) << 32;
+                        self.bars[current_bar].address =
+                            u64::from(bar & 0xffff_fff0) | u64::from(high) << 32;
+                        current_bar += 1;
                     }
-                    _ => panic!("Unsupported BAR type"),
+                    _ => log!("Unsupported BAR type: {:x}", bar),
                 }
             }
 
             current_bar += 1;
-            current_bar_offset += 4;
         }
 
         #[allow(clippy::blacklisted_name)]
@@ -215,8 +589,107 @@ impl PciDevice {
             log!("Bar: type={:?} address={:x}", bar.bar_type, bar.address);
         }
     }
+
+    // Sizes the memory BARs, with decoding switched off while their
+    // addresses are all ones
+    fn memory_bars(&self) -> [Option<MemoryBar>; 6] {
+        let mut bars = [None; 6];
+        let bar_count = match self.read_u8(0x0e) & !HEADER_TYPE_MULTI_FUNCTION {
+            0 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => return bars,
+        };
+
+        let command = self.read_u16(0x04);
+        self.write_u32(
+            0x04,
+            u32::from(command & !(COMMAND_MEMORY_SPACE | COMMAND_IO_SPACE)),
+        );
+
+        let mut index = 0;
+        while index < bar_count {
+            let offset = 0x10 + 4 * index as u8;
+            let low = self.read_u32(offset);
+            // Only memory BARs, with type 2 using the next BAR too
+            if low & 1 == 1 {
+                index += 1;
+                continue;
+            }
+            let is_64bit = low >> 1 & 3 == 2 && index + 1 < bar_count;
+
+            self.write_u32(offset, 0xffff_ffff);
+            let mut mask = 0xffff_ffff_0000_0000 | u64::from(self.read_u32(offset));
+            self.write_u32(offset, low);
+            let mut address = u64::from(low & 0xffff_fff0);
+            if is_64bit {
+                let high = self.read_u32(offset + 4);
+                self.write_u32(offset + 4, 0xffff_ffff);
+                mask = u64::from(self.read_u32(offset + 4)) << 32 | mask & 0xffff_ffff;
+                self.write_u32(offset + 4, high);
+                address |= u64::from(high) << 32;
+            }
+
+            // Unimplemented BARs have no writable address bits
+            let size = bar_size(mask);
+            if mask & !0xf != 0xffff_ffff_0000_0000 && size.is_power_of_two() {
+                bars[index] = Some(MemoryBar {
+                    index: index as u8,
+                    is_64bit,
+                    address,
+                    size,
+                });
+            }
+            index += if is_64bit { 2 } else { 1 };
+        }
+
+        self.write_u32(0x04, u32::from(command));
+        bars
+    }
+
+    fn set_bar_address(&self, bar: &MemoryBar, address: u64) {
+        let offset = 0x10 + 4 * bar.index;
+        let flags = self.read_u32(offset) & 0xf;
+        self.write_u32(offset, address as u32 & 0xffff_fff0 | flags);
+        if bar.is_64bit {
+            self.write_u32(offset + 4, (address >> 32) as u32);
+        }
+    }
+
+    #[cfg_attr(not(feature = "gop"), allow(dead_code))]
+    pub fn bar_address(&self, index: usize) -> u64 {
+        self.bars[index].address
+    }
+
+    // A region within a memory BAR, once it has been identity mapped
+    pub fn bar_region(&self, index: u8, offset: u32, length: u32) -> Option<mem::MemoryRegion> {
+        #[allow(clippy::blacklisted_name)]
+        let bar = self.bars.get(usize::from(index))?;
+        match bar.bar_type {
+            PciBarType::MemorySpace32 | PciBarType::MemorySpace64 => {}
+            _ => return None,
+        }
+        let address = bar.address + u64::from(offset);
+        if !paging::map_region(address, u64::from(length)) {
+            log!("Unable to map BAR {} at {:#x}", index, bar.address);
+            return None;
+        }
+        Some(mem::MemoryRegion::new(address, u64::from(length)))
+    }
 }
 
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_TABLE_ENTRY_SIZE: u32 = 16;
+// MSI-X messages go to the local APIC of the boot CPU, using interrupt
+// vectors from here up
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+const MSIX_FIRST_VECTOR: u32 = 0x40;
+// Table entries used for configuration changes and the queue, or the last
+// entry if there are fewer
+const CONFIG_MSIX_VECTOR: u16 = 0;
+const QUEUE_MSIX_VECTOR: u16 = 1;
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
 #[allow(clippy::enum_variant_names)]
 enum VirtioPciCapabilityType {
     CommonConfig = 1,
@@ -228,6 +701,28 @@ enum VirtioPciCapabilityType {
     PciConfig = 5,
 }
 
+// A legacy device's queue has the used ring on the next page after the
+// available ring, and is given as a page frame number
+const LEGACY_QUEUE_ALIGN: u64 = 4096;
+
+// The page frame number a legacy device is given for a queue of the size, if
+// the rings are where it will look for them: the available ring straight
+// after the descriptors and the used ring aligned after that. Devices
+// disagree on whether the available ring's used_event counts, so it has to
+// fit either way.
+fn legacy_queue_pfn(size: u16, descriptors: u64, avail: u64, used: u64) -> Option<u32> {
+    let size = u64::from(size);
+    let align_up = |addr: u64| (addr + LEGACY_QUEUE_ALIGN - 1) & !(LEGACY_QUEUE_ALIGN - 1);
+    if descriptors % LEGACY_QUEUE_ALIGN != 0
+        || avail != descriptors + 16 * size
+        || align_up(avail + 4 + 2 * size) != used
+        || align_up(avail + 6 + 2 * size) != used
+    {
+        return None;
+    }
+    u32::try_from(descriptors / LEGACY_QUEUE_ALIGN).ok()
+}
+
 #[derive(Default)]
 pub struct VirtioPciTransport {
     device: PciDevice,
@@ -235,6 +730,14 @@ pub struct VirtioPciTransport {
     notify_region: mem::MemoryRegion,        // notify region
     notify_off_multiplier: u32,              // from notify config cap
     device_config_region: mem::MemoryRegion, // device specific region
+    msix_vectors: u16,                       // MSI-X table size, if enabled
+    // The I/O BAR of a legacy device, 0 when the modern interface is used,
+    // and where its queue is until it is all known and can be given to it
+    legacy_port: u16,
+    queue_size: Cell<u16>,
+    descriptors: Cell<u64>,
+    avail: Cell<u64>,
+    used: Cell<u64>,
 }
 
 impl VirtioPciTransport {
@@ -244,6 +747,121 @@ impl VirtioPciTransport {
             ..Default::default()
         }
     }
+
+    // Points every MSI-X table entry at the boot CPU's local APIC but leaves
+    // them masked, as we poll the queues. The device can then be given
+    // vectors, which is what some expect when MSI-X is there.
+    fn init_msix(&mut self, cap: u8) {
+        // struct msix_cap {
+        //         u8 cap_vndr;
+        //         u8 cap_next;
+        //         le16 message_control;   /* Table size - 1 in bits 10-0 */
+        //         le32 table;             /* BAR in bits 2-0, offset in the rest */
+        //         le32 pba;
+        // };
+        let control = self.device.read_u16(cap + 2);
+        let table_size = (control & 0x7ff) + 1;
+        let table = self.device.read_u32(cap + 4);
+        log!("MSI-X table size {}", table_size);
+
+        let region = match self.device.bar_region(
+            (table & 0x7) as u8,
+            table & !0x7,
+            u32::from(table_size) * MSIX_TABLE_ENTRY_SIZE,
+        ) {
+            Some(region) => region,
+            None => {
+                log!("MSI-X table not usable");
+                return;
+            }
+        };
+        for entry in 0..table_size {
+            let offset = u64::from(entry) * u64::from(MSIX_TABLE_ENTRY_SIZE);
+            // message_address: 0x0, message_upper_address: 0x4
+            region.write_at::<u32>(offset, MSI_ADDRESS);
+            region.write_at::<u32>(offset + 0x4, 0);
+            // message_data: 0x8, vector_control: 0xc (bit 0 masks)
+            region.write_at::<u32>(offset + 0x8, MSIX_FIRST_VECTOR + u32::from(entry));
+            region.write_at::<u32>(offset + 0xc, 1);
+        }
+
+        let header = u32::from(self.device.read_u16(cap));
+        self.device
+            .write_u32(cap, u32::from(control | MSIX_ENABLE) << 16 | header);
+        self.msix_vectors = table_size;
+    }
+
+    // Gives an MSI-X vector to the configuration or a queue, the device
+    // reading back VIRTIO_MSI_NO_VECTOR if it couldn't use it
+    fn set_msix_vector(&self, offset: u64, vector: u16) {
+        if self.msix_vectors == 0 {
+            return;
+        }
+        let vector = core::cmp::min(vector, self.msix_vectors - 1);
+        self.region.write_at::<u16>(offset, vector);
+        if self.region.read_at::<u16>(offset) == VIRTIO_MSI_NO_VECTOR {
+            log!("Virtio device refused MSI-X vector {}", vector);
+        }
+    }
+
+    fn legacy_read_u8(&self, offset: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.legacy_port + offset).read() }
+    }
+
+    fn legacy_read_u16(&self, offset: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.legacy_port + offset).read() }
+    }
+
+    fn legacy_read_u32(&self, offset: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.legacy_port + offset).read() }
+    }
+
+    fn legacy_write_u8(&self, offset: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.legacy_port + offset).write(value) }
+    }
+
+    fn legacy_write_u16(&self, offset: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.legacy_port + offset).write(value) }
+    }
+
+    fn legacy_write_u32(&self, offset: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.legacy_port + offset).write(value) }
+    }
+
+    // Uses the legacy interface in the I/O BAR 0, once it decodes
+    fn init_legacy(&mut self) -> Result<(), VirtioError> {
+        #[allow(clippy::blacklisted_name)]
+        let bar = &self.device.bars[0];
+        match bar.bar_type {
+            PciBarType::IoSpace if bar.address != 0 && bar.address <= 0xffff => {}
+            _ => {
+                log!("No virtio common configuration capability or legacy I/O BAR found");
+                return Err(VirtioError::VirtioUnsupportedDevice);
+            }
+        }
+        self.legacy_port = bar.address as u16;
+        let command = self.device.read_u16(0x04);
+        self.device
+            .write_u32(0x04, u32::from(command | COMMAND_IO_SPACE));
+        log!(
+            "Using the legacy virtio interface at port {:#x}",
+            self.legacy_port
+        );
+        Ok(())
+    }
+
+    // Where a virtio structure is, from its capability
+    fn bar_region(
+        &self,
+        bar: u8,
+        offset: u32,
+        length: u32,
+    ) -> Result<mem::MemoryRegion, VirtioError> {
+        self.device.bar_region(bar, offset, length).ok_or_else(|| {
+            log!("Virtio structure in unusable BAR {}", bar);
+            VirtioError::VirtioUnsupportedDevice
+        })
+    }
 }
 // Common Configuration registers:
 /// le32 device_feature_select;     // 0x00 // read-write
@@ -263,6 +881,18 @@ impl VirtioPciTransport {
 /// le64 queue_desc;                // 0x20 // read-write
 /// le64 queue_avail;               // 0x28 // read-write
 /// le64 queue_used;                // 0x30 // read-write
+///
+/// The legacy interface has these registers in I/O BAR 0 instead, with
+/// only 32 bits of features and a queue size the driver can't change:
+/// le32 device_features;           // 0x00 // read-only for driver
+/// le32 driver_features;           // 0x04 // read-write
+/// le32 queue_pfn;                 // 0x08 // read-write
+/// le16 queue_size;                // 0x0C // read-only for driver
+/// le16 queue_select;              // 0x0E // read-write
+/// le16 queue_notify;              // 0x10 // read-write
+/// u8 device_status;               // 0x12 // read-write
+/// u8 isr_status;                  // 0x13 // read-only for driver
+/// device specific configuration   // 0x14 // without MSI-X enabled
 
 impl VirtioTransport for VirtioPciTransport {
     fn init(&mut self, _device_type: u32) -> Result<(), VirtioError> {
@@ -271,14 +901,17 @@ impl VirtioTransport for VirtioPciTransport {
         // Read status register
         let status = self.device.read_u16(0x06);
 
-        // bit 4 of status is capability bit
+        // bit 4 of status is capability bit, without which there are none of
+        // the virtio 1.0 ones
         if status & 1 << 4 == 0 {
             log!("No capabilities detected");
-            return Err(VirtioError::VirtioUnsupportedDevice);
+            return self.init_legacy();
         }
 
         // capabilities list offset is at 0x34
         let mut cap_next = self.device.read_u8(0x34);
+        let mut common_config_found = false;
+        let mut msix_cap = None;
 
         while cap_next < 0xff && cap_next > 0 {
             // vendor specific capability
@@ -301,17 +934,12 @@ impl VirtioTransport for VirtioPciTransport {
                 let length = self.device.read_u32(cap_next + 12);
 
                 if cfg_type == VirtioPciCapabilityType::CommonConfig as u8 {
-                    self.region = mem::MemoryRegion::new(
-                        self.device.bars[usize::from(bar)].address + u64::from(offset),
-                        u64::from(length),
-                    );
+                    self.region = self.bar_region(bar, offset, length)?;
+                    common_config_found = true;
                 }
 
                 if cfg_type == VirtioPciCapabilityType::NotifyConfig as u8 {
-                    self.notify_region = mem::MemoryRegion::new(
-                        self.device.bars[usize::from(bar)].address + u64::from(offset),
-                        u64::from(length),
-                    );
+                    self.notify_region = self.bar_region(bar, offset, length)?;
 
                     // struct virtio_pci_notify_cap {
                     //         struct virtio_pci_cap cap;
@@ -321,26 +949,50 @@ impl VirtioTransport for VirtioPciTransport {
                 }
 
                 if cfg_type == VirtioPciCapabilityType::DeviceConfig as u8 {
-                    self.device_config_region = mem::MemoryRegion::new(
-                        self.device.bars[usize::from(bar)].address + u64::from(offset),
-                        u64::from(length),
-                    );
+                    self.device_config_region = self.bar_region(bar, offset, length)?;
                 }
+            } else if self.device.read_u8(cap_next) == PCI_CAP_ID_MSIX {
+                msix_cap = Some(cap_next);
             }
             cap_next = self.device.read_u8(cap_next + 1)
         }
 
+        // Only legacy devices lack the virtio 1.0 capabilities. Transitional
+        // devices offer the modern interface alongside the legacy one, and
+        // that is used when it's there. MSI-X is left off for legacy devices,
+        // as enabling it moves their device specific configuration.
+        if !common_config_found {
+            return self.init_legacy();
+        }
+
+        if let Some(cap) = msix_cap {
+            self.init_msix(cap);
+        }
+
         Ok(())
     }
 
+    fn legacy(&self) -> bool {
+        self.legacy_port != 0
+    }
+
     fn get_status(&self) -> u32 {
+        if self.legacy() {
+            // device_status: 0x12
+            return u32::from(self.legacy_read_u8(0x12));
+        }
         // device_status: 0x14
-        u32::from(self.region.io_read_u8(0x14))
+        u32::from(self.region.read_at::<u8>(0x14))
     }
 
     fn set_status(&self, value: u32) {
+        if self.legacy() {
+            // device_status: 0x12
+            self.legacy_write_u8(0x12, value as u8);
+            return;
+        }
         // device_status: 0x14
-        self.region.io_write_u8(0x14, value as u8);
+        self.region.write_at::<u8>(0x14, value as u8);
     }
 
     fn add_status(&self, value: u32) {
@@ -352,75 +1004,338 @@ impl VirtioTransport for VirtioPciTransport {
     }
 
     fn get_features(&self) -> u64 {
+        if self.legacy() {
+            // device_features: 0x00
+            return u64::from(self.legacy_read_u32(0x00));
+        }
         // device_feature_select: 0x00
-        self.region.io_write_u32(0x00, 0);
+        self.region.write_at::<u32>(0x00, 0);
         // device_feature: 0x04
-        let mut device_features: u64 = u64::from(self.region.io_read_u32(0x04));
+        let mut device_features: u64 = u64::from(self.region.read_at::<u32>(0x04));
         // device_feature_select: 0x00
-        self.region.io_write_u32(0x00, 1);
+        self.region.write_at::<u32>(0x00, 1);
         // device_feature: 0x04
-        device_features |= u64::from(self.region.io_read_u32(0x04)) << 32;
+        device_features |= u64::from(self.region.read_at::<u32>(0x04)) << 32;
 
         device_features
     }
 
     fn set_features(&self, features: u64) {
+        if self.legacy() {
+            // driver_features: 0x04
+            self.legacy_write_u32(0x04, features as u32);
+            return;
+        }
         // driver_feature_select: 0x08
-        self.region.io_write_u32(0x08, 0);
+        self.region.write_at::<u32>(0x08, 0);
         // driver_feature: 0x0c
-        self.region.io_write_u32(0x0c, features as u32);
+        self.region.write_at::<u32>(0x0c, features as u32);
         // driver_feature_select: 0x08
-        self.region.io_write_u32(0x08, 1);
+        self.region.write_at::<u32>(0x08, 1);
         // driver_feature: 0x0c
-        self.region.io_write_u32(0x0c, (features >> 32) as u32);
+        self.region.write_at::<u32>(0x0c, (features >> 32) as u32);
+        // msix_config: 0x10, which the reset before negotiation clears
+        self.set_msix_vector(0x10, CONFIG_MSIX_VECTOR);
     }
 
     fn set_queue(&self, queue: u16) {
+        if self.legacy() {
+            // queue_select: 0x0e
+            self.legacy_write_u16(0x0e, queue);
+            return;
+        }
         // queue_select: 0x16
-        self.region.io_write_u16(0x16, queue);
+        self.region.write_at::<u16>(0x16, queue);
     }
 
     fn get_queue_max_size(&self) -> u16 {
+        if self.legacy() {
+            // queue_size: 0x0c
+            return self.legacy_read_u16(0x0c);
+        }
         // queue_size: 0x18
-        self.region.io_read_u16(0x18)
+        self.region.read_at::<u16>(0x18)
     }
 
     fn set_queue_size(&self, queue_size: u16) {
-        // queue_size: 0x18
-        self.region.io_write_u16(0x18, queue_size);
+        self.queue_size.set(queue_size);
+        if !self.legacy() {
+            // queue_size: 0x18
+            self.region.write_at::<u16>(0x18, queue_size);
+        }
     }
 
     fn set_descriptors_address(&self, addr: u64) {
-        // queue_desc: 0x20
-        self.region.io_write_u64(0x20, addr);
+        self.descriptors.set(addr);
+        if !self.legacy() {
+            // queue_desc: 0x20
+            self.region.write_at::<u64>(0x20, addr);
+        }
     }
 
     fn set_avail_ring(&self, addr: u64) {
-        // queue_avail: 0x28
-        self.region.io_write_u64(0x28, addr);
+        self.avail.set(addr);
+        if !self.legacy() {
+            // queue_avail: 0x28
+            self.region.write_at::<u64>(0x28, addr);
+        }
     }
 
     fn set_used_ring(&self, addr: u64) {
-        // queue_used: 0x28
-        self.region.io_write_u64(0x30, addr);
+        self.used.set(addr);
+        if !self.legacy() {
+            // queue_used: 0x28
+            self.region.write_at::<u64>(0x30, addr);
+        }
     }
 
-    fn set_queue_enable(&self) {
+    fn set_queue_enable(&self) -> Result<(), VirtioError> {
+        if self.legacy() {
+            let size = self.get_queue_max_size();
+            if self.queue_size.get() != size {
+                log!(
+                    "Legacy virtio device has a queue of {}, not {}",
+                    size,
+                    self.queue_size.get()
+                );
+                return Err(VirtioError::VirtioUnsupportedQueueLayout);
+            }
+            let pfn = legacy_queue_pfn(
+                size,
+                self.descriptors.get(),
+                self.avail.get(),
+                self.used.get(),
+            )
+            .ok_or_else(|| {
+                log!("Queue can't be laid out for a legacy virtio PCI device");
+                VirtioError::VirtioUnsupportedQueueLayout
+            })?;
+            // queue_pfn: 0x08
+            self.legacy_write_u32(0x08, pfn);
+            return Ok(());
+        }
+        // queue_msix_vector: 0x1a
+        self.set_msix_vector(0x1a, QUEUE_MSIX_VECTOR);
         // queue_enable: 0x1c
-        self.region.io_write_u16(0x1c, 0x1);
+        self.region.write_at::<u16>(0x1c, 0x1);
+        Ok(())
     }
 
     fn notify_queue(&self, queue: u16) {
+        if self.legacy() {
+            // queue_notify: 0x10
+            self.legacy_write_u16(0x10, queue);
+            return;
+        }
         // queue_notify_off: 0x1e
-        let queue_notify_off = self.region.io_read_u16(0x1e);
+        let queue_notify_off = self.region.read_at::<u16>(0x1e);
 
-        self.notify_region.io_write_u32(
+        self.notify_region.write_at::<u32>(
             u64::from(queue_notify_off) * u64::from(self.notify_off_multiplier),
             u32::from(queue),
         );
     }
 
     fn read_device_config(&self, offset: u64) -> u32 {
-        self.device_config_region.io_read_u32(offset)
+        if self.legacy() {
+            // device specific configuration: 0x14
+            return self.legacy_read_u32(0x14 + offset as u16);
+        }
+        self.device_config_region.read_at::<u32>(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bar_size, legacy_queue_pfn, mmio_window, parse_mcfg, BarAllocator, Ecam, MemoryBar,
+    };
+    use crate::{
+        boot::E820Entry,
+        virtio::{AvailRing, Desc, UsedRing, QUEUE_SIZE},
+    };
+
+    fn make_mcfg(allocations: &[(u64, u16, u8, u8)]) -> Vec<u8> {
+        let mut data = vec![0u8; 44];
+        data[0..4].copy_from_slice(b"MCFG");
+        for (base, segment, start_bus, end_bus) in allocations {
+            data.extend_from_slice(&base.to_le_bytes());
+            data.extend_from_slice(&segment.to_le_bytes());
+            data.extend_from_slice(&[*start_bus, *end_bus, 0, 0, 0, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_mcfg() {
+        assert_eq!(parse_mcfg(&make_mcfg(&[])), None);
+        assert_eq!(parse_mcfg(&make_mcfg(&[(0xe800_0000, 1, 0, 0)])), None);
+        assert_eq!(
+            parse_mcfg(&make_mcfg(&[
+                (0xe000_0000, 1, 0, 255),
+                (0xb000_0000, 0, 0, 255)
+            ])),
+            Some(Ecam {
+                base: 0xb000_0000,
+                start_bus: 0,
+                end_bus: 255
+            })
+        );
+        assert_eq!(parse_mcfg(&[0; 20]), None);
+    }
+
+    #[test]
+    fn test_ecam_address() {
+        let ecam = Ecam {
+            base: 0xe800_0000,
+            start_bus: 1,
+            end_bus: 3,
+        };
+        assert_eq!(ecam.address(0, 0, 0), None);
+        assert_eq!(ecam.address(4, 0, 0), None);
+        assert_eq!(
+            ecam.address(2, 3, 1),
+            Some(0xe800_0000 + (1 << 20) + (3 << 15) + (1 << 12))
+        );
+    }
+
+    #[test]
+    fn test_bar_size() {
+        // A 16KiB 32-bit BAR, with the upper half as all ones
+        assert_eq!(bar_size(0xffff_ffff_ffff_c000), 0x4000);
+        // A prefetchable 64-bit 8GiB BAR
+        assert_eq!(bar_size(0xffff_fffe_0000_000c), 0x2_0000_0000);
+    }
+
+    #[test]
+    fn test_bar_allocator() {
+        let mut allocator = BarAllocator::new(0xc000_0000, 0xc100_0000);
+        allocator.reserve(0xc000_1000, 0x1000);
+        allocator.reserve(0xc010_0000, 0x10_0000);
+
+        assert_eq!(allocator.allocate(0x1000), Some(0xc000_0000));
+        // Skips the reserved page
+        assert_eq!(allocator.allocate(0x1000), Some(0xc000_2000));
+        // Naturally aligned, and past the reserved MiB
+        assert_eq!(allocator.allocate(0x10_0000), Some(0xc020_0000));
+        assert_eq!(allocator.allocate(0x4000), Some(0xc030_0000));
+        assert_eq!(allocator.allocate(0x100_0000), None);
+        assert_eq!(allocator.allocate(0x80_0000), Some(0xc080_0000));
+        assert_eq!(allocator.allocate(0x1000), None);
+    }
+
+    fn e820(addr: u64, size: u64, entry_type: u32) -> E820Entry {
+        E820Entry {
+            addr,
+            size,
+            entry_type,
+        }
+    }
+
+    #[test]
+    fn test_mmio_window() {
+        // QEMU's q35 with 1GiB, ECAM above the RAM and a reserved page at
+        // the top of the hole
+        let q35 = [
+            e820(0, 0x9_fc00, E820Entry::RAM_TYPE),
+            e820(0x10_0000, 0x3ff0_0000, E820Entry::RAM_TYPE),
+            e820(0xfeff_c000, 0x4000, 2),
+            e820(0x1_0000_0000, 0x4000_0000, E820Entry::RAM_TYPE),
+        ];
+        assert_eq!(
+            mmio_window(&q35, Some(0xb000_0000)),
+            (0x4000_0000, 0xb000_0000)
+        );
+        // ECAM below the end of the RAM, or none at all
+        assert_eq!(
+            mmio_window(&q35, Some(0x3000_0000)),
+            (0x4000_0000, 0xfec0_0000)
+        );
+        assert_eq!(mmio_window(&q35, None), (0x4000_0000, 0xfec0_0000));
+
+        // Cloud Hypervisor with its ACPI tables above the RAM
+        let ch = [
+            e820(0, 0x2000_0000, E820Entry::RAM_TYPE),
+            e820(0x2000_0000, 0x1_0000, E820Entry::ACPI_TYPE),
+        ];
+        assert_eq!(
+            mmio_window(&ch, Some(0xe800_0000)),
+            (0x2001_0000, 0xe800_0000)
+        );
+    }
+
+    fn memory_bar(index: u8, address: u64, size: u64) -> Option<MemoryBar> {
+        Some(MemoryBar {
+            index,
+            is_64bit: false,
+            address,
+            size,
+        })
+    }
+
+    #[test]
+    fn test_assign_zeroed_bars() {
+        let mut allocator = BarAllocator::new(0x4000_0000, 0x4010_0000);
+        let first = [
+            memory_bar(0, 0, 0x1000),
+            None,
+            memory_bar(2, 0x4000_1000, 0x1000),
+            None,
+            None,
+            None,
+        ];
+        let second = [
+            memory_bar(0, 0, 0x4000),
+            memory_bar(1, 0, 0x10_0000),
+            memory_bar(2, 0, 0x1000),
+            None,
+            None,
+            None,
+        ];
+        allocator.reserve_assigned(&first);
+        allocator.reserve_assigned(&second);
+
+        // The BAR that already has an address is left alone
+        assert_eq!(
+            allocator.assign_zeroed(&first),
+            [Some(0x4000_0000), None, None, None, None, None]
+        );
+        // Past the reserved page, and without room for the MiB
+        assert_eq!(
+            allocator.assign_zeroed(&second),
+            [Some(0x4000_4000), None, Some(0x4000_8000), None, None, None]
+        );
+    }
+
+    #[test]
+    fn test_legacy_queue_pfn() {
+        // As the drivers lay out their split rings
+        #[repr(C)]
+        #[derive(Default)]
+        struct Queue {
+            descriptors: [Desc; QUEUE_SIZE],
+            avail: AvailRing,
+            used: UsedRing,
+        }
+        let queue = Box::new(Queue::default());
+        let start = queue.descriptors.as_ptr() as u64;
+        assert_eq!(start % 4096, 0);
+        // Where the rings would be with the queue at 64KiB, as the host's
+        // addresses are too high for a page frame number
+        let avail = 0x1_0000 + (&queue.avail as *const _ as u64 - start);
+        let used = 0x1_0000 + (&queue.used as *const _ as u64 - start);
+        assert_eq!(
+            legacy_queue_pfn(QUEUE_SIZE as u16, 0x1_0000, avail, used),
+            Some(0x10)
+        );
+
+        // A bigger queue needs more room before each ring
+        assert_eq!(legacy_queue_pfn(32, 0x1_0000, avail, used), None);
+        assert_eq!(legacy_queue_pfn(16, 0x1_0040, 0x1_0140, 0x1_1000), None);
+        assert_eq!(legacy_queue_pfn(16, 0x1_0000, 0x1_0100, 0x1_0128), None);
+        assert_eq!(
+            legacy_queue_pfn(16, 1 << 44, (1 << 44) + 0x100, (1 << 44) + 0x1000),
+            None
+        );
     }
 }
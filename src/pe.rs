@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::mem::MemoryRegion;
+use crate::{mem::MemoryRegion, sha256::Sha256};
 
 pub struct Loader<'a> {
     file: &'a mut dyn crate::fat::Read,
@@ -21,12 +21,26 @@ pub struct Loader<'a> {
     image_size: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Error {
     FileError,
     InvalidExecutable,
 }
 
+// Base relocation types
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+const IMAGE_REL_BASED_HIGH: u16 = 1;
+const IMAGE_REL_BASED_LOW: u16 = 2;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_HIGHADJ: u16 = 4;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+// Section characteristics
+#[cfg(feature = "section-protection")]
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+#[cfg(feature = "section-protection")]
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
 #[repr(packed)]
 struct Section {
     _name: [u8; 8],
@@ -34,7 +48,218 @@ struct Section {
     virt_address: u32,
     raw_size: u32,
     raw_offset: u32,
-    _unused: [u8; 16],
+    _unused: [u8; 12],
+    #[cfg_attr(not(feature = "section-protection"), allow(dead_code))]
+    characteristics: u32,
+}
+
+// Gives the headers and each section the access rights their characteristics
+// ask for, which needs the sections to be page aligned
+#[cfg(feature = "section-protection")]
+fn protect(address: u64, size_of_headers: u32, section_alignment: u32, sections: &[Section]) {
+    if section_alignment < 4096 {
+        log!("PE sections aren't page aligned, leaving them unprotected");
+        return;
+    }
+    let mut protected = crate::paging::protect(address, u64::from(size_of_headers), false, false);
+    for section in sections {
+        let characteristics = section.characteristics;
+        protected &= crate::paging::protect(
+            address + u64::from(section.virt_address),
+            u64::from(section.virt_size),
+            characteristics & IMAGE_SCN_MEM_WRITE != 0,
+            characteristics & IMAGE_SCN_MEM_EXECUTE != 0,
+        );
+    }
+    if !protected {
+        log!("Unable to protect all of the PE sections");
+    }
+}
+
+// The address and size of the base relocation table, if the image has one
+// that was loaded
+fn relocation_table(optional_region: &MemoryRegion, sections: &[Section]) -> Option<(u32, u32)> {
+    let num_data_dirs = optional_region.read_u32(108);
+    if num_data_dirs < 5 {
+        // No base relocation table entry
+        return None;
+    }
+    let reloc_dir_virt_addr = optional_region.read_u32(152);
+    let reloc_dir_size = optional_region.read_u32(156);
+    if reloc_dir_virt_addr == 0 || reloc_dir_size == 0 {
+        // No base relocation table available
+        return None;
+    }
+    for section in sections {
+        if section.virt_address == reloc_dir_virt_addr && section.raw_offset % 512 != 0 {
+            // This section is not loaded
+            return None;
+        }
+    }
+    Some((reloc_dir_virt_addr, reloc_dir_size))
+}
+
+// Applies the blocks of base relocations in relocations, which is size bytes
+// of the image's .reloc section, to the loaded image
+fn relocate(
+    image: &MemoryRegion,
+    relocations: &MemoryRegion,
+    size: u32,
+    base_diff: i64,
+) -> Result<(), Error> {
+    let mut section_bytes_remaining = size;
+    let mut offset = 0;
+    while section_bytes_remaining > 0 {
+        // Read details for block
+        let page_rva = relocations.read_u32(offset);
+        let block_size = relocations.read_u32(offset + 4);
+        if block_size < 8 || block_size > section_bytes_remaining {
+            return Err(Error::InvalidExecutable);
+        }
+        let mut block_offset = 8;
+        while block_offset + 2 <= block_size {
+            let entry = relocations.read_u16(offset + u64::from(block_offset));
+            block_offset += 2;
+
+            let entry_type = entry >> 12;
+            let location = u64::from(page_rva + u32::from(entry & 0xfff));
+            match entry_type {
+                // Padding to keep the blocks 32 bit aligned
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_HIGH => {
+                    let value = image.read_u16(location);
+                    image.write_u16(location, value.wrapping_add((base_diff >> 16) as u16));
+                }
+                IMAGE_REL_BASED_LOW => {
+                    let value = image.read_u16(location);
+                    image.write_u16(location, value.wrapping_add(base_diff as u16));
+                }
+                IMAGE_REL_BASED_HIGHLOW => {
+                    let value = image.read_u32(location);
+                    image.write_u32(location, value.wrapping_add(base_diff as u32));
+                }
+                // The high half of a 32 bit value whose low half, which is
+                // sign extended when it is used, is in the next entry
+                IMAGE_REL_BASED_HIGHADJ => {
+                    if block_offset + 2 > block_size {
+                        return Err(Error::InvalidExecutable);
+                    }
+                    let low = relocations.read_u16(offset + u64::from(block_offset)) as i16;
+                    block_offset += 2;
+                    let value = (u32::from(image.read_u16(location)) << 16)
+                        .wrapping_add(low as u32)
+                        .wrapping_add(base_diff as u32)
+                        .wrapping_add(0x8000);
+                    image.write_u16(location, (value >> 16) as u16);
+                }
+                IMAGE_REL_BASED_DIR64 => {
+                    let value = image.read_u64(location);
+                    image.write_u64(location, (value as i64).wrapping_add(base_diff) as u64);
+                }
+                _ => return Err(Error::InvalidExecutable),
+            }
+        }
+
+        section_bytes_remaining -= block_size;
+        offset += u64::from(block_size);
+    }
+    Ok(())
+}
+
+// Enough for the section headers that fit in the sectors read_headers() reads
+const MAX_SECTIONS: usize = 24;
+// How much of the signature is read, the digest comes before the certificates
+const SIGNATURE_READ_SIZE: usize = 1024;
+// WIN_CERTIFICATE wCertificateType for a PKCS#7 SignedData signature
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 2;
+
+// DER tags and the object identifiers of an Authenticode signature
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_EXPLICIT_0: u8 = 0xa0;
+const OID_SIGNED_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02];
+const OID_SPC_INDIRECT_DATA: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x04];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+pub struct Authenticode {
+    // SHA-256 of the image, leaving out the checksum and the certificates
+    pub hash: [u8; 32],
+    // The SHA-256 digest the image's signature was made over, if it is signed
+    // with one
+    pub signed_hash: Option<[u8; 32]>,
+}
+
+// The tag of a DER element, the length of its contents and what follows the
+// tag and length, which the contents may run past
+fn der_header(data: &[u8]) -> Option<(u8, usize, &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&length, data) = data.split_first()?;
+    if length < 0x80 {
+        return Some((tag, usize::from(length), data));
+    }
+    let count = usize::from(length & 0x7f);
+    if count == 0 || count > 4 || data.len() < count {
+        return None;
+    }
+    let length = data[..count]
+        .iter()
+        .fold(0, |length, b| length << 8 | usize::from(*b));
+    Some((tag, length, &data[count..]))
+}
+
+// The contents of a constructed element that may not have been read in full
+fn der_open(data: &[u8], tag: u8) -> Option<&[u8]> {
+    match der_header(data)? {
+        (t, _, contents) if t == tag => Some(contents),
+        _ => None,
+    }
+}
+
+// The contents of a whole element and what follows it
+fn der_element(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    match der_header(data)? {
+        (t, length, rest) if t == tag && rest.len() >= length => Some(rest.split_at(length)),
+        _ => None,
+    }
+}
+
+// The digest of the image in a PKCS#7 SignedData signature, which holds it in
+// an SpcIndirectDataContent ahead of the certificates and signer infos. Only
+// SHA-256 digests are looked for.
+fn signed_digest(signature: &[u8]) -> Option<[u8; 32]> {
+    let content_info = der_open(signature, DER_SEQUENCE)?;
+    let (oid, rest) = der_element(content_info, DER_OID)?;
+    if oid != OID_SIGNED_DATA {
+        return None;
+    }
+    let signed_data = der_open(der_open(rest, DER_EXPLICIT_0)?, DER_SEQUENCE)?;
+    let (_version, rest) = der_element(signed_data, DER_INTEGER)?;
+    let (_digest_algorithms, rest) = der_element(rest, DER_SET)?;
+
+    let (content_info, _) = der_element(rest, DER_SEQUENCE)?;
+    let (oid, rest) = der_element(content_info, DER_OID)?;
+    if oid != OID_SPC_INDIRECT_DATA {
+        return None;
+    }
+    let (content, _) = der_element(rest, DER_EXPLICIT_0)?;
+    let (indirect_data, _) = der_element(content, DER_SEQUENCE)?;
+    let (_data, rest) = der_element(indirect_data, DER_SEQUENCE)?;
+    let (digest_info, _) = der_element(rest, DER_SEQUENCE)?;
+    let (algorithm, rest) = der_element(digest_info, DER_SEQUENCE)?;
+    let (oid, _) = der_element(algorithm, DER_OID)?;
+    if oid != OID_SHA256 {
+        return None;
+    }
+    let (digest, _) = der_element(rest, DER_OCTET_STRING)?;
+    if digest.len() != 32 {
+        return None;
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest);
+    Some(hash)
 }
 
 impl<'a> Loader<'a> {
@@ -47,8 +272,12 @@ impl<'a> Loader<'a> {
         }
     }
 
-    pub fn load(&mut self, load_addr: u64) -> Result<(u64, u64, u64), Error> {
-        let mut data: [u8; 1024] = [0; 1024];
+    // Reads the first two sectors of the file and checks they hold the
+    // headers of an x86-64 PE image, returning the offset of the PE header
+    fn read_headers(&mut self, data: &mut [u8; 1024]) -> Result<u32, Error> {
+        if self.file.seek(0).is_err() {
+            return Err(Error::FileError);
+        }
 
         match self.file.read(&mut data[0..512]) {
             Ok(_) => {}
@@ -60,7 +289,7 @@ impl<'a> Loader<'a> {
             Err(_) => return Err(Error::FileError),
         }
 
-        let dos_region = MemoryRegion::from_bytes(&mut data);
+        let dos_region = MemoryRegion::from_bytes(data);
 
         // 'MZ' magic
         if dos_region.read_u16(0) != 0x5a4d {
@@ -88,17 +317,166 @@ impl<'a> Loader<'a> {
             return Err(Error::InvalidExecutable);
         }
 
-        self.num_sections = pe_region.read_u16(6);
+        // Only support x86-64 EFI
+        if pe_region.read_u16(24) != 0x20b {
+            return Err(Error::InvalidExecutable);
+        }
 
-        let optional_header_size = pe_region.read_u16(20);
+        Ok(pe_header_offset)
+    }
+
+    // The amount of memory the image needs once loaded
+    pub fn image_size(&mut self) -> Result<u64, Error> {
+        let mut data: [u8; 1024] = [0; 1024];
+        let pe_header_offset = self.read_headers(&mut data)?;
         let optional_region =
             MemoryRegion::from_bytes(&mut data[(24 + pe_header_offset) as usize..]);
+        Ok(u64::from(optional_region.read_u32(56)))
+    }
 
-        // Only support x86-64 EFI
-        if optional_region.read_u16(0) != 0x20b {
+    // Hashes the image the way Authenticode does: the headers without the
+    // checksum and the certificate table entry, then the sections in file
+    // order and then anything after them but the certificate table, which is
+    // also where the signature is read from
+    pub fn authenticode(&mut self) -> Result<Authenticode, Error> {
+        let mut data: [u8; 1024] = [0; 1024];
+        let pe_header_offset = self.read_headers(&mut data)?;
+        let pe_region = MemoryRegion::from_bytes(&mut data[pe_header_offset as usize..]);
+        let num_sections = usize::from(pe_region.read_u16(6));
+        let optional_header_size = u32::from(pe_region.read_u16(20));
+
+        let optional_offset = pe_header_offset + 24;
+        let optional_region = MemoryRegion::from_bytes(&mut data[optional_offset as usize..]);
+        let size_of_headers = optional_region.read_u32(60);
+        let checksum = optional_offset + 64;
+        let security_dir = optional_offset + 144;
+        let has_security_dir = optional_region.read_u32(108) >= 5;
+        let (cert_offset, cert_size) = if has_security_dir {
+            (optional_region.read_u32(144), optional_region.read_u32(148))
+        } else {
+            (0, 0)
+        };
+        let file_size = self.file.get_size();
+
+        let sections_offset = (optional_offset + optional_header_size) as usize;
+        if num_sections > MAX_SECTIONS
+            || sections_offset + num_sections * core::mem::size_of::<Section>() > data.len()
+        {
+            return Err(Error::InvalidExecutable);
+        }
+        let sections: &[Section] = unsafe {
+            core::slice::from_raw_parts(
+                data[sections_offset..].as_ptr() as *const Section,
+                num_sections,
+            )
+        };
+
+        let mut ranges = [(0u32, 0u32); MAX_SECTIONS + 4];
+        ranges[0] = (0, checksum);
+        let mut count = if has_security_dir {
+            ranges[1] = (checksum + 4, security_dir);
+            ranges[2] = (security_dir + 8, size_of_headers);
+            3
+        } else {
+            ranges[1] = (checksum + 4, size_of_headers);
+            2
+        };
+        let first_section = count;
+        let mut hashed = size_of_headers;
+        for section in sections {
+            let (raw_offset, raw_size) = (section.raw_offset, section.raw_size);
+            if raw_size == 0 {
+                continue;
+            }
+            let end = raw_offset
+                .checked_add(raw_size)
+                .ok_or(Error::InvalidExecutable)?;
+            ranges[count] = (raw_offset, end);
+            count += 1;
+            hashed = hashed
+                .checked_add(raw_size)
+                .ok_or(Error::InvalidExecutable)?;
+        }
+        ranges[first_section..count].sort_unstable_by_key(|r| r.0);
+        if file_size > hashed.saturating_add(cert_size) {
+            ranges[count] = (hashed, file_size - cert_size);
+            count += 1;
+        }
+        // The file is only read through once so they can't overlap
+        let ranges = &ranges[..count];
+        if ranges.iter().any(|r| r.0 > r.1 || r.1 > file_size)
+            || ranges.windows(2).any(|r| r[0].1 > r[1].0)
+        {
             return Err(Error::InvalidExecutable);
         }
 
+        let signature_start = cert_offset;
+        let signature_end =
+            cert_offset.saturating_add(core::cmp::min(cert_size, SIGNATURE_READ_SIZE as u32));
+        let mut signature = [0u8; SIGNATURE_READ_SIZE];
+
+        if self.file.seek(0).is_err() {
+            return Err(Error::FileError);
+        }
+        let mut hasher = Sha256::new();
+        let mut sector = [0u8; 512];
+        let mut position = 0;
+        let mut next = 0;
+        while position < file_size && (next < ranges.len() || position < signature_end) {
+            let bytes = match self.file.read(&mut sector) {
+                Ok(0) | Err(_) => return Err(Error::FileError),
+                Ok(bytes) => bytes,
+            };
+            let end = position + bytes;
+            while let Some(&(start, stop)) = ranges.get(next) {
+                let (from, to) = (core::cmp::max(start, position), core::cmp::min(stop, end));
+                if from < to {
+                    hasher.update(&sector[(from - position) as usize..(to - position) as usize]);
+                }
+                if stop > end {
+                    break;
+                }
+                next += 1;
+            }
+            let (from, to) = (
+                core::cmp::max(signature_start, position),
+                core::cmp::min(signature_end, end),
+            );
+            if from < to {
+                signature[(from - signature_start) as usize..(to - signature_start) as usize]
+                    .copy_from_slice(&sector[(from - position) as usize..(to - position) as usize]);
+            }
+            position = end;
+        }
+
+        // A WIN_CERTIFICATE: its length, revision and type, then the signature
+        let certificate = &signature[..(signature_end - signature_start) as usize];
+        let signed_hash = match certificate.get(6..8) {
+            Some(&[low, high])
+                if u16::from_le_bytes([low, high]) == WIN_CERT_TYPE_PKCS_SIGNED_DATA =>
+            {
+                signed_digest(&certificate[8..])
+            }
+            _ => None,
+        };
+
+        Ok(Authenticode {
+            hash: hasher.finish(),
+            signed_hash,
+        })
+    }
+
+    pub fn load(&mut self, load_addr: u64) -> Result<(u64, u64, u64), Error> {
+        let mut data: [u8; 1024] = [0; 1024];
+        let pe_header_offset = self.read_headers(&mut data)?;
+        let pe_region = MemoryRegion::from_bytes(&mut data[pe_header_offset as usize..]);
+
+        self.num_sections = pe_region.read_u16(6);
+
+        let optional_header_size = pe_region.read_u16(20);
+        let optional_region =
+            MemoryRegion::from_bytes(&mut data[(24 + pe_header_offset) as usize..]);
+
         let entry_point = optional_region.read_u32(16);
 
         self.image_base = optional_region.read_u64(24);
@@ -186,70 +564,188 @@ impl<'a> Loader<'a> {
 
         let base_diff = address as i64 - self.image_base as i64;
 
-        let num_data_dirs = optional_region.read_u32(108);
-        if num_data_dirs < 5 {
-            // No base relocation table entry
-            return Ok(image_info);
+        if let Some((reloc_dir_virt_addr, reloc_dir_size)) =
+            relocation_table(&optional_region, sections)
+        {
+            let l: &mut [u8] = loaded_region
+                .as_mut_slice(u64::from(reloc_dir_virt_addr), u64::from(reloc_dir_size));
+            let reloc_region = MemoryRegion::from_bytes(l);
+            relocate(&loaded_region, &reloc_region, reloc_dir_size, base_diff)?;
         }
-        let reloc_dir_virt_addr = optional_region.read_u32(152);
-        let reloc_dir_size = optional_region.read_u32(156);
-        if reloc_dir_virt_addr == 0 || reloc_dir_size == 0 {
-            // No base relocation table available
-            return Ok(image_info);
+
+        #[cfg(feature = "section-protection")]
+        protect(
+            address,
+            size_of_headers,
+            optional_region.read_u32(32),
+            sections,
+        );
+
+        Ok(image_info)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{relocate, Authenticode, Loader};
+    use crate::mem::MemoryRegion;
+    use crate::part::tests::FakeDisk;
+    use crate::sha256::Sha256;
+
+    use std::alloc;
+
+    // An image in memory, read a sector at a time like a file on the ESP
+    pub struct TestFile<'a> {
+        data: &'a [u8],
+        position: u32,
+    }
+
+    impl<'a> crate::fat::Read for TestFile<'a> {
+        fn read(&mut self, data: &mut [u8]) -> Result<u32, crate::fat::Error> {
+            assert_eq!(data.len(), 512);
+            let start = self.position as usize;
+            if start >= self.data.len() {
+                return Err(crate::fat::Error::EndOfFile);
+            }
+            let bytes = std::cmp::min(512, self.data.len() - start);
+            data[..bytes].copy_from_slice(&self.data[start..start + bytes]);
+            self.position += bytes as u32;
+            Ok(bytes as u32)
         }
-        for section in sections {
-            if section.virt_address == reloc_dir_virt_addr && section.raw_offset % 512 != 0 {
-                // This section is not loaded
-                return Ok(image_info);
+
+        fn seek(&mut self, position: u32) -> Result<(), crate::fat::Error> {
+            if position % 512 != 0 {
+                return Err(crate::fat::Error::InvalidOffset);
             }
+            self.position = position;
+            Ok(())
         }
 
-        let section_size = reloc_dir_size;
-        let l: &mut [u8] =
-            loaded_region.as_mut_slice(u64::from(reloc_dir_virt_addr), u64::from(section_size));
-
-        let reloc_region = MemoryRegion::from_bytes(l);
-
-        let mut section_bytes_remaining = section_size;
-        let mut offset = 0;
-        while section_bytes_remaining > 0 {
-            // Read details for block
-            let page_rva = reloc_region.read_u32(offset);
-            let block_size = reloc_region.read_u32(offset + 4);
-            let mut block_offset = 8;
-            while block_offset < block_size {
-                let entry = reloc_region.read_u16(offset + u64::from(block_offset));
-
-                let entry_type = entry >> 12;
-                let entry_offset = entry & 0xfff;
-
-                if entry_type == 10 {
-                    let location = u64::from(page_rva + u32::from(entry_offset));
-                    let value = loaded_region.read_u64(location);
-                    loaded_region.write_u64(location, (value as i64 + base_diff) as u64);
-                }
+        fn get_size(&self) -> u32 {
+            self.data.len() as u32
+        }
+    }
 
-                block_offset += 2;
-            }
+    impl<'a> TestFile<'a> {
+        pub fn new(data: &'a [u8]) -> TestFile<'a> {
+            TestFile { data, position: 0 }
+        }
+    }
+
+    // Where the image from image() has its checksum and certificate table
+    // directory entry
+    const CHECKSUM: usize = 0x98;
+    const SECURITY_DIR: usize = 0xe8;
 
-            section_bytes_remaining -= block_size;
-            offset += u64::from(block_size);
+    fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+        data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // A PE image with two sections that have their headers in the reverse
+    // order to their data, followed by a few more bytes
+    pub fn image() -> Vec<u8> {
+        let mut data = vec![0u8; 0x610];
+        data[0..2].copy_from_slice(b"MZ");
+        write_u32(&mut data, 0x3c, 0x40);
+        data[0x40..0x44].copy_from_slice(b"PE\0\0");
+        data[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+        data[0x46..0x48].copy_from_slice(&2u16.to_le_bytes());
+        data[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+        // The optional header
+        data[0x58..0x5a].copy_from_slice(&0x20bu16.to_le_bytes());
+        write_u32(&mut data, 0x58 + 32, 0x1000);
+        write_u32(&mut data, 0x58 + 56, 0x3000);
+        write_u32(&mut data, 0x58 + 60, 0x200);
+        write_u32(&mut data, 0x58 + 108, 16);
+        // The section headers
+        for (i, (address, offset)) in [(0x2000, 0x400), (0x1000, 0x200)].iter().enumerate() {
+            let header = 0x148 + i * 40;
+            write_u32(&mut data, header + 8, 0x200);
+            write_u32(&mut data, header + 12, *address);
+            write_u32(&mut data, header + 16, 0x200);
+            write_u32(&mut data, header + 20, *offset);
+        }
+        for (i, b) in data[0x200..].iter_mut().enumerate() {
+            *b = i as u8;
         }
+        data
+    }
 
-        Ok(image_info)
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        if contents.len() < 0x80 {
+            element.push(contents.len() as u8);
+        } else {
+            element.push(0x82);
+            element.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        }
+        element.extend_from_slice(contents);
+        element
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::part::tests::FakeDisk;
+    // Appends a certificate table with a signature over hash, which has
+    // enough in the place of the certificates to go past the first sector
+    pub fn sign(data: &mut Vec<u8>, hash: &[u8; 32]) {
+        let sha256 = der(
+            0x30,
+            &[der(0x06, super::OID_SHA256), der(0x05, &[])].concat(),
+        );
+        let indirect_data = der(
+            0x30,
+            &[
+                der(
+                    0x30,
+                    &der(
+                        0x06,
+                        &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x01, 0x0f],
+                    ),
+                ),
+                der(0x30, &[sha256.clone(), der(0x04, hash)].concat()),
+            ]
+            .concat(),
+        );
+        let content_info = der(
+            0x30,
+            &[
+                der(0x06, super::OID_SPC_INDIRECT_DATA),
+                der(0xa0, &indirect_data),
+            ]
+            .concat(),
+        );
+        let signed_data = der(
+            0x30,
+            &[
+                der(0x02, &[1]),
+                der(0x31, &sha256),
+                content_info,
+                der(0xa0, &[0xcc; 600]),
+            ]
+            .concat(),
+        );
+        let signature = der(
+            0x30,
+            &[der(0x06, super::OID_SIGNED_DATA), der(0xa0, &signed_data)].concat(),
+        );
 
-    use std::alloc;
+        let offset = data.len() as u32;
+        let size = 8 + signature.len() as u32;
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(&[0x00, 0x02, 0x02, 0x00]);
+        data.extend_from_slice(&signature);
+        write_u32(data, SECURITY_DIR, offset);
+        write_u32(data, SECURITY_DIR + 4, size);
+    }
+
+    fn authenticode(data: &[u8]) -> Authenticode {
+        let mut file = TestFile::new(data);
+        Loader::new(&mut file).authenticode().unwrap()
+    }
 
     #[test]
     fn test_loader() {
         let d = FakeDisk::new("clear-28660-kvm.img");
-        let (start, end) = crate::part::find_efi_partition(&d).unwrap();
+        let (start, end, _) =
+            crate::part::find_efi_partition(&d, crate::part::PartitionSelector::First).unwrap();
 
         let mut f = crate::fat::Filesystem::new(&d, start, end);
         f.init().unwrap();
@@ -266,4 +762,82 @@ mod tests {
         assert_eq!(addr, fake_mem as u64);
         assert_eq!(size, 110_592);
     }
+
+    #[test]
+    fn test_relocate() {
+        let mut image = vec![0u8; 0x3000];
+        let image = MemoryRegion::from_bytes(&mut image);
+        image.write_u16(0x1000, 0x1234);
+        image.write_u16(0x1002, 0x1234);
+        image.write_u32(0x1004, 0x1234_5678);
+        image.write_u16(0x1008, 0x1234);
+        image.write_u64(0x2000, 0x1_0000_1000);
+        image.write_u32(0x2008, 0xaaaa_aaaa);
+
+        // Two blocks, the first padded out with an ABSOLUTE entry
+        let entries: &[&[u16]] = &[
+            &[0x1000, 0x2002, 0x3004, 0x4008, 0x8000, 0x0000],
+            &[0xa000, 0x0008],
+        ];
+        let mut relocations = Vec::new();
+        for (page, entries) in [0x1000u32, 0x2000].iter().zip(entries) {
+            relocations.extend_from_slice(&page.to_le_bytes());
+            relocations.extend_from_slice(&(8 + 2 * entries.len() as u32).to_le_bytes());
+            for entry in entries.iter() {
+                relocations.extend_from_slice(&entry.to_le_bytes());
+            }
+        }
+        let size = relocations.len() as u32;
+        let relocations = MemoryRegion::from_bytes(&mut relocations);
+
+        relocate(&image, &relocations, size, 0x0001_9000).unwrap();
+        assert_eq!(image.read_u16(0x1000), 0x1235);
+        assert_eq!(image.read_u16(0x1002), 0xa234);
+        assert_eq!(image.read_u32(0x1004), 0x1235_e678);
+        // Rounded for the low half of 0x8000 being negative
+        assert_eq!(image.read_u16(0x1008), 0x1235);
+        assert_eq!(image.read_u64(0x2000), 0x1_0001_a000);
+        // The ABSOLUTE entry has left this alone
+        assert_eq!(image.read_u32(0x2008), 0xaaaa_aaaa);
+
+        // Types that aren't handled and blocks that don't fit
+        let mut bad = [0u8, 0x10, 0, 0, 10, 0, 0, 0, 0, 0x50];
+        let bad = MemoryRegion::from_bytes(&mut bad);
+        assert!(relocate(&image, &bad, 10, 0x1000).is_err());
+        assert!(relocate(&image, &bad, 8, 0x1000).is_err());
+    }
+
+    #[test]
+    fn test_authenticode() {
+        let mut data = image();
+        let mut expected = Sha256::new();
+        expected.update(&data[..CHECKSUM]);
+        expected.update(&data[CHECKSUM + 4..SECURITY_DIR]);
+        expected.update(&data[SECURITY_DIR + 8..]);
+        let expected = expected.finish();
+
+        let a = authenticode(&data);
+        assert_eq!(a.hash, expected);
+        assert_eq!(a.signed_hash, None);
+
+        // Neither the checksum nor the signature are part of the hash
+        write_u32(&mut data, CHECKSUM, 0x1234);
+        sign(&mut data, &expected);
+        assert!(data.len() > 1024);
+        let a = authenticode(&data);
+        assert_eq!(a.hash, expected);
+        assert_eq!(a.signed_hash, Some(expected));
+
+        // Changed after it was signed
+        data[0x300] ^= 1;
+        let a = authenticode(&data);
+        assert_ne!(a.hash, expected);
+        assert_eq!(a.signed_hash, Some(expected));
+
+        // Sections running into each other
+        let mut data = image();
+        write_u32(&mut data, 0x148 + 40 + 16, 0x300);
+        let mut file = TestFile::new(&data);
+        assert!(Loader::new(&mut file).authenticode().is_err());
+    }
 }
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Only the serial port of aarch64 machines, but built for the tests
+// everywhere so that it is tested along with everything else
+
+use crate::{mem, serial::Uart};
+
+const PL011_SIZE: u64 = 0x1000;
+
+// Flag register
+const FLAG_RX_FIFO_EMPTY: u32 = 1 << 4;
+const FLAG_TX_FIFO_FULL: u32 = 1 << 5;
+// Line control register: 8 data bits with the FIFOs enabled
+const LINE_CONTROL_8_BITS: u32 = 3 << 5;
+const LINE_CONTROL_FIFO_ENABLE: u32 = 1 << 4;
+// Control register
+const CONTROL_UART_ENABLE: u32 = 1 << 0;
+const CONTROL_TX_ENABLE: u32 = 1 << 8;
+const CONTROL_RX_ENABLE: u32 = 1 << 9;
+
+// Registers:
+/// le32 dr;        // 0x000
+/// le32 fr;        // 0x018 // read-only
+/// le32 ibrd;      // 0x024
+/// le32 fbrd;      // 0x028
+/// le32 lcr_h;     // 0x02c
+/// le32 cr;        // 0x030
+pub struct Pl011 {
+    region: mem::MemoryRegion,
+}
+
+// The integer and fractional (in 64ths) parts of the baud rate divisor,
+// which divides the reference clock divided by 16
+fn divisors(clock: u32, baud: u32) -> (u32, u32) {
+    let divisor = (u64::from(clock) * 4 + u64::from(baud) / 2) / u64::from(baud);
+    ((divisor >> 6) as u32, (divisor & 0x3f) as u32)
+}
+
+impl Pl011 {
+    pub const fn new(base: u64) -> Pl011 {
+        Pl011 {
+            region: mem::MemoryRegion::new(base, PL011_SIZE),
+        }
+    }
+
+    /// Sets the baud rate for the UART's reference clock, with 8 data bits,
+    /// no parity and 1 stop bit
+    pub fn init(&self, clock: u32, baud: u32) {
+        let (integer, fraction) = divisors(clock, baud);
+        // The UART has to be disabled while it is being set up
+        self.region.io_write_u32(0x030, 0);
+        self.region.io_write_u32(0x024, integer);
+        self.region.io_write_u32(0x028, fraction);
+        // Writing lcr_h is what latches the divisors
+        self.region
+            .io_write_u32(0x02c, LINE_CONTROL_8_BITS | LINE_CONTROL_FIFO_ENABLE);
+        self.region.io_write_u32(
+            0x030,
+            CONTROL_UART_ENABLE | CONTROL_TX_ENABLE | CONTROL_RX_ENABLE,
+        );
+    }
+}
+
+impl Uart for Pl011 {
+    fn write_byte(&self, b: u8) {
+        while self.region.io_read_u32(0x018) & FLAG_TX_FIFO_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        self.region.io_write_u32(0x000, u32::from(b));
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        if self.region.io_read_u32(0x018) & FLAG_RX_FIFO_EMPTY != 0 {
+            return None;
+        }
+        // The top bits of dr are the receive error flags
+        Some(self.region.io_read_u32(0x000) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{divisors, Pl011, FLAG_RX_FIFO_EMPTY};
+    use crate::serial::Uart;
+
+    #[test]
+    fn test_divisors() {
+        // QEMU's virt machine and the 3 MHz clock of some real ones
+        assert_eq!(divisors(24_000_000, 115_200), (13, 1));
+        assert_eq!(divisors(24_000_000, 9_600), (156, 16));
+        assert_eq!(divisors(3_000_000, 115_200), (1, 40));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut registers = [0u32; 0x400];
+        let uart = Pl011::new(registers.as_mut_ptr() as u64);
+
+        uart.write_byte(b'x');
+        assert_eq!(uart.region.read_u32(0x000), u32::from(b'x'));
+        uart.region.write_u32(0x018, FLAG_RX_FIFO_EMPTY);
+        assert_eq!(uart.read_byte(), None);
+
+        // With a framing error flagged above the data
+        uart.region.write_u32(0x000, 1 << 8 | u32::from(b'y'));
+        uart.region.write_u32(0x018, 0);
+        assert_eq!(uart.read_byte(), Some(b'y'));
+
+        uart.init(24_000_000, 115_200);
+        assert_eq!(uart.region.read_u32(0x024), 13);
+        assert_eq!(uart.region.read_u32(0x028), 1);
+        assert_eq!(uart.region.read_u32(0x030), 0x301);
+    }
+}
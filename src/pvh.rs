@@ -1,7 +1,7 @@
 use core::mem::size_of;
 
 use crate::{
-    boot::{E820Entry, Info},
+    boot::{Cmdline, E820Entry, Info, MemoryMap, CMDLINE_MAX_LEN, MAX_ENTRIES},
     common,
 };
 
@@ -21,6 +21,15 @@ pub struct StartInfo {
     _pad: u32,
 }
 
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct ModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    _reserved: u64,
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 struct MemMapEntry {
@@ -30,6 +39,100 @@ struct MemMapEntry {
     _pad: u32,
 }
 
+const START_INFO_MAGIC: [u8; 4] = *b"xEn3";
+
+// What we start a PVH kernel with, which the start info points into
+#[repr(C)]
+pub struct BootParams {
+    start_info: StartInfo,
+    module: ModlistEntry,
+    memmap: [MemMapEntry; MAX_ENTRIES],
+    cmdline: [u8; CMDLINE_MAX_LEN],
+}
+
+impl BootParams {
+    pub const fn new() -> Self {
+        Self {
+            start_info: StartInfo {
+                magic: [0; 4],
+                version: 0,
+                flags: 0,
+                nr_modules: 0,
+                modlist_paddr: 0,
+                cmdline_paddr: 0,
+                rsdp_paddr: 0,
+                memmap_paddr: 0,
+                memmap_entries: 0,
+                _pad: 0,
+            },
+            module: ModlistEntry {
+                paddr: 0,
+                size: 0,
+                cmdline_paddr: 0,
+                _reserved: 0,
+            },
+            memmap: [MemMapEntry {
+                addr: 0,
+                size: 0,
+                entry_type: 0,
+                _pad: 0,
+            }; MAX_ENTRIES],
+            cmdline: [0; CMDLINE_MAX_LEN],
+        }
+    }
+
+    // Fills in the start info, with the module as the kernel's initrd,
+    // returning its address
+    pub fn fill(
+        &mut self,
+        memory: &MemoryMap,
+        cmdline: &Cmdline,
+        rsdp_addr: u64,
+        module: Option<(u64, u64)>,
+    ) -> u64 {
+        let cmdline = cmdline.as_bytes_with_nul();
+        self.cmdline[..cmdline.len()].copy_from_slice(cmdline);
+        for (entry, e820) in self.memmap.iter_mut().zip(memory.entries()) {
+            *entry = MemMapEntry {
+                addr: e820.addr,
+                size: e820.size,
+                entry_type: e820.entry_type,
+                _pad: 0,
+            };
+        }
+
+        let info = &mut self.start_info;
+        info.magic = START_INFO_MAGIC;
+        info.version = 1;
+        info.cmdline_paddr = self.cmdline.as_ptr() as u64;
+        info.rsdp_paddr = rsdp_addr;
+        info.memmap_paddr = self.memmap.as_ptr() as u64;
+        info.memmap_entries = memory.entries().len() as u32;
+        if let Some((start, end)) = module {
+            self.module.paddr = start;
+            self.module.size = end - start;
+            info.nr_modules = 1;
+            info.modlist_paddr = &self.module as *const _ as u64;
+        }
+        info as *const _ as u64
+    }
+}
+
+impl StartInfo {
+    // The start info at the address, if its magic says that's what is there
+    #[cfg(any(test, not(feature = "coreboot")))]
+    pub unsafe fn from_addr<'a>(addr: u64) -> Option<&'a StartInfo> {
+        if addr == 0 || addr % core::mem::align_of::<StartInfo>() as u64 != 0 {
+            return None;
+        }
+        let info = &*(addr as *const StartInfo);
+        if info.magic != START_INFO_MAGIC {
+            return None;
+        }
+        Some(info)
+    }
+}
+
 impl Info for StartInfo {
     fn name(&self) -> &str {
         "PVH Boot Protocol"
@@ -66,7 +169,7 @@ extern "C" {
 
 // The kind/name/desc of the PHV ELF Note are from xen/include/public/elfnote.h.
 // This is the "Physical entry point into the kernel".
-const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+pub const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
 type Name = [u8; 4];
 type Desc = unsafe extern "C" fn();
 
@@ -92,3 +195,77 @@ static PVH_NOTE: Note = Note {
     name: *b"Xen\0",
     desc: ram32_start,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::{BootParams, StartInfo};
+    use crate::boot::{Cmdline, E820Entry, Info, MemoryMap};
+
+    struct TestInfo {}
+
+    impl Info for TestInfo {
+        fn name(&self) -> &str {
+            "Test"
+        }
+        fn rsdp_addr(&self) -> u64 {
+            0xf_0000
+        }
+        fn cmdline(&self) -> &[u8] {
+            b""
+        }
+        fn num_entries(&self) -> u8 {
+            1
+        }
+        fn entry(&self, _idx: u8) -> E820Entry {
+            E820Entry {
+                addr: 0x10_0000,
+                size: 0x3ff0_0000,
+                entry_type: E820Entry::RAM_TYPE,
+            }
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut cmdline = Cmdline::new();
+        cmdline.append(b"console=hvc0");
+        let mut params = Box::new(BootParams::new());
+        let addr = params.fill(
+            &MemoryMap::new(&TestInfo {}),
+            &cmdline,
+            0xf_0000,
+            Some((0x3000_0000, 0x3000_1000)),
+        );
+
+        // A kernel reads it back the way we read what we were booted with
+        let info = unsafe { &*(addr as *const StartInfo) };
+        assert_eq!(&info.magic, b"xEn3");
+        assert_eq!(info.cmdline(), b"console=hvc0");
+        assert_eq!(info.rsdp_addr(), 0xf_0000);
+        assert_eq!(info.num_entries(), 1);
+        let entry = info.entry(0);
+        assert_eq!({ entry.addr }, 0x10_0000);
+        assert_eq!(info.nr_modules, 1);
+        assert_eq!(params.module.paddr, 0x3000_0000);
+        assert_eq!(params.module.size, 0x1000);
+    }
+
+    #[test]
+    fn test_from_addr() {
+        let mut params = Box::new(BootParams::new());
+        let addr = &params.start_info as *const _ as u64;
+        assert!(unsafe { StartInfo::from_addr(addr) }.is_none());
+
+        params.fill(&MemoryMap::new(&TestInfo {}), &Cmdline::new(), 0, None);
+        let info = unsafe { StartInfo::from_addr(addr) }.unwrap();
+        assert_eq!(info.rsdp_addr(), 0);
+        assert!(unsafe { StartInfo::from_addr(addr + 4) }.is_none());
+        assert!(unsafe { StartInfo::from_addr(0) }.is_none());
+
+        // Linux boot parameters are no start info
+        let mut linux = crate::boot::Params::default();
+        linux.hdr.boot_flag = 0xaa55;
+        linux.hdr.header = *b"HdrS";
+        assert!(unsafe { StartInfo::from_addr(&linux as *const _ as u64) }.is_none());
+    }
+}
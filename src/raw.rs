@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Boots images in none of the formats the loader knows, such as a vmlinux
+// stripped down to a flat binary, the way the ESP's rawboot.conf says: the
+// whole file is put at its load address and entered in 64-bit mode at its
+// entry point, with %rsi pointing at Linux boot parameters if they are asked
+// for and 0 otherwise. Like the ELF loader's, everything has to be in RAM
+// below 4GiB clear of the firmware.
+
+use crate::{
+    boot::{Info, MemoryMap},
+    bzimage,
+    fat::{self, Read},
+    mem::MemoryRegion,
+    sha256::Sha256,
+    tpm,
+};
+
+pub const MAX_CONFIG_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    FileError(fat::Error),
+    InvalidConfig,
+    // The image isn't all in RAM below 4GiB or would overwrite the firmware
+    InvalidAddress,
+    // The entry point isn't in the image
+    InvalidEntry,
+}
+
+impl From<fat::Error> for Error {
+    fn from(e: fat::Error) -> Error {
+        Error::FileError(e)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    load_address: u64,
+    entry: u64,
+    boot_params: bool,
+}
+
+// Either hexadecimal with a 0x in front or decimal
+fn parse_number(value: &str) -> Result<u64, Error> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| Error::InvalidConfig)
+}
+
+impl Config {
+    // Lines of a key and its value, with anything after a '#' left out. The
+    // load address has to be given, the entry point is the load address
+    // unless it's given and the boot parameters are left out unless
+    // "boot_params yes" is.
+    pub fn parse(data: &[u8]) -> Result<Config, Error> {
+        let text = core::str::from_utf8(data).map_err(|_| Error::InvalidConfig)?;
+        let mut load_address = None;
+        let mut entry = None;
+        let mut boot_params = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let (key, value) = match (words.next(), words.next(), words.next()) {
+                (None, _, _) => continue,
+                (Some(key), Some(value), None) => (key, value),
+                _ => return Err(Error::InvalidConfig),
+            };
+            match key {
+                "load_address" => load_address = Some(parse_number(value)?),
+                "entry" => entry = Some(parse_number(value)?),
+                "boot_params" => {
+                    boot_params = match value {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(Error::InvalidConfig),
+                    }
+                }
+                _ => return Err(Error::InvalidConfig),
+            }
+        }
+        let load_address = load_address.ok_or(Error::InvalidConfig)?;
+        Ok(Config {
+            load_address,
+            entry: entry.unwrap_or(load_address),
+            boot_params,
+        })
+    }
+}
+
+pub struct Kernel {
+    config: Config,
+    memory: MemoryMap,
+    // The boot parameters, and where the initrd and command line are kept
+    // track of
+    params: bzimage::Kernel,
+}
+
+impl Kernel {
+    pub fn new(info: &dyn Info, config: Config) -> Self {
+        Self {
+            config,
+            memory: MemoryMap::new(info),
+            params: bzimage::Kernel::new(info),
+        }
+    }
+
+    // Returns the SHA-256 hash of the image as it was loaded
+    pub fn load_kernel(&mut self, f: &mut dyn Read) -> Result<[u8; 32], Error> {
+        let size = u64::from(f.get_size());
+        let (start, end) = check_layout(&self.config, size)?;
+        if !self.memory.usable((start, end)) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let mut region = MemoryRegion::new(start, size);
+        f.seek(0)?;
+        f.load_file(&mut region)?;
+        let mut hash = Sha256::new();
+        hash.update(region.as_bytes());
+        let digest = hash.finish();
+        tpm::measure(tpm::PCR_KERNEL, &digest, b"kernel");
+
+        self.params.set_headerless(start, size);
+        log!("Loaded raw kernel at {:#x}", start);
+        Ok(digest)
+    }
+
+    pub fn load_initrd(&mut self, f: &mut dyn Read) -> Result<[u8; 32], bzimage::Error> {
+        self.params.load_initrd(f)
+    }
+
+    pub fn append_cmdline(&mut self, addition: &[u8]) {
+        self.params.append_cmdline(addition);
+    }
+
+    pub fn cmdline(&self) -> &[u8] {
+        self.params.cmdline()
+    }
+
+    pub fn boot(&mut self) {
+        self.params
+            .boot_at(self.config.entry, self.config.boot_params);
+    }
+}
+
+// Where an image of the size goes, with the entry point in it
+fn check_layout(config: &Config, size: u64) -> Result<(u64, u64), Error> {
+    let start = config.load_address;
+    let end = match start.checked_add(size) {
+        Some(end) if size != 0 => end,
+        _ => return Err(Error::InvalidAddress),
+    };
+    if config.entry < start || config.entry >= end {
+        return Err(Error::InvalidEntry);
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_layout, Config, Error};
+
+    #[test]
+    fn test_parse_config() {
+        assert_eq!(
+            Config::parse(b"# Loaded at 4MiB\nload_address 0x400000\n\nentry\t0x400010 # startup\nboot_params yes\n")
+                .unwrap(),
+            Config {
+                load_address: 0x40_0000,
+                entry: 0x40_0010,
+                boot_params: true,
+            }
+        );
+        assert_eq!(
+            Config::parse(b"load_address 16777216").unwrap(),
+            Config {
+                load_address: 0x100_0000,
+                entry: 0x100_0000,
+                boot_params: false,
+            }
+        );
+
+        for config in &[
+            &b""[..],
+            b"entry 0x400000",
+            b"load_address",
+            b"load_address 0x40 0000",
+            b"load_address 0xg",
+            b"load_address 0x400000\nboot_params 1",
+            b"load_address 0x400000\nstack 0x1000",
+            b"load_address 0x400000\nentry -1",
+        ] {
+            assert!(matches!(Config::parse(config), Err(Error::InvalidConfig)));
+        }
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let config = Config::parse(b"load_address 0x400000\nentry 0x400fff").unwrap();
+        assert_eq!(
+            check_layout(&config, 0x1000).unwrap(),
+            (0x40_0000, 0x40_1000)
+        );
+        assert!(matches!(
+            check_layout(&config, 0xfff),
+            Err(Error::InvalidEntry)
+        ));
+        assert!(matches!(
+            check_layout(&config, 0),
+            Err(Error::InvalidAddress)
+        ));
+
+        let config = Config::parse(b"load_address 0xffffffffffffff00").unwrap();
+        assert!(matches!(
+            check_layout(&config, 0x1000),
+            Err(Error::InvalidAddress)
+        ));
+    }
+}
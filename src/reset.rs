@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: Apache-2.0
+
+use atomic_refcell::AtomicRefCell;
+use x86_64::instructions::{hlt, port::PortWriteOnly};
+
+use crate::acpi::{self, read_u32, read_u64};
+
+// Reset control register: bit 1 requests a system reset, bit 2 performs it
+// and bit 3 makes it a cold (full) reset
+const RESET_CONTROL_PORT: u16 = 0xcf9;
+const RESET_SYSTEM: u8 = 0x02;
+const RESET_CPU: u8 = 0x04;
+const RESET_FULL: u8 = 0x08;
+
+// Pulsing the keyboard controller's output port resets the CPU
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+// PM1a control on QEMU's q35/piix4 PM devices: SLP_EN with an S5 SLP_TYP of 0
+const QEMU_PM1A_CONTROL_PORT: u16 = 0x604;
+const QEMU_PM1A_SHUTDOWN: u16 = 1 << 13;
+// Cloud Hypervisor's shutdown device: S5 SLP_TYP with SLP_EN
+const CLOUD_HYPERVISOR_SHUTDOWN_PORT: u16 = 0x600;
+const CLOUD_HYPERVISOR_SHUTDOWN: u8 = (5 << 2) | (1 << 5);
+// QEMU's isa-debug-exit device, if present
+const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+// How to enter S5, worked out from the FADT and DSDT while they are mapped
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AcpiShutdown {
+    // PM1 control blocks: SLP_TYP in bits 10-12 and SLP_EN in bit 13
+    Pm1Control {
+        pm1a: u16,
+        pm1b: u16,
+        sleep_type_a: u8,
+        sleep_type_b: u8,
+    },
+    // Hardware-reduced sleep control register: SLP_TYP in bits 2-4 and
+    // SLP_EN in bit 5
+    SleepControl {
+        port: u16,
+        sleep_type: u8,
+    },
+}
+
+static ACPI_SHUTDOWN: AtomicRefCell<Option<AcpiShutdown>> = AtomicRefCell::new(None);
+
+// AML opcodes
+const NAME_OP: u8 = 0x08;
+const ROOT_CHAR: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const BYTE_PREFIX: u8 = 0x0a;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+
+// The value of a package element that is a byte constant, and its length
+fn byte_element(aml: &[u8]) -> Option<(u8, usize)> {
+    match *aml.first()? {
+        BYTE_PREFIX => Some((*aml.get(1)?, 2)),
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        _ => None,
+    }
+}
+
+// Whether the name at the position in the AML is what a NameOp defines,
+// with or without the root prefix
+fn is_object_name(aml: &[u8], position: usize) -> bool {
+    match position.checked_sub(1).map(|p| aml[p]) {
+        Some(NAME_OP) => true,
+        Some(ROOT_CHAR) => position >= 2 && aml[position - 2] == NAME_OP,
+        _ => false,
+    }
+}
+
+// Finds the SLP_TYPa and SLP_TYPb values from the \_S5 package in the DSDT,
+// e.g. Name (_S5, Package (0x04) { 0x05, 0x00, 0x00, 0x00 })
+fn s5_sleep_types(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let start = dsdt
+        .windows(4)
+        .enumerate()
+        .position(|(p, w)| w == b"_S5_" && is_object_name(dsdt, p))?
+        + 4;
+    let aml = &dsdt[start..];
+    if aml.first() != Some(&PACKAGE_OP) {
+        return None;
+    }
+    // The top two bits of PkgLength give the number of extra length bytes,
+    // then comes NumElements
+    let num_elements = 2 + usize::from(aml.get(1)? >> 6);
+    let count = *aml.get(num_elements)?;
+    let element = num_elements + 1;
+    let (a, length) = byte_element(aml.get(element..)?)?;
+    // Without SLP_TYPb it takes the same value
+    if count < 2 {
+        return Some((a, a));
+    }
+    let (b, _) = byte_element(aml.get(element + length..)?)?;
+    Some((a, b))
+}
+
+fn parse_fadt(fadt: &[u8], dsdt: &[u8]) -> Option<AcpiShutdown> {
+    // Address space id of a Generic Address Structure
+    const SYSTEM_IO: u8 = 1;
+    const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+    let (sleep_type_a, sleep_type_b) = s5_sleep_types(dsdt)?;
+    // flags: 112, sleep_control_reg: 244
+    if fadt.len() >= 256 && read_u32(fadt, 112) & HW_REDUCED_ACPI != 0 {
+        if fadt[244] != SYSTEM_IO {
+            return None;
+        }
+        return Some(AcpiShutdown::SleepControl {
+            port: read_u64(fadt, 248) as u16,
+            sleep_type: sleep_type_a,
+        });
+    }
+
+    // pm1a_cnt_blk: 64, pm1b_cnt_blk: 68, x_pm1a_cnt_blk: 172
+    let mut pm1a = read_u32(fadt, 64) as u16;
+    if pm1a == 0 && fadt.len() >= 184 && fadt[172] == SYSTEM_IO {
+        pm1a = read_u64(fadt, 176) as u16;
+    }
+    if pm1a == 0 {
+        return None;
+    }
+    Some(AcpiShutdown::Pm1Control {
+        pm1a,
+        pm1b: read_u32(fadt, 68) as u16,
+        sleep_type_a,
+        sleep_type_b,
+    })
+}
+
+fn find_acpi_shutdown(rsdp: u64) -> Result<Option<AcpiShutdown>, acpi::Error> {
+    let fadt = acpi::table(acpi::find_table(rsdp, b"FACP")?)?;
+    if fadt.len() < 116 {
+        return Err(acpi::Error::InvalidLength);
+    }
+    // dsdt: 40, x_dsdt: 140
+    let dsdt = if fadt.len() >= 148 && read_u64(fadt, 140) != 0 {
+        read_u64(fadt, 140)
+    } else {
+        u64::from(read_u32(fadt, 40))
+    };
+    Ok(parse_fadt(fadt, acpi::table(dsdt)?))
+}
+
+// Looks up the ACPI shutdown registers, falling back to the legacy ports if
+// they can't be found
+pub fn init(rsdp: u64) {
+    if rsdp == 0 {
+        return;
+    }
+    match find_acpi_shutdown(rsdp) {
+        Ok(Some(s)) => {
+            log!("Using ACPI shutdown: {:?}", s);
+            *ACPI_SHUTDOWN.borrow_mut() = Some(s);
+        }
+        Ok(None) => log!("No usable ACPI shutdown registers"),
+        Err(e) => log!("Error reading ACPI tables: {:?}", e),
+    }
+}
+
+pub fn reset(cold: bool) -> ! {
+    let value = RESET_SYSTEM | if cold { RESET_FULL } else { 0 };
+    unsafe {
+        let mut port = PortWriteOnly::<u8>::new(RESET_CONTROL_PORT);
+        port.write(value);
+        port.write(value | RESET_CPU);
+    }
+    crate::delay::mdelay(100);
+
+    log!("Reset via 0xcf9 failed, trying the keyboard controller");
+    unsafe { PortWriteOnly::<u8>::new(KBC_COMMAND_PORT).write(KBC_PULSE_RESET) };
+    crate::delay::mdelay(100);
+
+    log!("Reset failed");
+    halt()
+}
+
+pub fn shutdown() -> ! {
+    let acpi_shutdown = *ACPI_SHUTDOWN.borrow();
+    match acpi_shutdown {
+        Some(AcpiShutdown::Pm1Control {
+            pm1a,
+            pm1b,
+            sleep_type_a,
+            sleep_type_b,
+        }) => unsafe {
+            let value = |sleep_type: u8| (u16::from(sleep_type) & 0x7) << 10 | 1 << 13;
+            PortWriteOnly::<u16>::new(pm1a).write(value(sleep_type_a));
+            if pm1b != 0 {
+                PortWriteOnly::<u16>::new(pm1b).write(value(sleep_type_b));
+            }
+        },
+        Some(AcpiShutdown::SleepControl { port, sleep_type }) => unsafe {
+            PortWriteOnly::<u8>::new(port).write((sleep_type & 0x7) << 2 | 1 << 5);
+        },
+        None => {}
+    }
+    if acpi_shutdown.is_some() {
+        crate::delay::mdelay(100);
+        log!("ACPI shutdown failed, trying the legacy ports");
+    }
+
+    unsafe {
+        PortWriteOnly::<u16>::new(QEMU_PM1A_CONTROL_PORT).write(QEMU_PM1A_SHUTDOWN);
+        PortWriteOnly::<u8>::new(CLOUD_HYPERVISOR_SHUTDOWN_PORT).write(CLOUD_HYPERVISOR_SHUTDOWN);
+        PortWriteOnly::<u8>::new(QEMU_DEBUG_EXIT_PORT).write(0);
+    }
+    crate::delay::mdelay(100);
+
+    log!("Shutdown failed");
+    halt()
+}
+
+// Has QEMU exit with (code << 1) | 1 as its status if it has an
+// isa-debug-exit device, and does nothing otherwise
+pub fn debug_exit(code: u8) {
+    unsafe { PortWriteOnly::<u8>::new(QEMU_DEBUG_EXIT_PORT).write(code) };
+}
+
+fn halt() -> ! {
+    loop {
+        hlt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_fadt, s5_sleep_types, AcpiShutdown};
+
+    // Name (_S5, Package (0x04) { 0x05, 0x00, 0x00, 0x00 })
+    const DSDT: [u8; 13] = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_s5_sleep_types() {
+        assert_eq!(s5_sleep_types(&DSDT), Some((5, 0)));
+        // Package (0x02) { Zero, Zero } as QEMU uses
+        let dsdt = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x00, 0x00];
+        assert_eq!(s5_sleep_types(&dsdt), Some((0, 0)));
+        // Name (\_S5, Package (0x02) { 0x07, One })
+        let dsdt = [
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x0a, 0x07, 0x01,
+        ];
+        assert_eq!(s5_sleep_types(&dsdt), Some((7, 1)));
+        // Package (0x01) { 0x05 }
+        let dsdt = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x01, 0x0a, 0x05];
+        assert_eq!(s5_sleep_types(&dsdt), Some((5, 5)));
+        // A reference to \_S5 rather than its definition, then the definition
+        let mut dsdt = vec![0x70, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x01, 0x01];
+        dsdt.extend_from_slice(&DSDT);
+        assert_eq!(s5_sleep_types(&dsdt), Some((5, 0)));
+        assert_eq!(s5_sleep_types(&dsdt[..10]), None);
+        assert_eq!(s5_sleep_types(&DSDT[..5]), None);
+        assert_eq!(s5_sleep_types(&DSDT[1..]), None);
+        assert_eq!(s5_sleep_types(b"_S4_"), None);
+    }
+
+    #[test]
+    fn test_parse_fadt() {
+        let mut fadt = vec![0u8; 276];
+        assert_eq!(parse_fadt(&fadt, &DSDT), None);
+
+        fadt[64..68].copy_from_slice(&0x604u32.to_le_bytes());
+        assert_eq!(
+            parse_fadt(&fadt, &DSDT),
+            Some(AcpiShutdown::Pm1Control {
+                pm1a: 0x604,
+                pm1b: 0,
+                sleep_type_a: 5,
+                sleep_type_b: 0
+            })
+        );
+
+        // Hardware-reduced with an I/O port sleep control register
+        fadt[112..116].copy_from_slice(&(1u32 << 20).to_le_bytes());
+        fadt[244] = 1;
+        fadt[248..256].copy_from_slice(&0x3c0u64.to_le_bytes());
+        assert_eq!(
+            parse_fadt(&fadt, &DSDT),
+            Some(AcpiShutdown::SleepControl {
+                port: 0x3c0,
+                sleep_type: 5
+            })
+        );
+        assert_eq!(parse_fadt(&fadt, &[]), None);
+    }
+}
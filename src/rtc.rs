@@ -47,43 +47,141 @@ impl Rtc {
         self.reg_b.unwrap()
     }
 
-    fn read_date(&mut self) -> Result<(u8, u8, u8), ()> {
-        let mut year = self.read(0x09)?;
-        let mut month = self.read(0x08)?;
-        let mut day = self.read(0x07)?;
-
-        if (self.get_reg_b() & 0x04) == 0 {
-            year = bcd2dec(year);
-            month = bcd2dec(month);
-            day = bcd2dec(day);
+    fn write_cmos(&mut self, addr: u8, value: u8) {
+        assert!(addr < 128);
+        unsafe {
+            self.address_port.write(addr);
+            self.data_port.write(value);
         }
+    }
+
+    fn read_date(&mut self) -> Result<(u8, u8, u8), ()> {
+        let year = self.read(0x09)?;
+        let month = self.read(0x08)?;
+        let day = self.read(0x07)?;
 
-        Ok((year, month, day))
+        Ok(decode_date(self.get_reg_b(), year, month, day))
     }
 
     fn read_time(&mut self) -> Result<(u8, u8, u8), ()> {
-        let mut hour = self.read(0x04)?;
-        let mut minute = self.read(0x02)?;
-        let mut second = self.read(0x00)?;
-
-        if (self.get_reg_b() & 0x04) == 0 {
-            hour = bcd2dec(hour);
-            minute = bcd2dec(minute);
-            second = bcd2dec(second);
-        }
+        let hour = self.read(0x04)?;
+        let minute = self.read(0x02)?;
+        let second = self.read(0x00)?;
+
+        Ok(decode_time(self.get_reg_b(), hour, minute, second))
+    }
 
-        if ((self.get_reg_b() & 0x02) == 0) && ((hour & 0x80) != 0) {
-            hour = (hour & 0x7f) + 12 % 24;
+    // Sets the SET bit in register B while updating the registers so the
+    // clock doesn't tick part way through
+    fn update<F>(&mut self, f: F) -> Result<(), ()>
+    where
+        F: FnOnce(&mut Self, u8),
+    {
+        if crate::delay::wait_while(1, || self.is_updating()) {
+            return Err(());
         }
+        let reg_b = self.get_reg_b();
+        self.write_cmos(0x0b, reg_b | 0x80);
+        f(self, reg_b);
+        self.write_cmos(0x0b, reg_b & !0x80);
+        Ok(())
+    }
 
-        Ok((hour, minute, second))
+    fn write_date(&mut self, year: u8, month: u8, day: u8) -> Result<(), ()> {
+        self.update(|rtc, reg_b| {
+            let (year, month, day) = encode_date(reg_b, year, month, day);
+            rtc.write_cmos(0x09, year);
+            rtc.write_cmos(0x08, month);
+            rtc.write_cmos(0x07, day);
+        })
     }
+
+    fn write_time(&mut self, hour: u8, minute: u8, second: u8) -> Result<(), ()> {
+        self.update(|rtc, reg_b| {
+            let (hour, minute, second) = encode_time(reg_b, hour, minute, second);
+            rtc.write_cmos(0x04, hour);
+            rtc.write_cmos(0x02, minute);
+            rtc.write_cmos(0x00, second);
+        })
+    }
+}
+
+// Register B: bit 1 selects 24 hour mode, bit 2 binary rather than BCD
+fn is_binary(reg_b: u8) -> bool {
+    reg_b & 0x04 != 0
+}
+
+fn is_24_hour(reg_b: u8) -> bool {
+    reg_b & 0x02 != 0
 }
 
 fn bcd2dec(b: u8) -> u8 {
     ((b >> 4) & 0x0f) * 10 + (b & 0x0f)
 }
 
+fn dec2bcd(d: u8) -> u8 {
+    ((d / 10) << 4) | (d % 10)
+}
+
+fn decode(reg_b: u8, value: u8) -> u8 {
+    if is_binary(reg_b) {
+        value
+    } else {
+        bcd2dec(value)
+    }
+}
+
+fn encode(reg_b: u8, value: u8) -> u8 {
+    if is_binary(reg_b) {
+        value
+    } else {
+        dec2bcd(value)
+    }
+}
+
+fn decode_date(reg_b: u8, year: u8, month: u8, day: u8) -> (u8, u8, u8) {
+    (
+        decode(reg_b, year),
+        decode(reg_b, month),
+        decode(reg_b, day),
+    )
+}
+
+fn encode_date(reg_b: u8, year: u8, month: u8, day: u8) -> (u8, u8, u8) {
+    (
+        encode(reg_b, year),
+        encode(reg_b, month),
+        encode(reg_b, day),
+    )
+}
+
+// In 12 hour mode bit 7 of the hour marks PM and midnight is 12 AM
+fn decode_time(reg_b: u8, hour: u8, minute: u8, second: u8) -> (u8, u8, u8) {
+    let hour = if is_24_hour(reg_b) {
+        decode(reg_b, hour)
+    } else {
+        let pm = hour & 0x80 != 0;
+        decode(reg_b, hour & 0x7f) % 12 + if pm { 12 } else { 0 }
+    };
+
+    (hour, decode(reg_b, minute), decode(reg_b, second))
+}
+
+fn encode_time(reg_b: u8, hour: u8, minute: u8, second: u8) -> (u8, u8, u8) {
+    let hour = if is_24_hour(reg_b) {
+        encode(reg_b, hour)
+    } else {
+        let pm = if hour >= 12 { 0x80 } else { 0 };
+        let hour = match hour % 12 {
+            0 => 12,
+            h => h,
+        };
+        encode(reg_b, hour) | pm
+    };
+
+    (hour, encode(reg_b, minute), encode(reg_b, second))
+}
+
 pub fn read_date() -> Result<(u8, u8, u8), ()> {
     RTC.borrow_mut().read_date()
 }
@@ -91,3 +189,54 @@ pub fn read_date() -> Result<(u8, u8, u8), ()> {
 pub fn read_time() -> Result<(u8, u8, u8), ()> {
     RTC.borrow_mut().read_time()
 }
+
+pub fn write_date(year: u8, month: u8, day: u8) -> Result<(), ()> {
+    RTC.borrow_mut().write_date(year, month, day)
+}
+
+pub fn write_time(hour: u8, minute: u8, second: u8) -> Result<(), ()> {
+    RTC.borrow_mut().write_time(hour, minute, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_date, decode_time, encode_date, encode_time};
+
+    const BCD_24H: u8 = 0x02;
+    const BCD_12H: u8 = 0x00;
+    const BINARY_24H: u8 = 0x06;
+    const BINARY_12H: u8 = 0x04;
+
+    #[test]
+    fn test_decode_date() {
+        assert_eq!(decode_date(BCD_24H, 0x21, 0x12, 0x31), (21, 12, 31));
+        assert_eq!(decode_date(BCD_12H, 0x99, 0x01, 0x09), (99, 1, 9));
+        assert_eq!(decode_date(BINARY_24H, 21, 12, 31), (21, 12, 31));
+    }
+
+    #[test]
+    fn test_decode_time() {
+        assert_eq!(decode_time(BCD_24H, 0x23, 0x59, 0x58), (23, 59, 58));
+        assert_eq!(decode_time(BINARY_24H, 23, 59, 58), (23, 59, 58));
+        // 12 AM, 11 AM, 12 PM and 11 PM
+        assert_eq!(decode_time(BCD_12H, 0x12, 0, 0).0, 0);
+        assert_eq!(decode_time(BCD_12H, 0x11, 0, 0).0, 11);
+        assert_eq!(decode_time(BCD_12H, 0x92, 0, 0).0, 12);
+        assert_eq!(decode_time(BCD_12H, 0x91, 0, 0).0, 23);
+        assert_eq!(decode_time(BINARY_12H, 0x8b, 0, 0).0, 23);
+    }
+
+    #[test]
+    fn test_encode() {
+        for reg_b in &[BCD_24H, BCD_12H, BINARY_24H, BINARY_12H] {
+            for hour in 0..24 {
+                let (h, m, s) = encode_time(*reg_b, hour, 34, 56);
+                assert_eq!(decode_time(*reg_b, h, m, s), (hour, 34, 56));
+            }
+            let (y, m, d) = encode_date(*reg_b, 21, 10, 14);
+            assert_eq!(decode_date(*reg_b, y, m, d), (21, 10, 14));
+        }
+        assert_eq!(encode_date(BCD_24H, 21, 10, 14), (0x21, 0x10, 0x14));
+        assert_eq!(encode_time(BCD_12H, 0, 0, 0).0, 0x12);
+    }
+}
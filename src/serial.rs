@@ -16,20 +16,142 @@
 // from Philipp Oppermann
 
 use core::fmt;
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::{AtomicU16, Ordering};
 
 use atomic_refcell::AtomicRefCell;
+#[cfg(target_arch = "x86_64")]
 use uart_16550::SerialPort;
+#[cfg(target_arch = "x86_64")]
+use x86_64::instructions::port::{Port, PortWriteOnly};
 
-// We use COM1 as it is the standard first serial port.
-pub static PORT: AtomicRefCell<SerialPort> = AtomicRefCell::new(unsafe { SerialPort::new(0x3f8) });
+#[cfg(target_arch = "aarch64")]
+use crate::pl011::Pl011;
+
+/// A serial port, a byte at a time
+pub trait Uart {
+    /// Waits for room to send the byte
+    fn write_byte(&self, b: u8);
+    /// Returns a received byte, if there is one
+    fn read_byte(&self) -> Option<u8>;
+}
+
+// Base ports of COM1 to COM4, which Linux calls ttyS0 to ttyS3
+#[cfg(target_arch = "x86_64")]
+const COM_PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+// We use COM1 as it is the standard first serial port, at the fastest rate
+// the standard clock gives. Both can be changed with a console=ttySn,baud
+// entry on the command line.
+#[cfg(target_arch = "x86_64")]
+const DEFAULT_PORT: u16 = COM_PORTS[0];
+const DEFAULT_BAUD: u32 = 115_200;
+
+// The 1.8432 MHz UART clock divided by 16, the divisor latch divides it
+// further
+#[cfg(target_arch = "x86_64")]
+const UART_BAUD_BASE: u32 = 115_200;
+
+// Line control register: 8 data bits, no parity and 1 stop bit, with the
+// top bit switching the first two registers over to the divisor latch
+#[cfg(target_arch = "x86_64")]
+const LINE_CONTROL_8N1: u8 = 0x03;
+#[cfg(target_arch = "x86_64")]
+const LINE_CONTROL_DLAB: u8 = 0x80;
+
+// Line status register: a byte has been received, and there is room for
+// another to send
+#[cfg(target_arch = "x86_64")]
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+#[cfg(target_arch = "x86_64")]
+const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+
+// The PL011 of QEMU's virt machine, with its 24 MHz reference clock
+#[cfg(target_arch = "aarch64")]
+const PL011_BASE: u64 = 0x0900_0000;
+#[cfg(target_arch = "aarch64")]
+const PL011_CLOCK: u32 = 24_000_000;
+
+/// 16550 compatible UART at an I/O port base
+#[cfg(target_arch = "x86_64")]
+pub struct Uart16550 {
+    base: u16,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Uart16550 {
+    pub const fn new(base: u16) -> Uart16550 {
+        Uart16550 { base }
+    }
+
+    // lsr: 0x5
+    fn line_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.base + 5).read() }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Uart for Uart16550 {
+    // thr: 0x0
+    fn write_byte(&self, b: u8) {
+        while self.line_status() & LINE_STATUS_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { PortWriteOnly::<u8>::new(self.base).write(b) }
+    }
+
+    // rbr: 0x0
+    fn read_byte(&self) -> Option<u8> {
+        if self.line_status() & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(unsafe { Port::<u8>::new(self.base).read() })
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+static PORT: AtomicRefCell<Uart16550> = AtomicRefCell::new(Uart16550::new(DEFAULT_PORT));
+#[cfg(target_arch = "aarch64")]
+static PORT: AtomicRefCell<Pl011> = AtomicRefCell::new(Pl011::new(PL011_BASE));
+
+// The base of the port in PORT, for writing to it without a borrow
+#[cfg(target_arch = "x86_64")]
+static BASE: AtomicU16 = AtomicU16::new(DEFAULT_PORT);
 
 pub struct Serial;
 impl fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        PORT.borrow_mut().write_str(s)
+        let port = PORT.borrow();
+        for b in s.bytes() {
+            port.write_byte(b);
+        }
+        Ok(())
     }
 }
 
+/// Writes to the port without borrowing it, for the panic handler and the
+/// timer interrupt, as either can come with it borrowed
+#[cfg(all(not(test), feature = "log-serial"))]
+pub struct UnlockedSerial;
+#[cfg(all(not(test), feature = "log-serial"))]
+impl fmt::Write for UnlockedSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        #[cfg(target_arch = "x86_64")]
+        let port = Uart16550::new(BASE.load(Ordering::Relaxed));
+        #[cfg(target_arch = "aarch64")]
+        let port = Pl011::new(PL011_BASE);
+        for b in s.bytes() {
+            port.write_byte(b);
+        }
+        Ok(())
+    }
+}
+
+/// The next byte received on the port, if one has come in
+pub fn read_byte() -> Option<u8> {
+    PORT.borrow().read_byte()
+}
+
 #[macro_export]
 macro_rules! log {
     ($($arg:tt)*) => {{
@@ -40,3 +162,149 @@ macro_rules! log {
         println!($($arg)*);
     }};
 }
+
+// Like log!, but without borrowing the port, for what can run in the middle
+// of a log!
+#[macro_export]
+macro_rules! log_unlocked {
+    ($($arg:tt)*) => {{
+        use core::fmt::Write;
+        #[cfg(all(feature = "log-serial", not(test)))]
+        writeln!(crate::serial::UnlockedSerial, $($arg)*).ok();
+        #[cfg(all(feature = "log-serial", test))]
+        println!($($arg)*);
+    }};
+}
+
+// The divisor latch value closest to the baud rate, if there is one
+#[cfg(target_arch = "x86_64")]
+fn divisor(baud: u32) -> Option<u16> {
+    if baud == 0 || baud > UART_BAUD_BASE {
+        return None;
+    }
+    let divisor = (UART_BAUD_BASE + baud / 2) / baud;
+    if divisor > u32::from(u16::MAX) {
+        return None;
+    }
+    Some(divisor as u16)
+}
+
+// Port and baud rate from the last console=ttySn[,baud] on the command line,
+// as for Linux
+#[cfg(target_arch = "x86_64")]
+fn parse_console(cmdline: &[u8]) -> Option<(u16, Option<u32>)> {
+    let mut console = None;
+    for option in cmdline.split(|c| c.is_ascii_whitespace()) {
+        let options = match option.strip_prefix(b"console=ttyS") {
+            Some(options) => options,
+            None => continue,
+        };
+        let index = match options.first() {
+            Some(c @ b'0'..=b'3') => usize::from(c - b'0'),
+            _ => continue,
+        };
+        // The baud rate can be followed by parity, bits and flow control,
+        // e.g. 9600n8
+        let baud = match options.get(1) {
+            Some(b',') => options[2..]
+                .iter()
+                .take_while(|c| c.is_ascii_digit())
+                .try_fold(0u32, |baud, c| {
+                    baud.checked_mul(10)?.checked_add(u32::from(c - b'0'))
+                }),
+            Some(_) => continue,
+            None => None,
+        };
+        console = Some((COM_PORTS[index], baud));
+    }
+    console
+}
+
+#[cfg(target_arch = "x86_64")]
+fn init_port(base: u16, baud: u32) {
+    let divisor = divisor(baud).unwrap();
+    unsafe { SerialPort::new(base) }.init();
+
+    // The port starts off at a fixed rate, so the divisor latch is
+    // reprogrammed: dll: 0x0, dlm: 0x1 and lcr: 0x3
+    unsafe {
+        let mut line_control = PortWriteOnly::<u8>::new(base + 3);
+        line_control.write(LINE_CONTROL_8N1 | LINE_CONTROL_DLAB);
+        PortWriteOnly::<u8>::new(base).write(divisor as u8);
+        PortWriteOnly::<u8>::new(base + 1).write((divisor >> 8) as u8);
+        line_control.write(LINE_CONTROL_8N1);
+    }
+    *PORT.borrow_mut() = Uart16550::new(base);
+    BASE.store(base, Ordering::Relaxed);
+}
+
+/// Sets up the default port, before anything is known about the machine
+#[cfg(target_arch = "x86_64")]
+pub fn init() {
+    init_port(DEFAULT_PORT, DEFAULT_BAUD);
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn init() {
+    PORT.borrow().init(PL011_CLOCK, DEFAULT_BAUD);
+}
+
+/// Switches to the port and baud rate given on the command line, if any
+#[cfg(target_arch = "x86_64")]
+pub fn configure(cmdline: &[u8]) {
+    let (port, baud) = match parse_console(cmdline) {
+        Some(console) => console,
+        None => return,
+    };
+    let baud = match baud {
+        Some(baud) if divisor(baud).is_some() => baud,
+        Some(baud) => {
+            log!("Unsupported serial baud rate {}", baud);
+            return;
+        }
+        None => DEFAULT_BAUD,
+    };
+    if port != DEFAULT_PORT || baud != DEFAULT_BAUD {
+        init_port(port, baud);
+    }
+}
+
+// There is only the one PL011
+#[cfg(target_arch = "aarch64")]
+pub fn configure(_cmdline: &[u8]) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{divisor, parse_console};
+
+    #[test]
+    fn test_divisor() {
+        assert_eq!(divisor(115_200), Some(1));
+        assert_eq!(divisor(57_600), Some(2));
+        assert_eq!(divisor(38_400), Some(3));
+        assert_eq!(divisor(19_200), Some(6));
+        assert_eq!(divisor(9_600), Some(12));
+        // Rounded to the closest rate that can be generated
+        assert_eq!(divisor(56_000), Some(2));
+        assert_eq!(divisor(230_400), None);
+        assert_eq!(divisor(1), None);
+        assert_eq!(divisor(0), None);
+    }
+
+    #[test]
+    fn test_parse_console() {
+        assert_eq!(parse_console(b""), None);
+        assert_eq!(parse_console(b"console=tty0 root=/dev/vda1"), None);
+        assert_eq!(parse_console(b"console=ttyS0"), Some((0x3f8, None)));
+        assert_eq!(
+            parse_console(b"root=/dev/vda1 console=ttyS1,9600n8 quiet"),
+            Some((0x2f8, Some(9600)))
+        );
+        // The last one wins, and ones we don't know about are skipped
+        assert_eq!(
+            parse_console(b"console=ttyS3,57600 console=ttyS2,115200 console=ttyS4"),
+            Some((0x3e8, Some(115_200)))
+        );
+        assert_eq!(parse_console(b"console=ttyS10"), None);
+    }
+}
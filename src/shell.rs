@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// A shell over serial for working out why nothing booted, started in place
+// of halting by builds with the diag-shell feature. It lists the PCI devices,
+// disks and partitions, and dumps a sector of a disk or the start of a file
+// on a FAT filesystem in hex. It only ever reads from the disks.
+
+use core::fmt::{self, Write};
+
+use crate::{
+    block::SectorRead,
+    fat::{self, Read},
+    part,
+};
+
+const PROMPT: &str = "rhfw> ";
+const MAX_LINE: usize = 128;
+// How much of a file is dumped
+const MAX_FILE_DUMP: usize = 4096;
+
+const HELP: &str = "\
+devices                        list the PCI devices and disks
+parts <disk>                   list the partitions on a disk
+sector <disk> <lba>            dump a sector
+file <disk> <partition> <path> dump the start of a file, partition 0 being
+                               the whole disk
+reset                          reset the machine";
+
+/// What the shell looks at, which tests give it in memory
+pub trait Machine {
+    /// Writes a line for each PCI device and each disk, numbered as the
+    /// disk commands take them
+    fn devices(&self, out: &mut dyn Write) -> fmt::Result;
+
+    /// Calls f with the disk and its size in sectors, returning false if
+    /// there is no such disk or it can't be set up
+    fn with_disk(&self, index: usize, f: &mut dyn FnMut(&dyn SectorRead, u64)) -> bool;
+}
+
+// A decimal number, or hex one starting with 0x
+fn parse_number(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+// 16 bytes a line, with their offset and as ASCII
+fn hex_dump(data: &[u8], out: &mut dyn Write) -> fmt::Result {
+    for (i, line) in data.chunks(16).enumerate() {
+        write!(out, "{:08x} ", i * 16)?;
+        for b in line {
+            write!(out, " {:02x}", b)?;
+        }
+        for _ in line.len()..16 {
+            write!(out, "   ")?;
+        }
+        write!(out, "  ")?;
+        for b in line {
+            let c = if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            };
+            write!(out, "{}", c)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn empty_drives() -> [part::HardDrive; 16] {
+    [part::HardDrive {
+        number: 0,
+        first_lba: 0,
+        last_lba: 0,
+        signature: part::PartitionSignature::Mbr(0),
+    }; 16]
+}
+
+fn list_partitions(device: &dyn SectorRead, sectors: u64, out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "{} sectors", sectors)?;
+    let mut drives = empty_drives();
+    match part::get_hard_drives(device, &mut drives) {
+        Ok(count) => {
+            for drive in &drives[..count as usize] {
+                writeln!(
+                    out,
+                    "Partition {}: LBA {}-{}",
+                    drive.number, drive.first_lba, drive.last_lba
+                )?;
+            }
+            Ok(())
+        }
+        Err(err) => writeln!(out, "Error reading partitions: {:?}", err),
+    }
+}
+
+fn dump_sector(device: &dyn SectorRead, lba: u64, out: &mut dyn Write) -> fmt::Result {
+    let mut data = [0; 512];
+    match device.read(lba, &mut data) {
+        Ok(()) => hex_dump(&data, out),
+        Err(err) => writeln!(out, "Error reading sector: {:?}", err),
+    }
+}
+
+// Only what fits in MAX_FILE_DUMP
+fn read_start(file: &mut dyn Read, data: &mut [u8; MAX_FILE_DUMP]) -> Result<usize, fat::Error> {
+    let length = core::cmp::min(file.get_size() as usize, MAX_FILE_DUMP);
+    let mut done = 0;
+    let mut sector = [0; 512];
+    while done < length {
+        let bytes = file.read(&mut sector)? as usize;
+        let bytes = core::cmp::min(bytes, length - done);
+        data[done..done + bytes].copy_from_slice(&sector[..bytes]);
+        done += bytes;
+    }
+    Ok(length)
+}
+
+fn dump_file(
+    device: &dyn SectorRead,
+    sectors: u64,
+    partition: u64,
+    path: &str,
+    out: &mut dyn Write,
+) -> fmt::Result {
+    let (start, last) = if partition == 0 {
+        (0, sectors.saturating_sub(1))
+    } else {
+        let mut drives = empty_drives();
+        let count = match part::get_hard_drives(device, &mut drives) {
+            Ok(count) => count,
+            Err(err) => return writeln!(out, "Error reading partitions: {:?}", err),
+        };
+        match drives[..count as usize]
+            .iter()
+            .find(|d| u64::from(d.number) == partition)
+        {
+            Some(drive) => (drive.first_lba, drive.last_lba),
+            None => return writeln!(out, "No partition {}", partition),
+        }
+    };
+
+    let mut fs = fat::Filesystem::new(device, start, last);
+    if let Err(err) = fs.init() {
+        return writeln!(out, "Error reading filesystem: {:?}", err);
+    }
+    let mut file = match fs.open(path) {
+        Ok(fat::Node::File(file)) => file,
+        Ok(fat::Node::Directory(_)) => return writeln!(out, "{} is a directory", path),
+        Err(err) => return writeln!(out, "Error opening {}: {:?}", path, err),
+    };
+    let mut data = [0; MAX_FILE_DUMP];
+    match read_start(&mut file, &mut data) {
+        Ok(length) => {
+            writeln!(out, "{} bytes", file.get_size())?;
+            hex_dump(&data[..length], out)
+        }
+        Err(err) => writeln!(out, "Error reading {}: {:?}", path, err),
+    }
+}
+
+// Runs the disk command on the disk the first argument names
+fn on_disk<F>(
+    machine: &dyn Machine,
+    arg: Option<&str>,
+    out: &mut dyn Write,
+    mut f: F,
+) -> fmt::Result
+where
+    F: FnMut(&dyn SectorRead, u64, &mut dyn Write) -> fmt::Result,
+{
+    let index = match arg.and_then(parse_number) {
+        Some(index) => index as usize,
+        None => return writeln!(out, "No disk given"),
+    };
+    let mut result = Ok(());
+    let mut run = |device: &dyn SectorRead, sectors| result = f(device, sectors, out);
+    if !machine.with_disk(index, &mut run) {
+        return writeln!(out, "No disk {}", index);
+    }
+    result
+}
+
+enum Outcome {
+    Continue,
+    Reset,
+}
+
+fn run_command(
+    machine: &dyn Machine,
+    line: &str,
+    out: &mut dyn Write,
+) -> Result<Outcome, fmt::Error> {
+    let mut args = line.split_whitespace();
+    match args.next() {
+        None => {}
+        Some("help") => writeln!(out, "{}", HELP)?,
+        Some("devices") => machine.devices(out)?,
+        Some("parts") => on_disk(machine, args.next(), out, list_partitions)?,
+        Some("sector") => {
+            let disk = args.next();
+            match args.next().and_then(parse_number) {
+                Some(lba) => on_disk(machine, disk, out, |device, _, out| {
+                    dump_sector(device, lba, out)
+                })?,
+                None => writeln!(out, "Usage: sector <disk> <lba>")?,
+            }
+        }
+        Some("file") => {
+            let disk = args.next();
+            match (args.next().and_then(parse_number), args.next()) {
+                (Some(partition), Some(path)) => {
+                    on_disk(machine, disk, out, |device, sectors, out| {
+                        dump_file(device, sectors, partition, path, out)
+                    })?
+                }
+                _ => writeln!(out, "Usage: file <disk> <partition> <path>")?,
+            }
+        }
+        Some("reset") => return Ok(Outcome::Reset),
+        Some(command) => writeln!(out, "Unknown command {}, try help", command)?,
+    }
+    Ok(Outcome::Continue)
+}
+
+// Reads a line, echoing it back, or returns None at the end of the input.
+// last is the byte read before, kept from one line to the next.
+fn read_line<'a>(
+    input: &mut dyn FnMut() -> Option<u8>,
+    out: &mut dyn Write,
+    line: &'a mut [u8; MAX_LINE],
+    last: &mut u8,
+) -> Option<&'a str> {
+    let mut length = 0;
+    loop {
+        let b = input()?;
+        let previous = core::mem::replace(last, b);
+        match b {
+            // A '\n' after a '\r' is the same line ending
+            b'\n' if previous == b'\r' => {}
+            b'\r' | b'\n' => {
+                writeln!(out).ok();
+                return core::str::from_utf8(&line[..length]).ok();
+            }
+            0x08 | 0x7f if length > 0 => {
+                length -= 1;
+                write!(out, "\x08 \x08").ok();
+            }
+            b' '..=b'~' if length < MAX_LINE => {
+                line[length] = b;
+                length += 1;
+                write!(out, "{}", b as char).ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+// Runs commands until the input runs out or the machine is to be reset,
+// returning true in that case
+fn session(
+    machine: &dyn Machine,
+    input: &mut dyn FnMut() -> Option<u8>,
+    out: &mut dyn Write,
+) -> bool {
+    writeln!(out, "Diagnostic shell, type help for the commands").ok();
+    let mut last = 0;
+    loop {
+        write!(out, "{}", PROMPT).ok();
+        let mut line = [0; MAX_LINE];
+        let line = match read_line(input, out, &mut line, &mut last) {
+            Some(line) => line,
+            None => return false,
+        };
+        match run_command(machine, line, out) {
+            Ok(Outcome::Reset) => return true,
+            Ok(Outcome::Continue) | Err(_) => {}
+        }
+    }
+}
+
+/// Runs the shell on the serial port until it is asked to reset
+pub fn run(machine: &dyn Machine) -> ! {
+    log!("Nothing could be booted, starting the diagnostic shell");
+    let mut input = || loop {
+        if let Some(b) = crate::serial::read_byte() {
+            return Some(b);
+        }
+        core::hint::spin_loop();
+    };
+    session(machine, &mut input, &mut crate::serial::Serial);
+    crate::reset::reset(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::fmt::{self, Write};
+
+    use super::{hex_dump, parse_number, session, Machine};
+    use crate::{
+        block::SectorRead,
+        fat::tests::{Dir, ImageBuilder},
+        part::tests::{gpt_disk, MemDisk},
+    };
+
+    struct TestMachine {
+        disks: Vec<MemDisk>,
+    }
+
+    impl Machine for TestMachine {
+        fn devices(&self, out: &mut dyn Write) -> fmt::Result {
+            for i in 0..self.disks.len() {
+                writeln!(out, "Disk {}: in memory", i)?;
+            }
+            Ok(())
+        }
+
+        fn with_disk(&self, index: usize, f: &mut dyn FnMut(&dyn SectorRead, u64)) -> bool {
+            match self.disks.get(index) {
+                Some(disk) => {
+                    f(disk, disk.len());
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    // What the session writes given the input
+    fn run(machine: &TestMachine, input: &[u8]) -> String {
+        let mut input = input.iter().copied();
+        let mut out = String::new();
+        assert!(!session(machine, &mut || input.next(), &mut out));
+        out
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("12"), Some(12));
+        assert_eq!(parse_number("0x1f"), Some(31));
+        assert_eq!(parse_number("x"), None);
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let mut out = String::new();
+        hex_dump(b"EFI PART\0\0\x01\0\\\0\0\0ab", &mut out).unwrap();
+        assert_eq!(
+            out,
+            "00000000  45 46 49 20 50 41 52 54 00 00 01 00 5c 00 00 00  EFI PART....\\...\n\
+             00000010  61 62                                            ab\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_gpt_header() {
+        let machine = TestMachine {
+            disks: vec![MemDisk::new(gpt_disk(&[([0x11; 16], 64, &[])]))],
+        };
+        // Typed a character at a time, with a typo rubbed out
+        let out = run(&machine, b"devices\r\nparts 0\rsectr\x7f\x7ftor 0 1\r");
+        assert!(out.contains("rhfw> devices\nDisk 0: in memory\nrhfw> parts 0\n"));
+        assert!(out.contains("Partition 1: LBA 64-71\n"));
+        assert!(
+            out.contains("rhfw> sectr\x08 \x08\x08 \x08tor 0 1\n00000000  45 46 49 20 50 41 52 54")
+        );
+        assert!(out.contains("  EFI PART"));
+
+        let out = run(&machine, b"sector 1 1\rsector 0\rformat\r");
+        assert!(out.contains("No disk 1\n"));
+        assert!(out.contains("Usage: sector <disk> <lba>\n"));
+        assert!(out.contains("Unknown command format, try help\n"));
+    }
+
+    #[test]
+    fn test_dump_file() {
+        let mut builder = ImageBuilder::new(crate::fat::FatType::FAT16);
+        builder.add_file(Dir::Root, b"HELLO   TXT", b"Hello, world");
+        let machine = TestMachine {
+            disks: vec![builder.disk()],
+        };
+        let out = run(
+            &machine,
+            b"file 0 0 \\HELLO.TXT\rfile 0 0 \\MISSING\rfile 0 1 \\HELLO.TXT\r",
+        );
+        assert!(out.contains("12 bytes\n00000000  48 65 6c 6c 6f"));
+        assert!(out.contains("  Hello, world\n"));
+        assert!(out.contains("Error opening \\MISSING: NotFound\n"));
+        assert!(out.contains("No partition 1\n"));
+
+        let mut input = b"reset\r".iter().copied();
+        assert!(session(&machine, &mut || input.next(), &mut String::new()));
+    }
+}
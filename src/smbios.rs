@@ -0,0 +1,340 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// The SMBIOS tables a VMM provides, found through the entry point it puts in
+// the BIOS area like a legacy BIOS would
+
+// Where the entry point can be, on a 16 byte boundary
+const SEARCH_START: u64 = 0xf_0000;
+const SEARCH_LENGTH: usize = 0x1_0000;
+
+const ENTRY_POINT_SIZE: usize = 31;
+const ENTRY_POINT_3_SIZE: usize = 24;
+
+// Structure types
+const SYSTEM_INFORMATION: u8 = 1;
+const END_OF_TABLE: u8 = 127;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    NotFound,
+    InvalidChecksum,
+    InvalidLength,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntryPoint {
+    // Where it is, for the EFI configuration table
+    pub address: u64,
+    // Whether it's the 64-bit "_SM3_" entry point
+    pub is_64bit: bool,
+    pub version: (u8, u8),
+    table_address: u64,
+    // The most the structures can take up
+    table_length: usize,
+}
+
+impl EntryPoint {
+    pub fn table(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.table_address as *const u8, self.table_length) }
+    }
+}
+
+// All the bytes of a valid entry point add up to zero
+fn checksum(data: &[u8]) -> Result<(), Error> {
+    if data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0 {
+        Ok(())
+    } else {
+        Err(Error::InvalidChecksum)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// Parses the entry point at the start of data, which was found at address
+fn parse_entry_point(data: &[u8], address: u64) -> Result<EntryPoint, Error> {
+    if data.starts_with(b"_SM3_") {
+        let length = usize::from(*data.get(6).ok_or(Error::InvalidLength)?);
+        if length < ENTRY_POINT_3_SIZE || length > data.len() {
+            return Err(Error::InvalidLength);
+        }
+        checksum(&data[..length])?;
+        Ok(EntryPoint {
+            address,
+            is_64bit: true,
+            version: (data[7], data[8]),
+            table_address: read_u64(data, 16),
+            table_length: read_u32(data, 12) as usize,
+        })
+    } else if data.starts_with(b"_SM_") {
+        let length = usize::from(*data.get(5).ok_or(Error::InvalidLength)?);
+        if length < ENTRY_POINT_SIZE || length > data.len() {
+            return Err(Error::InvalidLength);
+        }
+        checksum(&data[..length])?;
+        // The intermediate "_DMI_" entry point has its own checksum
+        if &data[16..21] != b"_DMI_" {
+            return Err(Error::NotFound);
+        }
+        checksum(&data[16..31])?;
+        Ok(EntryPoint {
+            address,
+            is_64bit: false,
+            version: (data[6], data[7]),
+            table_address: u64::from(read_u32(data, 24)),
+            table_length: usize::from(read_u16(data, 22)),
+        })
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+// Looks through data, which starts at address, preferring the 64-bit entry
+// point if there are both
+fn find_entry_point(data: &[u8], address: u64) -> Result<EntryPoint, Error> {
+    let mut found = Err(Error::NotFound);
+    for offset in (0..data.len()).step_by(16) {
+        match parse_entry_point(&data[offset..], address + offset as u64) {
+            Ok(entry_point) if entry_point.is_64bit => return Ok(entry_point),
+            Ok(entry_point) => found = Ok(entry_point),
+            Err(Error::NotFound) => {}
+            Err(e) => log!(
+                "Invalid SMBIOS entry point at {:#x}: {:?}",
+                address + offset as u64,
+                e
+            ),
+        }
+    }
+    found
+}
+
+pub fn entry_point() -> Result<EntryPoint, Error> {
+    let data = unsafe { core::slice::from_raw_parts(SEARCH_START as *const u8, SEARCH_LENGTH) };
+    find_entry_point(data, SEARCH_START)
+}
+
+// A structure's formatted area, which starts with its type, and the strings
+// after it
+#[derive(Debug, PartialEq)]
+struct Structure<'a> {
+    data: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    fn kind(&self) -> u8 {
+        self.data[0]
+    }
+
+    // Strings are numbered from 1, with 0 meaning there isn't one
+    fn string(&self, index: u8) -> Option<&'a [u8]> {
+        if index == 0 {
+            return None;
+        }
+        self.strings
+            .split(|c| *c == 0)
+            .take_while(|s| !s.is_empty())
+            .nth(usize::from(index) - 1)
+    }
+
+    // The string whose index is at the offset in the formatted area
+    fn string_at(&self, offset: usize) -> Option<&'a [u8]> {
+        self.string(*self.data.get(offset)?)
+    }
+}
+
+// The structures in the table, stopping at the end of table structure or at
+// any that doesn't fit
+fn structures(table: &[u8]) -> impl Iterator<Item = Structure> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        let header = table.get(offset..offset + 4)?;
+        let length = usize::from(header[1]);
+        if header[0] == END_OF_TABLE || length < 4 || offset + length > table.len() {
+            return None;
+        }
+        let data = &table[offset..offset + length];
+        // The strings end with two NULs, even if there aren't any
+        let rest = &table[offset + length..];
+        let end = rest.windows(2).position(|w| w == [0, 0])?;
+        offset += length + end + 2;
+        Some(Structure {
+            data,
+            strings: &rest[..end + 1],
+        })
+    })
+}
+
+// The strings of the System Information (type 1) structure
+#[derive(Debug, Default, PartialEq)]
+pub struct SystemInfo<'a> {
+    pub manufacturer: Option<&'a [u8]>,
+    pub product_name: Option<&'a [u8]>,
+    pub version: Option<&'a [u8]>,
+}
+
+pub fn parse_system_info(table: &[u8]) -> Option<SystemInfo> {
+    let system = structures(table).find(|s| s.kind() == SYSTEM_INFORMATION)?;
+    Some(SystemInfo {
+        manufacturer: system.string_at(4),
+        product_name: system.string_at(5),
+        version: system.string_at(6),
+    })
+}
+
+// For logging
+#[cfg_attr(not(feature = "log-serial"), allow(dead_code))]
+fn name(s: Option<&[u8]>) -> &str {
+    s.and_then(|s| core::str::from_utf8(s).ok()).unwrap_or("")
+}
+
+pub fn init() {
+    let entry_point = match entry_point() {
+        Ok(entry_point) => entry_point,
+        Err(Error::NotFound) => return,
+        Err(e) => {
+            log!("Error finding SMBIOS tables: {:?}", e);
+            return;
+        }
+    };
+    log!(
+        "SMBIOS {}.{} entry point at {:#x}",
+        entry_point.version.0,
+        entry_point.version.1,
+        entry_point.address
+    );
+    if let Some(system) = parse_system_info(entry_point.table()) {
+        log!(
+            "System: {} {} {}",
+            name(system.manufacturer),
+            name(system.product_name),
+            name(system.version)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_entry_point, parse_system_info, structures, Error, SystemInfo};
+
+    fn fix_checksum(data: &mut [u8], offset: usize) {
+        data[offset] = 0;
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        data[offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn entry_point_3(table: u64, length: u32) -> Vec<u8> {
+        let mut data = vec![0; 24];
+        data[0..5].copy_from_slice(b"_SM3_");
+        data[6] = 24;
+        data[7] = 3;
+        data[8] = 2;
+        data[10] = 1;
+        data[12..16].copy_from_slice(&length.to_le_bytes());
+        data[16..24].copy_from_slice(&table.to_le_bytes());
+        fix_checksum(&mut data, 5);
+        data
+    }
+
+    fn entry_point(table: u32, length: u16) -> Vec<u8> {
+        let mut data = vec![0; 31];
+        data[0..4].copy_from_slice(b"_SM_");
+        data[5] = 31;
+        data[6] = 2;
+        data[7] = 8;
+        data[16..21].copy_from_slice(b"_DMI_");
+        data[22..24].copy_from_slice(&length.to_le_bytes());
+        data[24..28].copy_from_slice(&table.to_le_bytes());
+        fix_checksum(&mut data[16..], 5);
+        fix_checksum(&mut data, 4);
+        data
+    }
+
+    #[test]
+    fn test_find_entry_point() {
+        let mut area = vec![0; 0x100];
+        assert_eq!(find_entry_point(&area, 0xf_0000), Err(Error::NotFound));
+
+        area[0x40..0x40 + 31].copy_from_slice(&entry_point(0xf_1000, 0x200));
+        let found = find_entry_point(&area, 0xf_0000).unwrap();
+        assert_eq!(found.address, 0xf_0040);
+        assert!(!found.is_64bit);
+        assert_eq!(found.version, (2, 8));
+        assert_eq!((found.table_address, found.table_length), (0xf_1000, 0x200));
+
+        // The 64-bit one is used if there is one
+        area[0x80..0x80 + 24].copy_from_slice(&entry_point_3(0x1_0000_0000, 0x400));
+        let found = find_entry_point(&area, 0xf_0000).unwrap();
+        assert_eq!(found.address, 0xf_0080);
+        assert!(found.is_64bit);
+        assert_eq!(found.version, (3, 2));
+        assert_eq!(
+            (found.table_address, found.table_length),
+            (0x1_0000_0000, 0x400)
+        );
+
+        // But not if it has the wrong checksum
+        area[0x90] ^= 1;
+        assert_eq!(find_entry_point(&area, 0xf_0000).unwrap().address, 0xf_0040);
+    }
+
+    // A type 1 structure with its strings, then a type 127 one
+    fn table(strings: &[&[u8]], indexes: [u8; 4]) -> Vec<u8> {
+        let mut data = vec![1, 27, 0x00, 0x01];
+        data.extend_from_slice(&indexes);
+        data.resize(27, 0xcc);
+        for s in strings {
+            data.extend_from_slice(s);
+            data.push(0);
+        }
+        if strings.is_empty() {
+            data.push(0);
+        }
+        data.push(0);
+        data.extend_from_slice(&[127, 4, 0x01, 0x01, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_system_info() {
+        let data = table(&[b"Cloud Hypervisor", b"cloud-hypervisor"], [1, 2, 0, 2]);
+        assert_eq!(
+            parse_system_info(&data),
+            Some(SystemInfo {
+                manufacturer: Some(b"Cloud Hypervisor"),
+                product_name: Some(b"cloud-hypervisor"),
+                version: None,
+            })
+        );
+        assert_eq!(structures(&data).count(), 1);
+
+        // Indexes past the strings there are
+        let data = table(&[], [1, 0, 0, 0]);
+        assert_eq!(parse_system_info(&data), Some(SystemInfo::default()));
+
+        // Other structures come first
+        let mut data = vec![0, 24, 0x00, 0x00];
+        data.resize(24, 0);
+        data.extend_from_slice(b"BIOS\0\0");
+        data.extend(table(&[b"Vendor"], [1, 0, 0, 0]));
+        assert_eq!(structures(&data).count(), 2);
+        assert_eq!(
+            parse_system_info(&data).unwrap().manufacturer,
+            Some(&b"Vendor"[..])
+        );
+        assert_eq!(parse_system_info(&data[..24]), None);
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// A summary of what was found and picked on the way to booting, for working
+// out why a boot failed. Every line starts with "summary:" so it can be
+// picked out of the rest of the log. It's only there with the boot-summary
+// feature, as going over the PCI buses and memory map again slows booting.
+#![cfg_attr(not(feature = "log-serial"), allow(dead_code, unused_variables))]
+
+use crate::{boot, fat, pci};
+
+const ENABLED: bool = cfg!(feature = "boot-summary");
+
+fn class_name(class: u8) -> &'static str {
+    match class {
+        0x01 => "mass storage",
+        0x02 => "network",
+        0x03 => "display",
+        0x06 => "bridge",
+        0x07 => "communication",
+        0x0c => "serial bus",
+        0xff => "unassigned",
+        _ => "other",
+    }
+}
+
+fn memory_type_name(entry_type: u32) -> &'static str {
+    match entry_type {
+        boot::E820Entry::RAM_TYPE => "RAM",
+        boot::E820Entry::ACPI_TYPE => "ACPI",
+        boot::E820Entry::NVS_TYPE => "ACPI NVS",
+        5 => "unusable",
+        _ => "reserved",
+    }
+}
+
+fn fat_type_name(fat_type: fat::FatType) -> &'static str {
+    match fat_type {
+        fat::FatType::FAT12 => "FAT12",
+        fat::FatType::FAT16 => "FAT16",
+        fat::FatType::FAT32 => "FAT32",
+        fat::FatType::Unknown => "unknown",
+    }
+}
+
+// What the boot protocol and the PCI buses describe
+pub fn platform(info: &dyn boot::Info) {
+    if !ENABLED {
+        return;
+    }
+    log!("summary: booted with {}", info.name());
+    for idx in 0..info.num_entries() {
+        let entry = info.entry(idx);
+        let (addr, size, entry_type) = (entry.addr, entry.size, entry.entry_type);
+        log!(
+            "summary: memory {:#x}-{:#x} {}",
+            addr,
+            addr + size,
+            memory_type_name(entry_type)
+        );
+    }
+    pci::scan(|bus, device, func| {
+        let (vendor_id, device_id) = pci::get_device_details(bus, device, func);
+        let (class, subclass) = pci::get_class_details(bus, device, func);
+        log!(
+            "summary: pci {}:{}.{} {:04x}:{:04x} class {:02x}:{:02x} ({})",
+            bus,
+            device,
+            func,
+            vendor_id,
+            device_id,
+            class,
+            subclass,
+            class_name(class)
+        );
+        false
+    });
+}
+
+pub fn partition(capacity: u64, start: u64, end: u64, name: &str) {
+    if !ENABLED {
+        return;
+    }
+    log!(
+        "summary: disk of {} sectors, partition \"{}\" LBA {}-{}",
+        capacity,
+        name,
+        start,
+        end
+    );
+}
+
+pub fn filesystem(fs: &fat::Filesystem) {
+    if !ENABLED {
+        return;
+    }
+    let fat_type = fat_type_name(fs.fat_type());
+    let label = fs.volume_label().unwrap_or("");
+    log!("summary: filesystem {} label \"{}\"", fat_type, label);
+}
+
+pub fn file(path: &str, size: u32) {
+    if !ENABLED {
+        return;
+    }
+    log!("summary: loading {} ({} bytes)", path, size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{class_name, memory_type_name};
+
+    #[test]
+    fn test_names() {
+        assert_eq!(class_name(0x01), "mass storage");
+        assert_eq!(class_name(0x02), "network");
+        assert_eq!(class_name(0x42), "other");
+        assert_eq!(memory_type_name(1), "RAM");
+        assert_eq!(memory_type_name(4), "ACPI NVS");
+        assert_eq!(memory_type_name(2), "reserved");
+        assert_eq!(memory_type_name(0x42), "reserved");
+    }
+}
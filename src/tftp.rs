@@ -0,0 +1,331 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// A TFTP client (RFC 1350) that downloads a file into memory, asking for
+// bigger blocks (RFC 2348) and the file's size (RFC 2349) where the server
+// supports it
+
+use crate::{
+    delay,
+    ip::{Endpoint, Interface, Ipv4Address, MAX_UDP_PAYLOAD},
+};
+
+const SERVER_PORT: u16 = 69;
+
+// Packet types
+const RRQ: u16 = 1;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+
+const ERROR_DISK_FULL: u16 = 3;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+// As big as fits in an Ethernet frame after the DATA header
+const BLOCK_SIZE: usize = 1468;
+const BLOCK_SIZE_OPTION: &[u8] = b"1468";
+
+// Each packet is sent this many times before giving up
+const ATTEMPTS: usize = 5;
+const TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug)]
+pub enum Error {
+    // The server's address can't be resolved
+    Unreachable,
+    Timeout,
+    FileTooLarge,
+    // The server sent something that makes no sense
+    InvalidPacket,
+    // The server sent an error, with its code
+    Server(u16),
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+// Writes the NUL terminated strings at the offset, returning the offset
+// after them
+fn write_strings(data: &mut [u8], mut offset: usize, strings: &[&[u8]]) -> usize {
+    for s in strings {
+        data[offset..offset + s.len()].copy_from_slice(s);
+        data[offset + s.len()] = 0;
+        offset += s.len() + 1;
+    }
+    offset
+}
+
+// A read request for the file, returning its length
+fn request(data: &mut [u8], name: &[u8]) -> usize {
+    data[0..2].copy_from_slice(&RRQ.to_be_bytes());
+    write_strings(
+        data,
+        2,
+        &[
+            name,
+            b"octet",
+            b"blksize",
+            BLOCK_SIZE_OPTION,
+            b"tsize",
+            b"0",
+        ],
+    )
+}
+
+fn ack(data: &mut [u8], block: u16) -> usize {
+    data[0..2].copy_from_slice(&ACK.to_be_bytes());
+    data[2..4].copy_from_slice(&block.to_be_bytes());
+    4
+}
+
+fn error(data: &mut [u8], code: u16, message: &[u8]) -> usize {
+    data[0..2].copy_from_slice(&ERROR.to_be_bytes());
+    data[2..4].copy_from_slice(&code.to_be_bytes());
+    write_strings(data, 4, &[message])
+}
+
+fn parse_number(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 10 || !s.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(s.iter().fold(0, |n, c| n * 10 + usize::from(c - b'0')))
+}
+
+// What to do after a packet from the server
+#[derive(Debug, PartialEq)]
+enum Step {
+    // Acknowledge the block (0 for the options) and wait for the next
+    Ack(u16),
+    // Acknowledge the last block, the file is complete
+    Done(u16),
+    // Keep waiting for the packet that was asked for
+    Ignore,
+}
+
+// The state of a download into a buffer
+struct Transfer<'a> {
+    buf: &'a mut [u8],
+    length: usize,
+    block_size: usize,
+    // The block last received, which is 0 until the first
+    block: u16,
+}
+
+impl<'a> Transfer<'a> {
+    fn new(buf: &'a mut [u8]) -> Transfer<'a> {
+        Transfer {
+            buf,
+            length: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            block: 0,
+        }
+    }
+
+    fn started(&self) -> bool {
+        self.block != 0 || self.length != 0
+    }
+
+    // The options the server agreed to, which can only come first
+    fn options(&mut self, mut options: &[u8]) -> Result<Step, Error> {
+        while !options.is_empty() {
+            let mut strings = options.splitn(3, |c| *c == 0);
+            let (name, value) = match (strings.next(), strings.next()) {
+                (Some(name), Some(value)) => (name, value),
+                _ => return Err(Error::InvalidPacket),
+            };
+            let value = parse_number(value).ok_or(Error::InvalidPacket)?;
+            if name.eq_ignore_ascii_case(b"blksize") {
+                if !(8..=BLOCK_SIZE).contains(&value) {
+                    return Err(Error::InvalidPacket);
+                }
+                self.block_size = value;
+            } else if name.eq_ignore_ascii_case(b"tsize") && value > self.buf.len() {
+                return Err(Error::FileTooLarge);
+            }
+            options = strings.next().unwrap_or(&[]);
+        }
+        Ok(Step::Ack(0))
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Result<Step, Error> {
+        if packet.len() < 4 {
+            return Ok(Step::Ignore);
+        }
+        match read_u16(packet, 0) {
+            OACK if !self.started() => self.options(&packet[2..]),
+            DATA => {
+                let block = read_u16(packet, 2);
+                // A repeat of the last one, our ACK must have been lost
+                if block == self.block && self.started() {
+                    return Ok(Step::Ack(block));
+                }
+                // Block numbers wrap around for big files
+                if block != self.block.wrapping_add(1) {
+                    return Ok(Step::Ignore);
+                }
+                let data = &packet[4..];
+                if data.len() > self.block_size {
+                    return Err(Error::InvalidPacket);
+                }
+                if self.length + data.len() > self.buf.len() {
+                    return Err(Error::FileTooLarge);
+                }
+                self.buf[self.length..self.length + data.len()].copy_from_slice(data);
+                self.length += data.len();
+                self.block = block;
+                if data.len() < self.block_size {
+                    Ok(Step::Done(block))
+                } else {
+                    Ok(Step::Ack(block))
+                }
+            }
+            ERROR => Err(Error::Server(read_u16(packet, 2))),
+            _ => Ok(Step::Ignore),
+        }
+    }
+}
+
+/// Downloads the file from the server into buf, returning how much of it
+/// the file took up
+pub fn download(
+    interface: &Interface,
+    server: Ipv4Address,
+    name: &[u8],
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let mut remote = Endpoint {
+        mac: interface.resolve(server).ok_or(Error::Unreachable)?,
+        ip: server,
+        port: SERVER_PORT,
+    };
+    // Each transfer is told apart by its port
+    let port = 0xc000 | (delay::now_ns() as u16 & 0x3fff);
+
+    let mut transfer = Transfer::new(buf);
+    let mut packet = [0; MAX_UDP_PAYLOAD];
+    let mut reply = [0; MAX_UDP_PAYLOAD];
+    let mut reply_length = request(&mut reply, name);
+    loop {
+        let mut step = None;
+        for _ in 0..ATTEMPTS {
+            interface.send_udp(port, &remote, &reply[..reply_length]);
+            let deadline = delay::now_ns() + TIMEOUT_MS * 1_000_000;
+            while let Some((source, len)) = interface.recv_udp(port, deadline, &mut packet) {
+                let connected = remote.port != SERVER_PORT;
+                if source.ip != server || (connected && source.port != remote.port) {
+                    continue;
+                }
+                let result = match transfer.handle(&packet[..len]) {
+                    Ok(Step::Ignore) => continue,
+                    result => result,
+                };
+                // The server answers from the port it uses for the rest
+                remote.port = source.port;
+                step = Some(result);
+                break;
+            }
+            if step.is_some() {
+                break;
+            }
+        }
+
+        match step.ok_or(Error::Timeout)? {
+            Ok(Step::Ack(block)) => reply_length = ack(&mut reply, block),
+            Ok(Step::Done(block)) => {
+                let length = ack(&mut reply, block);
+                interface.send_udp(port, &remote, &reply[..length]);
+                return Ok(transfer.length);
+            }
+            Ok(Step::Ignore) => unreachable!(),
+            Err(err) => {
+                // Let the server know we aren't carrying on
+                if let Error::FileTooLarge = err {
+                    let length = error(&mut reply, ERROR_DISK_FULL, b"File too large");
+                    interface.send_udp(port, &remote, &reply[..length]);
+                }
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_number, request, Error, Step, Transfer, BLOCK_SIZE, BLOCK_SIZE_OPTION};
+    use crate::ip::MAX_UDP_PAYLOAD;
+
+    fn data(block: u16, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 3];
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn test_request() {
+        let mut packet = [0; 512];
+        let length = request(&mut packet, b"boot.efi");
+        assert_eq!(
+            &packet[..length],
+            &b"\0\x01boot.efi\0octet\0blksize\01468\0tsize\00\0"[..]
+        );
+        assert_eq!(parse_number(BLOCK_SIZE_OPTION), Some(BLOCK_SIZE));
+        assert_eq!(BLOCK_SIZE + 4, MAX_UDP_PAYLOAD);
+    }
+
+    #[test]
+    fn test_transfer() {
+        // Without options, blocks are 512 bytes
+        let mut buf = [0; 2048];
+        let mut transfer = Transfer::new(&mut buf);
+        assert_eq!(transfer.handle(&data(2, &[1; 512])).unwrap(), Step::Ignore);
+        assert_eq!(transfer.handle(&data(1, &[1; 512])).unwrap(), Step::Ack(1));
+        assert_eq!(transfer.handle(&data(1, &[1; 512])).unwrap(), Step::Ack(1));
+        // Options can't come once the data has started
+        assert_eq!(
+            transfer.handle(b"\0\x06blksize\01024\0").unwrap(),
+            Step::Ignore
+        );
+        assert_eq!(transfer.handle(&data(2, &[2; 100])).unwrap(), Step::Done(2));
+        assert_eq!(transfer.length, 612);
+        assert_eq!(buf[511..513], [1, 2]);
+
+        let mut buf = [0; 2048];
+        let mut transfer = Transfer::new(&mut buf);
+        assert_eq!(
+            transfer
+                .handle(b"\0\x06blksize\01024\0tsize\02048\0")
+                .unwrap(),
+            Step::Ack(0)
+        );
+        assert_eq!(transfer.handle(&data(1, &[1; 1024])).unwrap(), Step::Ack(1));
+        assert_eq!(transfer.handle(&data(2, &[1; 1024])).unwrap(), Step::Ack(2));
+        // The file ends with an empty block when it fills the last
+        assert_eq!(transfer.handle(&data(3, &[])).unwrap(), Step::Done(3));
+        assert_eq!(transfer.length, 2048);
+    }
+
+    #[test]
+    fn test_transfer_errors() {
+        let mut buf = [0; 1000];
+        let mut transfer = Transfer::new(&mut buf);
+        assert!(matches!(
+            transfer.handle(b"\0\x06tsize\01001\0"),
+            Err(Error::FileTooLarge)
+        ));
+        assert!(matches!(
+            transfer.handle(b"\0\x06blksize\0big\0"),
+            Err(Error::InvalidPacket)
+        ));
+        assert!(matches!(
+            transfer.handle(b"\0\x05\0\x01File not found\0"),
+            Err(Error::Server(1))
+        ));
+        assert_eq!(transfer.handle(&data(1, &[1; 512])).unwrap(), Step::Ack(1));
+        assert!(matches!(
+            transfer.handle(&data(2, &[1; 512])),
+            Err(Error::FileTooLarge)
+        ));
+    }
+}
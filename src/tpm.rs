@@ -0,0 +1,562 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// Measured boot: the SHA-256 hashes of what gets loaded are extended into
+// the PCRs of a TPM 2.0, if there is one, and recorded in an event log in
+// the TCG crypto agile format. Without a TPM the measurements are still
+// logged over serial.
+
+use core::fmt;
+
+use atomic_refcell::AtomicRefCell;
+
+use crate::{delay, mem::MemoryRegion};
+
+// Locality 0 of the TPM, where both the TIS and CRB interfaces live
+const TPM_BASE: u64 = 0xfed4_0000;
+const TPM_SIZE: u64 = 0x1000;
+
+// How long to wait for the TPM to be ready, and then for a command to
+// complete
+const READY_TIMEOUT_MS: u64 = 750;
+const COMMAND_TIMEOUT_MS: u64 = 2000;
+
+// The PCRs measurements go into, the same as GRUB uses
+pub const PCR_BOOT_APPLICATION: u32 = 4;
+pub const PCR_COMMAND_LINE: u32 = 8;
+pub const PCR_KERNEL: u32 = 9;
+
+// The bottom bits of the interface identifier register, which both
+// interfaces have
+const INTERFACE_ID: u64 = 0x30;
+const INTERFACE_TYPE_FIFO: u32 = 0x0;
+const INTERFACE_TYPE_CRB: u32 = 0x1;
+const INTERFACE_TYPE_TIS: u32 = 0xf;
+
+// TIS registers
+const TIS_ACCESS: u64 = 0x00;
+const TIS_STS: u64 = 0x18;
+const TIS_DATA_FIFO: u64 = 0x24;
+const TIS_DID_VID: u64 = 0xf00;
+const TIS_ACCESS_REQUEST_USE: u8 = 1 << 1;
+const TIS_ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+const TIS_ACCESS_VALID: u8 = 1 << 7;
+const TIS_STS_DATA_AVAIL: u8 = 1 << 4;
+const TIS_STS_GO: u8 = 1 << 5;
+const TIS_STS_COMMAND_READY: u8 = 1 << 6;
+const TIS_STS_VALID: u8 = 1 << 7;
+
+// CRB registers
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_INTF_ID_VID: u64 = 0x34;
+const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_CMD_HADDR: u64 = 0x60;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+const CRB_LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const CRB_LOC_STATE_VALID: u32 = 1 << 7;
+const CRB_LOC_CTRL_REQUEST_ACCESS: u32 = 1 << 0;
+const CRB_LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+const CRB_CTRL_REQ_CMD_READY: u32 = 1 << 0;
+const CRB_CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+const CRB_CTRL_STS_ERROR: u32 = 1 << 0;
+
+// TPM 2.0 commands
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_STARTUP: u32 = 0x144;
+const TPM_CC_PCR_EXTEND: u32 = 0x182;
+const TPM_SU_CLEAR: u16 = 0x0000;
+// The empty password session that authorizes using the PCRs
+const TPM_RS_PW: u32 = 0x4000_0009;
+const TPM_ALG_SHA256: u16 = 0x000b;
+const TPM_RC_SUCCESS: u32 = 0x000;
+// Returned by TPM2_Startup when something already started the TPM
+const TPM_RC_INITIALIZE: u32 = 0x100;
+// Tag, size and command or response code
+const HEADER_SIZE: usize = 10;
+// Big enough for the commands we send and their responses
+const BUFFER_SIZE: usize = 128;
+
+// Event log entries
+const EV_NO_ACTION: u32 = 0x3;
+const EV_IPL: u32 = 0xd;
+const SPEC_ID_SIGNATURE: &[u8; 16] = b"Spec ID Event03\0";
+const EVENT_LOG_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    NoTpm,
+    Timeout,
+    // The TPM's buffers can't take the command
+    BufferTooSmall,
+    InvalidResponse,
+    // A response code other than success
+    Response(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Interface {
+    Tis,
+    Crb,
+}
+
+struct Tpm {
+    region: MemoryRegion,
+    interface: Interface,
+}
+
+impl Tpm {
+    // Which interface the TPM has, if there is one. Nothing being there
+    // reads back as all zeros or all ones.
+    fn detect(region: MemoryRegion) -> Option<Tpm> {
+        let interface = match region.io_read_u32(INTERFACE_ID) & 0xf {
+            INTERFACE_TYPE_CRB => Interface::Crb,
+            INTERFACE_TYPE_FIFO | INTERFACE_TYPE_TIS => Interface::Tis,
+            _ => return None,
+        };
+        let vendor_id = match interface {
+            Interface::Tis => region.io_read_u32(TIS_DID_VID),
+            Interface::Crb => region.io_read_u32(CRB_INTF_ID_VID),
+        } & 0xffff;
+        if vendor_id == 0 || vendor_id == 0xffff {
+            return None;
+        }
+        Some(Tpm { region, interface })
+    }
+
+    // Sends the command, returning the length of the response
+    fn transmit(&self, command: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        match self.interface {
+            Interface::Tis => {
+                let result = self.tis_transmit(command, response);
+                self.region.io_write_u8(TIS_STS, TIS_STS_COMMAND_READY);
+                self.region
+                    .io_write_u8(TIS_ACCESS, TIS_ACCESS_ACTIVE_LOCALITY);
+                result
+            }
+            Interface::Crb => {
+                let result = self.crb_transmit(command, response);
+                self.region.io_write_u32(CRB_CTRL_REQ, CRB_CTRL_REQ_GO_IDLE);
+                self.region
+                    .io_write_u32(CRB_LOC_CTRL, CRB_LOC_CTRL_RELINQUISH);
+                result
+            }
+        }
+    }
+
+    fn tis_wait(&self, register: u64, bits: u8, timeout_ms: u64) -> Result<(), Error> {
+        wait_until(timeout_ms, || {
+            self.region.io_read_u8(register) & bits == bits
+        })
+    }
+
+    // The FIFO takes as many bytes at a time as the burst count in the middle
+    // of the status register says
+    fn tis_burst_count(&self) -> Result<usize, Error> {
+        let mut count = 0;
+        wait_until(READY_TIMEOUT_MS, || {
+            count = (self.region.io_read_u32(TIS_STS) >> 8) & 0xffff;
+            count != 0
+        })?;
+        Ok(count as usize)
+    }
+
+    fn tis_read(&self, data: &mut [u8]) -> Result<(), Error> {
+        let mut offset = 0;
+        while offset < data.len() {
+            self.tis_wait(
+                TIS_STS,
+                TIS_STS_VALID | TIS_STS_DATA_AVAIL,
+                READY_TIMEOUT_MS,
+            )?;
+            let count = core::cmp::min(self.tis_burst_count()?, data.len() - offset);
+            for byte in &mut data[offset..offset + count] {
+                *byte = self.region.io_read_u8(TIS_DATA_FIFO);
+            }
+            offset += count;
+        }
+        Ok(())
+    }
+
+    fn tis_transmit(&self, command: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        self.region.io_write_u8(TIS_ACCESS, TIS_ACCESS_REQUEST_USE);
+        self.tis_wait(
+            TIS_ACCESS,
+            TIS_ACCESS_VALID | TIS_ACCESS_ACTIVE_LOCALITY,
+            READY_TIMEOUT_MS,
+        )?;
+        self.region.io_write_u8(TIS_STS, TIS_STS_COMMAND_READY);
+        self.tis_wait(TIS_STS, TIS_STS_COMMAND_READY, READY_TIMEOUT_MS)?;
+
+        let mut offset = 0;
+        while offset < command.len() {
+            let count = core::cmp::min(self.tis_burst_count()?, command.len() - offset);
+            for byte in &command[offset..offset + count] {
+                self.region.io_write_u8(TIS_DATA_FIFO, *byte);
+            }
+            offset += count;
+        }
+        self.region.io_write_u8(TIS_STS, TIS_STS_GO);
+        self.tis_wait(
+            TIS_STS,
+            TIS_STS_VALID | TIS_STS_DATA_AVAIL,
+            COMMAND_TIMEOUT_MS,
+        )?;
+
+        // The header says how much more there is to read
+        self.tis_read(&mut response[..HEADER_SIZE])?;
+        let size = response_size(response)?;
+        self.tis_read(&mut response[HEADER_SIZE..size])?;
+        Ok(size)
+    }
+
+    fn crb_transmit(&self, command: &[u8], response: &mut [u8]) -> Result<usize, Error> {
+        let region = &self.region;
+        region.io_write_u32(CRB_LOC_CTRL, CRB_LOC_CTRL_REQUEST_ACCESS);
+        let bits = CRB_LOC_STATE_VALID | CRB_LOC_STATE_ASSIGNED;
+        wait_until(READY_TIMEOUT_MS, || {
+            region.io_read_u32(CRB_LOC_STATE) & bits == bits
+        })?;
+        region.io_write_u32(CRB_CTRL_REQ, CRB_CTRL_REQ_CMD_READY);
+        wait_until(READY_TIMEOUT_MS, || {
+            region.io_read_u32(CRB_CTRL_REQ) & CRB_CTRL_REQ_CMD_READY == 0
+        })?;
+
+        let command_address = u64::from(region.io_read_u32(CRB_CTRL_CMD_HADDR)) << 32
+            | u64::from(region.io_read_u32(CRB_CTRL_CMD_LADDR));
+        let command_size = region.io_read_u32(CRB_CTRL_CMD_SIZE) as usize;
+        let response_address = region.io_read_u64(CRB_CTRL_RSP_ADDR);
+        let response_size_max = region.io_read_u32(CRB_CTRL_RSP_SIZE) as usize;
+        if command.len() > command_size || response_size_max < HEADER_SIZE {
+            return Err(Error::BufferTooSmall);
+        }
+
+        let command_buffer = MemoryRegion::new(command_address, command_size as u64);
+        for (i, byte) in command.iter().enumerate() {
+            command_buffer.io_write_u8(i as u64, *byte);
+        }
+        region.io_write_u32(CRB_CTRL_START, 1);
+        wait_until(COMMAND_TIMEOUT_MS, || {
+            region.io_read_u32(CRB_CTRL_START) & 1 == 0
+        })?;
+        if region.io_read_u32(CRB_CTRL_STS) & CRB_CTRL_STS_ERROR != 0 {
+            return Err(Error::InvalidResponse);
+        }
+
+        let response_buffer = MemoryRegion::new(response_address, response_size_max as u64);
+        for (i, byte) in response[..HEADER_SIZE].iter_mut().enumerate() {
+            *byte = response_buffer.io_read_u8(i as u64);
+        }
+        let size = response_size(response)?;
+        if size > response_size_max {
+            return Err(Error::InvalidResponse);
+        }
+        for (i, byte) in response[..size].iter_mut().enumerate().skip(HEADER_SIZE) {
+            *byte = response_buffer.io_read_u8(i as u64);
+        }
+        Ok(size)
+    }
+
+    fn execute(&self, command: &Command) -> Result<(), Error> {
+        let mut response = [0u8; BUFFER_SIZE];
+        let size = self.transmit(command.as_bytes(), &mut response)?;
+        response_code(&response[..size])
+    }
+}
+
+fn wait_until<F>(ms: u64, cond: F) -> Result<(), Error>
+where
+    F: FnMut() -> bool,
+{
+    if delay::wait_until(ms, cond) {
+        Ok(())
+    } else {
+        Err(Error::Timeout)
+    }
+}
+
+// A TPM 2.0 command, marshalled big endian
+struct Command {
+    data: [u8; BUFFER_SIZE],
+    len: usize,
+}
+
+impl Command {
+    fn new(tag: u16, code: u32) -> Command {
+        let mut command = Command {
+            data: [0; BUFFER_SIZE],
+            len: 0,
+        };
+        command.push(&tag.to_be_bytes());
+        // The size is filled in as the rest is added
+        command.push(&[0; 4]);
+        command.push(&code.to_be_bytes());
+        command
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.data[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        self.data[2..6].copy_from_slice(&(self.len as u32).to_be_bytes());
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+fn startup_command() -> Command {
+    let mut command = Command::new(TPM_ST_NO_SESSIONS, TPM_CC_STARTUP);
+    command.push(&TPM_SU_CLEAR.to_be_bytes());
+    command
+}
+
+fn pcr_extend_command(index: u32, digest: &[u8; 32]) -> Command {
+    let mut command = Command::new(TPM_ST_SESSIONS, TPM_CC_PCR_EXTEND);
+    command.push(&index.to_be_bytes());
+    // The authorization area: the password session with an empty nonce,
+    // no attributes and an empty password
+    command.push(&9u32.to_be_bytes());
+    command.push(&TPM_RS_PW.to_be_bytes());
+    command.push(&[0, 0, 0, 0, 0]);
+    // A TPML_DIGEST_VALUES of just the SHA-256 digest
+    command.push(&1u32.to_be_bytes());
+    command.push(&TPM_ALG_SHA256.to_be_bytes());
+    command.push(digest);
+    command
+}
+
+// The size in a response's header, if the response fits in the buffer
+fn response_size(response: &[u8]) -> Result<usize, Error> {
+    let size = u32::from_be_bytes([response[2], response[3], response[4], response[5]]) as usize;
+    if size < HEADER_SIZE || size > response.len() {
+        return Err(Error::InvalidResponse);
+    }
+    Ok(size)
+}
+
+fn response_code(response: &[u8]) -> Result<(), Error> {
+    if response.len() < HEADER_SIZE || response_size(response)? != response.len() {
+        return Err(Error::InvalidResponse);
+    }
+    match u32::from_be_bytes([response[6], response[7], response[8], response[9]]) {
+        TPM_RC_SUCCESS => Ok(()),
+        rc => Err(Error::Response(rc)),
+    }
+}
+
+// The log starts with the header Linux expects of the table it takes an
+// event log in (LINUX_EFI_TPM_EVENT_LOG_GUID) so it can be handed over as
+// it is
+#[repr(C, packed)]
+pub struct EventLog {
+    size: u32,
+    final_events_preboot_size: u32,
+    // EFI_TCG2_EVENT_LOG_FORMAT_TCG_2
+    version: u8,
+    log: [u8; EVENT_LOG_SIZE],
+}
+
+impl EventLog {
+    const fn new() -> EventLog {
+        EventLog {
+            size: 0,
+            final_events_preboot_size: 0,
+            version: 2,
+            log: [0; EVENT_LOG_SIZE],
+        }
+    }
+
+    fn append(&mut self, parts: &[&[u8]]) -> bool {
+        let start = self.size as usize;
+        let length: usize = parts.iter().map(|p| p.len()).sum();
+        if start + length > EVENT_LOG_SIZE {
+            return false;
+        }
+        let mut offset = start;
+        for part in parts {
+            self.log[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        self.size = offset as u32;
+        true
+    }
+
+    // The first entry is in the old SHA-1 format and says which digests the
+    // rest have
+    fn append_spec_id_event(&mut self) -> bool {
+        let mut spec_id = [0u8; 33];
+        spec_id[..16].copy_from_slice(SPEC_ID_SIGNATURE);
+        // Platform class 0, then version 2.0 errata 0 and UINTN of 8 bytes
+        spec_id[21..24].copy_from_slice(&[2, 0, 2]);
+        // The one algorithm, SHA-256, and no vendor information
+        spec_id[24..28].copy_from_slice(&1u32.to_le_bytes());
+        spec_id[28..30].copy_from_slice(&TPM_ALG_SHA256.to_le_bytes());
+        spec_id[30..32].copy_from_slice(&32u16.to_le_bytes());
+        self.append(&[
+            &0u32.to_le_bytes(),
+            &EV_NO_ACTION.to_le_bytes(),
+            &[0; 20],
+            &(spec_id.len() as u32).to_le_bytes(),
+            &spec_id,
+        ])
+    }
+
+    // A TCG_PCR_EVENT2 with the SHA-256 digest
+    fn add(&mut self, pcr: u32, event_type: u32, digest: &[u8; 32], event: &[u8]) -> bool {
+        if self.size == 0 && !self.append_spec_id_event() {
+            return false;
+        }
+        self.append(&[
+            &pcr.to_le_bytes(),
+            &event_type.to_le_bytes(),
+            &1u32.to_le_bytes(),
+            &TPM_ALG_SHA256.to_le_bytes(),
+            digest,
+            &(event.len() as u32).to_le_bytes(),
+            event,
+        ])
+    }
+}
+
+static TPM: AtomicRefCell<Option<Tpm>> = AtomicRefCell::new(None);
+static EVENT_LOG: AtomicRefCell<EventLog> = AtomicRefCell::new(EventLog::new());
+
+struct Digest<'a>(&'a [u8; 32]);
+
+impl<'a> fmt::Display for Digest<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+// Looks for a TPM and starts it up, which nothing before us has done
+pub fn init() {
+    let tpm = match Tpm::detect(MemoryRegion::new(TPM_BASE, TPM_SIZE)) {
+        Some(tpm) => tpm,
+        None => {
+            log!("No TPM found, measurements are only logged");
+            return;
+        }
+    };
+    match tpm.execute(&startup_command()) {
+        Ok(()) | Err(Error::Response(TPM_RC_INITIALIZE)) => {
+            log!("TPM 2.0 found with {:?} interface", tpm.interface);
+            *TPM.borrow_mut() = Some(tpm);
+        }
+        Err(e) => log!("Error starting up TPM: {:?}", e),
+    }
+}
+
+pub fn present() -> bool {
+    TPM.borrow().is_some()
+}
+
+pub fn pcr_extend(index: u32, digest: &[u8; 32]) -> Result<(), Error> {
+    match &*TPM.borrow() {
+        Some(tpm) => tpm.execute(&pcr_extend_command(index, digest)),
+        None => Err(Error::NoTpm),
+    }
+}
+
+// Records the digest of something that is about to be used, with the event
+// data saying what it is
+pub fn measure(pcr: u32, digest: &[u8; 32], event: &[u8]) {
+    log!(
+        "Measured {} into PCR {}: {}",
+        core::str::from_utf8(event).unwrap_or("?"),
+        pcr,
+        Digest(digest)
+    );
+    if !EVENT_LOG.borrow_mut().add(pcr, EV_IPL, digest, event) {
+        log!("TPM event log is full");
+    }
+    match pcr_extend(pcr, digest) {
+        Ok(()) | Err(Error::NoTpm) => {}
+        Err(e) => log!("Error extending PCR {}: {:?}", pcr, e),
+    }
+}
+
+// The event log, for handing over to the OS
+pub fn event_log() -> *const EventLog {
+    &*EVENT_LOG.borrow()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pcr_extend_command, response_code, startup_command, Error, EventLog};
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            startup_command().as_bytes(),
+            &[0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x44, 0, 0]
+        );
+
+        let command = pcr_extend_command(9, &[0xaa; 32]);
+        let bytes = command.as_bytes();
+        assert_eq!(bytes.len(), 65);
+        assert_eq!(&bytes[..10], &[0x80, 0x02, 0, 0, 0, 65, 0, 0, 0x01, 0x82]);
+        assert_eq!(&bytes[10..14], &[0, 0, 0, 9]);
+        assert_eq!(
+            &bytes[14..27],
+            &[0, 0, 0, 9, 0x40, 0, 0, 0x09, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(&bytes[27..33], &[0, 0, 0, 1, 0, 0x0b]);
+        assert_eq!(&bytes[33..], &[0xaa; 32]);
+    }
+
+    #[test]
+    fn test_response_code() {
+        assert!(response_code(&[0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0]).is_ok());
+        assert!(matches!(
+            response_code(&[0x80, 0x01, 0, 0, 0, 10, 0, 0, 0x01, 0x00]),
+            Err(Error::Response(0x100))
+        ));
+        // The size has to be that of the response
+        assert!(matches!(
+            response_code(&[0x80, 0x01, 0, 0, 0, 12, 0, 0, 0, 0]),
+            Err(Error::InvalidResponse)
+        ));
+        assert!(matches!(
+            response_code(&[0x80, 0x01, 0, 0, 0]),
+            Err(Error::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn test_event_log() {
+        let mut log = Box::new(EventLog::new());
+        assert!(log.add(8, 0xd, &[0x11; 32], b"console=ttyS0"));
+
+        let size = log.size as usize;
+        let data = &log.log[..size];
+        // The spec ID event, then the SHA-256 one
+        assert_eq!(&data[..8], &[0, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(&data[28..32], &33u32.to_le_bytes());
+        assert_eq!(&data[32..48], b"Spec ID Event03\0");
+        assert_eq!(&data[53..56], &[2, 0, 2]);
+        assert_eq!(&data[56..64], &[1, 0, 0, 0, 0x0b, 0, 32, 0]);
+        let event = &data[65..];
+        assert_eq!(
+            &event[..14],
+            &[8, 0, 0, 0, 0xd, 0, 0, 0, 1, 0, 0, 0, 0x0b, 0]
+        );
+        assert_eq!(&event[14..46], &[0x11; 32]);
+        assert_eq!(&event[46..50], &13u32.to_le_bytes());
+        assert_eq!(&event[50..], b"console=ttyS0");
+
+        // Until it is full
+        assert!(!log.add(8, 0xd, &[0; 32], &[0; 16 * 1024]));
+        assert_eq!(log.size as usize, size);
+        assert!(log.add(9, 0xd, &[0; 32], b"initrd"));
+    }
+}
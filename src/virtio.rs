@@ -19,11 +19,21 @@ pub enum Error {
     VirtioLegacyOnly,
     VirtioFeatureNegotiationFailed,
     VirtioQueueTooSmall,
+    // The queue isn't laid out in a way the device can be told about
+    VirtioUnsupportedQueueLayout,
 }
 
+/// The PCI vendor ID of virtio devices
+pub const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+
 /// Trait to allow separation of transport from block driver
 pub trait VirtioTransport {
     fn init(&mut self, device_type: u32) -> Result<(), Error>;
+    // A legacy device, which doesn't offer VIRTIO_F_VERSION_1 nor have
+    // FEATURES_OK
+    fn legacy(&self) -> bool {
+        false
+    }
     fn get_status(&self) -> u32;
     fn set_status(&self, status: u32);
     fn add_status(&self, status: u32);
@@ -36,7 +46,110 @@ pub trait VirtioTransport {
     fn set_descriptors_address(&self, address: u64);
     fn set_avail_ring(&self, address: u64);
     fn set_used_ring(&self, address: u64);
-    fn set_queue_enable(&self);
+    fn set_queue_enable(&self) -> Result<(), Error>;
     fn notify_queue(&self, queue: u16);
     fn read_device_config(&self, offset: u64) -> u32;
 }
+
+// How many times the status and feature handshake is tried, and how long to
+// wait before trying again, doubled after each failure
+const HANDSHAKE_ATTEMPTS: u32 = 4;
+const HANDSHAKE_BACKOFF_MS: u64 = 10;
+
+/// Runs the handshake, which has to start by resetting the device, until the
+/// device accepts the features offered, as one that isn't ready yet might
+/// not. Other errors aren't retried.
+pub fn retry_handshake<F>(mut handshake: F) -> Result<(), Error>
+where
+    F: FnMut() -> Result<(), Error>,
+{
+    let mut attempt = 1;
+    let mut backoff = HANDSHAKE_BACKOFF_MS;
+    loop {
+        match handshake() {
+            Err(Error::VirtioFeatureNegotiationFailed) if attempt < HANDSHAKE_ATTEMPTS => {
+                log!(
+                    "Virtio feature negotiation failed (attempt {} of {}), retrying in {}ms",
+                    attempt,
+                    HANDSHAKE_ATTEMPTS,
+                    backoff
+                );
+                // Tests don't wait, as calibrating the TSC outside a VM can
+                // take seconds
+                #[cfg(not(test))]
+                crate::delay::mdelay(backoff);
+                attempt += 1;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Number of entries in the virtqueues the drivers set up
+pub const QUEUE_SIZE: usize = 16;
+
+#[repr(C)]
+#[repr(align(16))]
+#[derive(Default)]
+/// A virtio qeueue entry descriptor
+pub struct Desc {
+    pub addr: u64,
+    pub length: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C)]
+#[repr(align(2))]
+#[derive(Default)]
+/// The virtio available ring
+pub struct AvailRing {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [u16; QUEUE_SIZE],
+    // Only looked at with VIRTIO_RING_F_EVENT_IDX
+    pub used_event: u16,
+}
+
+#[repr(C)]
+#[repr(align(4096))]
+#[derive(Default)]
+/// The virtio used ring, page aligned so that straight after the descriptors
+/// and available ring it is where a legacy PCI device looks for it
+pub struct UsedRing {
+    pub flags: u16,
+    pub idx: u16,
+    pub ring: [UsedElem; QUEUE_SIZE],
+    // Only written with VIRTIO_RING_F_EVENT_IDX
+    pub avail_event: u16,
+}
+
+#[repr(C)]
+#[derive(Default)]
+/// A single element in the used ring
+pub struct UsedElem {
+    pub id: u32,
+    pub len: u32,
+}
+
+#[repr(C)]
+#[repr(align(16))]
+#[derive(Default)]
+/// A descriptor in a packed virtqueue, which the device writes back over
+/// the first one of each chain when it is done with it
+pub struct PackedDesc {
+    pub addr: u64,
+    pub length: u32,
+    pub id: u16,
+    pub flags: u16,
+}
+
+#[repr(C)]
+#[repr(align(4))]
+#[derive(Default)]
+/// Where each side of a packed virtqueue says when it wants notifying
+pub struct EventSuppression {
+    pub desc: u16,
+    pub flags: u16,
+}
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: Apache-2.0
+
+// A deadline for handing over to what is booted, after which the firmware
+// resets rather than hang for good. It's set with rhfw.boot_timeout=<seconds>
+// on the firmware's command line or in the ESP's command line file, and is
+// off without either. This is apart from the watchdog EFI applications set
+// with SetWatchdogTimer(). Both are checked from the timer interrupt, so a
+// loop that never calls back into the firmware is caught too.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{delay, interrupts, reset};
+
+const OPTION: &[u8] = b"rhfw.boot_timeout=";
+
+// When booting started, and when it has to be handed over by, in ns. A
+// deadline of 0 is none.
+static START: AtomicU64 = AtomicU64::new(0);
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+// The number of seconds in the last valid option
+fn parse_timeout(cmdline: &[u8]) -> Option<u64> {
+    cmdline
+        .split(|c| c.is_ascii_whitespace())
+        .filter_map(|arg| arg.strip_prefix(OPTION))
+        .filter_map(|value| core::str::from_utf8(value).ok()?.parse().ok())
+        .last()
+}
+
+fn deadline(start: u64, seconds: u64) -> u64 {
+    if seconds == 0 {
+        0
+    } else {
+        start.saturating_add(seconds.saturating_mul(1_000_000_000))
+    }
+}
+
+// Counts the timeout from now, with what the command line sets it to
+pub fn init(cmdline: &[u8]) {
+    START.store(delay::now_ns(), Ordering::Relaxed);
+    configure(cmdline);
+}
+
+// A timeout on this command line takes the place of any earlier one, still
+// counting from the start of booting. Zero turns it off.
+pub fn configure(cmdline: &[u8]) {
+    if let Some(seconds) = parse_timeout(cmdline) {
+        log!("Boot timeout: {} seconds", seconds);
+        let start = START.load(Ordering::Relaxed);
+        DEADLINE.store(deadline(start, seconds), Ordering::Relaxed);
+    }
+}
+
+// Called just before handing over, as what is booted then has the machine,
+// timer interrupt included
+pub fn disarm() {
+    DEADLINE.store(0, Ordering::Relaxed);
+    interrupts::stop_timer();
+}
+
+pub fn check() {
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    if deadline != 0 && deadline <= delay::now_ns() {
+        disarm();
+        // This can be the timer interrupt, come in the middle of a log!
+        log_unlocked!("Boot timed out, resetting");
+        reset::reset(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deadline, parse_timeout};
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout(b""), None);
+        assert_eq!(parse_timeout(b"console=ttyS0 root=/dev/vda1"), None);
+        assert_eq!(parse_timeout(b"rhfw.boot_timeout=30"), Some(30));
+        // The last valid one wins
+        assert_eq!(
+            parse_timeout(b"rhfw.boot_timeout=30 quiet\trhfw.boot_timeout=0 rhfw.boot_timeout=x"),
+            Some(0)
+        );
+        assert_eq!(parse_timeout(b"rhfw.boot_timeout="), None);
+        assert_eq!(parse_timeout(b"xrhfw.boot_timeout=30"), None);
+    }
+
+    #[test]
+    fn test_deadline() {
+        assert_eq!(deadline(1000, 0), 0);
+        assert_eq!(deadline(1000, 5), 5_000_001_000);
+        assert_eq!(deadline(1000, u64::MAX), u64::MAX);
+    }
+}